@@ -0,0 +1,87 @@
+//! Lock contention under concurrent clients: one exclusive lock, one shared
+//! by reads, and each of those split into shards
+//!
+//! Run with `cargo bench --bench contention`. Every task hammers the same
+//! few hot keys, the worst case for the locks; a tenth of the mixed load's
+//! commands are writes.
+use bytes::Bytes;
+use rdb::commands::{execute, Command};
+use rdb::config::StorageConfig;
+use rdb::connection::{ClientRegistry, ConnCtx};
+use rdb::storage::{Deadline, Shards};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const TASKS: usize = 64;
+const OPS_PER_TASK: usize = 20_000;
+const HOT_KEYS: usize = 16;
+
+/// (name, shards, shared reads)
+const DESIGNS: [(&str, usize, bool); 4] = [
+    ("mutex", 1, false),
+    ("rwlock", 1, true),
+    ("sharded mutex", 16, false),
+    ("sharded rwlock", 16, true),
+];
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    for write_every in [None, Some(10)] {
+        let load = match write_every {
+            None => "GET only",
+            Some(_) => "GET + 10% INCRBY",
+        };
+        println!("{} ({} tasks x {} ops)", load, TASKS, OPS_PER_TASK);
+        for (name, shards, shared_reads) in DESIGNS {
+            let elapsed = runtime.block_on(run(shards, shared_reads, write_every));
+            let ops = (TASKS * OPS_PER_TASK) as f64 / elapsed.as_secs_f64();
+            println!("  {:<16} {:>10.0} ops/s", name, ops);
+        }
+    }
+}
+
+async fn run(shards: usize, shared_reads: bool, write_every: Option<usize>) -> Duration {
+    let db = Arc::new(Shards::new(StorageConfig {
+        shards,
+        shared_reads,
+        ..StorageConfig::default()
+    }));
+    let clients = Arc::new(ClientRegistry::new());
+    let keys: Vec<Bytes> = (0..HOT_KEYS).map(|i| format!("key:{}", i).into()).collect();
+    for key in &keys {
+        let set = Command::IncrBy(key.clone(), 1);
+        execute(
+            set,
+            &db,
+            &ConnCtx::default(),
+            &clients,
+            Deadline::after(None),
+        )
+        .await;
+    }
+
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let (db, clients, keys) = (db.clone(), clients.clone(), keys.clone());
+            tokio::spawn(async move {
+                let conn = ConnCtx::default();
+                for i in 0..OPS_PER_TASK {
+                    let key = keys[(task + i) % keys.len()].clone();
+                    let command = match write_every {
+                        Some(n) if i % n == 0 => Command::IncrBy(key, 1),
+                        _ => Command::Get(key),
+                    };
+                    execute(command, &db, &conn, &clients, Deadline::after(None)).await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    start.elapsed()
+}
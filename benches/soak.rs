@@ -0,0 +1,286 @@
+//! Soak test: thousands of short connections and a few long pipelined ones
+//! against an in-process server, checking nothing leaks
+//!
+//! Run with `cargo bench --bench soak`; it exits with an error if the heap
+//! or the open file descriptors grew. Heap bytes are counted by a global
+//! allocator wrapping the system one, so the numbers are exact whatever the
+//! allocator underneath. File descriptors are counted in `/proc/self/fd`,
+//! so that check is skipped where there's none.
+//!
+//! The commands write the same few keys over and over, so the dataset stays
+//! the same size; whatever else the server keeps per client has to be gone
+//! once they disconnect.
+use rdb::config::Config;
+use rdb::protocol::{parse_resp, RespError, RespValue};
+use rdb::Server;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CYCLES: usize = 5_000;
+/// Short connections open at once.
+const CONCURRENCY: usize = 50;
+const PIPELINED_CONNECTIONS: usize = 16;
+const PIPELINE_ROUNDS: usize = 200;
+const PIPELINE_DEPTH: usize = 100;
+const KEYS: usize = 100;
+/// Heap growth tolerated between the first and the last measurement, for
+/// the bounded history the server keeps: the slow log, latency samples and
+/// per-command counters.
+const HEAP_SLACK: usize = 1024 * 1024;
+
+struct Counting;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            LIVE_BYTES.fetch_add(new_size, Ordering::Relaxed);
+            LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Open file descriptors of this process, or `None` if it can't tell.
+fn open_fds() -> Option<usize> {
+    std::fs::read_dir("/proc/self/fd")
+        .ok()
+        .map(|entries| entries.count())
+}
+
+/// Heap and descriptors in use at one point.
+#[derive(Debug, Clone, Copy)]
+struct Usage {
+    heap: usize,
+    fds: Option<usize>,
+}
+
+impl Usage {
+    fn now() -> Self {
+        Usage {
+            heap: LIVE_BYTES.load(Ordering::Relaxed),
+            fds: open_fds(),
+        }
+    }
+}
+
+/// A bare RESP client.
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> Self {
+        Client {
+            stream: TcpStream::connect(addr).await.expect("connect"),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Sends `commands` in one write and reads a reply to each.
+    async fn pipeline(&mut self, commands: &[Vec<String>]) -> Vec<RespValue> {
+        let mut request = Vec::new();
+        for args in commands {
+            let frame = RespValue::Array(args.iter().map(|a| RespValue::bulk(a.clone())).collect());
+            request.extend_from_slice(&frame.serialize());
+        }
+        self.stream.write_all(&request).await.expect("write");
+        let mut replies = Vec::with_capacity(commands.len());
+        while replies.len() < commands.len() {
+            match parse_resp(&self.buffer) {
+                Ok((reply, used)) => {
+                    self.buffer.drain(..used);
+                    replies.push(reply);
+                }
+                Err(RespError::Incomplete) => {
+                    let mut chunk = [0; 16 * 1024];
+                    let n = self.stream.read(&mut chunk).await.expect("read");
+                    assert!(n > 0, "server closed the connection");
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) => panic!("bad reply: {}", e),
+            }
+        }
+        replies
+    }
+}
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+/// Connects, runs a few commands and disconnects, `count` times,
+/// `CONCURRENCY` at once.
+async fn short_connections(addr: SocketAddr, count: usize) {
+    let mut tasks = tokio::task::JoinSet::new();
+    for cycle in 0..count {
+        if tasks.len() == CONCURRENCY {
+            tasks.join_next().await.unwrap().unwrap();
+        }
+        tasks.spawn(async move {
+            let key = format!("soak:{}", cycle % KEYS);
+            let mut client = Client::connect(addr).await;
+            let replies = client
+                .pipeline(&[
+                    command(&["CLIENT", "SETNAME", &format!("short-{}", cycle)]),
+                    command(&["SET", &key, &"x".repeat(64)]),
+                    command(&["GET", &key]),
+                    command(&["PING"]),
+                ])
+                .await;
+            assert_eq!(replies[3], RespValue::SimpleString("PONG".to_string()));
+        });
+    }
+    while let Some(task) = tasks.join_next().await {
+        task.unwrap();
+    }
+}
+
+/// A few connections kept open, each sending deep pipelines.
+async fn pipelined_connections(addr: SocketAddr) {
+    let mut tasks = tokio::task::JoinSet::new();
+    for connection in 0..PIPELINED_CONNECTIONS {
+        tasks.spawn(async move {
+            let mut client = Client::connect(addr).await;
+            for round in 0..PIPELINE_ROUNDS {
+                let commands: Vec<_> = (0..PIPELINE_DEPTH)
+                    .map(|i| {
+                        let key = format!("soak:{}", (connection + round + i) % KEYS);
+                        match i % 3 {
+                            0 => command(&["SET", &key, &"y".repeat(64)]),
+                            1 => command(&["GET", &key]),
+                            _ => command(&["MGET", &key, "soak:0", "missing"]),
+                        }
+                    })
+                    .collect();
+                let replies = client.pipeline(&commands).await;
+                assert!(replies.iter().all(|r| !matches!(r, RespValue::Error(_))));
+            }
+        });
+    }
+    while let Some(task) = tasks.join_next().await {
+        task.unwrap();
+    }
+}
+
+/// Waits for the server to notice every client but the probe is gone.
+async fn wait_for_disconnects(addr: SocketAddr) {
+    let mut probe = Client::connect(addr).await;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let replies = probe.pipeline(&[command(&["INFO"])]).await;
+        let RespValue::BulkString(Some(info)) = &replies[0] else {
+            panic!("INFO replies with a bulk string");
+        };
+        if String::from_utf8_lossy(info).contains("connected_clients:1\r\n") {
+            return;
+        }
+        assert!(Instant::now() < deadline, "clients never disconnected");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Usage once the server has settled: clients gone and their descriptors
+/// closed.
+async fn settled(addr: SocketAddr) -> Usage {
+    wait_for_disconnects(addr).await;
+    // The probe's own socket takes a moment to close on the server side.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    Usage::now()
+}
+
+fn check(phase: &str, baseline: Usage, after: Usage) -> bool {
+    let growth = after.heap.saturating_sub(baseline.heap);
+    println!(
+        "{:<24} heap {:>10} bytes ({:+}), fds {:?}",
+        phase,
+        after.heap,
+        after.heap as i64 - baseline.heap as i64,
+        after.fds
+    );
+    let mut ok = true;
+    if growth > HEAP_SLACK {
+        eprintln!("{}: heap grew by {} bytes", phase, growth);
+        ok = false;
+    }
+    if let (Some(before), Some(after)) = (baseline.fds, after.fds) {
+        if after > before {
+            eprintln!("{}: {} file descriptors leaked", phase, after - before);
+            ok = false;
+        }
+    }
+    ok
+}
+
+fn main() -> ExitCode {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let ok = runtime.block_on(async {
+        let mut config = Config::default();
+        config.storage.save_rules = vec![];
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .expect("server starts");
+        let addr = server.local_addr();
+
+        // Once through everything first, so buffers, tables and the
+        // runtime's threads grown on first use count towards the baseline.
+        short_connections(addr, CONCURRENCY * 4).await;
+        pipelined_connections(addr).await;
+        let baseline = settled(addr).await;
+        println!(
+            "{:<24} heap {:>10} bytes, fds {:?}",
+            "baseline", baseline.heap, baseline.fds
+        );
+
+        let started = Instant::now();
+        short_connections(addr, CYCLES).await;
+        let ok = check(
+            &format!("{} connections", CYCLES),
+            baseline,
+            settled(addr).await,
+        );
+        pipelined_connections(addr).await;
+        let ok = check("pipelined connections", baseline, settled(addr).await) && ok;
+        short_connections(addr, CYCLES).await;
+        let ok = check("both again", baseline, settled(addr).await) && ok;
+        println!("done in {:.1?}", started.elapsed());
+
+        server.shutdown().await;
+        ok
+    });
+    if ok {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,95 @@
+// Checks run with the node-redis client against a server, printing one
+// `<check> pass|fail` line each.
+const { createClient } = require('redis');
+const assert = require('assert/strict');
+
+const [host = '127.0.0.1', port = '6379'] = process.argv.slice(2);
+const url = `redis://${host}:${port}`;
+
+const checks = {
+  async set_get(client) {
+    await client.set('node:k', 'v');
+    assert.equal(await client.get('node:k'), 'v');
+    assert.equal(await client.get('node:missing'), null);
+  },
+  async del(client) {
+    await client.set('node:a', '1');
+    await client.set('node:b', '1');
+    assert.equal(await client.del(['node:a', 'node:b', 'node:c']), 2);
+  },
+  async getrange(client) {
+    await client.set('node:s', 'This is a string');
+    assert.equal(await client.getRange('node:s', -3, -1), 'ing');
+  },
+  async sets(client) {
+    assert.equal(await client.sAdd('node:set1', ['a', 'b', 'c']), 3);
+    await client.sAdd('node:set2', ['b', 'c', 'd']);
+    assert.deepEqual((await client.sInter(['node:set1', 'node:set2'])).sort(), ['b', 'c']);
+    assert.equal(await client.sUnionStore('node:set3', ['node:set1', 'node:set2']), 4);
+    assert.equal(await client.sIsMember('node:set3', 'd'), true);
+  },
+  async sorted_sets(client) {
+    await client.zAdd('node:z', [
+      { score: 1, value: 'a' },
+      { score: 2, value: 'b' },
+      { score: 3, value: 'c' },
+    ]);
+    assert.equal(await client.zScore('node:z', 'b'), 2);
+    assert.deepEqual(await client.zRange('node:z', 0, -1), ['a', 'b', 'c']);
+    assert.deepEqual(await client.zRangeByScore('node:z', '(1', '+inf'), ['b', 'c']);
+  },
+  async zadd_gt(client) {
+    await client.zAdd('node:zgt', { score: 5, value: 'a' });
+    await client.zAdd('node:zgt', { score: 1, value: 'a' }, { GT: true });
+    assert.equal(await client.zScore('node:zgt', 'a'), 5);
+  },
+  async lmpop(client) {
+    await client.rPush('node:list', ['a', 'b']);
+    const popped = await client.lmPop(['node:list'], 'LEFT');
+    assert.deepEqual(popped.elements, ['a']);
+  },
+  async multi_exec(client) {
+    const replies = await client.multi().set('node:tx', '1').get('node:tx').exec();
+    assert.deepEqual(replies, ['OK', '1']);
+  },
+  async execabort(client) {
+    // A command the server rejects while queueing discards the transaction.
+    await assert.rejects(
+      client.multi().set('node:tx2', '1').addCommand(['BOGUSCOMMAND']).exec(),
+    );
+    assert.equal(await client.get('node:tx2'), null);
+  },
+  async pubsub(client) {
+    const subscriber = client.duplicate();
+    await subscriber.connect();
+    try {
+      const received = new Promise((resolve) => {
+        subscriber.subscribe('node:channel', resolve);
+      });
+      // Let the subscription land before publishing.
+      await new Promise((resolve) => setTimeout(resolve, 100));
+      assert.equal(await client.publish('node:channel', 'hello'), 1);
+      assert.equal(await received, 'hello');
+      await subscriber.unsubscribe('node:channel');
+    } finally {
+      await subscriber.quit();
+    }
+  },
+};
+
+(async () => {
+  for (const [name, check] of Object.entries(checks)) {
+    // A fresh client each, so a failed check can't affect the next one.
+    const client = createClient({ url });
+    client.on('error', () => {});
+    try {
+      await client.connect();
+      await check(client);
+      console.log(`${name} pass`);
+    } catch (e) {
+      console.log(`${name} fail ${String(e.message || e).split('\n')[0]}`);
+    } finally {
+      await client.disconnect().catch(() => {});
+    }
+  }
+})();
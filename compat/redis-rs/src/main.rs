@@ -0,0 +1,151 @@
+//! Checks run with the redis-rs client against a server, printing one
+//! `<check> pass|fail` line each.
+use redis::{Commands, Connection, RedisResult};
+use std::time::Duration;
+
+type Check = fn(&mut Connection) -> RedisResult<()>;
+
+fn ensure(ok: bool, what: &str) -> RedisResult<()> {
+    if ok {
+        Ok(())
+    } else {
+        Err((redis::ErrorKind::ResponseError, "unexpected reply", what.to_string()).into())
+    }
+}
+
+fn set_get(con: &mut Connection) -> RedisResult<()> {
+    con.set::<_, _, ()>("rs:k", "v")?;
+    let value: Option<String> = con.get("rs:k")?;
+    ensure(value.as_deref() == Some("v"), "GET")?;
+    let missing: Option<String> = con.get("rs:missing")?;
+    ensure(missing.is_none(), "GET missing")
+}
+
+fn del(con: &mut Connection) -> RedisResult<()> {
+    con.set::<_, _, ()>("rs:a", "1")?;
+    con.set::<_, _, ()>("rs:b", "1")?;
+    let deleted: usize = con.del(&["rs:a", "rs:b", "rs:c"])?;
+    ensure(deleted == 2, "DEL")
+}
+
+fn getrange(con: &mut Connection) -> RedisResult<()> {
+    con.set::<_, _, ()>("rs:s", "This is a string")?;
+    let part: String = con.getrange("rs:s", -3, -1)?;
+    ensure(part == "ing", "GETRANGE")
+}
+
+fn sets(con: &mut Connection) -> RedisResult<()> {
+    let added: usize = con.sadd("rs:set1", &["a", "b", "c"])?;
+    ensure(added == 3, "SADD")?;
+    con.sadd::<_, _, ()>("rs:set2", &["b", "c", "d"])?;
+    let mut inter: Vec<String> = con.sinter(&["rs:set1", "rs:set2"])?;
+    inter.sort();
+    ensure(inter == ["b", "c"], "SINTER")?;
+    let stored: usize = con.sunionstore("rs:set3", &["rs:set1", "rs:set2"])?;
+    ensure(stored == 4, "SUNIONSTORE")?;
+    let member: bool = con.sismember("rs:set3", "d")?;
+    ensure(member, "SISMEMBER")
+}
+
+fn sorted_sets(con: &mut Connection) -> RedisResult<()> {
+    con.zadd_multiple::<_, _, _, ()>("rs:z", &[(1, "a"), (2, "b"), (3, "c")])?;
+    let score: Option<f64> = con.zscore("rs:z", "b")?;
+    ensure(score == Some(2.0), "ZSCORE")?;
+    let range: Vec<(String, f64)> = con.zrange_withscores("rs:z", 0, -1)?;
+    ensure(range.len() == 3 && range[0].0 == "a", "ZRANGE")?;
+    let by_score: Vec<String> = con.zrangebyscore("rs:z", "(1", "+inf")?;
+    ensure(by_score == ["b", "c"], "ZRANGEBYSCORE")
+}
+
+fn zadd_gt(con: &mut Connection) -> RedisResult<()> {
+    con.zadd::<_, _, _, ()>("rs:zgt", "a", 5)?;
+    redis::cmd("ZADD")
+        .arg("rs:zgt")
+        .arg("GT")
+        .arg(1)
+        .arg("a")
+        .query::<()>(con)?;
+    let score: Option<f64> = con.zscore("rs:zgt", "a")?;
+    ensure(score == Some(5.0), "ZADD GT")
+}
+
+fn lmpop(con: &mut Connection) -> RedisResult<()> {
+    redis::cmd("RPUSH")
+        .arg("rs:list")
+        .arg(&["a", "b"])
+        .query::<()>(con)?;
+    let popped: (String, Vec<String>) = redis::cmd("LMPOP")
+        .arg(1)
+        .arg("rs:list")
+        .arg("LEFT")
+        .query(con)?;
+    ensure(popped.1 == ["a"], "LMPOP")
+}
+
+fn pipeline(con: &mut Connection) -> RedisResult<()> {
+    let (a, b): (String, String) = redis::pipe()
+        .set("rs:p1", "1")
+        .ignore()
+        .set("rs:p2", "2")
+        .ignore()
+        .get("rs:p1")
+        .get("rs:p2")
+        .query(con)?;
+    ensure((a.as_str(), b.as_str()) == ("1", "2"), "pipeline")
+}
+
+fn transaction(con: &mut Connection) -> RedisResult<()> {
+    let (value,): (String,) = redis::transaction(con, &["rs:tx"], |con, pipe| {
+        let old: Option<String> = con.get("rs:tx")?;
+        pipe.set("rs:tx", format!("{}x", old.unwrap_or_default()))
+            .ignore()
+            .get("rs:tx")
+            .query(con)
+    })?;
+    ensure(value.ends_with('x'), "MULTI/EXEC")
+}
+
+fn pubsub(con: &mut Connection) -> RedisResult<()> {
+    let client = redis::Client::open(server_url())?;
+    let mut publisher = client.get_connection()?;
+    let mut pubsub = con.as_pubsub();
+    pubsub.set_read_timeout(Some(Duration::from_secs(2)))?;
+    pubsub.subscribe("rs:channel")?;
+    let receivers: usize = publisher.publish("rs:channel", "hello")?;
+    ensure(receivers == 1, "PUBLISH")?;
+    let message = pubsub.get_message()?;
+    ensure(message.get_payload::<String>()? == "hello", "message")?;
+    pubsub.unsubscribe("rs:channel")
+}
+
+fn server_url() -> String {
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:6379".to_string());
+    format!("redis://{}/", addr)
+}
+
+fn main() {
+    let checks: &[(&str, Check)] = &[
+        ("set_get", set_get),
+        ("del", del),
+        ("getrange", getrange),
+        ("sets", sets),
+        ("sorted_sets", sorted_sets),
+        ("zadd_gt", zadd_gt),
+        ("lmpop", lmpop),
+        ("pipeline", pipeline),
+        ("transaction", transaction),
+        ("pubsub", pubsub),
+    ];
+    let client = redis::Client::open(server_url()).expect("valid server address");
+    for (name, check) in checks {
+        // A fresh connection each, so a failed check can't leave the next
+        // one in subscribe mode or inside a transaction.
+        let result = client.get_connection().and_then(|mut con| check(&mut con));
+        match result {
+            Ok(()) => println!("{} pass", name),
+            Err(e) => println!("{} fail {}", name, e),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+#!/usr/bin/env bash
+# Runs client library checks against a fresh rdb server and compares the
+# outcomes with compat/matrix.txt. The clients run in Docker, so nothing but
+# Docker and Cargo is needed on the host.
+#
+#   compat/run.sh                  all clients
+#   compat/run.sh redis-py         one client
+#
+# redis-py runs a subset of its own test suite, pointed at the server with
+# --redis-url. The redis-rs and node-redis suites start their own Redis, so
+# those clients run the vendored checks next to this script instead.
+set -euo pipefail
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+compat="$root/compat"
+port="${RDB_COMPAT_PORT:-6399}"
+if [[ $# -gt 0 ]]; then
+    clients=("$@")
+else
+    clients=(redis-py redis-rs node-redis)
+fi
+
+REDIS_PY_VERSION=v5.0.8
+NODE_IMAGE=node:20
+PYTHON_IMAGE=python:3.12
+RUST_IMAGE=rust:1
+
+cargo build --release --manifest-path "$root/Cargo.toml"
+workdir="$(mktemp -d)"
+trap 'kill "$server" 2>/dev/null || true; rm -rf "$workdir"' EXIT
+cat > "$workdir/config.json" <<CONFIG
+{
+  "server": {"listen_addr": "127.0.0.1:$port", "max_connections": 1000, "buffer_size": 4096},
+  "storage": {"max_memory": 268435456, "persistence_enabled": false}
+}
+CONFIG
+(cd "$workdir" && exec "$root/target/release/rdb") > "$workdir/rdb.log" 2>&1 &
+server=$!
+sleep 1
+
+results="$workdir/results.txt"
+: > "$results"
+
+# The checks of `client` listed in the matrix, one per line.
+checks() {
+    awk -v client="$1" '$1 == client { print $2 }' "$compat/matrix.txt"
+}
+
+run_redis_py() {
+    local tests
+    tests="$(checks redis-py | sed 's|^|tests/|' | tr '\n' ' ')"
+    docker run --rm --network host "$PYTHON_IMAGE" sh -c "
+        git clone -q --depth 1 --branch $REDIS_PY_VERSION https://github.com/redis/redis-py /redis-py &&
+        cd /redis-py && pip install -q . pytest pytest-asyncio mock &&
+        pytest -q -rA -p no:cacheprovider --redis-url=redis://127.0.0.1:$port $tests" \
+        | sed -n 's|^\(PASSED\|FAILED\|ERROR\|SKIPPED\) tests/\([^ []*\).*|\1 \2|p' \
+        | awk '{ print "redis-py", $2, ($1 == "PASSED" ? "pass" : "fail") }' >> "$results" || true
+}
+
+run_redis_rs() {
+    docker run --rm --network host -v "$compat/redis-rs:/checks" -w /checks "$RUST_IMAGE" \
+        cargo run -q --release -- "127.0.0.1:$port" \
+        | awk '{ print "redis-rs", $1, $2 }' >> "$results" || true
+}
+
+run_node_redis() {
+    docker run --rm --network host -v "$compat/node-redis:/checks" -w /checks "$NODE_IMAGE" \
+        sh -c "npm install -q --no-audit --no-fund >/dev/null && node check.js 127.0.0.1 $port" \
+        | awk '{ print "node-redis", $1, $2 }' >> "$results" || true
+}
+
+for client in "${clients[@]}"; do
+    echo "== $client"
+    "run_${client//-/_}"
+done
+
+# Compare with the matrix. A check missing from the results didn't run,
+# which counts as a failure.
+status=0
+while read -r client check expected; do
+    [[ -z "$client" || "$client" == \#* ]] && continue
+    [[ " ${clients[*]} " == *" $client "* ]] || continue
+    actual="$(awk -v c="$client" -v t="$check" '$1 == c && $2 == t { print $3 }' "$results" | tail -1)"
+    actual="${actual:-fail}"
+    if [[ "$expected" == pass && "$actual" != pass ]]; then
+        echo "REGRESSION $client $check"
+        status=1
+    elif [[ "$expected" == fail && "$actual" == pass ]]; then
+        echo "NOW PASSING $client $check (update compat/matrix.txt)"
+    fi
+done < "$compat/matrix.txt"
+[[ $status == 0 ]] && echo "Compatibility matrix holds"
+exit $status
@@ -0,0 +1,584 @@
+//! Checking passwords against a source outside the config
+//!
+//! A password that matches no configured user can still be vouched for by
+//! an [`AuthBackend`]: a file of users in `ACL LIST` form, an HTTP
+//! introspection endpoint, or HMAC-signed tokens. Answers are cached for
+//! `cache_ttl_ms`, so reconnecting clients don't wait on the backend each
+//! time; a backend that fails is not cached, and rejects.
+use super::{parse_categories, AclError, PasswordHash};
+use crate::commands::CommandClass;
+use crate::config::{AuthBackendConfig, AuthBackendKind, Secret};
+use log::warn;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Cached answers kept at most; past that, expired ones are dropped, and
+/// all of them if none has.
+const MAX_CACHED: usize = 10_000;
+
+/// Longest introspection response read.
+const MAX_RESPONSE: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("no answer within {0:?}")]
+    Timeout(Duration),
+    #[error("unexpected response: {0}")]
+    BadResponse(String),
+    #[error("{0}")]
+    InvalidFile(String),
+}
+
+/// What a backend grants a user it vouched for. Unset permissions are those
+/// of the configured user of the same name, or else the backend's defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Grant {
+    pub categories: Option<Vec<CommandClass>>,
+    pub keys: Option<Vec<String>>,
+}
+
+pub type Verdict<'a> = Pin<Box<dyn Future<Output = Result<Option<Grant>, AuthError>> + Send + 'a>>;
+
+pub trait AuthBackend: Send + Sync {
+    /// Checks `password` for `username`: what they're granted, or `None`
+    /// if the backend turns them down.
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> Verdict<'a>;
+
+    /// Whether answers are worth caching; not if checking is as cheap as a
+    /// cache lookup.
+    fn cacheable(&self) -> bool {
+        true
+    }
+}
+
+/// Builds the backend `config` describes, `None` if it names none.
+pub fn from_config(config: &AuthBackendConfig) -> Result<Option<Box<dyn AuthBackend>>, AclError> {
+    let missing = |option: &str| {
+        AclError::InvalidBackend(format!(
+            "the {} auth backend needs {}",
+            config.kind.as_str(),
+            option
+        ))
+    };
+    let backend: Box<dyn AuthBackend> = match config.kind {
+        AuthBackendKind::None => return Ok(None),
+        AuthBackendKind::File => Box::new(UserFile::new(
+            config.path.clone().ok_or_else(|| missing("a path"))?,
+        )),
+        AuthBackendKind::Http => Box::new(Introspection::new(
+            config.url.as_deref().ok_or_else(|| missing("a url"))?,
+            Duration::from_millis(config.timeout_ms.max(1)),
+        )?),
+        AuthBackendKind::Hmac => Box::new(HmacTokens::new(
+            config.secret.clone().ok_or_else(|| missing("a secret"))?,
+        )),
+    };
+    Ok(Some(backend))
+}
+
+/// A user and the hash of the password they gave.
+type Credentials = (String, PasswordHash);
+
+/// Answers of a backend by user and password hash, so no plaintext is kept.
+pub struct AuthCache {
+    ttl: Duration,
+    /// Answers and when they expire.
+    entries: Mutex<HashMap<Credentials, (Instant, Option<Grant>)>>,
+}
+
+impl AuthCache {
+    pub fn new(ttl: Duration) -> Self {
+        AuthCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, username: &str, password: &str) -> Option<Option<Grant>> {
+        let key = (username.to_string(), PasswordHash::of(password.as_bytes()));
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&key)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, grant)| grant.clone())
+    }
+
+    pub fn insert(&self, username: &str, password: &str, grant: Option<Grant>) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHED {
+            entries.retain(|_, (expires, _)| *expires > now);
+            if entries.len() >= MAX_CACHED {
+                entries.clear();
+            }
+        }
+        let key = (username.to_string(), PasswordHash::of(password.as_bytes()));
+        entries.insert(key, (now + self.ttl, grant));
+    }
+}
+
+/// A backend with its cache.
+pub struct CachedBackend {
+    backend: Box<dyn AuthBackend>,
+    cache: AuthCache,
+}
+
+impl CachedBackend {
+    pub fn new(backend: Box<dyn AuthBackend>, ttl: Duration) -> Self {
+        CachedBackend {
+            backend,
+            cache: AuthCache::new(ttl),
+        }
+    }
+
+    pub async fn verify(&self, username: &str, password: &str) -> Option<Grant> {
+        let cacheable = self.backend.cacheable();
+        if cacheable {
+            if let Some(grant) = self.cache.get(username, password) {
+                return grant;
+            }
+        }
+        match self.backend.verify(username, password).await {
+            Ok(grant) => {
+                if cacheable {
+                    self.cache.insert(username, password, grant.clone());
+                }
+                grant
+            }
+            Err(e) => {
+                warn!("Auth backend failed to check user {}: {}", username, e);
+                None
+            }
+        }
+    }
+}
+
+/// Users in `ACL LIST` form, one per line: `user alice on #<sha256> ~cache:*
+/// +@read`. `>password` gives a plaintext password, `off` disables the
+/// user, and lines starting with `#` are comments. The file is read again
+/// whenever it changes.
+pub struct UserFile {
+    path: PathBuf,
+    loaded: tokio::sync::Mutex<Option<(SystemTime, HashMap<String, FileUser>)>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct FileUser {
+    enabled: bool,
+    nopass: bool,
+    passwords: Vec<PasswordHash>,
+    grant: Grant,
+}
+
+impl UserFile {
+    pub fn new(path: PathBuf) -> Self {
+        UserFile {
+            path,
+            loaded: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn user(&self, username: &str) -> Result<Option<FileUser>, AuthError> {
+        let modified = tokio::fs::metadata(&self.path).await?.modified()?;
+        let mut loaded = self.loaded.lock().await;
+        if !matches!(&*loaded, Some((at, _)) if *at == modified) {
+            let contents = tokio::fs::read_to_string(&self.path).await?;
+            *loaded = Some((modified, parse_user_file(&contents)?));
+        }
+        Ok(loaded
+            .as_ref()
+            .and_then(|(_, users)| users.get(username).cloned()))
+    }
+}
+
+impl AuthBackend for UserFile {
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> Verdict<'a> {
+        Box::pin(async move {
+            let Some(user) = self.user(username).await? else {
+                return Ok(None);
+            };
+            let matches = user
+                .passwords
+                .iter()
+                .fold(false, |ok, hash| hash.verify(password.as_bytes()) | ok);
+            Ok((user.enabled && (user.nopass || matches)).then_some(user.grant))
+        })
+    }
+}
+
+fn parse_user_file(contents: &str) -> Result<HashMap<String, FileUser>, AuthError> {
+    let mut users = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid =
+            |reason: String| AuthError::InvalidFile(format!("line {}: {}", number + 1, reason));
+        let mut words = line.split_whitespace();
+        let name = match (words.next(), words.next()) {
+            (Some("user"), Some(name)) => name.to_string(),
+            _ => return Err(invalid("expected user <name> ...".to_string())),
+        };
+        let mut user = FileUser {
+            enabled: false,
+            nopass: false,
+            passwords: vec![],
+            grant: Grant::default(),
+        };
+        let mut categories = Vec::new();
+        for rule in words {
+            if let Some(hex) = rule.strip_prefix('#') {
+                let hash = PasswordHash::from_hex(hex).map_err(|e| invalid(e.to_string()))?;
+                user.passwords.push(hash);
+            } else if let Some(password) = rule.strip_prefix('>') {
+                user.passwords.push(PasswordHash::of(password.as_bytes()));
+            } else if let Some(pattern) = rule.strip_prefix('~') {
+                user.grant
+                    .keys
+                    .get_or_insert_with(Vec::new)
+                    .push(pattern.to_string());
+            } else if let Some(category) = rule.strip_prefix('+') {
+                categories.push(category.to_string());
+            } else {
+                match rule {
+                    "on" => user.enabled = true,
+                    "off" => user.enabled = false,
+                    "nopass" => user.nopass = true,
+                    _ => return Err(invalid(format!("unknown rule '{}'", rule))),
+                }
+            }
+        }
+        if !categories.is_empty() {
+            let classes =
+                parse_categories(&categories, &name).map_err(|e| invalid(e.to_string()))?;
+            user.grant.categories = Some(classes);
+        }
+        users.insert(name, user);
+    }
+    Ok(users)
+}
+
+/// Asks an HTTP endpoint, as in OAuth token introspection: the credentials
+/// are POSTed as JSON, `{"username": ..., "password": ...}`, and a 200
+/// answer of `{"active": true}` accepts them, optionally with `categories`
+/// and `keys` for the user. 401, 403 and `"active": false` reject them;
+/// anything else is a failure. Only plain `http://` URLs are supported.
+pub struct Introspection {
+    /// `host:port`.
+    authority: String,
+    path: String,
+    timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct IntrospectionAnswer {
+    active: bool,
+    categories: Option<Vec<String>>,
+    keys: Option<Vec<String>>,
+}
+
+impl Introspection {
+    pub fn new(url: &str, timeout: Duration) -> Result<Self, AclError> {
+        let rest = url.strip_prefix("http://").ok_or_else(|| {
+            AclError::InvalidBackend(format!("unsupported auth url {}: expected http://...", url))
+        })?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(AclError::InvalidBackend(format!(
+                "no host in auth url {}",
+                url
+            )));
+        }
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(Introspection {
+            authority,
+            path: path.to_string(),
+            timeout,
+        })
+    }
+
+    async fn ask(&self, username: &str, password: &str) -> Result<Option<Grant>, AuthError> {
+        let body = serde_json::json!({ "username": username, "password": password }).to_string();
+        let host = self.authority.trim_end_matches(":80");
+        // HTTP/1.0, so the answer comes whole rather than chunked.
+        let request = format!(
+            "POST {} HTTP/1.0\r\n\
+             Host: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            self.path,
+            host,
+            body.len(),
+            body
+        );
+        let mut stream = TcpStream::connect(&self.authority).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        (&mut stream)
+            .take(MAX_RESPONSE as u64)
+            .read_to_end(&mut response)
+            .await?;
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| AuthError::BadResponse("no end of headers".to_string()))?;
+        let status = head.split(' ').nth(1).unwrap_or_default();
+        match status {
+            "200" => {}
+            "401" | "403" => return Ok(None),
+            _ => return Err(AuthError::BadResponse(format!("status {}", status))),
+        }
+        let answer: IntrospectionAnswer =
+            serde_json::from_str(body).map_err(|e| AuthError::BadResponse(e.to_string()))?;
+        if !answer.active {
+            return Ok(None);
+        }
+        let categories = match answer.categories {
+            Some(names) => Some(
+                parse_categories(&names, username)
+                    .map_err(|e| AuthError::BadResponse(e.to_string()))?,
+            ),
+            None => None,
+        };
+        Ok(Some(Grant {
+            categories,
+            keys: answer.keys,
+        }))
+    }
+}
+
+impl AuthBackend for Introspection {
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> Verdict<'a> {
+        Box::pin(async move {
+            tokio::time::timeout(self.timeout, self.ask(username, password))
+                .await
+                .map_err(|_| AuthError::Timeout(self.timeout))?
+        })
+    }
+}
+
+/// Tokens signed with a shared secret, so an issuer can hand out
+/// credentials without telling the server: `<expiry>.<signature>`, where
+/// the expiry is in Unix seconds and the signature is the hex HMAC-SHA256
+/// of `<username>.<expiry>`.
+pub struct HmacTokens {
+    secret: Secret,
+}
+
+impl HmacTokens {
+    pub fn new(secret: Secret) -> Self {
+        HmacTokens { secret }
+    }
+
+    /// A token for `username` valid until `expiry`.
+    pub fn sign(&self, username: &str, expiry: u64) -> String {
+        let mac = hmac_sha256(
+            self.secret.expose().as_bytes(),
+            format!("{}.{}", username, expiry).as_bytes(),
+        );
+        format!("{}.{}", expiry, super::encode_hex(&mac))
+    }
+
+    fn check(&self, username: &str, token: &str) -> bool {
+        let Some((expiry, _)) = token.split_once('.') else {
+            return false;
+        };
+        let Ok(expiry) = expiry.parse::<u64>() else {
+            return false;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let expected = self.sign(username, expiry);
+        // Compared without short-circuiting, like passwords.
+        expiry > now
+            && expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl AuthBackend for HmacTokens {
+    fn verify<'a>(&'a self, username: &'a str, password: &'a str) -> Verdict<'a> {
+        let verdict = self.check(username, password).then(Grant::default);
+        Box::pin(async move { Ok(verdict) })
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            super::super::encode_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hmac_tokens() {
+        let tokens = HmacTokens::new(Secret::new("shared".to_string()));
+        let later = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let token = tokens.sign("alice", later);
+        assert_eq!(
+            tokens.verify("alice", &token).await.unwrap(),
+            Some(Grant::default())
+        );
+        assert_eq!(tokens.verify("bob", &token).await.unwrap(), None);
+        let expired = tokens.sign("alice", 1);
+        assert_eq!(tokens.verify("alice", &expired).await.unwrap(), None);
+        assert_eq!(tokens.verify("alice", "garbage").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_user_file() {
+        let users = parse_user_file(&format!(
+            "# comment\n\
+             user alice on #{} ~cache:* +@read\n\
+             user bob on >plain\n\
+             user carol off nopass\n",
+            PasswordHash::of(b"pw")
+        ))
+        .unwrap();
+        let alice = &users["alice"];
+        assert!(alice.enabled && alice.passwords[0].verify(b"pw"));
+        assert_eq!(
+            alice.grant,
+            Grant {
+                categories: Some(vec![CommandClass::Read]),
+                keys: Some(vec!["cache:*".to_string()]),
+            }
+        );
+        assert!(users["bob"].passwords[0].verify(b"plain"));
+        assert_eq!(users["bob"].grant, Grant::default());
+        assert!(!users["carol"].enabled);
+
+        assert!(parse_user_file("user dave on sudo").is_err());
+        assert!(parse_user_file("alice on").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_introspection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let n = socket.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                let (status, body) = if request.contains(r#""password":"good""#) {
+                    ("200 OK", r#"{"active":true,"keys":["a:*"]}"#)
+                } else if request.contains(r#""password":"down""#) {
+                    ("503 Service Unavailable", "")
+                } else {
+                    ("200 OK", r#"{"active":false}"#)
+                };
+                let response = format!("HTTP/1.0 {}\r\n\r\n{}", status, body);
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let backend = Introspection::new(
+            &format!("http://{}/introspect", addr),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(
+            backend.verify("alice", "good").await.unwrap(),
+            Some(Grant {
+                categories: None,
+                keys: Some(vec!["a:*".to_string()]),
+            })
+        );
+        assert_eq!(backend.verify("alice", "bad").await.unwrap(), None);
+        assert!(backend.verify("alice", "down").await.is_err());
+        assert!(Introspection::new("https://auth", Duration::from_secs(1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct Counting(Arc<AtomicUsize>);
+        impl AuthBackend for Counting {
+            fn verify<'a>(&'a self, _: &'a str, password: &'a str) -> Verdict<'a> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                let grant = (password == "good").then(Grant::default);
+                Box::pin(async move { Ok(grant) })
+            }
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cached = CachedBackend::new(Box::new(Counting(calls.clone())), Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(cached.verify("alice", "good").await, Some(Grant::default()));
+            assert_eq!(cached.verify("alice", "bad").await, None);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        let uncached = CachedBackend::new(Box::new(Counting(calls.clone())), Duration::ZERO);
+        uncached.verify("alice", "good").await;
+        uncached.verify("alice", "good").await;
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+    }
+}
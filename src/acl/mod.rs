@@ -0,0 +1,449 @@
+//! Users, their permissions, password hashing and secret generation
+mod backend;
+
+pub use backend::{
+    AuthBackend, AuthCache, AuthError, CachedBackend, Grant, HmacTokens, Introspection, UserFile,
+    Verdict,
+};
+
+use crate::commands::CommandClass;
+use crate::config::{Secret, SecurityConfig, UserConfig};
+use crate::glob;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+pub const DEFAULT_USER: &str = "default";
+
+/// Number of random bits `ACL GENPASS` produces when no size is given.
+pub const DEFAULT_GENPASS_BITS: u32 = 256;
+
+#[derive(Error, Debug)]
+pub enum AclError {
+    #[error("invalid password hash: {0}")]
+    InvalidHash(String),
+    #[error("failed to read random bytes: {0}")]
+    Random(String),
+    #[error("unknown command category '{0}' for user {1}")]
+    UnknownCategory(String, String),
+    #[error("user {0} is defined more than once")]
+    DuplicateUser(String),
+    #[error("the default user is defined in users, so requirepass can't be set")]
+    DefaultUserConflict,
+    #[error("{0}")]
+    InvalidBackend(String),
+}
+
+/// SHA-256 digest of a password. Plaintext passwords are never retained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PasswordHash([u8; 32]);
+
+impl PasswordHash {
+    pub fn of(password: &[u8]) -> Self {
+        PasswordHash(Sha256::digest(password).into())
+    }
+
+    /// Parses a 64 character hex digest as produced by `Display`.
+    pub fn from_hex(hex: &str) -> Result<Self, AclError> {
+        let bytes = decode_hex(hex).ok_or_else(|| AclError::InvalidHash(hex.to_string()))?;
+        let digest: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| AclError::InvalidHash(hex.to_string()))?;
+        Ok(PasswordHash(digest))
+    }
+
+    /// Compares against the hash of `password` without short-circuiting.
+    pub fn verify(&self, password: &[u8]) -> bool {
+        let candidate = PasswordHash::of(password);
+        self.0
+            .iter()
+            .zip(candidate.0.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&encode_hex(&self.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    pub passwords: Vec<PasswordHash>,
+    pub nopass: bool,
+    /// Classes of commands the user may run.
+    pub categories: Vec<CommandClass>,
+    /// Glob patterns of the keys the user may read and write.
+    pub keys: Vec<String>,
+}
+
+impl User {
+    /// A user allowed everything, as the default user is.
+    fn unrestricted(name: &str, passwords: Vec<PasswordHash>) -> Self {
+        User {
+            name: name.to_string(),
+            nopass: passwords.is_empty(),
+            passwords,
+            categories: CommandClass::ALL.to_vec(),
+            keys: vec!["*".to_string()],
+        }
+    }
+
+    fn from_config(config: &UserConfig) -> Result<Self, AclError> {
+        let passwords = config
+            .passwords
+            .iter()
+            .map(hash_secret)
+            .collect::<Result<_, _>>()?;
+        let mut user = User::unrestricted(&config.name, passwords);
+        user.categories = parse_categories(&config.categories, &config.name)?;
+        user.keys = config.keys.clone();
+        Ok(user)
+    }
+
+    /// The user named `name` as a backend vouched for them: with the
+    /// permissions of `self` unless the grant has its own.
+    fn granted(&self, name: &str, grant: Grant) -> Self {
+        User {
+            name: name.to_string(),
+            passwords: vec![],
+            nopass: false,
+            categories: grant.categories.unwrap_or_else(|| self.categories.clone()),
+            keys: grant.keys.unwrap_or_else(|| self.keys.clone()),
+        }
+    }
+
+    pub fn can_run(&self, class: CommandClass) -> bool {
+        self.categories.contains(&class)
+    }
+
+    pub fn can_access(&self, key: &[u8]) -> bool {
+        self.keys.iter().any(|pattern| glob::matches(pattern, key))
+    }
+
+    /// The user in `ACL LIST` form, e.g. `user alice on #<hash> ~cache:*
+    /// +@read`.
+    pub fn describe(&self) -> String {
+        let mut rules = vec![format!("user {} on", self.name)];
+        if self.nopass {
+            rules.push("nopass".to_string());
+        }
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        rules.extend(self.keys.iter().map(|pattern| format!("~{}", pattern)));
+        if CommandClass::ALL.iter().all(|class| self.can_run(*class)) {
+            rules.push("+@all".to_string());
+        } else {
+            rules.extend(
+                self.categories
+                    .iter()
+                    .map(|class| format!("+@{}", class.category())),
+            );
+        }
+        rules.join(" ")
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        // Every hash is checked so timing doesn't reveal which one matched.
+        self.nopass
+            || self
+                .passwords
+                .iter()
+                .fold(false, |ok, hash| hash.verify(password.as_bytes()) | ok)
+    }
+}
+
+/// Parses category names as the config gives them, `@` optional, with
+/// `all` for every class.
+fn parse_categories(names: &[String], user: &str) -> Result<Vec<CommandClass>, AclError> {
+    let mut categories = Vec::new();
+    for name in names {
+        let name = name.strip_prefix('@').unwrap_or(name);
+        if name == "all" {
+            categories = CommandClass::ALL.to_vec();
+            continue;
+        }
+        let class = CommandClass::ALL
+            .into_iter()
+            .find(|class| class.category() == name)
+            .ok_or_else(|| AclError::UnknownCategory(name.to_string(), user.to_string()))?;
+        if !categories.contains(&class) {
+            categories.push(class);
+        }
+    }
+    Ok(categories)
+}
+
+pub struct Acl {
+    users: HashMap<String, Arc<User>>,
+    /// Checks the passwords no configured user matches, if set.
+    backend: Option<CachedBackend>,
+    /// Permissions of users the backend vouches for that aren't configured.
+    external: User,
+}
+
+impl Acl {
+    pub fn from_config(config: &SecurityConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let requirepass = config.resolve_requirepass()?;
+        let mut users = HashMap::new();
+        for user in &config.users {
+            if user.name == DEFAULT_USER && requirepass.is_some() {
+                return Err(AclError::DefaultUserConflict.into());
+            }
+            let user = User::from_config(user)?;
+            if users.contains_key(&user.name) {
+                return Err(AclError::DuplicateUser(user.name).into());
+            }
+            users.insert(user.name.clone(), Arc::new(user));
+        }
+        if !users.contains_key(DEFAULT_USER) {
+            let passwords = match requirepass {
+                Some(secret) => vec![hash_secret(&secret)?],
+                None => vec![],
+            };
+            let default = User::unrestricted(DEFAULT_USER, passwords);
+            users.insert(default.name.clone(), Arc::new(default));
+        }
+        let auth = &config.auth_backend;
+        let backend = backend::from_config(auth)?
+            .map(|backend| CachedBackend::new(backend, Duration::from_millis(auth.cache_ttl_ms)));
+        let mut external = User::unrestricted("", vec![]);
+        external.categories = parse_categories(&auth.categories, "of the auth backend")?;
+        external.keys = auth.keys.clone();
+        Ok(Acl {
+            users,
+            backend,
+            external,
+        })
+    }
+
+    /// Whether passwords are checked by a backend besides the config.
+    pub fn has_backend(&self) -> bool {
+        self.backend.is_some()
+    }
+
+    /// Whether connections start out unauthenticated.
+    pub fn requires_auth(&self) -> bool {
+        self.users
+            .get(DEFAULT_USER)
+            .map(|user| !user.nopass)
+            .unwrap_or(true)
+    }
+
+    /// The user new connections start as, unless they must authenticate.
+    pub fn initial_user(&self) -> Option<Arc<User>> {
+        self.users
+            .get(DEFAULT_USER)
+            .filter(|user| user.nopass)
+            .cloned()
+    }
+
+    /// The configured user named `name`.
+    pub fn user(&self, name: &str) -> Option<Arc<User>> {
+        self.users.get(name).cloned()
+    }
+
+    /// The user `username` if `password` is theirs: in the config, or else
+    /// as the backend says.
+    pub async fn authenticate(&self, username: &str, password: &str) -> Option<Arc<User>> {
+        let configured = self.users.get(username);
+        if let Some(user) = configured.filter(|user| user.check_password(password)) {
+            return Some(user.clone());
+        }
+        let grant = self.backend.as_ref()?.verify(username, password).await?;
+        let base = configured.map_or(&self.external, |user| user.as_ref());
+        Some(Arc::new(base.granted(username, grant)))
+    }
+
+    /// Every user in `ACL LIST` form, sorted by name.
+    pub fn list(&self) -> Vec<String> {
+        let mut users: Vec<_> = self.users.values().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users.into_iter().map(|user| user.describe()).collect()
+    }
+}
+
+/// Hashes a configured secret; values of the form `#<hex>` are already hashed.
+fn hash_secret(secret: &Secret) -> Result<PasswordHash, AclError> {
+    match secret.expose().strip_prefix('#') {
+        Some(hex) => PasswordHash::from_hex(hex),
+        None => Ok(PasswordHash::of(secret.expose().as_bytes())),
+    }
+}
+
+/// Generates a random password as a hex string carrying `bits` bits of entropy
+/// (rounded up to a whole hex digit), like `ACL GENPASS`.
+pub fn genpass(bits: u32) -> Result<String, AclError> {
+    let digits = bits.div_ceil(4) as usize;
+    let mut bytes = Zeroizing::new(vec![0u8; digits.div_ceil(2)]);
+    getrandom::getrandom(&mut bytes).map_err(|e| AclError::Random(e.to_string()))?;
+    let mut hex = encode_hex(&bytes);
+    hex.truncate(digits);
+    Ok(hex)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AuthBackendConfig, AuthBackendKind};
+
+    #[test]
+    fn test_password_hash() {
+        let hash = PasswordHash::of(b"foobar");
+        assert_eq!(
+            hash.to_string(),
+            "c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2"
+        );
+        assert!(hash.verify(b"foobar"));
+        assert!(!hash.verify(b"foobaz"));
+        assert_eq!(PasswordHash::from_hex(&hash.to_string()).unwrap(), hash);
+        assert!(PasswordHash::from_hex("abcd").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_requirepass() {
+        let config = SecurityConfig {
+            requirepass: Some(Secret::new("secret".to_string())),
+            ..Default::default()
+        };
+        let acl = Acl::from_config(&config).unwrap();
+        assert!(acl.requires_auth());
+        assert!(acl.authenticate(DEFAULT_USER, "secret").await.is_some());
+        assert!(acl.authenticate(DEFAULT_USER, "wrong").await.is_none());
+        assert!(acl.authenticate("nobody", "secret").await.is_none());
+        assert!(acl.initial_user().is_none());
+
+        let hashed = SecurityConfig {
+            requirepass: Some(Secret::new(format!("#{}", PasswordHash::of(b"secret")))),
+            ..Default::default()
+        };
+        let acl = Acl::from_config(&hashed).unwrap();
+        assert!(acl.authenticate(DEFAULT_USER, "secret").await.is_some());
+
+        let acl = Acl::from_config(&SecurityConfig::default()).unwrap();
+        assert!(!acl.requires_auth());
+    }
+
+    #[tokio::test]
+    async fn test_configured_users() {
+        let user =
+            |name: &str, passwords: &[&str], categories: &[&str], keys: &[&str]| UserConfig {
+                name: name.to_string(),
+                passwords: passwords
+                    .iter()
+                    .map(|p| Secret::new(p.to_string()))
+                    .collect(),
+                categories: categories.iter().map(|c| c.to_string()).collect(),
+                keys: keys.iter().map(|k| k.to_string()).collect(),
+            };
+        let config = SecurityConfig {
+            users: vec![
+                user("alice", &["pw"], &["@read", "connection"], &["cache:*"]),
+                user("default", &["root"], &["@all"], &["*"]),
+            ],
+            ..Default::default()
+        };
+        let acl = Acl::from_config(&config).unwrap();
+        assert!(acl.requires_auth());
+        let alice = acl.authenticate("alice", "pw").await.unwrap();
+        assert!(alice.can_run(CommandClass::Read));
+        assert!(!alice.can_run(CommandClass::Write));
+        assert!(alice.can_access(b"cache:1"));
+        assert!(!alice.can_access(b"session:1"));
+        assert_eq!(
+            acl.list(),
+            vec![
+                format!(
+                    "user alice on #{} ~cache:* +@read +@connection",
+                    PasswordHash::of(b"pw")
+                ),
+                format!("user default on #{} ~* +@all", PasswordHash::of(b"root")),
+            ]
+        );
+
+        let conflicting = SecurityConfig {
+            requirepass: Some(Secret::new("secret".to_string())),
+            ..config.clone()
+        };
+        assert!(Acl::from_config(&conflicting).is_err());
+        let unknown = SecurityConfig {
+            users: vec![user("bob", &[], &["@dangerous"], &["*"])],
+            ..Default::default()
+        };
+        assert!(Acl::from_config(&unknown).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backend_users() {
+        let config = SecurityConfig {
+            users: vec![UserConfig {
+                name: "alice".to_string(),
+                passwords: vec![Secret::new("pw".to_string())],
+                categories: vec!["@read".to_string()],
+                keys: vec!["*".to_string()],
+            }],
+            auth_backend: AuthBackendConfig {
+                kind: AuthBackendKind::Hmac,
+                secret: Some(Secret::new("shared".to_string())),
+                categories: vec!["@connection".to_string()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let acl = Acl::from_config(&config).unwrap();
+        let tokens = HmacTokens::new(Secret::new("shared".to_string()));
+
+        assert!(acl.authenticate("alice", "pw").await.is_some());
+        // Configured users keep their permissions whichever way they
+        // authenticate; others get the backend's.
+        let alice = acl
+            .authenticate("alice", &tokens.sign("alice", u64::MAX))
+            .await
+            .unwrap();
+        assert_eq!(alice.categories, vec![CommandClass::Read]);
+        let bob = acl
+            .authenticate("bob", &tokens.sign("bob", u64::MAX))
+            .await
+            .unwrap();
+        assert_eq!(bob.categories, vec![CommandClass::Connection]);
+        assert!(acl.authenticate("bob", "pw").await.is_none());
+
+        let missing = SecurityConfig {
+            auth_backend: AuthBackendConfig {
+                kind: AuthBackendKind::File,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(Acl::from_config(&missing).is_err());
+    }
+
+    #[test]
+    fn test_genpass() {
+        let pass = genpass(DEFAULT_GENPASS_BITS).unwrap();
+        assert_eq!(pass.len(), 64);
+        assert!(pass.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(genpass(5).unwrap().len(), 2);
+        assert_ne!(genpass(128).unwrap(), genpass(128).unwrap());
+    }
+}
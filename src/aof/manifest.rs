@@ -0,0 +1,245 @@
+//! The manifest of a multi-part append-only file, as Redis 7 lays it out
+//!
+//! The log lives in `appenddirname` as a base file, the dataset as of the
+//! last rewrite, and the incremental files holding the writes since, in
+//! order. The manifest lists them, one line each:
+//!
+//! ```text
+//! file appendonly.aof.2.base.aof seq 2 type b
+//! file appendonly.aof.3.incr.aof seq 3 type i
+//! ```
+//!
+//! It's only ever replaced whole, by renaming a new one over it, so a crash
+//! leaves either the old list or the new one, never a mix.
+use super::AofError;
+use log::info;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Base,
+    Incr,
+}
+
+impl FileKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FileKind::Base => "b",
+            FileKind::Incr => "i",
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            FileKind::Base => "base",
+            FileKind::Incr => "incr",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+    pub kind: FileKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    dir: PathBuf,
+    /// `appendfilename`, which every file's name starts with.
+    prefix: String,
+    pub base: Option<AofFile>,
+    /// Oldest first; the last is the one being appended to.
+    pub incrs: Vec<AofFile>,
+}
+
+impl Manifest {
+    /// Reads the manifest for `appendfilename` in `dir`, creating the
+    /// directory and a manifest with one empty incremental file if there's
+    /// none yet. A single-file log from before, at `appendfilename` itself,
+    /// is moved in as the base.
+    pub fn load(dir: &Path, appendfilename: &Path) -> Result<Self, AofError> {
+        let prefix = appendfilename
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                AofError::Manifest(format!("bad appendfilename {:?}", appendfilename))
+            })?;
+        let mut manifest = Manifest {
+            dir: dir.to_path_buf(),
+            prefix,
+            base: None,
+            incrs: Vec::new(),
+        };
+        match std::fs::read_to_string(manifest.path()) {
+            Ok(text) => {
+                manifest.parse(&text)?;
+                return Ok(manifest);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        std::fs::create_dir_all(dir)?;
+        if appendfilename.is_file() {
+            let base = manifest.file(1, FileKind::Base);
+            std::fs::rename(appendfilename, manifest.path_of(&base))?;
+            info!(
+                "Moved the append only file {} into {} as its base",
+                appendfilename.display(),
+                dir.display()
+            );
+            manifest.base = Some(base);
+        }
+        let incr = manifest.file(1, FileKind::Incr);
+        File::create(manifest.path_of(&incr))?;
+        manifest.incrs.push(incr);
+        manifest.persist()?;
+        Ok(manifest)
+    }
+
+    fn parse(&mut self, text: &str) -> Result<(), AofError> {
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = || AofError::Manifest(format!("bad line {}: {}", number + 1, line));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            for pair in fields.chunks(2) {
+                match pair {
+                    ["file", value] => name = Some(value.to_string()),
+                    ["seq", value] => seq = Some(value.parse().map_err(|_| bad())?),
+                    ["type", "b"] => kind = Some(FileKind::Base),
+                    ["type", "i"] => kind = Some(FileKind::Incr),
+                    // Other keys, as later versions may add, are left alone.
+                    [_, _] => {}
+                    _ => return Err(bad()),
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(bad());
+            };
+            if name.contains('/') {
+                return Err(bad());
+            }
+            let file = AofFile { name, seq, kind };
+            match kind {
+                FileKind::Base if self.base.is_some() => return Err(bad()),
+                FileKind::Base => self.base = Some(file),
+                FileKind::Incr => self.incrs.push(file),
+            }
+        }
+        if self.incrs.is_empty() {
+            return Err(AofError::Manifest("no incremental file".to_string()));
+        }
+        Ok(())
+    }
+
+    fn to_text(&self) -> String {
+        self.files()
+            .map(|file| {
+                format!(
+                    "file {} seq {} type {}\n",
+                    file.name,
+                    file.seq,
+                    file.kind.as_str()
+                )
+            })
+            .collect()
+    }
+
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.prefix))
+    }
+
+    pub fn path_of(&self, file: &AofFile) -> PathBuf {
+        self.dir.join(&file.name)
+    }
+
+    /// The base, if any, then the incremental files, in replay order.
+    pub fn files(&self) -> impl Iterator<Item = &AofFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+
+    /// A file named as Redis names them.
+    pub fn file(&self, seq: u64, kind: FileKind) -> AofFile {
+        AofFile {
+            name: format!("{}.{}.{}.aof", self.prefix, seq, kind.suffix()),
+            seq,
+            kind,
+        }
+    }
+
+    /// The file to start appending to next.
+    pub fn next_incr(&self) -> AofFile {
+        let seq = self.incrs.last().map_or(1, |incr| incr.seq + 1);
+        self.file(seq, FileKind::Incr)
+    }
+
+    /// The file to write the next rewrite's base to.
+    pub fn next_base(&self) -> AofFile {
+        let seq = self.base.as_ref().map_or(1, |base| base.seq + 1);
+        self.file(seq, FileKind::Base)
+    }
+
+    /// Durably replaces the manifest on disk with this one.
+    pub fn persist(&self) -> std::io::Result<()> {
+        let path = self.path();
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(self.to_text().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)?;
+        // The rename itself is only durable once the directory is synced.
+        OpenOptions::new().read(true).open(&self.dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrates_a_single_file_log() {
+        let root = std::env::temp_dir().join(format!("rdb-manifest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let legacy = root.join("appendonly.aof");
+        std::fs::write(&legacy, b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let dir = root.join("appendonlydir");
+
+        let manifest = Manifest::load(&dir, &legacy).unwrap();
+        assert!(!legacy.exists());
+        let names: Vec<&str> = manifest.files().map(|file| file.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["appendonly.aof.1.base.aof", "appendonly.aof.1.incr.aof"]
+        );
+        assert_eq!(
+            std::fs::read(dir.join("appendonly.aof.1.base.aof")).unwrap(),
+            b"*1\r\n$4\r\nPING\r\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("appendonly.aof.manifest")).unwrap(),
+            "file appendonly.aof.1.base.aof seq 1 type b\n\
+             file appendonly.aof.1.incr.aof seq 1 type i\n"
+        );
+        // Loaded back as written.
+        assert_eq!(Manifest::load(&dir, &legacy).unwrap(), manifest);
+        assert_eq!(manifest.next_base().name, "appendonly.aof.2.base.aof");
+        assert_eq!(manifest.next_incr().name, "appendonly.aof.2.incr.aof");
+
+        std::fs::write(dir.join("appendonly.aof.manifest"), "file x seq 1\n").unwrap();
+        assert!(matches!(
+            Manifest::load(&dir, &legacy),
+            Err(AofError::Manifest(_))
+        ));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,566 @@
+//! Append-only file persistence
+//!
+//! The log is split in parts as in Redis 7 (see [`Manifest`]): a base file
+//! and incremental ones. A rewrite starts a new incremental file for the
+//! writes from then on and writes a new base from a snapshot meanwhile;
+//! once it's down, the manifest is switched over to them and the old files
+//! deleted. Nothing is buffered or copied at the switch, and until it the
+//! old files stay listed, so a crash mid-rewrite loses nothing.
+mod manifest;
+
+pub use manifest::{AofFile, FileKind, Manifest};
+
+use crate::commands::{execute_locked, format_score, select_frame, Command};
+use crate::config::AppendFsync;
+use crate::connection::{ClientRegistry, ConnCtx};
+use crate::protocol::{parse_resp, RespError, RespValue};
+use crate::storage::{Deadline, ShardLocks, Snapshot, Value};
+use bytes::Bytes;
+use log::{error, info, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Most members a rewritten `SADD`/`ZADD` carries, so huge collections don't
+/// turn into a single enormous command.
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum AofError {
+    #[error("ERR Background append only file rewriting already in progress")]
+    RewriteInProgress,
+    #[error("ERR append only file is disabled")]
+    Disabled,
+    #[error("corrupt append only file {} at byte {1}", .0.display())]
+    Corrupt(PathBuf, usize),
+    #[error("invalid append only file manifest: {0}")]
+    Manifest(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// The append-only log. Writers append under the storage lock, so the log
+/// holds commands in the order they ran.
+pub struct Aof {
+    fsync: AppendFsync,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The last incremental file in the manifest.
+    file: File,
+    manifest: Manifest,
+    /// Set while a rewrite is running.
+    rewriting: bool,
+    /// Database the logged commands run in from here on; `None` when the
+    /// next append must select one to be sure.
+    db: Option<usize>,
+}
+
+impl Aof {
+    /// Opens the last incremental file of `manifest` for appending. With
+    /// `everysec` this also starts the background fsync, so it must run
+    /// inside a Tokio runtime.
+    pub fn open(manifest: Manifest, fsync: AppendFsync) -> std::io::Result<Arc<Self>> {
+        let incr = manifest
+            .incrs
+            .last()
+            .expect("a manifest lists an incr file");
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(manifest.path_of(incr))?;
+        let aof = Arc::new(Aof {
+            fsync,
+            state: Mutex::new(State {
+                file,
+                manifest,
+                rewriting: false,
+                db: None,
+            }),
+        });
+        if fsync == AppendFsync::Everysec {
+            let aof = aof.clone();
+            tokio::spawn(async move {
+                let mut tick = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    tick.tick().await;
+                    let aof = aof.clone();
+                    if let Ok(Err(e)) = tokio::task::spawn_blocking(move || aof.sync()).await {
+                        error!("Failed to fsync the append only file: {}", e);
+                    }
+                }
+            });
+        }
+        Ok(aof)
+    }
+
+    /// Logs `frame`, run in database `db`.
+    pub fn append(&self, db: usize, frame: &RespValue) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut bytes = match state.db {
+            Some(current) if current == db => Vec::new(),
+            _ => select_frame(db).serialize(),
+        };
+        bytes.extend_from_slice(&frame.serialize());
+        state.file.write_all(&bytes)?;
+        state.db = Some(db);
+        if self.fsync == AppendFsync::Always {
+            state.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log to disk without blocking appends meanwhile.
+    pub fn sync(&self) -> std::io::Result<()> {
+        let file = self.state.lock().unwrap().file.try_clone()?;
+        file.sync_data()
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.state.lock().unwrap().rewriting
+    }
+
+    /// The files making up the log now.
+    pub fn manifest(&self) -> Manifest {
+        self.state.lock().unwrap().manifest.clone()
+    }
+
+    /// Rewrites the log from the snapshot `snapshot` takes, on a blocking
+    /// thread once it's copied. Appends from the moment it's taken go to a
+    /// new incremental file, which follows the new base.
+    pub fn start_rewrite(
+        self: &Arc<Self>,
+        snapshot: impl FnOnce() -> Snapshot,
+    ) -> Result<(), AofError> {
+        let (previous, incr) = {
+            let mut state = self.state.lock().unwrap();
+            if state.rewriting {
+                return Err(AofError::RewriteInProgress);
+            }
+            let incr = state.manifest.next_incr();
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(state.manifest.path_of(&incr))?;
+            let mut manifest = state.manifest.clone();
+            manifest.incrs.push(incr.clone());
+            manifest.persist()?;
+            state.manifest = manifest;
+            let previous = std::mem::replace(&mut state.file, file);
+            state.rewriting = true;
+            // The new file must select a database before its first write.
+            state.db = None;
+            (previous, incr)
+        };
+
+        let snapshot = snapshot();
+        let aof = self.clone();
+        tokio::spawn(async move {
+            let scripts = snapshot.scripts();
+            let snapshot = snapshot.collect().await;
+            let keys = snapshot.iter().map(Vec::len).sum::<usize>();
+            let rewriting = aof.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                // Everything before the switch is in the old files, which
+                // stay in the manifest until the rewrite is done.
+                previous.sync_data()?;
+                rewriting.rewrite(&scripts, &snapshot, &incr)
+            })
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            match result {
+                Ok(()) => info!("Append only file rewritten with {} keys", keys),
+                Err(e) => {
+                    error!("Append only file rewrite failed: {}", e);
+                    aof.state.lock().unwrap().rewriting = false;
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Writes `scripts` and `snapshot` as the new base and drops the files
+    /// before `incr`, the first written after the snapshot was taken.
+    fn rewrite(
+        &self,
+        scripts: &[Bytes],
+        snapshot: &[Vec<(Bytes, Value)>],
+        incr: &AofFile,
+    ) -> std::io::Result<()> {
+        let (base, base_path, temp_path) = {
+            let state = self.state.lock().unwrap();
+            let base = state.manifest.next_base();
+            let path = state.manifest.path_of(&base);
+            let mut temp = path.clone().into_os_string();
+            temp.push(".tmp");
+            (base, path, PathBuf::from(temp))
+        };
+
+        let mut out = BufWriter::new(File::create(&temp_path)?);
+        for script in scripts {
+            let args = [Bytes::from("SCRIPT"), Bytes::from("LOAD"), script.clone()];
+            let frame = RespValue::Array(args.into_iter().map(RespValue::bulk).collect());
+            out.write_all(&frame.serialize())?;
+        }
+        for (db, entries) in snapshot.iter().enumerate() {
+            if !entries.is_empty() {
+                out.write_all(&select_frame(db).serialize())?;
+            }
+            for (key, value) in entries {
+                for frame in rewrite_commands(key, value) {
+                    out.write_all(&frame.serialize())?;
+                }
+            }
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&temp_path, &base_path)?;
+
+        let mut state = self.state.lock().unwrap();
+        let mut manifest = state.manifest.clone();
+        let old: Vec<AofFile> = manifest
+            .files()
+            .filter(|file| file.kind == FileKind::Base || file.seq < incr.seq)
+            .cloned()
+            .collect();
+        manifest.base = Some(base);
+        manifest.incrs.retain(|file| file.seq >= incr.seq);
+        manifest.persist()?;
+        for file in &old {
+            if let Err(e) = std::fs::remove_file(state.manifest.path_of(file)) {
+                warn!(
+                    "Failed to delete the old append only file {}: {}",
+                    file.name, e
+                );
+            }
+        }
+        state.manifest = manifest;
+        state.rewriting = false;
+        Ok(())
+    }
+}
+
+/// Commands that recreate `key` with `value`.
+fn rewrite_commands(key: &Bytes, value: &Value) -> Vec<RespValue> {
+    let command =
+        |args: Vec<Bytes>| RespValue::Array(args.into_iter().map(RespValue::bulk).collect());
+    match value {
+        Value::String(value) => vec![command(vec!["SET".into(), key.clone(), value.clone()])],
+        Value::Set(set) => {
+            let members: Vec<&Bytes> = set.iter().collect();
+            members
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut args = vec!["SADD".into(), key.clone()];
+                    args.extend(chunk.iter().map(|m| (*m).clone()));
+                    command(args)
+                })
+                .collect()
+        }
+        Value::SortedSet(zset) => {
+            let members: Vec<(&Bytes, f64)> = zset.iter().collect();
+            members
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut args = vec!["ZADD".into(), key.clone()];
+                    for (member, score) in chunk {
+                        args.push(format_score(*score).into());
+                        args.push((*member).clone());
+                    }
+                    command(args)
+                })
+                .collect()
+        }
+        Value::List(list) => {
+            let elements: Vec<&Bytes> = list.iter().collect();
+            elements
+                .chunks(REWRITE_ITEMS_PER_COMMAND)
+                .map(|chunk| {
+                    let mut args = vec!["RPUSH".into(), key.clone()];
+                    args.extend(chunk.iter().map(|e| (*e).clone()));
+                    command(args)
+                })
+                .collect()
+        }
+    }
+}
+
+/// Replays every file of `manifest` into `store`, in order, returning how
+/// many commands ran. A command cut off at the end of the last file, as a
+/// crash mid-write leaves it, is dropped and the file truncated after the
+/// last complete one; anywhere else it's corruption.
+pub fn replay(manifest: &Manifest, store: &mut ShardLocks) -> Result<usize, AofError> {
+    let clients = ClientRegistry::new();
+    let mut applied = 0;
+    let last = manifest.incrs.last().map(|incr| incr.seq);
+    for file in manifest.files() {
+        let path = manifest.path_of(file);
+        let data = std::fs::read(&path)?;
+        let mut pos = 0;
+        while pos < data.len() {
+            match parse_resp(&data[pos..]) {
+                Ok((frame, len)) => {
+                    let command = Command::from_frame(frame)
+                        .map_err(|_| AofError::Corrupt(path.clone(), pos))?;
+                    execute_locked(
+                        command,
+                        store,
+                        &ConnCtx::default(),
+                        &clients,
+                        Deadline::after(None),
+                    );
+                    pos += len;
+                    applied += 1;
+                }
+                Err(RespError::Incomplete)
+                    if file.kind == FileKind::Incr && Some(file.seq) == last =>
+                {
+                    warn!(
+                        "Truncating an incomplete command at the end of {}",
+                        path.display()
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(pos as u64)?;
+                    break;
+                }
+                Err(_) => return Err(AofError::Corrupt(path, pos)),
+            }
+        }
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+    use std::path::Path;
+
+    /// A fresh log in a directory of its own.
+    fn temp_manifest(name: &str) -> Manifest {
+        let dir = std::env::temp_dir().join(format!("rdb-{}-{}-aof", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        Manifest::load(&dir, Path::new("appendonly.aof")).unwrap()
+    }
+
+    fn remove(manifest: &Manifest) {
+        std::fs::remove_dir_all(manifest.path().parent().unwrap()).unwrap();
+    }
+
+    fn shards() -> Shards {
+        Shards::new(StorageConfig {
+            max_memory: 1024 * 1024,
+            ..Default::default()
+        })
+    }
+
+    fn run(store: &mut ShardLocks, args: &[&'static str]) -> RespValue {
+        let frame = RespValue::Array(args.iter().map(|a| RespValue::bulk(*a)).collect());
+        let command = Command::from_frame(frame).unwrap();
+        execute_locked(
+            command,
+            store,
+            &ConnCtx::default(),
+            &ClientRegistry::new(),
+            Deadline::after(None),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_append_and_replay() {
+        let manifest = temp_manifest("replay");
+        let mut db = shards();
+        db.attach_aof(Aof::open(manifest.clone(), AppendFsync::Always).unwrap());
+        let mut store = db.lock_all().await;
+        run(&mut store, &["SET", "k", "v1"]);
+        run(&mut store, &["SADD", "s", "a", "b"]);
+        run(&mut store, &["SREM", "s", "missing"]);
+        run(&mut store, &["GET", "k"]);
+        run(&mut store, &["ZADD", "z", "1.5", "m"]);
+        run(&mut store, &["SET", "k", "v2"]);
+        run(&mut store, &["SELECT", "2"]);
+        run(&mut store, &["SET", "k", "two"]);
+
+        let db = shards();
+        let mut replayed = db.lock_all().await;
+        // Reads and writes that changed nothing aren't logged; a SELECT is
+        // logged ahead of the first write and of each switch.
+        assert_eq!(replay(&manifest, &mut replayed).unwrap(), 7);
+        assert_eq!(
+            replayed.shard(b"k").get(b"k").unwrap(),
+            Some(&Bytes::from("two"))
+        );
+        replayed.select(0).unwrap();
+        assert_eq!(
+            replayed.shard(b"k").get(b"k").unwrap(),
+            Some(&Bytes::from("v2"))
+        );
+        assert!(replayed.shard(b"s").sismember(b"s", b"b").unwrap());
+        assert_eq!(replayed.shard(b"z").zscore(b"z", b"m"), Ok(Some(1.5)));
+        assert_eq!(replayed.memory_usage(), store.memory_usage());
+        remove(&manifest);
+    }
+
+    #[tokio::test]
+    async fn test_spop_replays_the_members_it_picked() {
+        let manifest = temp_manifest("spop");
+        let mut db = shards();
+        db.attach_aof(Aof::open(manifest.clone(), AppendFsync::Always).unwrap());
+        let mut store = db.lock_all().await;
+        let members = "abcdefghijklmnopqrst".split("").filter(|m| !m.is_empty());
+        let sadd: Vec<&'static str> = ["SADD", "s"].into_iter().chain(members).collect();
+        run(&mut store, &sadd);
+        run(&mut store, &["SPOP", "s", "5"]);
+        run(&mut store, &["SPOP", "s"]);
+        run(&mut store, &["SPOP", "missing"]);
+        run(&mut store, &["SRANDMEMBER", "s", "3"]);
+
+        let db = shards();
+        let mut replayed = db.lock_all().await;
+        // The SELECT, the SADD and an SREM per SPOP that popped something.
+        assert_eq!(replay(&manifest, &mut replayed).unwrap(), 4);
+        let sorted = |store: &ShardLocks| {
+            let mut members: Vec<Bytes> = store
+                .shard(b"s")
+                .smembers(b"s", &Deadline::after(None))
+                .unwrap()
+                .into_iter()
+                .cloned()
+                .collect();
+            members.sort();
+            members
+        };
+        assert_eq!(sorted(&store).len(), 14);
+        assert_eq!(sorted(&replayed), sorted(&store));
+        remove(&manifest);
+    }
+
+    #[tokio::test]
+    async fn test_replay_truncates_partial_command() {
+        let mut manifest = temp_manifest("truncated");
+        let complete = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        let mut data = complete.to_vec();
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nx");
+        let incr = manifest.path_of(&manifest.incrs[0]);
+        std::fs::write(&incr, &data).unwrap();
+
+        let db = shards();
+        let mut store = db.lock_all().await;
+        assert_eq!(replay(&manifest, &mut store).unwrap(), 1);
+        assert_eq!(std::fs::read(&incr).unwrap(), complete);
+
+        // Only the last file may be cut short.
+        std::fs::write(&incr, &data).unwrap();
+        let next = manifest.next_incr();
+        std::fs::write(manifest.path_of(&next), complete).unwrap();
+        manifest.incrs.push(next);
+        assert!(matches!(
+            replay(&manifest, &mut store),
+            Err(AofError::Corrupt(path, 27)) if path == incr
+        ));
+
+        std::fs::write(&incr, b"garbage").unwrap();
+        assert!(matches!(
+            replay(&manifest, &mut store),
+            Err(AofError::Corrupt(_, 0))
+        ));
+        remove(&manifest);
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_compacts_log() {
+        let manifest = temp_manifest("rewrite");
+        let mut db = shards();
+        let aof = Aof::open(manifest.clone(), AppendFsync::No).unwrap();
+        db.attach_aof(aof.clone());
+        let mut store = db.lock_all().await;
+        for i in 0..100 {
+            run(
+                &mut store,
+                &["SET", "k", if i % 2 == 0 { "even" } else { "odd" }],
+            );
+        }
+        run(&mut store, &["ZADD", "z", "1", "a", "2", "b"]);
+        let size = |manifest: &Manifest| -> u64 {
+            manifest
+                .files()
+                .map(|file| std::fs::metadata(manifest.path_of(file)).unwrap().len())
+                .sum()
+        };
+        let before = size(&manifest);
+
+        store.rewrite_aof().unwrap();
+        assert!(matches!(
+            store.rewrite_aof(),
+            Err(AofError::RewriteInProgress)
+        ));
+        // Written while the rewrite runs, so it must survive the switch.
+        run(&mut store, &["SADD", "s", "late"]);
+        drop(store);
+        while aof.is_rewriting() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        // A new base and the file written to since, and the old ones gone.
+        let rewritten = Manifest::load(
+            manifest.path().parent().unwrap(),
+            Path::new("appendonly.aof"),
+        )
+        .unwrap();
+        assert_eq!(rewritten, aof.manifest());
+        let names: Vec<&str> = rewritten.files().map(|file| file.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["appendonly.aof.1.base.aof", "appendonly.aof.2.incr.aof"]
+        );
+        assert!(!manifest.path_of(&manifest.incrs[0]).exists());
+        assert!(size(&rewritten) < before);
+
+        let db = shards();
+        let mut replayed = db.lock_all().await;
+        replay(&rewritten, &mut replayed).unwrap();
+        assert_eq!(
+            replayed.shard(b"k").get(b"k").unwrap(),
+            Some(&Bytes::from("odd"))
+        );
+        assert_eq!(replayed.shard(b"z").zscore(b"z", b"b"), Ok(Some(2.0)));
+        assert!(replayed.shard(b"s").sismember(b"s", b"late").unwrap());
+        remove(&manifest);
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn test_scripts_are_logged() {
+        let manifest = temp_manifest("scripts");
+        let mut db = shards();
+        let aof = Aof::open(manifest.clone(), AppendFsync::No).unwrap();
+        db.attach_aof(aof.clone());
+        let mut store = db.lock_all().await;
+        run(&mut store, &["SCRIPT", "LOAD", "return 1"]);
+        run(&mut store, &["SCRIPT", "FLUSH"]);
+        run(&mut store, &["SCRIPT", "LOAD", "return 2"]);
+        run(&mut store, &["EVAL", "return 3", "0"]);
+        let replayed = |manifest: Manifest| async move {
+            let db = shards();
+            let mut store = db.lock_all().await;
+            replay(&manifest, &mut store).unwrap();
+            let mut sources = store.scripts().sources();
+            sources.sort();
+            sources
+        };
+        assert_eq!(replayed(aof.manifest()).await, ["return 2", "return 3"]);
+
+        store.rewrite_aof().unwrap();
+        drop(store);
+        while aof.is_rewriting() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(replayed(aof.manifest()).await, ["return 2", "return 3"]);
+        remove(&manifest);
+    }
+}
@@ -0,0 +1,683 @@
+//! A command-line client, in the manner of `redis-cli`.
+//!
+//! Runs the command given on the command line, or one per line of standard
+//! input. With `-c` it follows cluster redirects: `MOVED` replies update a
+//! map of which node serves each hash slot, so later commands go straight
+//! to the right node, and `ASK` replies are retried once on the node named.
+//!
+//! The `--stdin-*` options bulk load standard input instead, a value or a
+//! tab-separated pair per line, batching lines into commands and pipelining
+//! the commands.
+use bytes::Bytes;
+use rdb::protocol::{parse_resp, quote, RespError, RespValue};
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: rdb-cli [-h host] [-p port] [-a password] [-c] [command [arg ...]]
+       rdb-cli [-h host] [-p port] [-a password] --stdin-<command> [key]
+       rdb-cli [-a password] --cluster check host:port
+
+Without a command, runs one command per line of standard input.
+  -c                   follow MOVED and ASK redirects from cluster nodes
+  --cluster check      report which node serves each range of hash slots and
+                       whether every slot is covered
+
+Bulk loading, a line of standard input at a time, pipelined:
+  --stdin-lpush key    LPUSH each line onto the list at key
+  --stdin-rpush key    RPUSH each line onto the list at key
+  --stdin-sadd key     SADD each line to the set at key
+  --stdin-zadd key     ZADD each `score<TAB>member` line to the sorted set
+  --stdin-mset         SET each `key<TAB>value` line
+There are no hashes, so no --stdin-hset. Bulk loads go to the one node
+given and don't follow redirects.";
+
+/// Hash slots in a cluster, as in Redis Cluster.
+const SLOTS: usize = 16384;
+
+/// Redirects followed for one command before giving up.
+const MAX_REDIRECTS: usize = 16;
+
+/// Lines of a bulk load sent per command.
+const BULK_BATCH: usize = 1000;
+
+/// Bulk load commands written before reading their replies.
+const BULK_PIPELINE: usize = 16;
+
+/// CRC16/XMODEM, the checksum Redis Cluster hashes keys with.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// The slot of `key`. Only the part between the first `{` and the next `}`
+/// is hashed when it isn't empty, so related keys can share a slot.
+fn key_slot(key: &[u8]) -> usize {
+    let tagged = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        let close = rest.iter().position(|&b| b == b'}')?;
+        (close > 0).then(|| &rest[..close])
+    });
+    crc16(tagged.unwrap_or(key)) as usize % SLOTS
+}
+
+/// A minimal blocking RESP client.
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    fn connect(addr: &str, password: Option<&str>) -> Result<Self, String> {
+        let stream = TcpStream::connect(addr).map_err(|e| format!("{}: {}", addr, e))?;
+        let mut client = Client {
+            stream,
+            buffer: Vec::new(),
+        };
+        if let Some(password) = password {
+            if let RespValue::Error(e) = client.call(&[b"AUTH", password.as_bytes()])? {
+                return Err(e);
+            }
+        }
+        Ok(client)
+    }
+
+    fn call(&mut self, args: &[&[u8]]) -> Result<RespValue, String> {
+        self.stream
+            .write_all(&frame(args).serialize())
+            .map_err(|e| e.to_string())?;
+        self.reply()
+    }
+
+    /// Sends `commands` in one write, then reads a reply to each.
+    fn pipeline(&mut self, commands: &[Vec<Vec<u8>>]) -> Result<Vec<RespValue>, String> {
+        let mut request = Vec::new();
+        for args in commands {
+            let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+            request.extend_from_slice(&frame(&args).serialize());
+        }
+        self.stream.write_all(&request).map_err(|e| e.to_string())?;
+        commands.iter().map(|_| self.reply()).collect()
+    }
+
+    fn reply(&mut self) -> Result<RespValue, String> {
+        loop {
+            match parse_resp(&self.buffer) {
+                Ok((reply, len)) => {
+                    self.buffer.drain(..len);
+                    return Ok(reply);
+                }
+                Err(RespError::Incomplete) => {
+                    let mut chunk = [0; 4096];
+                    let n = self.stream.read(&mut chunk).map_err(|e| e.to_string())?;
+                    if n == 0 {
+                        return Err("connection closed by server".to_string());
+                    }
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(_) => return Err("invalid reply".to_string()),
+            }
+        }
+    }
+}
+
+fn frame(args: &[&[u8]]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|a| RespValue::bulk(Bytes::copy_from_slice(a)))
+            .collect(),
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum Redirect {
+    Moved(usize, String),
+    Ask(usize, String),
+}
+
+/// The redirect an error reply asks for, if it is one.
+fn redirect(reply: &RespValue) -> Option<Redirect> {
+    let RespValue::Error(e) = reply else {
+        return None;
+    };
+    let mut words = e.split(' ');
+    let kind = words.next()?;
+    let slot: usize = words.next()?.parse().ok().filter(|slot| *slot < SLOTS)?;
+    let addr = words.next()?.to_string();
+    match kind {
+        "MOVED" => Some(Redirect::Moved(slot, addr)),
+        "ASK" => Some(Redirect::Ask(slot, addr)),
+        _ => None,
+    }
+}
+
+/// Connections to every node reached so far, and which node serves each
+/// slot as far as the redirects told.
+struct Cluster {
+    password: Option<String>,
+    seed: String,
+    nodes: HashMap<String, Client>,
+    slots: Vec<Option<String>>,
+    follow: bool,
+}
+
+impl Cluster {
+    fn new(seed: String, password: Option<String>, follow: bool) -> Self {
+        Cluster {
+            password,
+            seed,
+            nodes: HashMap::new(),
+            slots: vec![None; SLOTS],
+            follow,
+        }
+    }
+
+    fn node(&mut self, addr: &str) -> Result<&mut Client, String> {
+        if !self.nodes.contains_key(addr) {
+            let client = Client::connect(addr, self.password.as_deref())?;
+            self.nodes.insert(addr.to_string(), client);
+        }
+        Ok(self.nodes.get_mut(addr).expect("just connected"))
+    }
+
+    /// Runs `args` on the node serving its key, taken to be the first
+    /// argument, following redirects if asked to.
+    fn call(&mut self, args: &[&[u8]]) -> Result<RespValue, String> {
+        let slot = args.get(1).map(|key| key_slot(key));
+        let mut addr = slot
+            .and_then(|slot| self.slots[slot].clone())
+            .unwrap_or_else(|| self.seed.clone());
+        let mut asking = false;
+        for _ in 0..MAX_REDIRECTS {
+            let node = self.node(&addr)?;
+            if asking {
+                node.call(&[b"ASKING"])?;
+            }
+            let reply = node.call(args)?;
+            match redirect(&reply) {
+                Some(Redirect::Moved(slot, to)) if self.follow => {
+                    self.slots[slot] = Some(to.clone());
+                    addr = to;
+                    asking = false;
+                }
+                Some(Redirect::Ask(_, to)) if self.follow => {
+                    addr = to;
+                    asking = true;
+                }
+                _ => return Ok(reply),
+            }
+        }
+        Err(format!(
+            "too many redirects for {}",
+            String::from_utf8_lossy(args[0])
+        ))
+    }
+}
+
+/// Formats `reply` the way redis-cli prints it.
+fn format_reply(reply: &RespValue) -> String {
+    let mut out = String::new();
+    write_reply(&mut out, reply, 0);
+    out
+}
+
+fn write_reply(out: &mut String, reply: &RespValue, indent: usize) {
+    let items = |out: &mut String, items: Vec<&RespValue>| {
+        if items.is_empty() {
+            out.push_str("(empty array)\n");
+        }
+        let width = items.len().to_string().len();
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                out.push_str(&" ".repeat(indent));
+            }
+            let label = format!("{:>width$}) ", i + 1, width = width);
+            out.push_str(&label);
+            write_reply(out, item, indent + label.len());
+        }
+    };
+    match reply {
+        RespValue::SimpleString(s) => out.push_str(&format!("{}\n", s)),
+        RespValue::Error(e) => out.push_str(&format!("(error) {}\n", e)),
+        RespValue::Integer(n) => out.push_str(&format!("(integer) {}\n", n)),
+        RespValue::BulkString(Some(s)) => out.push_str(&format!("{}\n", quote(s))),
+        RespValue::BulkString(None) | RespValue::NullArray | RespValue::Null => {
+            out.push_str("(nil)\n")
+        }
+        RespValue::Double(d) => out.push_str(&format!("(double) {}\n", d)),
+        RespValue::BigNumber(n) => out.push_str(&format!("(integer) {}\n", n)),
+        RespValue::Array(list) | RespValue::Set(list) | RespValue::Push(list) => {
+            items(out, list.iter().collect())
+        }
+        RespValue::Map(pairs) => items(out, pairs.iter().flat_map(|(k, v)| [k, v]).collect()),
+    }
+}
+
+/// Splits a line into arguments on whitespace; double quotes group words
+/// and take the escapes `quote` writes.
+fn split_line(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = Vec::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => arg.push(b'\n'),
+                        Some('r') => arg.push(b'\r'),
+                        Some('t') => arg.push(b'\t'),
+                        Some('a') => arg.push(0x07),
+                        Some('b') => arg.push(0x08),
+                        Some('x') => {
+                            let hex: String = chars.by_ref().take(2).collect();
+                            let byte = u8::from_str_radix(&hex, 16)
+                                .map_err(|_| format!("invalid escape \\x{}", hex))?;
+                            arg.push(byte);
+                        }
+                        Some(c) => arg.extend_from_slice(c.to_string().as_bytes()),
+                        None => return Err("unbalanced quotes".to_string()),
+                    },
+                    Some(c) => arg.extend_from_slice(c.to_string().as_bytes()),
+                    None => return Err("unbalanced quotes".to_string()),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.extend_from_slice(c.to_string().as_bytes());
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// `--cluster check`: the slot ranges each node serves, per
+/// `CLUSTER SLOTS`, and any slots nobody serves.
+fn cluster_check(client: &mut Client) -> Result<bool, String> {
+    let reply = client.call(&[b"CLUSTER", b"SLOTS"])?;
+    let ranges = match reply {
+        RespValue::Array(ranges) => ranges,
+        RespValue::Error(e) => return Err(e),
+        other => return Err(format!("unexpected reply to CLUSTER SLOTS: {:?}", other)),
+    };
+    let mut covered = vec![false; SLOTS];
+    for range in &ranges {
+        let RespValue::Array(fields) = range else {
+            return Err("malformed CLUSTER SLOTS reply".to_string());
+        };
+        let (start, end, node) = match fields.as_slice() {
+            [RespValue::Integer(start), RespValue::Integer(end), RespValue::Array(node), ..] => {
+                (*start as usize, *end as usize, node)
+            }
+            _ => return Err("malformed CLUSTER SLOTS reply".to_string()),
+        };
+        let addr = match node.as_slice() {
+            [RespValue::BulkString(Some(host)), RespValue::Integer(port), ..] => {
+                format!("{}:{}", String::from_utf8_lossy(host), port)
+            }
+            _ => return Err("malformed CLUSTER SLOTS reply".to_string()),
+        };
+        println!("{}-{} {} ({} replicas)", start, end, addr, fields.len() - 3);
+        for slot in covered.iter_mut().take(end.min(SLOTS - 1) + 1).skip(start) {
+            *slot = true;
+        }
+    }
+    let missing = covered.iter().filter(|covered| !**covered).count();
+    if missing == 0 {
+        println!("[OK] All {} slots covered.", SLOTS);
+    } else {
+        println!(
+            "[ERR] Not all {} slots are covered: {} missing.",
+            SLOTS, missing
+        );
+    }
+    Ok(missing == 0)
+}
+
+/// What a bulk load makes of each line of standard input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bulk {
+    LPush,
+    RPush,
+    SAdd,
+    /// `score<TAB>member` lines.
+    ZAdd,
+    /// `key<TAB>value` lines.
+    MSet,
+}
+
+impl Bulk {
+    fn from_flag(flag: &str) -> Option<Self> {
+        Some(match flag {
+            "--stdin-lpush" => Bulk::LPush,
+            "--stdin-rpush" => Bulk::RPush,
+            "--stdin-sadd" => Bulk::SAdd,
+            "--stdin-zadd" => Bulk::ZAdd,
+            "--stdin-mset" => Bulk::MSet,
+            _ => return None,
+        })
+    }
+
+    fn command(self) -> &'static [u8] {
+        match self {
+            Bulk::LPush => b"LPUSH",
+            Bulk::RPush => b"RPUSH",
+            Bulk::SAdd => b"SADD",
+            Bulk::ZAdd => b"ZADD",
+            Bulk::MSet => b"MSET",
+        }
+    }
+
+    /// Whether the command names one key ahead of the lines.
+    fn takes_key(self) -> bool {
+        self != Bulk::MSet
+    }
+
+    /// Appends what `line` adds to a command's arguments.
+    fn push_line(self, line: &[u8], args: &mut Vec<Vec<u8>>) -> Result<(), String> {
+        match self {
+            Bulk::LPush | Bulk::RPush | Bulk::SAdd => args.push(line.to_vec()),
+            Bulk::ZAdd | Bulk::MSet => {
+                let tab = line
+                    .iter()
+                    .position(|&b| b == b'\t')
+                    .ok_or_else(|| "expected two tab-separated fields".to_string())?;
+                let (first, second) = (&line[..tab], &line[tab + 1..]);
+                if self == Bulk::ZAdd
+                    && std::str::from_utf8(first)
+                        .ok()
+                        .and_then(|score| score.parse::<f64>().ok())
+                        .is_none()
+                {
+                    return Err(format!("bad score {:?}", String::from_utf8_lossy(first)));
+                }
+                args.push(first.to_vec());
+                args.push(second.to_vec());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loads `input` a line at a time as `bulk` says, `BULK_BATCH` lines to a
+/// command and `BULK_PIPELINE` commands to a write. Error replies and bad
+/// lines are reported and skipped; returns whether there were none.
+fn bulk_load(
+    client: &mut Client,
+    bulk: Bulk,
+    key: Option<&[u8]>,
+    mut input: impl BufRead,
+) -> Result<bool, String> {
+    let start = || {
+        let mut args = vec![bulk.command().to_vec()];
+        args.extend(key.map(<[u8]>::to_vec));
+        args
+    };
+    let fixed = start().len();
+    let (mut lines, mut replies, mut errors) = (0, 0, 0);
+    let mut flush = |client: &mut Client, commands: &mut Vec<Vec<Vec<u8>>>| {
+        replies += commands.len();
+        let failed = client
+            .pipeline(commands)?
+            .into_iter()
+            .filter_map(|reply| match reply {
+                RespValue::Error(e) => Some(e),
+                _ => None,
+            })
+            .inspect(|e| eprintln!("rdb-cli: {}", e))
+            .count();
+        commands.clear();
+        Ok::<_, String>(failed)
+    };
+
+    let mut commands = Vec::with_capacity(BULK_PIPELINE);
+    let mut args = start();
+    let mut batched = 0;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input
+            .read_until(b'\n', &mut line)
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            break;
+        }
+        lines += 1;
+        let trimmed = line.strip_suffix(b"\n").unwrap_or(&line);
+        let trimmed = trimmed.strip_suffix(b"\r").unwrap_or(trimmed);
+        if let Err(e) = bulk.push_line(trimmed, &mut args) {
+            eprintln!("rdb-cli: line {}: {}", lines, e);
+            errors += 1;
+            continue;
+        }
+        batched += 1;
+        if batched == BULK_BATCH {
+            commands.push(std::mem::replace(&mut args, start()));
+            batched = 0;
+            if commands.len() == BULK_PIPELINE {
+                errors += flush(client, &mut commands)?;
+            }
+        }
+    }
+    if args.len() > fixed {
+        commands.push(args);
+    }
+    errors += flush(client, &mut commands)?;
+    println!("lines: {}, replies: {}, errors: {}", lines, replies, errors);
+    Ok(errors == 0)
+}
+
+struct Options {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    follow: bool,
+    /// `--cluster` and its subcommand's arguments.
+    cluster: Option<Vec<String>>,
+    /// A `--stdin-*` bulk load, with its key if it takes one.
+    bulk: Option<(Bulk, Option<String>)>,
+    command: Vec<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    let mut options = Options {
+        host: "127.0.0.1".to_string(),
+        port: 6379,
+        password: None,
+        follow: false,
+        cluster: None,
+        bulk: None,
+        command: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" => options.host = args.next()?,
+            "-p" => options.port = args.next()?.parse().ok()?,
+            "-a" => options.password = Some(args.next()?),
+            "-c" => options.follow = true,
+            "--cluster" => options.cluster = Some(args.by_ref().collect()),
+            "--help" => return None,
+            flag if flag.starts_with("--stdin-") => {
+                let bulk = Bulk::from_flag(flag)?;
+                let key = if bulk.takes_key() {
+                    Some(args.next()?)
+                } else {
+                    None
+                };
+                options.bulk = Some((bulk, key));
+            }
+            _ => {
+                options.command.push(arg);
+                options.command.extend(args.by_ref());
+            }
+        }
+    }
+    Some(options)
+}
+
+/// Runs the command and prints the reply; returns whether it succeeded.
+fn run_command(cluster: &mut Cluster, args: &[Vec<u8>]) -> Result<bool, String> {
+    let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+    let reply = cluster.call(&args)?;
+    print!("{}", format_reply(&reply));
+    Ok(!matches!(reply, RespValue::Error(_)))
+}
+
+fn run(options: Options) -> Result<bool, String> {
+    let addr = format!("{}:{}", options.host, options.port);
+    if let Some(cluster) = &options.cluster {
+        return match cluster.as_slice() {
+            [check, node] if check == "check" => {
+                cluster_check(&mut Client::connect(node, options.password.as_deref())?)
+            }
+            _ => Err("usage: --cluster check host:port".to_string()),
+        };
+    }
+    if let Some((bulk, key)) = &options.bulk {
+        if options.follow || !options.command.is_empty() {
+            return Err("--stdin-* takes neither -c nor a command".to_string());
+        }
+        let mut client = Client::connect(&addr, options.password.as_deref())?;
+        return bulk_load(
+            &mut client,
+            *bulk,
+            key.as_deref().map(str::as_bytes),
+            std::io::stdin().lock(),
+        );
+    }
+    let mut cluster = Cluster::new(addr, options.password, options.follow);
+    if !options.command.is_empty() {
+        let args: Vec<Vec<u8>> = options
+            .command
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+        return run_command(&mut cluster, &args);
+    }
+    let mut ok = true;
+    for line in std::io::stdin().lock().lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        match split_line(&line) {
+            Ok(args) if args.is_empty() => {}
+            Ok(args) => ok &= run_command(&mut cluster, &args)?,
+            Err(e) => {
+                eprintln!("rdb-cli: {}", e);
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn main() -> ExitCode {
+    let Some(options) = parse_args(std::env::args().skip(1)) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    match run(options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("rdb-cli: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_and_redirects() {
+        // Values from the Redis Cluster specification.
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(
+            key_slot(b"foo{}{bar}"),
+            crc16(b"foo{}{bar}") as usize % SLOTS
+        );
+
+        assert_eq!(
+            redirect(&RespValue::Error("MOVED 3999 127.0.0.1:6381".to_string())),
+            Some(Redirect::Moved(3999, "127.0.0.1:6381".to_string()))
+        );
+        assert_eq!(
+            redirect(&RespValue::Error("ASK 3999 127.0.0.1:6381".to_string())),
+            Some(Redirect::Ask(3999, "127.0.0.1:6381".to_string()))
+        );
+        assert_eq!(redirect(&RespValue::Error("ERR MOVED".to_string())), None);
+    }
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(
+            split_line(r#"SET "a key" "x\ny\x00\a" plain"#).unwrap(),
+            vec![
+                b"SET".to_vec(),
+                b"a key".to_vec(),
+                b"x\ny\x00\x07".to_vec(),
+                b"plain".to_vec()
+            ]
+        );
+        assert!(split_line("GET \"open").is_err());
+        let reply = RespValue::Array(vec![
+            RespValue::bulk("a"),
+            RespValue::Integer(2),
+            RespValue::Array(vec![RespValue::BulkString(None), RespValue::bulk("b")]),
+        ]);
+        assert_eq!(
+            format_reply(&reply),
+            "1) \"a\"\n2) (integer) 2\n3) 1) (nil)\n   2) \"b\"\n"
+        );
+        assert_eq!(format_reply(&RespValue::Array(vec![])), "(empty array)\n");
+    }
+
+    #[test]
+    fn test_bulk_lines() {
+        let mut args = Vec::new();
+        Bulk::LPush.push_line(b"a\tb", &mut args).unwrap();
+        Bulk::SAdd.push_line(b"", &mut args).unwrap();
+        Bulk::ZAdd.push_line(b"1.5\tm\tn", &mut args).unwrap();
+        Bulk::MSet.push_line(b"k\t", &mut args).unwrap();
+        assert_eq!(
+            args,
+            vec![
+                b"a\tb".to_vec(),
+                b"".to_vec(),
+                b"1.5".to_vec(),
+                b"m\tn".to_vec(),
+                b"k".to_vec(),
+                b"".to_vec()
+            ]
+        );
+        assert!(Bulk::ZAdd.push_line(b"x\tm", &mut args).is_err());
+        assert!(Bulk::MSet.push_line(b"k", &mut args).is_err());
+
+        let options = parse_args(["--stdin-rpush", "q"].map(String::from).into_iter()).unwrap();
+        assert_eq!(options.bulk, Some((Bulk::RPush, Some("q".to_string()))));
+        let options = parse_args(["--stdin-mset"].map(String::from).into_iter()).unwrap();
+        assert_eq!(options.bulk, Some((Bulk::MSet, None)));
+        assert!(parse_args(["--stdin-hset", "h"].map(String::from).into_iter()).is_none());
+    }
+}
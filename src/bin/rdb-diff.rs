@@ -0,0 +1,350 @@
+//! Compares two dump files, or a dump file and a live server, key by key.
+//!
+//! Exits with 0 if both sides hold the same data, 1 if they differ and 2 on
+//! errors, like `diff`.
+use bytes::Bytes;
+use rdb::commands::format_score;
+use rdb::protocol::{parse_resp, quote, RespError, RespValue};
+use rdb::storage::{rdb as dump, SortedSet, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: rdb-diff [--values] [-a password] <old.rdb> <new.rdb | host:port>
+
+Reports keys added (+), removed (-) and changed (~) between two dump files.
+Against a live server, the keys of the dump are looked up one by one, so keys
+that only exist on the server are not listed as added. Only database 0 is
+compared.";
+
+/// Databases a dump may use, as in the default configuration.
+const DATABASES: usize = 16;
+
+type Dataset = HashMap<Bytes, Value>;
+
+#[derive(Debug, PartialEq)]
+enum Change<'a> {
+    Added(&'a Value),
+    Removed(&'a Value),
+    Changed(&'a Value, &'a Value),
+}
+
+/// Differences between `old` and `new`, ordered by key.
+fn diff<'a>(old: &'a Dataset, new: &'a Dataset) -> Vec<(&'a Bytes, Change<'a>)> {
+    let keys: BTreeSet<&Bytes> = old.keys().chain(new.keys()).collect();
+    keys.into_iter()
+        .filter_map(|key| {
+            let change = match (old.get(key), new.get(key)) {
+                (Some(before), Some(after)) if before == after => return None,
+                (Some(before), Some(after)) => Change::Changed(before, after),
+                (Some(before), None) => Change::Removed(before),
+                (None, Some(after)) => Change::Added(after),
+                (None, None) => unreachable!(),
+            };
+            Some((key, change))
+        })
+        .collect()
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Set(_) => "set",
+        Value::SortedSet(_) => "zset",
+        Value::List(_) => "list",
+    }
+}
+
+/// Lines of `value`, each tagged with `sign`.
+fn value_lines(sign: char, value: &Value) -> Vec<String> {
+    match value {
+        Value::String(s) => vec![format!("{} {}", sign, quote(s))],
+        Value::Set(set) => {
+            let members: BTreeSet<&Bytes> = set.iter().collect();
+            members
+                .into_iter()
+                .map(|m| format!("{} {}", sign, quote(m)))
+                .collect()
+        }
+        Value::SortedSet(zset) => zset
+            .iter()
+            .map(|(m, score)| format!("{} {} {}", sign, quote(m), format_score(score)))
+            .collect(),
+        Value::List(list) => list
+            .iter()
+            .map(|e| format!("{} {}", sign, quote(e)))
+            .collect(),
+    }
+}
+
+/// What changed inside a value. Sets and sorted sets of the same type only
+/// list the members that differ.
+fn change_lines(before: &Value, after: &Value) -> Vec<String> {
+    match (before, after) {
+        (Value::Set(a), Value::Set(b)) => {
+            let mut lines = value_lines('-', &Value::Set(a - b));
+            lines.extend(value_lines('+', &Value::Set(b - a)));
+            lines
+        }
+        (Value::SortedSet(a), Value::SortedSet(b)) => {
+            let only = |x: &SortedSet, y: &SortedSet| -> Value {
+                Value::SortedSet(
+                    x.iter()
+                        .filter(|(m, score)| y.score(m) != Some(*score))
+                        .map(|(m, score)| (m.clone(), score))
+                        .collect::<Vec<_>>()
+                        .into(),
+                )
+            };
+            let mut lines = value_lines('-', &only(a, b));
+            lines.extend(value_lines('+', &only(b, a)));
+            lines
+        }
+        _ => {
+            let mut lines = value_lines('-', before);
+            lines.extend(value_lines('+', after));
+            lines
+        }
+    }
+}
+
+fn load_dump(path: &str) -> Result<Dataset, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut dump = dump::read(&data, DATABASES).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(dump.databases.swap_remove(0).into_iter().collect())
+}
+
+/// A minimal blocking RESP client.
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    fn call(&mut self, args: &[&[u8]]) -> Result<RespValue, String> {
+        let frame = RespValue::Array(
+            args.iter()
+                .map(|a| RespValue::bulk(Bytes::copy_from_slice(a)))
+                .collect(),
+        );
+        self.stream
+            .write_all(&frame.serialize())
+            .map_err(|e| e.to_string())?;
+        loop {
+            match parse_resp(&self.buffer) {
+                Ok((reply, len)) => {
+                    self.buffer.drain(..len);
+                    return Ok(reply);
+                }
+                Err(RespError::Incomplete) => {
+                    let mut chunk = [0; 4096];
+                    let n = self.stream.read(&mut chunk).map_err(|e| e.to_string())?;
+                    if n == 0 {
+                        return Err("connection closed by server".to_string());
+                    }
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(_) => return Err("invalid reply".to_string()),
+            }
+        }
+    }
+
+    /// Reads `key` whatever its type, or `None` if it doesn't exist.
+    fn fetch(&mut self, key: &[u8]) -> Result<Option<Value>, String> {
+        let wrong_type =
+            |reply: &RespValue| matches!(reply, RespValue::Error(e) if e.starts_with("WRONGTYPE"));
+        let reply = self.call(&[b"GET", key])?;
+        if !wrong_type(&reply) {
+            return match reply {
+                RespValue::BulkString(Some(s)) => Ok(Some(Value::String(s))),
+                RespValue::BulkString(None) => Ok(None),
+                other => Err(format!("unexpected reply to GET: {:?}", other)),
+            };
+        }
+        let reply = self.call(&[b"SMEMBERS", key])?;
+        if !wrong_type(&reply) {
+            return Ok(Some(Value::Set(bulk_items(reply)?.into_iter().collect())));
+        }
+        let reply = self.call(&[b"LRANGE", key, b"0", b"-1"])?;
+        if !wrong_type(&reply) {
+            return Ok(Some(Value::List(bulk_items(reply)?.into())));
+        }
+        let reply = self.call(&[b"ZRANGE", key, b"0", b"-1", b"WITHSCORES"])?;
+        let items = bulk_items(reply)?;
+        let mut zset = SortedSet::new();
+        for pair in items.chunks_exact(2) {
+            let score = std::str::from_utf8(&pair[1])
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or("invalid score from server")?;
+            zset.insert(pair[0].clone(), score);
+        }
+        Ok(Some(Value::SortedSet(zset)))
+    }
+}
+
+fn bulk_items(reply: RespValue) -> Result<Vec<Bytes>, String> {
+    match reply {
+        RespValue::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                RespValue::BulkString(Some(s)) => Ok(s),
+                other => Err(format!("unexpected item in reply: {:?}", other)),
+            })
+            .collect(),
+        other => Err(format!("unexpected reply: {:?}", other)),
+    }
+}
+
+/// Looks up every key of `reference` on the server at `addr`.
+fn load_server(addr: &str, password: Option<&str>, reference: &Dataset) -> Result<Dataset, String> {
+    let stream = TcpStream::connect(addr).map_err(|e| format!("{}: {}", addr, e))?;
+    let mut client = Client {
+        stream,
+        buffer: Vec::new(),
+    };
+    if let Some(password) = password {
+        if let RespValue::Error(e) = client.call(&[b"AUTH", password.as_bytes()])? {
+            return Err(e);
+        }
+    }
+    let mut dataset = Dataset::new();
+    for key in reference.keys() {
+        if let Some(value) = client.fetch(key)? {
+            dataset.insert(key.clone(), value);
+        }
+    }
+    Ok(dataset)
+}
+
+struct Options {
+    values: bool,
+    password: Option<String>,
+    old: String,
+    new: String,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    let mut values = false;
+    let mut password = None;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--values" => values = true,
+            "-a" => password = Some(args.next()?),
+            "-h" | "--help" => return None,
+            _ => paths.push(arg),
+        }
+    }
+    let [old, new]: [String; 2] = paths.try_into().ok()?;
+    Some(Options {
+        values,
+        password,
+        old,
+        new,
+    })
+}
+
+fn run(options: &Options) -> Result<bool, String> {
+    let old = load_dump(&options.old)?;
+    // A path that parses as host:port and isn't a file means a live server.
+    let new = if !std::path::Path::new(&options.new).exists() && options.new.contains(':') {
+        load_server(&options.new, options.password.as_deref(), &old)?
+    } else {
+        load_dump(&options.new)?
+    };
+
+    let changes = diff(&old, &new);
+    let (mut added, mut removed, mut changed) = (0, 0, 0);
+    for (key, change) in &changes {
+        let (sign, value, lines) = match change {
+            Change::Added(after) => {
+                added += 1;
+                ('+', *after, value_lines('+', after))
+            }
+            Change::Removed(before) => {
+                removed += 1;
+                ('-', *before, value_lines('-', before))
+            }
+            Change::Changed(before, after) => {
+                changed += 1;
+                ('~', *after, change_lines(before, after))
+            }
+        };
+        let kind = match change {
+            Change::Changed(before, after) if type_name(before) != type_name(after) => {
+                format!("{} -> {}", type_name(before), type_name(after))
+            }
+            _ => type_name(value).to_string(),
+        };
+        println!("{} {} ({})", sign, quote(key), kind);
+        if options.values {
+            for line in lines {
+                println!("    {}", line);
+            }
+        }
+    }
+    let unchanged = old.keys().filter(|key| new.contains_key(*key)).count() - changed;
+    println!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        added, removed, changed, unchanged
+    );
+    Ok(changes.is_empty())
+}
+
+fn main() -> ExitCode {
+    let Some(options) = parse_args(std::env::args().skip(1)) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("rdb-diff: {}", e);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: &[&'static str]) -> Value {
+        Value::Set(members.iter().map(|m| Bytes::from(*m)).collect())
+    }
+
+    #[test]
+    fn test_diff() {
+        let old: Dataset = [
+            ("same".into(), Value::String("v".into())),
+            ("gone".into(), Value::String("v".into())),
+            ("members".into(), set(&["a", "b"])),
+        ]
+        .into_iter()
+        .collect();
+        let new: Dataset = [
+            ("same".into(), Value::String("v".into())),
+            ("members".into(), set(&["b", "c"])),
+            ("new\n".into(), Value::String("\x00".into())),
+        ]
+        .into_iter()
+        .collect();
+
+        let changes = diff(&old, &new);
+        let keys: Vec<String> = changes.iter().map(|(k, _)| quote(k)).collect();
+        assert_eq!(keys, vec!["\"gone\"", "\"members\"", "\"new\\n\""]);
+        assert!(matches!(changes[0].1, Change::Removed(_)));
+        let Change::Changed(before, after) = changes[1].1 else {
+            panic!("expected a change");
+        };
+        assert_eq!(change_lines(before, after), vec!["- \"a\"", "+ \"c\""]);
+        assert_eq!(
+            value_lines('+', &new[&Bytes::from("new\n")]),
+            vec!["+ \"\\x00\""]
+        );
+    }
+}
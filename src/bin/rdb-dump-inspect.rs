@@ -0,0 +1,283 @@
+//! Prints statistics about a dump file without loading it into a server:
+//! keys per type, the biggest keys, how soon keys expire and which key
+//! prefixes take up the memory.
+use rdb::protocol::quote;
+use rdb::storage::rdb::{read_all, Entry};
+use rdb::storage::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "\
+usage: rdb-dump-inspect [--top N] [--delimiter C] <dump.rdb>
+
+  --top N        how many of the biggest keys and prefixes to list (default 10)
+  --delimiter C  what separates a key's prefix from the rest (default ':')";
+
+/// Upper bounds of the TTL buckets, in seconds.
+const TTL_BUCKETS: [(u64, &str); 5] = [
+    (60, "< 1 minute"),
+    (3600, "< 1 hour"),
+    (86400, "< 1 day"),
+    (7 * 86400, "< 1 week"),
+    (u64::MAX, ">= 1 week"),
+];
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::String(_) => "string",
+        Value::Set(_) => "set",
+        Value::SortedSet(_) => "zset",
+        Value::List(_) => "list",
+    }
+}
+
+/// Bytes for strings, members or elements for collections.
+fn length(value: &Value) -> (usize, &'static str) {
+    match value {
+        Value::String(s) => (s.len(), "bytes"),
+        Value::Set(set) => (set.len(), "members"),
+        Value::SortedSet(zset) => (zset.len(), "members"),
+        Value::List(list) => (list.len(), "elements"),
+    }
+}
+
+/// Memory a key accounts for, as the server counts it.
+fn memory(entry: &Entry) -> usize {
+    entry.key.len() + entry.value.size()
+}
+
+fn human(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Totals {
+    keys: usize,
+    bytes: usize,
+}
+
+impl Totals {
+    fn add(&mut self, bytes: usize) {
+        self.keys += 1;
+        self.bytes += bytes;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report<'a> {
+    total: Totals,
+    by_db: BTreeMap<usize, Totals>,
+    by_type: BTreeMap<&'static str, Totals>,
+    no_ttl: usize,
+    expired: usize,
+    ttl_buckets: [usize; TTL_BUCKETS.len()],
+    by_prefix: HashMap<&'a [u8], Totals>,
+    biggest: Vec<&'a Entry>,
+}
+
+fn analyze(entries: &[Entry], delimiter: u8, top: usize, now_ms: u64) -> Report<'_> {
+    let mut report = Report::default();
+    for entry in entries {
+        let bytes = memory(entry);
+        report.total.add(bytes);
+        report.by_db.entry(entry.db).or_default().add(bytes);
+        report
+            .by_type
+            .entry(type_name(&entry.value))
+            .or_default()
+            .add(bytes);
+
+        match entry.expires_at {
+            None => report.no_ttl += 1,
+            Some(at) if at <= now_ms => report.expired += 1,
+            Some(at) => {
+                let ttl = (at - now_ms) / 1000;
+                let bucket = TTL_BUCKETS.iter().position(|(max, _)| ttl < *max);
+                report.ttl_buckets[bucket.unwrap_or(TTL_BUCKETS.len() - 1)] += 1;
+            }
+        }
+
+        // Keys without the delimiter count as their own prefix.
+        let prefix = match entry.key.iter().position(|b| *b == delimiter) {
+            Some(end) => &entry.key[..=end],
+            None => &entry.key[..],
+        };
+        report.by_prefix.entry(prefix).or_default().add(bytes);
+    }
+
+    let mut biggest: Vec<&Entry> = entries.iter().collect();
+    biggest.sort_by_key(|entry| std::cmp::Reverse(memory(entry)));
+    biggest.truncate(top);
+    report.biggest = biggest;
+    report
+}
+
+fn print(report: &Report, top: usize) {
+    let share = |bytes: usize| 100.0 * bytes as f64 / report.total.bytes.max(1) as f64;
+
+    println!(
+        "Keys: {} ({})",
+        report.total.keys,
+        human(report.total.bytes)
+    );
+    for (db, totals) in &report.by_db {
+        println!(
+            "  db{:<6} {:>10} keys  {:>10}",
+            db,
+            totals.keys,
+            human(totals.bytes)
+        );
+    }
+
+    println!("\nBy type:");
+    for (name, totals) in &report.by_type {
+        println!(
+            "  {:<8} {:>10} keys  {:>10}  {:5.1}%",
+            name,
+            totals.keys,
+            human(totals.bytes),
+            share(totals.bytes)
+        );
+    }
+
+    println!("\nExpiry:");
+    println!("  {:<12} {:>10}", "no expiry", report.no_ttl);
+    println!("  {:<12} {:>10}", "expired", report.expired);
+    for ((_, label), count) in TTL_BUCKETS.iter().zip(report.ttl_buckets) {
+        println!("  {:<12} {:>10}", label, count);
+    }
+
+    println!("\nBiggest keys:");
+    for (i, entry) in report.biggest.iter().enumerate() {
+        let (len, unit) = length(&entry.value);
+        println!(
+            "  {:>3}. {} ({}, {} {}, {})",
+            i + 1,
+            quote(&entry.key),
+            type_name(&entry.value),
+            len,
+            unit,
+            human(memory(entry))
+        );
+    }
+
+    println!("\nBiggest prefixes:");
+    let mut prefixes: Vec<_> = report.by_prefix.iter().collect();
+    prefixes.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+    for (prefix, totals) in prefixes.into_iter().take(top) {
+        println!(
+            "  {:<24} {:>10} keys  {:>10}  {:5.1}%",
+            quote(prefix),
+            totals.keys,
+            human(totals.bytes),
+            share(totals.bytes)
+        );
+    }
+}
+
+struct Options {
+    top: usize,
+    delimiter: u8,
+    path: String,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    let mut options = Options {
+        top: 10,
+        delimiter: b':',
+        path: String::new(),
+    };
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--top" => options.top = args.next()?.parse().ok()?,
+            "--delimiter" => match args.next()?.as_bytes() {
+                [delimiter] => options.delimiter = *delimiter,
+                _ => return None,
+            },
+            "-h" | "--help" => return None,
+            _ if path.is_none() => path = Some(arg),
+            _ => return None,
+        }
+    }
+    options.path = path?;
+    Some(options)
+}
+
+fn main() -> ExitCode {
+    let Some(options) = parse_args(std::env::args().skip(1)) else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    let entries = match std::fs::read(&options.path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| read_all(&data).map_err(|e| e.to_string()))
+    {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("rdb-dump-inspect: {}: {}", options.path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    print(
+        &analyze(&entries, options.delimiter, options.top, now_ms),
+        options.top,
+    );
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn entry(key: &'static str, value: &'static str, expires_at: Option<u64>) -> Entry {
+        Entry {
+            db: 0,
+            key: Bytes::from(key),
+            value: Value::String(Bytes::from(value)),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_analyze() {
+        let now = 1_000_000_000;
+        let entries = vec![
+            entry("user:1", "alice", None),
+            entry("user:2", "bob", Some(now + 30_000)),
+            entry("session:x", "a much longer value", Some(now + 7_200_000)),
+            entry("plain", "v", Some(now - 1)),
+        ];
+        let report = analyze(&entries, b':', 2, now);
+
+        assert_eq!(report.total, Totals { keys: 4, bytes: 54 });
+        assert_eq!((report.no_ttl, report.expired), (1, 1));
+        assert_eq!(report.ttl_buckets, [1, 0, 1, 0, 0]);
+        assert_eq!(
+            report.by_prefix[&b"user:"[..]],
+            Totals { keys: 2, bytes: 20 }
+        );
+        assert_eq!(
+            report.by_prefix[&b"plain"[..]],
+            Totals { keys: 1, bytes: 6 }
+        );
+        let biggest: Vec<&[u8]> = report.biggest.iter().map(|e| e.key.as_ref()).collect();
+        assert_eq!(biggest, vec![&b"session:x"[..], b"user:1"]);
+    }
+}
@@ -0,0 +1,57 @@
+//! What the running binary was built from and with
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short hash of the commit built, or `unknown` outside a git checkout.
+pub const GIT_HASH: &str = env!("RDB_GIT_HASH");
+/// `debug` or `release`.
+pub const PROFILE: &str = env!("RDB_BUILD_PROFILE");
+
+/// Every optional Cargo feature, and whether this binary has it.
+pub const FEATURES: &[(&str, bool)] = &[
+    ("tls", cfg!(feature = "tls")),
+    ("cluster", cfg!(feature = "cluster")),
+    ("scripting", cfg!(feature = "scripting")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("json", cfg!(feature = "json")),
+    ("sql", cfg!(feature = "sql")),
+];
+
+pub fn enabled_features() -> impl Iterator<Item = &'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+}
+
+/// One line identifying the build, logged at startup.
+pub fn banner() -> String {
+    let mut banner = format!(
+        "rdb {} ({}, {} build), features: ",
+        VERSION, GIT_HASH, PROFILE
+    );
+    let features: Vec<_> = enabled_features().collect();
+    if features.is_empty() {
+        banner.push_str("none");
+    } else {
+        banner.push_str(&features.join(", "));
+    }
+    banner
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_banner_lists_enabled_features() {
+        let banner = banner();
+        assert!(banner.starts_with(&format!("rdb {} ({}, ", VERSION, GIT_HASH)));
+        assert!(!GIT_HASH.is_empty());
+        assert_eq!(
+            banner.ends_with("features: none"),
+            enabled_features().next().is_none()
+        );
+        for name in enabled_features() {
+            assert!(banner.contains(name));
+        }
+    }
+}
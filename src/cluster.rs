@@ -0,0 +1,296 @@
+//! Cluster mode: keys hash to one of 16384 slots, as in Redis Cluster
+//!
+//! The slot map is static, from the `cluster` config: each node is listed
+//! with the slots it serves. Commands on keys this node doesn't serve get a
+//! `MOVED` redirect to the node that does, so cluster-aware clients route
+//! them there. Slots never migrate, and there are no replicas or failover.
+use crate::config::ClusterConfig;
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use thiserror::Error;
+
+pub const SLOTS: u16 = 16384;
+
+#[derive(Debug, Error)]
+pub enum ClusterError {
+    #[error("invalid slot range '{0}'")]
+    InvalidRange(String),
+    #[error("slot {0} is assigned to more than one node")]
+    Overlap(u16),
+    #[error("this node ({0}) isn't among the cluster nodes")]
+    NotANode(SocketAddr),
+}
+
+/// Why a command can't run here.
+#[derive(Debug, PartialEq)]
+pub enum Redirect {
+    CrossSlot,
+    Moved(u16, SocketAddr),
+    Down,
+}
+
+impl Redirect {
+    pub fn to_resp(&self) -> RespValue {
+        RespValue::Error(match self {
+            Redirect::CrossSlot => {
+                "CROSSSLOT Keys in request don't hash to the same slot".to_string()
+            }
+            Redirect::Moved(slot, addr) => format!("MOVED {} {}", slot, addr),
+            Redirect::Down => "CLUSTERDOWN Hash slot not served".to_string(),
+        })
+    }
+}
+
+pub struct Node {
+    /// 40 hex digits, as Redis names nodes.
+    pub id: String,
+    pub addr: SocketAddr,
+    /// Inclusive ranges of the slots served.
+    pub slots: Vec<(u16, u16)>,
+}
+
+/// The slot map, and which of its nodes this one is.
+pub struct Cluster {
+    nodes: Vec<Node>,
+    /// Index into `nodes` of each slot's owner.
+    owners: Box<[Option<usize>]>,
+    myself: usize,
+}
+
+impl Cluster {
+    /// The slot map in `config`, or `None` if cluster mode is off. This node
+    /// is the one at `myself`, defaulting to `listen_addr`.
+    pub fn from_config(
+        config: &ClusterConfig,
+        listen_addr: SocketAddr,
+    ) -> Result<Option<Self>, ClusterError> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let mut owners = vec![None; SLOTS as usize].into_boxed_slice();
+        let mut nodes = Vec::with_capacity(config.nodes.len());
+        for (index, node) in config.nodes.iter().enumerate() {
+            let mut slots = Vec::new();
+            for range in &node.slots {
+                let (start, end) = parse_range(range)?;
+                for slot in start..=end {
+                    if owners[slot as usize].replace(index).is_some() {
+                        return Err(ClusterError::Overlap(slot));
+                    }
+                }
+                slots.push((start, end));
+            }
+            nodes.push(Node {
+                id: node.id.clone().unwrap_or_else(|| node_id(node.addr)),
+                addr: node.addr,
+                slots,
+            });
+        }
+        let me = config.myself.unwrap_or(listen_addr);
+        let myself = nodes
+            .iter()
+            .position(|node| node.addr == me)
+            .ok_or(ClusterError::NotANode(me))?;
+        Ok(Some(Cluster {
+            nodes,
+            owners,
+            myself,
+        }))
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[self.myself]
+    }
+
+    /// Checks that `keys`, if any, all hash to one slot this node serves.
+    pub fn route(&self, keys: &[Bytes]) -> Result<(), Redirect> {
+        let Some((first, rest)) = keys.split_first() else {
+            return Ok(());
+        };
+        let slot = key_slot(first);
+        if rest.iter().any(|key| key_slot(key) != slot) {
+            return Err(Redirect::CrossSlot);
+        }
+        match self.owners[slot as usize] {
+            Some(owner) if owner == self.myself => Ok(()),
+            Some(owner) => Err(Redirect::Moved(slot, self.nodes[owner].addr)),
+            None => Err(Redirect::Down),
+        }
+    }
+
+    /// `CLUSTER SLOTS`: each range with the node serving it.
+    pub fn slots_reply(&self) -> RespValue {
+        let mut ranges: Vec<_> = self
+            .nodes
+            .iter()
+            .flat_map(|node| node.slots.iter().map(move |&range| (range, node)))
+            .collect();
+        ranges.sort_by_key(|&(range, _)| range);
+        RespValue::Array(
+            ranges
+                .into_iter()
+                .map(|((start, end), node)| {
+                    RespValue::Array(vec![
+                        RespValue::Integer(start.into()),
+                        RespValue::Integer(end.into()),
+                        RespValue::Array(vec![
+                            RespValue::bulk(node.addr.ip().to_string()),
+                            RespValue::Integer(node.addr.port().into()),
+                            RespValue::bulk(node.id.clone()),
+                        ]),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// `CLUSTER SHARDS`: each node as a shard of its own, with its slots as
+    /// a flat list of range bounds.
+    pub fn shards_reply(&self) -> RespValue {
+        let field = |name: &str| RespValue::bulk(name.to_string());
+        RespValue::Array(
+            self.nodes
+                .iter()
+                .map(|node| {
+                    let slots = node
+                        .slots
+                        .iter()
+                        .flat_map(|&(start, end)| [start, end])
+                        .map(|bound| RespValue::Integer(bound.into()))
+                        .collect();
+                    let ip = node.addr.ip().to_string();
+                    let description = RespValue::Map(vec![
+                        (field("id"), RespValue::bulk(node.id.clone())),
+                        (field("port"), RespValue::Integer(node.addr.port().into())),
+                        (field("ip"), RespValue::bulk(ip.clone())),
+                        (field("endpoint"), RespValue::bulk(ip)),
+                        (field("role"), RespValue::bulk("master")),
+                        (field("replication-offset"), RespValue::Integer(0)),
+                        (field("health"), RespValue::bulk("online")),
+                    ]);
+                    RespValue::Map(vec![
+                        (field("slots"), RespValue::Array(slots)),
+                        (field("nodes"), RespValue::Array(vec![description])),
+                    ])
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The slot of `key`: the CRC16 of its hash tag, the part between the
+/// first `{` and the `}` after it, if that's not empty, or else of the key.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let tag = key
+        .iter()
+        .position(|&c| c == b'{')
+        .and_then(|open| {
+            let rest = &key[open + 1..];
+            rest.iter()
+                .position(|&c| c == b'}')
+                .map(|close| &rest[..close])
+        })
+        .filter(|tag| !tag.is_empty());
+    crc16(tag.unwrap_or(key)) % SLOTS
+}
+
+/// CRC16-CCITT (XModem), the checksum Redis Cluster hashes keys with.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc
+}
+
+/// `"5"` or `"0-5460"`, inclusive.
+fn parse_range(range: &str) -> Result<(u16, u16), ClusterError> {
+    let invalid = || ClusterError::InvalidRange(range.to_string());
+    let (start, end) = range.split_once('-').unwrap_or((range, range));
+    let start: u16 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u16 = end.trim().parse().map_err(|_| invalid())?;
+    if start > end || end >= SLOTS {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+/// A stable id for a node configured without one.
+fn node_id(addr: SocketAddr) -> String {
+    Sha256::digest(addr.to_string().as_bytes())[..20]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClusterNodeConfig;
+
+    #[test]
+    fn test_key_slot() {
+        // The values Redis gives, from `CLUSTER KEYSLOT`.
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % SLOTS);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[test]
+    fn test_route() {
+        let node = |port: u16, slots: &[&str]| ClusterNodeConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            id: None,
+            slots: slots.iter().map(|s| s.to_string()).collect(),
+        };
+        let config = ClusterConfig {
+            enabled: true,
+            myself: None,
+            nodes: vec![node(7000, &["0-8191"]), node(7001, &["8192-16000"])],
+        };
+        let cluster = Cluster::from_config(&config, "127.0.0.1:7000".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(cluster.myself().id.len(), 40);
+
+        // "a" hashes to slot 15495 and "b" to 3300.
+        assert_eq!(cluster.route(&[Bytes::from("b")]), Ok(()));
+        assert_eq!(
+            cluster.route(&[Bytes::from("a")]),
+            Err(Redirect::Moved(15495, "127.0.0.1:7001".parse().unwrap()))
+        );
+        assert_eq!(
+            cluster.route(&[Bytes::from("a"), Bytes::from("b")]),
+            Err(Redirect::CrossSlot)
+        );
+        assert_eq!(
+            cluster.route(&[Bytes::from("{b}1"), Bytes::from("{b}2")]),
+            Ok(())
+        );
+        // "x" is slot 16287, which no node serves.
+        assert_eq!(cluster.route(&[Bytes::from("x")]), Err(Redirect::Down));
+
+        let overlapping = ClusterConfig {
+            nodes: vec![node(7000, &["0-10"]), node(7001, &["10"])],
+            ..config
+        };
+        assert!(matches!(
+            Cluster::from_config(&overlapping, "127.0.0.1:7000".parse().unwrap()),
+            Err(ClusterError::Overlap(10))
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+//! Server administration: `INFO`, `CONFIG`, saving, the slow log and the
+//! latency monitor
+use super::handler::{CommandHandler, Context};
+use super::{info, Command};
+use crate::config::runtime;
+use crate::latency::{SlowEntry, UserStats};
+use crate::protocol::RespValue;
+use crate::storage::{MemoryReport, ShardLocks, StorageError};
+
+pub struct Admin;
+
+impl CommandHandler for Admin {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let Context { clients, .. } = ctx;
+        let resp = match command {
+            Command::Info { sections, json } => info::reply(store, clients, &sections, json),
+            Command::MemoryUsage(key, samples) => match store.key_usage(&key, samples) {
+                Some(bytes) => RespValue::Integer(bytes as i64),
+                None => RespValue::BulkString(None),
+            },
+            Command::MemoryStats => memory_stats_reply(&store.memory_report()),
+            Command::MemoryDoctor => RespValue::bulk(store.memory_report().diagnose()),
+            Command::ExpiresNext { count, pattern } => RespValue::Array(
+                store
+                    .expiring_next(count, pattern.as_deref())
+                    .into_iter()
+                    .map(|(key, ttl)| {
+                        RespValue::Array(vec![RespValue::bulk(key), RespValue::Integer(ttl as i64)])
+                    })
+                    .collect(),
+            ),
+            Command::BgRewriteAof => match store.rewrite_aof() {
+                Ok(()) => RespValue::SimpleString(
+                    "Background append only file rewriting started".to_string(),
+                ),
+                Err(e) => RespValue::Error(e.to_string()),
+            },
+            Command::ConfigGet(patterns) => RespValue::Map(
+                runtime::get(store, &patterns)
+                    .into_iter()
+                    .map(|(name, value)| (RespValue::bulk(name), RespValue::bulk(value)))
+                    .collect(),
+            ),
+            Command::ConfigSet(name, value) => match runtime::set(store, &name, &value) {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(e.to_string()),
+            },
+            Command::ConfigRewrite => match runtime::rewrite(store) {
+                Ok(()) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(e.to_string()),
+            },
+            Command::SlowlogGet(count) => slowlog_reply(store.stats().slowlog().get(count)),
+            Command::SlowlogLen => RespValue::Integer(store.stats().slowlog().len() as i64),
+            Command::SlowlogReset => {
+                store.stats().slowlog().reset();
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::LatencyLatest => RespValue::Array(
+                store
+                    .stats()
+                    .latency()
+                    .latest()
+                    .into_iter()
+                    .map(|latest| {
+                        RespValue::Array(vec![
+                            RespValue::bulk(latest.event),
+                            RespValue::Integer(latest.timestamp as i64),
+                            RespValue::Integer(latest.latest_ms as i64),
+                            RespValue::Integer(latest.max_ms as i64),
+                        ])
+                    })
+                    .collect(),
+            ),
+            Command::LatencyHistory(event) => RespValue::Array(
+                store
+                    .stats()
+                    .latency()
+                    .history(&event)
+                    .into_iter()
+                    .map(|(timestamp, ms)| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(timestamp as i64),
+                            RespValue::Integer(ms as i64),
+                        ])
+                    })
+                    .collect(),
+            ),
+            Command::LatencyReset(events) => {
+                RespValue::Integer(store.stats().latency().reset(&events) as i64)
+            }
+            Command::AclStats(user) => {
+                acl_stats_reply(store.stats().users().report(user.as_deref()))
+            }
+            Command::Save => match store.save_to_disk() {
+                Ok(_) => RespValue::SimpleString("OK".to_string()),
+                Err(e) => RespValue::Error(e.to_string()),
+            },
+            Command::BgSave => match store.bgsave() {
+                Ok(()) => RespValue::SimpleString("Background saving started".to_string()),
+                Err(e) => RespValue::Error(e.to_string()),
+            },
+            _ => unreachable!("not a admin command"),
+        };
+        Ok(resp)
+    }
+}
+
+/// `SLOWLOG GET` entries as Redis 4 and later reply them: id, unix time,
+/// microseconds, arguments, client address and client name.
+fn slowlog_reply(entries: Vec<SlowEntry>) -> RespValue {
+    RespValue::Array(
+        entries
+            .into_iter()
+            .map(|entry| {
+                RespValue::Array(vec![
+                    RespValue::Integer(entry.id as i64),
+                    RespValue::Integer(entry.timestamp as i64),
+                    RespValue::Integer(entry.usec as i64),
+                    RespValue::Array(entry.args.into_iter().map(RespValue::bulk).collect()),
+                    RespValue::bulk(entry.client_addr),
+                    RespValue::bulk(entry.client_name),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// `ACL STATS` as a map of each user's name to a map of their calls, time
+/// in microseconds and, over the last minute, commands per second and
+/// latency percentiles in microseconds.
+fn acl_stats_reply(users: Vec<UserStats>) -> RespValue {
+    RespValue::Map(
+        users
+            .into_iter()
+            .map(|user| {
+                let integer = |value: u64| RespValue::Integer(value as i64);
+                let stats = vec![
+                    (RespValue::bulk("calls"), integer(user.calls)),
+                    (RespValue::bulk("usec"), integer(user.usec)),
+                    (
+                        RespValue::bulk("ops_per_sec"),
+                        RespValue::bulk(format!("{:.2}", user.ops_per_sec)),
+                    ),
+                    (RespValue::bulk("p50_usec"), integer(user.p50_usec)),
+                    (RespValue::bulk("p99_usec"), integer(user.p99_usec)),
+                    (RespValue::bulk("p999_usec"), integer(user.p999_usec)),
+                ];
+                (RespValue::bulk(user.user), RespValue::Map(stats))
+            })
+            .collect(),
+    )
+}
+
+/// `MEMORY STATS` as a map of field names to values, named as in Redis
+/// where it has them. Databases without keys are left out.
+fn memory_stats_reply(report: &MemoryReport) -> RespValue {
+    let integer = |value: usize| RespValue::Integer(value as i64);
+    let mut reply = vec![(RespValue::bulk("total.allocated"), integer(report.total()))];
+    for (index, db) in report.databases.iter().enumerate() {
+        if db.keys == 0 {
+            continue;
+        }
+        reply.push((
+            RespValue::bulk(format!("db.{}", index)),
+            RespValue::Map(vec![
+                (RespValue::bulk("overhead.hashtable.main"), integer(db.main)),
+                (
+                    RespValue::bulk("overhead.hashtable.expires"),
+                    integer(db.expires),
+                ),
+            ]),
+        ));
+    }
+    let keys = report.keys();
+    reply.extend([
+        (
+            RespValue::bulk("overhead.total"),
+            integer(report.overhead()),
+        ),
+        (RespValue::bulk("keys.count"), integer(keys)),
+        (
+            RespValue::bulk("keys.bytes-per-key"),
+            integer(report.total().checked_div(keys).unwrap_or(0)),
+        ),
+        (RespValue::bulk("dataset.bytes"), integer(report.dataset)),
+        (
+            RespValue::bulk("dataset.percentage"),
+            RespValue::bulk(format!("{:.2}", report.dataset_percentage())),
+        ),
+        (
+            RespValue::bulk("fragmentation"),
+            RespValue::bulk(format!("{:.2}", report.fragmentation)),
+        ),
+    ]);
+
+    let mut fields = vec![("slab.enabled", report.slab.is_some() as u64)];
+    if let Some(slab) = report.slab {
+        fields.extend([
+            ("slab.chunks", slab.chunks),
+            ("slab.chunk.bytes", slab.chunk_bytes),
+            ("slab.small.allocations", slab.small_allocations),
+            ("slab.small.bytes", slab.small_bytes),
+            ("slab.large.allocations", slab.large_allocations),
+        ]);
+    }
+    if let Some((entries, hits)) = report.intern {
+        fields.extend([("intern.entries", entries as u64), ("intern.hits", hits)]);
+    }
+    reply.extend(
+        fields
+            .into_iter()
+            .map(|(name, value)| (RespValue::bulk(name), RespValue::Integer(value as i64))),
+    );
+    RespValue::Map(reply)
+}
@@ -0,0 +1,83 @@
+//! Commands a listener refuses, whoever the client is
+//!
+//! Each listener's `deny_commands` names commands, as `config`, or flags of
+//! the command table, as `@admin` for every command flagged admin. A public
+//! port can so refuse what only operators should run, while an admin port
+//! on loopback allows everything.
+use super::registry::{lookup, Flag};
+use super::Command;
+use crate::protocol::RespValue;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Blocklist {
+    names: Vec<&'static str>,
+    flags: Vec<Flag>,
+}
+
+impl Blocklist {
+    /// The blocklist of `entries`; unknown commands and flags are errors,
+    /// so a typo doesn't leave a command open.
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        let mut blocklist = Blocklist::default();
+        for entry in entries {
+            match entry.strip_prefix('@') {
+                Some(flag) => blocklist.flags.push(
+                    Flag::ALL
+                        .into_iter()
+                        .find(|known| known.name().eq_ignore_ascii_case(flag))
+                        .ok_or_else(|| format!("unknown command flag '{}'", entry))?,
+                ),
+                None => blocklist.names.push(
+                    lookup(entry.as_bytes())
+                        .ok_or_else(|| format!("unknown command '{}'", entry))?
+                        .name,
+                ),
+            }
+        }
+        Ok(blocklist)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.flags.is_empty()
+    }
+
+    pub fn blocks(&self, command: &Command) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let name = command.name();
+        self.names.contains(&name)
+            || lookup(name.as_bytes())
+                .is_some_and(|spec| self.flags.iter().any(|&flag| spec.has_flag(flag)))
+    }
+
+    /// The reply to a command refused here.
+    pub fn refusal(command: &Command) -> RespValue {
+        RespValue::Error(format!(
+            "ERR '{}' is not allowed on this port",
+            command.name()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_blocks_names_and_flags() {
+        let blocklist = Blocklist::parse(&["FlushAll".to_string(), "@admin".to_string()]).unwrap();
+        assert!(blocklist.blocks(&Command::FlushAll));
+        assert!(blocklist.blocks(&Command::ConfigGet(vec!["*".to_string()])));
+        assert!(blocklist.blocks(&Command::DebugBigKeys));
+        assert!(!blocklist.blocks(&Command::Get(Bytes::from("k"))));
+        assert!(!Blocklist::default().blocks(&Command::FlushAll));
+
+        assert_eq!(
+            Blocklist::parse(&["nosuch".to_string()]),
+            Err("unknown command 'nosuch'".to_string())
+        );
+        assert!(Blocklist::parse(&["@nosuch".to_string()]).is_err());
+    }
+}
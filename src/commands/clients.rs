@@ -0,0 +1,43 @@
+//! `CLIENT` commands, about the client sending them and the others
+use super::handler::{CommandHandler, Context};
+use super::Command;
+use crate::protocol::RespValue;
+use crate::storage::{ShardLocks, StorageError};
+use bytes::Bytes;
+
+pub struct Clients;
+
+impl CommandHandler for Clients {
+    fn run(
+        &self,
+        command: Command,
+        _store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let Context { conn, clients, .. } = ctx;
+        let Some(id) = conn.client_id else {
+            return Ok(RespValue::Error(
+                "ERR CLIENT needs a client connection".to_string(),
+            ));
+        };
+        let resp = match command {
+            Command::ClientId => RespValue::Integer(id as i64),
+            Command::ClientGetName => RespValue::BulkString(clients.name(id).map(Bytes::from)),
+            Command::ClientSetName(name) => {
+                clients.set_name(id, (!name.is_empty()).then_some(name));
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::ClientList => RespValue::bulk(clients.list()),
+            Command::ClientKill { filter, legacy } => {
+                let killed = clients.kill(&filter, id);
+                match (legacy, killed) {
+                    (false, killed) => RespValue::Integer(killed as i64),
+                    (true, 0) => RespValue::Error("ERR No such client".to_string()),
+                    (true, _) => RespValue::SimpleString("OK".to_string()),
+                }
+            }
+            _ => unreachable!("not a CLIENT command"),
+        };
+        Ok(resp)
+    }
+}
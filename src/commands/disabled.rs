@@ -0,0 +1,71 @@
+//! Command groups turned off server-wide
+//!
+//! `disabled_command_groups` names groups of the command table, as
+//! `COMMAND DOCS` files them, such as `scripting` or `cluster`. Their
+//! commands are refused on every port, from scripts too, even though the
+//! build has them, so operators can roll capabilities out in stages or keep
+//! what they don't use out of reach. `CONFIG SET` changes the list at
+//! runtime.
+use super::registry::{lookup, COMMANDS};
+use super::Command;
+use crate::protocol::RespValue;
+
+/// Groups that can't be turned off: without them, clients couldn't connect,
+/// and operators couldn't turn the others back on.
+const ALWAYS_ON: [&str; 2] = ["connection", "server"];
+
+/// Checks that `groups` are all groups of the command table that may be
+/// turned off.
+pub fn check_disabled_groups(groups: &[String]) -> Result<(), String> {
+    for group in groups {
+        if ALWAYS_ON.contains(&group.as_str()) {
+            return Err(format!("the {} commands can't be disabled", group));
+        }
+        if !COMMANDS.iter().any(|spec| spec.group == group) {
+            return Err(format!("unknown command group '{}'", group));
+        }
+    }
+    Ok(())
+}
+
+/// The reply to `command` if `disabled` turns its group off.
+pub fn disabled_refusal(disabled: &[String], command: &Command) -> Option<RespValue> {
+    if disabled.is_empty() {
+        return None;
+    }
+    let spec = lookup(command.name().as_bytes())?;
+    disabled.iter().any(|group| group == spec.group).then(|| {
+        RespValue::Error(format!(
+            "ERR '{}' is disabled: the {} commands are turned off on this server",
+            command.name(),
+            spec.group
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_refuses_disabled_groups() {
+        let disabled = vec!["scripting".to_string(), "cluster".to_string()];
+        assert_eq!(check_disabled_groups(&disabled), Ok(()));
+        assert_eq!(
+            disabled_refusal(&disabled, &Command::ScriptFlush),
+            Some(RespValue::Error(
+                "ERR 'script' is disabled: the scripting commands are turned off on this server"
+                    .to_string()
+            ))
+        );
+        assert_eq!(
+            disabled_refusal(&disabled, &Command::Get(Bytes::from("k"))),
+            None
+        );
+        assert_eq!(disabled_refusal(&[], &Command::ScriptFlush), None);
+
+        assert!(check_disabled_groups(&["streams".to_string()]).is_err());
+        assert!(check_disabled_groups(&["server".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+//! Routing commands to the handler of their family
+//!
+//! Each family of commands (strings, keyspace, sets, sorted sets, lists,
+//! clients, admin, scripting) lives in a module of its own with a
+//! [`CommandHandler`], which the command table names for each command.
+//! Handlers run with the command's shards already
+//! locked, so they are plain functions: waiting for the locks is done by [`super::execute`]
+//! beforehand, and logging to the AOF, replication and the change feed by
+//! [`super::execute_locked`] around them, so a handler only reads and
+//! changes the data. Blocking pops don't wait here either; the connection
+//! retries them as lists are pushed to.
+use super::admin::Admin;
+use super::Command;
+use crate::connection::{ClientRegistry, ConnCtx};
+use crate::protocol::RespValue;
+use crate::storage::{Deadline, ShardLocks, StorageError};
+
+/// What a handler may consult besides the locked shards.
+#[derive(Clone, Copy)]
+pub struct Context<'a> {
+    /// The connection the command came from.
+    pub conn: &'a ConnCtx,
+    pub clients: &'a ClientRegistry,
+    /// Bounds reads walking a collection.
+    pub deadline: &'a Deadline,
+}
+
+pub trait CommandHandler: Sync {
+    /// Runs `command`, one of the family's, against `store`.
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError>;
+}
+
+impl Command {
+    /// The handler of the command's family, from its entry in the table;
+    /// `None` for commands answered without storage, or by the connection.
+    pub fn handler(&self) -> Option<&'static dyn CommandHandler> {
+        match self {
+            // Subcommands run apart from the rest of their command.
+            Command::AclStats(_) => Some(&Admin),
+            Command::ClientReply(_) => None,
+            _ => self.spec().handler,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! `INFO`: its sections, which of them a request picks, and the JSON form
+//!
+//! As in Redis, `INFO` alone or `INFO default` gives every section but
+//! `commandstats`, `INFO all` and `INFO everything` give every one, and
+//! section names can be mixed with those. `--json` is an extension: the same
+//! fields as one JSON object per section, numbers as numbers, and the
+//! `key=value,...` lists of `commandstats`, `keyspace` and the replicas as
+//! objects of their own.
+use crate::build_info;
+use crate::connection::ClientRegistry;
+use crate::protocol::RespValue;
+use crate::storage::ShardLocks;
+use serde_json::{Map, Value};
+
+/// Every section in the order they're listed, and whether `default` has it.
+const SECTIONS: &[(&str, bool)] = &[
+    ("server", true),
+    ("clients", true),
+    ("memory", true),
+    ("stats", true),
+    ("persistence", true),
+    ("replication", true),
+    ("commandstats", false),
+    ("keyspace", true),
+];
+
+/// The reply to `INFO` with `requested`, the lowercase section names and
+/// selectors given.
+pub(super) fn reply(
+    store: &ShardLocks,
+    clients: &ClientRegistry,
+    requested: &[String],
+    json: bool,
+) -> RespValue {
+    let info: String = selected(requested)
+        .into_iter()
+        .map(|name| section(name, store, clients))
+        .collect();
+    if json {
+        RespValue::bulk(to_json(&info).to_string())
+    } else {
+        RespValue::bulk(info)
+    }
+}
+
+/// The sections `requested` picks, in listing order.
+fn selected(requested: &[String]) -> Vec<&'static str> {
+    let requested: Vec<&str> = match requested {
+        [] => vec!["default"],
+        requested => requested.iter().map(String::as_str).collect(),
+    };
+    SECTIONS
+        .iter()
+        .filter(|(name, default)| {
+            requested.iter().any(|wanted| match *wanted {
+                "all" | "everything" => true,
+                "default" => *default,
+                wanted => wanted == *name,
+            })
+        })
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+fn section(name: &str, store: &ShardLocks, clients: &ClientRegistry) -> String {
+    match name {
+        "server" => format!(
+            "# Server\r\nredis_version:1.0.0\r\n\
+            rdb_version:{}\r\n\
+            rdb_git_sha1:{}\r\n\
+            rdb_build_profile:{}\r\n",
+            build_info::VERSION,
+            build_info::GIT_HASH,
+            build_info::PROFILE,
+        ),
+        "clients" => clients.info().to_info_section(),
+        "memory" => {
+            let defrag = store.defrag_stats();
+            let alarm = store.memory_alarm();
+            format!(
+                "# Memory\r\nused_memory:{}\r\n\
+                maxmemory:{}\r\n\
+                maxmemory_policy:{}\r\n\
+                mem_alarm:{}\r\n\
+                mem_alarm_threshold:{}\r\n\
+                mem_fragmentation_ratio:{:.2}\r\n\
+                active_defrag_running:{}\r\n\
+                active_defrag_hits:{}\r\n\
+                active_defrag_key_hits:{}\r\n\
+                active_defrag_key_misses:{}\r\n\
+                {}",
+                store.memory_usage(),
+                store.max_memory(),
+                store.maxmemory_policy().as_str(),
+                alarm.is_some() as u8,
+                alarm.unwrap_or(0),
+                store.fragmentation_ratio(),
+                defrag.is_running() as u8,
+                defrag.hits,
+                defrag.key_hits,
+                defrag.key_misses,
+                store.quota_info(),
+            )
+        }
+        "stats" => store.stats().to_info_section(),
+        "persistence" => store.persistence_info(),
+        "replication" => store.replication().map(|r| r.info()).unwrap_or_default(),
+        "commandstats" => store.stats().to_commandstats_section(),
+        "keyspace" => store.keyspace_info(),
+        _ => String::new(),
+    }
+}
+
+/// The `INFO` text as a JSON object of sections, each an object of its
+/// fields.
+fn to_json(info: &str) -> Value {
+    let mut sections = Map::new();
+    let mut fields = None;
+    for line in info.lines() {
+        if let Some(title) = line.strip_prefix("# ") {
+            fields = Some(
+                sections
+                    .entry(title.to_lowercase())
+                    .or_insert_with(|| Value::Object(Map::new())),
+            );
+        } else if let (Some(Value::Object(fields)), Some((name, value))) =
+            (fields.as_mut(), line.split_once(':'))
+        {
+            fields.insert(name.to_string(), field(value));
+        }
+    }
+    Value::Object(sections)
+}
+
+/// A field's value as a JSON number where it's one, an object where it's a
+/// `key=value,...` list, or else a string.
+fn field(value: &str) -> Value {
+    if let Ok(n) = value.parse::<i64>() {
+        return n.into();
+    }
+    if let Some(n) = value
+        .parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+    {
+        return Value::Number(n);
+    }
+    if value.split(',').all(|part| part.contains('=')) {
+        return Value::Object(
+            value
+                .split(',')
+                .filter_map(|part| part.split_once('='))
+                .map(|(name, value)| (name.to_string(), field(value)))
+                .collect(),
+        );
+    }
+    Value::String(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn names(requested: &[&str]) -> Vec<&'static str> {
+        let requested: Vec<String> = requested.iter().map(|s| s.to_string()).collect();
+        selected(&requested)
+    }
+
+    #[test]
+    fn test_selectors() {
+        let default = names(&[]);
+        assert_eq!(default, names(&["default"]));
+        assert!(!default.contains(&"commandstats"));
+        assert_eq!(names(&["all"]).len(), SECTIONS.len());
+        assert_eq!(names(&["everything"]), names(&["all"]));
+        // Mixed, in listing order, each once.
+        assert_eq!(
+            names(&["keyspace", "commandstats", "server", "keyspace"]),
+            vec!["server", "commandstats", "keyspace"]
+        );
+        assert_eq!(names(&["default", "commandstats"]).len(), SECTIONS.len());
+        assert!(names(&["nosuchsection"]).is_empty());
+    }
+
+    #[test]
+    fn test_json() {
+        let info = "# Server\r\nredis_version:1.0.0\r\n\
+            # Memory\r\nused_memory:1024\r\nmem_fragmentation_ratio:1.25\r\n\
+            # Keyspace\r\ndb0:keys=2,expires=0,avg_ttl=0\r\n";
+        assert_eq!(
+            to_json(info),
+            json!({
+                "server": { "redis_version": "1.0.0" },
+                "memory": { "used_memory": 1024, "mem_fragmentation_ratio": 1.25 },
+                "keyspace": { "db0": { "keys": 2, "expires": 0, "avg_ttl": 0 } },
+            })
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Commands on keys and whole databases: `DEL`, `SCAN`, `TTL`, `RESTORE`,
+//! `SELECT`, `FLUSHDB` and the rest
+use super::handler::{CommandHandler, Context};
+use super::{Command, Restore, TimeUnit};
+use crate::protocol::RespValue;
+use crate::storage::{unix_ms, ShardLocks, StorageError};
+use bytes::Bytes;
+
+pub struct Keyspace;
+
+impl CommandHandler for Keyspace {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let Context { deadline, .. } = ctx;
+        let resp = match command {
+            Command::Del(keys) => {
+                let deleted: usize = keys
+                    .iter()
+                    .map(|key| store.shard_mut(key).del(std::slice::from_ref(key)))
+                    .sum();
+                RespValue::Integer(deleted as i64)
+            }
+            Command::Exists(keys) => RespValue::Integer(store.exists(&keys) as i64),
+            Command::Keys(pattern) => keys_reply(store.keys(&pattern, deadline)?),
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+            } => {
+                let (next, keys) = store.scan(cursor, pattern.as_deref(), count)?;
+                RespValue::Array(vec![RespValue::bulk(next.to_string()), keys_reply(keys)])
+            }
+            Command::Type(key) => {
+                RespValue::SimpleString(store.shard(&key).key_type(&key).to_string())
+            }
+            // -2 for a missing key and -1 for one that doesn't expire.
+            Command::Ttl(key, unit) => {
+                let shard = store.shard(&key);
+                let ttl = match (shard.value(&key), shard.expiry(&key)) {
+                    (None, _) => -2,
+                    (Some(_), None) => -1,
+                    (Some(_), Some(at)) => {
+                        let ms = at.saturating_sub(unix_ms()) as i64;
+                        match unit {
+                            TimeUnit::Seconds => (ms + 500) / 1000,
+                            TimeUnit::Milliseconds => ms,
+                        }
+                    }
+                };
+                RespValue::Integer(ttl)
+            }
+            Command::Dump(key) => {
+                RespValue::BulkString(store.shard(&key).dump(&key).map(Bytes::from))
+            }
+            Command::Restore(restore) => {
+                let options = restore.options(unix_ms());
+                let Restore { key, payload, .. } = restore;
+                store.shard_mut(&key).restore(key, &payload, options)?;
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::RandomKey => RespValue::BulkString(store.random_key()),
+            Command::Select(index) => {
+                store.select(index)?;
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::DbSize => RespValue::Integer(store.key_count() as i64),
+            Command::FlushDb => {
+                store.flushdb();
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::FlushAll => {
+                store.flushall();
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::SwapDb(a, b) => {
+                store.swapdb(a, b)?;
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => unreachable!("not a keyspace command"),
+        };
+        Ok(resp)
+    }
+}
+
+fn keys_reply(keys: Vec<Bytes>) -> RespValue {
+    RespValue::Array(keys.into_iter().map(RespValue::bulk).collect())
+}
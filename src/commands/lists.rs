@@ -0,0 +1,70 @@
+//! List commands
+use super::handler::{CommandHandler, Context};
+use super::Command;
+use crate::protocol::RespValue;
+use crate::storage::{End, ShardLocks, StorageError};
+use bytes::Bytes;
+
+pub struct Lists;
+
+impl CommandHandler for Lists {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let Context { deadline, .. } = ctx;
+        let resp = match command {
+            Command::LPush(key, elements) => {
+                RespValue::Integer(store.push(&key, elements, End::Left)? as i64)
+            }
+            Command::RPush(key, elements) => {
+                RespValue::Integer(store.push(&key, elements, End::Right)? as i64)
+            }
+            Command::LPop(key, count) => pop_reply(store, &key, End::Left, count)?,
+            Command::RPop(key, count) => pop_reply(store, &key, End::Right, count)?,
+            Command::LLen(key) => RespValue::Integer(store.shard(&key).llen(&key)? as i64),
+            Command::LRange(key, start, stop) => RespValue::Array(
+                store
+                    .shard(&key)
+                    .lrange(&key, start, stop, deadline)?
+                    .into_iter()
+                    .map(|e| RespValue::bulk(e.clone()))
+                    .collect(),
+            ),
+            Command::BLPop(keys, _) => popped_reply(store.pop_first(&keys, End::Left)?),
+            Command::BRPop(keys, _) => popped_reply(store.pop_first(&keys, End::Right)?),
+            _ => unreachable!("not a list command"),
+        };
+        Ok(resp)
+    }
+}
+
+/// `LPOP`/`RPOP`: one element, or an array of up to `count`; nil if the key
+/// doesn't exist.
+fn pop_reply(
+    store: &mut ShardLocks,
+    key: &Bytes,
+    end: End,
+    count: Option<usize>,
+) -> Result<RespValue, StorageError> {
+    let exists = store.shard(key).value(key).is_some();
+    let popped = store.shard_mut(key).pop(key, end, count.unwrap_or(1))?;
+    Ok(match count {
+        None => RespValue::BulkString(popped.into_iter().next()),
+        Some(_) if !exists => RespValue::NullArray,
+        Some(_) => RespValue::Array(popped.into_iter().map(RespValue::bulk).collect()),
+    })
+}
+
+/// A blocking pop's key and element, or a nil array if every list was
+/// empty.
+fn popped_reply(popped: Option<(Bytes, Bytes)>) -> RespValue {
+    match popped {
+        Some((key, element)) => {
+            RespValue::Array(vec![RespValue::bulk(key), RespValue::bulk(element)])
+        }
+        None => RespValue::NullArray,
+    }
+}
@@ -1,146 +1,1617 @@
-use crate::storage::Db;
+//! Parsing frames into commands, what each command touches, and running
+//! them
+//!
+//! Running a command is left to the handler of its family, one module each;
+//! see [`CommandHandler`].
+mod admin;
+mod blocklist;
+mod clients;
+mod disabled;
+mod handler;
+mod info;
+mod keyspace;
+mod lists;
+mod namespace;
+mod parse;
+mod registry;
+mod scripting;
+mod sets;
+mod strings;
+mod zsets;
+
+pub use blocklist::Blocklist;
+pub use disabled::{check_disabled_groups, disabled_refusal};
+pub use handler::{CommandHandler, Context};
+pub use namespace::execute_locked_in;
+pub use registry::{lookup, CommandSpec, Flag, COMMANDS};
+
+use crate::acl;
+use crate::build_info;
+use crate::changefeed::Change;
+use crate::config::Secret;
+use crate::connection::{ClientRegistry, ConnCtx, KillFilter, ReplyMode, ShutdownRequest};
+use crate::protocol::{parse_resp, RespValue};
+use crate::pubsub::EventClass;
+use crate::storage::{
+    cursor_shard, jittered_ttl, unix_ms, Aggregate, Db, Deadline, RestoreOptions, ScoreBound,
+    SetCondition, SetExpiry, SetOptions, ShardLocks, StorageError, DEFAULT_SAMPLES,
+};
+use bytes::Bytes;
+use std::borrow::Cow;
 use std::str::FromStr;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, PartialEq)]
 pub enum Command {
-    Set(String, String),
-    Get(String),
-    Info,
-    CmdInfo,
-    Memory,
+    Set(Bytes, Bytes, SetOptions),
+    Get(Bytes),
+    GetRange(Bytes, i64, i64),
+    Del(Vec<Bytes>),
+    Exists(Vec<Bytes>),
+    /// `INCR`, `DECR` and `INCRBY`, as the amount to add.
+    IncrBy(Bytes, i64),
+    Append(Bytes, Bytes),
+    StrLen(Bytes),
+    SetNx(Bytes, Bytes),
+    GetSet(Bytes, Bytes),
+    MSet(Vec<(Bytes, Bytes)>),
+    MGet(Vec<Bytes>),
+    Keys(Bytes),
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`.
+    Scan {
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+    },
+    Type(Bytes),
+    /// `TTL` and `PTTL`, whose replies are in seconds or milliseconds.
+    Ttl(Bytes, TimeUnit),
+    Dump(Bytes),
+    Restore(Restore),
+    RandomKey,
+    Select(usize),
+    DbSize,
+    FlushDb,
+    FlushAll,
+    SwapDb(usize, usize),
+    /// `INFO [section ...] [--json]`, the sections lowercase.
+    Info {
+        sections: Vec<String>,
+        json: bool,
+    },
+    /// `COMMAND INFO`, and `COMMAND` alone: the named commands, or all of
+    /// them if none is.
+    CommandInfo(Vec<String>),
+    CommandCount,
+    CommandDocs(Vec<String>),
+    /// `MEMORY USAGE key [SAMPLES count]`, sizing collections from
+    /// `count` of their elements; 0 for all of them.
+    MemoryUsage(Bytes, usize),
+    MemoryStats,
+    /// `EXPIRES NEXT`: the keys expiring soonest, with their TTLs.
+    ExpiresNext {
+        count: usize,
+        pattern: Option<Bytes>,
+    },
+    MemoryDoctor,
+    Features,
+    /// `DEBUG BIGKEYS`: the largest key of each type in the selected
+    /// database.
+    DebugBigKeys,
+    /// `DEBUG LOCKSTATS`: waits for and holds of each shard's lock.
+    DebugLockStats,
     Save,
+    BgSave,
+    BgRewriteAof,
+    /// `CONFIG GET pattern [pattern ...]`.
+    ConfigGet(Vec<String>),
+    ConfigSet(String, String),
+    ConfigRewrite,
+    /// `SLOWLOG GET [count]`; `None` for all entries.
+    SlowlogGet(Option<usize>),
+    SlowlogLen,
+    SlowlogReset,
+    LatencyLatest,
+    LatencyHistory(String),
+    /// `LATENCY RESET [event ...]`; no events resets all of them.
+    LatencyReset(Vec<String>),
+    Auth(Option<String>, Secret),
+    AclGenPass(u32),
+    AclList,
+    /// `ACL STATS [username]`: every user's load, or one's.
+    AclStats(Option<String>),
+    AclWhoAmI,
+    ClientId,
+    ClientGetName,
+    /// `CLIENT SETNAME`; an empty name clears it.
+    ClientSetName(String),
+    /// `CLIENT REPLY ON|OFF|SKIP`.
+    ClientReply(ReplyMode),
+    ClientList,
+    /// `CLIENT KILL`, in the old form naming one address if `legacy`.
+    ClientKill {
+        filter: KillFilter,
+        legacy: bool,
+    },
+    /// `HELLO [protover [AUTH username password]]`.
+    Hello {
+        protover: Option<i64>,
+        auth: Option<(String, Secret)>,
+    },
+    SAdd(Bytes, Vec<Bytes>),
+    SRem(Bytes, Vec<Bytes>),
+    SMembers(Bytes),
+    SIsMember(Bytes, Bytes),
+    /// `SPOP key [count]`; without a count the reply is a single member.
+    SPop(Bytes, Option<usize>),
+    /// `SRANDMEMBER key [count]`: a negative count may repeat members.
+    SRandMember(Bytes, Option<i64>),
+    SInter(Vec<Bytes>),
+    SUnion(Vec<Bytes>),
+    SInterStore(Bytes, Vec<Bytes>),
+    SUnionStore(Bytes, Vec<Bytes>),
+    ZAdd(Bytes, Vec<(f64, Bytes)>),
+    ZScore(Bytes, Bytes),
+    ZRange {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+        withscores: bool,
+    },
+    ZRangeByScore {
+        key: Bytes,
+        min: ScoreBound,
+        max: ScoreBound,
+        withscores: bool,
+        limit: Option<(i64, i64)>,
+    },
+    ZRem(Bytes, Vec<Bytes>),
+    ZUnionStore(ZStore),
+    ZInterStore(ZStore),
+    LPush(Bytes, Vec<Bytes>),
+    RPush(Bytes, Vec<Bytes>),
+    /// `LPOP key [count]`; without a count the reply is a single element.
+    LPop(Bytes, Option<usize>),
+    RPop(Bytes, Option<usize>),
+    LLen(Bytes),
+    LRange(Bytes, i64, i64),
+    /// `BLPOP key [key ...] timeout`; a zero timeout waits forever. Only
+    /// the connection waits: run directly, as in a transaction, it pops or
+    /// replies nil right away.
+    BLPop(Vec<Bytes>, Duration),
+    BRPop(Vec<Bytes>, Duration),
+    Subscribe(Vec<Bytes>),
+    Unsubscribe(Vec<Bytes>),
+    PSubscribe(Vec<Bytes>),
+    PUnsubscribe(Vec<Bytes>),
+    Publish(Bytes, Bytes),
+    /// `PUBSUB HISTORY`: the last messages kept for a channel, or all of
+    /// them.
+    PubSubHistory(Bytes, Option<usize>),
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<Bytes>),
+    Unwatch,
+    Ping(Option<Bytes>),
+    Quit,
+    Reset,
+    Shutdown(ShutdownRequest),
+    /// `REPLICAOF host port`, or `REPLICAOF NO ONE` as `None`.
+    ReplicaOf(Option<(String, u16)>),
+    ReplConf(Vec<Bytes>),
+    Psync,
+    /// `CDC TAIL [MATCH pattern]`.
+    CdcTail(Option<Bytes>),
+    Monitor,
+    ClusterSlots,
+    ClusterShards,
+    ClusterKeySlot(Bytes),
+    /// `EVAL script numkeys [key ...] [arg ...]`.
+    Eval {
+        script: Bytes,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    },
+    EvalSha {
+        sha: Bytes,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    },
+    ScriptLoad(Bytes),
+    ScriptExists(Vec<Bytes>),
+    ScriptFlush,
+}
+
+/// Arguments of `ZUNIONSTORE` and `ZINTERSTORE`. `weights` has one entry
+/// per key.
+#[derive(Debug, PartialEq)]
+pub struct ZStore {
+    pub dest: Bytes,
+    pub keys: Vec<Bytes>,
+    pub weights: Vec<f64>,
+    pub aggregate: Aggregate,
+}
+
+impl ZStore {
+    fn args(&self, name: &'static str) -> Vec<Bytes> {
+        let mut args = vec![
+            Bytes::from(name),
+            self.dest.clone(),
+            self.keys.len().to_string().into(),
+        ];
+        args.extend_from_slice(&self.keys);
+        args.push("WEIGHTS".into());
+        args.extend(self.weights.iter().map(|w| Bytes::from(format_score(*w))));
+        let aggregate = match self.aggregate {
+            Aggregate::Sum => "SUM",
+            Aggregate::Min => "MIN",
+            Aggregate::Max => "MAX",
+        };
+        args.extend(["AGGREGATE".into(), aggregate.into()]);
+        args
+    }
+}
+
+/// `RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds]
+/// [FREQ frequency]`.
+#[derive(Debug, PartialEq)]
+pub struct Restore {
+    pub key: Bytes,
+    /// Milliseconds to live, or a Unix time in milliseconds if `absttl`;
+    /// 0 for no expiry.
+    pub ttl: u64,
+    pub payload: Bytes,
+    pub replace: bool,
+    pub absttl: bool,
+    /// Seconds since the key was last accessed.
+    pub idle: Option<u64>,
+    /// The key's access frequency as Redis' LFU policies count it. Without
+    /// those policies it's accepted but has no use.
+    pub freq: Option<u8>,
+}
+
+impl Restore {
+    fn parse(args: &[Bytes]) -> Result<Self, CommandError> {
+        if args.len() < 4 {
+            return Err(wrong_arity(args));
+        }
+        let ttl = u64::try_from(parse_integer(&args[2])?).map_err(|_| {
+            CommandError::InvalidArgument("Invalid TTL value, must be >= 0".to_string())
+        })?;
+        let mut restore = Restore {
+            key: args[1].clone(),
+            ttl,
+            payload: args[3].clone(),
+            replace: false,
+            absttl: false,
+            idle: None,
+            freq: None,
+        };
+        let mut i = 4;
+        while i < args.len() {
+            let value = args.get(i + 1);
+            match text(&args[i]).to_uppercase().as_str() {
+                "REPLACE" => restore.replace = true,
+                "ABSTTL" => restore.absttl = true,
+                "IDLETIME" if restore.freq.is_none() => {
+                    let idle = parse_integer(value.ok_or(CommandError::SyntaxError)?)?;
+                    restore.idle = Some(u64::try_from(idle).map_err(|_| {
+                        CommandError::InvalidArgument(
+                            "Invalid IDLETIME value, must be >= 0".to_string(),
+                        )
+                    })?);
+                    i += 1;
+                }
+                "FREQ" if restore.idle.is_none() => {
+                    let freq = parse_integer(value.ok_or(CommandError::SyntaxError)?)?;
+                    restore.freq = Some(u8::try_from(freq).map_err(|_| {
+                        CommandError::InvalidArgument(
+                            "Invalid FREQ value, must be >= 0 and <= 255".to_string(),
+                        )
+                    })?);
+                    i += 1;
+                }
+                _ => return Err(CommandError::SyntaxError),
+            }
+            i += 1;
+        }
+        Ok(restore)
+    }
+
+    /// How to store the key, with its TTL made absolute as of `now`.
+    fn options(&self, now: u64) -> RestoreOptions {
+        let expires_at = match (self.ttl, self.absttl) {
+            (0, _) => None,
+            (at, true) => Some(at),
+            (ttl, false) => Some(now.saturating_add(ttl)),
+        };
+        RestoreOptions {
+            replace: self.replace,
+            expires_at,
+            idle_ms: self.idle.map(|idle| idle.saturating_mul(1000)),
+        }
+    }
+
+    fn args(&self) -> Vec<Bytes> {
+        let mut args = vec![
+            "RESTORE".into(),
+            self.key.clone(),
+            self.ttl.to_string().into(),
+            self.payload.clone(),
+        ];
+        if self.replace {
+            args.push("REPLACE".into());
+        }
+        if self.absttl {
+            args.push("ABSTTL".into());
+        }
+        if let Some(idle) = self.idle {
+            args.extend(["IDLETIME".into(), idle.to_string().into()]);
+        }
+        if let Some(freq) = self.freq {
+            args.extend(["FREQ".into(), freq.to_string().into()]);
+        }
+        args
+    }
 }
 
-#[derive(Error, Debug)]
+/// Why a request isn't a valid command. Each displays as the error reply
+/// Redis gives, word for word, since clients and their test suites match on
+/// it.
+#[derive(Error, Debug, PartialEq)]
 pub enum CommandError {
-    #[error("invalid command format")]
+    #[error("ERR Protocol error: expected an array of bulk strings")]
     InvalidFormat,
-    #[error("unknown command: {0}")]
-    UnknownCommand(String),
-    #[error("wrong number of arguments for command")]
-    WrongNumberOfArguments,
+    /// The name as given, and its arguments quoted as Redis lists them.
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+    /// The subcommand as given, and the command's name in uppercase.
+    #[error("ERR unknown subcommand '{0}'. Try {1} HELP.")]
+    UnknownSubcommand(String, String),
+    /// The lowercase name, as `command|subcommand` for subcommands.
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongNumberOfArguments(String),
+    #[error("ERR {0}")]
+    InvalidArgument(String),
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR value is not a valid float")]
+    NotAFloat,
+    #[error("ERR min or max is not a float")]
+    InvalidScoreRange,
+    #[error("ERR syntax error")]
+    SyntaxError,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(&'static str),
+}
+
+/// Longest prefix of a name or of the arguments quoted in an error, as in
+/// Redis.
+const ERROR_ARGS_LEN: usize = 128;
+
+/// The error for a command `args` gives the wrong number of arguments.
+fn wrong_arity(args: &[Bytes]) -> CommandError {
+    CommandError::WrongNumberOfArguments(text(&args[0]).to_lowercase())
+}
+
+/// The error for a subcommand, the second of `args`, given the wrong number
+/// of arguments.
+fn wrong_subcommand_arity(args: &[Bytes]) -> CommandError {
+    CommandError::WrongNumberOfArguments(format!(
+        "{}|{}",
+        text(&args[0]).to_lowercase(),
+        text(&args[1]).to_lowercase()
+    ))
+}
+
+/// The error for a command nobody knows, quoting as many of its arguments
+/// as fit.
+fn unknown_command(args: &[Bytes]) -> CommandError {
+    let mut quoted = String::new();
+    for arg in &args[1..] {
+        if quoted.len() >= ERROR_ARGS_LEN {
+            break;
+        }
+        let arg = text(arg);
+        let room = ERROR_ARGS_LEN - quoted.len();
+        quoted.push('\'');
+        quoted.extend(arg.chars().take(room));
+        quoted.push_str("' ");
+    }
+    CommandError::UnknownCommand(
+        text(&args[0]).chars().take(ERROR_ARGS_LEN).collect(),
+        quoted,
+    )
+}
+
+/// The error for an unknown subcommand, the second of `args`.
+fn unknown_subcommand(args: &[Bytes]) -> CommandError {
+    CommandError::UnknownSubcommand(
+        text(&args[1]).chars().take(ERROR_ARGS_LEN).collect(),
+        text(&args[0]).to_uppercase(),
+    )
+}
+
+/// The unit a TTL is given or reported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    Seconds,
+    Milliseconds,
+}
+
+fn parse_integer(arg: &[u8]) -> Result<i64, CommandError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(CommandError::NotAnInteger)
+}
+
+/// Parses the time following `SET`'s `EX`, `PX`, `EXAT` or `PXAT`, in
+/// units of `unit_ms` milliseconds, into milliseconds.
+fn parse_expire_time(arg: Option<&Bytes>, unit_ms: u64) -> Result<u64, CommandError> {
+    let time = parse_integer(arg.ok_or(CommandError::SyntaxError)?)?;
+    u64::try_from(time)
+        .ok()
+        .filter(|&time| time > 0)
+        .and_then(|time| time.checked_mul(unit_ms))
+        .filter(|&ms| ms <= i64::MAX as u64)
+        .ok_or(CommandError::InvalidExpireTime("set"))
+}
+
+/// Parses a database number. Negative ones come out too large for any
+/// configuration, so they're rejected as out of range like those.
+fn parse_db_index(arg: &[u8]) -> Result<usize, CommandError> {
+    Ok(usize::try_from(parse_integer(arg)?).unwrap_or(usize::MAX))
+}
+
+fn parse_float(arg: &[u8]) -> Result<f64, CommandError> {
+    let arg = std::str::from_utf8(arg).map_err(|_| CommandError::NotAFloat)?;
+    match arg.to_lowercase().as_str() {
+        "inf" | "+inf" => Ok(f64::INFINITY),
+        "-inf" => Ok(f64::NEG_INFINITY),
+        s => match s.parse::<f64>() {
+            Ok(score) if score.is_finite() => Ok(score),
+            _ => Err(CommandError::NotAFloat),
+        },
+    }
+}
+
+/// Parses a blocking command's timeout, in seconds with an optional
+/// fraction.
+fn parse_timeout(arg: &[u8]) -> Result<Duration, CommandError> {
+    let invalid = |reason: &str| CommandError::InvalidArgument(format!("timeout {}", reason));
+    let seconds = parse_float(arg).map_err(|_| invalid("is not a float or out of range"))?;
+    if seconds < 0.0 {
+        return Err(invalid("is negative"));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|_| invalid("is out of range"))
+}
+
+/// Parses a `ZRANGEBYSCORE` bound: a score, optionally prefixed with `(` to
+/// make it exclusive.
+fn parse_score_bound(arg: &[u8]) -> Result<ScoreBound, CommandError> {
+    match arg.strip_prefix(b"(") {
+        Some(score) => parse_float(score).map(ScoreBound::Exclusive),
+        None => parse_float(arg).map(ScoreBound::Inclusive),
+    }
+    .map_err(|_| CommandError::InvalidScoreRange)
+}
+
+/// Lossy text view of an argument, for names, options and numbers.
+fn text(arg: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(arg)
+}
+
+/// `SELECT db`, written into the AOF and the replication stream ahead of
+/// commands that ran in another database than the ones before.
+pub fn select_frame(db: usize) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::bulk("SELECT"),
+        RespValue::bulk(db.to_string()),
+    ])
+}
+
+/// Formats a score the way Redis replies with it.
+pub fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+/// Splits `numkeys [key ...] [arg ...]` of `EVAL` and `EVALSHA` into the
+/// keys and the other arguments.
+fn parse_script_args(args: &[Bytes]) -> Result<(Vec<Bytes>, Vec<Bytes>), CommandError> {
+    let numkeys = parse_integer(&args[0])?;
+    let numkeys = usize::try_from(numkeys).map_err(|_| {
+        CommandError::InvalidArgument("Number of keys can't be negative".to_string())
+    })?;
+    if numkeys > args.len() - 1 {
+        return Err(CommandError::InvalidArgument(
+            "Number of keys can't be greater than number of args".to_string(),
+        ));
+    }
+    let (keys, rest) = args[1..].split_at(numkeys);
+    Ok((keys.to_vec(), rest.to_vec()))
+}
+
+/// Parses `ZUNIONSTORE` or `ZINTERSTORE`, as named first in `args`, with
+/// `dest numkeys key [key ...] [WEIGHTS w ...] [AGGREGATE f]` after it.
+fn parse_zstore(args: &[Bytes]) -> Result<ZStore, CommandError> {
+    if args.len() < 4 {
+        return Err(wrong_arity(args));
+    }
+    let (name, args) = (text(&args[0]).to_lowercase(), &args[1..]);
+    let numkeys = usize::try_from(parse_integer(&args[1])?)
+        .ok()
+        .filter(|&numkeys| numkeys > 0)
+        .ok_or_else(|| {
+            CommandError::InvalidArgument(format!(
+                "at least 1 input key is needed for '{}' command",
+                name
+            ))
+        })?;
+    if args.len() < 2 + numkeys {
+        return Err(CommandError::SyntaxError);
+    }
+    let mut zstore = ZStore {
+        dest: args[0].clone(),
+        keys: args[2..2 + numkeys].to_vec(),
+        weights: vec![1.0; numkeys],
+        aggregate: Aggregate::Sum,
+    };
+
+    let mut i = 2 + numkeys;
+    while i < args.len() {
+        match text(&args[i]).to_uppercase().as_str() {
+            "WEIGHTS" if i + numkeys < args.len() => {
+                for (weight, arg) in zstore.weights.iter_mut().zip(&args[i + 1..]) {
+                    *weight = parse_float(arg)?;
+                }
+                i += 1 + numkeys;
+            }
+            "AGGREGATE" if i + 1 < args.len() => {
+                zstore.aggregate = match text(&args[i + 1]).to_uppercase().as_str() {
+                    "SUM" => Aggregate::Sum,
+                    "MIN" => Aggregate::Min,
+                    "MAX" => Aggregate::Max,
+                    _ => return Err(CommandError::SyntaxError),
+                };
+                i += 2;
+            }
+            _ => return Err(CommandError::SyntaxError),
+        }
+    }
+    Ok(zstore)
 }
 
 impl FromStr for Command {
     type Err = CommandError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let lines: Vec<&str> = s.split("\r\n").collect();
-        if lines.is_empty() {
+        let (frame, _) = parse_resp(s.as_bytes()).map_err(|_| CommandError::InvalidFormat)?;
+        Command::from_frame(frame)
+    }
+}
+
+impl Command {
+    /// Builds a command from a request frame: an array of bulk strings.
+    pub fn from_frame(frame: RespValue) -> Result<Self, CommandError> {
+        let items = match frame {
+            RespValue::Array(items) => items,
+            _ => return Err(CommandError::InvalidFormat),
+        };
+        let args = items
+            .into_iter()
+            .map(|item| match item {
+                RespValue::BulkString(Some(arg)) => Ok(arg),
+                _ => Err(CommandError::InvalidFormat),
+            })
+            .collect::<Result<Vec<Bytes>, _>>()?;
+
+        if args.is_empty() {
             return Err(CommandError::InvalidFormat);
         }
 
-        if !lines[0].starts_with('*') {
-            return Err(CommandError::InvalidFormat);
+        let spec = registry::lookup(&args[0]).ok_or_else(|| unknown_command(&args))?;
+        if !spec.accepts(args.len()) {
+            return Err(wrong_arity(&args));
+        }
+        (spec.parse)(&args)
+    }
+}
+
+/// Command classes that can be given separate execution time limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandClass {
+    Read,
+    Write,
+    Admin,
+    Connection,
+    PubSub,
+}
+
+impl CommandClass {
+    pub const ALL: [CommandClass; 5] = [
+        CommandClass::Read,
+        CommandClass::Write,
+        CommandClass::Admin,
+        CommandClass::Connection,
+        CommandClass::PubSub,
+    ];
+
+    /// The ACL category name, without the `@`.
+    pub fn category(&self) -> &'static str {
+        match self {
+            CommandClass::Read => "read",
+            CommandClass::Write => "write",
+            CommandClass::Admin => "admin",
+            CommandClass::Connection => "connection",
+            CommandClass::PubSub => "pubsub",
+        }
+    }
+}
+
+impl Command {
+    /// Lowercase command name, as used in error messages and the command
+    /// table.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set(..) => "set",
+            Command::Get(_) => "get",
+            Command::GetRange(..) => "getrange",
+            Command::Del(_) => "del",
+            Command::Exists(_) => "exists",
+            Command::IncrBy(..) => "incrby",
+            Command::Append(..) => "append",
+            Command::StrLen(_) => "strlen",
+            Command::SetNx(..) => "setnx",
+            Command::GetSet(..) => "getset",
+            Command::MSet(_) => "mset",
+            Command::MGet(_) => "mget",
+            Command::Keys(_) => "keys",
+            Command::Scan { .. } => "scan",
+            Command::Type(_) => "type",
+            Command::Ttl(_, TimeUnit::Seconds) => "ttl",
+            Command::Ttl(_, TimeUnit::Milliseconds) => "pttl",
+            Command::Dump(_) => "dump",
+            Command::Restore(_) => "restore",
+            Command::RandomKey => "randomkey",
+            Command::Select(_) => "select",
+            Command::DbSize => "dbsize",
+            Command::FlushDb => "flushdb",
+            Command::FlushAll => "flushall",
+            Command::SwapDb(..) => "swapdb",
+            Command::Info { .. } => "info",
+            Command::CommandInfo(_) | Command::CommandCount | Command::CommandDocs(_) => "command",
+            Command::MemoryUsage(..) | Command::MemoryStats | Command::MemoryDoctor => "memory",
+            Command::ExpiresNext { .. } => "expires",
+            Command::Features => "features",
+            Command::DebugBigKeys | Command::DebugLockStats => "debug",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::BgRewriteAof => "bgrewriteaof",
+            Command::ConfigGet(_) | Command::ConfigSet(..) | Command::ConfigRewrite => "config",
+            Command::SlowlogGet(_) | Command::SlowlogLen | Command::SlowlogReset => "slowlog",
+            Command::LatencyLatest | Command::LatencyHistory(_) | Command::LatencyReset(_) => {
+                "latency"
+            }
+            Command::Auth(..) => "auth",
+            Command::AclGenPass(_)
+            | Command::AclList
+            | Command::AclStats(_)
+            | Command::AclWhoAmI => "acl",
+            Command::ClientId
+            | Command::ClientGetName
+            | Command::ClientSetName(_)
+            | Command::ClientReply(_)
+            | Command::ClientList
+            | Command::ClientKill { .. } => "client",
+            Command::Hello { .. } => "hello",
+            Command::SAdd(..) => "sadd",
+            Command::SRem(..) => "srem",
+            Command::SMembers(_) => "smembers",
+            Command::SIsMember(..) => "sismember",
+            Command::SPop(..) => "spop",
+            Command::SRandMember(..) => "srandmember",
+            Command::SInter(_) => "sinter",
+            Command::SUnion(_) => "sunion",
+            Command::SInterStore(..) => "sinterstore",
+            Command::SUnionStore(..) => "sunionstore",
+            Command::ZAdd(..) => "zadd",
+            Command::ZScore(..) => "zscore",
+            Command::ZRange { .. } => "zrange",
+            Command::ZRangeByScore { .. } => "zrangebyscore",
+            Command::ZRem(..) => "zrem",
+            Command::ZUnionStore(_) => "zunionstore",
+            Command::ZInterStore(_) => "zinterstore",
+            Command::LPush(..) => "lpush",
+            Command::RPush(..) => "rpush",
+            Command::LPop(..) => "lpop",
+            Command::RPop(..) => "rpop",
+            Command::LLen(_) => "llen",
+            Command::LRange(..) => "lrange",
+            Command::BLPop(..) => "blpop",
+            Command::BRPop(..) => "brpop",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::PUnsubscribe(_) => "punsubscribe",
+            Command::Publish(..) => "publish",
+            Command::PubSubHistory(..) => "pubsub",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::Unwatch => "unwatch",
+            Command::Ping(_) => "ping",
+            Command::Quit => "quit",
+            Command::Reset => "reset",
+            Command::Shutdown(_) => "shutdown",
+            Command::ReplicaOf(_) => "replicaof",
+            Command::ReplConf(_) => "replconf",
+            Command::Psync => "psync",
+            Command::CdcTail(_) => "cdc",
+            Command::Monitor => "monitor",
+            Command::ClusterSlots | Command::ClusterShards | Command::ClusterKeySlot(_) => {
+                "cluster"
+            }
+            Command::Eval { .. } => "eval",
+            Command::EvalSha { .. } => "evalsha",
+            Command::ScriptLoad(_) | Command::ScriptExists(_) | Command::ScriptFlush => "script",
         }
+    }
 
-        let mut args = Vec::new();
-        let mut i = 1;
-        while i < lines.len() {
-            if lines[i].starts_with('$') {
-                if i + 1 < lines.len() {
-                    args.push(lines[i + 1]);
-                    i += 2;
+    /// Whether a connection in subscribe mode may run this command.
+    pub fn allowed_while_subscribed(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::Ping(_)
+                | Command::Quit
+                | Command::Reset
+        )
+    }
+
+    /// The command with the relative TTL of `SET` lengthened by up to
+    /// `percent`, as `ttl_jitter_percent` asks.
+    pub fn with_ttl_jitter(self, percent: u8) -> Self {
+        match self {
+            Command::Set(key, value, mut options) if percent > 0 => {
+                if let SetExpiry::After(ms) = options.expiry {
+                    options.expiry = SetExpiry::After(jittered_ttl(&key, ms, percent));
                 }
-            } else {
-                i += 1;
+                Command::Set(key, value, options)
             }
+            command => command,
         }
+    }
 
-        if args.is_empty() {
-            return Err(CommandError::InvalidFormat);
+    /// The command with relative expiry times turned into Unix times in
+    /// milliseconds, counted from `now`. Run like this, the key gets the
+    /// same expiry as the write propagated will give it on a replica, or
+    /// replayed from the AOF, however much later and by whatever clock.
+    pub fn with_absolute_expiry(self, now: u64) -> Self {
+        match self {
+            Command::Set(key, value, mut options) => {
+                if let SetExpiry::After(ms) = options.expiry {
+                    options.expiry = SetExpiry::At(now.saturating_add(ms));
+                }
+                Command::Set(key, value, options)
+            }
+            Command::Restore(mut restore) if !restore.absttl && restore.ttl > 0 => {
+                restore.ttl = now.saturating_add(restore.ttl);
+                restore.absttl = true;
+                Command::Restore(restore)
+            }
+            command => command,
         }
+    }
 
-        match args[0].to_uppercase().as_str() {
-            "SET" => {
-                if args.len() != 3 {
-                    return Err(CommandError::WrongNumberOfArguments);
+    /// The frame logged to the AOF and streamed to replicas for a write
+    /// command, re-encoded from its parsed arguments; `None` for commands
+    /// that don't modify data.
+    pub fn propagation(&self) -> Option<RespValue> {
+        let with_key = |name: &'static str, key: &Bytes, rest: &[Bytes]| {
+            let mut args = vec![Bytes::from(name), key.clone()];
+            args.extend_from_slice(rest);
+            args
+        };
+        let args = match self {
+            // Propagated only if the value was stored, so the condition
+            // held, and the replaced value is only for the reply.
+            Command::Set(key, value, options) => {
+                let mut args = vec!["SET".into(), key.clone(), value.clone()];
+                match options.expiry {
+                    SetExpiry::Persist => {}
+                    SetExpiry::Keep => args.push("KEEPTTL".into()),
+                    SetExpiry::After(ms) => args.extend(["PX".into(), ms.to_string().into()]),
+                    SetExpiry::At(ms) => args.extend(["PXAT".into(), ms.to_string().into()]),
+                }
+                args
+            }
+            Command::Del(keys) => with_key("DEL", &keys[0], &keys[1..]),
+            Command::Restore(restore) => restore.args(),
+            Command::IncrBy(key, delta) => {
+                vec!["INCRBY".into(), key.clone(), delta.to_string().into()]
+            }
+            Command::Append(key, value) => vec!["APPEND".into(), key.clone(), value.clone()],
+            Command::SetNx(key, value) => vec!["SETNX".into(), key.clone(), value.clone()],
+            Command::GetSet(key, value) => vec!["GETSET".into(), key.clone(), value.clone()],
+            Command::FlushDb => vec!["FLUSHDB".into()],
+            Command::FlushAll => vec!["FLUSHALL".into()],
+            Command::SwapDb(a, b) => {
+                vec!["SWAPDB".into(), a.to_string().into(), b.to_string().into()]
+            }
+            Command::MSet(pairs) => {
+                let mut args = vec!["MSET".into()];
+                for (key, value) in pairs {
+                    args.extend([key.clone(), value.clone()]);
+                }
+                args
+            }
+            Command::SAdd(key, members) => with_key("SADD", key, members),
+            Command::SRem(key, members) => with_key("SREM", key, members),
+            Command::SInterStore(dest, keys) => with_key("SINTERSTORE", dest, keys),
+            Command::SUnionStore(dest, keys) => with_key("SUNIONSTORE", dest, keys),
+            Command::ZAdd(key, members) => {
+                let mut args = vec!["ZADD".into(), key.clone()];
+                for (score, member) in members {
+                    args.push(format_score(*score).into());
+                    args.push(member.clone());
                 }
-                Ok(Command::Set(args[1].to_string(), args[2].to_string()))
+                args
+            }
+            Command::ZRem(key, members) => with_key("ZREM", key, members),
+            Command::ZUnionStore(zstore) => zstore.args("ZUNIONSTORE"),
+            Command::ZInterStore(zstore) => zstore.args("ZINTERSTORE"),
+            Command::LPush(key, elements) => with_key("LPUSH", key, elements),
+            Command::RPush(key, elements) => with_key("RPUSH", key, elements),
+            Command::LPop(key, count) | Command::RPop(key, count) => {
+                let name = if matches!(self, Command::LPop(..)) {
+                    "LPOP"
+                } else {
+                    "RPOP"
+                };
+                let mut args = vec![name.into(), key.clone()];
+                args.extend(count.map(|count| Bytes::from(count.to_string())));
+                args
+            }
+            // Replayed without blocking, these pop off the same list again.
+            Command::BLPop(keys, timeout) | Command::BRPop(keys, timeout) => {
+                let name = if matches!(self, Command::BLPop(..)) {
+                    "BLPOP"
+                } else {
+                    "BRPOP"
+                };
+                let mut args = vec![Bytes::from(name)];
+                args.extend_from_slice(keys);
+                args.push(timeout.as_secs_f64().to_string().into());
+                args
+            }
+            _ => return None,
+        };
+        Some(RespValue::Array(
+            args.into_iter().map(RespValue::bulk).collect(),
+        ))
+    }
+
+    /// The arguments a reply is cached under, re-encoded, for the reads
+    /// that are worth caching: those taking time in proportion to the
+    /// members they go through, with a reply that depends on nothing but
+    /// their arguments and the values of their keys. `None` for the rest.
+    pub fn cache_key(&self) -> Option<Vec<Bytes>> {
+        let bound = |bound: &ScoreBound| -> Bytes {
+            match bound {
+                ScoreBound::Inclusive(score) => format_score(*score).into(),
+                ScoreBound::Exclusive(score) => format!("({}", format_score(*score)).into(),
+            }
+        };
+        let args = match self {
+            Command::SInter(keys) | Command::SUnion(keys) => {
+                let name = if matches!(self, Command::SInter(_)) {
+                    "SINTER"
+                } else {
+                    "SUNION"
+                };
+                let mut args = vec![Bytes::from(name)];
+                args.extend_from_slice(keys);
+                args
             }
-            "GET" => {
-                if args.len() != 2 {
-                    return Err(CommandError::WrongNumberOfArguments);
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                withscores,
+            } => {
+                let mut args = vec![
+                    "ZRANGE".into(),
+                    key.clone(),
+                    start.to_string().into(),
+                    stop.to_string().into(),
+                ];
+                args.extend(withscores.then(|| Bytes::from("WITHSCORES")));
+                args
+            }
+            Command::ZRangeByScore {
+                key,
+                min,
+                max,
+                withscores,
+                limit,
+            } => {
+                let mut args = vec!["ZRANGEBYSCORE".into(), key.clone(), bound(min), bound(max)];
+                args.extend(withscores.then(|| Bytes::from("WITHSCORES")));
+                if let Some((offset, count)) = limit {
+                    args.push("LIMIT".into());
+                    args.push(offset.to_string().into());
+                    args.push(count.to_string().into());
                 }
-                Ok(Command::Get(args[1].to_string()))
+                args
+            }
+            _ => return None,
+        };
+        Some(args)
+    }
+
+    /// The keys the command reads or writes, whose shards it locks.
+    pub fn keys(&self) -> Vec<Bytes> {
+        match self {
+            Command::Set(key, ..)
+            | Command::Get(key)
+            | Command::GetRange(key, ..)
+            | Command::Type(key)
+            | Command::Ttl(key, _)
+            | Command::Dump(key)
+            | Command::Restore(Restore { key, .. })
+            | Command::IncrBy(key, _)
+            | Command::Append(key, _)
+            | Command::StrLen(key)
+            | Command::SetNx(key, _)
+            | Command::GetSet(key, _)
+            | Command::SAdd(key, _)
+            | Command::SRem(key, _)
+            | Command::SMembers(key)
+            | Command::SIsMember(key, _)
+            | Command::SPop(key, _)
+            | Command::SRandMember(key, _)
+            | Command::ZAdd(key, _)
+            | Command::ZScore(key, _)
+            | Command::ZRange { key, .. }
+            | Command::ZRangeByScore { key, .. }
+            | Command::ZRem(key, _)
+            | Command::LPush(key, _)
+            | Command::RPush(key, _)
+            | Command::LPop(key, _)
+            | Command::RPop(key, _)
+            | Command::LLen(key)
+            | Command::LRange(key, ..)
+            | Command::MemoryUsage(key, _) => vec![key.clone()],
+            Command::Del(keys)
+            | Command::Exists(keys)
+            | Command::MGet(keys)
+            | Command::SInter(keys)
+            | Command::SUnion(keys)
+            | Command::BLPop(keys, _)
+            | Command::BRPop(keys, _)
+            | Command::Watch(keys) => keys.clone(),
+            // Only checked against ACLs: scripts lock every shard, as they
+            // may touch keys they didn't declare.
+            Command::Eval { keys, .. } | Command::EvalSha { keys, .. } => keys.clone(),
+            Command::MSet(pairs) => pairs.iter().map(|(key, _)| key.clone()).collect(),
+            Command::SInterStore(dest, keys) | Command::SUnionStore(dest, keys) => {
+                let mut all = vec![dest.clone()];
+                all.extend_from_slice(keys);
+                all
+            }
+            Command::ZUnionStore(zstore) | Command::ZInterStore(zstore) => {
+                let mut all = vec![zstore.dest.clone()];
+                all.extend_from_slice(&zstore.keys);
+                all
             }
-            "INFO" => Ok(Command::Info),
-            "COMMAND" => Ok(Command::CmdInfo),
-            "MEMORY" => Ok(Command::Memory),
-            "SAVE" => Ok(Command::Save),
-            cmd => Err(CommandError::UnknownCommand(cmd.to_string())),
+            _ => vec![],
         }
     }
-}
 
-use crate::protocol::RespValue;
+    /// The command with each of its keys replaced by `f` of it. Scripts
+    /// keep theirs; the commands they run are translated instead.
+    pub fn map_keys(mut self, f: impl Fn(&Bytes) -> Bytes) -> Self {
+        match &mut self {
+            Command::Set(key, ..)
+            | Command::Get(key)
+            | Command::GetRange(key, ..)
+            | Command::Type(key)
+            | Command::Ttl(key, _)
+            | Command::Dump(key)
+            | Command::Restore(Restore { key, .. })
+            | Command::IncrBy(key, _)
+            | Command::Append(key, _)
+            | Command::StrLen(key)
+            | Command::SetNx(key, _)
+            | Command::GetSet(key, _)
+            | Command::SAdd(key, _)
+            | Command::SRem(key, _)
+            | Command::SMembers(key)
+            | Command::SIsMember(key, _)
+            | Command::SPop(key, _)
+            | Command::SRandMember(key, _)
+            | Command::ZAdd(key, _)
+            | Command::ZScore(key, _)
+            | Command::ZRange { key, .. }
+            | Command::ZRangeByScore { key, .. }
+            | Command::ZRem(key, _)
+            | Command::LPush(key, _)
+            | Command::RPush(key, _)
+            | Command::LPop(key, _)
+            | Command::RPop(key, _)
+            | Command::LLen(key)
+            | Command::LRange(key, ..)
+            | Command::MemoryUsage(key, _) => *key = f(key),
+            Command::Del(keys)
+            | Command::Exists(keys)
+            | Command::MGet(keys)
+            | Command::SInter(keys)
+            | Command::SUnion(keys)
+            | Command::BLPop(keys, _)
+            | Command::BRPop(keys, _)
+            | Command::Watch(keys) => keys.iter_mut().for_each(|key| *key = f(key)),
+            Command::MSet(pairs) => pairs.iter_mut().for_each(|(key, _)| *key = f(key)),
+            Command::SInterStore(dest, keys) | Command::SUnionStore(dest, keys) => {
+                *dest = f(dest);
+                keys.iter_mut().for_each(|key| *key = f(key));
+            }
+            Command::ZUnionStore(zstore) | Command::ZInterStore(zstore) => {
+                zstore.dest = f(&zstore.dest);
+                zstore.keys.iter_mut().for_each(|key| *key = f(key));
+            }
+            _ => {}
+        }
+        self
+    }
 
-pub async fn handle_command(cmd: &str, db: &Db) -> RespValue {
-    let command = match Command::from_str(cmd) {
-        Ok(cmd) => cmd,
-        Err(e) => return RespValue::Error(e.to_string()),
-    };
+    /// The keys a write command may change, for the change feed.
+    pub fn written_keys(&self) -> Vec<Bytes> {
+        match self {
+            Command::Set(key, ..)
+            | Command::Restore(Restore { key, .. })
+            | Command::IncrBy(key, _)
+            | Command::Append(key, _)
+            | Command::SetNx(key, _)
+            | Command::GetSet(key, _)
+            | Command::SAdd(key, _)
+            | Command::SRem(key, _)
+            | Command::SPop(key, _)
+            | Command::ZAdd(key, _)
+            | Command::ZRem(key, _)
+            | Command::SInterStore(key, _)
+            | Command::SUnionStore(key, _)
+            | Command::LPush(key, _)
+            | Command::RPush(key, _)
+            | Command::LPop(key, _)
+            | Command::RPop(key, _) => vec![key.clone()],
+            Command::ZUnionStore(zstore) | Command::ZInterStore(zstore) => {
+                vec![zstore.dest.clone()]
+            }
+            Command::Del(keys) | Command::BLPop(keys, _) | Command::BRPop(keys, _) => keys.clone(),
+            Command::MSet(pairs) => pairs.iter().map(|(key, _)| key.clone()).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The keyspace notification published for each of the written keys
+    /// the command changed, named as in Redis.
+    pub fn keyspace_event(&self) -> Option<(EventClass, &'static str)> {
+        let event = match self {
+            Command::Set(..) | Command::SetNx(..) | Command::GetSet(..) | Command::MSet(_) => {
+                (EventClass::String, "set")
+            }
+            Command::IncrBy(_, delta) if *delta < 0 => (EventClass::String, "decrby"),
+            Command::IncrBy(..) => (EventClass::String, "incrby"),
+            Command::Append(..) => (EventClass::String, "append"),
+            Command::Del(_) => (EventClass::Generic, "del"),
+            Command::Restore(_) => (EventClass::Generic, "restore"),
+            Command::SAdd(..) => (EventClass::Set, "sadd"),
+            Command::SRem(..) => (EventClass::Set, "srem"),
+            Command::SPop(..) => (EventClass::Set, "spop"),
+            Command::SInterStore(..) => (EventClass::Set, "sinterstore"),
+            Command::SUnionStore(..) => (EventClass::Set, "sunionstore"),
+            Command::ZAdd(..) => (EventClass::SortedSet, "zadd"),
+            Command::ZRem(..) => (EventClass::SortedSet, "zrem"),
+            Command::ZUnionStore(_) => (EventClass::SortedSet, "zunionstore"),
+            Command::ZInterStore(_) => (EventClass::SortedSet, "zinterstore"),
+            Command::LPush(..) => (EventClass::List, "lpush"),
+            Command::RPush(..) => (EventClass::List, "rpush"),
+            Command::LPop(..) | Command::BLPop(..) => (EventClass::List, "lpop"),
+            Command::RPop(..) | Command::BRPop(..) => (EventClass::List, "rpop"),
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    /// Whether the command may be queued between `MULTI` and `EXEC`.
+    /// Subscribing and replication switch the connection into another mode,
+    /// which can't be deferred to `EXEC`.
+    pub fn allowed_in_transaction(&self) -> bool {
+        !matches!(
+            self,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::Shutdown(_)
+                | Command::ReplicaOf(_)
+                | Command::ReplConf(_)
+                | Command::Psync
+                | Command::CdcTail(_)
+                | Command::Monitor
+                | Command::DebugBigKeys
+                | Command::DebugLockStats
+                | Command::ClientReply(_)
+        )
+    }
+
+    /// The class of the command's entry in the table, but for subcommands
+    /// classed apart from the rest of their command.
+    pub fn class(&self) -> CommandClass {
+        match self {
+            Command::MemoryUsage(..) | Command::ScriptExists(_) => CommandClass::Read,
+            Command::AclList
+            | Command::AclStats(_)
+            | Command::ClientList
+            | Command::ClientKill { .. }
+            | Command::ScriptFlush => CommandClass::Admin,
+            _ => self.spec().class,
+        }
+    }
+}
 
-    match command {
-        Command::Set(key, value) => {
-            let mut store = db.lock().await;
-            if store.insert(key, value) {
-                RespValue::SimpleString("OK".to_string())
-            } else {
-                RespValue::Error("ERR max memory limit exceeded".to_string())
+/// Runs an already parsed command. Connection-level commands such as `AUTH`
+/// and the pub/sub family are answered by the caller, which owns the
+/// connection state.
+///
+/// Only the shards owning the command's keys are locked. The deadline bounds
+/// both the wait for the locks and, for reads that walk a collection, the
+/// execution itself.
+pub async fn execute(
+    command: Command,
+    db: &Db,
+    conn: &ConnCtx,
+    clients: &ClientRegistry,
+    deadline: Deadline,
+) -> RespValue {
+    // Commands that never look at the keyspace skip the lock.
+    if let Some(reply) = reply_without_storage(&command) {
+        return reply;
+    }
+    let namespace = db.namespace(conn.db);
+    let (command, reply) = match namespace::translate(command, namespace) {
+        namespace::Plan::Run(command, reply) => (command, reply),
+        namespace::Plan::Flush(keys) => {
+            let storage_db = namespace.storage_db();
+            return match deadline
+                .lock(db, &[])
+                .await
+                .and_then(|mut store| store.select(storage_db).map(|()| store))
+            {
+                Ok(mut store) => {
+                    namespace::flush(namespace, keys, &mut store, conn, clients, deadline)
+                }
+                Err(e) => RespValue::Error(e.to_string()),
+            };
+        }
+        namespace::Plan::Done(reply) => return reply,
+    };
+    // Hot keys' values are read without their shard's lock.
+    if let Command::Get(key) = &command {
+        if db.is_serving_hot_keys() {
+            if let Some(value) = db.hot_get(namespace.storage_db(), key) {
+                return reply.apply(RespValue::bulk(value), namespace);
             }
         }
-        Command::Get(key) => {
-            let store = db.lock().await;
-            match store.get(&key) {
-                Some(value) => RespValue::BulkString(Some(value.clone())),
-                None => RespValue::BulkString(None),
+    }
+    let locks = match &command {
+        // Each SCAN step locks only the shard its cursor is in, so walking
+        // the keyspace never blocks all of it at once.
+        Command::Scan { cursor, .. } if cursor_shard(*cursor) < db.count() => {
+            deadline.lock_shard(db, cursor_shard(*cursor)).await
+        }
+        Command::Scan { .. } => Err(StorageError::InvalidCursor),
+        Command::Eval { .. } | Command::EvalSha { .. } => deadline.lock(db, &[]).await,
+        // Reads share their shards with other reads, unless one of them has
+        // to switch databases first or a key read is to be deleted as
+        // expired.
+        _ if command.class() == CommandClass::Read && db.shares_reads() => {
+            let keys = command.keys();
+            match deadline
+                .lock_shared(db, &keys, namespace.storage_db())
+                .await
+            {
+                Ok(Some(locks)) if !locks.any_expired(&keys) => Ok(locks),
+                Ok(locks) => {
+                    drop(locks);
+                    deadline.lock(db, &keys).await
+                }
+                Err(e) => Err(e),
             }
         }
-        Command::CmdInfo => RespValue::Array(vec![]),
-        Command::Info => {
-            let store = db.lock().await;
-            let info = format!(
-                "# Server\r\nredis_version:1.0.0\r\n\
-                # Memory\r\nused_memory:{}\r\n\
-                persistence_enabled:{}\r\n",
-                store.memory_usage(),
-                store.is_persistence_enabled()
-            );
-            RespValue::BulkString(Some(info))
+        _ => deadline.lock(db, &command.keys()).await,
+    };
+    match locks.and_then(|mut store| store.select(namespace.storage_db()).map(|()| store)) {
+        Ok(mut store) => reply.apply(
+            execute_locked(command, &mut store, conn, clients, deadline),
+            namespace,
+        ),
+        Err(e) => RespValue::Error(e.to_string()),
+    }
+}
+
+/// Runs a command against shards the caller already holds locked, so `EXEC`
+/// can run a whole transaction without interleaving other clients. They must
+/// include the shards of all the command's keys.
+pub fn execute_locked(
+    command: Command,
+    store: &mut ShardLocks,
+    conn: &ConnCtx,
+    clients: &ClientRegistry,
+    deadline: Deadline,
+) -> RespValue {
+    // Expired keys are deleted before the command gets to them.
+    store.expire_due(&command.keys());
+    // Jittered before the TTL is made absolute, so replicas and the AOF get
+    // the same expiry.
+    let jitter = store.config().ttl_jitter_percent;
+    let command = command
+        .with_ttl_jitter(jitter)
+        .with_absolute_expiry(unix_ms());
+    // Writes are logged and replicated as issued, but only if they changed
+    // something.
+    let propagation = if store.is_propagating() {
+        command.propagation()
+    } else {
+        None
+    };
+    // SPOP picks its members at random, so it's propagated as the SREM of
+    // those it picked, once they're known.
+    let popped_from = match &command {
+        Command::SPop(key, _) if store.is_propagating() => Some(key.clone()),
+        _ => None,
+    };
+    // Cacheable reads take their reply from the cache while it's good.
+    let cache_key = command.cache_key().filter(|_| store.is_caching_results());
+    let mut cached = None;
+    if command.class() == CommandClass::Read {
+        let keys = command.keys();
+        let hits = keys
+            .iter()
+            .filter(|key| store.shard(key).value(key).is_some())
+            .count();
+        store
+            .stats()
+            .record_lookups(hits as u64, (keys.len() - hits) as u64);
+        // Eviction goes by last access; writes count as one already.
+        if store.is_evicting() {
+            store.record_access(&keys);
+        }
+        cached = cache_key
+            .as_deref()
+            .and_then(|args| store.cached_reply(args));
+        if let Command::Get(key) = &command {
+            store.record_hot_read(key);
+        }
+    }
+    // Keys whose version moves get reported to change feed tailers, and
+    // published as keyspace notifications.
+    let event = command
+        .keyspace_event()
+        .filter(|(class, _)| store.config().notify_keyspace_events.wants(*class));
+    let written: Vec<(Bytes, Option<u64>)> = if store.changefeed().is_tailed() || event.is_some() {
+        let keys = command.written_keys();
+        keys.into_iter()
+            .map(|key| {
+                let version = store.shard(&key).version(&key);
+                (key, version)
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    // Flushes and swaps change whole databases; they're reported once,
+    // with an empty key.
+    let resets = store.changefeed().is_tailed()
+        && matches!(
+            command,
+            Command::FlushDb | Command::FlushAll | Command::SwapDb(..)
+        );
+    let op = command.name();
+    let changes = store.changes();
+    let ctx = Context {
+        conn,
+        clients,
+        deadline: &deadline,
+    };
+    let reply = match (cached, cache_key) {
+        (Some(reply), _) => reply,
+        (None, cache_key) => {
+            let keys = cache_key.as_ref().map(|_| command.keys());
+            let reply = match run(command, store, ctx) {
+                Ok(resp) => resp,
+                Err(e) => RespValue::Error(e.to_string()),
+            };
+            if let (Some(args), Some(keys)) = (cache_key, keys) {
+                store.cache_reply(args, &keys, &reply);
+            }
+            reply
+        }
+    };
+    let propagation = propagation.or_else(|| sets::spop_propagation(popped_from.as_ref()?, &reply));
+    if let Some(frame) = propagation {
+        if store.changes() != changes {
+            store.propagate(&frame);
         }
-        Command::Memory => {
-            let store = db.lock().await;
-            RespValue::Integer(store.memory_usage() as i64)
+    }
+    // Expirations and the evictions making room for the write came first,
+    // so they're reported first.
+    for (db, key) in store.take_expired() {
+        store.notify(EventClass::Expired, "expired", db, &key);
+    }
+    for (db, key) in store.take_evicted() {
+        store.notify(EventClass::Evicted, "evicted", db, &key);
+    }
+    let db = store.selected();
+    for (key, version) in written {
+        let shard = store.shard(&key);
+        if shard.version(&key) == version {
+            continue;
         }
-        Command::Save => {
-            let store = db.lock().await;
-            match store.save_to_disk() {
-                Ok(_) => RespValue::SimpleString("OK".to_string()),
-                Err(e) => RespValue::Error(format!("ERR saving to disk: {}", e)),
+        if let Some((class, name)) = event {
+            store.notify(class, name, db, &key);
+            // As in Redis, emptying a collection deletes the key too.
+            if shard.value(&key).is_none() && name != "del" {
+                store.notify(EventClass::Generic, "del", db, &key);
             }
         }
+        if store.changefeed().is_tailed() {
+            let value = shard.get(&key).ok().flatten().cloned();
+            store.changefeed().publish(Change { db, key, op, value });
+        }
+    }
+    if resets && store.changes() != changes {
+        store.changefeed().publish(Change {
+            db,
+            key: Bytes::new(),
+            op,
+            value: None,
+        });
+    }
+    reply
+}
+
+/// Replies to commands that don't touch storage; `None` for all others.
+fn reply_without_storage(command: &Command) -> Option<RespValue> {
+    let reply = match command {
+        Command::CommandInfo(names) => registry::info_reply(names),
+        Command::CommandCount => RespValue::Integer(COMMANDS.len() as i64),
+        Command::CommandDocs(names) => registry::docs_reply(names),
+        Command::Features => features_reply(),
+        Command::Auth(..)
+        | Command::Hello { .. }
+        | Command::AclList
+        | Command::AclWhoAmI
+        | Command::ClientReply(_)
+        | Command::Multi
+        | Command::Exec
+        | Command::Discard
+        | Command::Watch(_)
+        | Command::Unwatch
+        | Command::Subscribe(_)
+        | Command::Unsubscribe(_)
+        | Command::PSubscribe(_)
+        | Command::PUnsubscribe(_)
+        | Command::Publish(..)
+        | Command::PubSubHistory(..)
+        | Command::Quit
+        | Command::Reset
+        | Command::Shutdown(_)
+        | Command::ReplicaOf(_)
+        | Command::ReplConf(_)
+        | Command::Psync
+        | Command::CdcTail(_)
+        | Command::Monitor
+        | Command::ClusterSlots
+        | Command::ClusterShards
+        | Command::ClusterKeySlot(_)
+        | Command::DebugBigKeys
+        | Command::DebugLockStats => RespValue::Error(format!(
+            "ERR {} must be handled by the connection",
+            command.name().to_uppercase()
+        )),
+        Command::Ping(None) => RespValue::SimpleString("PONG".to_string()),
+        Command::Ping(Some(message)) => RespValue::bulk(message.clone()),
+        Command::AclGenPass(bits) => match acl::genpass(*bits) {
+            Ok(pass) => RespValue::bulk(pass),
+            Err(e) => RespValue::Error(format!("ERR {}", e)),
+        },
+        _ => return None,
+    };
+    Some(reply)
+}
+
+/// `FEATURES` as a map of field names to values: the build, then each
+/// optional feature as 1 or 0.
+fn features_reply() -> RespValue {
+    let mut reply = vec![
+        (
+            RespValue::bulk("version"),
+            RespValue::bulk(build_info::VERSION),
+        ),
+        (
+            RespValue::bulk("git_sha1"),
+            RespValue::bulk(build_info::GIT_HASH),
+        ),
+        (
+            RespValue::bulk("build_profile"),
+            RespValue::bulk(build_info::PROFILE),
+        ),
+    ];
+    for (name, enabled) in build_info::FEATURES {
+        reply.push((RespValue::bulk(*name), RespValue::Integer(*enabled as i64)));
+    }
+    RespValue::Map(reply)
+}
+
+/// Hands the command to its family's handler.
+fn run(command: Command, store: &mut ShardLocks, ctx: Context) -> Result<RespValue, StorageError> {
+    match command.handler() {
+        Some(handler) => handler.run(command, store, ctx),
+        None => Ok(reply_without_storage(&command).expect("command doesn't use storage")),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::Storage;
+    use crate::storage::{unix_ms, Shards, MAX_REPEATED_PICKS};
     use std::sync::Arc;
-    use tokio::sync::Mutex;
+
+    async fn handle_command(cmd: &str, db: &Db) -> RespValue {
+        match Command::from_str(cmd) {
+            Ok(command) => {
+                execute(
+                    command,
+                    db,
+                    &ConnCtx::default(),
+                    &ClientRegistry::new(),
+                    Deadline::after(None),
+                )
+                .await
+            }
+            Err(e) => RespValue::Error(e.to_string()),
+        }
+    }
 
     #[test]
     fn test_command_parsing() {
         assert_eq!(
             Command::from_str("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n").unwrap(),
-            Command::Set("key1".to_string(), "value1".to_string())
+            Command::Set("key1".into(), "value1".into(), SetOptions::default())
         );
 
         assert_eq!(
             Command::from_str("*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n").unwrap(),
-            Command::Get("key1".to_string())
+            Command::Get("key1".into())
+        );
+
+        assert_eq!(
+            Command::from_str("*3\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$2\r\npw\r\n").unwrap(),
+            Command::Auth(Some("alice".to_string()), Secret::new("pw".to_string()))
+        );
+
+        assert_eq!(
+            Command::from_str("*3\r\n$3\r\nACL\r\n$7\r\nGENPASS\r\n$2\r\n32\r\n").unwrap(),
+            Command::AclGenPass(32)
+        );
+        assert!(Command::from_str("*3\r\n$3\r\nACL\r\n$7\r\nGENPASS\r\n$1\r\n0\r\n").is_err());
+        assert_eq!(
+            Command::from_str("*3\r\n$3\r\nACL\r\n$5\r\nSTATS\r\n$5\r\nalice\r\n").unwrap(),
+            Command::AclStats(Some("alice".to_string()))
+        );
+
+        assert_eq!(
+            Command::from_str("*2\r\n$5\r\nDEBUG\r\n$8\r\nfeatures\r\n").unwrap(),
+            Command::Features
+        );
+        assert_eq!(
+            Command::from_str("*2\r\n$5\r\nDEBUG\r\n$9\r\nLOCKSTATS\r\n").unwrap(),
+            Command::DebugLockStats
+        );
+        assert!(Command::from_str("*2\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n").is_err());
+
+        assert_eq!(
+            Command::from_str(
+                "*5\r\n$5\r\nHELLO\r\n$1\r\n2\r\n$4\r\nAUTH\r\n$5\r\nalice\r\n$2\r\npw\r\n"
+            )
+            .unwrap(),
+            Command::Hello {
+                protover: Some(2),
+                auth: Some(("alice".to_string(), Secret::new("pw".to_string()))),
+            }
+        );
+        assert!(Command::from_str("*3\r\n$5\r\nHELLO\r\n$1\r\n2\r\n$4\r\nAUTH\r\n").is_err());
+
+        assert_eq!(
+            Command::from_str("*2\r\n$4\r\nDECR\r\n$1\r\nn\r\n").unwrap(),
+            Command::IncrBy("n".into(), -1)
+        );
+        assert!(matches!(
+            Command::from_str("*3\r\n$6\r\nINCRBY\r\n$1\r\nn\r\n$3\r\n1.5\r\n"),
+            Err(CommandError::NotAnInteger)
+        ));
+        assert!(Command::from_str("*3\r\n$4\r\nMSET\r\n$1\r\nk\r\n$1\r\nv\r\n").is_ok());
+        assert!(Command::from_str("*2\r\n$4\r\nMSET\r\n$1\r\nk\r\n").is_err());
+    }
+
+    #[test]
+    fn test_errors_match_redis() {
+        let error = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            Command::from_frame(frame).unwrap_err().to_string()
+        };
+        for (args, expected) in [
+            (
+                &["GET"][..],
+                "ERR wrong number of arguments for 'get' command",
+            ),
+            (
+                &["decr", "a", "b"][..],
+                "ERR wrong number of arguments for 'decr' command",
+            ),
+            (
+                &["DBSIZE", "x"][..],
+                "ERR wrong number of arguments for 'dbsize' command",
+            ),
+            (
+                &["CONFIG", "get"][..],
+                "ERR wrong number of arguments for 'config|get' command",
+            ),
+            (
+                &["ZUNIONSTORE", "d", "0", "k"][..],
+                "ERR at least 1 input key is needed for 'zunionstore' command",
+            ),
+            (
+                &["INCRBY", "n", "x"][..],
+                "ERR value is not an integer or out of range",
+            ),
+            (&["ZADD", "z", "1", "a", "2"][..], "ERR syntax error"),
+            (
+                &["CLIENT", "Nope"][..],
+                "ERR unknown subcommand 'Nope'. Try CLIENT HELP.",
+            ),
+            (
+                &["foo", "a", "b"][..],
+                "ERR unknown command 'foo', with args beginning with: 'a' 'b' ",
+            ),
+            (
+                &["foo"][..],
+                "ERR unknown command 'foo', with args beginning with: ",
+            ),
+        ] {
+            assert_eq!(error(args), expected, "{:?}", args);
+        }
+
+        let long = "x".repeat(200);
+        let quoted = error(&["foo", &long, "more"]);
+        assert_eq!(
+            quoted,
+            format!(
+                "ERR unknown command 'foo', with args beginning with: '{}' ",
+                &long[..128]
+            )
         );
     }
 
@@ -149,17 +1620,1346 @@ mod tests {
         let config = crate::config::StorageConfig {
             max_memory: 1024 * 1024, // 1MB
             persistence_enabled: false,
+            ..Default::default()
         };
-        let db: Db = Arc::new(Mutex::new(Storage::new(config)));
+        let db: Db = Arc::new(Shards::new(config));
 
         let response =
             handle_command("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n", &db).await;
         assert_eq!(response, RespValue::SimpleString("OK".to_string()));
 
         let response = handle_command("*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", &db).await;
-        assert_eq!(response, RespValue::BulkString(Some("value1".to_string())));
+        assert_eq!(response, RespValue::bulk("value1"));
+
+        let response = handle_command("*2\r\n$3\r\nGET\r\n$11\r\nnonexistent\r\n", &db).await;
+        assert_eq!(response, RespValue::BulkString(None));
+
+        let getrange = |key: &str, start: &str, stop: &str| {
+            format!(
+                "*4\r\n$8\r\nGETRANGE\r\n${}\r\n{}\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                key.len(),
+                key,
+                start.len(),
+                start,
+                stop.len(),
+                stop
+            )
+        };
+        let response = handle_command(&getrange("key1", "-3", "-1"), &db).await;
+        assert_eq!(response, RespValue::bulk("ue1"));
+        let response = handle_command(&getrange("key1", "0", "-100"), &db).await;
+        assert_eq!(response, RespValue::bulk("v"));
+        let response = handle_command(&getrange("nonexistent", "0", "-1"), &db).await;
+        assert_eq!(response, RespValue::bulk(""));
 
-        let response = handle_command("*2\r\n$3\r\nGET\r\n$10\r\nnonexistent\r\n", &db).await;
+        let response = handle_command(
+            "*3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$11\r\nnonexistent\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(response, RespValue::Integer(1));
+        let response = handle_command("*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", &db).await;
         assert_eq!(response, RespValue::BulkString(None));
     }
+
+    fn bulk(s: &'static str) -> RespValue {
+        RespValue::bulk(s)
+    }
+
+    fn test_db() -> Db {
+        let config = crate::config::StorageConfig {
+            max_memory: 1024 * 1024,
+            persistence_enabled: false,
+            ..Default::default()
+        };
+        Arc::new(Shards::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_binary_keys_and_values() {
+        let db = test_db();
+        let key = Bytes::from_static(b"k\x00\xff");
+        let value = Bytes::from_static(b"\r\n\x80\x00");
+        let set = RespValue::Array(vec![
+            RespValue::bulk("SET"),
+            RespValue::bulk(key.clone()),
+            RespValue::bulk(value.clone()),
+        ]);
+        let command = Command::from_frame(set).unwrap();
+        let response = execute(
+            command,
+            &db,
+            &ConnCtx::default(),
+            &ClientRegistry::new(),
+            Deadline::after(None),
+        )
+        .await;
+        assert_eq!(response, RespValue::SimpleString("OK".to_string()));
+
+        let response = execute(
+            Command::Get(key),
+            &db,
+            &ConnCtx::default(),
+            &ClientRegistry::new(),
+            Deadline::after(None),
+        )
+        .await;
+        assert_eq!(response, RespValue::bulk(value));
+    }
+
+    #[tokio::test]
+    async fn test_writes_reach_the_changefeed() {
+        let db = test_db();
+        let mut tail = db.changefeed().tail(None);
+        let clients = ClientRegistry::new();
+        for command in [
+            Command::IncrBy("n".into(), 2),
+            Command::SAdd("s".into(), vec!["a".into()]),
+            // Neither changes anything, so neither is reported.
+            Command::SetNx("n".into(), "x".into()),
+            Command::Del(vec!["missing".into()]),
+            Command::Del(vec!["n".into(), "s".into()]),
+            Command::FlushAll,
+        ] {
+            execute(
+                command,
+                &db,
+                &ConnCtx::default(),
+                &clients,
+                Deadline::after(None),
+            )
+            .await;
+        }
+        let change = |key: &'static str, op, value: Option<&'static str>| Change {
+            db: 0,
+            key: key.into(),
+            op,
+            value: value.map(Bytes::from),
+        };
+        assert_eq!(tail.recv().await, Ok(change("n", "incrby", Some("2"))));
+        assert_eq!(tail.recv().await, Ok(change("s", "sadd", None)));
+        assert_eq!(tail.recv().await, Ok(change("n", "del", None)));
+        assert_eq!(tail.recv().await, Ok(change("s", "del", None)));
+        assert_eq!(tail.recv().await, Ok(change("", "flushall", None)));
+    }
+
+    #[tokio::test]
+    async fn test_writes_publish_keyspace_events() {
+        let db: Db = Arc::new(Shards::new(crate::config::StorageConfig {
+            max_memory: 10,
+            shards: 1,
+            maxmemory_policy: crate::config::MaxMemoryPolicy::AllKeysLru,
+            notify_keyspace_events: "EA".parse().unwrap(),
+            ..Default::default()
+        }));
+        let mut subscriber = crate::pubsub::Subscriber::new(db.broker().clone());
+        subscriber.psubscribe(vec!["__keyevent@0__:*".into()]);
+        let clients = ClientRegistry::new();
+        for command in [
+            Command::Set("a".into(), "12345".into(), SetOptions::default()),
+            // Only room for one of them.
+            Command::Set("b".into(), "12345".into(), SetOptions::default()),
+            Command::Del(vec!["missing".into()]),
+            Command::SAdd("s".into(), vec!["x".into()]),
+            Command::SRem("s".into(), vec!["x".into()]),
+        ] {
+            execute(
+                command,
+                &db,
+                &ConnCtx::default(),
+                &clients,
+                Deadline::after(None),
+            )
+            .await;
+        }
+        for (event, key) in [
+            ("set", "a"),
+            ("evicted", "a"),
+            ("set", "b"),
+            ("sadd", "s"),
+            ("srem", "s"),
+            ("del", "s"),
+        ] {
+            let channel = format!("__keyevent@0__:{}", event);
+            assert_eq!(
+                subscriber.recv().await,
+                RespValue::Push(vec![
+                    bulk("pmessage"),
+                    bulk("__keyevent@0__:*"),
+                    RespValue::bulk(channel),
+                    bulk(key),
+                ])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_evictions_are_counted_once() {
+        let db: Db = Arc::new(Shards::new(crate::config::StorageConfig {
+            // Room for one key per shard.
+            max_memory: 40,
+            shards: 4,
+            maxmemory_policy: crate::config::MaxMemoryPolicy::AllKeysRandom,
+            notify_keyspace_events: "Ee".parse().unwrap(),
+            ..Default::default()
+        }));
+        let mut subscriber = crate::pubsub::Subscriber::new(db.broker().clone());
+        subscriber.subscribe(vec!["__keyevent@0__:evicted".into()]);
+        let clients = Arc::new(ClientRegistry::new());
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let (db, clients) = (db.clone(), clients.clone());
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        let key = Bytes::from(format!("k{}:{:02}", writer, i));
+                        let set = Command::Set(key, "v".into(), SetOptions::default());
+                        let conn = ConnCtx::default();
+                        execute(set, &db, &conn, &clients, Deadline::after(None)).await;
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        let kept = db.lock_all().await.key_count() as u64;
+        let evicted = db.stats().evicted_keys();
+        assert_eq!(evicted, 200 - kept);
+        let mut keys = std::collections::HashSet::new();
+        for _ in 0..evicted {
+            let RespValue::Push(message) = subscriber.recv().await else {
+                panic!("not a message");
+            };
+            let RespValue::BulkString(Some(key)) = &message[2] else {
+                panic!("no key in {:?}", message);
+            };
+            assert!(keys.insert(key.clone()), "{:?} evicted twice", key);
+        }
+        let more = tokio::time::timeout(Duration::from_millis(50), subscriber.recv()).await;
+        assert!(more.is_err(), "more evictions published than counted");
+    }
+
+    #[tokio::test]
+    async fn test_set_options() {
+        let db = test_db();
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        execute(
+                            command,
+                            &db,
+                            &ConnCtx::default(),
+                            &ClientRegistry::new(),
+                            Deadline::after(None),
+                        )
+                        .await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+        let ok = || RespValue::SimpleString("OK".to_string());
+        let nil = RespValue::BulkString(None);
+
+        assert_eq!(run(&["SET", "lock", "a", "XX"]).await, nil);
+        assert_eq!(run(&["SET", "lock", "a", "NX"]).await, ok());
+        assert_eq!(run(&["SET", "lock", "b", "nx"]).await, nil);
+        assert_eq!(run(&["SET", "lock", "b", "XX", "GET"]).await, bulk("a"));
+        // GET replies with the old value even when the condition fails.
+        assert_eq!(run(&["SET", "lock", "c", "NX", "GET"]).await, bulk("b"));
+        assert_eq!(run(&["GETSET", "lock", "d"]).await, bulk("b"));
+        assert_eq!(run(&["SETNX", "lock", "e"]).await, RespValue::Integer(0));
+        assert_eq!(run(&["GET", "lock"]).await, bulk("d"));
+
+        run(&["SADD", "s", "m"]).await;
+        assert!(matches!(
+            run(&["SET", "s", "v", "GET"]).await,
+            RespValue::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+        assert_eq!(run(&["SET", "s", "v"]).await, ok());
+        for args in [
+            &["SET", "k", "v", "NX", "XX"][..],
+            &["SET", "k", "v", "EVERYWHERE"][..],
+            &["SET", "k", "v", "EX", "10", "KEEPTTL"][..],
+            &["SET", "k", "v", "PX", "10", "EXAT", "10"][..],
+            &["SET", "k", "v", "EX"][..],
+        ] {
+            assert_eq!(
+                run(args).await,
+                RespValue::Error(CommandError::SyntaxError.to_string())
+            );
+        }
+        for time in ["0", "-5", "9223372036854776"] {
+            assert_eq!(
+                run(&["SET", "k", "v", "EX", time]).await,
+                RespValue::Error(CommandError::InvalidExpireTime("set").to_string())
+            );
+        }
+
+        // TTLs, kept through KEEPTTL and dropped by a plain SET.
+        let int = RespValue::Integer;
+        assert_eq!(
+            run(&["SET", "t", "v", "EX", "100", "EX", "200"]).await,
+            ok()
+        );
+        assert_eq!(run(&["TTL", "t"]).await, int(200));
+        assert_eq!(run(&["SET", "t", "v", "XX", "KEEPTTL"]).await, ok());
+        assert!(matches!(run(&["PTTL", "t"]).await, RespValue::Integer(ms) if ms > 199_000));
+        assert_eq!(run(&["SET", "t", "v"]).await, ok());
+        assert_eq!(run(&["TTL", "t"]).await, int(-1));
+        assert_eq!(run(&["TTL", "nope"]).await, int(-2));
+        let at = unix_ms() + 50_000;
+        run(&["SET", "t", "v", "PXAT", &at.to_string()]).await;
+        assert_eq!(run(&["TTL", "t"]).await, int(50));
+
+        // Expired keys are gone for reads and writes alike, counted once.
+        run(&["SET", "t", "v", "PX", "1"]).await;
+        run(&["SET", "u", "v", "PX", "1"]).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(run(&["GET", "t"]).await, nil);
+        assert_eq!(run(&["TTL", "t"]).await, int(-2));
+        assert_eq!(run(&["SET", "u", "w", "NX"]).await, ok());
+        assert_eq!(run(&["TTL", "u"]).await, int(-1));
+        assert_eq!(db.stats().expired_keys(), 2);
+
+        // Relative TTLs are propagated as the Unix times they end at, in
+        // milliseconds.
+        let parse = |args: &[&str]| {
+            Command::from_frame(RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            ))
+            .unwrap()
+        };
+        let set = parse(&["SET", "k", "v", "EX", "10", "GET"]).with_absolute_expiry(1_000);
+        assert_eq!(
+            set.propagation().unwrap().serialize(),
+            b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$4\r\nPXAT\r\n$5\r\n11000\r\n"
+        );
+        let restore = parse(&["RESTORE", "k", "500", "payload"]).with_absolute_expiry(1_000);
+        assert_eq!(
+            restore.propagation(),
+            parse(&["RESTORE", "k", "1500", "payload", "ABSTTL"]).propagation()
+        );
+        let persist = parse(&["RESTORE", "k", "0", "payload"]);
+        assert_eq!(
+            persist.propagation(),
+            parse(&["RESTORE", "k", "0", "payload"])
+                .with_absolute_expiry(1_000)
+                .propagation()
+        );
+    }
+
+    #[test]
+    fn test_ttl_jitter() {
+        let expiry = |args: &[&str], percent| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            match Command::from_frame(frame).unwrap().with_ttl_jitter(percent) {
+                Command::Set(_, _, options) => options.expiry,
+                command => panic!("not a SET: {:?}", command),
+            }
+        };
+        assert_eq!(
+            expiry(&["SET", "k", "v", "EX", "100"], 0),
+            SetExpiry::After(100_000)
+        );
+        // Only relative TTLs are stretched; a time to expire at is kept.
+        assert_eq!(
+            expiry(&["SET", "k", "v", "PXAT", "100"], 50),
+            SetExpiry::At(100)
+        );
+
+        let ttls: Vec<u64> = (0..100)
+            .map(
+                |i| match expiry(&["SET", &format!("k{}", i), "v", "EX", "100"], 20) {
+                    SetExpiry::After(ms) => ms,
+                    other => panic!("{:?}", other),
+                },
+            )
+            .collect();
+        assert!(ttls.iter().all(|&ms| (100_000..=120_000).contains(&ms)));
+        assert!(ttls.iter().any(|&ms| ms != ttls[0]));
+        // The same key gets the same TTL every time.
+        assert_eq!(
+            expiry(&["SET", "k7", "v", "EX", "100"], 20),
+            SetExpiry::After(ttls[7])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dump_and_restore() {
+        let db = test_db();
+        let run = |args: Vec<Bytes>| {
+            let frame = RespValue::Array(args.into_iter().map(RespValue::bulk).collect());
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        execute(
+                            command,
+                            &db,
+                            &ConnCtx::default(),
+                            &ClientRegistry::new(),
+                            Deadline::after(None),
+                        )
+                        .await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+        let args = |args: &[&str]| -> Vec<Bytes> {
+            args.iter().map(|a| Bytes::from(a.to_string())).collect()
+        };
+        let ok = || RespValue::SimpleString("OK".to_string());
+
+        run(args(&["RPUSH", "list", "a", "b"])).await;
+        let RespValue::BulkString(Some(payload)) = run(args(&["DUMP", "list"])).await else {
+            panic!("no payload");
+        };
+        assert_eq!(
+            run(args(&["DUMP", "missing"])).await,
+            RespValue::BulkString(None)
+        );
+        let restore = |key: &str, ttl: &str, options: &[&str]| {
+            let mut all = args(&["RESTORE", key, ttl]);
+            all.push(payload.clone());
+            all.extend(args(options));
+            all
+        };
+        assert!(matches!(
+            run(restore("list", "0", &[])).await,
+            RespValue::Error(e) if e.starts_with("BUSYKEY")
+        ));
+        assert_eq!(run(restore("list", "0", &["REPLACE"])).await, ok());
+        assert_eq!(
+            run(restore("copy", "5000", &["IDLETIME", "60"])).await,
+            ok()
+        );
+        assert_eq!(
+            run(args(&["LRANGE", "copy", "0", "-1"])).await,
+            RespValue::Array(vec![bulk("a"), bulk("b")])
+        );
+        assert_eq!(run(args(&["TTL", "copy"])).await, RespValue::Integer(5));
+
+        // ABSTTL takes a Unix time; one already past stores nothing.
+        let at = (unix_ms() + 50_000).to_string();
+        assert_eq!(
+            run(restore("abs", &at, &["ABSTTL", "FREQ", "5"])).await,
+            ok()
+        );
+        assert_eq!(run(args(&["TTL", "abs"])).await, RespValue::Integer(50));
+        assert_eq!(run(restore("gone", "1", &["ABSTTL"])).await, ok());
+        assert_eq!(
+            run(args(&["TYPE", "gone"])).await,
+            RespValue::SimpleString("none".into())
+        );
+
+        for (options, error) in [
+            (
+                &["IDLETIME", "1", "FREQ", "1"][..],
+                CommandError::SyntaxError,
+            ),
+            (
+                &["FREQ", "256"][..],
+                CommandError::InvalidArgument("Invalid FREQ value, must be >= 0 and <= 255".into()),
+            ),
+            (
+                &["IDLETIME", "-1"][..],
+                CommandError::InvalidArgument("Invalid IDLETIME value, must be >= 0".into()),
+            ),
+        ] {
+            assert_eq!(
+                run(restore("k", "0", options)).await,
+                RespValue::Error(error.to_string())
+            );
+        }
+        assert!(matches!(
+            run(args(&["RESTORE", "k", "0", "garbage"])).await,
+            RespValue::Error(e) if e.contains("checksum")
+        ));
+    }
+
+    #[cfg(feature = "scripting")]
+    #[tokio::test]
+    async fn test_scripts() {
+        let db = test_db();
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        execute(
+                            command,
+                            &db,
+                            &ConnCtx::default(),
+                            &ClientRegistry::new(),
+                            Deadline::after(None),
+                        )
+                        .await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+        let script = "redis.call('SET', KEYS[1], ARGV[1]) return redis.call('INCRBY', KEYS[1], 2)";
+        assert_eq!(
+            run(&["EVAL", script, "1", "n", "40"]).await,
+            RespValue::Integer(42)
+        );
+        assert_eq!(run(&["GET", "n"]).await, bulk("42"));
+
+        let RespValue::BulkString(Some(sha)) = run(&["SCRIPT", "LOAD", "return ARGV[1]"]).await
+        else {
+            panic!("SCRIPT LOAD replied with no SHA");
+        };
+        let sha = std::str::from_utf8(&sha).unwrap();
+        let missing = "b2cbc6a6ab3aa1b2bb4d1e6ac9228dbac6e0e0b5";
+        assert_eq!(run(&["EVALSHA", sha, "0", "hi"]).await, bulk("hi"));
+        assert_eq!(
+            run(&["SCRIPT", "EXISTS", sha, missing]).await,
+            RespValue::Array(vec![RespValue::Integer(1), RespValue::Integer(0)])
+        );
+        assert_eq!(
+            run(&["SCRIPT", "FLUSH"]).await,
+            RespValue::SimpleString("OK".to_string())
+        );
+        assert!(matches!(
+            run(&["EVALSHA", sha, "0"]).await,
+            RespValue::Error(e) if e.starts_with("NOSCRIPT")
+        ));
+
+        assert!(matches!(
+            run(&["EVAL", "return redis.call('MULTI')", "0"]).await,
+            RespValue::Error(e) if e.contains("not allowed from script")
+        ));
+        assert!(matches!(
+            run(&["EVAL", "return 1", "2", "k"]).await,
+            RespValue::Error(e) if e.contains("greater than number of args")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_keyspace_commands() {
+        let db = test_db();
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        execute(
+                            command,
+                            &db,
+                            &ConnCtx::default(),
+                            &ClientRegistry::new(),
+                            Deadline::after(None),
+                        )
+                        .await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+
+        assert_eq!(run(&["RANDOMKEY"]).await, RespValue::BulkString(None));
+        run(&["SET", "user:1", "a"]).await;
+        run(&["SET", "user:2", "b"]).await;
+        run(&["SADD", "tags", "x"]).await;
+        assert_eq!(
+            run(&["TYPE", "user:1"]).await,
+            RespValue::SimpleString("string".into())
+        );
+        assert_eq!(
+            run(&["TYPE", "tags"]).await,
+            RespValue::SimpleString("set".into())
+        );
+        assert_eq!(
+            run(&["TYPE", "nope"]).await,
+            RespValue::SimpleString("none".into())
+        );
+
+        let RespValue::Array(mut keys) = run(&["KEYS", "user:*"]).await else {
+            panic!("KEYS replies with an array");
+        };
+        keys.sort_by_key(|key| format!("{:?}", key));
+        assert_eq!(keys, vec![bulk("user:1"), bulk("user:2")]);
+
+        // Walk the whole keyspace a key at a time.
+        let (mut cursor, mut seen) = ("0".to_string(), 0);
+        loop {
+            match run(&["SCAN", &cursor, "COUNT", "1"]).await {
+                RespValue::Array(reply) => match &reply[..] {
+                    [RespValue::BulkString(Some(next)), RespValue::Array(page)] => {
+                        seen += page.len();
+                        cursor = String::from_utf8(next.to_vec()).unwrap();
+                    }
+                    _ => panic!("unexpected SCAN reply {:?}", reply),
+                },
+                reply => panic!("unexpected SCAN reply {:?}", reply),
+            }
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(seen, 3);
+
+        assert!(matches!(
+            run(&["SCAN", "0", "COUNT", "0"]).await,
+            RespValue::Error(_)
+        ));
+        assert!(matches!(run(&["SCAN", "x"]).await, RespValue::Error(_)));
+        assert_eq!(
+            run(&["SCAN", &u64::MAX.to_string()]).await,
+            RespValue::Error("ERR invalid cursor".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_commands() {
+        let db = test_db();
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        execute(
+                            command,
+                            &db,
+                            &ConnCtx::default(),
+                            &ClientRegistry::new(),
+                            Deadline::after(None),
+                        )
+                        .await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+
+        run(&["SET", "k", "value"]).await;
+        run(&["RPUSH", "l", "a", "bb", "ccc", "dddd"]).await;
+        let RespValue::Integer(string) = run(&["MEMORY", "USAGE", "k"]).await else {
+            panic!("MEMORY USAGE replies with an integer");
+        };
+        assert!(string > 6, "{} bytes", string);
+        let RespValue::Integer(sampled) = run(&["memory", "usage", "l", "SAMPLES", "1"]).await
+        else {
+            panic!("MEMORY USAGE replies with an integer");
+        };
+        let RespValue::Integer(exact) = run(&["MEMORY", "USAGE", "l", "SAMPLES", "0"]).await else {
+            panic!("MEMORY USAGE replies with an integer");
+        };
+        assert!(sampled < exact, "{} sampled, {} exact", sampled, exact);
+        assert_eq!(
+            run(&["MEMORY", "USAGE", "missing"]).await,
+            RespValue::BulkString(None)
+        );
+        assert_eq!(
+            run(&["MEMORY", "USAGE", "k", "SAMPLES"]).await,
+            RespValue::Error("ERR syntax error".into())
+        );
+        assert_eq!(
+            run(&["MEMORY"]).await,
+            RespValue::Error("ERR wrong number of arguments for 'memory' command".into())
+        );
+
+        let RespValue::Map(stats) = run(&["MEMORY", "STATS"]).await else {
+            panic!("MEMORY STATS replies with a map");
+        };
+        let field = |name: &str| {
+            stats
+                .iter()
+                .find(|(key, _)| *key == RespValue::bulk(name.to_string()))
+                .map(|(_, value)| value)
+        };
+        assert_eq!(field("keys.count"), Some(&RespValue::Integer(2)));
+        assert!(matches!(field("db.0"), Some(RespValue::Map(_))));
+        assert_eq!(field("db.1"), None);
+        let (Some(&RespValue::Integer(total)), Some(&RespValue::Integer(dataset))) =
+            (field("total.allocated"), field("dataset.bytes"))
+        else {
+            panic!("totals missing from {:?}", stats);
+        };
+        assert!(total > dataset);
+
+        assert!(matches!(
+            run(&["MEMORY", "DOCTOR"]).await,
+            RespValue::BulkString(Some(_))
+        ));
+    }
+
+    #[test]
+    fn test_command_introspection() {
+        let reply = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            match Command::from_frame(frame) {
+                Ok(command) => reply_without_storage(&command).expect("answered without storage"),
+                Err(e) => RespValue::Error(e.to_string()),
+            }
+        };
+
+        assert_eq!(
+            reply(&["COMMAND", "COUNT"]),
+            RespValue::Integer(COMMANDS.len() as i64)
+        );
+        let RespValue::Array(all) = reply(&["COMMAND"]) else {
+            panic!("COMMAND replies with an array");
+        };
+        assert_eq!(all.len(), COMMANDS.len());
+
+        let RespValue::Array(entries) = reply(&["command", "info", "GET", "nosuch", "mset"]) else {
+            panic!("COMMAND INFO replies with an array");
+        };
+        let RespValue::Array(get) = &entries[0] else {
+            panic!("each command is an array");
+        };
+        assert_eq!(get.len(), 10);
+        assert_eq!(get[0], bulk("get"));
+        assert_eq!(get[1], RespValue::Integer(2));
+        assert_eq!(
+            get[2],
+            RespValue::Set(vec![
+                RespValue::SimpleString("readonly".to_string()),
+                RespValue::SimpleString("fast".to_string()),
+            ])
+        );
+        assert_eq!(&get[3..6], &[1, 1, 1].map(RespValue::Integer));
+        assert_eq!(
+            get[6],
+            RespValue::Set(vec![RespValue::SimpleString("@read".to_string())])
+        );
+        assert_eq!(entries[1], RespValue::NullArray);
+        let RespValue::Array(mset) = &entries[2] else {
+            panic!("each command is an array");
+        };
+        assert_eq!(mset[1], RespValue::Integer(-3));
+        assert_eq!(&mset[3..6], &[1, -1, 2].map(RespValue::Integer));
+
+        let RespValue::Map(docs) = reply(&["COMMAND", "DOCS", "zadd", "nosuch"]) else {
+            panic!("COMMAND DOCS replies with a map");
+        };
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].0, bulk("zadd"));
+        let RespValue::Map(fields) = &docs[0].1 else {
+            panic!("each command's docs are a map");
+        };
+        assert!(fields.contains(&(bulk("group"), bulk("sorted-set"))));
+
+        assert_eq!(
+            reply(&["COMMAND", "COUNT", "x"]),
+            RespValue::Error(
+                "ERR wrong number of arguments for 'command|count' command".to_string()
+            )
+        );
+        assert_eq!(
+            reply(&["COMMAND", "LIST"]),
+            RespValue::Error("ERR unknown subcommand 'LIST'. Try COMMAND HELP.".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_database_commands() {
+        let db = test_db();
+        let run = |index: usize, args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        let conn = ConnCtx {
+                            db: index,
+                            ..ConnCtx::default()
+                        };
+                        let deadline = Deadline::after(None);
+                        execute(command, &db, &conn, &ClientRegistry::new(), deadline).await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+        let ok = RespValue::SimpleString("OK".into());
+
+        run(0, &["SET", "k", "zero"]).await;
+        run(1, &["SET", "k", "one"]).await;
+        run(1, &["SET", "x", "one"]).await;
+        assert_eq!(run(0, &["GET", "k"]).await, bulk("zero"));
+        assert_eq!(run(1, &["DBSIZE"]).await, RespValue::Integer(2));
+        assert_eq!(run(2, &["DBSIZE"]).await, RespValue::Integer(0));
+        assert_eq!(
+            run(16, &["GET", "k"]).await,
+            RespValue::Error("ERR DB index is out of range".into())
+        );
+
+        assert_eq!(run(0, &["SWAPDB", "0", "1"]).await, ok);
+        assert_eq!(run(0, &["GET", "k"]).await, bulk("one"));
+        assert_eq!(run(1, &["GET", "k"]).await, bulk("zero"));
+        assert_eq!(
+            run(0, &["SWAPDB", "0", "-1"]).await,
+            RespValue::Error("ERR DB index is out of range".into())
+        );
+
+        assert_eq!(run(1, &["FLUSHDB", "ASYNC"]).await, ok);
+        assert_eq!(run(1, &["DBSIZE"]).await, RespValue::Integer(0));
+        assert_eq!(run(0, &["DBSIZE"]).await, RespValue::Integer(2));
+        assert!(matches!(
+            run(0, &["FLUSHALL", "NOW"]).await,
+            RespValue::Error(_)
+        ));
+        assert_eq!(run(3, &["FLUSHALL"]).await, ok);
+        assert_eq!(run(0, &["DBSIZE"]).await, RespValue::Integer(0));
+
+        // Within a transaction, SELECT switches the rest of it over.
+        let mut store = db.lock_all().await;
+        let clients = ClientRegistry::new();
+        let mut run_locked = |args: &[&'static str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let command = Command::from_frame(frame).unwrap();
+            execute_locked(
+                command,
+                &mut store,
+                &ConnCtx::default(),
+                &clients,
+                Deadline::after(None),
+            )
+        };
+        assert_eq!(run_locked(&["SELECT", "5"]), ok);
+        run_locked(&["SET", "k", "five"]);
+        assert_eq!(store.selected(), 5);
+        drop(store);
+        assert_eq!(run(5, &["GET", "k"]).await, bulk("five"));
+    }
+
+    #[tokio::test]
+    async fn test_prefixed_databases() {
+        let db: Db = Arc::new(Shards::new(crate::config::StorageConfig {
+            prefixed_databases: true,
+            ..Default::default()
+        }));
+        let run = |index: usize, args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                let command = Command::from_frame(frame).unwrap();
+                let conn = ConnCtx {
+                    db: index,
+                    ..ConnCtx::default()
+                };
+                execute(
+                    command,
+                    &db,
+                    &conn,
+                    &ClientRegistry::new(),
+                    Deadline::after(None),
+                )
+                .await
+            }
+        };
+        let ok = RespValue::SimpleString("OK".into());
+
+        run(0, &["SET", "k", "zero"]).await;
+        run(3, &["SET", "k", "three"]).await;
+        run(3, &["RPUSH", "list", "a"]).await;
+        assert_eq!(run(0, &["GET", "k"]).await, bulk("zero"));
+        assert_eq!(run(3, &["GET", "k"]).await, bulk("three"));
+        let RespValue::Array(keys) = run(3, &["KEYS", "*"]).await else {
+            panic!("KEYS replies with an array");
+        };
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&bulk("list")));
+        assert_eq!(
+            run(0, &["KEYS", "*"]).await,
+            RespValue::Array(vec![bulk("k")])
+        );
+        assert_eq!(run(0, &["DBSIZE"]).await, RespValue::Integer(1));
+        assert_eq!(run(3, &["DBSIZE"]).await, RespValue::Integer(2));
+        assert_eq!(run(0, &["RANDOMKEY"]).await, bulk("k"));
+        assert_eq!(
+            run(3, &["BLPOP", "list", "0"]).await,
+            RespValue::Array(vec![bulk("list"), bulk("a")])
+        );
+        assert!(matches!(
+            run(0, &["SWAPDB", "0", "3"]).await,
+            RespValue::Error(_)
+        ));
+
+        // Everything is stored in database 0, behind the prefixes.
+        {
+            let store = db.lock_all().await;
+            let stored = store.shard(b"\x03k").get(b"\x03k").unwrap().cloned();
+            assert_eq!(stored, Some(Bytes::from("three")));
+        }
+
+        assert_eq!(run(3, &["FLUSHDB"]).await, ok);
+        assert_eq!(run(3, &["DBSIZE"]).await, RespValue::Integer(0));
+        assert_eq!(run(0, &["GET", "k"]).await, bulk("zero"));
+    }
+
+    #[tokio::test]
+    async fn test_set_commands() {
+        let db = test_db();
+
+        let response =
+            handle_command("*4\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n$1\r\nb\r\n", &db).await;
+        assert_eq!(response, RespValue::Integer(2));
+        let response = handle_command("*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n", &db).await;
+        assert_eq!(response, RespValue::Integer(0));
+
+        let response = handle_command("*3\r\n$9\r\nSISMEMBER\r\n$1\r\ns\r\n$1\r\na\r\n", &db).await;
+        assert_eq!(response, RespValue::Integer(1));
+
+        match handle_command("*2\r\n$8\r\nSMEMBERS\r\n$1\r\ns\r\n", &db).await {
+            RespValue::Set(mut items) => {
+                items.sort_by_key(|item| item.serialize());
+                assert_eq!(items, vec![bulk("a"), bulk("b")]);
+            }
+            other => panic!("Expected array, got {:?}", other),
+        }
+
+        let response =
+            handle_command("*4\r\n$4\r\nSREM\r\n$1\r\ns\r\n$1\r\na\r\n$1\r\nb\r\n", &db).await;
+        assert_eq!(response, RespValue::Integer(2));
+        assert_eq!(db.lock_all().await.memory_usage(), 0);
+
+        handle_command("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n", &db).await;
+        let response = handle_command("*3\r\n$4\r\nSADD\r\n$1\r\nk\r\n$1\r\na\r\n", &db).await;
+        assert!(matches!(response, RespValue::Error(e) if e.starts_with("WRONGTYPE")));
+
+        handle_command(
+            "*5\r\n$4\r\nSADD\r\n$1\r\nr\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            &db,
+        )
+        .await;
+        match handle_command("*3\r\n$11\r\nSRANDMEMBER\r\n$1\r\nr\r\n$2\r\n-5\r\n", &db).await {
+            RespValue::Array(picked) => assert_eq!(picked.len(), 5),
+            other => panic!("Expected array, got {:?}", other),
+        }
+        match handle_command("*3\r\n$11\r\nSRANDMEMBER\r\n$1\r\nr\r\n$2\r\n10\r\n", &db).await {
+            RespValue::Array(mut picked) => {
+                picked.sort_by_key(|item| item.serialize());
+                assert_eq!(picked, vec![bulk("a"), bulk("b"), bulk("c")]);
+            }
+            other => panic!("Expected array, got {:?}", other),
+        }
+        let popped = handle_command("*2\r\n$4\r\nSPOP\r\n$1\r\nr\r\n", &db).await;
+        assert!(matches!(popped, RespValue::BulkString(Some(_))));
+        match handle_command("*3\r\n$4\r\nSPOP\r\n$1\r\nr\r\n$1\r\n5\r\n", &db).await {
+            RespValue::Array(popped) => assert_eq!(popped.len(), 2),
+            other => panic!("Expected array, got {:?}", other),
+        }
+        let response = handle_command("*2\r\n$4\r\nSPOP\r\n$1\r\nr\r\n", &db).await;
+        assert_eq!(response, RespValue::BulkString(None));
+        let response = handle_command("*2\r\n$6\r\nEXISTS\r\n$1\r\nr\r\n", &db).await;
+        assert_eq!(response, RespValue::Integer(0));
+
+        // Repeated picks aren't bounded by the set, so they're limited
+        // whether or not there's a command timeout, down to the most
+        // negative count, which has no positive counterpart.
+        handle_command("*3\r\n$4\r\nSADD\r\n$1\r\nr\r\n$1\r\na\r\n", &db).await;
+        for count in [i64::MIN, -i64::MAX, -(MAX_REPEATED_PICKS as i64) - 1] {
+            let count = count.to_string();
+            let request = format!(
+                "*3\r\n$11\r\nSRANDMEMBER\r\n$1\r\nr\r\n${}\r\n{}\r\n",
+                count.len(),
+                count
+            );
+            let response = handle_command(&request, &db).await;
+            assert_eq!(
+                response,
+                RespValue::Error("ERR value is out of range".to_string())
+            );
+        }
+        let store = db.lock_all().await;
+        let picked = store
+            .shard(b"r")
+            .srandmember(b"r", -i64::MAX, &Deadline::after(None))
+            .unwrap();
+        assert_eq!(picked.len(), MAX_REPEATED_PICKS as usize);
+        let deadline = Deadline::after(Some(Duration::from_millis(0)));
+        assert_eq!(
+            store.shard(b"r").srandmember(b"r", -i64::MAX, &deadline),
+            Err(StorageError::Timeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_result_cache() {
+        let db: Db = Arc::new(Shards::new(crate::config::StorageConfig {
+            result_cache: crate::config::ResultCacheConfig {
+                ttl_ms: 60_000,
+                max_entries: 16,
+            },
+            ..Default::default()
+        }));
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                let command = Command::from_frame(frame).unwrap();
+                execute(
+                    command,
+                    &db,
+                    &ConnCtx::default(),
+                    &ClientRegistry::new(),
+                    Deadline::after(None),
+                )
+                .await
+            }
+        };
+        let range = ["ZRANGEBYSCORE", "z", "(1", "+inf"];
+        run(&["ZADD", "z", "1", "a", "2", "b"]).await;
+        assert_eq!(run(&range).await, RespValue::Array(vec![bulk("b")]));
+        assert_eq!(run(&range).await, RespValue::Array(vec![bulk("b")]));
+        let stats = db.stats();
+        assert_eq!(
+            (stats.result_cache_hits(), stats.result_cache_misses()),
+            (1, 1)
+        );
+
+        // A write to a key read invalidates the reply, even one that
+        // creates the key.
+        run(&["ZADD", "z", "3", "c"]).await;
+        let both = RespValue::Array(vec![bulk("b"), bulk("c")]);
+        assert_eq!(run(&range).await, both);
+        assert_eq!(run(&["SINTER", "s", "t"]).await, RespValue::Set(vec![]));
+        run(&["SADD", "s", "x"]).await;
+        run(&["SADD", "t", "x"]).await;
+        assert_eq!(
+            run(&["SINTER", "s", "t"]).await,
+            RespValue::Set(vec![bulk("x")])
+        );
+        // Other arguments are another entry.
+        let limited = ["ZRANGEBYSCORE", "z", "(1", "+inf", "LIMIT", "0", "1"];
+        assert_eq!(run(&limited).await, RespValue::Array(vec![bulk("b")]));
+        assert_eq!(stats.result_cache_hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_hot_keys_skip_the_lock() {
+        let db: Db = Arc::new(Shards::new(crate::config::StorageConfig {
+            hot_keys: crate::config::HotKeysConfig {
+                enabled: true,
+                reads_per_sec: 64,
+                max_keys: 4,
+            },
+            ..Default::default()
+        }));
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                let command = Command::from_frame(frame).unwrap();
+                execute(
+                    command,
+                    &db,
+                    &ConnCtx::default(),
+                    &ClientRegistry::new(),
+                    Deadline::after(None),
+                )
+                .await
+            }
+        };
+        run(&["SET", "celebrity", "v1"]).await;
+        for _ in 0..64 {
+            run(&["GET", "celebrity"]).await;
+        }
+        // Hot, it's read with every shard locked by someone else.
+        let hits = db.stats().hot_key_hits();
+        let store = db.lock_all().await;
+        assert_eq!(run(&["GET", "celebrity"]).await, bulk("v1"));
+        assert_eq!(db.stats().hot_key_hits(), hits + 1);
+        drop(store);
+
+        // A write drops the copy; the next read is under the lock again.
+        run(&["SET", "celebrity", "v2"]).await;
+        assert_eq!(run(&["GET", "celebrity"]).await, bulk("v2"));
+        assert_eq!(db.stats().hot_key_hits(), hits + 1);
+        run(&["DEL", "celebrity"]).await;
+        assert_eq!(
+            run(&["GET", "celebrity"]).await,
+            RespValue::BulkString(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_commands_see_the_connection() {
+        let db = test_db();
+        let clients = Arc::new(ClientRegistry::new());
+        let client = clients.register(None);
+        let conn = ConnCtx::new(client.id(), None);
+        let run = |command: Command, conn: &ConnCtx| {
+            let (db, clients, conn) = (db.clone(), clients.clone(), conn.clone());
+            async move { execute(command, &db, &conn, &clients, Deadline::after(None)).await }
+        };
+
+        assert_eq!(
+            run(Command::ClientId, &conn).await,
+            RespValue::Integer(client.id() as i64)
+        );
+        run(Command::ClientSetName("worker".to_string()), &conn).await;
+        assert_eq!(client.name().as_deref(), Some("worker"));
+        assert_eq!(run(Command::ClientGetName, &conn).await, bulk("worker"));
+        assert!(matches!(
+            run(Command::ClientId, &ConnCtx::default()).await,
+            RespValue::Error(_)
+        ));
+        assert_eq!(
+            Command::from_frame(RespValue::Array(vec![
+                bulk("CLIENT"),
+                bulk("reply"),
+                bulk("skip")
+            ]))
+            .unwrap(),
+            Command::ClientReply(ReplyMode::Skip)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_commands() {
+        let db = test_db();
+        let run = |args: &[&str]| {
+            let frame = RespValue::Array(
+                args.iter()
+                    .map(|a| RespValue::bulk(a.to_string()))
+                    .collect(),
+            );
+            let db = db.clone();
+            async move {
+                match Command::from_frame(frame) {
+                    Ok(command) => {
+                        execute(
+                            command,
+                            &db,
+                            &ConnCtx::default(),
+                            &ClientRegistry::new(),
+                            Deadline::after(None),
+                        )
+                        .await
+                    }
+                    Err(e) => RespValue::Error(e.to_string()),
+                }
+            }
+        };
+
+        assert_eq!(run(&["RPUSH", "l", "a", "b"]).await, RespValue::Integer(2));
+        assert_eq!(run(&["LPUSH", "l", "z"]).await, RespValue::Integer(3));
+        assert_eq!(run(&["LLEN", "l"]).await, RespValue::Integer(3));
+        assert_eq!(
+            run(&["LRANGE", "l", "0", "-1"]).await,
+            RespValue::Array(vec![bulk("z"), bulk("a"), bulk("b")])
+        );
+        assert_eq!(run(&["LPOP", "l"]).await, bulk("z"));
+        assert_eq!(
+            run(&["RPOP", "l", "5"]).await,
+            RespValue::Array(vec![bulk("b"), bulk("a")])
+        );
+        assert_eq!(run(&["LPOP", "l"]).await, RespValue::BulkString(None));
+        assert_eq!(run(&["LPOP", "l", "2"]).await, RespValue::NullArray);
+
+        // Run directly, blocking pops don't wait.
+        assert_eq!(run(&["BLPOP", "l", "m", "0"]).await, RespValue::NullArray);
+        run(&["RPUSH", "m", "x", "y"]).await;
+        assert_eq!(
+            run(&["BRPOP", "l", "m", "0.5"]).await,
+            RespValue::Array(vec![bulk("m"), bulk("y")])
+        );
+        assert_eq!(
+            Command::BLPop(vec!["m".into()], Duration::from_millis(1500)).propagation(),
+            Some(RespValue::Array(vec![
+                bulk("BLPOP"),
+                bulk("m"),
+                bulk("1.5")
+            ]))
+        );
+        assert!(matches!(
+            run(&["BLPOP", "m", "-1"]).await,
+            RespValue::Error(e) if e.contains("timeout is negative")
+        ));
+
+        run(&["SET", "s", "v"]).await;
+        assert!(matches!(
+            run(&["BLPOP", "s", "0"]).await,
+            RespValue::Error(e) if e.starts_with("WRONGTYPE")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sorted_set_commands() {
+        let db = test_db();
+
+        let response = handle_command(
+            "*8\r\n$4\r\nZADD\r\n$1\r\nz\r\n$1\r\n3\r\n$1\r\nc\r\n$1\r\n1\r\n$1\r\na\r\n$3\r\n2.5\r\n$1\r\nb\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(response, RespValue::Integer(3));
+
+        let response = handle_command("*3\r\n$6\r\nZSCORE\r\n$1\r\nz\r\n$1\r\nb\r\n", &db).await;
+        assert_eq!(response, RespValue::Double(2.5));
+        assert_eq!(response.serialize(), b"$3\r\n2.5\r\n");
+
+        let response = handle_command(
+            "*5\r\n$6\r\nZRANGE\r\n$1\r\nz\r\n$1\r\n0\r\n$2\r\n-1\r\n$10\r\nWITHSCORES\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(
+            response,
+            RespValue::Array(vec![
+                bulk("a"),
+                RespValue::Double(1.0),
+                bulk("b"),
+                RespValue::Double(2.5),
+                bulk("c"),
+                RespValue::Double(3.0)
+            ])
+        );
+
+        let response = handle_command(
+            "*4\r\n$6\r\nZRANGE\r\n$1\r\nz\r\n$2\r\n-2\r\n$3\r\n100\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(response, RespValue::Array(vec![bulk("b"), bulk("c")]));
+
+        let response = handle_command(
+            "*4\r\n$13\r\nZRANGEBYSCORE\r\n$1\r\nz\r\n$2\r\n(1\r\n$4\r\n+inf\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(response, RespValue::Array(vec![bulk("b"), bulk("c")]));
+
+        let response = handle_command(
+            "*7\r\n$13\r\nZRANGEBYSCORE\r\n$1\r\nz\r\n$4\r\n-inf\r\n$1\r\n3\r\n$5\r\nLIMIT\r\n$1\r\n1\r\n$1\r\n1\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(response, RespValue::Array(vec![bulk("b")]));
+
+        let response = handle_command(
+            "*4\r\n$4\r\nZADD\r\n$1\r\nz\r\n$3\r\nnan\r\n$1\r\nx\r\n",
+            &db,
+        )
+        .await;
+        assert!(matches!(response, RespValue::Error(_)));
+
+        let response = handle_command(
+            "*5\r\n$4\r\nZREM\r\n$1\r\nz\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n",
+            &db,
+        )
+        .await;
+        assert_eq!(response, RespValue::Integer(3));
+        assert_eq!(db.lock_all().await.memory_usage(), 0);
+    }
+
+    #[test]
+    fn test_zstore_parsing() {
+        // AGGREGATE without a function.
+        assert!(Command::from_str(
+            "*9\r\n$11\r\nZUNIONSTORE\r\n$1\r\nd\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\nb\r\n\
+             $7\r\nWEIGHTS\r\n$1\r\n2\r\n$1\r\n3\r\n$9\r\nAGGREGATE\r\n"
+        )
+        .is_err());
+        assert_eq!(
+            Command::from_str(
+                "*10\r\n$11\r\nZUNIONSTORE\r\n$1\r\nd\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\nb\r\n\
+                 $7\r\nWEIGHTS\r\n$1\r\n2\r\n$1\r\n3\r\n$9\r\nAGGREGATE\r\n$3\r\nmax\r\n"
+            )
+            .unwrap(),
+            Command::ZUnionStore(ZStore {
+                dest: "d".into(),
+                keys: vec!["a".into(), "b".into()],
+                weights: vec![2.0, 3.0],
+                aggregate: Aggregate::Max,
+            })
+        );
+        assert!(Command::from_str("*3\r\n$11\r\nZINTERSTORE\r\n$1\r\nd\r\n$1\r\n0\r\n").is_err());
+        assert!(
+            Command::from_str("*4\r\n$11\r\nZINTERSTORE\r\n$1\r\nd\r\n$1\r\n2\r\n$1\r\na\r\n")
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout() {
+        let db = test_db();
+        handle_command("*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\na\r\n", &db).await;
+
+        let command = Command::SMembers("s".into());
+        assert_eq!(command.class(), CommandClass::Read);
+        let expired = Deadline::after(Some(std::time::Duration::ZERO));
+        let response = execute(
+            command,
+            &db,
+            &ConnCtx::default(),
+            &ClientRegistry::new(),
+            expired,
+        )
+        .await;
+        assert!(matches!(response, RespValue::Error(e) if e.starts_with("TIMEOUT")));
+
+        // A command stuck behind a held lock gives up instead of waiting forever.
+        let _guard = db.lock(&["s".into()]).await;
+        let deadline = Deadline::after(Some(std::time::Duration::from_millis(10)));
+        let response = execute(
+            Command::Get("s".into()),
+            &db,
+            &ConnCtx::default(),
+            &ClientRegistry::new(),
+            deadline,
+        )
+        .await;
+        assert!(matches!(response, RespValue::Error(e) if e.starts_with("TIMEOUT")));
+    }
 }
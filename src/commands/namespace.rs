@@ -0,0 +1,194 @@
+//! Running commands in the namespace of the client's database
+//!
+//! With prefixed databases (see [`Namespace`]), a command's keys are
+//! translated to how they're stored before it runs, and the keys in its
+//! reply back. Commands over a whole database only get to see its keys:
+//! `KEYS`, `SCAN` and `EXPIRES NEXT` match within it, and `DBSIZE`,
+//! `RANDOMKEY` and `FLUSHDB` go through the keys `KEYS` finds, so they take
+//! time in proportion to the keys of every database.
+use super::{execute_locked, Command};
+use crate::connection::{ClientRegistry, ConnCtx};
+use crate::protocol::RespValue;
+use crate::storage::{Deadline, Namespace, ShardLocks};
+use bytes::Bytes;
+
+/// How a reply is translated back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    AsIs,
+    /// An array of keys, from `KEYS`.
+    Keys,
+    /// A cursor and an array of keys, from `SCAN`.
+    Scan,
+    /// One of the keys `KEYS` found, at random, for `RANDOMKEY`.
+    RandomKey,
+    /// How many keys `KEYS` found, for `DBSIZE`.
+    Count,
+    /// Pairs of a key and its TTL, from `EXPIRES NEXT`.
+    Expiring,
+    /// A key and the element popped off it, or nil, from `BLPOP` and
+    /// `BRPOP`.
+    Pop,
+}
+
+/// What running a command in a namespace takes.
+#[derive(Debug)]
+pub enum Plan {
+    /// Run the command, then translate the reply.
+    Run(Command, Reply),
+    /// `FLUSHDB`: delete each key the `KEYS` command finds.
+    Flush(Command),
+    /// Nothing to run: this is the reply.
+    Done(RespValue),
+}
+
+/// Translates `command` into `namespace`.
+pub fn translate(command: Command, namespace: Namespace) -> Plan {
+    if !namespace.is_prefixed() {
+        return Plan::Run(command, Reply::AsIs);
+    }
+    let everything = || Command::Keys(namespace.pattern(&Bytes::from_static(b"*")));
+    match command {
+        Command::Keys(pattern) => {
+            Plan::Run(Command::Keys(namespace.pattern(&pattern)), Reply::Keys)
+        }
+        Command::Scan {
+            cursor,
+            pattern,
+            count,
+        } => {
+            let pattern = pattern.unwrap_or_else(|| Bytes::from_static(b"*"));
+            let command = Command::Scan {
+                cursor,
+                pattern: Some(namespace.pattern(&pattern)),
+                count,
+            };
+            Plan::Run(command, Reply::Scan)
+        }
+        Command::ExpiresNext { count, pattern } => {
+            let pattern = pattern.unwrap_or_else(|| Bytes::from_static(b"*"));
+            let command = Command::ExpiresNext {
+                count,
+                pattern: Some(namespace.pattern(&pattern)),
+            };
+            Plan::Run(command, Reply::Expiring)
+        }
+        Command::RandomKey => Plan::Run(everything(), Reply::RandomKey),
+        Command::DbSize => Plan::Run(everything(), Reply::Count),
+        Command::FlushDb => Plan::Flush(everything()),
+        Command::SwapDb(..) => Plan::Done(RespValue::Error(
+            "ERR SWAPDB is not supported with prefixed databases".to_string(),
+        )),
+        // The connection switches namespaces; scripts can't.
+        Command::Select(_) => Plan::Done(RespValue::Error(
+            "ERR SELECT is not allowed in scripts with prefixed databases".to_string(),
+        )),
+        command @ (Command::BLPop(..) | Command::BRPop(..)) => {
+            Plan::Run(command.map_keys(|key| namespace.key(key)), Reply::Pop)
+        }
+        command => Plan::Run(command.map_keys(|key| namespace.key(key)), Reply::AsIs),
+    }
+}
+
+impl Reply {
+    /// Translates `reply` back out of `namespace`. Errors pass through.
+    pub fn apply(self, reply: RespValue, namespace: Namespace) -> RespValue {
+        let strip = |keys: Vec<RespValue>| -> Vec<RespValue> {
+            keys.into_iter()
+                .filter_map(|key| match key {
+                    RespValue::BulkString(Some(key)) => namespace.strip(&key).map(RespValue::bulk),
+                    other => Some(other),
+                })
+                .collect()
+        };
+        match (self, reply) {
+            (Reply::AsIs, reply) => reply,
+            (Reply::Keys, RespValue::Array(keys)) => RespValue::Array(strip(keys)),
+            (Reply::Scan, RespValue::Array(mut parts)) => {
+                if let Some(RespValue::Array(keys)) = parts.pop() {
+                    parts.push(RespValue::Array(strip(keys)));
+                }
+                RespValue::Array(parts)
+            }
+            (Reply::Expiring, RespValue::Array(pairs)) => RespValue::Array(
+                pairs
+                    .into_iter()
+                    .map(|pair| match pair {
+                        RespValue::Array(pair) => RespValue::Array(strip(pair)),
+                        other => other,
+                    })
+                    .collect(),
+            ),
+            (Reply::RandomKey, RespValue::Array(keys)) => {
+                let mut keys = strip(keys);
+                if keys.is_empty() {
+                    return RespValue::BulkString(None);
+                }
+                let mut seed = [0u8; 8];
+                getrandom::getrandom(&mut seed).expect("failed to draw a random key");
+                let nth = (u64::from_le_bytes(seed) % keys.len() as u64) as usize;
+                keys.swap_remove(nth)
+            }
+            (Reply::Count, RespValue::Array(keys)) => RespValue::Integer(strip(keys).len() as i64),
+            (Reply::Pop, RespValue::Array(mut popped)) => {
+                if let Some(RespValue::BulkString(Some(key))) = popped.first() {
+                    let key = namespace.strip(key).unwrap_or_else(|| key.clone());
+                    popped[0] = RespValue::bulk(key);
+                }
+                RespValue::Array(popped)
+            }
+            (_, reply) => reply,
+        }
+    }
+}
+
+/// [`execute_locked`] for a command from a client, in the namespace of
+/// its database. The locked shards must include those of the keys as
+/// stored, and have its storage database selected.
+pub fn execute_locked_in(
+    namespace: Namespace,
+    command: Command,
+    store: &mut ShardLocks,
+    conn: &ConnCtx,
+    clients: &ClientRegistry,
+    deadline: Deadline,
+) -> RespValue {
+    match translate(command, namespace) {
+        Plan::Run(command, reply) => reply.apply(
+            execute_locked(command, store, conn, clients, deadline),
+            namespace,
+        ),
+        Plan::Flush(keys) => flush(namespace, keys, store, conn, clients, deadline),
+        Plan::Done(reply) => reply,
+    }
+}
+
+/// Deletes the keys of `namespace` that `keys` finds, all shards locked.
+pub(super) fn flush(
+    namespace: Namespace,
+    keys: Command,
+    store: &mut ShardLocks,
+    conn: &ConnCtx,
+    clients: &ClientRegistry,
+    deadline: Deadline,
+) -> RespValue {
+    let keys = match execute_locked(keys, store, conn, clients, deadline) {
+        RespValue::Array(keys) => keys,
+        error => return error,
+    };
+    let keys: Vec<Bytes> = keys
+        .into_iter()
+        .filter_map(|key| match key {
+            RespValue::BulkString(Some(key)) if namespace.strip(&key).is_some() => Some(key),
+            _ => None,
+        })
+        .collect();
+    if !keys.is_empty() {
+        if let error @ RespValue::Error(_) =
+            execute_locked(Command::Del(keys), store, conn, clients, deadline)
+        {
+            return error;
+        }
+    }
+    RespValue::SimpleString("OK".to_string())
+}
@@ -0,0 +1,798 @@
+//! Building each command from its arguments
+//!
+//! One function per command, or per family of commands that differ only by
+//! name, each looked up through the [`super::registry`]. The table has
+//! already checked the number of arguments against the command's arity, so
+//! these only check what it can't express.
+use super::{
+    acl, parse_db_index, parse_expire_time, parse_float, parse_integer, parse_score_bound,
+    parse_script_args, parse_timeout, parse_zstore, text, unknown_subcommand, wrong_arity,
+    wrong_subcommand_arity, Command, CommandError, KillFilter, ReplyMode, Restore, Secret,
+    SetCondition, SetExpiry, SetOptions, ShutdownRequest, TimeUnit, DEFAULT_SAMPLES,
+};
+use crate::storage::MAX_REPEATED_PICKS;
+use bytes::Bytes;
+
+pub(super) fn set(args: &[Bytes]) -> Result<Command, CommandError> {
+    let mut options = SetOptions::default();
+    // NX and XX exclude each other, and the TTL options all
+    // the others, but each may be repeated.
+    let mut ttl_option = None;
+    let mut rest = args[3..].iter();
+    while let Some(arg) = rest.next() {
+        let option = text(arg).to_uppercase();
+        let condition = match option.as_str() {
+            "NX" => SetCondition::IfMissing,
+            "XX" => SetCondition::IfExists,
+            "GET" => {
+                options.get = true;
+                continue;
+            }
+            "KEEPTTL" | "EX" | "PX" | "EXAT" | "PXAT" => {
+                if ttl_option.as_ref().is_some_and(|seen| *seen != option) {
+                    return Err(CommandError::SyntaxError);
+                }
+                options.expiry = match option.as_str() {
+                    "KEEPTTL" => SetExpiry::Keep,
+                    "EX" => SetExpiry::After(parse_expire_time(rest.next(), 1000)?),
+                    "PX" => SetExpiry::After(parse_expire_time(rest.next(), 1)?),
+                    "EXAT" => SetExpiry::At(parse_expire_time(rest.next(), 1000)?),
+                    _ => SetExpiry::At(parse_expire_time(rest.next(), 1)?),
+                };
+                ttl_option = Some(option);
+                continue;
+            }
+            _ => return Err(CommandError::SyntaxError),
+        };
+        if options.condition != SetCondition::Always && options.condition != condition {
+            return Err(CommandError::SyntaxError);
+        }
+        options.condition = condition;
+    }
+    Ok(Command::Set(args[1].clone(), args[2].clone(), options))
+}
+
+pub(super) fn get(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Get(args[1].clone()))
+}
+
+pub(super) fn getrange(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::GetRange(
+        args[1].clone(),
+        parse_integer(&args[2])?,
+        parse_integer(&args[3])?,
+    ))
+}
+
+pub(super) fn del(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Del(args[1..].to_vec()))
+}
+
+pub(super) fn exists_mget(args: &[Bytes]) -> Result<Command, CommandError> {
+    let keys = args[1..].to_vec();
+    if args[0].eq_ignore_ascii_case(b"EXISTS") {
+        Ok(Command::Exists(keys))
+    } else {
+        Ok(Command::MGet(keys))
+    }
+}
+
+pub(super) fn incr_decr(args: &[Bytes]) -> Result<Command, CommandError> {
+    let delta = if args[0].eq_ignore_ascii_case(b"INCR") {
+        1
+    } else {
+        -1
+    };
+    Ok(Command::IncrBy(args[1].clone(), delta))
+}
+
+pub(super) fn incrby(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::IncrBy(args[1].clone(), parse_integer(&args[2])?))
+}
+
+pub(super) fn strlen(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::StrLen(args[1].clone()))
+}
+
+pub(super) fn append_setnx_getset(args: &[Bytes]) -> Result<Command, CommandError> {
+    let (key, value) = (args[1].clone(), args[2].clone());
+    match text(&args[0]).to_uppercase().as_str() {
+        "APPEND" => Ok(Command::Append(key, value)),
+        "SETNX" => Ok(Command::SetNx(key, value)),
+        _ => Ok(Command::GetSet(key, value)),
+    }
+}
+
+pub(super) fn mset(args: &[Bytes]) -> Result<Command, CommandError> {
+    if args.len().is_multiple_of(2) {
+        return Err(wrong_arity(args));
+    }
+    let pairs = args[1..]
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
+    Ok(Command::MSet(pairs))
+}
+
+pub(super) fn keys(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Keys(args[1].clone()))
+}
+
+pub(super) fn scan(args: &[Bytes]) -> Result<Command, CommandError> {
+    let cursor = text(&args[1])
+        .parse()
+        .map_err(|_| CommandError::InvalidArgument("invalid cursor".to_string()))?;
+    let (mut pattern, mut count) = (None, 10);
+    let mut i = 2;
+    while i < args.len() {
+        match text(&args[i]).to_uppercase().as_str() {
+            "MATCH" if i + 1 < args.len() => pattern = Some(args[i + 1].clone()),
+            "COUNT" if i + 1 < args.len() => {
+                count = usize::try_from(parse_integer(&args[i + 1])?)
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or(CommandError::SyntaxError)?;
+            }
+            _ => return Err(CommandError::SyntaxError),
+        }
+        i += 2;
+    }
+    Ok(Command::Scan {
+        cursor,
+        pattern,
+        count,
+    })
+}
+
+pub(super) fn key_type(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Type(args[1].clone()))
+}
+
+pub(super) fn ttl_pttl(args: &[Bytes]) -> Result<Command, CommandError> {
+    let unit = match text(&args[0]).to_uppercase().as_str() {
+        "TTL" => TimeUnit::Seconds,
+        _ => TimeUnit::Milliseconds,
+    };
+    Ok(Command::Ttl(args[1].clone(), unit))
+}
+
+pub(super) fn dump(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Dump(args[1].clone()))
+}
+
+pub(super) fn restore(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Restore(Restore::parse(args)?))
+}
+
+pub(super) fn randomkey(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::RandomKey)
+}
+
+pub(super) fn select(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Select(parse_db_index(&args[1])?))
+}
+
+pub(super) fn dbsize(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::DbSize)
+}
+
+/// Flushing is always synchronous; ASYNC and SYNC are accepted
+/// for compatibility.
+pub(super) fn flushdb_flushall(args: &[Bytes]) -> Result<Command, CommandError> {
+    match &args[1..] {
+        [] => {}
+        [mode] if mode.eq_ignore_ascii_case(b"ASYNC") || mode.eq_ignore_ascii_case(b"SYNC") => {}
+        _ => return Err(CommandError::SyntaxError),
+    }
+    if args[0].eq_ignore_ascii_case(b"FLUSHDB") {
+        Ok(Command::FlushDb)
+    } else {
+        Ok(Command::FlushAll)
+    }
+}
+
+pub(super) fn swapdb(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::SwapDb(
+        parse_db_index(&args[1])?,
+        parse_db_index(&args[2])?,
+    ))
+}
+
+/// `INFO [section ...] [--json]`.
+pub(super) fn info(args: &[Bytes]) -> Result<Command, CommandError> {
+    let mut sections = Vec::new();
+    let mut json = false;
+    for arg in &args[1..] {
+        match text(arg).to_lowercase() {
+            flag if flag == "--json" => json = true,
+            section => sections.push(section),
+        }
+    }
+    Ok(Command::Info { sections, json })
+}
+
+/// `COMMAND` alone is `COMMAND INFO` for every command.
+pub(super) fn command(args: &[Bytes]) -> Result<Command, CommandError> {
+    let Some(sub) = args.get(1) else {
+        return Ok(Command::CommandInfo(vec![]));
+    };
+    let names = || args[2..].iter().map(|n| text(n).to_lowercase()).collect();
+    match text(sub).to_uppercase().as_str() {
+        "COUNT" if args.len() == 2 => Ok(Command::CommandCount),
+        "COUNT" => Err(wrong_subcommand_arity(args)),
+        "INFO" => Ok(Command::CommandInfo(names())),
+        "DOCS" => Ok(Command::CommandDocs(names())),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn memory(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("USAGE", [key]) => Ok(Command::MemoryUsage(key.clone(), DEFAULT_SAMPLES)),
+        ("USAGE", [key, option, count]) if option.eq_ignore_ascii_case(b"SAMPLES") => {
+            let samples =
+                usize::try_from(parse_integer(count)?).map_err(|_| CommandError::NotAnInteger)?;
+            Ok(Command::MemoryUsage(key.clone(), samples))
+        }
+        ("USAGE", [_, ..]) => Err(CommandError::SyntaxError),
+        ("STATS", []) => Ok(Command::MemoryStats),
+        ("DOCTOR", []) => Ok(Command::MemoryDoctor),
+        ("USAGE" | "STATS" | "DOCTOR", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn expires(args: &[Bytes]) -> Result<Command, CommandError> {
+    let count = |count: &Bytes| {
+        usize::try_from(parse_integer(count)?).map_err(|_| CommandError::NotAnInteger)
+    };
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("NEXT", [n]) => Ok(Command::ExpiresNext {
+            count: count(n)?,
+            pattern: None,
+        }),
+        ("NEXT", [n, option, pattern]) if option.eq_ignore_ascii_case(b"MATCH") => {
+            Ok(Command::ExpiresNext {
+                count: count(n)?,
+                pattern: Some(pattern.clone()),
+            })
+        }
+        ("NEXT", [_, ..]) => Err(CommandError::SyntaxError),
+        ("NEXT", []) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn features(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Features)
+}
+
+pub(super) fn debug(args: &[Bytes]) -> Result<Command, CommandError> {
+    match text(&args[1]).to_uppercase().as_str() {
+        "FEATURES" => Ok(Command::Features),
+        "BIGKEYS" => Ok(Command::DebugBigKeys),
+        "LOCKSTATS" => Ok(Command::DebugLockStats),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn save(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Save)
+}
+
+pub(super) fn bgsave(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::BgSave)
+}
+
+pub(super) fn bgrewriteaof(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::BgRewriteAof)
+}
+
+pub(super) fn config(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("GET", patterns) if !patterns.is_empty() => Ok(Command::ConfigGet(
+            patterns.iter().map(|p| text(p).into_owned()).collect(),
+        )),
+        ("SET", [name, value]) => Ok(Command::ConfigSet(
+            text(name).into_owned(),
+            text(value).into_owned(),
+        )),
+        ("REWRITE", []) => Ok(Command::ConfigRewrite),
+        ("GET" | "SET" | "REWRITE", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn slowlog(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("GET", []) => Ok(Command::SlowlogGet(Some(10))),
+        ("GET", [count]) => match text(count).parse::<i64>() {
+            Ok(-1) => Ok(Command::SlowlogGet(None)),
+            Ok(count) if count >= 0 => Ok(Command::SlowlogGet(Some(count as usize))),
+            _ => Err(CommandError::InvalidArgument(
+                "count should be greater than or equal to -1".to_string(),
+            )),
+        },
+        ("LEN", []) => Ok(Command::SlowlogLen),
+        ("RESET", []) => Ok(Command::SlowlogReset),
+        ("GET" | "LEN" | "RESET", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn latency(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("LATEST", []) => Ok(Command::LatencyLatest),
+        ("HISTORY", [event]) => Ok(Command::LatencyHistory(text(event).to_lowercase())),
+        ("RESET", events) => Ok(Command::LatencyReset(
+            events.iter().map(|e| text(e).into_owned()).collect(),
+        )),
+        ("LATEST" | "HISTORY", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn auth(args: &[Bytes]) -> Result<Command, CommandError> {
+    match args.len() {
+        2 => Ok(Command::Auth(
+            None,
+            Secret::new(text(&args[1]).into_owned()),
+        )),
+        3 => Ok(Command::Auth(
+            Some(text(&args[1]).into_owned()),
+            Secret::new(text(&args[2]).into_owned()),
+        )),
+        _ => Err(wrong_arity(args)),
+    }
+}
+
+pub(super) fn client(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("ID", []) => Ok(Command::ClientId),
+        ("GETNAME", []) => Ok(Command::ClientGetName),
+        ("LIST", []) => Ok(Command::ClientList),
+        ("REPLY", [mode]) => match text(mode).to_uppercase().as_str() {
+            "ON" => Ok(Command::ClientReply(ReplyMode::On)),
+            "OFF" => Ok(Command::ClientReply(ReplyMode::Off)),
+            "SKIP" => Ok(Command::ClientReply(ReplyMode::Skip)),
+            _ => Err(CommandError::SyntaxError),
+        },
+        ("SETNAME", [name]) => {
+            if name.iter().any(|b| *b <= b' ' || *b > b'~') {
+                return Err(CommandError::InvalidArgument(
+                    "Client names cannot contain spaces, newlines or special characters."
+                        .to_string(),
+                ));
+            }
+            Ok(Command::ClientSetName(text(name).into_owned()))
+        }
+        ("KILL", [addr]) => Ok(Command::ClientKill {
+            filter: KillFilter {
+                addr: Some(text(addr).into_owned()),
+                ..KillFilter::default()
+            },
+            legacy: true,
+        }),
+        ("KILL", filters) if !filters.is_empty() && filters.len() % 2 == 0 => {
+            let mut filter = KillFilter {
+                skip_me: true,
+                ..KillFilter::default()
+            };
+            for pair in filters.chunks(2) {
+                let value = text(&pair[1]);
+                match text(&pair[0]).to_uppercase().as_str() {
+                    "ID" => filter.id = Some(value.parse().map_err(|_| CommandError::SyntaxError)?),
+                    "ADDR" => filter.addr = Some(value.into_owned()),
+                    "SKIPME" => {
+                        filter.skip_me = match value.to_lowercase().as_str() {
+                            "yes" => true,
+                            "no" => false,
+                            _ => return Err(CommandError::SyntaxError),
+                        }
+                    }
+                    _ => return Err(CommandError::SyntaxError),
+                }
+            }
+            Ok(Command::ClientKill {
+                filter,
+                legacy: false,
+            })
+        }
+        ("ID" | "GETNAME" | "LIST" | "SETNAME" | "KILL", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn acl(args: &[Bytes]) -> Result<Command, CommandError> {
+    match text(&args[1]).to_uppercase().as_str() {
+        "GENPASS" => match args.len() {
+            2 => Ok(Command::AclGenPass(acl::DEFAULT_GENPASS_BITS)),
+            3 => match text(&args[2]).parse::<u32>() {
+                Ok(bits) if (1..=4096).contains(&bits) => {
+                    Ok(Command::AclGenPass(bits))
+                }
+                _ => Err(CommandError::InvalidArgument(
+                    "ACL GENPASS argument must be the number of bits for the output password, a positive number up to 4096".to_string(),
+                )),
+            },
+            _ => Err(wrong_subcommand_arity(args)),
+        },
+        "LIST" => Ok(Command::AclList),
+        "STATS" => match args.len() {
+            2 => Ok(Command::AclStats(None)),
+            3 => Ok(Command::AclStats(Some(text(&args[2]).into_owned()))),
+            _ => Err(wrong_subcommand_arity(args)),
+        },
+        "WHOAMI" => Ok(Command::AclWhoAmI),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn sadd_srem(args: &[Bytes]) -> Result<Command, CommandError> {
+    let key = args[1].clone();
+    let members = args[2..].to_vec();
+    if args[0].eq_ignore_ascii_case(b"SADD") {
+        Ok(Command::SAdd(key, members))
+    } else {
+        Ok(Command::SRem(key, members))
+    }
+}
+
+pub(super) fn smembers(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::SMembers(args[1].clone()))
+}
+
+pub(super) fn sismember(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::SIsMember(args[1].clone(), args[2].clone()))
+}
+
+pub(super) fn spop(args: &[Bytes]) -> Result<Command, CommandError> {
+    let count = match args.len() {
+        2 => None,
+        3 => match parse_integer(&args[2])? {
+            count if count >= 0 => Some(count as usize),
+            _ => {
+                return Err(CommandError::InvalidArgument(
+                    "value is out of range, must be positive".to_string(),
+                ))
+            }
+        },
+        _ => return Err(wrong_arity(args)),
+    };
+    Ok(Command::SPop(args[1].clone(), count))
+}
+
+pub(super) fn srandmember(args: &[Bytes]) -> Result<Command, CommandError> {
+    let count = match args.len() {
+        2 => None,
+        // As in Redis, a count has to have a positive counterpart; repeated
+        // picks are also limited, as they aren't by the set's size.
+        3 => match parse_integer(&args[2])? {
+            count if count < -(MAX_REPEATED_PICKS as i64) => {
+                return Err(CommandError::InvalidArgument(
+                    "value is out of range".to_string(),
+                ))
+            }
+            count => Some(count),
+        },
+        _ => return Err(wrong_arity(args)),
+    };
+    Ok(Command::SRandMember(args[1].clone(), count))
+}
+
+pub(super) fn sinter_sunion(args: &[Bytes]) -> Result<Command, CommandError> {
+    let keys = args[1..].to_vec();
+    if args[0].eq_ignore_ascii_case(b"SINTER") {
+        Ok(Command::SInter(keys))
+    } else {
+        Ok(Command::SUnion(keys))
+    }
+}
+
+pub(super) fn sinterstore_sunionstore(args: &[Bytes]) -> Result<Command, CommandError> {
+    let dest = args[1].clone();
+    let keys = args[2..].to_vec();
+    if args[0].eq_ignore_ascii_case(b"SINTERSTORE") {
+        Ok(Command::SInterStore(dest, keys))
+    } else {
+        Ok(Command::SUnionStore(dest, keys))
+    }
+}
+
+pub(super) fn zadd(args: &[Bytes]) -> Result<Command, CommandError> {
+    if !args.len().is_multiple_of(2) {
+        return Err(CommandError::SyntaxError);
+    }
+    let members = args[2..]
+        .chunks(2)
+        .map(|pair| Ok((parse_float(&pair[0])?, pair[1].clone())))
+        .collect::<Result<_, CommandError>>()?;
+    Ok(Command::ZAdd(args[1].clone(), members))
+}
+
+pub(super) fn zscore(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::ZScore(args[1].clone(), args[2].clone()))
+}
+
+pub(super) fn zrange(args: &[Bytes]) -> Result<Command, CommandError> {
+    let withscores = match args.len() {
+        4 => false,
+        5 if args[4].eq_ignore_ascii_case(b"WITHSCORES") => true,
+        5 => return Err(CommandError::SyntaxError),
+        _ => return Err(wrong_arity(args)),
+    };
+    Ok(Command::ZRange {
+        key: args[1].clone(),
+        start: parse_integer(&args[2])?,
+        stop: parse_integer(&args[3])?,
+        withscores,
+    })
+}
+
+pub(super) fn zrangebyscore(args: &[Bytes]) -> Result<Command, CommandError> {
+    let mut withscores = false;
+    let mut limit = None;
+    let mut i = 4;
+    while i < args.len() {
+        match text(&args[i]).to_uppercase().as_str() {
+            "WITHSCORES" => {
+                withscores = true;
+                i += 1;
+            }
+            "LIMIT" if i + 2 < args.len() => {
+                limit = Some((parse_integer(&args[i + 1])?, parse_integer(&args[i + 2])?));
+                i += 3;
+            }
+            _ => return Err(CommandError::SyntaxError),
+        }
+    }
+    Ok(Command::ZRangeByScore {
+        key: args[1].clone(),
+        min: parse_score_bound(&args[2])?,
+        max: parse_score_bound(&args[3])?,
+        withscores,
+        limit,
+    })
+}
+
+pub(super) fn zrem(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::ZRem(args[1].clone(), args[2..].to_vec()))
+}
+
+pub(super) fn zunionstore(args: &[Bytes]) -> Result<Command, CommandError> {
+    parse_zstore(args).map(Command::ZUnionStore)
+}
+
+pub(super) fn zinterstore(args: &[Bytes]) -> Result<Command, CommandError> {
+    parse_zstore(args).map(Command::ZInterStore)
+}
+
+pub(super) fn lpush_rpush(args: &[Bytes]) -> Result<Command, CommandError> {
+    let key = args[1].clone();
+    let elements = args[2..].to_vec();
+    if args[0].eq_ignore_ascii_case(b"LPUSH") {
+        Ok(Command::LPush(key, elements))
+    } else {
+        Ok(Command::RPush(key, elements))
+    }
+}
+
+pub(super) fn lpop_rpop(args: &[Bytes]) -> Result<Command, CommandError> {
+    let count = match args.len() {
+        2 => None,
+        3 => match parse_integer(&args[2])? {
+            count if count >= 0 => Some(count as usize),
+            _ => {
+                return Err(CommandError::InvalidArgument(
+                    "value is out of range, must be positive".to_string(),
+                ))
+            }
+        },
+        _ => return Err(wrong_arity(args)),
+    };
+    let key = args[1].clone();
+    if args[0].eq_ignore_ascii_case(b"LPOP") {
+        Ok(Command::LPop(key, count))
+    } else {
+        Ok(Command::RPop(key, count))
+    }
+}
+
+pub(super) fn llen(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::LLen(args[1].clone()))
+}
+
+pub(super) fn lrange(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::LRange(
+        args[1].clone(),
+        parse_integer(&args[2])?,
+        parse_integer(&args[3])?,
+    ))
+}
+
+pub(super) fn blpop_brpop(args: &[Bytes]) -> Result<Command, CommandError> {
+    let timeout = parse_timeout(&args[args.len() - 1])?;
+    let keys = args[1..args.len() - 1].to_vec();
+    if args[0].eq_ignore_ascii_case(b"BLPOP") {
+        Ok(Command::BLPop(keys, timeout))
+    } else {
+        Ok(Command::BRPop(keys, timeout))
+    }
+}
+
+pub(super) fn subscribe_psubscribe(args: &[Bytes]) -> Result<Command, CommandError> {
+    let names = args[1..].to_vec();
+    if args[0].eq_ignore_ascii_case(b"SUBSCRIBE") {
+        Ok(Command::Subscribe(names))
+    } else {
+        Ok(Command::PSubscribe(names))
+    }
+}
+
+pub(super) fn unsubscribe(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Unsubscribe(args[1..].to_vec()))
+}
+
+pub(super) fn punsubscribe(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::PUnsubscribe(args[1..].to_vec()))
+}
+
+pub(super) fn publish(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Publish(args[1].clone(), args[2].clone()))
+}
+
+pub(super) fn pubsub(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("HISTORY", [channel]) => Ok(Command::PubSubHistory(channel.clone(), None)),
+        ("HISTORY", [channel, count]) => match parse_integer(count)? {
+            count if count >= 0 => Ok(Command::PubSubHistory(
+                channel.clone(),
+                Some(count as usize),
+            )),
+            _ => Err(CommandError::InvalidArgument(
+                "value is out of range, must be positive".to_string(),
+            )),
+        },
+        ("HISTORY", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn multi(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Multi)
+}
+
+pub(super) fn exec(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Exec)
+}
+
+pub(super) fn discard(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Discard)
+}
+
+pub(super) fn watch(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Watch(args[1..].to_vec()))
+}
+
+pub(super) fn unwatch(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Unwatch)
+}
+
+pub(super) fn ping(args: &[Bytes]) -> Result<Command, CommandError> {
+    match args.len() {
+        1 => Ok(Command::Ping(None)),
+        2 => Ok(Command::Ping(Some(args[1].clone()))),
+        _ => Err(wrong_arity(args)),
+    }
+}
+
+pub(super) fn quit(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Quit)
+}
+
+pub(super) fn reset(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Reset)
+}
+
+pub(super) fn hello(args: &[Bytes]) -> Result<Command, CommandError> {
+    let protover = args.get(1).map(|arg| parse_integer(arg)).transpose()?;
+    let auth = match &args[args.len().min(2)..] {
+        [] => None,
+        [option, user, password] if option.eq_ignore_ascii_case(b"AUTH") => Some((
+            text(user).into_owned(),
+            Secret::new(text(password).into_owned()),
+        )),
+        _ => return Err(CommandError::SyntaxError),
+    };
+    Ok(Command::Hello { protover, auth })
+}
+
+pub(super) fn shutdown(args: &[Bytes]) -> Result<Command, CommandError> {
+    let mut request = ShutdownRequest::default();
+    for arg in &args[1..] {
+        match text(arg).to_uppercase().as_str() {
+            "NOSAVE" if request.save.is_none() => request.save = Some(false),
+            "SAVE" if request.save.is_none() => request.save = Some(true),
+            "NOW" => request.now = true,
+            _ => return Err(CommandError::SyntaxError),
+        }
+    }
+    Ok(Command::Shutdown(request))
+}
+
+pub(super) fn replicaof(args: &[Bytes]) -> Result<Command, CommandError> {
+    let (host, port) = (text(&args[1]), text(&args[2]));
+    if host.eq_ignore_ascii_case("NO") && port.eq_ignore_ascii_case("ONE") {
+        return Ok(Command::ReplicaOf(None));
+    }
+    let port = port.parse().map_err(|_| CommandError::NotAnInteger)?;
+    Ok(Command::ReplicaOf(Some((host.into_owned(), port))))
+}
+
+pub(super) fn replconf(args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::ReplConf(args[1..].to_vec()))
+}
+
+pub(super) fn psync(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Psync)
+}
+
+pub(super) fn monitor(_args: &[Bytes]) -> Result<Command, CommandError> {
+    Ok(Command::Monitor)
+}
+
+pub(super) fn cluster(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), args.len()) {
+        ("SLOTS", 2) => Ok(Command::ClusterSlots),
+        ("SHARDS", 2) => Ok(Command::ClusterShards),
+        ("KEYSLOT", 3) => Ok(Command::ClusterKeySlot(args[2].clone())),
+        ("SLOTS" | "SHARDS" | "KEYSLOT", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
+
+pub(super) fn cdc(args: &[Bytes]) -> Result<Command, CommandError> {
+    if !args[1].eq_ignore_ascii_case(b"TAIL") {
+        return Err(unknown_subcommand(args));
+    }
+    match &args[2..] {
+        [] => Ok(Command::CdcTail(None)),
+        [option, pattern] if option.eq_ignore_ascii_case(b"MATCH") => {
+            Ok(Command::CdcTail(Some(pattern.clone())))
+        }
+        _ => Err(CommandError::SyntaxError),
+    }
+}
+
+pub(super) fn eval_evalsha(args: &[Bytes]) -> Result<Command, CommandError> {
+    let (keys, rest) = parse_script_args(&args[2..])?;
+    if args[0].eq_ignore_ascii_case(b"EVAL") {
+        Ok(Command::Eval {
+            script: args[1].clone(),
+            keys,
+            args: rest,
+        })
+    } else {
+        Ok(Command::EvalSha {
+            sha: args[1].clone(),
+            keys,
+            args: rest,
+        })
+    }
+}
+
+pub(super) fn script(args: &[Bytes]) -> Result<Command, CommandError> {
+    match (text(&args[1]).to_uppercase().as_str(), &args[2..]) {
+        ("LOAD", [script]) => Ok(Command::ScriptLoad(script.clone())),
+        ("EXISTS", shas) if !shas.is_empty() => Ok(Command::ScriptExists(shas.to_vec())),
+        // Flushing is always synchronous.
+        ("FLUSH", []) => Ok(Command::ScriptFlush),
+        ("FLUSH", [mode])
+            if mode.eq_ignore_ascii_case(b"ASYNC") || mode.eq_ignore_ascii_case(b"SYNC") =>
+        {
+            Ok(Command::ScriptFlush)
+        }
+        ("LOAD" | "EXISTS" | "FLUSH", _) => Err(wrong_subcommand_arity(args)),
+        _ => Err(unknown_subcommand(args)),
+    }
+}
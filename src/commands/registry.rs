@@ -0,0 +1,1434 @@
+//! The command table: the name, arity, flags, key positions and class of
+//! every command, the function building it from its arguments and the
+//! handler running it
+//!
+//! [`Command::from_frame`] dispatches through it, so a command missing from
+//! the table is unknown, and `COMMAND` describes the commands from it. A
+//! parsed command finds its entry again by name, for its class and handler.
+use super::admin::Admin;
+use super::clients::Clients;
+use super::handler::CommandHandler;
+use super::keyspace::Keyspace;
+use super::lists::Lists;
+use super::scripting::Scripting;
+use super::sets::Sets;
+use super::strings::Strings;
+use super::zsets::SortedSets;
+use super::{parse, Command, CommandClass, CommandError};
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Command flags, as `COMMAND INFO` lists them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Write,
+    ReadOnly,
+    /// Refused once `max_memory` is reached.
+    DenyOom,
+    Admin,
+    PubSub,
+    /// Not allowed in scripts.
+    NoScript,
+    /// Allowed while the dataset is loading.
+    Loading,
+    /// Allowed on a replica with stale data.
+    Stale,
+    Fast,
+    /// The keys aren't at fixed positions; see `first_key`.
+    MovableKeys,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 10] = [
+        Flag::Write,
+        Flag::ReadOnly,
+        Flag::DenyOom,
+        Flag::Admin,
+        Flag::PubSub,
+        Flag::NoScript,
+        Flag::Loading,
+        Flag::Stale,
+        Flag::Fast,
+        Flag::MovableKeys,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::ReadOnly => "readonly",
+            Flag::DenyOom => "denyoom",
+            Flag::Admin => "admin",
+            Flag::PubSub => "pubsub",
+            Flag::NoScript => "noscript",
+            Flag::Loading => "loading",
+            Flag::Stale => "stale",
+            Flag::Fast => "fast",
+            Flag::MovableKeys => "movablekeys",
+        }
+    }
+}
+
+/// One command of the table.
+pub struct CommandSpec {
+    /// Lowercase.
+    pub name: &'static str,
+    /// Arguments taken, the name included; negative for at least that many.
+    pub arity: i32,
+    pub flags: &'static [Flag],
+    /// Positions of the first and last key, and the step between keys, in
+    /// the arguments; `(0, 0, 0)` for none. A negative last key counts from
+    /// the end: -1 for the last argument.
+    pub keys: (i32, i32, i32),
+    /// The class of most of its forms; subcommands may differ, see
+    /// [`Command::class`].
+    pub class: CommandClass,
+    /// The group `COMMAND DOCS` files it under.
+    pub group: &'static str,
+    pub summary: &'static str,
+    /// Builds the command from its arguments, their number already checked
+    /// against `arity`.
+    pub(super) parse: fn(&[Bytes]) -> Result<Command, CommandError>,
+    /// Runs the command; `None` for commands answered without storage, or
+    /// by the connection. Like `class`, that of most of its forms.
+    pub(super) handler: Option<&'static dyn CommandHandler>,
+}
+
+impl CommandSpec {
+    /// Whether `count` arguments, the name included, fit the arity.
+    pub fn accepts(&self, count: usize) -> bool {
+        let arity = self.arity.unsigned_abs() as usize;
+        if self.arity < 0 {
+            count >= arity
+        } else {
+            count == arity
+        }
+    }
+
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// The entry of `COMMAND INFO`: name, arity, flags, key positions, ACL
+    /// categories, then tips, key specs and subcommands, which are left
+    /// empty.
+    pub fn info(&self) -> RespValue {
+        let (first, last, step) = self.keys;
+        RespValue::Array(vec![
+            RespValue::bulk(self.name),
+            RespValue::Integer(self.arity.into()),
+            RespValue::Set(
+                self.flags
+                    .iter()
+                    .map(|flag| RespValue::SimpleString(flag.name().to_string()))
+                    .collect(),
+            ),
+            RespValue::Integer(first.into()),
+            RespValue::Integer(last.into()),
+            RespValue::Integer(step.into()),
+            RespValue::Set(vec![RespValue::SimpleString(format!(
+                "@{}",
+                self.class.category()
+            ))]),
+            RespValue::Set(vec![]),
+            RespValue::Array(vec![]),
+            RespValue::Array(vec![]),
+        ])
+    }
+
+    /// The name and documentation `COMMAND DOCS` gives for the command.
+    pub fn docs(&self) -> (RespValue, RespValue) {
+        (
+            RespValue::bulk(self.name),
+            RespValue::Map(vec![
+                (RespValue::bulk("summary"), RespValue::bulk(self.summary)),
+                (RespValue::bulk("group"), RespValue::bulk(self.group)),
+            ]),
+        )
+    }
+}
+
+/// Every command, in no particular order.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "set",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Sets the string value of a key, optionally with a condition or an expiry.",
+        parse: parse::set,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "string",
+        summary: "Returns the string value of a key.",
+        parse: parse::get,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "getrange",
+        arity: 4,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "string",
+        summary: "Returns a substring of the string stored at a key.",
+        parse: parse::getrange,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "del",
+        arity: -2,
+        flags: &[Flag::Write],
+        keys: (1, -1, 1),
+        class: CommandClass::Write,
+        group: "generic",
+        summary: "Deletes one or more keys.",
+        parse: parse::del,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "exists",
+        arity: -2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, -1, 1),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Counts how many of the given keys exist.",
+        parse: parse::exists_mget,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "incr",
+        arity: 2,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Increments the integer value of a key by one.",
+        parse: parse::incr_decr,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "decr",
+        arity: 2,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Decrements the integer value of a key by one.",
+        parse: parse::incr_decr,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "incrby",
+        arity: 3,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Increments the integer value of a key by a number.",
+        parse: parse::incrby,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "strlen",
+        arity: 2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "string",
+        summary: "Returns the length of a string value.",
+        parse: parse::strlen,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "append",
+        arity: 3,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Appends a string to the value of a key.",
+        parse: parse::append_setnx_getset,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "setnx",
+        arity: 3,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Sets the string value of a key only when it doesn't exist.",
+        parse: parse::append_setnx_getset,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "getset",
+        arity: 3,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Sets the string value of a key and returns its previous value.",
+        parse: parse::append_setnx_getset,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "mset",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, -1, 2),
+        class: CommandClass::Write,
+        group: "string",
+        summary: "Sets the string values of several keys.",
+        parse: parse::mset,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "mget",
+        arity: -2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, -1, 1),
+        class: CommandClass::Read,
+        group: "string",
+        summary: "Returns the string values of several keys.",
+        parse: parse::exists_mget,
+        handler: Some(&Strings),
+    },
+    CommandSpec {
+        name: "keys",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        keys: (0, 0, 0),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Returns the keys matching a pattern.",
+        parse: parse::keys,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "scan",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        keys: (0, 0, 0),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Iterates over the keys of the database.",
+        parse: parse::scan,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "type",
+        arity: 2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Returns the type of the value stored at a key.",
+        parse: parse::key_type,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "ttl",
+        arity: 2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Returns the time to live of a key, in seconds.",
+        parse: parse::ttl_pttl,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "pttl",
+        arity: 2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Returns the time to live of a key, in milliseconds.",
+        parse: parse::ttl_pttl,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "dump",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Returns a serialized copy of the value stored at a key.",
+        parse: parse::dump,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "restore",
+        arity: -4,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "generic",
+        summary: "Creates a key from the serialized value made by DUMP.",
+        parse: parse::restore,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "randomkey",
+        arity: 1,
+        flags: &[Flag::ReadOnly],
+        keys: (0, 0, 0),
+        class: CommandClass::Read,
+        group: "generic",
+        summary: "Returns a random key of the database.",
+        parse: parse::randomkey,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "select",
+        arity: 2,
+        flags: &[Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Changes the database of the connection.",
+        parse: parse::select,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "dbsize",
+        arity: 1,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Read,
+        group: "server",
+        summary: "Returns the number of keys in the database.",
+        parse: parse::dbsize,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "flushdb",
+        arity: -1,
+        flags: &[Flag::Write],
+        keys: (0, 0, 0),
+        class: CommandClass::Write,
+        group: "server",
+        summary: "Removes all keys from the current database.",
+        parse: parse::flushdb_flushall,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "flushall",
+        arity: -1,
+        flags: &[Flag::Write],
+        keys: (0, 0, 0),
+        class: CommandClass::Write,
+        group: "server",
+        summary: "Removes all keys from all databases.",
+        parse: parse::flushdb_flushall,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "swapdb",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Write,
+        group: "server",
+        summary: "Swaps two databases.",
+        parse: parse::swapdb,
+        handler: Some(&Keyspace),
+    },
+    CommandSpec {
+        name: "info",
+        arity: -1,
+        flags: &[Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Returns information and statistics about the server.",
+        parse: parse::info,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: &[Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "server",
+        summary: "Returns details about the commands the server knows.",
+        parse: parse::command,
+        handler: None,
+    },
+    CommandSpec {
+        name: "memory",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Reports on the memory used by keys and the server.",
+        parse: parse::memory,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "expires",
+        arity: -3,
+        flags: &[Flag::Admin, Flag::ReadOnly, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Lists the keys expiring soonest, with their TTLs in milliseconds.",
+        parse: parse::expires,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "features",
+        arity: 1,
+        flags: &[Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Returns the version and optional features of the build.",
+        parse: parse::features,
+        handler: None,
+    },
+    CommandSpec {
+        name: "debug",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Debugging and introspection commands.",
+        parse: parse::debug,
+        handler: None,
+    },
+    CommandSpec {
+        name: "save",
+        arity: 1,
+        flags: &[Flag::Admin, Flag::NoScript],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Saves the dataset to disk in the foreground.",
+        parse: parse::save,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "bgsave",
+        arity: -1,
+        flags: &[Flag::Admin, Flag::NoScript],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Saves the dataset to disk in the background.",
+        parse: parse::bgsave,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "bgrewriteaof",
+        arity: 1,
+        flags: &[Flag::Admin, Flag::NoScript],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Rewrites the append-only file in the background.",
+        parse: parse::bgrewriteaof,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "config",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Reads, changes and saves the configuration.",
+        parse: parse::config,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "slowlog",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Reads and resets the slow log.",
+        parse: parse::slowlog,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "latency",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Reads and resets the latency monitor.",
+        parse: parse::latency,
+        handler: Some(&Admin),
+    },
+    CommandSpec {
+        name: "auth",
+        arity: -2,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Authenticates the connection.",
+        parse: parse::auth,
+        handler: None,
+    },
+    CommandSpec {
+        name: "client",
+        arity: -2,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Inspects and manages client connections.",
+        parse: parse::client,
+        handler: Some(&Clients),
+    },
+    CommandSpec {
+        name: "acl",
+        arity: -2,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "server",
+        summary: "Inspects access control users.",
+        parse: parse::acl,
+        handler: None,
+    },
+    CommandSpec {
+        name: "sadd",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "set",
+        summary: "Adds members to a set.",
+        parse: parse::sadd_srem,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "srem",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "set",
+        summary: "Removes members from a set.",
+        parse: parse::sadd_srem,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "smembers",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "set",
+        summary: "Returns all members of a set.",
+        parse: parse::smembers,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "sismember",
+        arity: 3,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "set",
+        summary: "Tells whether a member belongs to a set.",
+        parse: parse::sismember,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "spop",
+        arity: -2,
+        flags: &[Flag::Write, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "set",
+        summary: "Removes and returns random members of a set.",
+        parse: parse::spop,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "srandmember",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "set",
+        summary: "Returns random members of a set.",
+        parse: parse::srandmember,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "sinter",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        keys: (1, -1, 1),
+        class: CommandClass::Read,
+        group: "set",
+        summary: "Returns the intersection of sets.",
+        parse: parse::sinter_sunion,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "sunion",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        keys: (1, -1, 1),
+        class: CommandClass::Read,
+        group: "set",
+        summary: "Returns the union of sets.",
+        parse: parse::sinter_sunion,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "sinterstore",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, -1, 1),
+        class: CommandClass::Write,
+        group: "set",
+        summary: "Stores the intersection of sets in a key.",
+        parse: parse::sinterstore_sunionstore,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "sunionstore",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom],
+        keys: (1, -1, 1),
+        class: CommandClass::Write,
+        group: "set",
+        summary: "Stores the union of sets in a key.",
+        parse: parse::sinterstore_sunionstore,
+        handler: Some(&Sets),
+    },
+    CommandSpec {
+        name: "zadd",
+        arity: -4,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "sorted-set",
+        summary: "Adds members to a sorted set, or updates their scores.",
+        parse: parse::zadd,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "zscore",
+        arity: 3,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "sorted-set",
+        summary: "Returns the score of a member of a sorted set.",
+        parse: parse::zscore,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "zrange",
+        arity: -4,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "sorted-set",
+        summary: "Returns members of a sorted set by rank.",
+        parse: parse::zrange,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "zrangebyscore",
+        arity: -4,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "sorted-set",
+        summary: "Returns members of a sorted set by score.",
+        parse: parse::zrangebyscore,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "zrem",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "sorted-set",
+        summary: "Removes members from a sorted set.",
+        parse: parse::zrem,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "zunionstore",
+        arity: -4,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::MovableKeys],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "sorted-set",
+        summary: "Stores the union of sorted sets in a key.",
+        parse: parse::zunionstore,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "zinterstore",
+        arity: -4,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::MovableKeys],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "sorted-set",
+        summary: "Stores the intersection of sorted sets in a key.",
+        parse: parse::zinterstore,
+        handler: Some(&SortedSets),
+    },
+    CommandSpec {
+        name: "lpush",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "list",
+        summary: "Prepends elements to a list.",
+        parse: parse::lpush_rpush,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "rpush",
+        arity: -3,
+        flags: &[Flag::Write, Flag::DenyOom, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "list",
+        summary: "Appends elements to a list.",
+        parse: parse::lpush_rpush,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "lpop",
+        arity: -2,
+        flags: &[Flag::Write, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "list",
+        summary: "Removes and returns the first elements of a list.",
+        parse: parse::lpop_rpop,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "rpop",
+        arity: -2,
+        flags: &[Flag::Write, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Write,
+        group: "list",
+        summary: "Removes and returns the last elements of a list.",
+        parse: parse::lpop_rpop,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "llen",
+        arity: 2,
+        flags: &[Flag::ReadOnly, Flag::Fast],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "list",
+        summary: "Returns the length of a list.",
+        parse: parse::llen,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "lrange",
+        arity: 4,
+        flags: &[Flag::ReadOnly],
+        keys: (1, 1, 1),
+        class: CommandClass::Read,
+        group: "list",
+        summary: "Returns a range of elements of a list.",
+        parse: parse::lrange,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "blpop",
+        arity: -3,
+        flags: &[Flag::Write, Flag::NoScript],
+        keys: (1, -2, 1),
+        class: CommandClass::Write,
+        group: "list",
+        summary: "Pops the first element of the first non-empty list, waiting for one.",
+        parse: parse::blpop_brpop,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "brpop",
+        arity: -3,
+        flags: &[Flag::Write, Flag::NoScript],
+        keys: (1, -2, 1),
+        class: CommandClass::Write,
+        group: "list",
+        summary: "Pops the last element of the first non-empty list, waiting for one.",
+        parse: parse::blpop_brpop,
+        handler: Some(&Lists),
+    },
+    CommandSpec {
+        name: "subscribe",
+        arity: -2,
+        flags: &[Flag::PubSub, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::PubSub,
+        group: "pubsub",
+        summary: "Listens for messages published to channels.",
+        parse: parse::subscribe_psubscribe,
+        handler: None,
+    },
+    CommandSpec {
+        name: "psubscribe",
+        arity: -2,
+        flags: &[Flag::PubSub, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::PubSub,
+        group: "pubsub",
+        summary: "Listens for messages published to channels matching patterns.",
+        parse: parse::subscribe_psubscribe,
+        handler: None,
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        arity: -1,
+        flags: &[Flag::PubSub, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::PubSub,
+        group: "pubsub",
+        summary: "Stops listening to channels.",
+        parse: parse::unsubscribe,
+        handler: None,
+    },
+    CommandSpec {
+        name: "punsubscribe",
+        arity: -1,
+        flags: &[Flag::PubSub, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::PubSub,
+        group: "pubsub",
+        summary: "Stops listening to channel patterns.",
+        parse: parse::punsubscribe,
+        handler: None,
+    },
+    CommandSpec {
+        name: "publish",
+        arity: 3,
+        flags: &[Flag::PubSub, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::PubSub,
+        group: "pubsub",
+        summary: "Posts a message to a channel.",
+        parse: parse::publish,
+        handler: None,
+    },
+    CommandSpec {
+        name: "pubsub",
+        arity: -3,
+        flags: &[Flag::PubSub, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::PubSub,
+        group: "pubsub",
+        summary: "Replays the last messages kept for a channel.",
+        parse: parse::pubsub,
+        handler: None,
+    },
+    CommandSpec {
+        name: "multi",
+        arity: 1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "transactions",
+        summary: "Starts a transaction.",
+        parse: parse::multi,
+        handler: None,
+    },
+    CommandSpec {
+        name: "exec",
+        arity: 1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "transactions",
+        summary: "Runs the commands queued in a transaction.",
+        parse: parse::exec,
+        handler: None,
+    },
+    CommandSpec {
+        name: "discard",
+        arity: 1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "transactions",
+        summary: "Discards a transaction.",
+        parse: parse::discard,
+        handler: None,
+    },
+    CommandSpec {
+        name: "watch",
+        arity: -2,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (1, -1, 1),
+        class: CommandClass::Connection,
+        group: "transactions",
+        summary: "Aborts the next transaction if any of the keys changes.",
+        parse: parse::watch,
+        handler: None,
+    },
+    CommandSpec {
+        name: "unwatch",
+        arity: 1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "transactions",
+        summary: "Forgets the watched keys.",
+        parse: parse::unwatch,
+        handler: None,
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: &[Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Returns the server's liveness response.",
+        parse: parse::ping,
+        handler: None,
+    },
+    CommandSpec {
+        name: "quit",
+        arity: -1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Closes the connection.",
+        parse: parse::quit,
+        handler: None,
+    },
+    CommandSpec {
+        name: "reset",
+        arity: 1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Resets the connection.",
+        parse: parse::reset,
+        handler: None,
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: &[Flag::NoScript, Flag::Loading, Flag::Stale, Flag::Fast],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "connection",
+        summary: "Handshakes with the server, choosing the protocol.",
+        parse: parse::hello,
+        handler: None,
+    },
+    CommandSpec {
+        name: "shutdown",
+        arity: -1,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Drains the clients, saves the dataset and stops the server.",
+        parse: parse::shutdown,
+        handler: None,
+    },
+    CommandSpec {
+        name: "replicaof",
+        arity: 3,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Makes the server a replica of another, or a primary.",
+        parse: parse::replicaof,
+        handler: None,
+    },
+    CommandSpec {
+        name: "slaveof",
+        arity: 3,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Makes the server a replica of another, or a primary.",
+        parse: parse::replicaof,
+        handler: None,
+    },
+    CommandSpec {
+        name: "replconf",
+        arity: -1,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Configures replication; used by replicas.",
+        parse: parse::replconf,
+        handler: None,
+    },
+    CommandSpec {
+        name: "psync",
+        arity: 3,
+        flags: &[Flag::Admin, Flag::NoScript],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Starts replicating; used by replicas.",
+        parse: parse::psync,
+        handler: None,
+    },
+    CommandSpec {
+        name: "monitor",
+        arity: 1,
+        flags: &[Flag::Admin, Flag::NoScript, Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Streams every command the server runs.",
+        parse: parse::monitor,
+        handler: None,
+    },
+    CommandSpec {
+        name: "cluster",
+        arity: -2,
+        flags: &[Flag::Loading, Flag::Stale],
+        keys: (0, 0, 0),
+        class: CommandClass::Connection,
+        group: "cluster",
+        summary: "Reports on the cluster.",
+        parse: parse::cluster,
+        handler: None,
+    },
+    CommandSpec {
+        name: "cdc",
+        arity: -2,
+        flags: &[Flag::Admin, Flag::NoScript],
+        keys: (0, 0, 0),
+        class: CommandClass::Admin,
+        group: "server",
+        summary: "Streams changes to the keyspace.",
+        parse: parse::cdc,
+        handler: None,
+    },
+    CommandSpec {
+        name: "eval",
+        arity: -3,
+        flags: &[Flag::Write, Flag::NoScript, Flag::Stale, Flag::MovableKeys],
+        keys: (0, 0, 0),
+        class: CommandClass::Write,
+        group: "scripting",
+        summary: "Runs a Lua script.",
+        parse: parse::eval_evalsha,
+        handler: Some(&Scripting),
+    },
+    CommandSpec {
+        name: "evalsha",
+        arity: -3,
+        flags: &[Flag::Write, Flag::NoScript, Flag::Stale, Flag::MovableKeys],
+        keys: (0, 0, 0),
+        class: CommandClass::Write,
+        group: "scripting",
+        summary: "Runs a Lua script cached by SCRIPT LOAD.",
+        parse: parse::eval_evalsha,
+        handler: Some(&Scripting),
+    },
+    CommandSpec {
+        name: "script",
+        arity: -2,
+        flags: &[Flag::Write, Flag::NoScript],
+        keys: (0, 0, 0),
+        class: CommandClass::Write,
+        group: "scripting",
+        summary: "Manages the script cache.",
+        parse: parse::script,
+        handler: Some(&Scripting),
+    },
+];
+
+fn by_name() -> &'static HashMap<&'static str, &'static CommandSpec> {
+    static BY_NAME: OnceLock<HashMap<&'static str, &'static CommandSpec>> = OnceLock::new();
+    BY_NAME.get_or_init(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect())
+}
+
+/// The command named `name`, in any case.
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    by_name()
+        .get(String::from_utf8_lossy(name).to_lowercase().as_str())
+        .copied()
+}
+
+impl Command {
+    /// The command's entry in the table.
+    pub fn spec(&self) -> &'static CommandSpec {
+        by_name()[self.name()]
+    }
+}
+
+/// The reply to `COMMAND INFO`: every command if `names` is empty, or each
+/// named one in turn, null for those that don't exist.
+pub fn info_reply(names: &[String]) -> RespValue {
+    if names.is_empty() {
+        return RespValue::Array(COMMANDS.iter().map(CommandSpec::info).collect());
+    }
+    RespValue::Array(
+        names
+            .iter()
+            .map(|name| match lookup(name.as_bytes()) {
+                Some(spec) => spec.info(),
+                None => RespValue::NullArray,
+            })
+            .collect(),
+    )
+}
+
+/// The reply to `COMMAND DOCS`: every command if `names` is empty, or the
+/// named ones that exist.
+pub fn docs_reply(names: &[String]) -> RespValue {
+    let specs: Vec<_> = if names.is_empty() {
+        COMMANDS.iter().collect()
+    } else {
+        names
+            .iter()
+            .filter_map(|name| lookup(name.as_bytes()))
+            .collect()
+    };
+    RespValue::Map(specs.into_iter().map(CommandSpec::docs).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_table() {
+        let mut names = HashSet::new();
+        for spec in COMMANDS {
+            assert!(names.insert(spec.name), "{} is listed twice", spec.name);
+            assert_eq!(spec.name, spec.name.to_lowercase());
+            assert_ne!(spec.arity, 0, "{}", spec.name);
+            assert!(
+                !(spec.has_flag(Flag::Write) && spec.has_flag(Flag::ReadOnly)),
+                "{}",
+                spec.name
+            );
+            // `@write` in a blocklist refuses what ACLs class as writes.
+            assert_eq!(
+                spec.has_flag(Flag::Write),
+                spec.class == CommandClass::Write,
+                "{}",
+                spec.name
+            );
+            assert!(
+                spec.class != CommandClass::Read || spec.has_flag(Flag::ReadOnly),
+                "{}",
+                spec.name
+            );
+            // Commands taking keys list the first one where it can be.
+            let (first, last, step) = spec.keys;
+            if first > 0 {
+                assert!(step > 0 && (last >= first || last < 0), "{}", spec.name);
+                assert!(
+                    spec.arity < 0 || first < spec.arity,
+                    "{} has fewer arguments than keys",
+                    spec.name
+                );
+            }
+        }
+
+        assert_eq!(lookup(b"GeT").map(|spec| spec.name), Some("get"));
+        assert_eq!(lookup(b"slaveof").map(|spec| spec.arity), Some(3));
+        assert!(lookup(b"nosuchcommand").is_none());
+
+        let set = lookup(b"set").unwrap();
+        assert!(!set.accepts(2));
+        assert!(set.accepts(3));
+        assert!(set.accepts(5));
+        let get = lookup(b"get").unwrap();
+        assert!(get.accepts(2));
+        assert!(!get.accepts(3));
+    }
+
+    /// A call of each command, in its main form.
+    const SAMPLES: &[&str] = &[
+        "set k v",
+        "get k",
+        "getrange k 0 1",
+        "del k1 k2",
+        "exists k1 k2",
+        "incr k",
+        "decr k",
+        "incrby k 2",
+        "strlen k",
+        "append k v",
+        "setnx k v",
+        "getset k v",
+        "mset k1 v1 k2 v2",
+        "mget k1 k2",
+        "keys *",
+        "scan 0",
+        "type k",
+        "ttl k",
+        "pttl k",
+        "dump k",
+        "restore k 0 payload",
+        "randomkey",
+        "select 1",
+        "dbsize",
+        "flushdb",
+        "flushall",
+        "swapdb 0 1",
+        "info",
+        "command",
+        "memory stats",
+        "expires next 1",
+        "features",
+        "debug bigkeys",
+        "save",
+        "bgsave",
+        "bgrewriteaof",
+        "config get maxmemory",
+        "slowlog get",
+        "latency latest",
+        "auth password",
+        "client id",
+        "acl whoami",
+        "sadd s m",
+        "srem s m",
+        "smembers s",
+        "sismember s m",
+        "spop s",
+        "srandmember s",
+        "sinter s1 s2",
+        "sunion s1 s2",
+        "sinterstore d s1 s2",
+        "sunionstore d s1 s2",
+        "zadd z 1 m",
+        "zscore z m",
+        "zrange z 0 1",
+        "zrangebyscore z 0 1",
+        "zrem z m",
+        "zunionstore d 2 z1 z2",
+        "zinterstore d 2 z1 z2",
+        "lpush l v",
+        "rpush l v",
+        "lpop l",
+        "rpop l",
+        "llen l",
+        "lrange l 0 1",
+        "blpop l1 l2 0",
+        "brpop l1 l2 0",
+        "subscribe c",
+        "psubscribe c*",
+        "unsubscribe",
+        "punsubscribe",
+        "publish c m",
+        "pubsub history c",
+        "multi",
+        "exec",
+        "discard",
+        "watch k1 k2",
+        "unwatch",
+        "ping",
+        "quit",
+        "reset",
+        "hello",
+        "shutdown",
+        "replicaof no one",
+        "slaveof no one",
+        "replconf listening-port 6380",
+        "psync ? -1",
+        "monitor",
+        "cluster slots",
+        "cdc tail",
+        "eval script 1 k arg",
+        "evalsha sha 1 k arg",
+        "script load script",
+    ];
+
+    /// Commands parsed as another command of the table.
+    const ALIASES: [(&str, &str); 3] = [
+        ("incr", "incrby"),
+        ("decr", "incrby"),
+        ("slaveof", "replicaof"),
+    ];
+
+    /// The arguments at the key positions `spec` gives.
+    fn keys_at(spec: &CommandSpec, args: &[Bytes]) -> Vec<Bytes> {
+        let (first, last, step) = spec.keys;
+        if first == 0 {
+            return vec![];
+        }
+        let last = if last < 0 {
+            args.len() as i32 + last
+        } else {
+            last
+        };
+        (first..=last)
+            .step_by(step as usize)
+            .map(|i| args[i as usize].clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_specs_match_commands() {
+        for spec in COMMANDS {
+            let sample = SAMPLES
+                .iter()
+                .find(|sample| sample.split(' ').next() == Some(spec.name))
+                .unwrap_or_else(|| panic!("no sample of {}", spec.name));
+            let args: Vec<Bytes> = sample.split(' ').map(Bytes::from).collect();
+            let frame = RespValue::Array(args.iter().cloned().map(RespValue::bulk).collect());
+            let command = Command::from_frame(frame)
+                .unwrap_or_else(|e| panic!("{} doesn't parse: {}", sample, e));
+
+            let name = ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == spec.name)
+                .map_or(spec.name, |(_, name)| name);
+            assert_eq!(command.name(), name);
+            // Aliases run as the command they're parsed as.
+            assert_eq!(command.class(), spec.class, "{}", sample);
+            assert_eq!(
+                command.handler().is_some(),
+                spec.handler.is_some(),
+                "{}",
+                sample
+            );
+            if !spec.has_flag(Flag::MovableKeys) {
+                assert_eq!(command.keys(), keys_at(spec, &args), "{}", sample);
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+//! Script commands: `EVAL`, `EVALSHA` and `SCRIPT`
+//!
+//! Commands a script makes run through [`execute_locked`] like any other,
+//! on the shards the script holds, so each is logged, replicated and
+//! published as it runs rather than the script as a whole. Scripts new to
+//! the cache are logged and replicated as a `SCRIPT LOAD`, and flushes as a
+//! `SCRIPT FLUSH`, so `EVALSHA` finds the same scripts after a restart and
+//! on replicas.
+use super::handler::{CommandHandler, Context};
+use super::Command;
+use crate::protocol::RespValue;
+use crate::storage::{ShardLocks, StorageError};
+
+pub struct Scripting;
+
+#[cfg(not(feature = "scripting"))]
+impl CommandHandler for Scripting {
+    fn run(
+        &self,
+        _command: Command,
+        _store: &mut ShardLocks,
+        _ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        Ok(RespValue::Error(
+            "ERR scripting is unavailable: this build lacks the scripting feature".to_string(),
+        ))
+    }
+}
+
+#[cfg(feature = "scripting")]
+impl CommandHandler for Scripting {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        use crate::scripting;
+
+        let resp = match command {
+            Command::Eval { script, keys, args } => {
+                if scripting::compile(&script).is_ok() {
+                    cache(store, script.clone());
+                }
+                run_script(&script, keys, args, store, ctx)?
+            }
+            Command::EvalSha { sha, keys, args } => match store.scripts().get(&sha) {
+                Some(script) => run_script(&script, keys, args, store, ctx)?,
+                None => {
+                    RespValue::Error("NOSCRIPT No matching script. Please use EVAL.".to_string())
+                }
+            },
+            Command::ScriptLoad(script) => match scripting::compile(&script) {
+                Ok(()) => RespValue::bulk(cache(store, script)),
+                Err(e) => RespValue::Error(e),
+            },
+            Command::ScriptExists(shas) => RespValue::Array(
+                shas.iter()
+                    .map(|sha| RespValue::Integer(store.scripts().get(sha).is_some() as i64))
+                    .collect(),
+            ),
+            Command::ScriptFlush => {
+                store.scripts().flush();
+                if store.is_propagating() {
+                    store.propagate(&script_command(&[b"SCRIPT", b"FLUSH"]));
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => unreachable!("not a scripting command"),
+        };
+        Ok(resp)
+    }
+}
+
+/// Adds `script` to the cache, returning its SHA-1, and logs and replicates
+/// it if it's new.
+#[cfg(feature = "scripting")]
+fn cache(store: &ShardLocks, script: bytes::Bytes) -> String {
+    let (sha, new) = store.scripts().load(script.clone());
+    if new && store.is_propagating() {
+        store.propagate(&script_command(&[b"SCRIPT", b"LOAD", &script]));
+    }
+    sha
+}
+
+#[cfg(feature = "scripting")]
+fn script_command(args: &[&[u8]]) -> RespValue {
+    RespValue::Array(
+        args.iter()
+            .map(|arg| RespValue::bulk(bytes::Bytes::copy_from_slice(arg)))
+            .collect(),
+    )
+}
+
+/// Runs `script`, handing the commands it makes to their handlers with the
+/// permissions of the client that sent it.
+#[cfg(feature = "scripting")]
+fn run_script(
+    script: &[u8],
+    keys: Vec<bytes::Bytes>,
+    args: Vec<bytes::Bytes>,
+    store: &mut ShardLocks,
+    ctx: Context,
+) -> Result<RespValue, StorageError> {
+    let mut call = |args: Vec<bytes::Bytes>| {
+        let frame = RespValue::Array(args.into_iter().map(RespValue::bulk).collect());
+        let command = match Command::from_frame(frame) {
+            Ok(command) => command,
+            Err(e) => return RespValue::Error(e.to_string()),
+        };
+        let scripted = matches!(
+            command,
+            Command::Eval { .. }
+                | Command::EvalSha { .. }
+                | Command::ScriptLoad(_)
+                | Command::ScriptExists(_)
+                | Command::ScriptFlush
+        );
+        // Commands without a handler need the connection.
+        if scripted || command.handler().is_none() {
+            return RespValue::Error(
+                "ERR This Redis command is not allowed from script".to_string(),
+            );
+        }
+        if ctx.conn.blocklist.blocks(&command) {
+            return super::Blocklist::refusal(&command);
+        }
+        if let Some(refusal) =
+            super::disabled_refusal(&store.config().disabled_command_groups, &command)
+        {
+            return refusal;
+        }
+        if let Some(user) = &ctx.conn.user {
+            if !user.can_run(command.class()) {
+                return RespValue::Error(format!(
+                    "NOPERM User {} has no permissions to run the '{}' command",
+                    user.name,
+                    command.name()
+                ));
+            }
+            if !command.keys().iter().all(|key| user.can_access(key)) {
+                return RespValue::Error("NOPERM No permissions to access a key".to_string());
+            }
+        }
+        let namespace = store.namespace(ctx.conn.db);
+        super::execute_locked_in(
+            namespace,
+            command,
+            store,
+            ctx.conn,
+            ctx.clients,
+            *ctx.deadline,
+        )
+    };
+    crate::scripting::eval(script, keys, args, *ctx.deadline, &mut call)
+}
@@ -0,0 +1,95 @@
+//! Set commands
+use super::handler::{CommandHandler, Context};
+use super::Command;
+use crate::protocol::RespValue;
+use crate::storage::{ShardLocks, StorageError};
+use bytes::Bytes;
+
+pub struct Sets;
+
+impl CommandHandler for Sets {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let Context { deadline, .. } = ctx;
+        let resp = match command {
+            Command::SAdd(key, members) => {
+                RespValue::Integer(store.shard_mut(&key).sadd(&key, members)? as i64)
+            }
+            Command::SRem(key, members) => {
+                RespValue::Integer(store.shard_mut(&key).srem(&key, &members)? as i64)
+            }
+            Command::SMembers(key) => members_reply(store.shard(&key).smembers(&key, deadline)?),
+            Command::SIsMember(key, member) => {
+                RespValue::Integer(store.shard(&key).sismember(&key, &member)? as i64)
+            }
+            Command::SPop(key, count) => {
+                let popped = store
+                    .shard_mut(&key)
+                    .spop(&key, count.unwrap_or(1), deadline)?;
+                random_reply(popped, count.is_some())
+            }
+            Command::SRandMember(key, count) => {
+                let picked = store
+                    .shard(&key)
+                    .srandmember(&key, count.unwrap_or(1), deadline)?;
+                random_reply(picked, count.is_some())
+            }
+            Command::SInter(keys) => members_reply(store.sinter(&keys, deadline)?),
+            Command::SUnion(keys) => members_reply(store.sunion(&keys, deadline)?),
+            Command::SInterStore(dest, keys) => {
+                RespValue::Integer(store.sinterstore(&dest, &keys, deadline)? as i64)
+            }
+            Command::SUnionStore(dest, keys) => {
+                RespValue::Integer(store.sunionstore(&dest, &keys, deadline)? as i64)
+            }
+            _ => unreachable!("not a set command"),
+        };
+        Ok(resp)
+    }
+}
+
+/// `SPOP`/`SRANDMEMBER`: one member, or nil, without a count; an array of
+/// them with one.
+fn random_reply(members: Vec<Bytes>, counted: bool) -> RespValue {
+    if counted {
+        RespValue::Array(members.into_iter().map(RespValue::bulk).collect())
+    } else {
+        RespValue::BulkString(members.into_iter().next())
+    }
+}
+
+/// What's propagated for an `SPOP` of `key` that replied `reply`: an
+/// `SREM` of the members it picked, so replicas and the AOF remove the
+/// same ones rather than drawing their own. `None` if it popped nothing.
+pub(super) fn spop_propagation(key: &Bytes, reply: &RespValue) -> Option<RespValue> {
+    let popped: Vec<Bytes> = match reply {
+        RespValue::BulkString(Some(member)) => vec![member.clone()],
+        RespValue::Array(members) => members
+            .iter()
+            .filter_map(|member| match member {
+                RespValue::BulkString(Some(member)) => Some(member.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    if popped.is_empty() {
+        return None;
+    }
+    let mut args = vec![RespValue::bulk("SREM"), RespValue::bulk(key.clone())];
+    args.extend(popped.into_iter().map(RespValue::bulk));
+    Some(RespValue::Array(args))
+}
+
+fn members_reply(members: Vec<&Bytes>) -> RespValue {
+    RespValue::Set(
+        members
+            .into_iter()
+            .map(|m| RespValue::bulk(m.clone()))
+            .collect(),
+    )
+}
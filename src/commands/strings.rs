@@ -0,0 +1,73 @@
+//! String commands: `SET`, `GET`, `INCRBY`, `MSET` and the rest
+use super::handler::{CommandHandler, Context};
+use super::Command;
+use crate::protocol::RespValue;
+use crate::storage::{SetCondition, SetOptions, ShardLocks, StorageError, StringOps};
+
+pub struct Strings;
+
+impl CommandHandler for Strings {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        _ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let resp = match command {
+            Command::Set(key, value, options) => {
+                let outcome = store
+                    .shard_mut(&key)
+                    .set_with_options(key, value, options)?;
+                match (options.get, outcome.stored) {
+                    (true, _) => RespValue::BulkString(outcome.old),
+                    (false, true) => RespValue::SimpleString("OK".to_string()),
+                    (false, false) => RespValue::BulkString(None),
+                }
+            }
+            Command::Get(key) => RespValue::BulkString(store.shard(&key).get(&key)?.cloned()),
+            Command::GetRange(key, start, stop) => {
+                RespValue::bulk(store.shard(&key).getrange(&key, start, stop)?)
+            }
+            Command::IncrBy(key, delta) => {
+                RespValue::Integer(store.shard_mut(&key).incr_by(key, delta)?)
+            }
+            Command::Append(key, value) => {
+                RespValue::Integer(store.shard_mut(&key).append(key, &value)? as i64)
+            }
+            Command::StrLen(key) => RespValue::Integer(store.shard(&key).strlen(&key)? as i64),
+            Command::SetNx(key, value) => {
+                let options = SetOptions {
+                    condition: SetCondition::IfMissing,
+                    ..SetOptions::default()
+                };
+                let outcome = store
+                    .shard_mut(&key)
+                    .set_with_options(key, value, options)?;
+                RespValue::Integer(outcome.stored as i64)
+            }
+            Command::GetSet(key, value) => {
+                let options = SetOptions {
+                    get: true,
+                    ..SetOptions::default()
+                };
+                let outcome = store
+                    .shard_mut(&key)
+                    .set_with_options(key, value, options)?;
+                RespValue::BulkString(outcome.old)
+            }
+            Command::MSet(pairs) => {
+                store.mset(pairs)?;
+                RespValue::SimpleString("OK".to_string())
+            }
+            Command::MGet(keys) => RespValue::Array(
+                store
+                    .mget(&keys)
+                    .into_iter()
+                    .map(RespValue::BulkString)
+                    .collect(),
+            ),
+            _ => unreachable!("not a string command"),
+        };
+        Ok(resp)
+    }
+}
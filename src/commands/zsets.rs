@@ -0,0 +1,90 @@
+//! Sorted set commands
+use super::handler::{CommandHandler, Context};
+use super::{Command, ZStore};
+use crate::protocol::RespValue;
+use crate::storage::{ShardLocks, StorageError};
+use bytes::Bytes;
+
+pub struct SortedSets;
+
+impl CommandHandler for SortedSets {
+    fn run(
+        &self,
+        command: Command,
+        store: &mut ShardLocks,
+        ctx: Context,
+    ) -> Result<RespValue, StorageError> {
+        let Context { deadline, .. } = ctx;
+        let resp = match command {
+            Command::ZAdd(key, members) => {
+                RespValue::Integer(store.shard_mut(&key).zadd(&key, members)? as i64)
+            }
+            Command::ZScore(key, member) => match store.shard(&key).zscore(&key, &member)? {
+                Some(score) => RespValue::Double(score),
+                None => RespValue::BulkString(None),
+            },
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                withscores,
+            } => scored_members_reply(
+                store.shard(&key).zrange(&key, start, stop, deadline)?,
+                withscores,
+            ),
+            Command::ZRangeByScore {
+                key,
+                min,
+                max,
+                withscores,
+                limit,
+            } => {
+                let limit = limit.map(|(offset, count)| {
+                    // A negative offset selects nothing and a negative count
+                    // means "all remaining", as in Redis.
+                    let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+                    let count = usize::try_from(count).unwrap_or(usize::MAX);
+                    (offset, count)
+                });
+                let range = store
+                    .shard(&key)
+                    .zrangebyscore(&key, min, max, limit, deadline)?;
+                scored_members_reply(range, withscores)
+            }
+            Command::ZRem(key, members) => {
+                RespValue::Integer(store.shard_mut(&key).zrem(&key, &members)? as i64)
+            }
+            Command::ZUnionStore(ZStore {
+                dest,
+                keys,
+                weights,
+                aggregate,
+            }) => {
+                let len = store.zunionstore(&dest, &keys, &weights, aggregate, deadline)?;
+                RespValue::Integer(len as i64)
+            }
+            Command::ZInterStore(ZStore {
+                dest,
+                keys,
+                weights,
+                aggregate,
+            }) => {
+                let len = store.zinterstore(&dest, &keys, &weights, aggregate, deadline)?;
+                RespValue::Integer(len as i64)
+            }
+            _ => unreachable!("not a sorted set command"),
+        };
+        Ok(resp)
+    }
+}
+
+fn scored_members_reply(members: Vec<(&Bytes, f64)>, withscores: bool) -> RespValue {
+    let mut items = Vec::with_capacity(members.len() * if withscores { 2 } else { 1 });
+    for (member, score) in members {
+        items.push(RespValue::bulk(member.clone()));
+        if withscores {
+            items.push(RespValue::Double(score));
+        }
+    }
+    RespValue::Array(items)
+}
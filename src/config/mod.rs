@@ -0,0 +1,840 @@
+mod redis_conf;
+pub mod runtime;
+pub mod units;
+
+use crate::commands::CommandClass;
+use crate::pubsub::KeyspaceEvents;
+use redis_conf::RedisConf;
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub storage: StorageConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub command_timeouts: CommandTimeoutConfig,
+    #[serde(default)]
+    pub replication: ReplicationConfig,
+    #[serde(default)]
+    pub sink: SinkConfig,
+    #[serde(default)]
+    pub memcached: MemcachedConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub latency: LatencyConfig,
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+}
+
+/// Sizes and durations throughout the config take units, as in `"512mb"`
+/// or `"30s"`; see [`units`].
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub listen_addr: SocketAddr,
+    pub max_connections: usize,
+    #[serde(deserialize_with = "units::size")]
+    pub buffer_size: usize,
+    /// Queued reply bytes after which a connection stops reading new
+    /// commands until the client has caught up.
+    #[serde(deserialize_with = "units::size")]
+    pub output_buffer_high_water: usize,
+    /// Disconnect clients idle this long between commands; 0 never does.
+    #[serde(deserialize_with = "units::secs")]
+    pub idle_timeout_secs: u64,
+    /// Disconnect clients taking this long to send the rest of a command
+    /// they started; 0 leaves it to `idle_timeout_secs`.
+    #[serde(deserialize_with = "units::millis")]
+    pub read_timeout_ms: u64,
+    /// Disconnect clients that haven't finished sending a command this long
+    /// after starting it, however steadily they trickle it in; 0 for no
+    /// limit.
+    #[serde(deserialize_with = "units::millis")]
+    pub partial_command_timeout_ms: u64,
+    /// The most input a client may have buffered short of a complete
+    /// command before it's disconnected.
+    #[serde(deserialize_with = "units::size")]
+    pub max_query_buffer: usize,
+    /// The most all client input and output buffers together may hold
+    /// before the largest are disconnected; 0 for no limit.
+    #[serde(deserialize_with = "units::size")]
+    pub max_memory_clients: usize,
+    /// Longest bulk string a request may carry.
+    #[serde(deserialize_with = "units::size")]
+    pub proto_max_bulk_len: usize,
+    /// Pipelined commands a connection runs before letting the others
+    /// ready to run go first; 0 for no limit.
+    pub commands_per_turn: usize,
+    /// How long a connection may run pipelined commands before letting the
+    /// others go first; 0 for no limit.
+    #[serde(deserialize_with = "units::millis")]
+    pub turn_ms: u64,
+    /// Commands refused on `listen_addr`: names, as `config`, or `@` and a
+    /// flag of the command table, as `@admin`.
+    pub deny_commands: Vec<String>,
+    /// More addresses to serve clients on, each refusing commands of its
+    /// own.
+    pub listeners: Vec<ListenerConfig>,
+    /// On shutdown, how long clients have to finish their transactions and
+    /// blocking commands before they're disconnected; 0 disconnects them
+    /// at once.
+    #[serde(deserialize_with = "units::millis")]
+    pub shutdown_grace_ms: u64,
+}
+
+/// A listener besides `listen_addr`, sharing everything but the commands
+/// it refuses.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ListenerConfig {
+    pub listen_addr: SocketAddr,
+    #[serde(default)]
+    pub deny_commands: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen_addr: "127.0.0.1:6379".parse().unwrap(),
+            max_connections: 1000,
+            buffer_size: 1024,
+            output_buffer_high_water: default_output_buffer_high_water(),
+            idle_timeout_secs: 60,
+            read_timeout_ms: 0,
+            partial_command_timeout_ms: 0,
+            max_query_buffer: 1024 * 1024 * 1024, // 1GB
+            max_memory_clients: 0,
+            proto_max_bulk_len: 512 * 1024 * 1024, // 512MB
+            commands_per_turn: 64,
+            turn_ms: 1,
+            deny_commands: Vec::new(),
+            listeners: Vec::new(),
+            shutdown_grace_ms: 10_000,
+        }
+    }
+}
+
+fn default_output_buffer_high_water() -> usize {
+    1024 * 1024 // 1MB
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    #[serde(deserialize_with = "units::size")]
+    pub max_memory: usize,
+    /// Independently locked partitions of the keyspace, so commands on
+    /// different keys run in parallel. `max_memory` is split evenly between
+    /// them.
+    #[serde(default = "default_shards")]
+    pub shards: usize,
+    /// Let reads lock their shards alongside other reads, so they only
+    /// wait on writes. Off, every command locks exclusively.
+    pub shared_reads: bool,
+    /// Numbered databases clients pick from with `SELECT`.
+    #[serde(default = "default_databases")]
+    pub databases: usize,
+    /// Keep every database in database 0, each other one's keys behind a
+    /// prefix byte of its index, so clients can `SELECT` in cluster mode.
+    /// At most 256 databases are told apart.
+    pub prefixed_databases: bool,
+    pub persistence_enabled: bool,
+    /// Dump file written by `SAVE`/`BGSAVE` and loaded on startup.
+    #[serde(default = "default_dbfilename")]
+    pub dbfilename: PathBuf,
+    /// Snapshot in the background once any rule matches; empty disables
+    /// automatic saves.
+    #[serde(default = "default_save_rules")]
+    pub save_rules: Vec<SaveRule>,
+    /// Log every write to an append-only file and replay it on startup.
+    #[serde(default)]
+    pub appendonly: bool,
+    /// Names the files of the log, which live in `appenddirname`; a
+    /// single-file log from before at this path is moved in on startup.
+    #[serde(default = "default_appendfilename")]
+    pub appendfilename: PathBuf,
+    #[serde(default = "default_appenddirname")]
+    pub appenddirname: PathBuf,
+    #[serde(default)]
+    pub appendfsync: AppendFsync,
+    /// What to do when a write would go over `max_memory`.
+    #[serde(default)]
+    pub maxmemory_policy: MaxMemoryPolicy,
+    /// Memory limits for parts of the keyspace, each evicting only its own
+    /// keys; a key belongs to the first quota matching it.
+    #[serde(default)]
+    pub quotas: Vec<QuotaConfig>,
+    /// Percentages of `max_memory` over which a warning is logged and
+    /// published; none by default.
+    #[serde(default)]
+    pub memory_alarms: Vec<u8>,
+    /// A Lua script consulted before each eviction, which can keep the key
+    /// (builds with `scripting`).
+    #[serde(default)]
+    pub eviction_hook: Option<PathBuf>,
+    #[serde(default)]
+    pub defrag: DefragConfig,
+    #[serde(default)]
+    pub slab: SlabConfig,
+    #[serde(default)]
+    pub intern: InternConfig,
+    #[serde(default)]
+    pub result_cache: ResultCacheConfig,
+    #[serde(default)]
+    pub hot_keys: HotKeysConfig,
+    /// Keyspace notifications published, as the flags of redis.conf's
+    /// `notify-keyspace-events`; none by default.
+    #[serde(default)]
+    pub notify_keyspace_events: KeyspaceEvents,
+    /// Lengthen the TTLs `SET` gives with `EX` and `PX` by up to this
+    /// percentage, picked per key, so keys cached together expire apart.
+    #[serde(default)]
+    pub ttl_jitter_percent: u8,
+    /// Command groups, as `COMMAND DOCS` names them, refused even though
+    /// the build has them, such as `scripting` or `cluster`.
+    #[serde(default)]
+    pub disabled_command_groups: Vec<String>,
+    #[serde(default)]
+    pub pubsub_history: PubSubHistoryConfig,
+}
+
+fn default_shards() -> usize {
+    16
+}
+
+fn default_databases() -> usize {
+    16
+}
+
+fn default_dbfilename() -> PathBuf {
+    PathBuf::from("dump.rdb")
+}
+
+/// Save once at least `changes` writes happened and `seconds` passed since
+/// the last save.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    #[serde(deserialize_with = "units::secs")]
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+fn default_save_rules() -> Vec<SaveRule> {
+    [(900, 1), (300, 10), (60, 10000)]
+        .into_iter()
+        .map(|(seconds, changes)| SaveRule { seconds, changes })
+        .collect()
+}
+
+fn default_appendfilename() -> PathBuf {
+    PathBuf::from("appendonly.aof")
+}
+
+fn default_appenddirname() -> PathBuf {
+    PathBuf::from("appendonlydir")
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            max_memory: 1024 * 1024 * 1024, // 1GB
+            shards: default_shards(),
+            shared_reads: true,
+            databases: default_databases(),
+            prefixed_databases: false,
+            persistence_enabled: false,
+            dbfilename: default_dbfilename(),
+            save_rules: default_save_rules(),
+            appendonly: false,
+            appendfilename: default_appendfilename(),
+            appenddirname: default_appenddirname(),
+            appendfsync: AppendFsync::default(),
+            maxmemory_policy: MaxMemoryPolicy::default(),
+            quotas: Vec::new(),
+            memory_alarms: Vec::new(),
+            eviction_hook: None,
+            defrag: DefragConfig::default(),
+            slab: SlabConfig::default(),
+            intern: InternConfig::default(),
+            result_cache: ResultCacheConfig::default(),
+            hot_keys: HotKeysConfig::default(),
+            notify_keyspace_events: KeyspaceEvents::default(),
+            ttl_jitter_percent: 0,
+            disabled_command_groups: Vec::new(),
+            pubsub_history: PubSubHistoryConfig::default(),
+        }
+    }
+}
+
+/// When the append-only file is flushed to disk.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AppendFsync {
+    /// After every write command: safest and slowest.
+    Always,
+    /// Once per second from a background task; a crash loses at most about
+    /// a second of writes.
+    #[default]
+    Everysec,
+    /// Never explicitly; the OS decides when data reaches the disk.
+    No,
+}
+
+impl AppendFsync {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::Everysec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// Which keys, if any, are evicted to make room at `max_memory`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MaxMemoryPolicy {
+    /// Reject the write with an out of memory error.
+    #[default]
+    #[serde(rename = "noeviction")]
+    NoEviction,
+    /// Evict the least recently used keys.
+    #[serde(rename = "allkeys-lru")]
+    AllKeysLru,
+    /// Evict random keys.
+    #[serde(rename = "allkeys-random")]
+    AllKeysRandom,
+    /// Evict the keys with a TTL that expire soonest.
+    #[serde(rename = "volatile-ttl")]
+    VolatileTtl,
+}
+
+impl MaxMemoryPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaxMemoryPolicy::NoEviction => "noeviction",
+            MaxMemoryPolicy::AllKeysLru => "allkeys-lru",
+            MaxMemoryPolicy::AllKeysRandom => "allkeys-random",
+            MaxMemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+/// Background defragmentation. A pass starts once at least `ignore_bytes`
+/// have been released since the last one and they amount to
+/// `threshold_percent` of the live data; it then reallocates up to
+/// `keys_per_cycle` keys every `interval_ms`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct DefragConfig {
+    pub enabled: bool,
+    #[serde(deserialize_with = "units::size")]
+    pub ignore_bytes: usize,
+    pub threshold_percent: usize,
+    pub keys_per_cycle: usize,
+    #[serde(deserialize_with = "units::millis")]
+    pub interval_ms: u64,
+}
+
+impl Default for DefragConfig {
+    fn default() -> Self {
+        DefragConfig {
+            enabled: false,
+            ignore_bytes: 100 * 1024 * 1024, // 100MB
+            threshold_percent: 10,
+            keys_per_cycle: 1000,
+            interval_ms: 100,
+        }
+    }
+}
+
+/// Slab allocation: keys and values of at most `max_entry_size` bytes are
+/// packed into shared chunks of `chunk_size` bytes.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SlabConfig {
+    pub enabled: bool,
+    #[serde(deserialize_with = "units::size")]
+    pub max_entry_size: usize,
+    #[serde(deserialize_with = "units::size")]
+    pub chunk_size: usize,
+}
+
+impl Default for SlabConfig {
+    fn default() -> Self {
+        SlabConfig {
+            enabled: false,
+            max_entry_size: 64,
+            chunk_size: 64 * 1024, // 64KB
+        }
+    }
+}
+
+/// Value interning: string values of at most `max_value_size` bytes are
+/// shared between keys, with up to `max_entries` distinct values pooled.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct InternConfig {
+    pub enabled: bool,
+    #[serde(deserialize_with = "units::size")]
+    pub max_value_size: usize,
+    pub max_entries: usize,
+}
+
+impl Default for InternConfig {
+    fn default() -> Self {
+        InternConfig {
+            enabled: false,
+            max_value_size: 16,
+            max_entries: 10000,
+        }
+    }
+}
+
+/// Replies to expensive reads are kept for `ttl_ms`, and reused until a
+/// key they read changes; 0 turns the cache off. At most `max_entries`
+/// replies are kept.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ResultCacheConfig {
+    #[serde(deserialize_with = "units::millis")]
+    pub ttl_ms: u64,
+    pub max_entries: usize,
+}
+
+impl Default for ResultCacheConfig {
+    fn default() -> Self {
+        ResultCacheConfig {
+            ttl_ms: 0,
+            max_entries: 1024,
+        }
+    }
+}
+
+/// A memory limit for the keys of database `db`, those starting with
+/// `prefix`, or those of the database starting with the prefix. Databases
+/// and keys are as clients see them, with `prefixed_databases` too.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub db: Option<usize>,
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(deserialize_with = "units::size")]
+    pub max_memory: usize,
+}
+
+impl QuotaConfig {
+    pub fn matches(&self, db: usize, key: &[u8]) -> bool {
+        self.db.is_none_or(|quota_db| quota_db == db)
+            && self
+                .prefix
+                .as_ref()
+                .is_none_or(|prefix| key.starts_with(prefix.as_bytes()))
+    }
+}
+
+/// String keys read more than `reads_per_sec` times a second get their
+/// value copied out of their shard, and `GET`s of them answered without
+/// its lock, for at most `max_keys` keys at once.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct HotKeysConfig {
+    pub enabled: bool,
+    pub reads_per_sec: u64,
+    pub max_keys: usize,
+}
+
+impl Default for HotKeysConfig {
+    fn default() -> Self {
+        HotKeysConfig {
+            enabled: false,
+            reads_per_sec: 10000,
+            max_keys: 16,
+        }
+    }
+}
+
+/// Channels matching one of `channels`, or every channel if there are
+/// none, keep their last `messages` messages, up to `max_size` bytes of
+/// them, for `PUBSUB HISTORY`; 0 messages keeps none. Keyspace
+/// notifications are never kept.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PubSubHistoryConfig {
+    pub messages: usize,
+    #[serde(deserialize_with = "units::size")]
+    pub max_size: usize,
+    pub channels: Vec<String>,
+}
+
+impl Default for PubSubHistoryConfig {
+    fn default() -> Self {
+        PubSubHistoryConfig {
+            messages: 0,
+            max_size: 64 * 1024,
+            channels: Vec::new(),
+        }
+    }
+}
+
+/// Maximum execution time per command class in milliseconds, 0 meaning no
+/// limit. Commands that run over are aborted with a `-TIMEOUT` error.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct CommandTimeoutConfig {
+    #[serde(deserialize_with = "units::millis")]
+    pub read_ms: u64,
+    #[serde(deserialize_with = "units::millis")]
+    pub write_ms: u64,
+    #[serde(deserialize_with = "units::millis")]
+    pub admin_ms: u64,
+}
+
+impl CommandTimeoutConfig {
+    pub fn limit_for(&self, class: CommandClass) -> Option<Duration> {
+        let ms = match class {
+            CommandClass::Read => self.read_ms,
+            CommandClass::Write => self.write_ms,
+            CommandClass::Admin => self.admin_ms,
+            CommandClass::Connection | CommandClass::PubSub => 0,
+        };
+        (ms > 0).then(|| Duration::from_millis(ms))
+    }
+}
+
+/// `SLOWLOG` keeps the last `slowlog_max_len` commands taking at least
+/// `slowlog_slower_than_us` microseconds; negative disables it and 0 logs
+/// every command. `LATENCY` samples events taking at least
+/// `monitor_threshold_ms`; 0 disables it.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LatencyConfig {
+    pub slowlog_slower_than_us: i64,
+    pub slowlog_max_len: usize,
+    #[serde(deserialize_with = "units::millis")]
+    pub monitor_threshold_ms: u64,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        LatencyConfig {
+            slowlog_slower_than_us: 10_000,
+            slowlog_max_len: 128,
+            monitor_threshold_ms: 0,
+        }
+    }
+}
+
+/// Cluster mode: with `enabled`, keys hash to slots and each node in
+/// `nodes` serves the slots listed for it. This node is the one at `myself`,
+/// or else at `server.listen_addr`.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ClusterConfig {
+    pub enabled: bool,
+    pub myself: Option<SocketAddr>,
+    pub nodes: Vec<ClusterNodeConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClusterNodeConfig {
+    pub addr: SocketAddr,
+    /// Derived from `addr` if not given.
+    #[serde(default)]
+    pub id: Option<String>,
+    /// Slots and inclusive ranges of them, as in `"0-5460"`.
+    pub slots: Vec<String>,
+}
+
+/// Replication settings. With `replicaof` (`host:port`) set, the server
+/// starts as a replica of that primary, authenticating with `masteruser` and
+/// `masterauth` if it requires a password.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    pub replicaof: Option<String>,
+    pub masteruser: Option<String>,
+    pub masterauth: Option<Secret>,
+    pub tls: ReplicationTlsConfig,
+}
+
+/// TLS for the replication link, in builds with the `tls` feature. Both
+/// ends present `cert_file` and verify the other's against `ca_file`. A
+/// primary takes replicas over TLS on `listen_addr`; a replica with
+/// `connect` set reaches its `replicaof` that way, checking the primary's
+/// certificate is for `server_name`, the `replicaof` host by default.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ReplicationTlsConfig {
+    pub listen_addr: Option<SocketAddr>,
+    pub connect: bool,
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub ca_file: Option<PathBuf>,
+    pub server_name: Option<String>,
+}
+
+/// A second listener speaking the memcached text protocol, on
+/// `listen_addr` if set. Items over `max_item_size` are refused.
+/// memcached clients can't authenticate, so they run as `user`, which
+/// servers requiring authentication must name; without it they're the
+/// default user. `deny_commands` refuses commands as on the other
+/// listeners, by the commands requests run: `get` runs `get`, `delete`
+/// runs `del`, and the others `set`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct MemcachedConfig {
+    pub listen_addr: Option<SocketAddr>,
+    #[serde(deserialize_with = "units::size")]
+    pub max_item_size: usize,
+    pub user: Option<String>,
+    pub deny_commands: Vec<String>,
+}
+
+impl Default for MemcachedConfig {
+    fn default() -> Self {
+        MemcachedConfig {
+            listen_addr: None,
+            max_item_size: 1024 * 1024, // 1MB
+            user: None,
+            deny_commands: Vec::new(),
+        }
+    }
+}
+
+/// An HTTP listener serving Prometheus metrics at `/metrics`, on
+/// `listen_addr` if set, in builds with the `metrics` feature.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub listen_addr: Option<SocketAddr>,
+}
+
+/// Write-behind mirroring of the dataset into a SQL table, in builds with
+/// the `sql` feature. `url` is `sqlite:PATH` or a `postgres://` URL; unset
+/// disables the sink. Changed keys are written in batches of up to
+/// `batch_size`, at least every `flush_interval_ms`, and failed batches are
+/// retried with a backoff doubling up to `max_backoff_ms`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct SinkConfig {
+    pub url: Option<String>,
+    pub table: String,
+    pub batch_size: usize,
+    #[serde(deserialize_with = "units::millis")]
+    pub flush_interval_ms: u64,
+    #[serde(deserialize_with = "units::millis")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        SinkConfig {
+            url: None,
+            table: "rdb_keys".to_string(),
+            batch_size: 1000,
+            flush_interval_ms: 1000,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+/// Authentication settings. The default user's password can be given inline
+/// (`requirepass`), read from a file (`requirepass_file`) or taken from an
+/// environment variable (`requirepass_env`); the latter two keep the secret
+/// out of the config file. An inline value starting with `#` is treated as a
+/// hex-encoded SHA-256 hash rather than a plaintext password.
+///
+/// Further users are listed in `users`; one named `default` replaces the
+/// default user, and then no `requirepass` may be set. Passwords matching
+/// none of them may still be accepted by `auth_backend`.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SecurityConfig {
+    pub requirepass: Option<Secret>,
+    pub requirepass_file: Option<PathBuf>,
+    pub requirepass_env: Option<String>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub auth_backend: AuthBackendConfig,
+}
+
+/// Where passwords the config doesn't know are checked, by `type`:
+/// - `file`: users in `ACL LIST` form in the file at `path`, read again
+///   when it changes;
+/// - `http`: an introspection endpoint at `url`, given `timeout_ms` to
+///   answer;
+/// - `hmac`: tokens signed with `secret`.
+///
+/// Answers are cached for `cache_ttl_ms`; 0 asks every time. Users the
+/// backend accepts have the permissions of the configured user of the same
+/// name, or else `categories` and `keys`, unless the backend grants its own.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthBackendConfig {
+    #[serde(rename = "type")]
+    pub kind: AuthBackendKind,
+    pub path: Option<PathBuf>,
+    pub url: Option<String>,
+    pub secret: Option<Secret>,
+    #[serde(deserialize_with = "units::millis")]
+    pub timeout_ms: u64,
+    #[serde(deserialize_with = "units::millis")]
+    pub cache_ttl_ms: u64,
+    pub categories: Vec<String>,
+    pub keys: Vec<String>,
+}
+
+impl Default for AuthBackendConfig {
+    fn default() -> Self {
+        AuthBackendConfig {
+            kind: AuthBackendKind::None,
+            path: None,
+            url: None,
+            secret: None,
+            timeout_ms: 2000,
+            cache_ttl_ms: 60_000,
+            categories: default_categories(),
+            keys: default_key_patterns(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackendKind {
+    /// Only the config's passwords.
+    #[default]
+    None,
+    File,
+    Http,
+    Hmac,
+}
+
+impl AuthBackendKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthBackendKind::None => "none",
+            AuthBackendKind::File => "file",
+            AuthBackendKind::Http => "http",
+            AuthBackendKind::Hmac => "hmac",
+        }
+    }
+}
+
+/// A user defined in the config. Passwords follow `requirepass`: plaintext,
+/// or a SHA-256 hex digest after `#`. Without passwords the user needs none.
+/// `categories` (`@read`, `@write`, `@admin`, `@connection`, `@pubsub` or
+/// `@all`) and glob `keys` patterns limit what it may run and touch.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserConfig {
+    pub name: String,
+    #[serde(default)]
+    pub passwords: Vec<Secret>,
+    #[serde(default = "default_categories")]
+    pub categories: Vec<String>,
+    #[serde(default = "default_key_patterns")]
+    pub keys: Vec<String>,
+}
+
+fn default_categories() -> Vec<String> {
+    vec!["@all".to_string()]
+}
+
+fn default_key_patterns() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+/// A string that is wiped from memory on drop and never printed by `Debug`.
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "String")]
+pub struct Secret(Zeroizing<String>);
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret::new(value)
+    }
+}
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl SecurityConfig {
+    /// Resolves the configured password source. At most one source may be set.
+    pub fn resolve_requirepass(&self) -> Result<Option<Secret>, config::ConfigError> {
+        let sources = [
+            self.requirepass.is_some(),
+            self.requirepass_file.is_some(),
+            self.requirepass_env.is_some(),
+        ];
+        if sources.iter().filter(|set| **set).count() > 1 {
+            return Err(config::ConfigError::Message(
+                "only one of requirepass, requirepass_file and requirepass_env may be set"
+                    .to_string(),
+            ));
+        }
+
+        if let Some(secret) = &self.requirepass {
+            return Ok(Some(secret.clone()));
+        }
+        if let Some(path) = &self.requirepass_file {
+            let contents = Zeroizing::new(std::fs::read_to_string(path).map_err(|e| {
+                config::ConfigError::Message(format!(
+                    "failed to read requirepass_file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?);
+            let trimmed = contents.trim_end_matches(['\r', '\n']);
+            return Ok(Some(Secret::new(trimmed.to_string())));
+        }
+        if let Some(var) = &self.requirepass_env {
+            let value = std::env::var(var).map_err(|e| {
+                config::ConfigError::Message(format!(
+                    "failed to read requirepass_env {}: {}",
+                    var, e
+                ))
+            })?;
+            return Ok(Some(Secret::new(value)));
+        }
+        Ok(None)
+    }
+}
+
+/// The config file `CONFIG REWRITE` writes to.
+pub const CONFIG_FILE: &str = "config.json";
+
+/// Loads the configuration, later sources overriding earlier ones:
+/// `redis.conf`, then `config.{json,toml,yaml,...}`, then `RDB_` environment
+/// variables with `__` between levels, as in `RDB_STORAGE__MAX_MEMORY=1gb`.
+/// Missing files are skipped; malformed values are errors.
+pub fn load_config() -> Result<Config, config::ConfigError> {
+    config::Config::builder()
+        .add_source(config::File::new("redis.conf", RedisConf).required(false))
+        .add_source(config::File::with_name("config").required(false))
+        .add_source(config::Environment::with_prefix("RDB").separator("__"))
+        .build()?
+        .try_deserialize()
+}
@@ -0,0 +1,229 @@
+//! Reading the options rdb shares with Redis from a `redis.conf` file
+use config::{FileStoredFormat, Format, Map, Value, ValueKind};
+use log::warn;
+use std::error::Error;
+
+/// The redis.conf format: one `directive arg...` per line, `#` comments,
+/// and arguments optionally in double quotes. Directives rdb has no
+/// equivalent for are skipped with a warning.
+#[derive(Debug, Clone, Copy)]
+pub struct RedisConf;
+
+impl FileStoredFormat for RedisConf {
+    fn file_extensions(&self) -> &'static [&'static str] {
+        &["conf"]
+    }
+}
+
+impl Format for RedisConf {
+    fn parse(
+        &self,
+        uri: Option<&String>,
+        text: &str,
+    ) -> Result<Map<String, Value>, Box<dyn Error + Send + Sync>> {
+        let mut options = Map::new();
+        let mut set = |key: &str, kind: ValueKind| {
+            options.insert(key.to_string(), Value::new(uri, kind));
+        };
+        let (mut bind, mut port) = (None, None);
+        let mut save_rules: Option<Vec<Value>> = None;
+
+        for (number, line) in text.lines().enumerate() {
+            let args = split_args(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+            let Some((directive, args)) = args.split_first() else {
+                continue;
+            };
+            let directive = directive.to_ascii_lowercase();
+            let one = || match args {
+                [arg] => Ok(ValueKind::String(arg.clone())),
+                _ => Err(format!(
+                    "line {}: {} takes exactly one argument",
+                    number + 1,
+                    directive
+                )),
+            };
+            match directive.as_str() {
+                "bind" => bind = args.first().cloned(),
+                "port" => port = Some(one()?.to_string()),
+                "maxclients" => set("server.max_connections", one()?),
+                "timeout" => set("server.idle_timeout_secs", one()?),
+                "shutdown-timeout" => set(
+                    "server.shutdown_grace_ms",
+                    ValueKind::String(format!("{}s", one()?)),
+                ),
+                "maxmemory-clients" => set("server.max_memory_clients", one()?),
+                "client-query-buffer-limit" => set("server.max_query_buffer", one()?),
+                "proto-max-bulk-len" => set("server.proto_max_bulk_len", one()?),
+                "slowlog-log-slower-than" => set("latency.slowlog_slower_than_us", one()?),
+                "slowlog-max-len" => set("latency.slowlog_max_len", one()?),
+                "latency-monitor-threshold" => set("latency.monitor_threshold_ms", one()?),
+                "maxmemory" => set("storage.max_memory", one()?),
+                "databases" => set("storage.databases", one()?),
+                "maxmemory-policy" => set("storage.maxmemory_policy", one()?),
+                "dbfilename" => set("storage.dbfilename", one()?),
+                "appendonly" => set("storage.appendonly", yes_no(&one()?.to_string())?),
+                "appendfilename" => set("storage.appendfilename", one()?),
+                "appenddirname" => set("storage.appenddirname", one()?),
+                "appendfsync" => set("storage.appendfsync", one()?),
+                "notify-keyspace-events" => set("storage.notify_keyspace_events", one()?),
+                "cluster-enabled" => set("cluster.enabled", yes_no(&one()?.to_string())?),
+                "requirepass" => set("security.requirepass", one()?),
+                "masteruser" => set("replication.masteruser", one()?),
+                "masterauth" => set("replication.masterauth", one()?),
+                "replicaof" | "slaveof" => match args {
+                    [host, port] => set(
+                        "replication.replicaof",
+                        ValueKind::String(format!("{}:{}", host, port)),
+                    ),
+                    _ => {
+                        return Err(format!(
+                            "line {}: {} takes a host and a port",
+                            number + 1,
+                            directive
+                        )
+                        .into())
+                    }
+                },
+                "save" => {
+                    let rules = save_rules.get_or_insert_with(Vec::new);
+                    // `save ""` turns snapshots off.
+                    if args.len() == 1 && args[0].is_empty() {
+                        rules.clear();
+                        continue;
+                    }
+                    if args.is_empty() || args.len() % 2 != 0 {
+                        return Err(format!(
+                            "line {}: save takes pairs of seconds and changes",
+                            number + 1
+                        )
+                        .into());
+                    }
+                    for pair in args.chunks(2) {
+                        let changes: u64 = pair[1].parse().map_err(|_| {
+                            format!("line {}: invalid number of changes", number + 1)
+                        })?;
+                        let mut rule = Map::new();
+                        rule.insert("seconds".to_string(), Value::new(uri, pair[0].clone()));
+                        rule.insert("changes".to_string(), Value::new(uri, changes));
+                        rules.push(Value::new(uri, rule));
+                    }
+                }
+                _ => warn!("Ignoring unsupported redis.conf directive {}", directive),
+            }
+        }
+
+        if bind.is_some() || port.is_some() {
+            let host = bind.unwrap_or_else(|| "127.0.0.1".to_string());
+            let port = port.unwrap_or_else(|| "6379".to_string());
+            let addr = match host.contains(':') {
+                true => format!("[{}]:{}", host, port),
+                false => format!("{}:{}", host, port),
+            };
+            set("server.listen_addr", ValueKind::String(addr));
+        }
+        if let Some(rules) = save_rules {
+            // Redis snapshots whenever save rules are given.
+            if !rules.is_empty() {
+                set("storage.persistence_enabled", ValueKind::Boolean(true));
+            }
+            set("storage.save_rules", ValueKind::Array(rules));
+        }
+        Ok(options)
+    }
+}
+
+fn yes_no(arg: &str) -> Result<ValueKind, String> {
+    match arg.to_ascii_lowercase().as_str() {
+        "yes" => Ok(ValueKind::Boolean(true)),
+        "no" => Ok(ValueKind::Boolean(false)),
+        _ => Err(format!("expected yes or no, got '{}'", arg)),
+    }
+}
+
+/// Splits a line into arguments, honoring double quotes and `\` escapes
+/// inside them.
+fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '#' && args.is_empty() {
+            break;
+        }
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => arg.extend(chars.next()),
+                    Some(c) => arg.push(c),
+                    None => return Err("unbalanced quotes".to_string()),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    #[test]
+    fn test_redis_conf() {
+        let text = "# Example\n\
+                    bind 0.0.0.0\n\
+                    port 7000\n\
+                    maxmemory 2gb\n\
+                    maxmemory-policy allkeys-lru\n\
+                    requirepass \"p w\"\n\
+                    save 15m 1\n\
+                    save 60 10000\n\
+                    appendonly yes\n\
+                    notify-keyspace-events Ex\n\
+                    slowlog-log-slower-than -1\n\
+                    shutdown-timeout 30\n\
+                    tcp-keepalive 300\n";
+        let config: Config = config::Config::builder()
+            .add_source(config::File::from_str(text, super::RedisConf))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(config.server.listen_addr.to_string(), "0.0.0.0:7000");
+        assert_eq!(config.storage.max_memory, 2 * 1024 * 1024 * 1024);
+        assert_eq!(config.storage.save_rules.len(), 2);
+        assert_eq!(config.storage.save_rules[0].seconds, 900);
+        assert!(config.storage.persistence_enabled && config.storage.appendonly);
+        assert_eq!(config.latency.slowlog_slower_than_us, -1);
+        assert_eq!(config.server.shutdown_grace_ms, 30_000);
+        assert_eq!(config.storage.notify_keyspace_events.to_string(), "xE");
+        assert_eq!(
+            config.security.requirepass.as_ref().map(|s| s.expose()),
+            Some("p w")
+        );
+
+        let malformed = config::Config::builder()
+            .add_source(config::File::from_str("maxmemory 2zb\n", super::RedisConf))
+            .build()
+            .unwrap()
+            .try_deserialize::<Config>();
+        assert!(malformed
+            .unwrap_err()
+            .to_string()
+            .contains("invalid size '2zb'"));
+    }
+}
@@ -0,0 +1,425 @@
+//! Reading and changing settings while the server runs: `CONFIG GET`,
+//! `CONFIG SET` and `CONFIG REWRITE`
+//!
+//! Parameters go by their redis.conf names. The storage settings live with
+//! the shards, which consult them on every use, so a change applies from the
+//! next command on.
+use super::{units, MaxMemoryPolicy, SaveRule, StorageConfig, CONFIG_FILE};
+use crate::commands::check_disabled_groups;
+use crate::glob;
+use crate::storage::ShardLocks;
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum RuntimeConfigError {
+    #[error("ERR Unknown option or number of arguments for CONFIG SET - '{0}'")]
+    Unknown(String),
+    #[error(
+        "ERR CONFIG SET failed (possibly related to argument '{0}') - can't set immutable config"
+    )]
+    Immutable(&'static str),
+    #[error("ERR CONFIG SET failed (possibly related to argument '{0}') - {1}")]
+    Invalid(&'static str, String),
+    #[error("ERR Rewriting config file: {0}")]
+    Rewrite(String),
+}
+
+/// Applies a parameter's new value, or says why it can't.
+type Setter = fn(&mut ShardLocks, &str) -> Result<(), String>;
+
+struct Param {
+    name: &'static str,
+    get: fn(&StorageConfig) -> String,
+    /// Changes the setting; `None` for those fixed at startup.
+    set: Option<Setter>,
+    /// The option in the `storage` section of the config file, and its
+    /// value there.
+    option: &'static str,
+    json: fn(&StorageConfig) -> Value,
+}
+
+const PARAMS: &[Param] = &[
+    Param {
+        name: "maxmemory",
+        get: |config| config.max_memory.to_string(),
+        set: Some(|store, value| {
+            let bytes = units::parse_size(value)?;
+            store.set_max_memory(bytes.try_into().map_err(|_| "size out of range")?);
+            Ok(())
+        }),
+        option: "max_memory",
+        json: |config| json!(config.max_memory),
+    },
+    Param {
+        name: "maxmemory-policy",
+        get: |config| config.maxmemory_policy.as_str().to_string(),
+        set: Some(|store, value| {
+            store.set_maxmemory_policy(parse_policy(value)?);
+            Ok(())
+        }),
+        option: "maxmemory_policy",
+        json: |config| json!(config.maxmemory_policy.as_str()),
+    },
+    Param {
+        name: "memory-alarms",
+        get: |config| {
+            let percents: Vec<String> = config.memory_alarms.iter().map(u8::to_string).collect();
+            percents.join(" ")
+        },
+        set: Some(|store, value| {
+            let percents = parse_memory_alarms(value)?;
+            store.update_config(|config| config.memory_alarms = percents);
+            Ok(())
+        }),
+        option: "memory_alarms",
+        json: |config| json!(config.memory_alarms),
+    },
+    Param {
+        name: "eviction-hook",
+        get: |config| {
+            config
+                .eviction_hook
+                .as_ref()
+                .map_or_else(String::new, |path| path.display().to_string())
+        },
+        set: Some(|store, value| {
+            let path = (!value.is_empty()).then(|| PathBuf::from(value));
+            set_eviction_hook(store, path.as_deref())?;
+            store.update_config(|config| config.eviction_hook = path);
+            Ok(())
+        }),
+        option: "eviction_hook",
+        json: |config| json!(config.eviction_hook),
+    },
+    Param {
+        name: "save",
+        get: |config| {
+            let rules: Vec<String> = config
+                .save_rules
+                .iter()
+                .map(|rule| format!("{} {}", rule.seconds, rule.changes))
+                .collect();
+            rules.join(" ")
+        },
+        set: Some(|store, value| {
+            let rules = parse_save_rules(value)?;
+            store.update_config(|config| config.save_rules = rules);
+            Ok(())
+        }),
+        option: "save_rules",
+        json: |config| {
+            let rules: Vec<Value> = config
+                .save_rules
+                .iter()
+                .map(|rule| json!({ "seconds": rule.seconds, "changes": rule.changes }))
+                .collect();
+            Value::Array(rules)
+        },
+    },
+    Param {
+        name: "dbfilename",
+        get: |config| config.dbfilename.display().to_string(),
+        set: Some(|store, value| {
+            if value.is_empty() {
+                return Err("dbfilename can't be empty".to_string());
+            }
+            let path = PathBuf::from(value);
+            store.update_config(|config| config.dbfilename = path);
+            Ok(())
+        }),
+        option: "dbfilename",
+        json: |config| json!(config.dbfilename),
+    },
+    Param {
+        name: "databases",
+        get: |config| config.databases.to_string(),
+        set: None,
+        option: "databases",
+        json: |config| json!(config.databases),
+    },
+    Param {
+        name: "appendonly",
+        get: |config| if config.appendonly { "yes" } else { "no" }.to_string(),
+        set: None,
+        option: "appendonly",
+        json: |config| json!(config.appendonly),
+    },
+    Param {
+        name: "appendfilename",
+        get: |config| config.appendfilename.display().to_string(),
+        set: None,
+        option: "appendfilename",
+        json: |config| json!(config.appendfilename),
+    },
+    Param {
+        name: "appenddirname",
+        get: |config| config.appenddirname.display().to_string(),
+        set: None,
+        option: "appenddirname",
+        json: |config| json!(config.appenddirname),
+    },
+    Param {
+        name: "appendfsync",
+        get: |config| config.appendfsync.as_str().to_string(),
+        set: None,
+        option: "appendfsync",
+        json: |config| json!(config.appendfsync.as_str()),
+    },
+    Param {
+        name: "notify-keyspace-events",
+        get: |config| config.notify_keyspace_events.to_string(),
+        set: Some(|store, value| {
+            let events = value.parse()?;
+            store.update_config(|config| config.notify_keyspace_events = events);
+            Ok(())
+        }),
+        option: "notify_keyspace_events",
+        json: |config| json!(config.notify_keyspace_events.to_string()),
+    },
+    Param {
+        name: "ttl-jitter-percent",
+        get: |config| config.ttl_jitter_percent.to_string(),
+        set: Some(|store, value| {
+            let percent = match value.trim_end_matches('%').parse() {
+                Ok(percent @ 0..=100) => percent,
+                _ => return Err(format!("invalid jitter percentage '{}'", value)),
+            };
+            store.update_config(|config| config.ttl_jitter_percent = percent);
+            Ok(())
+        }),
+        option: "ttl_jitter_percent",
+        json: |config| json!(config.ttl_jitter_percent),
+    },
+    Param {
+        name: "disabled-command-groups",
+        get: |config| config.disabled_command_groups.join(" "),
+        set: Some(|store, value| {
+            let groups: Vec<String> = value.split_whitespace().map(str::to_lowercase).collect();
+            check_disabled_groups(&groups)?;
+            store.update_config(|config| config.disabled_command_groups = groups);
+            Ok(())
+        }),
+        option: "disabled_command_groups",
+        json: |config| json!(config.disabled_command_groups),
+    },
+];
+
+fn parse_policy(value: &str) -> Result<MaxMemoryPolicy, String> {
+    [
+        MaxMemoryPolicy::NoEviction,
+        MaxMemoryPolicy::AllKeysLru,
+        MaxMemoryPolicy::AllKeysRandom,
+        MaxMemoryPolicy::VolatileTtl,
+    ]
+    .into_iter()
+    .find(|policy| policy.as_str().eq_ignore_ascii_case(value))
+    .ok_or_else(|| format!("unknown maxmemory policy '{}'", value))
+}
+
+/// Consults the Lua script at `path` before each eviction, or nothing for
+/// `None`.
+#[cfg(feature = "scripting")]
+fn set_eviction_hook(store: &mut ShardLocks, path: Option<&Path>) -> Result<(), String> {
+    let hook = match path {
+        Some(path) => Some(std::sync::Arc::new(crate::scripting::EvictionScript::load(path)?) as _),
+        None => None,
+    };
+    store.set_eviction_hook(hook);
+    Ok(())
+}
+
+#[cfg(not(feature = "scripting"))]
+fn set_eviction_hook(_store: &mut ShardLocks, path: Option<&Path>) -> Result<(), String> {
+    match path {
+        Some(_) => Err("this build lacks the scripting feature".to_string()),
+        None => Ok(()),
+    }
+}
+
+/// Percentages of `maxmemory` separated by spaces; an empty value turns
+/// the alarms off.
+fn parse_memory_alarms(value: &str) -> Result<Vec<u8>, String> {
+    value
+        .split_whitespace()
+        .map(|percent| match percent.trim_end_matches('%').parse() {
+            Ok(percent @ 1..=100) => Ok(percent),
+            _ => Err(format!("invalid memory alarm percentage '{}'", percent)),
+        })
+        .collect()
+}
+
+/// `seconds changes` pairs, as in redis.conf; an empty value disables
+/// automatic saves.
+fn parse_save_rules(value: &str) -> Result<Vec<SaveRule>, String> {
+    let args: Vec<&str> = value.split_whitespace().collect();
+    if !args.len().is_multiple_of(2) {
+        return Err("save takes pairs of seconds and changes".to_string());
+    }
+    args.chunks(2)
+        .map(|pair| {
+            let seconds = units::parse_duration(pair[0], Duration::from_secs(1))?.as_secs();
+            let changes = pair[1]
+                .parse()
+                .map_err(|_| format!("invalid number of changes '{}'", pair[1]))?;
+            Ok(SaveRule { seconds, changes })
+        })
+        .collect()
+}
+
+/// Names and values of the parameters matching any of `patterns`.
+pub fn get(store: &ShardLocks, patterns: &[String]) -> Vec<(&'static str, String)> {
+    let config = store.config();
+    PARAMS
+        .iter()
+        .filter(|param| {
+            patterns
+                .iter()
+                .any(|pattern| glob::matches(pattern.to_ascii_lowercase(), param.name))
+        })
+        .map(|param| (param.name, (param.get)(&config)))
+        .collect()
+}
+
+/// Changes parameter `name` to `value`, with every shard locked.
+pub fn set(store: &mut ShardLocks, name: &str, value: &str) -> Result<(), RuntimeConfigError> {
+    let param = PARAMS
+        .iter()
+        .find(|param| param.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| RuntimeConfigError::Unknown(name.to_string()))?;
+    let set = param.set.ok_or(RuntimeConfigError::Immutable(param.name))?;
+    set(store, value).map_err(|e| RuntimeConfigError::Invalid(param.name, e))
+}
+
+/// Writes the current value of every parameter `CONFIG SET` can change
+/// into the JSON config file, keeping the file's other options.
+pub fn rewrite(store: &ShardLocks) -> Result<(), RuntimeConfigError> {
+    rewrite_file(Path::new(CONFIG_FILE), &store.config())
+}
+
+fn rewrite_file(path: &Path, config: &StorageConfig) -> Result<(), RuntimeConfigError> {
+    let error = |e: &dyn std::fmt::Display| RuntimeConfigError::Rewrite(e.to_string());
+    let mut file = match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).map_err(|e| error(&e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Value::Object(Map::new()),
+        Err(e) => return Err(error(&e)),
+    };
+    let storage = file
+        .as_object_mut()
+        .ok_or_else(|| error(&"the file doesn't hold an object"))?
+        .entry("storage")
+        .or_insert_with(|| Value::Object(Map::new()))
+        .as_object_mut()
+        .ok_or_else(|| error(&"the storage section isn't an object"))?;
+    for param in PARAMS.iter().filter(|param| param.set.is_some()) {
+        storage.insert(param.option.to_string(), (param.json)(config));
+    }
+    let mut text = serde_json::to_string_pretty(&file).map_err(|e| error(&e))?;
+    text.push('\n');
+    // Written aside and renamed over, so a crash can't leave half a file.
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, text).map_err(|e| error(&e))?;
+    std::fs::rename(&temp, path).map_err(|e| error(&e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::storage::Shards;
+
+    #[tokio::test]
+    async fn test_get_and_set() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1000,
+            shards: 3,
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        assert_eq!(
+            get(&store, &["MAXMEMORY*".to_string()]),
+            vec![
+                ("maxmemory", "1000".to_string()),
+                ("maxmemory-policy", "noeviction".to_string())
+            ]
+        );
+
+        set(&mut store, "maxmemory", "2kb").unwrap();
+        assert_eq!(store.max_memory(), 2048);
+        let budgets: Vec<usize> = store.iter().map(|shard| shard.max_memory()).collect();
+        assert_eq!(budgets, vec![683, 683, 682]);
+        set(&mut store, "MaxMemory-Policy", "allkeys-lru").unwrap();
+        assert!(store.iter().all(|shard| shard.is_evicting()));
+        set(&mut store, "save", "15m 1 60 100").unwrap();
+        assert_eq!(get(&store, &["save".to_string()])[0].1, "900 1 60 100");
+        set(&mut store, "save", "").unwrap();
+        assert!(store.config().save_rules.is_empty());
+        set(&mut store, "notify-keyspace-events", "KEA").unwrap();
+        assert_eq!(get(&store, &["notify-*".to_string()])[0].1, "AKE");
+        set(&mut store, "memory-alarms", "80 90%").unwrap();
+        assert_eq!(store.config().memory_alarms, vec![80, 90]);
+        assert_eq!(get(&store, &["memory-alarms".to_string()])[0].1, "80 90");
+        assert!(set(&mut store, "memory-alarms", "120").is_err());
+        set(&mut store, "ttl-jitter-percent", "10%").unwrap();
+        assert_eq!(store.config().ttl_jitter_percent, 10);
+        assert!(set(&mut store, "ttl-jitter-percent", "101").is_err());
+        set(&mut store, "disabled-command-groups", "Scripting cluster").unwrap();
+        assert_eq!(
+            get(&store, &["disabled-command-groups".to_string()])[0].1,
+            "scripting cluster"
+        );
+        assert!(set(&mut store, "disabled-command-groups", "server").is_err());
+        assert!(set(&mut store, "disabled-command-groups", "streams").is_err());
+        set(&mut store, "disabled-command-groups", "").unwrap();
+        assert!(store.config().disabled_command_groups.is_empty());
+
+        assert_eq!(
+            set(&mut store, "databases", "4"),
+            Err(RuntimeConfigError::Immutable("databases"))
+        );
+        assert_eq!(
+            set(&mut store, "nosuch", "1"),
+            Err(RuntimeConfigError::Unknown("nosuch".to_string()))
+        );
+        assert!(matches!(
+            set(&mut store, "maxmemory", "lots"),
+            Err(RuntimeConfigError::Invalid("maxmemory", _))
+        ));
+    }
+
+    #[test]
+    fn test_rewrite_keeps_other_options() {
+        let dir = std::env::temp_dir().join(format!("rdb-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"server": {"buffer_size": "4kb"}, "storage": {"max_memory": "1gb", "shards": 4}}"#,
+        )
+        .unwrap();
+        let config = StorageConfig {
+            max_memory: 512,
+            save_rules: vec![SaveRule {
+                seconds: 60,
+                changes: 10,
+            }],
+            ..Default::default()
+        };
+        rewrite_file(&path, &config).unwrap();
+
+        let loaded: Config = config::Config::builder()
+            .add_source(config::File::from(path.clone()))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(loaded.server.buffer_size, 4096);
+        assert_eq!(loaded.storage.shards, 4);
+        assert_eq!(loaded.storage.max_memory, 512);
+        assert_eq!(loaded.storage.save_rules, config.save_rules);
+        assert_eq!(loaded.storage.maxmemory_policy, MaxMemoryPolicy::NoEviction);
+    }
+}
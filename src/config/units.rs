@@ -0,0 +1,174 @@
+//! Sizes and durations with units, as in `max_memory: "1gb"`
+//!
+//! Every option using these also takes a plain number, in bytes for sizes
+//! and in the option's own unit for durations.
+use serde::de::{self, Deserializer, Visitor};
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// Parses a whole number of bytes with an optional unit. As in redis.conf,
+/// `k`, `m` and `g` are powers of 1000 and `kb`, `mb` and `gb` powers of
+/// 1024; units are case insensitive.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let (number, unit) = split_unit(text);
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(format!(
+                "invalid size '{}': expected a whole number with an optional unit \
+                 (b, k, kb, m, mb, g or gb)",
+                text
+            ))
+        }
+    };
+    scale(text, number, multiplier)
+}
+
+/// Parses a whole duration with an optional unit: `ms`, `s`, `m`, `h` or
+/// `d`. Plain numbers are in `unit`.
+pub fn parse_duration(text: &str, unit: Duration) -> Result<Duration, String> {
+    let (number, suffix) = split_unit(text);
+    let unit = match suffix.to_ascii_lowercase().as_str() {
+        "" => unit,
+        "ms" => Duration::from_millis(1),
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(60 * 60),
+        "d" => Duration::from_secs(24 * 60 * 60),
+        _ => {
+            return Err(format!(
+                "invalid duration '{}': expected a whole number with an optional unit \
+                 (ms, s, m, h or d)",
+                text
+            ))
+        }
+    };
+    let millis = scale(text, number, unit.as_millis() as u64)?;
+    Ok(Duration::from_millis(millis))
+}
+
+fn split_unit(text: &str) -> (&str, &str) {
+    let text = text.trim();
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    (&text[..digits], text[digits..].trim_start())
+}
+
+fn scale(text: &str, number: &str, multiplier: u64) -> Result<u64, String> {
+    if number.is_empty() {
+        return Err(format!("invalid value '{}': expected a number", text));
+    }
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("value '{}' is too large", text))
+}
+
+/// Accepts a number as is or a string with a unit, converted by `parse`.
+struct UnitVisitor<T> {
+    expecting: &'static str,
+    parse: fn(&str) -> Result<u64, String>,
+    target: PhantomData<T>,
+}
+
+impl<T: TryFrom<u64>> Visitor<'_> for UnitVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, n: u64) -> Result<T, E> {
+        T::try_from(n).map_err(|_| E::custom(format!("value {} is too large", n)))
+    }
+
+    fn visit_i64<E: de::Error>(self, n: i64) -> Result<T, E> {
+        let n = u64::try_from(n).map_err(|_| E::custom(format!("value {} is negative", n)))?;
+        self.visit_u64(n)
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<T, E> {
+        let n = (self.parse)(text).map_err(E::custom)?;
+        self.visit_u64(n)
+    }
+}
+
+fn deserialize_with<'de, D, T>(
+    deserializer: D,
+    expecting: &'static str,
+    parse: fn(&str) -> Result<u64, String>,
+) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserializer.deserialize_any(UnitVisitor {
+        expecting,
+        parse,
+        target: PhantomData,
+    })
+}
+
+/// For `#[serde(deserialize_with)]` on sizes in bytes.
+pub fn size<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_with(deserializer, "a size such as 1024 or \"512mb\"", parse_size)
+}
+
+/// For durations kept as milliseconds.
+pub fn millis<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_with(deserializer, "a duration such as 500 or \"30s\"", |text| {
+        parse_duration(text, Duration::from_millis(1)).map(|d| d.as_millis() as u64)
+    })
+}
+
+/// For durations kept as seconds. Units finer than a second must add up
+/// to whole seconds.
+pub fn secs<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    deserialize_with(deserializer, "a duration such as 60 or \"15m\"", |text| {
+        let duration = parse_duration(text, Duration::from_secs(1))?;
+        if duration.subsec_nanos() != 0 {
+            return Err(format!(
+                "duration '{}' isn't a whole number of seconds",
+                text
+            ));
+        }
+        Ok(duration.as_secs())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_units() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("1k"), Ok(1000));
+        assert_eq!(parse_size("512mb"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("1GB"), Ok(1024 * 1024 * 1024));
+        assert_eq!(parse_size(" 2 g "), Ok(2_000_000_000));
+        for bad in ["", "mb", "1.5gb", "-1", "12xb", "99999999999999999999gb"] {
+            assert!(parse_size(bad).is_err(), "{:?}", bad);
+        }
+
+        let ms = Duration::from_millis(1);
+        assert_eq!(parse_duration("250", ms), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30s", ms), Ok(Duration::from_secs(30)));
+        assert_eq!(
+            parse_duration("15m", Duration::from_secs(1)),
+            Ok(Duration::from_secs(900))
+        );
+        assert_eq!(parse_duration("1d", ms), Ok(Duration::from_secs(86400)));
+        assert!(parse_duration("30 sec", ms).is_err());
+        assert!(parse_duration("s", ms).is_err());
+    }
+}
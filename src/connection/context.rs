@@ -0,0 +1,50 @@
+//! The connection state a command runs with
+//!
+//! Each connection keeps its [`ConnCtx`] and hands it to the command
+//! handlers with every command, so a handler can answer for the client
+//! instead of the connection special-casing the command. Commands no client
+//! sent, replayed from the append-only file, applied from a primary or
+//! translated from memcached, run with the default context.
+use crate::acl::User;
+use crate::commands::Blocklist;
+use crate::protocol::Protocol;
+use std::sync::Arc;
+
+/// Whether the connection replies to commands, set with `CLIENT REPLY`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReplyMode {
+    #[default]
+    On,
+    Off,
+    /// Skips the reply to the next command only.
+    Skip,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ConnCtx {
+    /// `None` for commands no client sent.
+    pub client_id: Option<u64>,
+    /// The database picked with `SELECT`.
+    pub db: usize,
+    /// Who the client is authenticated as; `None` until it is.
+    pub user: Option<Arc<User>>,
+    pub protocol: Protocol,
+    /// Whether the command runs inside `MULTI`/`EXEC`.
+    pub in_transaction: bool,
+    /// Channels and patterns subscribed to.
+    pub subscriptions: usize,
+    pub reply_mode: ReplyMode,
+    /// Commands the listener the client connected to refuses.
+    pub blocklist: Arc<Blocklist>,
+}
+
+impl ConnCtx {
+    /// The context of a freshly connected client.
+    pub fn new(client_id: u64, user: Option<Arc<User>>) -> Self {
+        ConnCtx {
+            client_id: Some(client_id),
+            user,
+            ..ConnCtx::default()
+        }
+    }
+}
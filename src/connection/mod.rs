@@ -0,0 +1,948 @@
+//! Per-client socket handling
+mod context;
+mod reader;
+mod stats;
+mod transaction;
+mod turn;
+mod writer;
+
+pub use context::{ConnCtx, ReplyMode};
+pub use reader::FrameReader;
+pub use stats::{ClientHandle, ClientRegistry, KillFilter, ShutdownRequest};
+use transaction::Transaction;
+use turn::Turn;
+pub use writer::ReplyWriter;
+
+use crate::acl::{Acl, DEFAULT_USER};
+use crate::build_info;
+use crate::changefeed::{Change, Tail};
+use crate::cluster::{key_slot, Cluster, Redirect};
+use crate::commands::{
+    disabled_refusal, execute, execute_locked_in, Blocklist, Command, CommandClass,
+};
+use crate::config::{Config, Secret};
+use crate::monitor::{MonitoredCommand, Watcher};
+use crate::protocol::{Limits, Protocol, RespValue};
+use crate::pubsub::{Broker, Subscriber};
+use crate::replication::{ReplicaFeed, Replication};
+use crate::storage::{big_keys, rdb, Db, Deadline, End, StorageError};
+use bytes::Bytes;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// A client connection: its socket halves plus per-connection state.
+pub struct Connection {
+    reader: FrameReader<OwnedReadHalf>,
+    writer: ReplyWriter<OwnedWriteHalf>,
+    addr: Option<SocketAddr>,
+    db: Db,
+    /// What handlers see of the connection.
+    ctx: ConnCtx,
+    acl: Arc<Acl>,
+    broker: Arc<Broker>,
+    clients: Arc<ClientRegistry>,
+    client: ClientHandle,
+    replication: Arc<Replication>,
+    /// The slot map, in cluster mode.
+    cluster: Option<Arc<Cluster>>,
+    config: Config,
+    subscriber: Subscriber,
+    transaction: Transaction,
+    /// Port announced with `REPLCONF listening-port` by a replica.
+    replica_port: Option<u16>,
+    /// Set once a replica sent `PSYNC`; its writes are forwarded from here.
+    feed: Option<ReplicaFeed>,
+    /// Set by `CDC TAIL`; changes are pushed to the client as they happen.
+    tail: Option<Tail>,
+    /// Set by `MONITOR`; every command run is pushed to the client.
+    monitor: Option<Watcher>,
+    /// Set once the server starts shutting down; the connection closes as
+    /// soon as it has nothing left half done.
+    draining: bool,
+}
+
+impl Connection {
+    pub fn new(
+        socket: TcpStream,
+        db: Db,
+        acl: Arc<Acl>,
+        clients: Arc<ClientRegistry>,
+        replication: Arc<Replication>,
+        cluster: Option<Arc<Cluster>>,
+        config: Config,
+    ) -> Self {
+        let addr = socket.peer_addr().ok();
+        let (reader, writer) = socket.into_split();
+        let client = clients.register(addr);
+        Connection {
+            reader: FrameReader::new(
+                reader,
+                config.server.buffer_size,
+                Limits::requests(config.server.proto_max_bulk_len),
+            ),
+            writer: ReplyWriter::new(writer, config.server.output_buffer_high_water),
+            addr,
+            ctx: ConnCtx::new(client.id(), acl.initial_user()),
+            subscriber: Subscriber::new(db.broker().clone()),
+            broker: db.broker().clone(),
+            client,
+            transaction: Transaction::default(),
+            replica_port: None,
+            feed: None,
+            tail: None,
+            monitor: None,
+            draining: false,
+            db,
+            acl,
+            clients,
+            replication,
+            cluster,
+            config,
+        }
+    }
+
+    /// Refuses the commands of `blocklist`, as the listener the client
+    /// connected to is configured to.
+    pub fn with_blocklist(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.ctx.blocklist = blocklist;
+        self
+    }
+
+    /// Serves the client until it disconnects, quits or times out.
+    pub async fn run(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut turn = Turn::new(&self.config.server);
+        loop {
+            self.client
+                .set_buffers(self.reader.buffered(), self.writer.queued());
+            self.client.set_parse_time(self.reader.parse_time());
+
+            // Run every complete command already buffered, unless the client
+            // has stopped reading replies; then stop taking input so its own
+            // socket backs up instead of our buffers.
+            turn.start();
+            while !self.writer.is_saturated() {
+                let frame = match self.reader.next_frame() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(e) => {
+                        // The stream can't be resynchronized after garbage.
+                        self.writer
+                            .push(&RespValue::Error(format!("ERR Protocol error: {}", e)));
+                        self.writer.flush().await?;
+                        return Ok(());
+                    }
+                };
+                debug!("Received frame: {:?}", frame);
+                if self.handle_frame(frame).await {
+                    self.writer.flush().await?;
+                    return Ok(());
+                }
+                // More is buffered, but others get a go first.
+                if turn.record() && self.reader.buffered() > 0 {
+                    self.writer.flush().await?;
+                    self.db.stats().record_yield();
+                    tokio::task::yield_now().await;
+                    turn.start();
+                }
+            }
+
+            // Shutting down, the client goes once its transaction is over
+            // and it isn't midway through sending a command.
+            if self.draining && self.reader.buffered() == 0 && !self.transaction.is_active() {
+                self.writer.flush().await?;
+                return Ok(());
+            }
+
+            // All that's buffered is part of a command: it can't grow past
+            // the limit waiting for the rest.
+            if self.reader.partial_for().is_some()
+                && self.reader.buffered() > self.config.server.max_query_buffer
+            {
+                warn!(
+                    "Client {} closed for a command over {} bytes",
+                    self.client.id(),
+                    self.config.server.max_query_buffer
+                );
+                return Ok(());
+            }
+
+            let read_timeout = self.read_timeout();
+
+            // Read more input with timeout, forwarding published messages and
+            // draining queued replies in the meantime.
+            let saturated = self.writer.is_saturated();
+            tokio::select! {
+                read = timeout(read_timeout, self.reader.fill()), if !saturated => match read {
+                    Ok(Ok(0)) => return Ok(()), // Client disconnected
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) if self.partial_command_expired() => {
+                        warn!(
+                            "Client {} closed for taking over {}ms to send a command",
+                            self.client.id(),
+                            self.config.server.partial_command_timeout_ms
+                        );
+                        return Ok(());
+                    }
+                    Err(_) => return Err("Client timeout".into()),
+                },
+                written = self.writer.write_some(), if self.writer.has_pending() => written?,
+                _ = self.client.killed() => {
+                    if self.client.is_evicted() {
+                        info!("Client {} evicted: client buffers over the limit", self.client.id());
+                    } else {
+                        info!("Client {} killed", self.client.id());
+                    }
+                    return Ok(());
+                }
+                _ = self.client.draining(), if !self.draining => {
+                    self.draining = true;
+                    // RESP3 clients are told why, and how long they have.
+                    if self.writer.protocol() == Protocol::Resp3 {
+                        self.writer.push(&RespValue::Push(vec![
+                            RespValue::bulk("shutdown"),
+                            RespValue::Integer(self.config.server.shutdown_grace_ms as i64),
+                        ]));
+                    }
+                }
+                message = self.subscriber.recv(), if !saturated => self.writer.push(&message),
+                frame = next_feed_frame(&mut self.feed), if !saturated && self.feed.is_some() => {
+                    match frame {
+                        Some(frame) => self.writer.push_bytes(frame),
+                        // Dropped for falling behind; it will reconnect.
+                        None => return Ok(()),
+                    }
+                }
+                command = next_monitored(&mut self.monitor), if !saturated && self.monitor.is_some() => {
+                    match command {
+                        Ok(command) => self.writer.push(&RespValue::SimpleString(command.to_line())),
+                        Err(missed) => info!(
+                            "Monitor {} fell behind, {} commands missed",
+                            self.client.id(),
+                            missed
+                        ),
+                    }
+                }
+                change = next_change(&mut self.tail), if !saturated && self.tail.is_some() => {
+                    match change {
+                        Ok(change) => self.writer.push(&change.to_resp()),
+                        Err(lost) => {
+                            // The tailer can't tell what it missed; it has to
+                            // start over from a full copy.
+                            self.tail = None;
+                            self.writer.push(&RespValue::Error(format!(
+                                "ERR change feed lagged, {} changes lost",
+                                lost
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// How long to wait for more input. A command started must be finished
+    /// within `read_timeout_ms`; between commands the client may stay idle
+    /// for `idle_timeout_secs`. Subscribers may legitimately sit idle while
+    /// waiting for messages, and so may replicas and tailers while there
+    /// are no writes. Zero means no limit. A command started must also be
+    /// finished within `partial_command_timeout_ms` of starting it.
+    fn read_timeout(&self) -> Duration {
+        let server = &self.config.server;
+        let limit = if self.reader.buffered() > 0 && server.read_timeout_ms > 0 {
+            Duration::from_millis(server.read_timeout_ms)
+        } else if self.subscriber.is_active()
+            || self.feed.is_some()
+            || self.tail.is_some()
+            || self.monitor.is_some()
+        {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(server.idle_timeout_secs)
+        };
+        let limit = if limit.is_zero() {
+            Duration::MAX
+        } else {
+            limit
+        };
+        match self.reader.partial_for() {
+            Some(waiting) if server.partial_command_timeout_ms > 0 => {
+                let total = Duration::from_millis(server.partial_command_timeout_ms);
+                limit.min(total.saturating_sub(waiting))
+            }
+            _ => limit,
+        }
+    }
+
+    /// Whether the client has been sending the command it started for
+    /// longer than `partial_command_timeout_ms`, however steadily.
+    fn partial_command_expired(&self) -> bool {
+        let limit = self.config.server.partial_command_timeout_ms;
+        limit > 0
+            && self
+                .reader
+                .partial_for()
+                .is_some_and(|waiting| waiting >= Duration::from_millis(limit))
+    }
+
+    /// Runs one command frame and queues its replies. Returns true if the
+    /// connection should be closed afterwards.
+    async fn handle_frame(&mut self, frame: RespValue) -> bool {
+        let mut quit = false;
+        let started = Instant::now();
+        self.ctx.in_transaction = self.transaction.is_active();
+        self.ctx.subscriptions = self.subscriber.count();
+        // Kept for the slow log; cloning `Bytes` only bumps a reference count.
+        let args: Vec<Bytes> = match &frame {
+            RespValue::Array(items) => items
+                .iter()
+                .filter_map(|item| match item {
+                    RespValue::BulkString(Some(arg)) => Some(arg.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        let command = Command::from_frame(frame);
+        // `CLIENT REPLY` itself answers `ON` even while replies are off.
+        let silenced = !matches!(command, Ok(Command::ClientReply(_)))
+            && match self.ctx.reply_mode {
+                ReplyMode::On => false,
+                ReplyMode::Off => true,
+                ReplyMode::Skip => {
+                    self.ctx.reply_mode = ReplyMode::On;
+                    true
+                }
+            };
+        let name = command.as_ref().ok().map(Command::name);
+        if let Some(name) = name {
+            self.client.record_command(name, self.ctx.db);
+        }
+        // Passwords stay out of the feed, and so do clients yet to log in.
+        let monitored = match &command {
+            Ok(Command::Auth(..) | Command::Hello { auth: Some(_), .. } | Command::Monitor) => {
+                false
+            }
+            Ok(_) => self.ctx.user.is_some(),
+            Err(_) => false,
+        };
+        if monitored && self.db.monitor().is_watched() {
+            self.db.monitor().publish(MonitoredCommand {
+                time: SystemTime::now(),
+                db: self.ctx.db,
+                client_addr: self
+                    .client
+                    .addr()
+                    .map_or_else(String::new, |addr| addr.to_string()),
+                args: args.clone(),
+            });
+        }
+        let redirect = match (&command, &self.cluster) {
+            (Ok(command), Some(cluster)) => cluster.route(&command.keys()).err(),
+            _ => None,
+        };
+        let disabled = command.as_ref().ok().and_then(|command| {
+            disabled_refusal(&self.db.config().disabled_command_groups, command)
+        });
+        let replies = match command {
+            Ok(Command::Auth(user, password)) => vec![self.authenticate(user, password).await],
+            Ok(Command::Hello { protover, auth }) => vec![self.hello(protover, auth).await],
+            Ok(Command::Quit) => {
+                quit = true;
+                self.reset();
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            Ok(Command::Reset) => {
+                self.reset();
+                vec![RespValue::SimpleString("RESET".to_string())]
+            }
+            Ok(_) if self.ctx.user.is_none() => vec![RespValue::Error(
+                "NOAUTH Authentication required.".to_string(),
+            )],
+            Ok(command) if self.ctx.blocklist.blocks(&command) => {
+                self.transaction.abort();
+                vec![Blocklist::refusal(&command)]
+            }
+            Ok(_) if disabled.is_some() => {
+                self.transaction.abort();
+                disabled.into_iter().collect()
+            }
+            Ok(command) if !self.permits(&command) => {
+                self.transaction.abort();
+                vec![self.permission_error(&command)]
+            }
+            // RESP3 carries messages out of band, so any command may run.
+            Ok(command)
+                if self.subscriber.is_active()
+                    && self.writer.protocol() == Protocol::Resp2
+                    && !command.allowed_while_subscribed() =>
+            {
+                vec![RespValue::Error(format!(
+                    "ERR Can't execute '{}': only (P|S)SUBSCRIBE / \
+                     (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                    command.name()
+                ))]
+            }
+            Ok(_) if redirect.is_some() => {
+                self.transaction.abort();
+                redirect.iter().map(Redirect::to_resp).collect()
+            }
+            Ok(Command::Multi) if self.transaction.is_active() => {
+                vec![RespValue::Error(
+                    "ERR MULTI calls can not be nested".to_string(),
+                )]
+            }
+            Ok(Command::Multi) => {
+                self.transaction.begin();
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            Ok(Command::Exec) => vec![self.exec().await],
+            Ok(Command::Discard) => match self.transaction.finish() {
+                Some(_) => vec![RespValue::SimpleString("OK".to_string())],
+                None => vec![RespValue::Error("ERR DISCARD without MULTI".to_string())],
+            },
+            Ok(Command::Watch(_)) if self.transaction.is_active() => {
+                vec![RespValue::Error(
+                    "ERR WATCH inside MULTI is not allowed".to_string(),
+                )]
+            }
+            Ok(Command::Watch(keys)) => {
+                let namespace = self.db.namespace(self.ctx.db);
+                let keys = namespace.keys(&keys);
+                let mut store = self.db.lock(&keys).await;
+                store
+                    .select(namespace.storage_db())
+                    .expect("the selected database exists");
+                self.transaction.watch(&store, keys);
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            // Unless databases are key prefixes, cluster mode only has one.
+            Ok(Command::Select(index))
+                if index != 0
+                    && self.cluster.is_some()
+                    && !self.db.namespace(index).is_prefixed() =>
+            {
+                vec![RespValue::Error(
+                    "ERR SELECT is not allowed in cluster mode".to_string(),
+                )]
+            }
+            Ok(Command::Select(index)) if !self.transaction.is_active() => {
+                if index < self.db.databases() {
+                    self.ctx.db = index;
+                    vec![RespValue::SimpleString("OK".to_string())]
+                } else {
+                    vec![RespValue::Error(StorageError::InvalidDbIndex.to_string())]
+                }
+            }
+            Ok(Command::Unwatch) => {
+                self.transaction.unwatch();
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            Ok(command)
+                if command.class() == CommandClass::Write && self.replication.is_replica() =>
+            {
+                self.transaction.abort();
+                vec![RespValue::Error(
+                    "READONLY You can't write against a read only replica.".to_string(),
+                )]
+            }
+            // Besides writes, a snapshot can't be saved or follow a primary.
+            Ok(command)
+                if self.db.is_read_only()
+                    && (command.class() == CommandClass::Write
+                        || matches!(
+                            command,
+                            Command::Save
+                                | Command::BgSave
+                                | Command::BgRewriteAof
+                                | Command::ReplicaOf(_)
+                        )) =>
+            {
+                self.transaction.abort();
+                vec![RespValue::Error(
+                    "READONLY You can't write against a read only snapshot.".to_string(),
+                )]
+            }
+            Ok(command) if self.transaction.is_active() => {
+                if command.allowed_in_transaction() {
+                    self.transaction.queue(command);
+                    vec![RespValue::SimpleString("QUEUED".to_string())]
+                } else {
+                    self.transaction.abort();
+                    vec![RespValue::Error(format!(
+                        "ERR Command '{}' not allowed inside a transaction",
+                        command.name()
+                    ))]
+                }
+            }
+            Ok(Command::Subscribe(channels)) => self.subscriber.subscribe(channels),
+            Ok(Command::Unsubscribe(channels)) => self.subscriber.unsubscribe(channels),
+            Ok(Command::PSubscribe(patterns)) => self.subscriber.psubscribe(patterns),
+            Ok(Command::PUnsubscribe(patterns)) => self.subscriber.punsubscribe(patterns),
+            Ok(Command::ReplicaOf(primary)) => {
+                self.replication.replicate(primary, self.db.clone());
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            // Redis replies to a successful `SHUTDOWN` by closing the
+            // connection, which draining does.
+            Ok(Command::Shutdown(request)) => {
+                self.clients.request_shutdown(request);
+                vec![]
+            }
+            Ok(Command::ReplConf(args)) => self.replconf(args),
+            Ok(command @ (Command::AclList | Command::AclWhoAmI)) => vec![self.acl_reply(&command)],
+            Ok(
+                command @ (Command::ClusterSlots
+                | Command::ClusterShards
+                | Command::ClusterKeySlot(_)),
+            ) => vec![self.cluster_reply(&command)],
+            Ok(Command::ClientReply(mode)) => {
+                self.ctx.reply_mode = mode;
+                match mode {
+                    ReplyMode::On => vec![RespValue::SimpleString("OK".to_string())],
+                    ReplyMode::Off | ReplyMode::Skip => vec![],
+                }
+            }
+            Ok(Command::Psync) => self.sync_replica().await,
+            // Walks the keyspace a page at a time, so it can't run under
+            // `execute`, which holds its locks throughout.
+            Ok(Command::DebugBigKeys) => {
+                vec![
+                    match big_keys(&self.db, self.db.namespace(self.ctx.db).storage_db()).await {
+                        Ok(found) => RespValue::bulk(found.report()),
+                        Err(e) => RespValue::Error(e.to_string()),
+                    },
+                ]
+            }
+            // Read without locking, so it answers while the shards are
+            // contended.
+            Ok(Command::DebugLockStats) => vec![RespValue::bulk(self.db.lock_stats().report())],
+            Ok(Command::Monitor) => {
+                self.monitor = Some(self.db.monitor().watch());
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            Ok(Command::CdcTail(pattern)) => {
+                self.tail = Some(self.db.changefeed().tail(pattern));
+                vec![RespValue::SimpleString("OK".to_string())]
+            }
+            Ok(Command::Publish(channel, message)) => {
+                vec![RespValue::Integer(
+                    self.broker.publish(channel, message) as i64
+                )]
+            }
+            Ok(Command::PubSubHistory(channel, count)) => {
+                let messages = self.broker.history(&channel, count);
+                vec![RespValue::Array(
+                    messages.into_iter().map(RespValue::bulk).collect(),
+                )]
+            }
+            // In RESP2 subscribe mode PING answers with a push-style array.
+            Ok(Command::Ping(message))
+                if self.subscriber.is_active() && self.writer.protocol() == Protocol::Resp2 =>
+            {
+                vec![RespValue::Array(vec![
+                    RespValue::bulk("pong"),
+                    RespValue::bulk(message.unwrap_or_default()),
+                ])]
+            }
+            Ok(command @ (Command::BLPop(..) | Command::BRPop(..))) => {
+                match self.blocking_pop(command).await {
+                    Some(reply) => vec![reply],
+                    None => {
+                        quit = true;
+                        vec![]
+                    }
+                }
+            }
+            Ok(command) => {
+                let limit = self.config.command_timeouts.limit_for(command.class());
+                let deadline = Deadline::after(limit);
+                vec![execute(command, &self.db, &self.ctx, &self.clients, deadline).await]
+            }
+            Err(e) => {
+                self.transaction.abort();
+                vec![RespValue::Error(e.to_string())]
+            }
+        };
+        if let Some(name) = name {
+            let elapsed = started.elapsed();
+            let stats = self.db.stats();
+            stats.record_command(name, elapsed);
+            stats.latency().record("command", elapsed);
+            if let Some(user) = &self.ctx.user {
+                stats.users().record(&user.name, elapsed);
+            }
+            if stats.slowlog().is_slow(elapsed) {
+                stats.slowlog().record(
+                    elapsed,
+                    &args,
+                    self.client
+                        .addr()
+                        .map_or_else(String::new, |addr| addr.to_string()),
+                    self.client.name().unwrap_or_default(),
+                );
+            }
+        }
+
+        self.client.set_subscribed(self.subscriber.is_active());
+        if silenced {
+            return quit;
+        }
+        for resp in replies {
+            debug!("Sending response: {:?}", resp);
+            self.writer.push(&resp);
+        }
+        quit
+    }
+
+    /// `BLPOP` and `BRPOP`: pops off the first of the keys holding a list,
+    /// waiting for a push to one of them while they're all empty, for up to
+    /// the timeout or forever if it's zero. `None` if the client went away
+    /// meanwhile, and the connection should be closed.
+    async fn blocking_pop(&mut self, command: Command) -> Option<RespValue> {
+        let (keys, end, timeout) = match command {
+            Command::BLPop(keys, timeout) => (keys, End::Left, timeout),
+            Command::BRPop(keys, timeout) => (keys, End::Right, timeout),
+            _ => unreachable!("not a blocking pop"),
+        };
+        // Registered before the first try, so no push can slip in between.
+        let db = self.db.clone();
+        let namespace = db.namespace(self.ctx.db);
+        let waiter = db
+            .waiters()
+            .register(namespace.storage_db(), &namespace.keys(&keys));
+        let until = (!timeout.is_zero()).then(|| tokio::time::Instant::now() + timeout);
+        self.client.set_blocked(true, until.is_some());
+        let reply = loop {
+            let command = match end {
+                End::Left => Command::BLPop(keys.clone(), timeout),
+                End::Right => Command::BRPop(keys.clone(), timeout),
+            };
+            let limit = self.config.command_timeouts.limit_for(command.class());
+            let deadline = Deadline::after(limit);
+            let reply = execute(command, &db, &self.ctx, &self.clients, deadline).await;
+            if reply != RespValue::NullArray {
+                break Some(reply);
+            }
+            // Input arriving meanwhile is left buffered for after the pop;
+            // only a disconnect ends the wait early.
+            let watch_input = self.reader.buffered() == 0;
+            tokio::select! {
+                _ = waiter.woken() => {}
+                _ = sleep_until(until) => break Some(RespValue::NullArray),
+                _ = self.client.killed() => {
+                    if self.client.is_evicted() {
+                        info!("Client {} evicted: client buffers over the limit", self.client.id());
+                    } else {
+                        info!("Client {} killed", self.client.id());
+                    }
+                    break None;
+                }
+                read = self.reader.fill(), if watch_input => match read {
+                    Ok(0) | Err(_) => break None,
+                    Ok(_) => {}
+                },
+            }
+        };
+        self.client.set_blocked(false, false);
+        reply
+    }
+
+    /// Runs the queued transaction with every shard locked, unless a watched
+    /// key changed, in which case nothing runs and the reply is nil. Errors
+    /// of individual commands are replied in place and don't stop the rest;
+    /// only a command rejected while queueing discards the whole transaction.
+    async fn exec(&mut self) -> RespValue {
+        if self.transaction.is_aborted() {
+            self.transaction.finish();
+            return RespValue::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            );
+        }
+        let db = self.db.clone();
+        let mut store = db.lock_all().await;
+        let dirty = self.transaction.is_dirty(&store);
+        let Some(commands) = self.transaction.finish() else {
+            return RespValue::Error("ERR EXEC without MULTI".to_string());
+        };
+        if dirty {
+            return RespValue::NullArray;
+        }
+
+        let prefixed = self.db.namespace(self.ctx.db).is_prefixed();
+        store
+            .select(self.db.namespace(self.ctx.db).storage_db())
+            .expect("the selected database exists");
+        let replies = commands
+            .into_iter()
+            .map(|command| match command {
+                // Prefixed databases all live in database 0: switching is
+                // up to the connection.
+                Command::Select(index) if prefixed => {
+                    if index < self.db.databases() {
+                        self.ctx.db = index;
+                        RespValue::SimpleString("OK".to_string())
+                    } else {
+                        RespValue::Error(StorageError::InvalidDbIndex.to_string())
+                    }
+                }
+                Command::Publish(channel, message) => {
+                    RespValue::Integer(self.broker.publish(channel, message) as i64)
+                }
+                Command::PubSubHistory(channel, count) => {
+                    let messages = self.broker.history(&channel, count);
+                    RespValue::Array(messages.into_iter().map(RespValue::bulk).collect())
+                }
+                Command::AclList | Command::AclWhoAmI => self.acl_reply(&command),
+                Command::ClusterSlots | Command::ClusterShards | Command::ClusterKeySlot(_) => {
+                    self.cluster_reply(&command)
+                }
+                command => {
+                    let limit = self.config.command_timeouts.limit_for(command.class());
+                    let deadline = Deadline::after(limit);
+                    let namespace = self.db.namespace(self.ctx.db);
+                    execute_locked_in(
+                        namespace,
+                        command,
+                        &mut store,
+                        &self.ctx,
+                        &self.clients,
+                        deadline,
+                    )
+                }
+            })
+            .collect();
+        // A `SELECT` in the transaction sticks after it.
+        if !prefixed {
+            self.ctx.db = store.selected();
+        }
+        RespValue::Array(replies)
+    }
+
+    /// `REPLCONF` from a replica. Offset acknowledgements get no reply;
+    /// options other than the announced port are accepted and ignored.
+    fn replconf(&mut self, args: Vec<Bytes>) -> Vec<RespValue> {
+        let option = args.first().map(|arg| arg.to_ascii_lowercase());
+        let value = args.get(1).and_then(|arg| std::str::from_utf8(arg).ok());
+        match (option.as_deref(), value) {
+            (Some(b"ack"), Some(offset)) => {
+                if let (Some(feed), Ok(offset)) = (&self.feed, offset.parse()) {
+                    self.replication.ack(feed.id, offset);
+                }
+                vec![]
+            }
+            (Some(b"listening-port"), Some(port)) => match port.parse() {
+                Ok(port) => {
+                    self.replica_port = Some(port);
+                    vec![RespValue::SimpleString("OK".to_string())]
+                }
+                Err(_) => vec![RespValue::Error("ERR invalid port".to_string())],
+            },
+            _ => vec![RespValue::SimpleString("OK".to_string())],
+        }
+    }
+
+    /// Serves `PSYNC`: sends the replica a snapshot of the dataset, after
+    /// which the connection streams it every write made since.
+    async fn sync_replica(&mut self) -> Vec<RespValue> {
+        let ip = self
+            .addr
+            .map_or_else(|| "?".to_string(), |addr| addr.ip().to_string());
+        let port = self.replica_port.unwrap_or(0);
+        let (feed, replid, offset, snapshot) = {
+            let mut store = self.db.lock_all().await;
+            let (feed, replid, offset) = self.replication.register(ip.clone(), port);
+            (feed, replid, offset, store.begin_snapshot())
+        };
+        let scripts = snapshot.scripts();
+        let snapshot = snapshot.collect().await;
+        let keys: usize = snapshot.iter().map(Vec::len).sum();
+        let payload = tokio::task::spawn_blocking(move || {
+            let mut out = Vec::new();
+            let databases = snapshot.iter().map(|db| db.iter().map(|(k, v)| (k, v)));
+            rdb::write(&mut out, &scripts, databases).map(|()| out)
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result.map_err(|e| e.to_string()));
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) => return vec![RespValue::Error(format!("ERR snapshot failed: {}", e))],
+        };
+
+        self.writer.push(&RespValue::SimpleString(format!(
+            "FULLRESYNC {} {}",
+            replid, offset
+        )));
+        self.writer
+            .push_bytes(format!("${}\r\n", payload.len()).into());
+        self.writer.push_bytes(payload.into());
+        self.replication.mark_online(feed.id);
+        self.feed = Some(feed);
+        self.client.set_no_evict(true);
+        info!("Sending {} keys to replica {}:{}", keys, ip, port);
+        vec![]
+    }
+
+    /// Returns the connection to its initial state: no transaction, watches,
+    /// subscriptions or change feed, replies on, and authenticated only if
+    /// the default user needs no password.
+    fn reset(&mut self) {
+        self.transaction.finish();
+        self.subscriber.reset();
+        self.tail = None;
+        self.monitor = None;
+        self.writer.set_protocol(Protocol::Resp2);
+        let blocklist = self.ctx.blocklist.clone();
+        self.ctx = ConnCtx::new(self.client.id(), self.acl.initial_user());
+        self.ctx.blocklist = blocklist;
+    }
+
+    async fn authenticate(&mut self, user: Option<String>, password: Secret) -> RespValue {
+        if user.is_none() && !self.acl.requires_auth() && !self.acl.has_backend() {
+            return RespValue::Error(
+                "ERR AUTH <password> called without any password configured for the default user. \
+                 Are you sure your configuration is correct?"
+                    .to_string(),
+            );
+        }
+
+        let user = user.as_deref().unwrap_or(DEFAULT_USER);
+        match self.acl.authenticate(user, password.expose()).await {
+            Some(user) => {
+                self.ctx.user = Some(user);
+                RespValue::SimpleString("OK".to_string())
+            }
+            None => RespValue::Error(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+            ),
+        }
+    }
+
+    /// `HELLO`: optionally authenticates, switches to the requested protocol
+    /// and describes the server.
+    async fn hello(&mut self, protover: Option<i64>, auth: Option<(String, Secret)>) -> RespValue {
+        let protocol = match protover {
+            None => self.writer.protocol(),
+            Some(2) => Protocol::Resp2,
+            Some(3) => Protocol::Resp3,
+            Some(_) => return RespValue::Error("NOPROTO unsupported protocol version".to_string()),
+        };
+        if let Some((user, password)) = auth {
+            let reply = self.authenticate(Some(user), password).await;
+            if matches!(reply, RespValue::Error(_)) {
+                return reply;
+            }
+        }
+        if self.ctx.user.is_none() {
+            return RespValue::Error(
+                "NOAUTH HELLO must be called with the client already authenticated, \
+                 otherwise the HELLO AUTH <user> <pass> option can be used to \
+                 authenticate the client and select the RESP protocol version at the same time"
+                    .to_string(),
+            );
+        }
+        self.writer.set_protocol(protocol);
+        self.ctx.protocol = protocol;
+        let role = if self.replication.is_replica() {
+            "replica"
+        } else {
+            "master"
+        };
+        let proto = match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let field = |name: &'static str, value| (RespValue::bulk(name), value);
+        RespValue::Map(vec![
+            field("server", RespValue::bulk("rdb")),
+            field("version", RespValue::bulk(build_info::VERSION)),
+            field("proto", RespValue::Integer(proto)),
+            field("id", RespValue::Integer(self.client.id() as i64)),
+            field("mode", RespValue::bulk("standalone")),
+            field("role", RespValue::bulk(role)),
+            field("modules", RespValue::Array(vec![])),
+        ])
+    }
+
+    /// Whether the authenticated user may run `command` on its keys.
+    fn permits(&self, command: &Command) -> bool {
+        self.ctx.user.as_ref().is_some_and(|user| {
+            user.can_run(command.class()) && command.keys().iter().all(|key| user.can_access(key))
+        })
+    }
+
+    fn permission_error(&self, command: &Command) -> RespValue {
+        let Some(user) = &self.ctx.user else {
+            return RespValue::Error("NOAUTH Authentication required.".to_string());
+        };
+        if user.can_run(command.class()) {
+            RespValue::Error("NOPERM No permissions to access a key".to_string())
+        } else {
+            RespValue::Error(format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user.name,
+                command.name()
+            ))
+        }
+    }
+
+    fn acl_reply(&self, command: &Command) -> RespValue {
+        match command {
+            Command::AclWhoAmI => match &self.ctx.user {
+                Some(user) => RespValue::bulk(user.name.clone()),
+                None => RespValue::Error("NOAUTH Authentication required.".to_string()),
+            },
+            _ => RespValue::Array(self.acl.list().into_iter().map(RespValue::bulk).collect()),
+        }
+    }
+
+    fn cluster_reply(&self, command: &Command) -> RespValue {
+        let Some(cluster) = &self.cluster else {
+            return RespValue::Error("ERR This instance has cluster support disabled".to_string());
+        };
+        match command {
+            Command::ClusterSlots => cluster.slots_reply(),
+            Command::ClusterShards => cluster.shards_reply(),
+            Command::ClusterKeySlot(key) => RespValue::Integer(key_slot(key).into()),
+            _ => unreachable!("not a cluster command"),
+        }
+    }
+}
+
+/// The next change for a tailing client; never resolves otherwise.
+async fn next_change(tail: &mut Option<Tail>) -> Result<Change, u64> {
+    match tail {
+        Some(tail) => tail.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next command for a monitoring client; never resolves otherwise.
+async fn next_monitored(monitor: &mut Option<Watcher>) -> Result<MonitoredCommand, u64> {
+    match monitor {
+        Some(monitor) => monitor.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves at `until`; never if there's no limit.
+async fn sleep_until(until: Option<tokio::time::Instant>) {
+    match until {
+        Some(until) => tokio::time::sleep_until(until).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The next frame for a replica being served; never resolves otherwise.
+async fn next_feed_frame(feed: &mut Option<ReplicaFeed>) -> Option<Bytes> {
+    match feed {
+        Some(feed) => feed.recv().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,241 @@
+//! Incremental RESP frame reader
+use crate::protocol::{parse_inline, parse_resp_limited, Limits, RespError, RespValue};
+use bytes::{Buf, BytesMut};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Accumulates socket input and splits it into complete frames. A read may
+/// carry several pipelined commands or only part of one; whatever isn't a
+/// whole frame yet stays buffered until more bytes arrive.
+///
+/// As in Redis, input not starting with `*` is an inline command, one line
+/// of words, and comes out as the array of them a client library would
+/// have sent.
+///
+/// It also keeps track of how long parsing took, and of how long the
+/// partial frame buffered has been waiting for the rest, so a client
+/// trickling a command in a byte at a time can be told apart from one that's
+/// just slow to start the next.
+pub struct FrameReader<R> {
+    inner: R,
+    buffer: BytesMut,
+    limits: Limits,
+    /// When the partial frame buffered was first found incomplete.
+    partial_since: Option<Instant>,
+    parse_time: Duration,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R, capacity: usize, limits: Limits) -> Self {
+        FrameReader {
+            inner,
+            buffer: BytesMut::with_capacity(capacity),
+            limits,
+            partial_since: None,
+            parse_time: Duration::ZERO,
+        }
+    }
+
+    /// Reads more input into the buffer, returning 0 once the peer closed.
+    /// Cancel safe.
+    pub async fn fill(&mut self) -> std::io::Result<usize> {
+        self.inner.read_buf(&mut self.buffer).await
+    }
+
+    /// Bytes received but not yet consumed as frames.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// How long the partial frame buffered has been waiting for the rest,
+    /// or `None` if there's none.
+    pub fn partial_for(&self) -> Option<Duration> {
+        self.partial_since.map(|since| since.elapsed())
+    }
+
+    /// Time spent parsing input so far.
+    pub fn parse_time(&self) -> Duration {
+        self.parse_time
+    }
+
+    /// Removes and returns the next complete frame, or `None` if the buffer
+    /// only holds a partial one.
+    pub fn next_frame(&mut self) -> Result<Option<RespValue>, RespError> {
+        let started = Instant::now();
+        let frame = self.parse_frame();
+        self.parse_time += started.elapsed();
+        match &frame {
+            Ok(None) if !self.buffer.is_empty() => {
+                self.partial_since.get_or_insert(started);
+            }
+            _ => self.partial_since = None,
+        }
+        frame
+    }
+
+    fn parse_frame(&mut self) -> Result<Option<RespValue>, RespError> {
+        while self.buffer.first().is_some_and(|&c| c != b'*') {
+            match parse_inline(&self.buffer) {
+                // Blank lines are skipped.
+                Ok((words, len)) if words.is_empty() => self.buffer.advance(len),
+                Ok((words, len)) => {
+                    self.buffer.advance(len);
+                    let frame = words.into_iter().map(RespValue::bulk).collect();
+                    return Ok(Some(RespValue::Array(frame)));
+                }
+                Err(RespError::Incomplete) => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+
+        match parse_resp_limited(&self.buffer, &self.limits) {
+            Ok((frame, len)) => {
+                self.buffer.advance(len);
+                Ok(Some(frame))
+            }
+            Err(RespError::Incomplete) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn bulk(s: &'static str) -> RespValue {
+        RespValue::bulk(s)
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_frames() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server, 64, Limits::requests(1024));
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+
+        reader.fill().await.unwrap();
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("PING")]))
+        );
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("GET"), bulk("k")]))
+        );
+        assert_eq!(reader.next_frame().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_frame_split_across_reads() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server, 64, Limits::requests(1024));
+
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$5\r\nhel")
+            .await
+            .unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(reader.next_frame().unwrap(), None);
+
+        client.write_all(b"lo\r\n").await.unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("GET"), bulk("hello")]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_partial_frame_age() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server, 64, Limits::requests(1024));
+        assert_eq!(reader.next_frame().unwrap(), None);
+        assert_eq!(reader.partial_for(), None);
+
+        client.write_all(b"*1\r\n$4\r\nPI").await.unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(reader.next_frame().unwrap(), None);
+        let waiting = reader.partial_for().unwrap();
+
+        // More of the same command doesn't start the clock over.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        client.write_all(b"N").await.unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(reader.next_frame().unwrap(), None);
+        assert!(reader.partial_for().unwrap() >= waiting + Duration::from_millis(5));
+
+        client.write_all(b"G\r\n").await.unwrap();
+        reader.fill().await.unwrap();
+        assert!(reader.next_frame().unwrap().is_some());
+        assert_eq!(reader.partial_for(), None);
+        assert!(reader.parse_time() > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_split_binary_payload() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server, 64, Limits::requests(1024));
+        let frame = b"*1\r\n$4\r\n\xff\r\n\x00\r\n";
+
+        client.write_all(&frame[..10]).await.unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(reader.next_frame().unwrap(), None);
+
+        client.write_all(&frame[10..]).await.unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![RespValue::bulk(
+                &b"\xff\r\n\x00"[..]
+            )]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inline_commands_between_frames() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server, 64, Limits::requests(1024));
+        client
+            .write_all(b"PING\r\n\r\n*1\r\n$4\r\nPING\r\nset k \"a b\"\nGET")
+            .await
+            .unwrap();
+
+        reader.fill().await.unwrap();
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("PING")]))
+        );
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("PING")]))
+        );
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("set"), bulk("k"), bulk("a b")]))
+        );
+        // Inline commands wait for their line ending.
+        assert_eq!(reader.next_frame().unwrap(), None);
+        client.write_all(b" k\r\n").await.unwrap();
+        reader.fill().await.unwrap();
+        assert_eq!(
+            reader.next_frame().unwrap(),
+            Some(RespValue::Array(vec![bulk("GET"), bulk("k")]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frame() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server, 64, Limits::requests(1024));
+        client.write_all(b"*1\r\n?garbage\r\n").await.unwrap();
+        reader.fill().await.unwrap();
+        assert!(reader.next_frame().is_err());
+    }
+}
@@ -0,0 +1,490 @@
+//! Per-client gauges aggregated for `INFO clients`, and the registry behind
+//! `CLIENT LIST`, `CLIENT KILL`, `maxmemory-clients` and draining clients on
+//! shutdown
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Live gauges for one connection. The connection updates them with plain
+/// atomic stores as it runs; `INFO` reads them without stopping anyone.
+pub struct ClientGauges {
+    addr: Option<SocketAddr>,
+    connected: Instant,
+    /// Milliseconds after `connected` the last command arrived.
+    last_command_at: AtomicU64,
+    last_command: Mutex<&'static str>,
+    db: AtomicUsize,
+    name: Mutex<Option<String>>,
+    kill: Notify,
+    input_buffer: AtomicUsize,
+    output_buffer: AtomicUsize,
+    /// Microseconds spent parsing the client's input.
+    parse_usec: AtomicU64,
+    subscribed: AtomicBool,
+    /// Replicas are never evicted for their buffers.
+    no_evict: AtomicBool,
+    /// Picked for eviction and on its way out.
+    evicted: AtomicBool,
+    /// Waiting in a blocking pop.
+    blocked: AtomicBool,
+    blocked_with_timeout: AtomicBool,
+    // Set by client-side caching once it exists.
+    tracking: AtomicBool,
+}
+
+impl ClientGauges {
+    pub fn set_subscribed(&self, subscribed: bool) {
+        self.subscribed.store(subscribed, Ordering::Relaxed);
+    }
+
+    /// Records a command named `name` run on database `db`.
+    pub fn record_command(&self, name: &'static str, db: usize) {
+        let at = self.connected.elapsed().as_millis() as u64;
+        self.last_command_at.store(at, Ordering::Relaxed);
+        *self.last_command.lock().unwrap() = name;
+        self.db.store(db, Ordering::Relaxed);
+    }
+
+    pub fn set_parse_time(&self, parsing: Duration) {
+        self.parse_usec
+            .store(parsing.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Marks the client as waiting in a blocking pop, or done waiting.
+    pub fn set_blocked(&self, blocked: bool, with_timeout: bool) {
+        self.blocked.store(blocked, Ordering::Relaxed);
+        self.blocked_with_timeout
+            .store(blocked && with_timeout, Ordering::Relaxed);
+    }
+
+    pub fn set_no_evict(&self, no_evict: bool) {
+        self.no_evict.store(no_evict, Ordering::Relaxed);
+    }
+
+    /// Whether the client is being disconnected for its buffers.
+    pub fn is_evicted(&self) -> bool {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Bytes held in the client's buffers.
+    fn buffers(&self) -> usize {
+        self.input_buffer.load(Ordering::Relaxed) + self.output_buffer.load(Ordering::Relaxed)
+    }
+
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.name.lock().unwrap().clone()
+    }
+
+    pub fn set_name(&self, name: Option<String>) {
+        *self.name.lock().unwrap() = name;
+    }
+
+    /// Resolves once `CLIENT KILL` picked the client; it should then
+    /// disconnect.
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    /// The client's line in `CLIENT LIST`.
+    fn describe(&self, id: u64) -> String {
+        let age = self.connected.elapsed();
+        let idle = age.as_millis() as u64 - self.last_command_at.load(Ordering::Relaxed);
+        format!(
+            "id={} addr={} name={} age={} idle={} db={} sub={} qbuf={} omem={} parse-usec={} cmd={}",
+            id,
+            self.addr.map_or_else(String::new, |addr| addr.to_string()),
+            self.name().unwrap_or_default(),
+            age.as_secs(),
+            idle / 1000,
+            self.db.load(Ordering::Relaxed),
+            self.subscribed.load(Ordering::Relaxed) as u8,
+            self.input_buffer.load(Ordering::Relaxed),
+            self.output_buffer.load(Ordering::Relaxed),
+            self.parse_usec.load(Ordering::Relaxed),
+            self.last_command.lock().unwrap(),
+        )
+    }
+}
+
+/// Which clients `CLIENT KILL` disconnects: those matching every filter
+/// given.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KillFilter {
+    pub id: Option<u64>,
+    pub addr: Option<String>,
+    /// Whether the client running the command is spared.
+    pub skip_me: bool,
+}
+
+/// What `SHUTDOWN` asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShutdownRequest {
+    /// `SAVE` or `NOSAVE`; `None` saves as configured.
+    pub save: Option<bool>,
+    /// `NOW`: close clients without waiting for them to finish.
+    pub now: bool,
+}
+
+/// Aggregated view over every connected client.
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientsInfo {
+    pub connected_clients: usize,
+    pub blocked_clients: usize,
+    pub tracking_clients: usize,
+    pub pubsub_clients: usize,
+    pub clients_in_timeout_table: usize,
+    pub max_input_buffer: usize,
+    pub max_output_buffer: usize,
+    /// Bytes in the buffers of every client together.
+    pub buffer_memory: usize,
+    pub evicted_clients: u64,
+}
+
+impl ClientsInfo {
+    /// Formats the `# Clients` section of an `INFO` reply.
+    pub fn to_info_section(&self) -> String {
+        format!(
+            "# Clients\r\n\
+            connected_clients:{}\r\n\
+            blocked_clients:{}\r\n\
+            tracking_clients:{}\r\n\
+            pubsub_clients:{}\r\n\
+            clients_in_timeout_table:{}\r\n\
+            client_recent_max_input_buffer:{}\r\n\
+            client_recent_max_output_buffer:{}\r\n\
+            mem_clients_normal:{}\r\n\
+            evicted_clients:{}\r\n",
+            self.connected_clients,
+            self.blocked_clients,
+            self.tracking_clients,
+            self.pubsub_clients,
+            self.clients_in_timeout_table,
+            self.max_input_buffer,
+            self.max_output_buffer,
+            self.buffer_memory,
+            self.evicted_clients,
+        )
+    }
+}
+
+/// Registry of connected clients, shared by all connections.
+#[derive(Default)]
+pub struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, Arc<ClientGauges>>>,
+    /// `maxmemory-clients`: the most the buffers of all clients together
+    /// may hold; 0 for no limit.
+    max_memory: usize,
+    buffer_memory: AtomicUsize,
+    evicted: AtomicU64,
+    /// Set once the server is shutting down: clients are to leave as soon
+    /// as they're between commands.
+    draining: AtomicBool,
+    drain: Notify,
+    /// The `SHUTDOWN` last sent, for the server to act on.
+    shutdown: Mutex<Option<ShutdownRequest>>,
+    shutdown_requested: Notify,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        ClientRegistry::default()
+    }
+
+    /// A registry disconnecting the clients with the largest buffers
+    /// whenever all buffers together exceed `max_memory` bytes.
+    pub fn with_max_memory(max_memory: usize) -> Self {
+        ClientRegistry {
+            max_memory,
+            ..ClientRegistry::default()
+        }
+    }
+
+    /// Adds a client connected from `addr`. It stays registered until the
+    /// handle is dropped. IDs start at 1 and are never reused.
+    pub fn register(self: &Arc<Self>, addr: Option<SocketAddr>) -> ClientHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let gauges = Arc::new(ClientGauges {
+            addr,
+            connected: Instant::now(),
+            last_command_at: AtomicU64::new(0),
+            last_command: Mutex::new("NULL"),
+            db: AtomicUsize::new(0),
+            name: Mutex::new(None),
+            kill: Notify::new(),
+            input_buffer: AtomicUsize::new(0),
+            parse_usec: AtomicU64::new(0),
+            output_buffer: AtomicUsize::new(0),
+            subscribed: AtomicBool::new(false),
+            no_evict: AtomicBool::new(false),
+            evicted: AtomicBool::new(false),
+            blocked: AtomicBool::new(false),
+            blocked_with_timeout: AtomicBool::new(false),
+            tracking: AtomicBool::new(false),
+        });
+        self.clients.lock().unwrap().insert(id, gauges.clone());
+        ClientHandle {
+            registry: self.clone(),
+            id,
+            gauges,
+        }
+    }
+
+    /// The name of the client with ID `id`, if it's connected and named.
+    pub fn name(&self, id: u64) -> Option<String> {
+        self.clients.lock().unwrap().get(&id)?.name()
+    }
+
+    pub fn set_name(&self, id: u64, name: Option<String>) {
+        if let Some(gauges) = self.clients.lock().unwrap().get(&id) {
+            gauges.set_name(name);
+        }
+    }
+
+    /// `CLIENT LIST`: a line per client, in ID order.
+    pub fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut ids: Vec<_> = clients.keys().copied().collect();
+        ids.sort_unstable();
+        ids.iter()
+            .map(|id| clients[id].describe(*id) + "\n")
+            .collect()
+    }
+
+    /// Disconnects the clients matching `filter`, `me` being the one asking,
+    /// and returns how many there were.
+    pub fn kill(&self, filter: &KillFilter, me: u64) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for (id, gauges) in clients.iter() {
+            let addr = gauges.addr.map(|addr| addr.to_string());
+            if filter.id.is_some_and(|wanted| wanted != *id)
+                || filter.addr.is_some() && filter.addr != addr
+                || filter.skip_me && *id == me
+            {
+                continue;
+            }
+            gauges.kill.notify_one();
+            killed += 1;
+        }
+        killed
+    }
+
+    /// Kills the clients with the largest buffers, as many as it takes to
+    /// bring the total back under the limit. Clients that can't be evicted
+    /// don't count towards it, and neither do those already leaving.
+    fn evict(&self) {
+        let clients = self.clients.lock().unwrap();
+        let mut candidates: Vec<_> = clients
+            .values()
+            .filter(|gauges| {
+                !gauges.no_evict.load(Ordering::Relaxed) && !gauges.evicted.load(Ordering::Relaxed)
+            })
+            .collect();
+        let mut total: usize = candidates.iter().map(|gauges| gauges.buffers()).sum();
+        candidates.sort_by_key(|gauges| std::cmp::Reverse(gauges.buffers()));
+        for gauges in candidates {
+            if total <= self.max_memory {
+                break;
+            }
+            total -= gauges.buffers();
+            gauges.evicted.store(true, Ordering::Relaxed);
+            gauges.kill.notify_one();
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Tells every client the server is shutting down; they close as
+    /// soon as what they're running is done.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+        self.drain.notify_waiters();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Asks the server to shut down, as `SHUTDOWN` does.
+    pub fn request_shutdown(&self, request: ShutdownRequest) {
+        *self.shutdown.lock().unwrap() = Some(request);
+        self.shutdown_requested.notify_one();
+    }
+
+    /// Resolves when a client sends `SHUTDOWN`, with what it asked for.
+    pub async fn shutdown_requested(&self) -> ShutdownRequest {
+        self.shutdown_requested.notified().await;
+        self.shutdown.lock().unwrap().take().unwrap_or_default()
+    }
+
+    pub fn info(&self) -> ClientsInfo {
+        let clients = self.clients.lock().unwrap();
+        let mut info = ClientsInfo {
+            connected_clients: clients.len(),
+            buffer_memory: self.buffer_memory.load(Ordering::Relaxed),
+            evicted_clients: self.evicted.load(Ordering::Relaxed),
+            ..ClientsInfo::default()
+        };
+        for gauges in clients.values() {
+            let flag = |flag: &AtomicBool| flag.load(Ordering::Relaxed) as usize;
+            info.blocked_clients += flag(&gauges.blocked);
+            info.tracking_clients += flag(&gauges.tracking);
+            info.pubsub_clients += flag(&gauges.subscribed);
+            info.clients_in_timeout_table += flag(&gauges.blocked_with_timeout);
+            info.max_input_buffer = info
+                .max_input_buffer
+                .max(gauges.input_buffer.load(Ordering::Relaxed));
+            info.max_output_buffer = info
+                .max_output_buffer
+                .max(gauges.output_buffer.load(Ordering::Relaxed));
+        }
+        info
+    }
+}
+
+/// A connection's registration; unregisters the client when dropped.
+pub struct ClientHandle {
+    registry: Arc<ClientRegistry>,
+    id: u64,
+    gauges: Arc<ClientGauges>,
+}
+
+impl ClientHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Records the bytes waiting in the connection's input and output
+    /// buffers, evicting clients if that takes them all over the limit.
+    pub fn set_buffers(&self, input: usize, output: usize) {
+        let old = self.gauges.input_buffer.swap(input, Ordering::Relaxed)
+            + self.gauges.output_buffer.swap(output, Ordering::Relaxed);
+        let registry = &self.registry;
+        let total = if input + output >= old {
+            registry
+                .buffer_memory
+                .fetch_add(input + output - old, Ordering::Relaxed)
+                + (input + output - old)
+        } else {
+            registry
+                .buffer_memory
+                .fetch_sub(old - input - output, Ordering::Relaxed)
+                - (old - input - output)
+        };
+        if registry.max_memory > 0 && total > registry.max_memory {
+            registry.evict();
+        }
+    }
+
+    /// Resolves once the server starts draining clients, at once if it
+    /// already has.
+    pub async fn draining(&self) {
+        let drain = self.registry.drain.notified();
+        if !self.registry.is_draining() {
+            drain.await;
+        }
+    }
+}
+
+impl std::ops::Deref for ClientHandle {
+    type Target = ClientGauges;
+
+    fn deref(&self) -> &ClientGauges {
+        &self.gauges
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        self.registry
+            .buffer_memory
+            .fetch_sub(self.gauges.buffers(), Ordering::Relaxed);
+        self.registry.clients.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregates_live_clients() {
+        let registry = Arc::new(ClientRegistry::new());
+        let first = registry.register(None);
+        let second = registry.register(None);
+        first.set_buffers(10, 500);
+        second.set_buffers(300, 20);
+        second.set_subscribed(true);
+
+        assert_eq!(
+            registry.info(),
+            ClientsInfo {
+                connected_clients: 2,
+                pubsub_clients: 1,
+                max_input_buffer: 300,
+                max_output_buffer: 500,
+                buffer_memory: 830,
+                ..ClientsInfo::default()
+            }
+        );
+
+        drop(second);
+        let info = registry.info();
+        assert_eq!(info.connected_clients, 1);
+        assert_eq!(info.pubsub_clients, 0);
+        assert_eq!(info.max_input_buffer, 10);
+        assert_eq!(info.buffer_memory, 510);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_largest_buffers() {
+        let registry = Arc::new(ClientRegistry::with_max_memory(1000));
+        let small = registry.register(None);
+        let large = registry.register(None);
+        let replica = registry.register(None);
+        replica.set_no_evict(true);
+        replica.set_buffers(0, 2000);
+        small.set_buffers(100, 0);
+        large.set_buffers(0, 800);
+        assert_eq!(registry.info().evicted_clients, 0);
+
+        large.set_buffers(0, 950);
+        large.killed().await;
+        assert_eq!(registry.info().evicted_clients, 1);
+        small.set_buffers(1200, 0);
+        assert_eq!(registry.info().evicted_clients, 2);
+        small.killed().await;
+    }
+
+    #[tokio::test]
+    async fn test_list_and_kill() {
+        let registry = Arc::new(ClientRegistry::new());
+        let me = registry.register(Some("127.0.0.1:5000".parse().unwrap()));
+        let other = registry.register(Some("127.0.0.1:5001".parse().unwrap()));
+        me.set_name(Some("worker".to_string()));
+        other.record_command("get", 3);
+        let list = registry.list();
+        assert!(list.starts_with("id=1 addr=127.0.0.1:5000 name=worker age=0 idle=0 db=0"));
+        assert!(list.contains("\nid=2 addr=127.0.0.1:5001 name= age=0 idle=0 db=3"));
+        assert!(list.ends_with("cmd=get\n"));
+
+        let everyone_else = KillFilter {
+            skip_me: true,
+            ..KillFilter::default()
+        };
+        assert_eq!(registry.kill(&everyone_else, me.id()), 1);
+        other.killed().await;
+        let by_addr = KillFilter {
+            addr: Some("127.0.0.1:9999".to_string()),
+            ..KillFilter::default()
+        };
+        assert_eq!(registry.kill(&by_addr, me.id()), 0);
+    }
+}
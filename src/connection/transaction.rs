@@ -0,0 +1,137 @@
+//! Per-connection MULTI/EXEC state
+use crate::commands::Command;
+use crate::storage::ShardLocks;
+use bytes::Bytes;
+
+/// Commands queued since `MULTI`, plus the keys `WATCH`ed with their
+/// database and the versions they had at the time.
+#[derive(Default)]
+pub struct Transaction {
+    queue: Option<Vec<Command>>,
+    /// Set when a command was rejected while queueing; `EXEC` then runs
+    /// nothing.
+    aborted: bool,
+    watched: Vec<(usize, Bytes, u64)>,
+}
+
+impl Transaction {
+    /// Whether `MULTI` was called and commands are being queued.
+    pub fn is_active(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    pub fn begin(&mut self) {
+        self.queue = Some(Vec::new());
+        self.aborted = false;
+    }
+
+    pub fn queue(&mut self, command: Command) {
+        if let Some(queue) = &mut self.queue {
+            queue.push(command);
+        }
+    }
+
+    /// Marks the active transaction, if any, as failed because a command
+    /// couldn't be queued.
+    pub fn abort(&mut self) {
+        self.aborted |= self.is_active();
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.aborted
+    }
+
+    /// Ends the transaction, returning its queued commands if one was
+    /// active. Watches end with it.
+    pub fn finish(&mut self) -> Option<Vec<Command>> {
+        self.watched.clear();
+        self.aborted = false;
+        self.queue.take()
+    }
+
+    /// Records the versions of `keys` in the selected database, whose
+    /// shards `store` must hold.
+    pub fn watch(&mut self, store: &ShardLocks, keys: Vec<Bytes>) {
+        let db = store.selected();
+        for key in keys {
+            let version = store.shard(&key).watch_version(&key);
+            self.watched.push((db, key, version));
+        }
+    }
+
+    pub fn unwatch(&mut self) {
+        self.watched.clear();
+    }
+
+    /// Whether any watched key changed since it was watched.
+    pub fn is_dirty(&self, store: &ShardLocks) -> bool {
+        self.watched
+            .iter()
+            .any(|(db, key, version)| store.shard(key).watch_version_in(*db, key) != *version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+
+    #[tokio::test]
+    async fn test_watch_detects_changes() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024,
+            persistence_enabled: false,
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        store.shard_mut(b"k").insert("k".into(), "v".into());
+
+        let mut tx = Transaction::default();
+        tx.watch(&store, vec!["k".into(), "missing".into()]);
+        assert!(!tx.is_dirty(&store));
+
+        // Rewriting a key counts as a change even if the value is the same.
+        store.shard_mut(b"k").insert("k".into(), "v".into());
+        assert!(tx.is_dirty(&store));
+
+        tx.unwatch();
+        tx.watch(&store, vec!["missing".into()]);
+        store
+            .shard_mut(b"missing")
+            .sadd(b"missing", vec!["m".into()])
+            .unwrap();
+        assert!(tx.is_dirty(&store));
+
+        // A key created and deleted again is as changed as one rewritten.
+        tx.unwatch();
+        tx.watch(&store, vec!["absent".into()]);
+        store
+            .shard_mut(b"absent")
+            .insert("absent".into(), "v".into());
+        store.shard_mut(b"absent").del(&["absent".into()]);
+        assert!(tx.is_dirty(&store));
+
+        tx.abort();
+        assert!(!tx.is_aborted());
+        tx.begin();
+        assert!(tx.is_active());
+        tx.abort();
+        assert!(tx.is_aborted());
+        assert_eq!(tx.finish(), Some(vec![]));
+        assert!(!tx.is_aborted());
+        assert!(!tx.is_dirty(&store));
+        assert_eq!(tx.finish(), None);
+
+        // Watches stay on the database selected when they were set.
+        store.select(1).unwrap();
+        tx.watch(&store, vec!["k".into()]);
+        store.select(0).unwrap();
+        store.shard_mut(b"k").insert("k".into(), "v".into());
+        assert!(!tx.is_dirty(&store));
+        store.select(1).unwrap();
+        store.shard_mut(b"k").insert("k".into(), "v".into());
+        store.select(0).unwrap();
+        assert!(tx.is_dirty(&store));
+    }
+}
@@ -0,0 +1,77 @@
+//! Taking turns between pipelining clients
+//!
+//! A connection runs the commands it has buffered one after the other
+//! without waiting on its socket, so a client loading data in deep pipelines
+//! could keep a worker thread for as long as its input lasts, and the
+//! clients queued behind it on that thread would wait the whole time. Each
+//! connection gets a turn instead: after `commands_per_turn` commands, or
+//! `turn_ms` of running them, it sends the replies so far and goes to the
+//! back of the runtime's queue, so every other connection ready to run gets
+//! a turn before it takes another.
+use crate::config::ServerConfig;
+use std::time::{Duration, Instant};
+
+/// How much of its input a connection has run since its turn started.
+#[derive(Debug)]
+pub struct Turn {
+    max_commands: usize,
+    max_time: Duration,
+    commands: usize,
+    started: Instant,
+}
+
+impl Turn {
+    pub fn new(config: &ServerConfig) -> Self {
+        Turn {
+            max_commands: config.commands_per_turn,
+            max_time: Duration::from_millis(config.turn_ms),
+            commands: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Starts a new turn.
+    pub fn start(&mut self) {
+        self.commands = 0;
+        self.started = Instant::now();
+    }
+
+    /// Counts a command run, and tells whether the turn is used up. Limits
+    /// of zero don't apply.
+    pub fn record(&mut self) -> bool {
+        self.commands += 1;
+        (self.max_commands > 0 && self.commands >= self.max_commands)
+            || (!self.max_time.is_zero() && self.started.elapsed() >= self.max_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn_ends() {
+        let mut config = ServerConfig {
+            commands_per_turn: 3,
+            turn_ms: 0,
+            ..Default::default()
+        };
+        let mut turn = Turn::new(&config);
+        assert!(!turn.record());
+        assert!(!turn.record());
+        assert!(turn.record());
+        turn.start();
+        assert!(!turn.record());
+
+        config.commands_per_turn = 0;
+        config.turn_ms = 1;
+        let mut turn = Turn::new(&config);
+        assert!(!turn.record());
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(turn.record());
+
+        config.turn_ms = 0;
+        let mut turn = Turn::new(&config);
+        assert!((0..1000).all(|_| !turn.record()));
+    }
+}
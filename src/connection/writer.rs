@@ -0,0 +1,151 @@
+//! Reply queue drained with vectored writes
+use crate::protocol::{Protocol, RespValue};
+use bytes::{Buf, Bytes};
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Most segments handed to a single `write_vectored` call.
+const MAX_IO_SLICES: usize = 64;
+
+/// Queues serialized replies and drains them with vectored writes. Once more
+/// than `high_water` bytes are waiting, `is_saturated` tells the connection
+/// to stop taking new commands until the client catches up, so a slow reader
+/// can't make the server buffer without bound.
+pub struct ReplyWriter<W> {
+    inner: W,
+    queue: VecDeque<Bytes>,
+    queued: usize,
+    high_water: usize,
+    protocol: Protocol,
+}
+
+impl<W: AsyncWrite + Unpin> ReplyWriter<W> {
+    pub fn new(inner: W, high_water: usize) -> Self {
+        ReplyWriter {
+            inner,
+            queue: VecDeque::new(),
+            queued: 0,
+            high_water,
+            protocol: Protocol::default(),
+        }
+    }
+
+    /// Encodes later replies in `protocol`.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn push(&mut self, reply: &RespValue) {
+        self.push_bytes(Bytes::from(reply.serialize_as(self.protocol)));
+    }
+
+    pub fn push_bytes(&mut self, segment: Bytes) {
+        if segment.is_empty() {
+            return;
+        }
+        self.queued += segment.len();
+        self.queue.push_back(segment);
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Bytes queued but not yet written to the socket.
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.queued >= self.high_water
+    }
+
+    /// Writes as much of the queue as the socket takes in one vectored write.
+    /// Cancel safe: if the future is dropped before completing, nothing was
+    /// written.
+    pub async fn write_some(&mut self) -> std::io::Result<()> {
+        let slices: Vec<IoSlice<'_>> = self
+            .queue
+            .iter()
+            .take(MAX_IO_SLICES)
+            .map(|segment| IoSlice::new(segment))
+            .collect();
+        let written = self.inner.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        self.advance(written);
+        Ok(())
+    }
+
+    /// Writes the whole queue and flushes the underlying writer.
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        while self.has_pending() {
+            self.write_some().await?;
+        }
+        self.inner.flush().await
+    }
+
+    fn advance(&mut self, mut written: usize) {
+        self.queued -= written;
+        while written > 0 {
+            let front = self.queue.front_mut().expect("wrote more than was queued");
+            if written >= front.len() {
+                written -= front.len();
+                self.queue.pop_front();
+            } else {
+                front.advance(written);
+                written = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_writes_queued_replies_in_order() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut writer = ReplyWriter::new(server, 1024);
+        writer.push(&RespValue::SimpleString("OK".to_string()));
+        writer.push(&RespValue::Integer(42));
+        writer.push(&RespValue::BulkString(None));
+        writer.flush().await.unwrap();
+        drop(writer);
+
+        let mut received = String::new();
+        let mut client = client;
+        client.read_to_string(&mut received).await.unwrap();
+        assert_eq!(received, "+OK\r\n:42\r\n$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_saturates_at_high_water_mark() {
+        // The pipe only holds 8 bytes, so a client that doesn't read leaves
+        // replies queued on our side.
+        let (mut client, server) = tokio::io::duplex(8);
+        let mut writer = ReplyWriter::new(server, 20);
+        writer.push(&RespValue::bulk("0123456789"));
+        assert!(!writer.is_saturated());
+        writer.push(&RespValue::bulk("0123456789"));
+        assert!(writer.is_saturated());
+
+        // 34 bytes queued, 8 fit into the pipe.
+        writer.write_some().await.unwrap();
+        assert!(writer.is_saturated());
+
+        let mut buf = [0u8; 8];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"$10\r\n012");
+        writer.write_some().await.unwrap();
+        assert!(!writer.is_saturated());
+    }
+}
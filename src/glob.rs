@@ -0,0 +1,132 @@
+//! Redis-style glob matching (`*`, `?`, `[...]` and `\` escapes)
+
+/// Returns true if `text` matches `pattern`. Both are compared byte-wise.
+pub fn matches(pattern: impl AsRef<[u8]>, text: impl AsRef<[u8]>) -> bool {
+    let (pattern, text) = (pattern.as_ref(), text.as_ref());
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*`: (pattern index, text index).
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            Some(b'[') => {
+                if let Some((matched, next)) = match_class(pattern, p, text[t]) {
+                    if matched {
+                        p = next;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+            Some(b'\\') if p + 1 < pattern.len() && pattern[p + 1] == text[t] => {
+                p += 2;
+                t += 1;
+                continue;
+            }
+            // An escape that doesn't match must not fall through to the
+            // literal comparison below.
+            Some(b'\\') if p + 1 < pattern.len() => {}
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // Mismatch: let the last `*` swallow one more byte, or fail.
+        match backtrack {
+            Some((star_p, star_t)) => {
+                backtrack = Some((star_p, star_t + 1));
+                p = star_p + 1;
+                t = star_t + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the class starting at `pattern[start] == b'['`.
+/// Returns whether it matched and the index just past the class, or `None`
+/// if the class is unterminated.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        let first = match pattern.get(i) {
+            Some(b']') => break,
+            Some(b'\\') => {
+                i += 1;
+                *pattern.get(i)?
+            }
+            Some(&b) => b,
+            None => return None,
+        };
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&b| b != b']') {
+            let last = pattern[i + 2];
+            let (lo, hi) = if first <= last {
+                (first, last)
+            } else {
+                (last, first)
+            };
+            matched |= (lo..=hi).contains(&c);
+            i += 3;
+        } else {
+            matched |= first == c;
+            i += 1;
+        }
+    }
+
+    Some((matched != negate, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("news.*", "news.tech"));
+        assert!(!matches("news.*", "sports.tech"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b", "xxaxxbxx"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-c]llo", "hbllo"));
+        assert!(!matches("h[a-c]llo", "hdllo"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(matches("h\\*llo", "h*llo"));
+        assert!(!matches("h\\*llo", "hello"));
+        assert!(matches("[\\]]", "]"));
+    }
+}
@@ -0,0 +1,502 @@
+//! The slow log and the latency monitor, behind `SLOWLOG` and `LATENCY`
+//!
+//! The slow log keeps the most recent commands that took at least
+//! `latency.slowlog_slower_than_us`, with their arguments and client. The
+//! latency monitor keeps, per event, a history of the worst time each
+//! second over `latency.monitor_threshold_ms`: `command` for commands and
+//! `snapshot` for copying a shard into a snapshot, with the shard locked.
+//!
+//! Per user, behind `ACL STATS`, every command's time goes into one-second
+//! histograms kept for the last minute, for its rate and percentiles, as
+//! well as the all-time calls and time.
+use crate::config::LatencyConfig;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Arguments kept per entry; the rest are summed up in the last one.
+const MAX_ARGS: usize = 32;
+
+/// Bytes kept per argument.
+const MAX_ARG_LEN: usize = 128;
+
+/// Samples kept per latency event, as in Redis.
+const HISTORY_LEN: usize = 160;
+
+/// Seconds of each user's commands behind their rate and percentiles.
+const USER_WINDOW_SECS: u64 = 60;
+
+/// Latency buckets per user and second: one per microsecond up to 4, then
+/// four to each doubling, up to about nine minutes.
+const BUCKETS: usize = 112;
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowEntry {
+    pub id: u64,
+    /// Unix time the command finished.
+    pub timestamp: u64,
+    pub usec: u64,
+    pub args: Vec<Bytes>,
+    pub client_addr: String,
+    pub client_name: String,
+}
+
+pub struct SlowLog {
+    /// Negative disables the log; 0 logs every command.
+    slower_than_us: AtomicI64,
+    max_len: AtomicUsize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowEntry>>,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        let config = LatencyConfig::default();
+        SlowLog {
+            slower_than_us: AtomicI64::new(config.slowlog_slower_than_us),
+            max_len: AtomicUsize::new(config.slowlog_max_len),
+            next_id: AtomicU64::new(0),
+            entries: Mutex::default(),
+        }
+    }
+}
+
+impl SlowLog {
+    pub fn configure(&self, slower_than_us: i64, max_len: usize) {
+        self.slower_than_us.store(slower_than_us, Ordering::Relaxed);
+        self.max_len.store(max_len, Ordering::Relaxed);
+    }
+
+    /// Whether a command taking `elapsed` belongs in the log.
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        let slower_than = self.slower_than_us.load(Ordering::Relaxed);
+        slower_than >= 0 && elapsed.as_micros() >= slower_than as u128
+    }
+
+    /// Logs a command that took `elapsed`, dropping the oldest entries over
+    /// the length limit. Long arguments and argument lists are cut short.
+    pub fn record(
+        &self,
+        elapsed: Duration,
+        args: &[Bytes],
+        client_addr: String,
+        client_name: String,
+    ) {
+        let mut kept: Vec<Bytes> = args
+            .iter()
+            .take(if args.len() > MAX_ARGS {
+                MAX_ARGS - 1
+            } else {
+                MAX_ARGS
+            })
+            .map(|arg| {
+                if arg.len() > MAX_ARG_LEN {
+                    let mut cut = arg[..MAX_ARG_LEN].to_vec();
+                    cut.extend_from_slice(
+                        format!("... ({} more bytes)", arg.len() - MAX_ARG_LEN).as_bytes(),
+                    );
+                    cut.into()
+                } else {
+                    arg.clone()
+                }
+            })
+            .collect();
+        if args.len() > MAX_ARGS {
+            kept.push(format!("... ({} more arguments)", args.len() - MAX_ARGS + 1).into());
+        }
+        let entry = SlowEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: unix_time(),
+            usec: elapsed.as_micros() as u64,
+            args: kept,
+            client_addr,
+            client_name,
+        };
+        let max_len = self.max_len.load(Ordering::Relaxed);
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_front(entry);
+        entries.truncate(max_len);
+    }
+
+    /// Up to `count` entries, newest first; all of them for `None`.
+    pub fn get(&self, count: Option<usize>) -> Vec<SlowEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .take(count.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// One latency event's samples, oldest first, as unix time and
+/// milliseconds, and the worst ever seen.
+#[derive(Debug, Default)]
+struct EventHistory {
+    samples: VecDeque<(u64, u64)>,
+    max_ms: u64,
+}
+
+/// The latest and worst sample of one event, for `LATENCY LATEST`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatestEvent {
+    pub event: &'static str,
+    pub timestamp: u64,
+    pub latest_ms: u64,
+    pub max_ms: u64,
+}
+
+pub struct LatencyMonitor {
+    /// 0 leaves the monitor off.
+    threshold_ms: AtomicU64,
+    events: Mutex<HashMap<&'static str, EventHistory>>,
+}
+
+impl Default for LatencyMonitor {
+    fn default() -> Self {
+        LatencyMonitor {
+            threshold_ms: AtomicU64::new(LatencyConfig::default().monitor_threshold_ms),
+            events: Mutex::default(),
+        }
+    }
+}
+
+impl LatencyMonitor {
+    pub fn set_threshold(&self, threshold_ms: u64) {
+        self.threshold_ms.store(threshold_ms, Ordering::Relaxed);
+    }
+
+    /// Samples `event` taking `elapsed`, if that's over the threshold. A
+    /// second's samples collapse into its worst one.
+    pub fn record(&self, event: &'static str, elapsed: Duration) {
+        let threshold = self.threshold_ms.load(Ordering::Relaxed);
+        let ms = elapsed.as_millis() as u64;
+        if threshold == 0 || ms < threshold {
+            return;
+        }
+        let now = unix_time();
+        let mut events = self.events.lock().unwrap();
+        let history = events.entry(event).or_default();
+        history.max_ms = history.max_ms.max(ms);
+        match history.samples.back_mut() {
+            Some((at, worst)) if *at == now => *worst = (*worst).max(ms),
+            _ => {
+                if history.samples.len() == HISTORY_LEN {
+                    history.samples.pop_front();
+                }
+                history.samples.push_back((now, ms));
+            }
+        }
+    }
+
+    /// Every event sampled, by name.
+    pub fn latest(&self) -> Vec<LatestEvent> {
+        let events = self.events.lock().unwrap();
+        let mut latest: Vec<_> = events
+            .iter()
+            .filter_map(|(event, history)| {
+                let (timestamp, latest_ms) = *history.samples.back()?;
+                Some(LatestEvent {
+                    event,
+                    timestamp,
+                    latest_ms,
+                    max_ms: history.max_ms,
+                })
+            })
+            .collect();
+        latest.sort_unstable_by_key(|latest| latest.event);
+        latest
+    }
+
+    /// The samples of `event`, oldest first.
+    pub fn history(&self, event: &str) -> Vec<(u64, u64)> {
+        let events = self.events.lock().unwrap();
+        events
+            .get(event)
+            .map(|history| history.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forgets the named events, or all of them if none are named; returns
+    /// how many were forgotten.
+    pub fn reset(&self, names: &[String]) -> usize {
+        let mut events = self.events.lock().unwrap();
+        let before = events.len();
+        if names.is_empty() {
+            events.clear();
+        } else {
+            events.retain(|event, _| !names.iter().any(|name| name.eq_ignore_ascii_case(event)));
+        }
+        before - events.len()
+    }
+}
+
+/// The bucket holding `usec`; within a quarter of a doubling of the truth.
+fn bucket(usec: u64) -> usize {
+    if usec < 4 {
+        return usec as usize;
+    }
+    let exp = 63 - usec.leading_zeros() as usize;
+    let sub = (usec >> (exp - 2)) as usize & 3;
+    (4 * (exp - 1) + sub).min(BUCKETS - 1)
+}
+
+/// The most microseconds `bucket` holds.
+fn bucket_max(bucket: usize) -> u64 {
+    if bucket < 4 {
+        return bucket as u64;
+    }
+    let exp = bucket / 4 + 1;
+    let sub = (bucket % 4) as u64;
+    ((4 + sub + 1) << (exp - 2)) - 1
+}
+
+/// One user's commands in one second.
+struct Second {
+    at: u64,
+    calls: u64,
+    histogram: [u32; BUCKETS],
+}
+
+#[derive(Default)]
+struct UserHistory {
+    calls: u64,
+    usec: u64,
+    /// Indexed by unix time modulo the window; stale once `at` is out of it.
+    seconds: Vec<Second>,
+}
+
+/// One user's load for `ACL STATS` and the metrics endpoint. Percentiles
+/// are of the last minute's commands, and 0 without any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserStats {
+    pub user: String,
+    pub calls: u64,
+    pub usec: u64,
+    pub ops_per_sec: f64,
+    pub p50_usec: u64,
+    pub p99_usec: u64,
+    pub p999_usec: u64,
+}
+
+/// Commands' calls and latency, by the user that ran them.
+#[derive(Default)]
+pub struct UserLatency {
+    users: Mutex<HashMap<String, UserHistory>>,
+}
+
+impl UserLatency {
+    /// Counts one command of `user`'s, which took `elapsed`.
+    pub fn record(&self, user: &str, elapsed: Duration) {
+        self.record_at(user, elapsed, unix_time());
+    }
+
+    fn record_at(&self, user: &str, elapsed: Duration, now: u64) {
+        let usec = elapsed.as_micros() as u64;
+        let mut users = self.users.lock().unwrap();
+        let history = match users.get_mut(user) {
+            Some(history) => history,
+            None => users.entry(user.to_string()).or_default(),
+        };
+        history.calls += 1;
+        history.usec += usec;
+        if history.seconds.is_empty() {
+            history.seconds = (0..USER_WINDOW_SECS)
+                .map(|_| Second {
+                    at: 0,
+                    calls: 0,
+                    histogram: [0; BUCKETS],
+                })
+                .collect();
+        }
+        let second = &mut history.seconds[(now % USER_WINDOW_SECS) as usize];
+        if second.at != now {
+            second.at = now;
+            second.calls = 0;
+            second.histogram = [0; BUCKETS];
+        }
+        second.calls += 1;
+        second.histogram[bucket(usec)] += 1;
+    }
+
+    /// Every user that has run a command, or just `user`, by name.
+    pub fn report(&self, user: Option<&str>) -> Vec<UserStats> {
+        self.report_at(user, unix_time())
+    }
+
+    fn report_at(&self, user: Option<&str>, now: u64) -> Vec<UserStats> {
+        let users = self.users.lock().unwrap();
+        let mut report: Vec<_> = users
+            .iter()
+            .filter(|(name, _)| user.is_none_or(|user| user == name.as_str()))
+            .map(|(name, history)| {
+                let mut histogram = [0u64; BUCKETS];
+                let mut calls = 0;
+                for second in &history.seconds {
+                    if second.at + USER_WINDOW_SECS > now && second.at <= now {
+                        calls += second.calls;
+                        for (total, count) in histogram.iter_mut().zip(second.histogram) {
+                            *total += count as u64;
+                        }
+                    }
+                }
+                let percentile = |fraction: f64| {
+                    let rank = (calls as f64 * fraction).ceil().max(1.0) as u64;
+                    let mut seen = 0;
+                    for (bucket, count) in histogram.iter().enumerate() {
+                        seen += count;
+                        if seen >= rank {
+                            return bucket_max(bucket);
+                        }
+                    }
+                    0
+                };
+                UserStats {
+                    user: name.clone(),
+                    calls: history.calls,
+                    usec: history.usec,
+                    ops_per_sec: calls as f64 / USER_WINDOW_SECS as f64,
+                    p50_usec: percentile(0.5),
+                    p99_usec: percentile(0.99),
+                    p999_usec: percentile(0.999),
+                }
+            })
+            .collect();
+        report.sort_unstable_by(|a, b| a.user.cmp(&b.user));
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slowlog_trims_and_caps() {
+        let slowlog = SlowLog::default();
+        slowlog.configure(1000, 2);
+        assert!(!slowlog.is_slow(Duration::from_micros(999)));
+        assert!(slowlog.is_slow(Duration::from_micros(1000)));
+
+        let long = Bytes::from(vec![b'x'; 130]);
+        slowlog.record(
+            Duration::from_millis(1),
+            &["GET".into()],
+            String::new(),
+            String::new(),
+        );
+        slowlog.record(
+            Duration::from_millis(2),
+            &["SET".into(), "k".into(), long],
+            "127.0.0.1:5000".into(),
+            "worker".into(),
+        );
+        let many: Vec<Bytes> = (0..40).map(|i| i.to_string().into()).collect();
+        slowlog.record(
+            Duration::from_millis(3),
+            &many,
+            String::new(),
+            String::new(),
+        );
+
+        assert_eq!(slowlog.len(), 2);
+        let entries = slowlog.get(None);
+        assert_eq!(entries[0].id, 2);
+        assert_eq!(entries[0].args.len(), MAX_ARGS);
+        assert_eq!(entries[0].args[31], Bytes::from("... (9 more arguments)"));
+        assert_eq!(entries[1].usec, 2000);
+        assert_eq!(entries[1].client_name, "worker");
+        assert!(entries[1].args[2].ends_with(b"x... (2 more bytes)"));
+        assert_eq!(slowlog.get(Some(1)).len(), 1);
+        slowlog.reset();
+        assert!(slowlog.is_empty());
+    }
+
+    #[test]
+    fn test_latency_keeps_worst_per_second() {
+        let monitor = LatencyMonitor::default();
+        monitor.record("command", Duration::from_millis(200));
+        assert!(monitor.latest().is_empty(), "the monitor is off by default");
+
+        monitor.set_threshold(100);
+        monitor.record("command", Duration::from_millis(50));
+        monitor.record("command", Duration::from_millis(150));
+        monitor.record("command", Duration::from_millis(120));
+        monitor.record("snapshot", Duration::from_millis(300));
+
+        let history = monitor.history("command");
+        assert!(!history.is_empty() && history.len() <= 2);
+        assert_eq!(history.iter().map(|(_, ms)| *ms).max(), Some(150));
+        let latest = monitor.latest();
+        assert_eq!(latest.len(), 2);
+        assert_eq!((latest[0].event, latest[0].max_ms), ("command", 150));
+        assert_eq!(monitor.reset(&["SNAPSHOT".to_string()]), 1);
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.history("command").is_empty());
+    }
+
+    #[test]
+    fn test_buckets_cover_their_values() {
+        for usec in [0, 1, 3, 4, 5, 7, 8, 9, 15, 16, 1000, 123_456, 1 << 28] {
+            let b = bucket(usec);
+            assert!(usec <= bucket_max(b), "{} in {}", usec, b);
+            assert!(b == 0 || usec > bucket_max(b - 1), "{} in {}", usec, b);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_user_latency_window() {
+        let users = UserLatency::default();
+        for _ in 0..98 {
+            users.record_at("app", Duration::from_micros(10), 1000);
+        }
+        users.record_at("app", Duration::from_micros(900), 1001);
+        users.record_at("app", Duration::from_millis(50), 1002);
+        users.record_at("batch", Duration::from_millis(2), 1002);
+
+        let report = users.report_at(Some("app"), 1002);
+        assert_eq!(report.len(), 1);
+        let app = &report[0];
+        assert_eq!(app.calls, 100);
+        assert_eq!(app.usec, 98 * 10 + 900 + 50_000);
+        assert!((app.ops_per_sec - 100.0 / 60.0).abs() < 1e-9);
+        assert_eq!(app.p50_usec, bucket_max(bucket(10)));
+        assert_eq!(app.p99_usec, bucket_max(bucket(900)));
+        assert_eq!(app.p999_usec, bucket_max(bucket(50_000)));
+
+        // A minute on, only the latest second's command is in the window,
+        // but the totals stand.
+        let report = users.report_at(None, 1061);
+        assert_eq!(
+            report.iter().map(|u| u.user.as_str()).collect::<Vec<_>>(),
+            ["app", "batch"]
+        );
+        assert_eq!(report[0].calls, 100);
+        assert_eq!(report[0].p50_usec, bucket_max(bucket(50_000)));
+        assert!((report[0].ops_per_sec - 1.0 / 60.0).abs() < 1e-9);
+        let report = users.report_at(None, 1062);
+        assert_eq!(report[0].ops_per_sec, 0.0);
+        assert_eq!(report[0].p99_usec, 0);
+    }
+}
@@ -0,0 +1,26 @@
+pub mod acl;
+pub mod aof;
+pub mod build_info;
+pub mod changefeed;
+pub mod cluster;
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod glob;
+pub mod latency;
+pub mod memcached;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod monitor;
+pub mod protocol;
+pub mod pubsub;
+pub mod replication;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod server;
+#[cfg(feature = "sql")]
+pub mod sink;
+pub mod stats;
+pub mod storage;
+
+pub use server::{Server, ServerHandle};
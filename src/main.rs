@@ -1,118 +1,73 @@
-mod commands;
-mod config;
-mod protocol;
-mod storage;
-
-use crate::commands::handle_command;
-use crate::config::{load_config, Config};
-use crate::protocol::{parse_resp, RespError};
-use crate::storage::{Db, Storage};
-use bytes::BytesMut;
-use log::{debug, error, info};
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
-
-use tokio::sync::Semaphore;
+use log::info;
+use rdb::build_info;
+use rdb::config::load_config;
+use rdb::replication::ImportSource;
+use rdb::Server;
+use std::path::Path;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logger
     env_logger::init();
+    info!("{}", build_info::banner());
 
     // Load configuration
-    let config = load_config().unwrap_or_default();
+    let config = load_config()?;
     info!("Server configuration:");
     info!("  Listen address: {}", config.server.listen_addr);
     info!("  Max connections: {}", config.server.max_connections);
     info!("  Buffer size: {} bytes", config.server.buffer_size);
     info!("Storage configuration:");
     info!("  Max memory: {} bytes", config.storage.max_memory);
-    info!("  Persistence enabled: {}", config.storage.persistence_enabled);
-
-    // Create a new database and load existing data if persistence is enabled
-    let mut storage = Storage::new(config.storage.clone());
-    if let Err(e) = storage.load_from_disk() {
-        error!("Failed to load data from disk: {}", e);
+    info!(
+        "  Persistence enabled: {}",
+        config.storage.persistence_enabled
+    );
+    info!(
+        "  Append only: {} (fsync {:?})",
+        config.storage.appendonly, config.storage.appendfsync
+    );
+
+    let mut builder = Server::builder().config(config);
+    let import_from = arg_value("--import-from", "a redis:// URL")?;
+    let snapshot = arg_value("--read-only-snapshot", "a dump file")?;
+    match (import_from, snapshot) {
+        (Some(_), Some(_)) => {
+            return Err("--import-from and --read-only-snapshot don't go together".into())
+        }
+        (Some(url), None) => builder = builder.import_from(url.parse::<ImportSource>()?),
+        (None, Some(path)) => builder = builder.read_only_snapshot(Path::new(&path)),
+        (None, None) => {}
     }
-    let db: Db = Arc::new(Mutex::new(storage));
-    info!("Initialized database");
-
-    // Create connection limiter
-    let connection_limit = Arc::new(Semaphore::new(config.server.max_connections));
-    info!("Connection limit set to {}", config.server.max_connections);
-
-    // Bind to configured address
-    let listener = TcpListener::bind(config.server.listen_addr).await?;
-    info!("Server listening on {}", config.server.listen_addr);
-
-    loop {
-        // Wait for a connection slot to become available
-        let permit = connection_limit.clone().acquire_owned().await?;
-        let (socket, addr) = listener.accept().await?;
-        info!("New connection from {}", addr);
-
-        let db = db.clone();
-
-        // Handle each client in a separate task
-        let config = config.clone();
-        tokio::spawn(async move {
-            // The permit is automatically released when dropped
-            let _permit = permit;
+    let server = builder.run().await?;
+    server.shutdown_on(shutdown_signal()).await;
+    Ok(())
+}
 
-            if let Err(e) = process_client(socket, db, &config).await {
-                error!("Error processing client: {}", e);
-            }
-        });
+/// The value of `flag`, as `--flag value` or `--flag=value`, if given:
+/// the Redis of `--import-from redis://host:port` or the dump file of
+/// `--read-only-snapshot dump.rdb`.
+fn arg_value(flag: &str, what: &str) -> Result<Option<String>, String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        return match arg.strip_prefix(flag) {
+            Some("") => args
+                .next()
+                .map(Some)
+                .ok_or_else(|| format!("{} needs {}", flag, what)),
+            Some(value) if value.starts_with('=') => Ok(Some(value[1..].to_string())),
+            _ => continue,
+        };
     }
+    Ok(None)
 }
 
-use std::time::Duration;
-use tokio::time::timeout;
-
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
-
-async fn process_client(
-    socket: TcpStream,
-    db: Db,
-    config: &Config,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer = BytesMut::with_capacity(config.server.buffer_size);
-    let (reader, mut writer) = socket.into_split();
-    let mut reader = BufReader::new(reader);
-
-    loop {
-        // Read command from client with timeout
-        match timeout(CLIENT_TIMEOUT, reader.read_buf(&mut buffer)).await {
-            Ok(Ok(0)) => return Ok(()), // Client disconnected
-            Ok(Ok(_)) => {
-                let command = String::from_utf8_lossy(&buffer);
-                debug!("Received raw input: {}", command.trim());
-
-                // Parse RESP protocol
-                match parse_resp(command.as_ref()) {
-                    Ok((_value, _)) => {
-                        let resp = handle_command(&command, &db).await;
-                        let response = resp.serialize();
-                        debug!("Sending response: {}", response.trim());
-                        writer.write_all(response.as_bytes()).await?;
-                        writer.flush().await?;
-                    }
-                    Err(RespError::Incomplete) => continue, // Need more data
-                    Err(e) => {
-                        let err = format!("-ERR Protocol error: {}\r\n", e);
-                        writer.write_all(err.as_bytes()).await?;
-                        writer.flush().await?;
-                    }
-                }
-
-                buffer.clear();
-            }
-            Ok(Err(e)) => return Err(e.into()),
-            Err(_) => {
-                return Err("Client timeout".into());
-            }
-        }
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
     }
 }
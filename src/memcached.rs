@@ -0,0 +1,818 @@
+//! The memcached text protocol, on a listener of its own
+//!
+//! Legacy memcached clients can use the server during a migration: `get`,
+//! `gets`, `set`, `add`, `replace`, `delete`, `incr` and `decr` act on
+//! string keys in database 0, and writes go through the usual command path,
+//! so they're logged, replicated and reported like any other.
+//!
+//! The protocol has no authentication, so the listener runs its clients as
+//! one ACL user, and checks each request as the commands it runs, against
+//! that user and the listener's blocklist, like a RESP client's.
+//!
+//! Flags and expiry times have no place in the keyspace, so they're kept
+//! here, with the version of the key they were given to. Once the key is
+//! written some other way its version moves on, and they no longer apply.
+use crate::acl::User;
+use crate::build_info;
+use crate::commands::{disabled_refusal, execute_locked, Blocklist, Command};
+use crate::connection::{ClientRegistry, ConnCtx};
+use crate::protocol::RespValue;
+use crate::pubsub::EventClass;
+use crate::replication::Replication;
+use crate::storage::{Db, Deadline, SetOptions, ShardLocks};
+use bytes::Bytes;
+use log::{error, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+
+/// Longest command line accepted, data blocks aside.
+const MAX_LINE: usize = 2048;
+/// Longest key memcached allows.
+const MAX_KEY: usize = 250;
+/// Expiry times up to this many seconds are relative; larger ones are Unix
+/// times, as in memcached.
+const RELATIVE_EXPTIME_LIMIT: i64 = 30 * 24 * 60 * 60;
+
+/// Flags and expiry of a key stored through memcached.
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    flags: u32,
+    expires: Option<Instant>,
+    version: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StoreMode {
+    Set,
+    Add,
+    Replace,
+}
+
+#[derive(Debug, PartialEq)]
+enum Request {
+    Get {
+        keys: Vec<Bytes>,
+        cas: bool,
+    },
+    Store {
+        mode: StoreMode,
+        key: Bytes,
+        flags: u32,
+        exptime: i64,
+        bytes: usize,
+        noreply: bool,
+    },
+    Delete {
+        key: Bytes,
+        noreply: bool,
+    },
+    /// `incr`, or `decr` if `decr` is set.
+    Incr {
+        key: Bytes,
+        amount: u64,
+        decr: bool,
+        noreply: bool,
+    },
+    Version,
+    Quit,
+}
+
+impl Request {
+    fn name(&self) -> &'static str {
+        match self {
+            Request::Get { cas: false, .. } => "get",
+            Request::Get { cas: true, .. } => "gets",
+            Request::Store { mode, .. } => match mode {
+                StoreMode::Set => "set",
+                StoreMode::Add => "add",
+                StoreMode::Replace => "replace",
+            },
+            Request::Delete { .. } => "delete",
+            Request::Incr { decr: false, .. } => "incr",
+            Request::Incr { decr: true, .. } => "decr",
+            Request::Version => "version",
+            Request::Quit => "quit",
+        }
+    }
+
+    fn noreply(&self) -> bool {
+        match self {
+            Request::Store { noreply, .. }
+            | Request::Delete { noreply, .. }
+            | Request::Incr { noreply, .. } => *noreply,
+            Request::Get { .. } | Request::Version | Request::Quit => false,
+        }
+    }
+
+    /// The commands the request runs, as far as permissions go: `incr`
+    /// and `decr` read and set the key.
+    fn commands(&self) -> Vec<Command> {
+        match self {
+            Request::Get { keys, .. } => keys.iter().cloned().map(Command::Get).collect(),
+            Request::Store { key, .. } | Request::Incr { key, .. } => vec![Command::Set(
+                key.clone(),
+                Bytes::new(),
+                SetOptions::default(),
+            )],
+            Request::Delete { key, .. } => vec![Command::Del(vec![key.clone()])],
+            Request::Version | Request::Quit => Vec::new(),
+        }
+    }
+}
+
+/// Parses a command line without its line ending. Errors are replies.
+fn parse_line(line: &[u8]) -> Result<Request, &'static str> {
+    const FORMAT: &str = "CLIENT_ERROR bad command line format";
+    let words: Vec<&[u8]> = line
+        .split(|b| *b == b' ')
+        .filter(|word| !word.is_empty())
+        .collect();
+    let Some((command, args)) = words.split_first() else {
+        return Err("ERROR");
+    };
+    let key = |word: &[u8]| {
+        if word.len() > MAX_KEY || word.iter().any(|b| b.is_ascii_control()) {
+            Err(FORMAT)
+        } else {
+            Ok(Bytes::copy_from_slice(word))
+        }
+    };
+    let number = |word: &[u8]| -> Result<i64, &'static str> {
+        std::str::from_utf8(word)
+            .ok()
+            .and_then(|word| word.parse().ok())
+            .ok_or(FORMAT)
+    };
+    let noreply = |rest: &[&[u8]]| match rest {
+        [] => Ok(false),
+        [word] if *word == b"noreply" => Ok(true),
+        _ => Err(FORMAT),
+    };
+    match *command {
+        b"get" | b"gets" if !args.is_empty() => Ok(Request::Get {
+            keys: args
+                .iter()
+                .map(|word| key(word))
+                .collect::<Result<_, _>>()?,
+            cas: *command == b"gets",
+        }),
+        b"set" | b"add" | b"replace" if (4..=5).contains(&args.len()) => Ok(Request::Store {
+            mode: match *command {
+                b"set" => StoreMode::Set,
+                b"add" => StoreMode::Add,
+                _ => StoreMode::Replace,
+            },
+            key: key(args[0])?,
+            flags: number(args[1])?.try_into().map_err(|_| FORMAT)?,
+            exptime: number(args[2])?,
+            bytes: number(args[3])?.try_into().map_err(|_| FORMAT)?,
+            noreply: noreply(&args[4..])?,
+        }),
+        b"delete" if (1..=2).contains(&args.len()) => Ok(Request::Delete {
+            key: key(args[0])?,
+            noreply: noreply(&args[1..])?,
+        }),
+        b"incr" | b"decr" if (2..=3).contains(&args.len()) => Ok(Request::Incr {
+            key: key(args[0])?,
+            amount: std::str::from_utf8(args[1])
+                .ok()
+                .and_then(|word| word.parse().ok())
+                .ok_or("CLIENT_ERROR invalid numeric delta argument")?,
+            decr: *command == b"decr",
+            noreply: noreply(&args[2..])?,
+        }),
+        b"version" if args.is_empty() => Ok(Request::Version),
+        b"quit" if args.is_empty() => Ok(Request::Quit),
+        b"get" | b"gets" | b"set" | b"add" | b"replace" | b"delete" | b"incr" | b"decr" => {
+            Err(FORMAT)
+        }
+        _ => Err("ERROR"),
+    }
+}
+
+/// When an item given `exptime` expires, or `None` if it doesn't.
+fn expiry(exptime: i64) -> Option<Instant> {
+    let seconds = match exptime {
+        0 => return None,
+        // Negative times expire the item at once.
+        i64::MIN..=-1 => 0,
+        1..=RELATIVE_EXPTIME_LIMIT => exptime as u64,
+        _ => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            (exptime as u64).saturating_sub(now)
+        }
+    };
+    Some(Instant::now() + Duration::from_secs(seconds))
+}
+
+pub struct Memcached {
+    db: Db,
+    clients: Arc<ClientRegistry>,
+    replication: Arc<Replication>,
+    max_item_size: usize,
+    /// Who clients run as; `None` lets them run everything.
+    user: Option<Arc<User>>,
+    blocklist: Blocklist,
+    items: Mutex<HashMap<Bytes, Item>>,
+}
+
+impl Memcached {
+    pub fn new(
+        db: Db,
+        clients: Arc<ClientRegistry>,
+        replication: Arc<Replication>,
+        max_item_size: usize,
+    ) -> Self {
+        Memcached {
+            db,
+            clients,
+            replication,
+            max_item_size,
+            user: None,
+            blocklist: Blocklist::default(),
+            items: Mutex::default(),
+        }
+    }
+
+    /// Runs clients as `user`, with its command and key permissions.
+    pub fn run_as(mut self, user: Arc<User>) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    /// Accepts memcached clients until the server exits. They share the
+    /// connection limit with RESP clients; those over it are turned away.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, limit: Arc<Semaphore>) {
+        loop {
+            let (mut socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept a memcached client: {}", e);
+                    continue;
+                }
+            };
+            let permit = limit.clone().try_acquire_owned();
+            self.db.stats().record_connection(permit.is_ok());
+            let Ok(permit) = permit else {
+                let _ = socket
+                    .write_all(b"SERVER_ERROR too many open connections\r\n")
+                    .await;
+                continue;
+            };
+            info!("New memcached connection from {}", addr);
+            let memcached = self.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                if let Err(e) = memcached.handle(socket, Some(addr)).await {
+                    error!("Error processing memcached client: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Deletes expired items every second, so clients of the other
+    /// protocol don't see them for long either.
+    pub async fn run_expiry(self: Arc<Self>) {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            let now = Instant::now();
+            let expired: Vec<Bytes> = {
+                let items = self.items.lock().unwrap();
+                items
+                    .iter()
+                    .filter(|(_, item)| item.expires.is_some_and(|at| at <= now))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            };
+            for key in expired {
+                let mut store = self.db.lock(std::slice::from_ref(&key)).await;
+                self.item(&mut store, &key);
+            }
+        }
+    }
+
+    async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        socket: S,
+        addr: Option<SocketAddr>,
+    ) -> std::io::Result<()> {
+        let client = self.clients.register(addr);
+        let (reader, mut writer) = tokio::io::split(socket);
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let mut limited = (&mut reader).take(MAX_LINE as u64);
+            let read = tokio::select! {
+                read = limited.read_until(b'\n', &mut line) => read?,
+                _ = client.killed() => return Ok(()),
+            };
+            if read == 0 {
+                return Ok(());
+            }
+            if line.last() != Some(&b'\n') {
+                writer.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+                return Ok(());
+            }
+            let text = line.strip_suffix(b"\n").unwrap_or(&line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            let mut reply = Vec::new();
+            let started = Instant::now();
+            let request = parse_line(text);
+            let name = request.as_ref().ok().map(Request::name);
+            let refusal = request
+                .as_ref()
+                .ok()
+                .and_then(|request| self.refusal(request));
+            if let Some(name) = name {
+                client.record_command(name, 0);
+            }
+            match request {
+                Ok(Request::Quit) => return Ok(()),
+                Ok(Request::Store {
+                    mode,
+                    key,
+                    flags,
+                    exptime,
+                    bytes,
+                    noreply,
+                }) => {
+                    if bytes > self.max_item_size {
+                        // The data can't be told from commands; give up on
+                        // the connection rather than misread it.
+                        writer
+                            .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                            .await?;
+                        return Ok(());
+                    }
+                    let mut data = vec![0; bytes + 2];
+                    reader.read_exact(&mut data).await?;
+                    if !data.ends_with(b"\r\n") {
+                        push_line(&mut reply, b"CLIENT_ERROR bad data chunk");
+                    } else if let Some(refusal) = refusal {
+                        if !noreply {
+                            push_line(&mut reply, refusal.as_bytes());
+                        }
+                    } else {
+                        data.truncate(bytes);
+                        let result = self.store(mode, key, flags, exptime, data.into()).await;
+                        if !noreply {
+                            push_line(&mut reply, result.as_bytes());
+                        }
+                    }
+                }
+                Ok(request) => match refusal {
+                    Some(refusal) if !request.noreply() => {
+                        push_line(&mut reply, refusal.as_bytes())
+                    }
+                    Some(_) => {}
+                    None => self.respond(request, &mut reply).await,
+                },
+                Err(e) => push_line(&mut reply, e.as_bytes()),
+            }
+            if let Some(name) = name {
+                self.db.stats().record_command(name, started.elapsed());
+            }
+            writer.write_all(&reply).await?;
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+        }
+    }
+
+    /// The error `request` gets if the listener's blocklist, the disabled
+    /// command groups or the user's permissions refuse a command it runs.
+    fn refusal(&self, request: &Request) -> Option<String> {
+        let config = self.db.config();
+        let disabled = &config.disabled_command_groups;
+        request.commands().iter().find_map(|command| {
+            let error = if self.blocklist.blocks(command) {
+                Blocklist::refusal(command)
+            } else if let Some(error) = disabled_refusal(disabled, command) {
+                error
+            } else {
+                let user = self.user.as_ref()?;
+                if !user.can_run(command.class()) {
+                    RespValue::Error(format!(
+                        "NOPERM User {} has no permissions to run the '{}' command",
+                        user.name,
+                        command.name()
+                    ))
+                } else if !command.keys().iter().all(|key| user.can_access(key)) {
+                    RespValue::Error("NOPERM No permissions to access a key".to_string())
+                } else {
+                    return None;
+                }
+            };
+            let RespValue::Error(message) = error else {
+                unreachable!("refusals are errors")
+            };
+            Some(format!("CLIENT_ERROR {}", message))
+        })
+    }
+
+    /// Runs the requests that carry no data and appends their reply.
+    async fn respond(&self, request: Request, reply: &mut Vec<u8>) {
+        match request {
+            Request::Get { keys, cas } => {
+                let mut store = self.db.lock(&keys).await;
+                let mut values = Vec::new();
+                for key in &keys {
+                    let Some(item) = self.item(&mut store, key) else {
+                        continue;
+                    };
+                    let shard = store.shard(key);
+                    if let Ok(Some(value)) = shard.get(key) {
+                        values.push((key, item.flags, value.clone(), shard.version(key)));
+                    }
+                }
+                let hits = values.len() as u64;
+                store.stats().record_lookups(hits, keys.len() as u64 - hits);
+                for (key, flags, value, version) in values {
+                    let mut header = format!(
+                        "VALUE {} {} {}",
+                        String::from_utf8_lossy(key),
+                        flags,
+                        value.len()
+                    );
+                    if cas {
+                        header.push_str(&format!(" {}", version.unwrap_or(0)));
+                    }
+                    push_line(reply, header.as_bytes());
+                    push_line(reply, &value);
+                }
+                push_line(reply, b"END");
+            }
+            Request::Delete { key, noreply } => {
+                let result = self.delete(key).await;
+                if !noreply {
+                    push_line(reply, result.as_bytes());
+                }
+            }
+            Request::Incr {
+                key,
+                amount,
+                decr,
+                noreply,
+            } => {
+                let result = self.incr(key, amount, decr).await;
+                if !noreply {
+                    push_line(reply, result.as_bytes());
+                }
+            }
+            Request::Version => {
+                push_line(reply, format!("VERSION {}", build_info::VERSION).as_bytes())
+            }
+            Request::Store { .. } | Request::Quit => unreachable!("handled by the caller"),
+        }
+    }
+
+    /// The item at `key` if it exists, deleting it if it expired. Keys
+    /// stored without memcached have no flags and don't expire.
+    fn item(&self, store: &mut ShardLocks, key: &Bytes) -> Option<Item> {
+        let version = store.shard(key).version(key);
+        let exists = store.shard(key).value(key).is_some();
+        let mut items = self.items.lock().unwrap();
+        let item = match items.get(key) {
+            Some(item) if item.version == version && exists => *item,
+            Some(_) => {
+                items.remove(key);
+                return exists.then_some(Item {
+                    flags: 0,
+                    expires: None,
+                    version,
+                });
+            }
+            None if exists => {
+                return Some(Item {
+                    flags: 0,
+                    expires: None,
+                    version,
+                })
+            }
+            None => return None,
+        };
+        if item.expires.is_some_and(|at| at <= Instant::now()) {
+            items.remove(key);
+            drop(items);
+            // Counted and published once: the item is gone for whoever
+            // looks next.
+            store.stats().record_expired(1);
+            let db = store.selected();
+            store.notify(EventClass::Expired, "expired", db, key);
+            self.execute(store, Command::Del(vec![key.clone()]));
+            return None;
+        }
+        Some(item)
+    }
+
+    async fn store(
+        &self,
+        mode: StoreMode,
+        key: Bytes,
+        flags: u32,
+        exptime: i64,
+        data: Bytes,
+    ) -> &'static str {
+        if let Some(error) = self.read_only() {
+            return error;
+        }
+        let mut store = self.db.lock(std::slice::from_ref(&key)).await;
+        let exists = self.item(&mut store, &key).is_some();
+        if (mode == StoreMode::Add && exists) || (mode == StoreMode::Replace && !exists) {
+            return "NOT_STORED";
+        }
+        let reply = self.execute(
+            &mut store,
+            Command::Set(key.clone(), data, SetOptions::default()),
+        );
+        if matches!(reply, RespValue::Error(_)) {
+            return "SERVER_ERROR out of memory storing object";
+        }
+        self.remember(&store, key, flags, expiry(exptime));
+        "STORED"
+    }
+
+    async fn delete(&self, key: Bytes) -> &'static str {
+        if let Some(error) = self.read_only() {
+            return error;
+        }
+        let mut store = self.db.lock(std::slice::from_ref(&key)).await;
+        if self.item(&mut store, &key).is_none() {
+            return "NOT_FOUND";
+        }
+        self.items.lock().unwrap().remove(&key);
+        self.execute(&mut store, Command::Del(vec![key]));
+        "DELETED"
+    }
+
+    /// Adds or, for `decr`, subtracts `amount`, keeping flags and expiry.
+    /// As in memcached, `incr` wraps around at 64 bits and `decr` stops at
+    /// zero.
+    async fn incr(&self, key: Bytes, amount: u64, decr: bool) -> String {
+        if let Some(error) = self.read_only() {
+            return error.to_string();
+        }
+        let mut store = self.db.lock(std::slice::from_ref(&key)).await;
+        let Some(item) = self.item(&mut store, &key) else {
+            return "NOT_FOUND".to_string();
+        };
+        let current = match store.shard(&key).get(&key) {
+            Ok(Some(value)) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok()),
+            _ => None,
+        };
+        let Some(current) = current else {
+            return "CLIENT_ERROR cannot increment or decrement non-numeric value".to_string();
+        };
+        let value = if decr {
+            current.saturating_sub(amount)
+        } else {
+            current.wrapping_add(amount)
+        };
+        let reply = self.execute(
+            &mut store,
+            Command::Set(key.clone(), value.to_string().into(), SetOptions::default()),
+        );
+        if matches!(reply, RespValue::Error(_)) {
+            return "SERVER_ERROR out of memory".to_string();
+        }
+        self.remember(&store, key, item.flags, item.expires);
+        value.to_string()
+    }
+
+    /// The error writes get, on a replica or a read-only snapshot.
+    fn read_only(&self) -> Option<&'static str> {
+        if self.replication.is_replica() {
+            Some("SERVER_ERROR read only replica")
+        } else if self.db.is_read_only() {
+            Some("SERVER_ERROR read only snapshot")
+        } else {
+            None
+        }
+    }
+
+    fn execute(&self, store: &mut ShardLocks, command: Command) -> RespValue {
+        let conn = ConnCtx::default();
+        execute_locked(command, store, &conn, &self.clients, Deadline::after(None))
+    }
+
+    /// Records the flags and expiry of `key` as just written.
+    fn remember(&self, store: &ShardLocks, key: Bytes, flags: u32, expires: Option<Instant>) {
+        let mut items = self.items.lock().unwrap();
+        if flags == 0 && expires.is_none() {
+            items.remove(&key);
+        } else {
+            let version = store.shard(&key).version(&key);
+            items.insert(
+                key,
+                Item {
+                    flags,
+                    expires,
+                    version,
+                },
+            );
+        }
+    }
+}
+
+/// Appends `text`, a reply line or data block, and its line ending.
+fn push_line(reply: &mut Vec<u8>, text: &[u8]) {
+    reply.extend_from_slice(text);
+    reply.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandClass;
+    use crate::config::{ReplicationConfig, StorageConfig};
+    use crate::storage::Shards;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line(b"set k 5 0 3 noreply"),
+            Ok(Request::Store {
+                mode: StoreMode::Set,
+                key: "k".into(),
+                flags: 5,
+                exptime: 0,
+                bytes: 3,
+                noreply: true,
+            })
+        );
+        assert_eq!(
+            parse_line(b"gets a  b"),
+            Ok(Request::Get {
+                keys: vec!["a".into(), "b".into()],
+                cas: true,
+            })
+        );
+        assert_eq!(
+            parse_line(b"incr n x"),
+            Err("CLIENT_ERROR invalid numeric delta argument")
+        );
+        assert_eq!(
+            parse_line(b"set k 0 0"),
+            Err("CLIENT_ERROR bad command line format")
+        );
+        assert_eq!(parse_line(b"stats"), Err("ERROR"));
+    }
+
+    #[tokio::test]
+    async fn test_expiry_is_counted_once() {
+        let db: Db = Arc::new(Shards::new(StorageConfig {
+            notify_keyspace_events: "Ex".parse().unwrap(),
+            ..Default::default()
+        }));
+        let mut subscriber = crate::pubsub::Subscriber::new(db.broker().clone());
+        subscriber.subscribe(vec!["__keyevent@0__:expired".into()]);
+        let memcached = Arc::new(Memcached::new(
+            db.clone(),
+            Arc::new(ClientRegistry::new()),
+            Arc::new(Replication::new(ReplicationConfig::default(), 0)),
+            1024,
+        ));
+        assert_eq!(
+            memcached
+                .store(StoreMode::Set, "k".into(), 0, -1, "v".into())
+                .await,
+            "STORED"
+        );
+
+        // Every reader finds it gone, but only one deletes it.
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let memcached = memcached.clone();
+                tokio::spawn(async move {
+                    let mut reply = Vec::new();
+                    let get = Request::Get {
+                        keys: vec!["k".into()],
+                        cas: false,
+                    };
+                    memcached.respond(get, &mut reply).await;
+                    reply
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.await.unwrap(), b"END\r\n");
+        }
+        assert_eq!(db.stats().expired_keys(), 1);
+        assert_eq!(
+            subscriber.recv().await,
+            RespValue::Push(vec![
+                RespValue::bulk("message"),
+                RespValue::bulk("__keyevent@0__:expired"),
+                RespValue::bulk("k"),
+            ])
+        );
+        let more = tokio::time::timeout(Duration::from_millis(50), subscriber.recv()).await;
+        assert!(more.is_err(), "expiry published twice");
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let db: Db = Arc::new(Shards::new(StorageConfig {
+            max_memory: 1024,
+            ..Default::default()
+        }));
+        let memcached = Arc::new(Memcached::new(
+            db.clone(),
+            Arc::new(ClientRegistry::new()),
+            Arc::new(Replication::new(ReplicationConfig::default(), 0)),
+            1024,
+        ));
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { memcached.handle(server, None).await });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        writer
+            .write_all(
+                b"set k 7 0 2\r\n10\r\nadd k 0 0 1\r\nx\r\nincr k 5\r\nget k other\r\n\
+                  set gone 0 -1 1\r\nx\r\nget gone\r\ndecr k 100\r\ndelete k\r\n\
+                  delete k\r\nincr k 1\r\nset r 0 0 1\r\nr\r\nquit\r\n",
+            )
+            .await
+            .unwrap();
+        let mut replies = String::new();
+        BufReader::new(reader)
+            .read_to_string(&mut replies)
+            .await
+            .unwrap();
+        assert_eq!(
+            replies,
+            "STORED\r\nNOT_STORED\r\n15\r\nVALUE k 7 2\r\n15\r\nEND\r\n\
+             STORED\r\nEND\r\n0\r\nDELETED\r\nNOT_FOUND\r\nNOT_FOUND\r\nSTORED\r\n"
+        );
+        // Writes land in the keyspace, for RESP clients to see.
+        let store = db.lock_all().await;
+        assert_eq!(store.key_count(), 1);
+        assert_eq!(store.shard(b"r").get(b"r"), Ok(Some(&Bytes::from("r"))));
+    }
+
+    #[tokio::test]
+    async fn test_permissions() {
+        let db: Db = Arc::new(Shards::new(StorageConfig::default()));
+        let user = User {
+            name: "cache".to_string(),
+            passwords: vec![],
+            nopass: false,
+            categories: vec![CommandClass::Read, CommandClass::Write],
+            keys: vec!["cache:*".to_string()],
+        };
+        let memcached = Arc::new(
+            Memcached::new(
+                db.clone(),
+                Arc::new(ClientRegistry::new()),
+                Arc::new(Replication::new(ReplicationConfig::default(), 0)),
+                1024,
+            )
+            .run_as(Arc::new(user))
+            .with_blocklist(Blocklist::parse(&["del".to_string()]).unwrap()),
+        );
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { memcached.handle(server, None).await });
+
+        let (reader, mut writer) = tokio::io::split(client);
+        // The refused set's data is read all the same, not taken for a
+        // command.
+        writer
+            .write_all(
+                b"set cache:a 0 0 1\r\na\r\nset secret 0 0 1\r\ns\r\n\
+                  set secret 0 0 1 noreply\r\ns\r\nget cache:a secret\r\n\
+                  incr secret 1\r\ndelete cache:a\r\nquit\r\n",
+            )
+            .await
+            .unwrap();
+        let mut replies = String::new();
+        BufReader::new(reader)
+            .read_to_string(&mut replies)
+            .await
+            .unwrap();
+        assert_eq!(
+            replies,
+            "STORED\r\nCLIENT_ERROR NOPERM No permissions to access a key\r\n\
+             CLIENT_ERROR NOPERM No permissions to access a key\r\n\
+             CLIENT_ERROR NOPERM No permissions to access a key\r\n\
+             CLIENT_ERROR ERR 'del' is not allowed on this port\r\n"
+        );
+        let store = db.lock_all().await;
+        assert_eq!(store.key_count(), 1);
+    }
+}
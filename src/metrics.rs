@@ -0,0 +1,428 @@
+//! Prometheus metrics over HTTP
+//!
+//! A scrape of `/metrics` gets the counters of [`crate::stats::Stats`], the client gauges
+//! and what the shards report about memory, keys, saves and their locks, in the text
+//! exposition format. The listener speaks just enough HTTP for that: one
+//! `GET` per connection, answered and closed.
+use crate::connection::ClientRegistry;
+use crate::storage::{Db, Histogram, BOUNDS_USEC};
+use log::{debug, error, info};
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Longest request head read before giving up on a client.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a scraper may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers scrapes until the server exits.
+pub async fn serve(listener: TcpListener, db: Db, clients: Arc<ClientRegistry>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept a metrics client: {}", e);
+                continue;
+            }
+        };
+        debug!("Metrics scrape from {}", addr);
+        let db = db.clone();
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(socket, &db, &clients).await {
+                info!("Metrics client {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    db: &Db,
+    clients: &ClientRegistry,
+) -> std::io::Result<()> {
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut socket)).await {
+        Ok(head) => head?,
+        Err(_) => return Ok(()),
+    };
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(db, clients).await),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// Reads up to the blank line ending the request headers.
+async fn read_head<S: AsyncRead + Unpin>(socket: &mut S) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut chunk = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST {
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..read]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+const COUNTER: &str = "counter";
+const GAUGE: &str = "gauge";
+
+/// Appends one metric family: its help, its type and each sample, given
+/// as the labels between the braces and the value.
+fn family(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+/// Appends a histogram family, a series per label set: the buckets, each
+/// counting everything up to its bound, then the sum and the count.
+fn histogram(out: &mut String, name: &str, help: &str, series: &[(String, &Histogram)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (labels, histogram) in series {
+        let counts = histogram.counts();
+        let mut seen = 0;
+        for (bound, count) in BOUNDS_USEC.iter().zip(counts) {
+            seen += count;
+            let le = *bound as f64 / 1e6;
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, seen);
+        }
+        let count: u64 = counts.iter().sum();
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let sum = histogram.total().as_secs_f64();
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Every metric, as of now.
+pub async fn render(db: &Db, clients: &ClientRegistry) -> String {
+    let mut out = String::new();
+    let stats = db.stats();
+    let commands = stats.commands();
+    let calls: Vec<_> = commands
+        .iter()
+        .map(|(name, command)| (format!("cmd=\"{}\"", name), command.calls as f64))
+        .collect();
+    family(
+        &mut out,
+        "rdb_commands_total",
+        COUNTER,
+        "Commands processed, by command.",
+        &calls,
+    );
+    let seconds: Vec<_> = commands
+        .iter()
+        .map(|(name, command)| (format!("cmd=\"{}\"", name), command.usec as f64 / 1e6))
+        .collect();
+    family(
+        &mut out,
+        "rdb_command_duration_seconds_total",
+        COUNTER,
+        "Time spent running each command.",
+        &seconds,
+    );
+
+    let users = stats.users().report(None);
+    let user_calls: Vec<_> = users
+        .iter()
+        .map(|user| (format!("user=\"{}\"", user.user), user.calls as f64))
+        .collect();
+    family(
+        &mut out,
+        "rdb_user_commands_total",
+        COUNTER,
+        "Commands processed, by ACL user.",
+        &user_calls,
+    );
+    let user_latency: Vec<_> = users
+        .iter()
+        .flat_map(|user| {
+            [
+                ("0.5", user.p50_usec),
+                ("0.99", user.p99_usec),
+                ("0.999", user.p999_usec),
+            ]
+            .map(|(quantile, usec)| {
+                (
+                    format!("user=\"{}\",quantile=\"{}\"", user.user, quantile),
+                    usec as f64 / 1e6,
+                )
+            })
+        })
+        .collect();
+    family(
+        &mut out,
+        "rdb_user_command_latency_seconds",
+        GAUGE,
+        "Command latency percentiles over the last minute, by ACL user.",
+        &user_latency,
+    );
+
+    // Taken before the scrape locks the shards itself.
+    let shards = db.lock_stats().shards();
+    let waits: Vec<_> = shards
+        .iter()
+        .enumerate()
+        .map(|(index, shard)| (format!("shard=\"{}\"", index), &shard.wait))
+        .collect();
+    histogram(
+        &mut out,
+        "rdb_shard_lock_wait_seconds",
+        "Time commands waited for each shard's lock.",
+        &waits,
+    );
+    let holds: Vec<_> = shards
+        .iter()
+        .enumerate()
+        .map(|(index, shard)| (format!("shard=\"{}\"", index), &shard.hold))
+        .collect();
+    histogram(
+        &mut out,
+        "rdb_shard_lock_hold_seconds",
+        "Time commands held each shard's lock.",
+        &holds,
+    );
+
+    let info = clients.info();
+    let store = db.lock_all().await;
+    let keys: Vec<_> = store
+        .database_sizes()
+        .into_iter()
+        .enumerate()
+        .filter(|(_, keys)| *keys > 0)
+        .map(|(index, keys)| (format!("db=\"{}\"", index), keys as f64))
+        .collect();
+    family(
+        &mut out,
+        "rdb_keys",
+        GAUGE,
+        "Keys in each database holding any.",
+        &keys,
+    );
+    let saves = store.save_stats();
+    let last_save = saves
+        .last_save
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+
+    let scalars = [
+        (
+            COUNTER,
+            "rdb_connections_received_total",
+            "Clients accepted.",
+            stats.connections_received() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_rejected_connections_total",
+            "Clients turned away over max_connections.",
+            stats.rejected_connections() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_keyspace_hits_total",
+            "Keys found by read commands.",
+            stats.keyspace_hits() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_keyspace_misses_total",
+            "Keys read commands didn't find.",
+            stats.keyspace_misses() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_expired_keys_total",
+            "Keys deleted for expiring.",
+            stats.expired_keys() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_evicted_keys_total",
+            "Keys evicted to stay under maxmemory.",
+            stats.evicted_keys() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_eviction_vetoes_total",
+            "Keys an eviction hook kept from being evicted.",
+            stats.eviction_vetoes() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_pipeline_yields_total",
+            "Turns pipelining clients gave up to others.",
+            stats.pipeline_yields() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_result_cache_hits_total",
+            "Reads answered from the result cache.",
+            stats.result_cache_hits() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_result_cache_misses_total",
+            "Cacheable reads that had to run.",
+            stats.result_cache_misses() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_hot_key_hits_total",
+            "GETs answered from a hot key's copy, without locking.",
+            stats.hot_key_hits() as f64,
+        ),
+        (
+            COUNTER,
+            "rdb_evicted_clients_total",
+            "Clients disconnected for their buffers.",
+            info.evicted_clients as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_connected_clients",
+            "Clients connected.",
+            info.connected_clients as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_pubsub_clients",
+            "Clients subscribed to channels.",
+            info.pubsub_clients as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_client_buffer_bytes",
+            "Bytes in client input and output buffers.",
+            info.buffer_memory as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_memory_used_bytes",
+            "Bytes used by the dataset.",
+            store.memory_usage() as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_memory_max_bytes",
+            "The maxmemory limit.",
+            store.max_memory() as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_changes_since_last_save",
+            "Writes not yet in the dump file.",
+            saves.changes_since_last_save as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_last_save_timestamp_seconds",
+            "When the last successful save finished.",
+            last_save,
+        ),
+        // -1 until a save has run.
+        (
+            GAUGE,
+            "rdb_last_save_duration_seconds",
+            "How long the last save took.",
+            saves.last_save_duration.map_or(-1.0, |d| d.as_secs_f64()),
+        ),
+        (
+            GAUGE,
+            "rdb_bgsave_in_progress",
+            "Whether a background save is running.",
+            saves.bgsave_in_progress as u8 as f64,
+        ),
+        (
+            GAUGE,
+            "rdb_last_bgsave_success",
+            "Whether the last background save succeeded.",
+            saves.last_bgsave_ok as u8 as f64,
+        ),
+    ];
+    drop(store);
+    for (kind, name, help, value) in scalars {
+        family(&mut out, name, kind, help, &[(String::new(), value)]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let db: Db = Arc::new(Shards::new(StorageConfig::default()));
+        db.lock_all()
+            .await
+            .shard_mut(b"k")
+            .insert("k".into(), "v".into());
+        db.stats()
+            .record_command("get", Duration::from_micros(1500));
+        db.stats().record_lookups(3, 1);
+        db.stats().users().record("app", Duration::from_micros(3));
+        let clients = Arc::new(ClientRegistry::new());
+        let _client = clients.register(None);
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let scrape = tokio::spawn(async move {
+            handle(server, &db, &clients).await.unwrap();
+        });
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        scrape.await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response
+            .contains("# TYPE rdb_commands_total counter\nrdb_commands_total{cmd=\"get\"} 1\n"));
+        assert!(response.contains("rdb_command_duration_seconds_total{cmd=\"get\"} 0.0015\n"));
+        assert!(response.contains("\nrdb_user_commands_total{user=\"app\"} 1\n"));
+        assert!(response.contains(
+            "\nrdb_user_command_latency_seconds{user=\"app\",quantile=\"0.99\"} 0.000003\n"
+        ));
+        assert!(response.contains("# TYPE rdb_shard_lock_wait_seconds histogram\n"));
+        assert!(
+            response.contains("\nrdb_shard_lock_wait_seconds_bucket{shard=\"0\",le=\"+Inf\"} 1\n")
+        );
+        assert!(response.contains("\nrdb_shard_lock_hold_seconds_count{shard=\"0\"} 1\n"));
+        assert!(response.contains("\nrdb_keyspace_hits_total 3\n"));
+        assert!(response.contains("\nrdb_keyspace_misses_total 1\n"));
+        assert!(response.contains("\nrdb_connected_clients 1\n"));
+        assert!(response.contains("\nrdb_keys{db=\"0\"} 1\n"));
+        assert!(response.contains("\nrdb_memory_used_bytes 2\n"));
+    }
+}
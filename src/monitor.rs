@@ -0,0 +1,115 @@
+//! A feed of every command run, for `MONITOR`
+//!
+//! The connection dispatcher publishes each command before running it, so
+//! monitors see commands in the order clients sent them, including those
+//! that go on to fail. Nothing is formatted unless someone is watching.
+use crate::protocol::quote;
+use bytes::Bytes;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Commands buffered per monitor before a slow one starts missing some.
+const FEED_CAPACITY: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonitoredCommand {
+    pub time: SystemTime,
+    /// The database selected when the command ran.
+    pub db: usize,
+    pub client_addr: String,
+    pub args: Vec<Bytes>,
+}
+
+impl MonitoredCommand {
+    /// The line sent to monitors, as Redis writes it:
+    /// `1339518083.107412 [0 127.0.0.1:60866] "keys" "*"`.
+    pub fn to_line(&self) -> String {
+        let since_epoch = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut line = format!(
+            "{}.{:06} [{} {}]",
+            since_epoch.as_secs(),
+            since_epoch.subsec_micros(),
+            self.db,
+            self.client_addr
+        );
+        for arg in &self.args {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+        line
+    }
+}
+
+pub struct Monitor {
+    sender: broadcast::Sender<MonitoredCommand>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor {
+            sender: broadcast::channel(FEED_CAPACITY).0,
+        }
+    }
+}
+
+impl Monitor {
+    /// Whether any client is monitoring, so commands need publishing.
+    pub fn is_watched(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, command: MonitoredCommand) {
+        let _ = self.sender.send(command);
+    }
+
+    pub fn watch(&self) -> Watcher {
+        Watcher {
+            commands: self.sender.subscribe(),
+        }
+    }
+}
+
+/// A monitoring client's end of the feed.
+pub struct Watcher {
+    commands: broadcast::Receiver<MonitoredCommand>,
+}
+
+impl Watcher {
+    /// The next command run. Fails with the number of commands missed if
+    /// the monitor fell behind; it can carry on from the next one.
+    pub async fn recv(&mut self) -> Result<MonitoredCommand, u64> {
+        match self.commands.recv().await {
+            Ok(command) => Ok(command),
+            Err(RecvError::Lagged(missed)) => Err(missed),
+            // The feed lives as long as the database.
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_watch_and_format() {
+        let monitor = Monitor::default();
+        assert!(!monitor.is_watched());
+        let mut watcher = monitor.watch();
+        assert!(monitor.is_watched());
+
+        monitor.publish(MonitoredCommand {
+            time: UNIX_EPOCH + Duration::from_micros(1_339_518_083_107_412),
+            db: 2,
+            client_addr: "127.0.0.1:60866".to_string(),
+            args: vec!["SET".into(), "k".into(), "a \"b\"\n\x00".into()],
+        });
+        assert_eq!(
+            watcher.recv().await.unwrap().to_line(),
+            r#"1339518083.107412 [2 127.0.0.1:60866] "SET" "k" "a \"b\"\n\x00""#
+        );
+        drop(watcher);
+        assert!(!monitor.is_watched());
+    }
+}
@@ -0,0 +1,128 @@
+//! Inline commands: the words of a command on one line, as typed into
+//! `telnet` or `nc`
+//!
+//! Words are split as Redis splits them: on whitespace, with double quotes
+//! taking the escapes `\n`, `\r`, `\t`, `\b`, `\a`, `\\`, `\"` and `\xHH`,
+//! and single quotes only `\'`.
+use super::RespError;
+use bytes::Bytes;
+
+/// Longest line taken as an inline command, as in Redis.
+pub const MAX_INLINE: usize = 64 * 1024;
+
+/// Parses the line at the start of `input`, ended by LF or CRLF, into its
+/// words and the offset just past it. A blank line has no words.
+pub fn parse_inline(input: &[u8]) -> Result<(Vec<Bytes>, usize), RespError> {
+    let Some(end) = input.iter().position(|&c| c == b'\n') else {
+        return Err(match input.len() > MAX_INLINE {
+            true => RespError::InlineTooBig,
+            false => RespError::Incomplete,
+        });
+    };
+    if end > MAX_INLINE {
+        return Err(RespError::InlineTooBig);
+    }
+    let line = input[..end].strip_suffix(b"\r").unwrap_or(&input[..end]);
+    Ok((split_words(line)?, end + 1))
+}
+
+fn split_words(line: &[u8]) -> Result<Vec<Bytes>, RespError> {
+    let mut words = Vec::new();
+    let mut pos = 0;
+    loop {
+        while line.get(pos).is_some_and(u8::is_ascii_whitespace) {
+            pos += 1;
+        }
+        if pos == line.len() {
+            return Ok(words);
+        }
+        let mut word = Vec::new();
+        match line[pos] {
+            quote @ (b'"' | b'\'') => {
+                pos += 1;
+                loop {
+                    match (line.get(pos), line.get(pos + 1)) {
+                        (None, _) => return Err(RespError::UnbalancedQuotes),
+                        (Some(b'\\'), Some(&c)) if quote == b'"' => {
+                            pos += 2;
+                            word.push(match c {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                b'x' => match line.get(pos..pos + 2).and_then(hex_byte) {
+                                    Some(byte) => {
+                                        pos += 2;
+                                        byte
+                                    }
+                                    None => b'x',
+                                },
+                                c => c,
+                            });
+                        }
+                        (Some(b'\\'), Some(b'\'')) => {
+                            pos += 2;
+                            word.push(b'\'');
+                        }
+                        (Some(&c), _) if c == quote => {
+                            pos += 1;
+                            // The closing quote must end the word.
+                            if line.get(pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+                                return Err(RespError::UnbalancedQuotes);
+                            }
+                            break;
+                        }
+                        (Some(&c), _) => {
+                            pos += 1;
+                            word.push(c);
+                        }
+                    }
+                }
+            }
+            _ => {
+                while let Some(&c) = line.get(pos).filter(|c| !c.is_ascii_whitespace()) {
+                    word.push(c);
+                    pos += 1;
+                }
+            }
+        }
+        words.push(word.into());
+    }
+}
+
+fn hex_byte(digits: &[u8]) -> Option<u8> {
+    u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_inline() {
+        let (words, len) = parse_inline(b"SET  k \"a b\\x41\\n\" 'it\\'s'\r\nGET k\n").unwrap();
+        assert_eq!(
+            words,
+            vec![
+                Bytes::from("SET"),
+                Bytes::from("k"),
+                Bytes::from("a bA\n"),
+                Bytes::from("it's"),
+            ]
+        );
+        assert_eq!(len, 28);
+        assert_eq!(parse_inline(b"\r\n").unwrap(), (vec![], 2));
+        assert!(matches!(parse_inline(b"GET k"), Err(RespError::Incomplete)));
+        assert!(matches!(
+            parse_inline(b"SET \"k\r\n"),
+            Err(RespError::UnbalancedQuotes)
+        ));
+        assert!(matches!(
+            parse_inline(b"SET \"k\"v\r\n"),
+            Err(RespError::UnbalancedQuotes)
+        ));
+        let long = vec![b'a'; MAX_INLINE + 1];
+        assert!(matches!(parse_inline(&long), Err(RespError::InlineTooBig)));
+    }
+}
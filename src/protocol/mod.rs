@@ -1,13 +1,39 @@
 //! RESP (Redis Serialization Protocol) implementation
+mod inline;
+
+pub use inline::{parse_inline, MAX_INLINE};
+
+use bytes::Bytes;
 use thiserror::Error;
 
-#[derive(Debug, PartialEq)]
+/// Protocol version a connection speaks, negotiated with `HELLO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// A RESP value. The RESP3 types are written in their RESP2 equivalents to
+/// RESP2 clients: maps, sets and pushes become arrays, doubles and big
+/// numbers bulk strings, and null a null bulk string.
+#[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(Option<String>),
+    BulkString(Option<Bytes>),
     Array(Vec<RespValue>),
+    /// `*-1`, e.g. the reply to an `EXEC` aborted by `WATCH`.
+    NullArray,
+    Null,
+    Double(f64),
+    /// An integer of any size, in decimal.
+    BigNumber(String),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    /// Out of band data such as pub/sub messages.
+    Push(Vec<RespValue>),
 }
 
 #[derive(Error, Debug)]
@@ -16,178 +42,523 @@ pub enum RespError {
     InvalidFormat,
     #[error("incomplete input")]
     Incomplete,
+    #[error("unbalanced quotes in request")]
+    UnbalancedQuotes,
+    #[error("too big inline request")]
+    InlineTooBig,
+    #[error("invalid bulk length")]
+    InvalidBulkLength,
+    #[error("invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("too deeply nested")]
+    TooDeep,
+}
+
+/// Most elements a request's array may have.
+pub const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Deepest nesting of aggregates parsed before giving up.
+const MAX_DEPTH: usize = 128;
+
+/// Longest a length header may get without its CRLF: a sign and 19 digits.
+const MAX_LENGTH_LINE: usize = 21;
+
+/// Bounds on what the parser accepts. Lengths are checked as soon as their
+/// header is in, so an oversized frame is refused before its payload is
+/// buffered.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_bulk_len: usize,
+    pub max_elements: usize,
+}
+
+impl Limits {
+    /// No bounds beyond what fits in memory, for trusted input such as our
+    /// own AOF.
+    pub const NONE: Limits = Limits {
+        max_bulk_len: usize::MAX,
+        max_elements: usize::MAX,
+    };
+
+    /// Bounds for client requests, taking bulk strings of up to
+    /// `max_bulk_len` bytes.
+    pub fn requests(max_bulk_len: usize) -> Self {
+        Limits {
+            max_bulk_len,
+            max_elements: MAX_MULTIBULK_LEN,
+        }
+    }
 }
 
 impl RespValue {
-    pub fn serialize(&self) -> String {
+    /// A non-null bulk string.
+    pub fn bulk(value: impl Into<Bytes>) -> Self {
+        RespValue::BulkString(Some(value.into()))
+    }
+
+    /// Encodes the value in RESP2, as for commands sent to other servers.
+    pub fn serialize(&self) -> Vec<u8> {
+        self.serialize_as(Protocol::Resp2)
+    }
+
+    pub fn serialize_as(&self, protocol: Protocol) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_to(&mut out, protocol);
+        out
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>, protocol: Protocol) {
+        let resp3 = protocol == Protocol::Resp3;
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s),
-            RespValue::Error(msg) => format!("-{}\r\n", msg),
-            RespValue::Integer(n) => format!(":{}\r\n", n),
-            RespValue::BulkString(None) => "$-1\r\n".to_string(),
-            RespValue::BulkString(Some(s)) => format!("${}\r\n{}\r\n", s.len(), s),
-            RespValue::Array(items) => {
-                let mut result = format!("*{}\r\n", items.len());
-                for item in items {
-                    result.push_str(&item.serialize());
+            RespValue::SimpleString(s) => {
+                out.push(b'+');
+                out.extend_from_slice(s.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Error(msg) => {
+                out.push(b'-');
+                out.extend_from_slice(msg.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            RespValue::BulkString(None) | RespValue::NullArray | RespValue::Null if resp3 => {
+                out.extend_from_slice(b"_\r\n")
+            }
+            RespValue::BulkString(None) | RespValue::Null => out.extend_from_slice(b"$-1\r\n"),
+            RespValue::BulkString(Some(s)) => {
+                out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+                out.extend_from_slice(s);
+                out.extend_from_slice(b"\r\n");
+            }
+            RespValue::NullArray => out.extend_from_slice(b"*-1\r\n"),
+            RespValue::Array(items) => write_aggregate(out, b'*', items, protocol),
+            RespValue::Double(d) if resp3 => {
+                out.extend_from_slice(format!(",{}\r\n", d).as_bytes())
+            }
+            RespValue::Double(d) => RespValue::bulk(d.to_string()).write_to(out, protocol),
+            RespValue::BigNumber(n) if resp3 => {
+                out.extend_from_slice(format!("({}\r\n", n).as_bytes())
+            }
+            RespValue::BigNumber(n) => RespValue::bulk(n.clone()).write_to(out, protocol),
+            RespValue::Map(pairs) => {
+                let (marker, len) = if resp3 {
+                    (b'%', pairs.len())
+                } else {
+                    (b'*', pairs.len() * 2)
+                };
+                out.extend_from_slice(format!("{}{}\r\n", marker as char, len).as_bytes());
+                for (key, value) in pairs {
+                    key.write_to(out, protocol);
+                    value.write_to(out, protocol);
                 }
-                result
             }
+            RespValue::Set(items) => {
+                write_aggregate(out, if resp3 { b'~' } else { b'*' }, items, protocol)
+            }
+            RespValue::Push(items) => {
+                write_aggregate(out, if resp3 { b'>' } else { b'*' }, items, protocol)
+            }
+        }
+    }
+}
+
+fn write_aggregate(out: &mut Vec<u8>, marker: u8, items: &[RespValue], protocol: Protocol) {
+    out.extend_from_slice(format!("{}{}\r\n", marker as char, items.len()).as_bytes());
+    for item in items {
+        item.write_to(out, protocol);
+    }
+}
+
+/// `data` in double quotes, with quotes, backslashes and unprintable bytes
+/// escaped, as Redis prints arguments in `MONITOR` and `redis-cli` prints
+/// replies.
+pub fn quote(data: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &b in data {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(b as char);
+            }
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            0x20..=0x7E => quoted.push(b as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", b)),
         }
     }
+    quoted.push('"');
+    quoted
+}
+
+pub fn parse_resp(input: &[u8]) -> Result<(RespValue, usize), RespError> {
+    parse_resp_limited(input, &Limits::NONE)
 }
 
-pub fn parse_resp(input: &str) -> Result<(RespValue, usize), RespError> {
+pub fn parse_resp_limited(input: &[u8], limits: &Limits) -> Result<(RespValue, usize), RespError> {
+    parse_value(input, limits, 0)
+}
+
+fn parse_value(
+    input: &[u8],
+    limits: &Limits,
+    depth: usize,
+) -> Result<(RespValue, usize), RespError> {
     if input.is_empty() {
         return Err(RespError::Incomplete);
     }
+    if depth > MAX_DEPTH {
+        return Err(RespError::TooDeep);
+    }
 
-    match input.chars().next().unwrap() {
-        '+' => parse_simple_string(input),
-        '-' => parse_error(input),
-        ':' => parse_integer(input),
-        '$' => parse_bulk_string(input),
-        '*' => parse_array(input),
+    match input[0] {
+        b'+' => parse_simple_string(input),
+        b'-' => parse_error(input),
+        b':' => parse_integer(input),
+        b'$' => parse_bulk_string(input, limits),
+        b'*' => parse_array(input, limits, depth),
+        b'_' => match line(input)? {
+            (b"", next) => Ok((RespValue::Null, next)),
+            _ => Err(RespError::InvalidFormat),
+        },
+        b',' => parse_double(input),
+        b'(' => {
+            let (text, next) = text_line(input)?;
+            let digits = text.strip_prefix('-').unwrap_or(&text);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(RespError::InvalidFormat);
+            }
+            Ok((RespValue::BigNumber(text), next))
+        }
+        b'%' => {
+            let (items, next) = parse_items(input, 2, limits, depth)?;
+            let mut items = items.into_iter();
+            let mut pairs = Vec::with_capacity(items.len() / 2);
+            while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                pairs.push((key, value));
+            }
+            Ok((RespValue::Map(pairs), next))
+        }
+        b'~' => {
+            parse_items(input, 1, limits, depth).map(|(items, next)| (RespValue::Set(items), next))
+        }
+        b'>' => {
+            parse_items(input, 1, limits, depth).map(|(items, next)| (RespValue::Push(items), next))
+        }
         _ => Err(RespError::InvalidFormat),
     }
 }
 
-fn parse_simple_string(input: &str) -> Result<(RespValue, usize), RespError> {
-    if let Some(end) = input[1..].find("\r\n") {
-        Ok((RespValue::SimpleString(input[1..=end].to_string()), end + 3))
-    } else {
-        Err(RespError::Incomplete)
+/// Finds the CRLF ending the line that starts after the type byte, returning
+/// the line's contents and the offset just past the CRLF.
+fn line(input: &[u8]) -> Result<(&[u8], usize), RespError> {
+    match input[1..].windows(2).position(|w| w == b"\r\n") {
+        Some(end) => Ok((&input[1..=end], end + 3)),
+        None => Err(RespError::Incomplete),
     }
 }
 
-fn parse_error(input: &str) -> Result<(RespValue, usize), RespError> {
-    if let Some(end) = input[1..].find("\r\n") {
-        Ok((RespValue::Error(input[1..=end].to_string()), end + 3))
-    } else {
-        Err(RespError::Incomplete)
-    }
+fn text_line(input: &[u8]) -> Result<(String, usize), RespError> {
+    let (line, next) = line(input)?;
+    let text = std::str::from_utf8(line).map_err(|_| RespError::InvalidFormat)?;
+    Ok((text.to_string(), next))
 }
 
-fn parse_integer(input: &str) -> Result<(RespValue, usize), RespError> {
-    if let Some(end) = input[1..].find("\r\n") {
-        let num = input[1..=end]
-            .parse::<i64>()
-            .map_err(|_| RespError::InvalidFormat)?;
-        Ok((RespValue::Integer(num), end + 3))
-    } else {
-        Err(RespError::Incomplete)
-    }
+fn integer_line(input: &[u8]) -> Result<(i64, usize), RespError> {
+    let (line, next) = match line(input) {
+        // No number is that long, so don't wait for the rest of it.
+        Err(RespError::Incomplete) if input.len() > 1 + MAX_LENGTH_LINE => {
+            return Err(RespError::InvalidFormat)
+        }
+        result => result?,
+    };
+    let n = std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or(RespError::InvalidFormat)?;
+    Ok((n, next))
 }
 
-fn parse_bulk_string(input: &str) -> Result<(RespValue, usize), RespError> {
-    if let Some(len_end) = input[1..].find("\r\n") {
-        let length = input[1..=len_end]
-            .parse::<i64>()
-            .map_err(|_| RespError::InvalidFormat)?;
+fn parse_simple_string(input: &[u8]) -> Result<(RespValue, usize), RespError> {
+    let (text, next) = text_line(input)?;
+    Ok((RespValue::SimpleString(text), next))
+}
 
-        if length == -1 {
-            return Ok((RespValue::BulkString(None), len_end + 3));
-        }
+fn parse_error(input: &[u8]) -> Result<(RespValue, usize), RespError> {
+    let (text, next) = text_line(input)?;
+    Ok((RespValue::Error(text), next))
+}
 
-        let start = len_end + 3;
-        let end = start + length as usize;
+fn parse_integer(input: &[u8]) -> Result<(RespValue, usize), RespError> {
+    let (n, next) = integer_line(input)?;
+    Ok((RespValue::Integer(n), next))
+}
 
-        if input.len() < end + 2 {
-            return Err(RespError::Incomplete);
-        }
+fn parse_bulk_string(input: &[u8], limits: &Limits) -> Result<(RespValue, usize), RespError> {
+    let (length, start) = integer_line(input)?;
 
-        if &input[end..end + 2] != "\r\n" {
-            return Err(RespError::InvalidFormat);
-        }
+    if length == -1 {
+        return Ok((RespValue::BulkString(None), start));
+    }
+    if length < 0 {
+        return Err(RespError::InvalidFormat);
+    }
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|&length| length <= limits.max_bulk_len)
+        .ok_or(RespError::InvalidBulkLength)?;
 
-        Ok((
-            RespValue::BulkString(Some(input[start..end].to_string())),
-            end + 2,
-        ))
-    } else {
-        Err(RespError::Incomplete)
+    let end = start.saturating_add(length);
+
+    if input.len() < end.saturating_add(2) {
+        return Err(RespError::Incomplete);
+    }
+
+    if input[end..end + 2] != *b"\r\n" {
+        return Err(RespError::InvalidFormat);
     }
+
+    Ok((
+        RespValue::BulkString(Some(Bytes::copy_from_slice(&input[start..end]))),
+        end + 2,
+    ))
 }
 
-fn parse_array(input: &str) -> Result<(RespValue, usize), RespError> {
-    if let Some(len_end) = input[1..].find("\r\n") {
-        let length = input[1..=len_end]
-            .parse::<i64>()
-            .map_err(|_| RespError::InvalidFormat)?;
+fn parse_double(input: &[u8]) -> Result<(RespValue, usize), RespError> {
+    let (text, next) = text_line(input)?;
+    let d = match text.as_str() {
+        "inf" => f64::INFINITY,
+        "-inf" => f64::NEG_INFINITY,
+        text => text.parse().map_err(|_| RespError::InvalidFormat)?,
+    };
+    Ok((RespValue::Double(d), next))
+}
 
-        if length == -1 {
-            return Ok((RespValue::Array(vec![]), len_end + 3));
-        }
+/// Parses the elements of an aggregate whose header counts `length` units
+/// of `per_unit` elements each.
+fn parse_items(
+    input: &[u8],
+    per_unit: usize,
+    limits: &Limits,
+    depth: usize,
+) -> Result<(Vec<RespValue>, usize), RespError> {
+    let (length, pos) = integer_line(input)?;
+    let count = usize::try_from(length)
+        .ok()
+        .and_then(|length| length.checked_mul(per_unit))
+        .ok_or(RespError::InvalidMultibulkLength)?;
+    parse_elements(input, pos, count, limits, depth)
+}
 
-        let mut pos = len_end + 3;
-        let mut items = Vec::new();
+fn parse_array(
+    input: &[u8],
+    limits: &Limits,
+    depth: usize,
+) -> Result<(RespValue, usize), RespError> {
+    let (length, pos) = integer_line(input)?;
 
-        for _ in 0..length {
-            if pos >= input.len() {
-                return Err(RespError::Incomplete);
-            }
+    if length == -1 {
+        return Ok((RespValue::NullArray, pos));
+    }
+    let count = usize::try_from(length).map_err(|_| RespError::InvalidMultibulkLength)?;
+    let (items, pos) = parse_elements(input, pos, count, limits, depth)?;
+    Ok((RespValue::Array(items), pos))
+}
 
-            let (value, len) = parse_resp(&input[pos..])?;
-            items.push(value);
-            pos += len;
+/// Parses `count` values starting at `pos`. Room for them is reserved as
+/// they arrive, never up front from the header.
+fn parse_elements(
+    input: &[u8],
+    mut pos: usize,
+    count: usize,
+    limits: &Limits,
+    depth: usize,
+) -> Result<(Vec<RespValue>, usize), RespError> {
+    if count > limits.max_elements {
+        return Err(RespError::InvalidMultibulkLength);
+    }
+    let mut items = Vec::new();
+    for _ in 0..count {
+        if pos >= input.len() {
+            return Err(RespError::Incomplete);
         }
-
-        Ok((RespValue::Array(items), pos))
-    } else {
-        Err(RespError::Incomplete)
+        let (value, len) = parse_value(&input[pos..], limits, depth + 1)?;
+        items.push(value);
+        pos += len;
     }
+    Ok((items, pos))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote(b"plain"), "\"plain\"");
+        assert_eq!(
+            quote(b"\"a\\b\"\r\n\t\x07\x08\x00\xff"),
+            r#""\"a\\b\"\r\n\t\a\b\x00\xff""#
+        );
+    }
+
     #[test]
     fn test_parse_simple_string() {
-        let input = "+OK\r\n";
+        let input = b"+OK\r\n";
         let (value, _) = parse_resp(input).unwrap();
         assert_eq!(value, RespValue::SimpleString("OK".to_string()));
     }
 
     #[test]
     fn test_parse_error() {
-        let input = "-Error message\r\n";
+        let input = b"-Error message\r\n";
         let (value, _) = parse_resp(input).unwrap();
         assert_eq!(value, RespValue::Error("Error message".to_string()));
     }
 
     #[test]
     fn test_parse_integer() {
-        let input = ":1000\r\n";
+        let input = b":1000\r\n";
         let (value, _) = parse_resp(input).unwrap();
         assert_eq!(value, RespValue::Integer(1000));
     }
 
     #[test]
     fn test_parse_bulk_string() {
-        let input = "$5\r\nhello\r\n";
+        let input = b"$5\r\nhello\r\n";
         let (value, _) = parse_resp(input).unwrap();
-        assert_eq!(value, RespValue::BulkString(Some("hello".to_string())));
+        assert_eq!(value, RespValue::bulk("hello"));
     }
 
     #[test]
     fn test_parse_null_bulk_string() {
-        let input = "$-1\r\n";
+        let input = b"$-1\r\n";
         let (value, _) = parse_resp(input).unwrap();
         assert_eq!(value, RespValue::BulkString(None));
     }
 
     #[test]
     fn test_parse_array() {
-        let input = "*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n";
+        let input = b"*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n";
         let (value, _) = parse_resp(input).unwrap();
         match value {
             RespValue::Array(items) => {
                 assert_eq!(items.len(), 2);
-                assert_eq!(items[0], RespValue::BulkString(Some("hello".to_string())));
-                assert_eq!(items[1], RespValue::BulkString(Some("world".to_string())));
+                assert_eq!(items[0], RespValue::bulk("hello"));
+                assert_eq!(items[1], RespValue::bulk("world"));
             }
             _ => panic!("Expected array"),
         }
     }
+
+    #[test]
+    fn test_parse_malformed_bulk_string() {
+        assert!(matches!(
+            parse_resp(b"$-5\r\n"),
+            Err(RespError::InvalidFormat)
+        ));
+        assert!(matches!(
+            parse_resp(b"$5\r\nhel"),
+            Err(RespError::Incomplete)
+        ));
+    }
+
+    #[test]
+    fn test_resp3_types() {
+        let value = RespValue::Map(vec![
+            (RespValue::bulk("score"), RespValue::Double(1.5)),
+            (
+                RespValue::bulk("members"),
+                RespValue::Set(vec![RespValue::bulk("a")]),
+            ),
+            (
+                RespValue::bulk("big"),
+                RespValue::BigNumber("-12".to_string()),
+            ),
+            (RespValue::bulk("none"), RespValue::Null),
+        ]);
+        let resp3 = value.serialize_as(Protocol::Resp3);
+        assert_eq!(
+            resp3,
+            b"%4\r\n$5\r\nscore\r\n,1.5\r\n$7\r\nmembers\r\n~1\r\n$1\r\na\r\n\
+              $3\r\nbig\r\n(-12\r\n$4\r\nnone\r\n_\r\n"
+        );
+        assert_eq!(parse_resp(&resp3).unwrap(), (value, resp3.len()));
+
+        assert_eq!(
+            RespValue::Map(vec![(RespValue::bulk("score"), RespValue::Double(1.5))]).serialize(),
+            b"*2\r\n$5\r\nscore\r\n$3\r\n1.5\r\n"
+        );
+        let push = RespValue::Push(vec![RespValue::bulk("message")]);
+        assert_eq!(push.serialize(), b"*1\r\n$7\r\nmessage\r\n");
+        assert_eq!(
+            push.serialize_as(Protocol::Resp3),
+            b">1\r\n$7\r\nmessage\r\n"
+        );
+        assert_eq!(
+            RespValue::BulkString(None).serialize_as(Protocol::Resp3),
+            b"_\r\n"
+        );
+        assert_eq!(
+            parse_resp(b",inf\r\n").unwrap().0,
+            RespValue::Double(f64::INFINITY)
+        );
+        assert!(parse_resp(b"(12a\r\n").is_err());
+    }
+
+    #[test]
+    fn test_malicious_headers() {
+        let limits = Limits::requests(512 * 1024 * 1024);
+        let nested = "*1\r\n".repeat(MAX_DEPTH + 2);
+        let corpus: &[(&[u8], &str)] = &[
+            (b"*1000000000\r\n", "invalid multibulk length"),
+            (b"*9223372036854775807\r\n", "invalid multibulk length"),
+            (b"*-2\r\n", "invalid multibulk length"),
+            (b"%9223372036854775807\r\n", "invalid multibulk length"),
+            (b"*1\r\n$1000000000\r\n", "invalid bulk length"),
+            (b"$9223372036854775807\r\nabc", "invalid bulk length"),
+            (b"*1\r\n$-9223372036854775808\r\n", "invalid RESP format"),
+            (b"*99999999999999999999\r\n", "invalid RESP format"),
+            (b"*1111111111111111111111111", "invalid RESP format"),
+            (b"$1\r\nabc\r\n", "invalid RESP format"),
+            (nested.as_bytes(), "too deeply nested"),
+        ];
+        for (input, expected) in corpus {
+            match parse_resp_limited(input, &limits) {
+                Err(e) if e.to_string() == *expected => {}
+                other => panic!("{:?} parsed as {:?}", String::from_utf8_lossy(input), other),
+            }
+        }
+
+        // Without limits, huge lengths wait for their data rather than
+        // overflowing.
+        assert!(matches!(
+            parse_resp(b"$9223372036854775807\r\nabc"),
+            Err(RespError::Incomplete)
+        ));
+
+        // Every prefix of a valid frame is incomplete.
+        let frame = RespValue::Array(vec![
+            RespValue::bulk("SET"),
+            RespValue::Map(vec![(RespValue::Integer(-1), RespValue::Double(0.5))]),
+        ])
+        .serialize_as(Protocol::Resp3);
+        for end in 0..frame.len() {
+            assert!(matches!(
+                parse_resp_limited(&frame[..end], &limits),
+                Err(RespError::Incomplete)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let payload: &[u8] = b"\x00\xff\r\n\xc3";
+        let encoded = RespValue::Array(vec![RespValue::bulk(payload)]).serialize();
+        let (value, len) = parse_resp(&encoded).unwrap();
+        assert_eq!(len, encoded.len());
+        assert_eq!(value, RespValue::Array(vec![RespValue::bulk(payload)]));
+    }
 }
@@ -0,0 +1,83 @@
+//! Messages kept per channel for `PUBSUB HISTORY`
+//!
+//! Pub/sub delivers a message to whoever listens when it's published and
+//! forgets it. With `pubsub_history` set, channels also keep their last few
+//! messages, so a subscriber coming back from a dropped connection can ask
+//! for what it missed.
+use crate::config::PubSubHistoryConfig;
+use crate::glob;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The last messages of each channel kept.
+#[derive(Default)]
+pub(super) struct History {
+    config: PubSubHistoryConfig,
+    channels: Mutex<HashMap<Bytes, Retained>>,
+}
+
+#[derive(Default)]
+struct Retained {
+    messages: VecDeque<Bytes>,
+    size: usize,
+}
+
+impl History {
+    pub(super) fn new(config: PubSubHistoryConfig) -> Self {
+        History {
+            config,
+            channels: Mutex::default(),
+        }
+    }
+
+    fn keeps(&self, channel: &[u8]) -> bool {
+        self.config.messages > 0
+            && (self.config.channels.is_empty()
+                || self
+                    .config
+                    .channels
+                    .iter()
+                    .any(|pattern| glob::matches(pattern.as_bytes(), channel)))
+    }
+
+    /// Keeps `payload` as the latest message of `channel`, dropping the
+    /// oldest ones over the count or size allowed.
+    pub(super) fn record(&self, channel: &Bytes, payload: &Bytes) {
+        if !self.keeps(channel) {
+            return;
+        }
+        let mut channels = self.channels.lock().unwrap();
+        let retained = channels.entry(channel.clone()).or_default();
+        retained.size += payload.len();
+        retained.messages.push_back(payload.clone());
+        while retained.messages.len() > self.config.messages || retained.size > self.config.max_size
+        {
+            let Some(oldest) = retained.messages.pop_front() else {
+                break;
+            };
+            retained.size -= oldest.len();
+        }
+        if retained.messages.is_empty() {
+            channels.remove(channel);
+        }
+    }
+
+    /// The last `count` messages of `channel`, or all those kept, oldest
+    /// first.
+    pub(super) fn last(&self, channel: &[u8], count: Option<usize>) -> Vec<Bytes> {
+        let channels = self.channels.lock().unwrap();
+        let Some(retained) = channels.get(channel) else {
+            return Vec::new();
+        };
+        let count = count.map_or(retained.messages.len(), |count| {
+            count.min(retained.messages.len())
+        });
+        retained
+            .messages
+            .iter()
+            .skip(retained.messages.len() - count)
+            .cloned()
+            .collect()
+    }
+}
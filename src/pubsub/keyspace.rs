@@ -0,0 +1,186 @@
+//! Keyspace notifications: `__keyspace@<db>__:<key>` and
+//! `__keyevent@<db>__:<event>` messages for writes, evictions and expiries
+//!
+//! Which get published is set with `notify-keyspace-events`, taking the
+//! flags of Redis: `K` and `E` pick the keyspace and keyevent channels, the
+//! others the kinds of event, with `A` for all of them. Nothing is published
+//! unless one of `K` and `E` is given along with a kind.
+use super::Broker;
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// A kind of event, as its `notify-keyspace-events` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    /// Commands on keys of any type, such as `DEL`.
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+    /// Keys reaching their TTL.
+    Expired,
+    /// Keys evicted at `max_memory`.
+    Evicted,
+    Stream,
+}
+
+impl EventClass {
+    fn bit(self) -> u16 {
+        1 << (self as u16)
+    }
+}
+
+/// The kinds, in the order Redis lists their flags.
+const CLASSES: &[(char, EventClass)] = &[
+    ('g', EventClass::Generic),
+    ('$', EventClass::String),
+    ('l', EventClass::List),
+    ('s', EventClass::Set),
+    ('h', EventClass::Hash),
+    ('z', EventClass::SortedSet),
+    ('x', EventClass::Expired),
+    ('e', EventClass::Evicted),
+    ('t', EventClass::Stream),
+];
+
+const KEYSPACE: u16 = 1 << 14;
+const KEYEVENT: u16 = 1 << 15;
+const ALL_CLASSES: u16 = (1 << 9) - 1;
+
+/// The parsed `notify-keyspace-events` setting; off by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "String")]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    /// Whether events of `class` get published at all.
+    pub fn wants(self, class: EventClass) -> bool {
+        self.0 & (KEYSPACE | KEYEVENT) != 0 && self.0 & class.bit() != 0
+    }
+
+    /// Publishes `event` on `key` of database `db`, if events of `class`
+    /// are wanted.
+    pub fn notify(self, broker: &Broker, class: EventClass, event: &str, db: usize, key: &[u8]) {
+        if !self.wants(class) {
+            return;
+        }
+        if self.0 & KEYSPACE != 0 {
+            let channel = channel(format_args!("__keyspace@{}__:", db), key);
+            broker.deliver(channel, Bytes::copy_from_slice(event.as_bytes()));
+        }
+        if self.0 & KEYEVENT != 0 {
+            let channel = channel(format_args!("__keyevent@{}__:", db), event.as_bytes());
+            broker.deliver(channel, Bytes::copy_from_slice(key));
+        }
+    }
+}
+
+fn channel(prefix: fmt::Arguments, name: &[u8]) -> Bytes {
+    let mut channel = BytesMut::new();
+    channel.put_slice(prefix.to_string().as_bytes());
+    channel.put_slice(name);
+    channel.freeze()
+}
+
+impl FromStr for KeyspaceEvents {
+    type Err = String;
+
+    fn from_str(flags: &str) -> Result<Self, String> {
+        let mut bits = 0;
+        for flag in flags.chars() {
+            bits |= match flag {
+                'A' => ALL_CLASSES,
+                'K' => KEYSPACE,
+                'E' => KEYEVENT,
+                _ => match CLASSES.iter().find(|(c, _)| *c == flag) {
+                    Some((_, class)) => class.bit(),
+                    None => return Err(format!("unknown keyspace event flag '{}'", flag)),
+                },
+            };
+        }
+        Ok(KeyspaceEvents(bits))
+    }
+}
+
+impl TryFrom<String> for KeyspaceEvents {
+    type Error = String;
+
+    fn try_from(flags: String) -> Result<Self, String> {
+        flags.parse()
+    }
+}
+
+/// The flags as `CONFIG GET` reports them: `A` for every kind, then `K` and
+/// `E`.
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 & ALL_CLASSES == ALL_CLASSES {
+            f.write_str("A")?;
+        } else {
+            for (flag, class) in CLASSES {
+                if self.0 & class.bit() != 0 {
+                    write!(f, "{}", flag)?;
+                }
+            }
+        }
+        if self.0 & KEYSPACE != 0 {
+            f.write_str("K")?;
+        }
+        if self.0 & KEYEVENT != 0 {
+            f.write_str("E")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::RespValue;
+    use crate::pubsub::Subscriber;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_flags() {
+        let events: KeyspaceEvents = "KEA".parse().unwrap();
+        assert_eq!(events.to_string(), "AKE");
+        assert!(events.wants(EventClass::Evicted));
+        let events: KeyspaceEvents = "Eg$".parse().unwrap();
+        assert_eq!(events.to_string(), "g$E");
+        assert!(!events.wants(EventClass::List));
+        // Kinds alone publish nowhere.
+        assert!(!"g$"
+            .parse::<KeyspaceEvents>()
+            .unwrap()
+            .wants(EventClass::Generic));
+        assert!(!KeyspaceEvents::default().wants(EventClass::Generic));
+        assert_eq!(KeyspaceEvents::default().to_string(), "");
+        assert!("KQ".parse::<KeyspaceEvents>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_notify_both_channels() {
+        let broker = Arc::new(Broker::new());
+        let mut subscriber = Subscriber::new(broker.clone());
+        subscriber.subscribe(vec!["__keyspace@0__:k".into(), "__keyevent@0__:set".into()]);
+
+        let events: KeyspaceEvents = "KE$".parse().unwrap();
+        events.notify(&broker, EventClass::Generic, "del", 0, b"k");
+        events.notify(&broker, EventClass::String, "set", 3, b"k");
+        events.notify(&broker, EventClass::String, "set", 0, b"k");
+        let message = |channel: &'static str, payload: &'static str| {
+            RespValue::Push(vec![
+                RespValue::bulk("message"),
+                RespValue::bulk(channel),
+                RespValue::bulk(payload),
+            ])
+        };
+        let received = [subscriber.recv().await, subscriber.recv().await];
+        assert!(received.contains(&message("__keyspace@0__:k", "set")));
+        assert!(received.contains(&message("__keyevent@0__:set", "k")));
+    }
+}
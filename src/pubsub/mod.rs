@@ -0,0 +1,384 @@
+//! Publish/subscribe messaging
+mod history;
+mod keyspace;
+
+pub use keyspace::{EventClass, KeyspaceEvents};
+
+use crate::config::PubSubHistoryConfig;
+use crate::glob;
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use history::History;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{StreamExt, StreamMap};
+
+/// Messages buffered per channel before slow subscribers start missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+struct Message {
+    channel: Bytes,
+    payload: Bytes,
+}
+
+/// Routes published messages to channel and pattern subscribers. Each
+/// channel and each pattern gets its own broadcast sender, created on first
+/// subscription and dropped once nobody listens.
+#[derive(Default)]
+pub struct Broker {
+    channels: Mutex<HashMap<Bytes, broadcast::Sender<Message>>>,
+    patterns: Mutex<HashMap<Bytes, broadcast::Sender<Message>>>,
+    history: History,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Broker::default()
+    }
+
+    /// A broker keeping messages for `PUBSUB HISTORY` as `config` asks.
+    pub fn with_history(config: PubSubHistoryConfig) -> Self {
+        Broker {
+            history: History::new(config),
+            ..Broker::default()
+        }
+    }
+
+    /// Delivers `payload` to everyone subscribed to `channel` directly or via
+    /// a matching pattern, returning the number of receivers reached.
+    pub fn publish(&self, channel: Bytes, payload: Bytes) -> usize {
+        self.history.record(&channel, &payload);
+        self.deliver(channel, payload)
+    }
+
+    /// The last `count` messages published on `channel`, or all those kept,
+    /// oldest first.
+    pub fn history(&self, channel: &[u8], count: Option<usize>) -> Vec<Bytes> {
+        self.history.last(channel, count)
+    }
+
+    /// Publishes without keeping the message, as for keyspace
+    /// notifications, which would keep some for every key.
+    fn deliver(&self, channel: Bytes, payload: Bytes) -> usize {
+        let message = Message { channel, payload };
+
+        let mut receivers = 0;
+        if let Some(sender) = self.channels.lock().unwrap().get(&message.channel) {
+            receivers += sender.send(message.clone()).unwrap_or(0);
+        }
+        for (pattern, sender) in self.patterns.lock().unwrap().iter() {
+            if glob::matches(pattern, &message.channel) {
+                receivers += sender.send(message.clone()).unwrap_or(0);
+            }
+        }
+        receivers
+    }
+
+    fn subscribe(&self, channel: &Bytes) -> broadcast::Receiver<Message> {
+        Self::receiver(&self.channels, channel)
+    }
+
+    fn psubscribe(&self, pattern: &Bytes) -> broadcast::Receiver<Message> {
+        Self::receiver(&self.patterns, pattern)
+    }
+
+    fn receiver(
+        senders: &Mutex<HashMap<Bytes, broadcast::Sender<Message>>>,
+        name: &Bytes,
+    ) -> broadcast::Receiver<Message> {
+        senders
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Drops the sender for `name` if its last receiver went away.
+    fn release(senders: &Mutex<HashMap<Bytes, broadcast::Sender<Message>>>, name: &[u8]) {
+        let mut senders = senders.lock().unwrap();
+        if senders
+            .get(name)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            senders.remove(name);
+        }
+    }
+}
+
+/// Subscription state of a single connection, polling one broadcast receiver
+/// per channel and per pattern.
+pub struct Subscriber {
+    broker: Arc<Broker>,
+    channels: StreamMap<Bytes, BroadcastStream<Message>>,
+    patterns: StreamMap<Bytes, BroadcastStream<Message>>,
+}
+
+impl Subscriber {
+    pub fn new(broker: Arc<Broker>) -> Self {
+        Subscriber {
+            broker,
+            channels: StreamMap::new(),
+            patterns: StreamMap::new(),
+        }
+    }
+
+    /// Total number of channel and pattern subscriptions.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Whether the connection is in subscribe mode.
+    pub fn is_active(&self) -> bool {
+        self.count() > 0
+    }
+
+    /// Waits for the next message, formatted as a push reply. Never resolves
+    /// while there are no subscriptions.
+    pub async fn recv(&mut self) -> RespValue {
+        loop {
+            let next = tokio::select! {
+                Some((_, message)) = self.channels.next() => message.map(|m| (None, m)),
+                Some((pattern, message)) = self.patterns.next() => {
+                    message.map(|m| (Some(pattern), m))
+                }
+                else => std::future::pending().await,
+            };
+            // Slow subscribers miss messages rather than stall publishers.
+            let Ok((pattern, message)) = next else {
+                continue;
+            };
+            return match pattern {
+                Some(pattern) => RespValue::Push(vec![
+                    RespValue::bulk("pmessage"),
+                    RespValue::bulk(pattern),
+                    RespValue::bulk(message.channel),
+                    RespValue::bulk(message.payload),
+                ]),
+                None => RespValue::Push(vec![
+                    RespValue::bulk("message"),
+                    RespValue::bulk(message.channel),
+                    RespValue::bulk(message.payload),
+                ]),
+            };
+        }
+    }
+
+    pub fn subscribe(&mut self, channels: Vec<Bytes>) -> Vec<RespValue> {
+        let mut replies = Vec::with_capacity(channels.len());
+        for channel in channels {
+            if !self.channels.contains_key(&channel) {
+                let rx = self.broker.subscribe(&channel);
+                self.channels
+                    .insert(channel.clone(), BroadcastStream::new(rx));
+            }
+            replies.push(confirmation("subscribe", Some(channel), self.count()));
+        }
+        replies
+    }
+
+    pub fn psubscribe(&mut self, patterns: Vec<Bytes>) -> Vec<RespValue> {
+        let mut replies = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            if !self.patterns.contains_key(&pattern) {
+                let rx = self.broker.psubscribe(&pattern);
+                self.patterns
+                    .insert(pattern.clone(), BroadcastStream::new(rx));
+            }
+            replies.push(confirmation("psubscribe", Some(pattern), self.count()));
+        }
+        replies
+    }
+
+    /// Unsubscribes from `channels`, or from every channel if empty.
+    pub fn unsubscribe(&mut self, channels: Vec<Bytes>) -> Vec<RespValue> {
+        let channels = if channels.is_empty() {
+            self.channels.keys().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            return vec![confirmation("unsubscribe", None, self.count())];
+        }
+
+        let mut replies = Vec::with_capacity(channels.len());
+        for channel in channels {
+            if self.channels.remove(&channel).is_some() {
+                Broker::release(&self.broker.channels, &channel);
+            }
+            replies.push(confirmation("unsubscribe", Some(channel), self.count()));
+        }
+        replies
+    }
+
+    /// Unsubscribes from `patterns`, or from every pattern if empty.
+    pub fn punsubscribe(&mut self, patterns: Vec<Bytes>) -> Vec<RespValue> {
+        let patterns = if patterns.is_empty() {
+            self.patterns.keys().cloned().collect()
+        } else {
+            patterns
+        };
+        if patterns.is_empty() {
+            return vec![confirmation("punsubscribe", None, self.count())];
+        }
+
+        let mut replies = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            if self.patterns.remove(&pattern).is_some() {
+                Broker::release(&self.broker.patterns, &pattern);
+            }
+            replies.push(confirmation("punsubscribe", Some(pattern), self.count()));
+        }
+        replies
+    }
+
+    /// Drops every subscription without confirmations, as on `RESET`.
+    pub fn reset(&mut self) {
+        self.unsubscribe(vec![]);
+        self.punsubscribe(vec![]);
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+/// `[kind, name, count]` push sent for each (un)subscribe.
+fn confirmation(kind: &'static str, name: Option<Bytes>, count: usize) -> RespValue {
+    RespValue::Push(vec![
+        RespValue::bulk(kind),
+        RespValue::BulkString(name),
+        RespValue::Integer(count as i64),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let broker = Arc::new(Broker::new());
+        let mut subscriber = Subscriber::new(broker.clone());
+
+        let replies = subscriber.subscribe(vec![Bytes::from("news"), Bytes::from("sports")]);
+        assert_eq!(
+            replies[1],
+            confirmation("subscribe", Some(Bytes::from("sports")), 2)
+        );
+
+        assert_eq!(broker.publish("news".into(), "hello".into()), 1);
+        assert_eq!(broker.publish("weather".into(), "sunny".into()), 0);
+        assert_eq!(
+            subscriber.recv().await,
+            RespValue::Push(vec![
+                RespValue::bulk("message"),
+                RespValue::bulk("news"),
+                RespValue::bulk("hello")
+            ])
+        );
+
+        let replies = subscriber.unsubscribe(vec![]);
+        assert_eq!(replies.len(), 2);
+        assert!(!subscriber.is_active());
+        assert_eq!(broker.publish("news".into(), "hello".into()), 0);
+        assert!(broker.channels.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pattern_subscribe() {
+        let broker = Arc::new(Broker::new());
+        let mut subscriber = Subscriber::new(broker.clone());
+        subscriber.psubscribe(vec![Bytes::from("news.*")]);
+
+        assert_eq!(broker.publish("news.tech".into(), "rust".into()), 1);
+        assert_eq!(broker.publish("sports.tech".into(), "rust".into()), 0);
+        assert_eq!(
+            subscriber.recv().await,
+            RespValue::Push(vec![
+                RespValue::bulk("pmessage"),
+                RespValue::bulk("news.*"),
+                RespValue::bulk("news.tech"),
+                RespValue::bulk("rust")
+            ])
+        );
+
+        assert_eq!(
+            subscriber.punsubscribe(vec![]),
+            vec![confirmation("punsubscribe", Some(Bytes::from("news.*")), 0)]
+        );
+        assert_eq!(
+            subscriber.punsubscribe(vec![]),
+            vec![confirmation("punsubscribe", None, 0)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_counts_span_channels_and_patterns() {
+        let broker = Arc::new(Broker::new());
+        let mut subscriber = Subscriber::new(broker.clone());
+        let channels = vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("a")];
+        let counts = |replies: Vec<RespValue>| -> Vec<RespValue> {
+            replies
+                .into_iter()
+                .map(|reply| match reply {
+                    RespValue::Push(mut parts) => parts.pop().unwrap(),
+                    other => other,
+                })
+                .collect()
+        };
+        assert_eq!(
+            counts(subscriber.subscribe(channels)),
+            vec![
+                RespValue::Integer(1),
+                RespValue::Integer(2),
+                RespValue::Integer(2)
+            ]
+        );
+        subscriber.psubscribe(vec![Bytes::from("c*")]);
+        // Unsubscribing from all channels still counts the pattern.
+        assert_eq!(
+            counts(subscriber.unsubscribe(vec![])),
+            vec![RespValue::Integer(2), RespValue::Integer(1)]
+        );
+
+        subscriber.subscribe(vec![Bytes::from("a")]);
+        subscriber.reset();
+        assert!(!subscriber.is_active());
+        assert_eq!(broker.publish("a".into(), "m".into()), 0);
+        assert!(broker.patterns.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_history() {
+        let broker = Broker::with_history(PubSubHistoryConfig {
+            messages: 3,
+            max_size: 10,
+            channels: vec!["news.*".to_string()],
+        });
+        for payload in ["a", "b", "c", "d"] {
+            broker.publish("news.tech".into(), payload.into());
+        }
+        broker.publish("sports".into(), "e".into());
+        assert_eq!(broker.history(b"news.tech", None), ["b", "c", "d"]);
+        assert_eq!(broker.history(b"news.tech", Some(2)), ["c", "d"]);
+        assert_eq!(broker.history(b"news.tech", Some(0)), Vec::<Bytes>::new());
+        assert!(broker.history(b"sports", None).is_empty());
+
+        // Messages past `max_size` push out the older ones, and one too big
+        // to keep at all empties the channel's history.
+        broker.publish("news.tech".into(), "efghijkl".into());
+        assert_eq!(broker.history(b"news.tech", None), ["c", "d", "efghijkl"]);
+        broker.publish("news.tech".into(), "too long to keep".into());
+        assert!(broker.history(b"news.tech", None).is_empty());
+
+        // Keyspace notifications aren't kept.
+        broker.deliver("news.keys".into(), "set".into());
+        assert!(broker.history(b"news.keys", None).is_empty());
+    }
+}
@@ -0,0 +1,371 @@
+//! The replica's side: syncing from the primary and applying its stream
+use super::{LinkState, Replication};
+use crate::commands::{execute_locked, Command};
+use crate::connection::{ClientRegistry, ConnCtx};
+use crate::protocol::{parse_resp, RespError, RespValue};
+use crate::storage::{rdb, Db, Deadline, RdbError};
+use bytes::{Buf, Bytes, BytesMut};
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the replica reports its offset with `REPLCONF ACK`.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Error, Debug)]
+pub enum LinkError {
+    #[error("connection closed by primary")]
+    Closed,
+    #[error("unexpected reply from primary: {0:?}")]
+    Unexpected(RespValue),
+    #[error("invalid data from primary")]
+    Protocol,
+    #[error("invalid snapshot from primary: {0}")]
+    Rdb(#[from] RdbError),
+    #[cfg(feature = "tls")]
+    #[error(transparent)]
+    Tls(#[from] super::tls::TlsError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Keeps the dataset in sync with the primary at `host:port`, reconnecting
+/// after errors, until the task is aborted.
+pub(super) async fn run(replication: Arc<Replication>, db: Db, host: String, port: u16) {
+    loop {
+        replication.set_link(LinkState::Connecting);
+        if let Err(e) = connect(&replication, &db, &host, port).await {
+            warn!("Replication from {}:{} failed: {}", host, port, e);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connects to the primary, over TLS if so configured, and syncs from it.
+async fn connect(
+    replication: &Replication,
+    db: &Db,
+    host: &str,
+    port: u16,
+) -> Result<(), LinkError> {
+    #[cfg(feature = "tls")]
+    if replication.config.tls.connect {
+        let config = &replication.config.tls;
+        let connector = super::tls::TlsFiles::from_config(config)?.connector()?;
+        let name = config.server_name.as_deref().unwrap_or(host);
+        let stream = super::tls::connect(&connector, host, port, name).await?;
+        return sync(replication, db, stream).await;
+    }
+    sync(replication, db, TcpStream::connect((host, port)).await?).await
+}
+
+/// One session with the primary: handshake, full sync, then the command
+/// stream until the connection breaks.
+async fn sync<S>(replication: &Replication, db: &Db, stream: S) -> Result<(), LinkError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut primary = PrimaryStream::new(stream);
+    let config = &replication.config;
+    let password = config.masterauth.as_ref().map(|password| password.expose());
+    primary
+        .handshake(
+            config.masteruser.as_deref(),
+            password,
+            replication.listening_port,
+        )
+        .await?;
+
+    replication.set_link(LinkState::Syncing);
+    let (replid, offset) = primary.psync().await?;
+    let keys = primary
+        .load_snapshot(db, || replication.finish_sync(replid, offset))
+        .await?;
+    info!("Synced {} keys from the primary", keys);
+
+    let clients = ClientRegistry::new();
+    let mut ack = tokio::time::interval(ACK_INTERVAL);
+    // The database the stream's last `SELECT` picked, kept across batches.
+    let mut selected = 0;
+    loop {
+        tokio::select! {
+            // Input first: a primary that hung up is reported as such, not
+            // as an ACK that failed to send.
+            biased;
+            read = primary.fill() => read?,
+            _ = ack.tick() => {
+                primary.send_ack(replication.offset()).await?;
+                continue;
+            }
+        }
+        replication.touch_link();
+
+        // Passed on to replicas of this server as is.
+        let getack = primary
+            .apply(db, &clients, &mut selected, |raw| replication.feed(raw))
+            .await?;
+        if getack {
+            primary.send_ack(replication.offset()).await?;
+        }
+    }
+}
+
+fn expect_ok(reply: RespValue) -> Result<(), LinkError> {
+    match reply {
+        RespValue::SimpleString(s) if s == "OK" => Ok(()),
+        other => Err(LinkError::Unexpected(other)),
+    }
+}
+
+/// Parses `FULLRESYNC <replid> <offset>`.
+fn parse_fullresync(reply: &str) -> Option<(String, u64)> {
+    let mut parts = reply.split(' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset), None) => {
+            Some((replid.to_string(), offset.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// The connection to the primary, with whatever was read but not consumed.
+pub(super) struct PrimaryStream<S> {
+    stream: S,
+    buffer: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PrimaryStream<S> {
+    pub(super) fn new(stream: S) -> Self {
+        PrimaryStream {
+            stream,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    /// Authenticates, if there's a password, and introduces this server as
+    /// a replica listening on `port`.
+    pub(super) async fn handshake(
+        &mut self,
+        user: Option<&str>,
+        password: Option<&str>,
+        port: u16,
+    ) -> Result<(), LinkError> {
+        if let Some(password) = password {
+            self.auth(user, password).await?;
+        }
+        match self.call(&[b"PING"]).await? {
+            RespValue::SimpleString(_) => {}
+            other => return Err(LinkError::Unexpected(other)),
+        }
+        let port = port.to_string();
+        expect_ok(
+            self.call(&[b"REPLCONF", b"listening-port", port.as_bytes()])
+                .await?,
+        )
+    }
+
+    pub(super) async fn auth(
+        &mut self,
+        user: Option<&str>,
+        password: &str,
+    ) -> Result<(), LinkError> {
+        let mut args = vec![&b"AUTH"[..]];
+        if let Some(user) = user {
+            args.push(user.as_bytes());
+        }
+        args.push(password.as_bytes());
+        expect_ok(self.call(&args).await?)
+    }
+
+    /// Asks for a full sync, returning the replication id and offset the
+    /// stream starts at. The snapshot follows.
+    pub(super) async fn psync(&mut self) -> Result<(String, u64), LinkError> {
+        match self.call(&[b"PSYNC", b"?", b"-1"]).await? {
+            RespValue::SimpleString(reply) => parse_fullresync(&reply).ok_or(LinkError::Protocol),
+            other => Err(LinkError::Unexpected(other)),
+        }
+    }
+
+    /// Reads the snapshot and loads it in place of the dataset, calling
+    /// `loaded` before the shards are unlocked. Returns the keys loaded.
+    pub(super) async fn load_snapshot(
+        &mut self,
+        db: &Db,
+        loaded: impl FnOnce(),
+    ) -> Result<usize, LinkError> {
+        let snapshot = rdb::read(&self.read_payload().await?, db.databases())?;
+        let keys = snapshot.databases.iter().map(Vec::len).sum();
+        let mut store = db.lock_all().await;
+        store.replace_dataset(snapshot.databases);
+        db.load_scripts(snapshot.scripts);
+        loaded();
+        Ok(keys)
+    }
+
+    /// Runs the complete commands buffered, starting in database
+    /// `selected` and leaving it at the one the last of them ran in, and
+    /// hands `applied` each one as received. Returns whether the primary
+    /// asked for an ACK.
+    pub(super) async fn apply(
+        &mut self,
+        db: &Db,
+        clients: &ClientRegistry,
+        selected: &mut usize,
+        mut applied: impl FnMut(&Bytes),
+    ) -> Result<bool, LinkError> {
+        let mut getack = false;
+        let mut store = db.lock_all().await;
+        store
+            .select(*selected)
+            .expect("a database the stream selected before");
+        while let Some((frame, raw)) = self.next_frame()? {
+            match Command::from_frame(frame) {
+                Ok(Command::ReplConf(args))
+                    if args
+                        .first()
+                        .is_some_and(|arg| arg.eq_ignore_ascii_case(b"GETACK")) =>
+                {
+                    getack = true;
+                }
+                Ok(command) => {
+                    let conn = ConnCtx::default();
+                    execute_locked(command, &mut store, &conn, clients, Deadline::after(None));
+                }
+                Err(e) => warn!("Ignoring an invalid command from the primary: {}", e),
+            }
+            applied(&raw);
+        }
+        *selected = store.selected();
+        Ok(getack)
+    }
+
+    async fn send(&mut self, args: &[&[u8]]) -> Result<(), LinkError> {
+        let frame = RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::bulk(Bytes::copy_from_slice(arg)))
+                .collect(),
+        );
+        self.stream.write_all(&frame.serialize()).await?;
+        Ok(())
+    }
+
+    pub(super) async fn send_ack(&mut self, offset: u64) -> Result<(), LinkError> {
+        let offset = offset.to_string();
+        self.send(&[b"REPLCONF", b"ACK", offset.as_bytes()]).await
+    }
+
+    /// Sends a command and waits for its reply.
+    pub(super) async fn call(&mut self, args: &[&[u8]]) -> Result<RespValue, LinkError> {
+        self.send(args).await?;
+        loop {
+            if let Some((reply, _)) = self.next_frame()? {
+                return Ok(reply);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads more input. Cancel safe.
+    pub(super) async fn fill(&mut self) -> Result<(), LinkError> {
+        match self.stream.read_buf(&mut self.buffer).await? {
+            0 => Err(LinkError::Closed),
+            _ => Ok(()),
+        }
+    }
+
+    /// The next complete frame with its raw bytes, if one is buffered.
+    fn next_frame(&mut self) -> Result<Option<(RespValue, Bytes)>, LinkError> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        match parse_resp(&self.buffer) {
+            Ok((frame, len)) => Ok(Some((frame, self.buffer.split_to(len).freeze()))),
+            Err(RespError::Incomplete) => Ok(None),
+            Err(_) => Err(LinkError::Protocol),
+        }
+    }
+
+    /// Reads the snapshot: `$<len>\r\n` followed by the RDB bytes, with no
+    /// trailing CRLF.
+    async fn read_payload(&mut self) -> Result<Bytes, LinkError> {
+        let len = loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let header = std::str::from_utf8(&self.buffer[..end]).ok();
+                let len = header
+                    .and_then(|h| h.strip_prefix('$'))
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .ok_or(LinkError::Protocol)?;
+                self.buffer.advance(end + 2);
+                break len;
+            }
+            self.fill().await?;
+        };
+        while self.buffer.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(len).freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ReplicationConfig, StorageConfig};
+    use crate::storage::{Shards, Value};
+
+    #[tokio::test]
+    async fn test_full_sync_then_stream() {
+        let replication = Arc::new(Replication::new(ReplicationConfig::default(), 6380));
+        let (replica, mut primary) = tokio::io::duplex(64 * 1024);
+        let db: Db = Arc::new(Shards::new(StorageConfig::default()));
+        db.lock_all()
+            .await
+            .shard_mut(b"stale")
+            .insert("stale".into(), "v".into());
+        let task = {
+            let (replication, db) = (replication.clone(), db.clone());
+            tokio::spawn(async move { sync(&replication, &db, replica).await })
+        };
+
+        let key = Bytes::from("k");
+        let value = Value::String("v".into());
+        let mut snapshot = Vec::new();
+        rdb::write(&mut snapshot, &[], [[(&key, &value)].into_iter()]).unwrap();
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nx\r\n$1\r\ny\r\n";
+
+        let mut script = b"+PONG\r\n+OK\r\n+FULLRESYNC abc 100\r\n".to_vec();
+        script.extend(format!("${}\r\n", snapshot.len()).as_bytes());
+        script.extend(&snapshot);
+        script.extend(set);
+        script.extend(b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n");
+        primary.write_all(&script).await.unwrap();
+
+        // The handshake, then an ACK covering both frames of the stream.
+        let expected_ack = (100 + set.len() + 37).to_string();
+        let mut received = Vec::new();
+        while !received.ends_with(format!("{}\r\n", expected_ack).as_bytes()) {
+            let mut chunk = [0; 1024];
+            let n = primary.read(&mut chunk).await.unwrap();
+            received.extend(&chunk[..n]);
+        }
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("*1\r\n$4\r\nPING\r\n"));
+        assert!(received.contains("$14\r\nlistening-port\r\n$4\r\n6380\r\n"));
+        assert!(received.contains("$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n"));
+
+        let store = db.lock_all().await;
+        assert_eq!(store.key_count(), 2);
+        assert_eq!(store.shard(b"k").get(b"k").unwrap().unwrap(), "v");
+        assert_eq!(store.shard(b"x").get(b"x").unwrap().unwrap(), "y");
+        assert!(replication.info().contains("master_replid:abc\r\n"));
+        drop(store);
+
+        drop(primary);
+        assert!(matches!(task.await.unwrap(), Err(LinkError::Closed)));
+    }
+}
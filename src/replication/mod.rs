@@ -0,0 +1,365 @@
+//! Primary/replica replication
+//!
+//! A replica connects to its primary like any client and sends `PSYNC`. The
+//! primary answers with a snapshot of its dataset in RDB format, then streams
+//! every write it runs from the moment the snapshot was taken. Both sides
+//! count the bytes of that stream as the replication offset. Only full
+//! resynchronization is supported: a replica that reconnects always starts
+//! over from a new snapshot.
+mod import;
+mod link;
+#[cfg(feature = "tls")]
+mod tls;
+
+pub use import::{import, ImportError, ImportSource};
+#[cfg(feature = "tls")]
+pub use tls::{accept_replicas, TlsError, TlsFiles};
+
+use crate::commands::select_frame;
+use crate::config::ReplicationConfig;
+use crate::storage::Db;
+use bytes::Bytes;
+use log::{info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+
+/// Stream frames queued for a replica before it counts as unable to keep up
+/// and is disconnected; it then reconnects and resyncs from a new snapshot.
+const REPLICA_QUEUE_FRAMES: usize = 64 * 1024;
+
+/// Role and stream state of this server, shared by the connections serving
+/// replicas and the link to the primary.
+pub struct Replication {
+    config: ReplicationConfig,
+    /// Port this server announces to its primary as a replica.
+    listening_port: u16,
+    state: Mutex<State>,
+}
+
+struct State {
+    role: Role,
+    replid: String,
+    /// Bytes of the replication stream produced, or applied on a replica.
+    offset: u64,
+    replicas: Vec<Replica>,
+    next_id: u64,
+    link: Option<JoinHandle<()>>,
+    /// Database the stream's commands run in from here on; `None` when the
+    /// next write must select one to be sure.
+    db: Option<usize>,
+}
+
+impl State {
+    fn replica(&mut self, id: u64) -> Option<&mut Replica> {
+        self.replicas.iter_mut().find(|replica| replica.id == id)
+    }
+
+    /// Queues `frame` for every replica, dropping those that can't keep up.
+    fn send(&mut self, frame: &[u8]) {
+        self.offset += frame.len() as u64;
+        if self.replicas.is_empty() {
+            return;
+        }
+        let frame = Bytes::copy_from_slice(frame);
+        self.replicas
+            .retain(|replica| match replica.frames.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Disconnecting replica {}:{}, which fell too far behind",
+                        replica.ip, replica.port
+                    );
+                    false
+                }
+                Err(TrySendError::Closed(_)) => {
+                    info!("Replica {}:{} disconnected", replica.ip, replica.port);
+                    false
+                }
+            });
+    }
+}
+
+enum Role {
+    Primary,
+    Replica {
+        host: String,
+        port: u16,
+        link: LinkState,
+        last_io: Option<Instant>,
+    },
+}
+
+/// Progress of a replica's connection to its primary.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LinkState {
+    Connecting,
+    Syncing,
+    Up,
+}
+
+struct Replica {
+    id: u64,
+    ip: String,
+    port: u16,
+    /// Whether the snapshot has been sent and the stream is flowing.
+    online: bool,
+    acked: u64,
+    last_ack: Instant,
+    frames: mpsc::Sender<Bytes>,
+}
+
+/// The stream for one replica, drained by the connection serving it.
+pub struct ReplicaFeed {
+    pub id: u64,
+    frames: mpsc::Receiver<Bytes>,
+}
+
+impl ReplicaFeed {
+    /// The next stream frame, or `None` once the replica was dropped for
+    /// falling behind.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.frames.recv().await
+    }
+}
+
+impl Replication {
+    pub fn new(config: ReplicationConfig, listening_port: u16) -> Self {
+        Replication {
+            config,
+            listening_port,
+            state: Mutex::new(State {
+                role: Role::Primary,
+                replid: new_replid(),
+                offset: 0,
+                replicas: Vec::new(),
+                next_id: 0,
+                link: None,
+                db: None,
+            }),
+        }
+    }
+
+    pub fn is_replica(&self) -> bool {
+        matches!(self.state.lock().unwrap().role, Role::Replica { .. })
+    }
+
+    pub fn has_replicas(&self) -> bool {
+        !self.state.lock().unwrap().replicas.is_empty()
+    }
+
+    /// Disconnects from the primary, if following one, for shutdown.
+    pub fn stop(&self) {
+        if let Some(link) = self.state.lock().unwrap().link.take() {
+            link.abort();
+        }
+    }
+
+    /// Follows the primary at `host:port`, or with `None` stops following
+    /// and becomes a primary. Replicas of this server are disconnected when
+    /// it starts following a new primary, so they resync from its data.
+    pub fn replicate(self: &Arc<Self>, primary: Option<(String, u16)>, db: Db) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(link) = state.link.take() {
+            link.abort();
+        }
+        state.db = None;
+        match primary {
+            Some((host, port)) => {
+                info!("Replicating from {}:{}", host, port);
+                state.replicas.clear();
+                state.role = Role::Replica {
+                    host: host.clone(),
+                    port,
+                    link: LinkState::Connecting,
+                    last_io: None,
+                };
+                state.link = Some(tokio::spawn(link::run(self.clone(), db, host, port)));
+            }
+            None => {
+                if matches!(state.role, Role::Replica { .. }) {
+                    info!("Promoted to primary");
+                    // A new history starts here.
+                    state.replid = new_replid();
+                }
+                state.role = Role::Primary;
+            }
+        }
+    }
+
+    /// Adds a replica receiving every frame fed from now on, returning its
+    /// feed plus the replication id and offset the stream starts at. Must
+    /// be called with the storage locked, while taking the snapshot the
+    /// replica starts from, so no write falls in between.
+    pub fn register(&self, ip: String, port: u16) -> (ReplicaFeed, String, u64) {
+        let mut state = self.state.lock().unwrap();
+        let (sender, frames) = mpsc::channel(REPLICA_QUEUE_FRAMES);
+        let id = state.next_id;
+        state.next_id += 1;
+        // The snapshot doesn't tell the replica which database is selected.
+        state.db = None;
+        state.replicas.push(Replica {
+            id,
+            ip,
+            port,
+            online: false,
+            acked: 0,
+            last_ack: Instant::now(),
+            frames: sender,
+        });
+        (
+            ReplicaFeed { id, frames },
+            state.replid.clone(),
+            state.offset,
+        )
+    }
+
+    /// Records that replica `id` got its snapshot.
+    pub fn mark_online(&self, id: u64) {
+        if let Some(replica) = self.state.lock().unwrap().replica(id) {
+            replica.online = true;
+        }
+    }
+
+    /// Records a replica's `REPLCONF ACK`.
+    pub fn ack(&self, id: u64, offset: u64) {
+        if let Some(replica) = self.state.lock().unwrap().replica(id) {
+            replica.acked = offset;
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    /// Appends a serialized write run in database `db` to the stream,
+    /// selecting the database first if the stream is in another one.
+    pub fn feed_write(&self, db: usize, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        if state.db != Some(db) {
+            state.db = Some(db);
+            state.send(&select_frame(db).serialize());
+        }
+        state.send(frame);
+    }
+
+    /// Appends serialized commands to the stream as they are. Called with
+    /// the storage locked, so replicas see writes in the order they ran.
+    pub fn feed(&self, frame: &[u8]) {
+        self.state.lock().unwrap().send(frame);
+    }
+
+    fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    fn set_link(&self, new: LinkState) {
+        if let Role::Replica { link, .. } = &mut self.state.lock().unwrap().role {
+            *link = new;
+        }
+    }
+
+    /// Records data received from the primary.
+    fn touch_link(&self) {
+        if let Role::Replica { last_io, .. } = &mut self.state.lock().unwrap().role {
+            *last_io = Some(Instant::now());
+        }
+    }
+
+    /// Adopts the primary's history after loading its snapshot.
+    fn finish_sync(&self, replid: String, offset: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replid = replid;
+        state.offset = offset;
+        if let Role::Replica { link, last_io, .. } = &mut state.role {
+            *link = LinkState::Up;
+            *last_io = Some(Instant::now());
+        }
+    }
+
+    /// Formats the `# Replication` section of an `INFO` reply, using the
+    /// field names Redis clients expect.
+    pub fn info(&self) -> String {
+        let mut state = self.state.lock().unwrap();
+        // Count only replicas that are still connected.
+        state.replicas.retain(|replica| !replica.frames.is_closed());
+        let mut info = String::from("# Replication\r\n");
+        match &state.role {
+            Role::Primary => info.push_str("role:master\r\n"),
+            Role::Replica {
+                host,
+                port,
+                link,
+                last_io,
+            } => info.push_str(&format!(
+                "role:slave\r\n\
+                master_host:{}\r\n\
+                master_port:{}\r\n\
+                master_link_status:{}\r\n\
+                master_last_io_seconds_ago:{}\r\n\
+                master_sync_in_progress:{}\r\n\
+                slave_repl_offset:{}\r\n",
+                host,
+                port,
+                if *link == LinkState::Up { "up" } else { "down" },
+                last_io.map_or(-1, |at| at.elapsed().as_secs() as i64),
+                (*link == LinkState::Syncing) as u8,
+                state.offset,
+            )),
+        }
+        info.push_str(&format!("connected_slaves:{}\r\n", state.replicas.len()));
+        for (i, replica) in state.replicas.iter().enumerate() {
+            info.push_str(&format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i,
+                replica.ip,
+                replica.port,
+                if replica.online {
+                    "online"
+                } else {
+                    "wait_bgsave"
+                },
+                replica.acked,
+                replica.last_ack.elapsed().as_secs(),
+            ));
+        }
+        info.push_str(&format!(
+            "master_replid:{}\r\nmaster_repl_offset:{}\r\n",
+            state.replid, state.offset
+        ));
+        info
+    }
+}
+
+/// A random 40 character hex id naming a replication history.
+fn new_replid() -> String {
+    let mut bytes = [0u8; 20];
+    getrandom::getrandom(&mut bytes).expect("failed to generate a replication id");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_feed_reaches_registered_replicas() {
+        let replication = Replication::new(ReplicationConfig::default(), 6379);
+        replication.feed(b"before");
+        let (mut feed, replid, offset) = replication.register("127.0.0.1".to_string(), 6380);
+        assert_eq!(replid.len(), 40);
+        assert_eq!(offset, 6);
+
+        replication.feed(b"after");
+        assert_eq!(feed.recv().await.unwrap(), "after");
+        replication.mark_online(feed.id);
+        replication.ack(feed.id, 11);
+        let info = replication.info();
+        assert!(info.contains("role:master\r\n"));
+        assert!(info.contains("slave0:ip=127.0.0.1,port=6380,state=online,offset=11,lag=0\r\n"));
+        assert!(info.contains("master_repl_offset:11\r\n"));
+
+        // A replica that went away is dropped on the next write.
+        drop(feed);
+        replication.feed(b"x");
+        assert!(!replication.has_replicas());
+    }
+}
@@ -0,0 +1,272 @@
+//! TLS on the replication link, for primaries and replicas talking across
+//! networks nobody trusts
+//!
+//! The encryption is done in process by rustls. A replica connects to its
+//! primary over TLS and speaks the usual protocol through the session. A
+//! primary accepts replicas on a listener of its own and hands each session
+//! on, decrypted, to a plain connection to the server's client port, so the
+//! replica is served like any other. Each end presents its certificate and
+//! checks the other's against the CA, so neither talks to a stranger; the
+//! replica also checks the name on the primary's.
+//!
+//! Seen from the primary, replicas on TLS connect from the loopback
+//! address, so that's where `INFO replication` says they are.
+use crate::config::ReplicationTlsConfig;
+use log::{debug, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::{self, PemObject};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use tokio_rustls::rustls::{self, ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// How long a replica gets to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum TlsError {
+    #[error("replication.tls.{0} must be set for TLS")]
+    Missing(&'static str),
+    #[error("failed to read {}: {1}", .0.display())]
+    Pem(PathBuf, pem::Error),
+    #[error("no certificates in {}", .0.display())]
+    NoCertificates(PathBuf),
+    #[error("invalid CA certificates: {0}")]
+    Verifier(#[from] VerifierBuilderError),
+    #[error("invalid server name '{0}'")]
+    ServerName(String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The files each end proves itself and checks the other with.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    ca: PathBuf,
+}
+
+impl TlsFiles {
+    pub fn from_config(config: &ReplicationTlsConfig) -> Result<Self, TlsError> {
+        let file = |path: &Option<PathBuf>, name| path.clone().ok_or(TlsError::Missing(name));
+        Ok(TlsFiles {
+            cert: file(&config.cert_file, "cert_file")?,
+            key: file(&config.key_file, "key_file")?,
+            ca: file(&config.ca_file, "ca_file")?,
+        })
+    }
+
+    /// The replica's end: presents the certificate and refuses primaries
+    /// whose own doesn't check out.
+    pub fn connector(&self) -> Result<TlsConnector, TlsError> {
+        let key = self.private_key()?;
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(self.roots()?)
+            .with_client_auth_cert(certificates(&self.cert)?, key)?;
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// The primary's end: presents the certificate and refuses replicas
+    /// without one that checks out.
+    pub fn acceptor(&self) -> Result<TlsAcceptor, TlsError> {
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(self.roots()?), provider())
+                .build()?;
+        let key = self.private_key()?;
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certificates(&self.cert)?, key)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn roots(&self) -> Result<RootCertStore, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in certificates(&self.ca)? {
+            roots.add(cert)?;
+        }
+        Ok(roots)
+    }
+
+    fn private_key(&self) -> Result<PrivateKeyDer<'static>, TlsError> {
+        PrivateKeyDer::from_pem_file(&self.key).map_err(|e| TlsError::Pem(self.key.clone(), e))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// The certificates in the PEM file at `path`; none is an error.
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Pem(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Connects to the primary at `host:port` over TLS, expecting its
+/// certificate to be for `server_name`.
+pub(super) async fn connect(
+    connector: &TlsConnector,
+    host: &str,
+    port: u16,
+    server_name: &str,
+) -> Result<TlsStream<TcpStream>, TlsError> {
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|_| TlsError::ServerName(server_name.to_string()))?;
+    let socket = TcpStream::connect((host, port)).await?;
+    Ok(connector.connect(name, socket).await?)
+}
+
+/// Accepts replicas over TLS on `listener`, handing each on in the clear to
+/// the server's client port at `server`.
+pub async fn accept_replicas(listener: TcpListener, acceptor: TlsAcceptor, server: SocketAddr) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Failed to accept a TLS replication connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            debug!("TLS replication connection from {}", addr);
+            if let Err(e) = terminate(socket, &acceptor, server).await {
+                warn!("TLS replication connection from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Runs one replica's TLS session until either side hangs up.
+async fn terminate(
+    socket: TcpStream,
+    acceptor: &TlsAcceptor,
+    server: SocketAddr,
+) -> io::Result<()> {
+    let mut replica = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+    let mut client = TcpStream::connect(server).await?;
+    tokio::io::copy_bidirectional(&mut replica, &mut client).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A CA and a certificate signed by it for each end, in `dir`.
+    fn make_certs(dir: &Path) -> TlsFiles {
+        std::fs::create_dir_all(dir).unwrap();
+        let ca_key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = params.self_signed(&ca_key).unwrap();
+        let node_key = KeyPair::generate().unwrap();
+        let node = CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+            .unwrap()
+            .signed_by(&node_key, &ca, &ca_key)
+            .unwrap();
+        let files = TlsFiles {
+            cert: dir.join("node.pem"),
+            key: dir.join("node.key"),
+            ca: dir.join("ca.pem"),
+        };
+        std::fs::write(&files.cert, node.pem()).unwrap();
+        std::fs::write(&files.key, node_key.serialize_pem()).unwrap();
+        std::fs::write(&files.ca, ca.pem()).unwrap();
+        files
+    }
+
+    #[test]
+    fn test_files_are_required() {
+        let config = ReplicationTlsConfig {
+            cert_file: Some("node.pem".into()),
+            key_file: Some("node.key".into()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            TlsFiles::from_config(&config),
+            Err(TlsError::Missing("ca_file"))
+        ));
+        let files = TlsFiles::from_config(&ReplicationTlsConfig {
+            ca_file: Some("/nonexistent/ca.pem".into()),
+            ..config
+        })
+        .unwrap();
+        assert!(matches!(files.acceptor(), Err(TlsError::Pem(..))));
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_through_tls() {
+        let dir = std::env::temp_dir().join(format!("rdb-tls-test-{}", std::process::id()));
+        let files = make_certs(&dir);
+        let strange = make_certs(&dir.join("strange"));
+
+        // Stands in for the server's client port.
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tls_port = tls.local_addr().unwrap().port();
+        tokio::spawn(accept_replicas(
+            tls,
+            files.acceptor().unwrap(),
+            server.local_addr().unwrap(),
+        ));
+
+        let connector = files.connector().unwrap();
+        let mut replica = connect(&connector, "127.0.0.1", tls_port, "localhost")
+            .await
+            .unwrap();
+        let payload: Vec<u8> = (0..=255).collect();
+        replica.write_all(&payload).await.unwrap();
+        replica.flush().await.unwrap();
+        let (mut primary, _) = server.accept().await.unwrap();
+        let mut received = vec![0; payload.len()];
+        primary.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+        primary.write_all(b"+OK\r\n").await.unwrap();
+        let mut reply = [0; 5];
+        replica.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+
+        // A primary whose certificate is for another name is refused.
+        assert!(connect(&connector, "127.0.0.1", tls_port, "elsewhere")
+            .await
+            .is_err());
+
+        // So is a replica whose certificate the CA didn't sign.
+        let stranger = TlsFiles {
+            ca: files.ca.clone(),
+            ..strange
+        };
+        let refused = async {
+            let connector = stranger.connector()?;
+            let mut stream = connect(&connector, "127.0.0.1", tls_port, "localhost").await?;
+            stream.write_all(b"PING\r\n").await?;
+            let mut buf = [0; 1];
+            Ok::<_, TlsError>(stream.read(&mut buf).await?)
+        };
+        assert!(!matches!(refused.await, Ok(1..)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
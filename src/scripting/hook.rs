@@ -0,0 +1,136 @@
+//! Scripts deciding evictions, set with `eviction_hook`
+use super::{from_resp, load, pcall_reply, set_arguments, stdlib, watch};
+use crate::protocol::RespValue;
+use crate::storage::{Deadline, EvictionHook};
+use bytes::Bytes;
+use log::warn;
+use mlua::{Function, Lua, MultiValue};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long a hook may run per key before the key is let go regardless.
+const HOOK_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// A script asked about each key before it's evicted, with the key in
+/// `KEYS[1]` and its database in `ARGV[1]`; returning `false` or 0 keeps
+/// the key. Scripts can `redis.log` but not run commands, since the
+/// key's shard is locked. A script failing or running too long lets the
+/// key go, so a broken hook can't stop eviction altogether.
+///
+/// Unlike `EVAL` scripts, the script is compiled once, and every call runs
+/// on the same state, so globals it sets stay set for the next key.
+pub struct EvictionScript {
+    state: Mutex<Loaded>,
+}
+
+/// A sandbox with the script loaded into it.
+struct Loaded {
+    lua: Lua,
+    script: Function,
+    /// Taken before the script first runs, since it may replace it.
+    pcall: Function,
+}
+
+impl EvictionScript {
+    pub fn new(source: Bytes) -> Result<Self, String> {
+        let lua = stdlib::sandbox().map_err(|e| format!("ERR {}", e))?;
+        let script = load(&lua, &source)?;
+        let install = || {
+            let refuse = lua.create_function(|lua, _: MultiValue| {
+                let refusal = RespValue::Error("ERR eviction hooks can't run commands".to_string());
+                Ok((from_resp(lua, refusal)?, None::<&str>))
+            })?;
+            stdlib::install_redis(&lua, refuse)?;
+            lua.globals().get::<Function>("pcall")
+        };
+        let pcall = install().map_err(|e| format!("ERR {}", e))?;
+        Ok(EvictionScript {
+            state: Mutex::new(Loaded { lua, script, pcall }),
+        })
+    }
+
+    /// The script in the file at `path`.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::new(source.into())
+    }
+}
+
+impl Loaded {
+    /// The script's reply for `key`, or `None` if it ran out of time.
+    fn call(&self, db: usize, key: &[u8]) -> mlua::Result<Option<RespValue>> {
+        let timed_out = Arc::new(AtomicBool::new(false));
+        watch(&self.lua, Deadline::after(Some(HOOK_TIMEOUT)), &timed_out)?;
+        set_arguments(
+            &self.lua,
+            &[Bytes::copy_from_slice(key)],
+            &[db.to_string().into()],
+        )?;
+        let results = self.pcall.call(&self.script)?;
+        if timed_out.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        Ok(Some(pcall_reply(&self.lua, results)))
+    }
+}
+
+impl EvictionHook for EvictionScript {
+    fn allow(&self, db: usize, key: &[u8]) -> bool {
+        let reply = self.state.lock().unwrap().call(db, key);
+        match reply {
+            Ok(Some(RespValue::BulkString(None) | RespValue::Integer(0))) => false,
+            Ok(Some(RespValue::Error(e))) => {
+                warn!("Eviction hook failed, evicting the key: {}", e);
+                true
+            }
+            Ok(Some(_)) => true,
+            Ok(None) => {
+                warn!(
+                    "Eviction hook ran over {:?}, evicting the key",
+                    HOOK_TIMEOUT
+                );
+                true
+            }
+            Err(e) => {
+                warn!("Eviction hook failed, evicting the key: {}", e);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripts_keep_keys() {
+        let hook = EvictionScript::new(
+            "if string.sub(KEYS[1], 1, 8) == 'session:' then return false end \
+             return ARGV[1] ~= '3' and 1 or 0"
+                .into(),
+        )
+        .unwrap();
+        assert!(!hook.allow(0, b"session:42"));
+        assert!(hook.allow(0, b"cache:42"));
+        assert!(!hook.allow(3, b"cache:42"));
+
+        // Failing scripts let keys go.
+        let hook = EvictionScript::new("return redis.call('DEL', KEYS[1])".into()).unwrap();
+        assert!(hook.allow(0, b"session:42"));
+        let hook = EvictionScript::new("while true do end".into()).unwrap();
+        assert!(hook.allow(0, b"session:42"));
+
+        assert!(EvictionScript::new("return (".into()).is_err());
+
+        // The script is compiled once and its state kept between keys.
+        let hook = EvictionScript::new(
+            "seen = (seen or 0) + 1 if seen > 1 then return 0 end return 1".into(),
+        )
+        .unwrap();
+        assert!(hook.allow(0, b"a"));
+        assert!(!hook.allow(0, b"b"));
+    }
+}
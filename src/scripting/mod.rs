@@ -0,0 +1,439 @@
+//! Server-side Lua scripts: `EVAL`, `EVALSHA` and `SCRIPT`
+//!
+//! Scripts run on Lua 5.1, built in from source, with the libraries Redis
+//! gives them; see [`stdlib`]. Each runs on a state of its own, thrown away
+//! after. `redis.call` runs a command as if a client had sent it, and what
+//! it returns is converted between RESP and Lua as Redis does. Every script
+//! runs with every shard locked, so it is atomic like `EXEC`.
+//!
+//! A script can also be consulted before each eviction, to keep keys the
+//! eviction policy would otherwise pick; see [`EvictionScript`].
+mod hook;
+mod sha1;
+mod stdlib;
+
+pub use hook::EvictionScript;
+
+use crate::protocol::RespValue;
+use crate::storage::{Deadline, StorageError};
+use bytes::Bytes;
+use mlua::chunk::ChunkMode;
+use mlua::{HookTriggers, Lua, MultiValue, Table, Value, Variadic, VmState};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Lua instructions run between deadline checks.
+const CHECK_EVERY: u32 = 1000;
+
+/// Runs commands for `redis.call`, taking the command and its arguments.
+pub type Bridge<'a> = dyn FnMut(Vec<Bytes>) -> RespValue + 'a;
+
+/// Scripts by the hex SHA-1 of their source.
+#[derive(Default)]
+pub struct ScriptCache {
+    scripts: Mutex<HashMap<String, Bytes>>,
+}
+
+impl ScriptCache {
+    /// Adds `source`, returning its SHA-1 and whether it's new.
+    pub fn load(&self, source: Bytes) -> (String, bool) {
+        let sha = sha1::hex(&source);
+        let mut scripts = self.scripts.lock().unwrap();
+        let new = !scripts.contains_key(&sha);
+        scripts.entry(sha.clone()).or_insert(source);
+        (sha, new)
+    }
+
+    /// The script named `sha`, in any case.
+    pub fn get(&self, sha: &[u8]) -> Option<Bytes> {
+        let sha = std::str::from_utf8(sha).ok()?.to_ascii_lowercase();
+        self.scripts.lock().unwrap().get(&sha).cloned()
+    }
+
+    pub fn flush(&self) {
+        self.scripts.lock().unwrap().clear();
+    }
+
+    /// The source of every script, for dumps and replicas to keep.
+    pub fn sources(&self) -> Vec<Bytes> {
+        self.scripts.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// `source` as a function of `lua`, named as Redis names scripts in error
+/// messages. Only source text is taken: Lua doesn't check bytecode.
+fn load(lua: &Lua, source: &[u8]) -> Result<mlua::Function, String> {
+    lua.load(source)
+        .set_name("=user_script")
+        .set_mode(ChunkMode::Text)
+        .into_function()
+        .map_err(|e| {
+            let message = match e {
+                mlua::Error::SyntaxError { message, .. } => message,
+                e => e.to_string(),
+            };
+            format!("ERR Error compiling script (new function): {}", message)
+        })
+}
+
+/// Checks that `source` parses, as `SCRIPT LOAD` does before caching it.
+pub fn compile(source: &[u8]) -> Result<(), String> {
+    let lua = Lua::new_with(mlua::StdLib::NONE, mlua::LuaOptions::default())
+        .map_err(|e| format!("ERR {}", e))?;
+    load(&lua, source).map(drop)
+}
+
+/// Runs `source` with `KEYS` and `ARGV` set, `call` running the commands
+/// it makes. Errors in the script are replies; only running past
+/// `deadline` is an error.
+pub fn eval(
+    source: &[u8],
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    deadline: Deadline,
+    call: &mut Bridge<'_>,
+) -> Result<RespValue, StorageError> {
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let reply = run(source, keys, args, deadline, call, &timed_out);
+    // Running out of time isn't for the script to catch, even with `pcall`.
+    if timed_out.load(Ordering::Relaxed) {
+        return Err(StorageError::Timeout);
+    }
+    Ok(reply.unwrap_or_else(|e| RespValue::Error(format!("ERR Error running script: {}", e))))
+}
+
+/// Stops what `lua` runs once `deadline` passes, setting `timed_out`.
+fn watch(lua: &Lua, deadline: Deadline, timed_out: &Arc<AtomicBool>) -> mlua::Result<()> {
+    let timed_out = timed_out.clone();
+    lua.set_hook(
+        HookTriggers::new().every_nth_instruction(CHECK_EVERY),
+        move |_, _| match deadline.check() {
+            Ok(()) => Ok(VmState::Continue),
+            Err(e) => {
+                timed_out.store(true, Ordering::Relaxed);
+                Err(mlua::Error::runtime(e))
+            }
+        },
+    )
+}
+
+/// Sets `KEYS` and `ARGV` for the next script `lua` runs.
+fn set_arguments(lua: &Lua, keys: &[Bytes], args: &[Bytes]) -> mlua::Result<()> {
+    let strings = |values: &[Bytes]| {
+        lua.create_sequence_from(
+            values
+                .iter()
+                .map(|value| lua.create_string(value))
+                .collect::<mlua::Result<Vec<_>>>()?,
+        )
+    };
+    let globals = lua.globals();
+    globals.set("KEYS", strings(keys)?)?;
+    globals.set("ARGV", strings(args)?)
+}
+
+/// The reply for what `pcall` returned from a script.
+fn pcall_reply(lua: &Lua, results: MultiValue) -> RespValue {
+    let mut results = results.into_iter();
+    let ok = matches!(results.next(), Some(Value::Boolean(true)));
+    let value = results.next().unwrap_or(Value::Nil);
+    match ok {
+        true => to_resp(value),
+        false => error_reply(lua, value),
+    }
+}
+
+fn run(
+    source: &[u8],
+    keys: Vec<Bytes>,
+    args: Vec<Bytes>,
+    deadline: Deadline,
+    call: &mut Bridge<'_>,
+    timed_out: &Arc<AtomicBool>,
+) -> mlua::Result<RespValue> {
+    let lua = stdlib::sandbox()?;
+    let script = match load(&lua, source) {
+        Ok(script) => script,
+        Err(e) => return Ok(RespValue::Error(e)),
+    };
+    set_arguments(&lua, &keys, &args)?;
+    watch(&lua, deadline, timed_out)?;
+    // Taken before the script runs, which may replace it.
+    let pcall: mlua::Function = lua.globals().get("pcall")?;
+    let call = RefCell::new(call);
+    lua.scope(|scope| {
+        let run = scope.create_function(|lua, args: Variadic<Value>| {
+            command(lua, &mut **call.borrow_mut(), args)
+        })?;
+        stdlib::install_redis(&lua, run)?;
+        Ok(pcall_reply(&lua, pcall.call(script)?))
+    })
+}
+
+/// Runs a command for `redis.call` and `redis.pcall`: its reply, or nil and
+/// what's wrong with the arguments.
+fn command(
+    lua: &Lua,
+    call: &mut Bridge<'_>,
+    args: Variadic<Value>,
+) -> mlua::Result<(Value, Option<&'static str>)> {
+    if args.is_empty() {
+        return Ok((
+            Value::Nil,
+            Some("Please specify at least one argument for this redis lib call"),
+        ));
+    }
+    let mut command = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg {
+            Value::String(_) | Value::Integer(_) | Value::Number(_) => {
+                let arg = lua.coerce_string(arg)?.expect("a string or number");
+                command.push(Bytes::copy_from_slice(&arg.as_bytes()));
+            }
+            _ => {
+                return Ok((
+                    Value::Nil,
+                    Some("Lua redis lib command arguments must be strings or integers"),
+                ))
+            }
+        }
+    }
+    Ok((from_resp(lua, call(command))?, None))
+}
+
+/// The reply for an error a script didn't catch: `{err = ...}` tables, as
+/// from `redis.call`, go back as they are.
+fn error_reply(lua: &Lua, value: Value) -> RespValue {
+    if let Value::Table(table) = &value {
+        if let Ok(Value::String(message)) = table.raw_get("err") {
+            return RespValue::Error(message.to_string_lossy());
+        }
+    }
+    let message = match value {
+        Value::Error(e) => e.to_string(),
+        value @ (Value::String(_) | Value::Integer(_) | Value::Number(_)) => lua
+            .coerce_string(value)
+            .ok()
+            .flatten()
+            .map_or_else(String::new, |s| s.to_string_lossy()),
+        value => format!("(error object is a {} value)", value.type_name()),
+    };
+    RespValue::Error(format!("ERR Error running script: {}", message))
+}
+
+/// A script's result as a reply: numbers are truncated to integers,
+/// `true` is 1, `false` and `nil` are nil, and a table is an array up to
+/// its first nil unless it's an `{err = ...}` or `{ok = ...}` reply.
+fn to_resp(value: Value) -> RespValue {
+    match value {
+        Value::Boolean(true) => RespValue::Integer(1),
+        Value::Integer(n) => RespValue::Integer(n),
+        Value::Number(n) => RespValue::Integer(n as i64),
+        Value::String(s) => RespValue::BulkString(Some(Bytes::copy_from_slice(&s.as_bytes()))),
+        Value::Table(table) => {
+            for (field, reply) in [
+                ("err", RespValue::Error as fn(String) -> RespValue),
+                ("ok", RespValue::SimpleString),
+            ] {
+                if let Ok(Value::String(message)) = table.raw_get(field) {
+                    return reply(message.to_string_lossy());
+                }
+            }
+            RespValue::Array(
+                (1..)
+                    .map(|i| table.raw_get(i).unwrap_or(Value::Nil))
+                    .take_while(|value| !value.is_nil())
+                    .map(to_resp)
+                    .collect(),
+            )
+        }
+        _ => RespValue::BulkString(None),
+    }
+}
+
+/// A reply as `redis.call` returns it: nils are `false`, status and error
+/// replies `{ok = ...}` and `{err = ...}` tables.
+fn from_resp(lua: &Lua, reply: RespValue) -> mlua::Result<Value> {
+    let field = |field: &str, message: String| -> mlua::Result<Value> {
+        let table = lua.create_table()?;
+        table.raw_set(field, message)?;
+        Ok(Value::Table(table))
+    };
+    let array = |values: Vec<RespValue>| -> mlua::Result<Value> {
+        let table = lua.create_table_with_capacity(values.len(), 0)?;
+        for (i, value) in values.into_iter().enumerate() {
+            table.raw_set(i + 1, from_resp(lua, value)?)?;
+        }
+        Ok(Value::Table(table))
+    };
+    Ok(match reply {
+        RespValue::Integer(n) => Value::Number(n as f64),
+        RespValue::BulkString(Some(s)) => Value::String(lua.create_string(&s)?),
+        RespValue::BulkString(None) | RespValue::Null | RespValue::NullArray => {
+            Value::Boolean(false)
+        }
+        RespValue::SimpleString(s) => field("ok", s)?,
+        RespValue::Error(s) => field("err", s)?,
+        RespValue::Double(n) => Value::String(
+            lua.coerce_string(Value::Number(n))?
+                .expect("numbers make strings"),
+        ),
+        RespValue::BigNumber(n) => Value::String(lua.create_string(&n)?),
+        RespValue::Array(values) | RespValue::Set(values) | RespValue::Push(values) => {
+            array(values)?
+        }
+        RespValue::Map(pairs) => {
+            let table: Table = lua.create_table()?;
+            for (key, value) in pairs {
+                // Keys that can't index a table (nil replies) are dropped.
+                let _ = table.raw_set(from_resp(lua, key)?, from_resp(lua, value)?);
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str, keys: &[&str], args: &[&str]) -> RespValue {
+        let mut calls = |command: Vec<Bytes>| match &command[0][..] {
+            b"PING" => RespValue::SimpleString("PONG".to_string()),
+            b"GET" => RespValue::BulkString(None),
+            b"INCR" => RespValue::Integer(42),
+            _ => RespValue::Error("ERR unknown command".to_string()),
+        };
+        let strings = |values: &[&str]| values.iter().map(|v| Bytes::from(v.to_string())).collect();
+        eval(
+            source.as_bytes(),
+            strings(keys),
+            strings(args),
+            Deadline::after(None),
+            &mut calls,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_language() {
+        let source = r#"
+            local function fib(n)
+                if n < 2 then return n end
+                return fib(n - 1) + fib(n - 2)
+            end
+            local t = {}
+            for i = 1, 10 do t[#t + 1] = fib(i) end
+            local words = {}
+            for _, w in ipairs({"a", "b"}) do table.insert(words, w:upper()) end
+            local sum = 0
+            for k, v in pairs({x = 1, y = 2}) do sum = sum + v end
+            local i = 0
+            repeat i = i + 1 until i >= 3
+            local ok, err = pcall(function() error("boom") end)
+            return {t[10], table.concat(words, "-"), sum, i, err,
+                    string.format("%05.1f|%-3s|%d", 3.14159, "x", 7), 3.9, ARGV[1] .. KEYS[1]}
+        "#;
+        assert_eq!(
+            run(source, &["k"], &["v"]),
+            RespValue::Array(vec![
+                RespValue::Integer(55),
+                RespValue::bulk("A-B"),
+                RespValue::Integer(3),
+                RespValue::Integer(3),
+                RespValue::bulk("user_script:14: boom"),
+                RespValue::bulk("003.1|x  |7"),
+                RespValue::Integer(3),
+                RespValue::bulk("vk"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_replies() {
+        assert_eq!(
+            run("return redis.call('PING')", &[], &[]),
+            RespValue::SimpleString("PONG".to_string())
+        );
+        assert_eq!(
+            run(
+                "return {redis.call('GET', 'k'), redis.call('INCR', 'n')}",
+                &[],
+                &[]
+            ),
+            RespValue::Array(vec![RespValue::BulkString(None), RespValue::Integer(42)])
+        );
+        // Arrays end at their first nil.
+        assert_eq!(
+            run("return {1, nil, 3}", &[], &[]),
+            RespValue::Array(vec![RespValue::Integer(1)])
+        );
+        assert_eq!(
+            run("return redis.call('NOPE')", &[], &[]),
+            RespValue::Error("ERR unknown command".to_string())
+        );
+        assert_eq!(
+            run("return redis.pcall('NOPE').err", &[], &[]),
+            RespValue::bulk("ERR unknown command")
+        );
+        assert_eq!(
+            run("return redis.error_reply('MY failure')", &[], &[]),
+            RespValue::Error("MY failure".to_string())
+        );
+        assert_eq!(
+            run("local x = nil\nreturn x.y", &[], &[]),
+            RespValue::Error(
+                "ERR Error running script: user_script:2: attempt to index local 'x' (a nil value)"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            run("return (", &[], &[]),
+            RespValue::Error(
+                "ERR Error compiling script (new function): user_script:1: unexpected symbol near '<eof>'"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        // Nesting deep enough to overflow a recursive parser's stack.
+        let nested = format!("return {}1{}", "(".repeat(5000), ")".repeat(5000));
+        assert!(matches!(
+            run(&nested, &[], &[]),
+            RespValue::Error(e) if e.contains("too many syntax levels")
+        ));
+        assert!(matches!(
+            run("local function f() return 1 + f() end return f()", &[], &[]),
+            RespValue::Error(e) if e.contains("stack overflow")
+        ));
+    }
+
+    #[test]
+    fn test_sandbox() {
+        assert_eq!(
+            run(
+                "return {type(dofile), type(loadstring), type(io), type(os), type(coroutine)}",
+                &[],
+                &[]
+            ),
+            RespValue::Array(vec![RespValue::bulk("nil"); 5])
+        );
+        // Precompiled chunks are refused.
+        assert!(matches!(
+            run("\x1bLua\x51\x00", &[], &[]),
+            RespValue::Error(e) if e.starts_with("ERR Error compiling script")
+        ));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut calls = |_: Vec<Bytes>| RespValue::Null;
+        let deadline = Deadline::after(Some(std::time::Duration::from_millis(10)));
+        let result = eval(b"while true do end", vec![], vec![], deadline, &mut calls);
+        assert!(matches!(result, Err(StorageError::Timeout)));
+    }
+}
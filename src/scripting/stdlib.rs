@@ -0,0 +1,95 @@
+//! The parts of the Lua standard library scripts get, and `redis.*`
+use super::sha1;
+use mlua::{Function, Lua, LuaOptions, LuaString, StdLib, Value};
+
+/// Globals taken away: functions reading files, writing to the server's
+/// output, or loading code, which could be bytecode Lua runs unchecked, and
+/// `coroutine`, whose threads the deadline hook doesn't watch.
+const REMOVED: [&str; 6] = [
+    "dofile",
+    "loadfile",
+    "load",
+    "loadstring",
+    "print",
+    "coroutine",
+];
+
+/// The `redis` library, over `run`, which returns a command's reply, or nil
+/// and what's wrong with its arguments.
+const REDIS: &str = r##"
+local run, sha1hex, log = ...
+redis = {LOG_DEBUG = 0, LOG_VERBOSE = 1, LOG_NOTICE = 2, LOG_WARNING = 3}
+
+function redis.call(...)
+    local reply, problem = run(...)
+    if problem then error(problem, 2) end
+    if type(reply) == "table" and reply.err then error(reply) end
+    return reply
+end
+
+function redis.pcall(...)
+    local reply, problem = run(...)
+    if problem then return {err = problem} end
+    return reply
+end
+
+local function reply(field, name)
+    return function(message)
+        if type(message) ~= "string" then
+            error("wrong number or type of arguments to redis." .. name, 2)
+        end
+        return {[field] = message}
+    end
+end
+redis.error_reply = reply("err", "error_reply")
+redis.status_reply = reply("ok", "status_reply")
+
+function redis.sha1hex(s)
+    if type(s) ~= "string" and type(s) ~= "number" then
+        error("wrong number or type of arguments to redis.sha1hex", 2)
+    end
+    return sha1hex(tostring(s))
+end
+
+function redis.log(level, ...)
+    if type(level) ~= "number" then
+        error("First argument must be a number (log level).", 2)
+    end
+    local words = {}
+    for i = 1, select("#", ...) do
+        words[i] = tostring((select(i, ...)))
+    end
+    log(level, table.concat(words, " "))
+end
+"##;
+
+/// A Lua state with the base, `string`, `table` and `math` libraries, less
+/// [`REMOVED`].
+pub fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(
+        StdLib::STRING | StdLib::TABLE | StdLib::MATH,
+        LuaOptions::default(),
+    )?;
+    let globals = lua.globals();
+    for name in REMOVED {
+        globals.raw_set(name, Value::Nil)?;
+    }
+    Ok(lua)
+}
+
+/// Adds the `redis` library to `lua`, with `run` running the commands.
+pub fn install_redis(lua: &Lua, run: Function) -> mlua::Result<()> {
+    let sha1hex =
+        lua.create_function(|lua, s: LuaString| lua.create_string(sha1::hex(&s.as_bytes())))?;
+    let log = lua.create_function(|_, (level, message): (f64, LuaString)| {
+        let message = message.to_string_lossy();
+        match level as i64 {
+            0 => log::debug!("{}", message),
+            1 => log::trace!("{}", message),
+            2 => log::info!("{}", message),
+            _ => log::warn!("{}", message),
+        }
+        Ok(())
+    })?;
+    lua.load(REDIS).set_name("=redis").call((run, sha1hex, log))
+}
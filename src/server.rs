@@ -0,0 +1,820 @@
+//! Running a server in-process
+//!
+//! The `rdb` binary is a thin wrapper around [`Server`]: it loads the
+//! configuration, starts a server and shuts it down on SIGINT or SIGTERM.
+//! Other crates can do the same, for example to give their integration
+//! tests a private instance bound to `127.0.0.1:0`.
+use crate::acl::Acl;
+use crate::aof::{self, Aof, AofError, Manifest};
+use crate::cluster::{Cluster, ClusterError};
+use crate::commands::{check_disabled_groups, Blocklist};
+use crate::config::Config;
+use crate::connection::{ClientRegistry, Connection, KillFilter, ShutdownRequest};
+use crate::memcached::Memcached;
+use crate::replication::{self, ImportError, ImportSource, Replication};
+use crate::storage::{self, Db, RdbError, Shards};
+use log::{error, info, warn};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("failed to load the append only file: {0}")]
+    Aof(#[from] AofError),
+    #[error(transparent)]
+    Import(#[from] ImportError),
+    #[error("invalid replicaof address: {0}")]
+    InvalidReplicaOf(String),
+    #[error("invalid security settings: {0}")]
+    Security(String),
+    #[error("invalid eviction hook: {0}")]
+    EvictionHook(String),
+    #[error("invalid deny_commands: {0}")]
+    Blocklist(String),
+    #[error("invalid memcached settings: {0}")]
+    Memcached(String),
+    #[error("invalid cluster settings: {0}")]
+    Cluster(#[from] ClusterError),
+    #[error("invalid disabled_command_groups: {0}")]
+    DisabledGroups(String),
+    #[error("invalid quotas: {0}")]
+    Quotas(String),
+    #[error("failed to load the snapshot {}: {1}", .0.display())]
+    Snapshot(PathBuf, RdbError),
+    #[cfg(feature = "sql")]
+    #[error(transparent)]
+    Sink(#[from] crate::sink::SinkError),
+    #[cfg(feature = "tls")]
+    #[error(transparent)]
+    Tls(#[from] replication::TlsError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+/// Settings for a server about to start; the defaults are those of
+/// [`Config::default`].
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    import_from: Option<ImportSource>,
+    read_only_snapshot: Option<PathBuf>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listens on `addr` instead of `server.listen_addr`. Port 0 picks a
+    /// free one; [`ServerHandle::local_addr`] tells which.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.config.server.listen_addr = addr;
+        self
+    }
+
+    /// Imports the dataset of the Redis at `source` on startup, in place of
+    /// the one loaded from disk, before serving clients.
+    pub fn import_from(mut self, source: ImportSource) -> Self {
+        self.import_from = Some(source);
+        self
+    }
+
+    /// Serves the dump file at `path` read-only, in place of the data on
+    /// disk or imported: writes are refused, and nothing is ever saved or
+    /// logged, whatever the config says about persistence.
+    pub fn read_only_snapshot(mut self, path: &Path) -> Self {
+        self.read_only_snapshot = Some(path.to_path_buf());
+        self
+    }
+
+    /// Loads the data, binds the listeners and starts serving clients in
+    /// the background. Returns once clients can connect.
+    pub async fn run(self) -> Result<ServerHandle, ServerError> {
+        let mut config = self.config;
+        if let Some(index) = config
+            .storage
+            .quotas
+            .iter()
+            .position(|quota| quota.db.is_none() && quota.prefix.is_none())
+        {
+            return Err(ServerError::Quotas(format!(
+                "quota {} names neither a db nor a prefix",
+                index
+            )));
+        }
+        check_disabled_groups(&config.storage.disabled_command_groups)
+            .map_err(ServerError::DisabledGroups)?;
+        if self.read_only_snapshot.is_some() {
+            config.storage.persistence_enabled = false;
+            config.storage.appendonly = false;
+            if config.replication.replicaof.take().is_some() {
+                warn!("Ignoring replicaof: a read-only snapshot follows no primary");
+            }
+        }
+
+        // The append-only file is more up to date than a snapshot, so it wins.
+        let mut shards = Shards::new(config.storage.clone());
+        if let Some(path) = &self.read_only_snapshot {
+            shards
+                .lock_all()
+                .await
+                .load_dump(path)
+                .map_err(|e| ServerError::Snapshot(path.clone(), e))?;
+            shards.make_read_only();
+            info!("Serving the snapshot {} read-only", path.display());
+        } else if config.storage.appendonly {
+            let manifest = Manifest::load(
+                &config.storage.appenddirname,
+                &config.storage.appendfilename,
+            )?;
+            let replayed = aof::replay(&manifest, &mut shards.lock_all().await)?;
+            info!(
+                "Replayed {} commands from {}",
+                replayed,
+                manifest.path().display()
+            );
+            shards.attach_aof(Aof::open(manifest, config.storage.appendfsync)?);
+        } else if let Err(e) = shards.lock_all().await.load_from_disk() {
+            error!("Failed to load data from disk: {}", e);
+        }
+        let listener = TcpListener::bind(config.server.listen_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let replication = Arc::new(Replication::new(
+            config.replication.clone(),
+            local_addr.port(),
+        ));
+        shards.attach_replication(replication.clone());
+        let db: Db = Arc::new(shards);
+        db.stats().configure_latency(&config.latency);
+        info!("Initialized database with {} shards", db.count());
+
+        if let (Some(source), None) = (&self.import_from, &self.read_only_snapshot) {
+            replication::import(source, &db, local_addr.port()).await?;
+            // The imported keys were never logged or saved.
+            let mut store = db.lock_all().await;
+            if config.storage.appendonly {
+                store.rewrite_aof()?;
+            }
+            if config.storage.persistence_enabled {
+                if let Err(e) = store.bgsave() {
+                    error!("Failed to save the imported dataset: {}", e);
+                }
+            }
+        }
+
+        if let Some(path) = &config.storage.eviction_hook {
+            #[cfg(feature = "scripting")]
+            {
+                let hook = crate::scripting::EvictionScript::load(path)
+                    .map_err(ServerError::EvictionHook)?;
+                db.set_eviction_hook(Some(Arc::new(hook)));
+                info!("Consulting {} before evictions", path.display());
+            }
+            #[cfg(not(feature = "scripting"))]
+            log::warn!(
+                "Ignoring eviction hook {}: this build lacks the scripting feature",
+                path.display()
+            );
+        }
+
+        let acl = Arc::new(
+            Acl::from_config(&config.security).map_err(|e| ServerError::Security(e.to_string()))?,
+        );
+        info!("Authentication required: {}", acl.requires_auth());
+
+        #[cfg(feature = "cluster")]
+        let cluster = Cluster::from_config(&config.cluster, local_addr)?.map(Arc::new);
+        #[cfg(not(feature = "cluster"))]
+        let cluster: Option<Arc<Cluster>> = None;
+        #[cfg(not(feature = "cluster"))]
+        if config.cluster.enabled {
+            log::warn!("Ignoring the cluster config: this build lacks the cluster feature");
+        }
+        if let Some(cluster) = &cluster {
+            info!(
+                "Cluster mode: {} nodes, serving slots {:?}",
+                cluster.nodes().len(),
+                cluster.myself().slots
+            );
+        }
+
+        let mut tasks = Vec::new();
+        let tls = &config.replication.tls;
+        if tls.connect || tls.listen_addr.is_some() {
+            #[cfg(feature = "tls")]
+            {
+                let files = replication::TlsFiles::from_config(tls)?;
+                // Read now, so bad files stop the server rather than fail
+                // every sync.
+                if tls.connect {
+                    files.connector()?;
+                }
+                if let Some(addr) = tls.listen_addr {
+                    let acceptor = files.acceptor()?;
+                    let listener = TcpListener::bind(addr).await?;
+                    // Replicas are handed on to the client port over loopback.
+                    let mut server = local_addr;
+                    if server.ip().is_unspecified() {
+                        server.set_ip(match server {
+                            SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                            SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+                        });
+                    }
+                    tasks.push(tokio::spawn(replication::accept_replicas(
+                        listener, acceptor, server,
+                    )));
+                    info!("Accepting replicas over TLS on {}", addr);
+                }
+            }
+            #[cfg(not(feature = "tls"))]
+            log::warn!("Ignoring the replication TLS config: this build lacks the tls feature");
+        }
+
+        if let Some(primary) = &config.replication.replicaof {
+            let (host, port) = primary
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+                .ok_or_else(|| ServerError::InvalidReplicaOf(primary.clone()))?;
+            replication.replicate(Some((host, port)), db.clone());
+        }
+
+        // Save rules can be set at runtime, so the task runs even without any.
+        if config.storage.persistence_enabled {
+            tasks.push(tokio::spawn(storage::run_autosave(db.clone())));
+        }
+
+        tasks.push(tokio::spawn(storage::run_active_expiry(db.clone())));
+        tasks.push(tokio::spawn(storage::run_memory_alarms(db.clone())));
+
+        let defrag = &config.storage.defrag;
+        if defrag.enabled {
+            let interval = Duration::from_millis(defrag.interval_ms.max(1));
+            tasks.push(tokio::spawn(storage::run_defrag(db.clone(), interval)));
+            info!("Active defragmentation every {:?}", interval);
+        }
+
+        if config.sink.url.is_some() {
+            #[cfg(feature = "sql")]
+            {
+                let sink = crate::sink::Sink::new(&config.sink)?;
+                tasks.push(tokio::spawn(sink.run(db.clone())));
+                info!("Mirroring writes into table {}", config.sink.table);
+            }
+            #[cfg(not(feature = "sql"))]
+            log::warn!("Ignoring the sink config: this build lacks the sql feature");
+        }
+
+        let clients = Arc::new(ClientRegistry::with_max_memory(
+            config.server.max_memory_clients,
+        ));
+        let connection_limit = Arc::new(Semaphore::new(config.server.max_connections));
+        info!("Connection limit set to {}", config.server.max_connections);
+
+        if let Some(addr) = config.memcached.listen_addr {
+            // memcached clients can't authenticate: where RESP clients must,
+            // they run as a user named for them.
+            let user = match &config.memcached.user {
+                Some(name) => acl
+                    .user(name)
+                    .ok_or_else(|| ServerError::Memcached(format!("no user named '{}'", name)))?,
+                None => acl.initial_user().ok_or_else(|| {
+                    ServerError::Memcached(
+                        "clients must authenticate, so memcached needs a user to run as"
+                            .to_string(),
+                    )
+                })?,
+            };
+            let blocklist = Blocklist::parse(&config.memcached.deny_commands)
+                .map_err(ServerError::Blocklist)?;
+            let listener = TcpListener::bind(addr).await?;
+            let memcached = Arc::new(
+                Memcached::new(
+                    db.clone(),
+                    clients.clone(),
+                    replication.clone(),
+                    config.memcached.max_item_size,
+                )
+                .run_as(user)
+                .with_blocklist(blocklist),
+            );
+            tasks.push(tokio::spawn(memcached.clone().run_expiry()));
+            tasks.push(tokio::spawn(
+                memcached.serve(listener, connection_limit.clone()),
+            ));
+            info!("memcached protocol listening on {}", addr);
+        }
+
+        if let Some(addr) = config.metrics.listen_addr {
+            #[cfg(feature = "metrics")]
+            {
+                let listener = TcpListener::bind(addr).await?;
+                tasks.push(tokio::spawn(crate::metrics::serve(
+                    listener,
+                    db.clone(),
+                    clients.clone(),
+                )));
+                info!("Metrics listening on {}", addr);
+            }
+            #[cfg(not(feature = "metrics"))]
+            log::warn!(
+                "Ignoring metrics listen_addr {}: this build lacks the metrics feature",
+                addr
+            );
+        }
+
+        let mut listeners = vec![Listener::new(listener, &config.server.deny_commands)?];
+        for extra in &config.server.listeners {
+            let listener = TcpListener::bind(extra.listen_addr).await?;
+            info!("Also listening on {}", listener.local_addr()?);
+            listeners.push(Listener::new(listener, &extra.deny_commands)?);
+        }
+
+        info!("Server listening on {}", local_addr);
+        let (stop, stopped) = oneshot::channel();
+        let handle_db = db.clone();
+        let accept = Accept {
+            listeners,
+            connection_limit,
+            db,
+            acl,
+            clients,
+            replication,
+            cluster,
+            config,
+        };
+        let task = tokio::spawn(accept.run(stopped, tasks));
+        Ok(ServerHandle {
+            local_addr,
+            db: handle_db,
+            stop,
+            task,
+        })
+    }
+}
+
+/// A running server. Dropping the handle stops it as well, without waiting
+/// for it to finish.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    db: Db,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    /// The address clients connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The store the server serves, for programs embedding it to work on
+    /// directly, such as with [`Shards::iter_prefix`].
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Stops accepting clients, lets the connected ones finish what they're
+    /// running for up to `server.shutdown_grace_ms` before disconnecting
+    /// them, and saves the dataset as configured, returning once that's
+    /// done.
+    pub async fn shutdown(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.task.await {
+            error!("Server task failed: {}", e);
+        }
+    }
+
+    /// Runs until `signal` resolves, then shuts down as [`Self::shutdown`]
+    /// does; returns early if a client shuts the server down with
+    /// `SHUTDOWN` first.
+    pub async fn shutdown_on(mut self, signal: impl Future<Output = ()>) {
+        tokio::select! {
+            _ = signal => {}
+            finished = &mut self.task => {
+                if let Err(e) = finished {
+                    error!("Server task failed: {}", e);
+                }
+                return;
+            }
+        }
+        self.shutdown().await
+    }
+}
+
+/// A port serving clients, and the commands refused on it.
+struct Listener {
+    listener: TcpListener,
+    blocklist: Arc<Blocklist>,
+}
+
+impl Listener {
+    fn new(listener: TcpListener, deny_commands: &[String]) -> Result<Self, ServerError> {
+        let blocklist = Blocklist::parse(deny_commands).map_err(ServerError::Blocklist)?;
+        Ok(Listener {
+            listener,
+            blocklist: Arc::new(blocklist),
+        })
+    }
+}
+
+/// Waits for a client on any of `listeners`, returning it with the index
+/// of the listener it came in on. They're tried from `first` on, so one
+/// flooded with clients doesn't keep the others waiting.
+async fn accept_any(
+    listeners: &[Listener],
+    first: usize,
+) -> (usize, std::io::Result<(TcpStream, SocketAddr)>) {
+    std::future::poll_fn(|cx| {
+        for offset in 0..listeners.len() {
+            let index = (first + offset) % listeners.len();
+            if let Poll::Ready(accepted) = listeners[index].listener.poll_accept(cx) {
+                return Poll::Ready((index, accepted));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// The RESP accept loop and what each connection is handed.
+struct Accept {
+    listeners: Vec<Listener>,
+    connection_limit: Arc<Semaphore>,
+    db: Db,
+    acl: Arc<Acl>,
+    clients: Arc<ClientRegistry>,
+    replication: Arc<Replication>,
+    cluster: Option<Arc<Cluster>>,
+    config: Config,
+}
+
+impl Accept {
+    /// Serves clients until `stopped` resolves or a client sends
+    /// `SHUTDOWN`, then drains the connections and stops `tasks`.
+    async fn run(mut self, mut stopped: oneshot::Receiver<()>, tasks: Vec<JoinHandle<()>>) {
+        let mut connections = JoinSet::new();
+        let mut first = 0;
+        let request = loop {
+            let (listener, (mut socket, addr)) = tokio::select! {
+                (index, accepted) = accept_any(&self.listeners, first) => match accepted {
+                    Ok(accepted) => {
+                        first = index + 1;
+                        (&self.listeners[index], accepted)
+                    }
+                    Err(e) => {
+                        error!("Failed to accept a client: {}", e);
+                        continue;
+                    }
+                },
+                // Finished connections are reaped as they go.
+                Some(_) = connections.join_next() => continue,
+                _ = &mut stopped => break ShutdownRequest::default(),
+                request = self.clients.shutdown_requested() => break request,
+            };
+            // Clients over the limit are told so and disconnected, as in Redis.
+            let permit = self.connection_limit.clone().try_acquire_owned();
+            self.db.stats().record_connection(permit.is_ok());
+            let Ok(permit) = permit else {
+                info!("Rejected connection from {}: too many clients", addr);
+                let _ = socket
+                    .write_all(b"-ERR max number of clients reached\r\n")
+                    .await;
+                continue;
+            };
+            info!("New connection from {}", addr);
+
+            let connection = Connection::new(
+                socket,
+                self.db.clone(),
+                self.acl.clone(),
+                self.clients.clone(),
+                self.replication.clone(),
+                self.cluster.clone(),
+                self.config.clone(),
+            )
+            .with_blocklist(listener.blocklist.clone());
+            connections.spawn(async move {
+                // The permit is automatically released when dropped
+                let _permit = permit;
+                if let Err(e) = connection.run().await {
+                    error!("Error processing client: {}", e);
+                }
+            });
+        };
+
+        info!("Shutting down");
+        // Closing the ports tells load balancers to send clients elsewhere.
+        self.listeners.clear();
+        let grace = Duration::from_millis(self.config.server.shutdown_grace_ms);
+        if !request.now && !grace.is_zero() && !connections.is_empty() {
+            info!(
+                "Waiting up to {:?} for {} clients to finish",
+                grace,
+                connections.len()
+            );
+            self.clients.drain();
+            let drained = tokio::time::timeout(grace, async {
+                while connections.join_next().await.is_some() {}
+            })
+            .await;
+            if drained.is_err() {
+                info!("Disconnecting {} clients still busy", connections.len());
+            }
+        }
+        for task in tasks {
+            task.abort();
+        }
+        // memcached clients run on their own tasks; this reaches them too.
+        self.clients.kill(&KillFilter::default(), 0);
+        connections.shutdown().await;
+        self.replication.stop();
+        storage::save_on_shutdown(&self.db, request.save).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MemcachedConfig;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_run_and_shutdown() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+
+        server.shutdown().await;
+        // The connection is closed and the port no longer listens.
+        assert_eq!(client.read(&mut reply).await.unwrap(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_clients() {
+        let server = Server::builder()
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        let stopped = tokio::spawn(server.shutdown_on(std::future::pending()));
+
+        let mut in_transaction = TcpStream::connect(addr).await.unwrap();
+        in_transaction
+            .write_all(b"MULTI\r\nSET k v\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 14];
+        in_transaction.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+OK\r\n+QUEUED\r\n");
+        let mut resp3 = TcpStream::connect(addr).await.unwrap();
+        resp3.write_all(b"HELLO 3\r\n").await.unwrap();
+        let mut reply = [0; 1];
+        resp3.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"%");
+
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        admin.write_all(b"SHUTDOWN NOSAVE\r\n").await.unwrap();
+        // Idle clients are told and let go at once; nobody new gets in.
+        let mut rest = Vec::new();
+        resp3.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.ends_with(b">2\r\n$8\r\nshutdown\r\n:10000\r\n"));
+        assert_eq!(admin.read(&mut reply).await.unwrap(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        // The transaction started still gets to run.
+        in_transaction.write_all(b"EXEC\r\n").await.unwrap();
+        let mut rest = Vec::new();
+        in_transaction.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"*1\r\n+OK\r\n");
+        tokio::time::timeout(Duration::from_secs(5), stopped)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_snapshot() {
+        let dir = std::env::temp_dir().join(format!("rdb-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot = dir.join("snapshot.rdb");
+        let (key, value) = (bytes::Bytes::from("k"), storage::Value::String("v".into()));
+        storage::rdb::save(&snapshot, &[], [vec![(&key, &value)].into_iter()]).unwrap();
+        // Persistence configured, to show it's ignored.
+        let mut config = Config::default();
+        config.storage.persistence_enabled = true;
+        config.storage.dbfilename = dir.join("dump.rdb");
+
+        let server = Server::builder()
+            .config(config.clone())
+            .bind("127.0.0.1:0".parse().unwrap())
+            .read_only_snapshot(&snapshot)
+            .run()
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+        client
+            .write_all(b"GET k\r\nSET k w\r\nSAVE\r\nGET k\r\n")
+            .await
+            .unwrap();
+        let expected: &[u8] = b"$1\r\nv\r\n\
+            -READONLY You can't write against a read only snapshot.\r\n\
+            -READONLY You can't write against a read only snapshot.\r\n\
+            $1\r\nv\r\n";
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+        server.shutdown().await;
+        assert!(!dir.join("dump.rdb").exists());
+
+        let missing = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .read_only_snapshot(&dir.join("missing.rdb"))
+            .run()
+            .await;
+        assert!(matches!(missing, Err(ServerError::Snapshot(..))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_listeners_refuse_their_commands() {
+        // A port free a moment ago, for the admin listener.
+        let admin = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Config {
+            server: serde_json::from_value(serde_json::json!({
+                "deny_commands": ["flushall", "@admin"],
+                "listeners": [{ "listen_addr": admin.to_string() }],
+            }))
+            .unwrap(),
+            ..Config::default()
+        };
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .unwrap();
+
+        let mut public = TcpStream::connect(server.local_addr()).await.unwrap();
+        public
+            .write_all(b"CONFIG GET maxmemory\r\nMULTI\r\nFLUSHALL\r\nEXEC\r\nPING\r\n")
+            .await
+            .unwrap();
+        let expected = "-ERR 'config' is not allowed on this port\r\n+OK\r\n\
+                        -ERR 'flushall' is not allowed on this port\r\n\
+                        -EXECABORT Transaction discarded because of previous errors.\r\n\
+                        +PONG\r\n";
+        let mut reply = vec![0; expected.len()];
+        public.read_exact(&mut reply).await.unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected);
+
+        let mut operator = TcpStream::connect(admin).await.unwrap();
+        operator.write_all(b"FLUSHALL\r\n").await.unwrap();
+        let mut reply = [0; 5];
+        operator.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_psync_needs_admin() {
+        let config = Config {
+            security: serde_json::from_value(serde_json::json!({
+                "users": [
+                    {"name": "cache", "passwords": ["pw"], "categories": ["@read", "@connection"], "keys": ["cache:*"]},
+                    {"name": "default", "passwords": ["root"], "categories": ["@all"], "keys": ["*"]}
+                ]
+            }))
+            .unwrap(),
+            ..Config::default()
+        };
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .unwrap();
+
+        // A user limited to some keys can't have the whole dataset sent.
+        let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+        client
+            .write_all(b"AUTH cache pw\r\nPSYNC ? -1\r\nREPLCONF listening-port 6380\r\n")
+            .await
+            .unwrap();
+        let expected = "+OK\r\n\
+                        -NOPERM User cache has no permissions to run the 'psync' command\r\n\
+                        -NOPERM User cache has no permissions to run the 'replconf' command\r\n";
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected);
+
+        let mut replica = TcpStream::connect(server.local_addr()).await.unwrap();
+        replica
+            .write_all(b"AUTH default root\r\nPSYNC ? -1\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 16];
+        replica.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+OK\r\n+FULLRESYNC");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_memcached_needs_a_user_with_auth() {
+        let config = |user: Option<&str>| Config {
+            security: serde_json::from_value(serde_json::json!({
+                "requirepass": "root",
+                "users": [{"name": "cache", "categories": ["@read", "@write"], "keys": ["cache:*"]}]
+            }))
+            .unwrap(),
+            memcached: MemcachedConfig {
+                listen_addr: Some("127.0.0.1:0".parse().unwrap()),
+                user: user.map(str::to_string),
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        for user in [None, Some("nobody")] {
+            let started = Server::builder()
+                .config(config(user))
+                .bind("127.0.0.1:0".parse().unwrap())
+                .run()
+                .await;
+            assert!(matches!(started, Err(ServerError::Memcached(_))));
+        }
+        let server = Server::builder()
+            .config(config(Some("cache")))
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .unwrap();
+        server.shutdown().await;
+    }
+
+    #[cfg(feature = "cluster")]
+    #[tokio::test]
+    async fn test_cluster_redirects() {
+        let config = Config {
+            cluster: serde_json::from_str(
+                r#"{"enabled": true, "myself": "127.0.0.1:7000", "nodes": [
+                    {"addr": "127.0.0.1:7000", "slots": ["0-8191"]},
+                    {"addr": "127.0.0.1:7001", "slots": ["8192-16383"]}]}"#,
+            )
+            .unwrap(),
+            ..Config::default()
+        };
+        let server = Server::builder()
+            .config(config)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .run()
+            .await
+            .unwrap();
+
+        let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+        // "b" is in slot 3300, here, and "a" in 15495.
+        client
+            .write_all(b"SET b 1\r\nGET a\r\nMGET a b\r\nCLUSTER KEYSLOT a\r\n")
+            .await
+            .unwrap();
+        let expected = "+OK\r\n-MOVED 15495 127.0.0.1:7001\r\n\
+                        -CROSSSLOT Keys in request don't hash to the same slot\r\n:15495\r\n";
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected);
+        server.shutdown().await;
+    }
+}
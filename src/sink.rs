@@ -0,0 +1,545 @@
+//! Write-behind mirroring of the dataset into a SQL table
+//!
+//! Small deployments get durable, queryable storage without running a
+//! replica: the sink follows the change feed, collects the keys written and
+//! upserts their current values into one table, a batch per transaction.
+//! SQLite is built in; Postgres is reached over its wire protocol, with the
+//! credentials of the URL, which never leave the process. Writes reach the
+//! table late, never out of order: a failed batch is retried with its keys,
+//! and a sink that falls behind the feed rewrites the whole table.
+use crate::config::SinkConfig;
+use crate::protocol::RespValue;
+use crate::storage::{Db, Value};
+use bytes::Bytes;
+use log::{error, info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SinkError {
+    #[error("unsupported sink url {0}: expected sqlite:PATH or postgres://...")]
+    InvalidUrl(String),
+    #[error("invalid sink table name {0}: use letters, digits and underscores")]
+    InvalidTable(String),
+    #[error("sqlite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("postgres: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("sqlite write task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dialect {
+    Sqlite,
+    Postgres,
+}
+
+/// An open connection to the database.
+enum Client {
+    /// Used from blocking tasks, one at a time.
+    Sqlite(Arc<Mutex<rusqlite::Connection>>),
+    Postgres(tokio_postgres::Client),
+}
+
+/// A change to one row.
+#[derive(Debug, PartialEq)]
+enum Row {
+    Upsert {
+        db: i64,
+        key: Bytes,
+        value: Vec<u8>,
+        kind: &'static str,
+    },
+    Delete {
+        db: i64,
+        key: Bytes,
+    },
+}
+
+/// The rows one transaction changes, after emptying the table if
+/// `replace` is set.
+#[derive(Debug, Default)]
+struct Batch {
+    replace: bool,
+    rows: Vec<Row>,
+}
+
+/// The statements a batch runs, in the database's dialect.
+#[derive(Clone)]
+struct Statements {
+    schema: String,
+    clear: String,
+    upsert: String,
+    delete: String,
+}
+
+pub struct Sink {
+    dialect: Dialect,
+    /// Where the client connects: a file path or a URL.
+    target: String,
+    table: String,
+    batch_size: usize,
+    interval: Duration,
+    max_backoff: Duration,
+    /// Opened on the first write, and again after one fails.
+    client: Option<Client>,
+}
+
+impl Sink {
+    pub fn new(config: &SinkConfig) -> Result<Self, SinkError> {
+        let url = config.url.clone().unwrap_or_default();
+        let (dialect, target) = if let Some(path) = url.strip_prefix("sqlite:") {
+            (Dialect::Sqlite, path.trim_start_matches("//").to_string())
+        } else if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            (Dialect::Postgres, url.clone())
+        } else {
+            return Err(SinkError::InvalidUrl(url));
+        };
+        let table = &config.table;
+        if table.is_empty()
+            || !table
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        {
+            return Err(SinkError::InvalidTable(table.clone()));
+        }
+        Ok(Sink {
+            dialect,
+            target,
+            table: table.clone(),
+            batch_size: config.batch_size.max(1),
+            interval: Duration::from_millis(config.flush_interval_ms.max(1)),
+            max_backoff: Duration::from_millis(config.max_backoff_ms.max(1)),
+            client: None,
+        })
+    }
+
+    /// Mirrors writes until the server exits, starting with a copy of the
+    /// whole dataset.
+    pub async fn run(mut self, db: Db) {
+        // Tail before the first copy, so no write falls between the two.
+        let mut tail = db.changefeed().tail(None);
+        let mut dirty: BTreeSet<(usize, Bytes)> = BTreeSet::new();
+        let mut resync = true;
+        let mut backoff = self.interval;
+        let mut ticker = tokio::time::interval(self.interval);
+        loop {
+            tokio::select! {
+                change = tail.recv() => {
+                    match change {
+                        // Flushes and swaps don't list their keys.
+                        Ok(change) if change.key.is_empty() => resync = true,
+                        Ok(change) => {
+                            dirty.insert((change.db, change.key));
+                        }
+                        Err(lost) => {
+                            warn!("Sink lost {} changes, rewriting the table", lost);
+                            resync = true;
+                        }
+                    }
+                    if dirty.len() < self.batch_size {
+                        continue;
+                    }
+                }
+                _ = ticker.tick() => {}
+            }
+            if !resync && dirty.is_empty() {
+                continue;
+            }
+            let batch = if resync {
+                dirty.clear();
+                // Copying takes the shards one at a time, so the locks have
+                // to go first.
+                let snapshot = db.lock_all().await.begin_snapshot();
+                resync_batch(&snapshot.collect().await)
+            } else {
+                changes_batch(&db, &dirty).await
+            };
+            match self.write(batch).await {
+                Ok(()) => {
+                    if resync {
+                        info!("Sink copied the dataset into {}", self.table);
+                    }
+                    dirty.clear();
+                    resync = false;
+                    backoff = self.interval;
+                }
+                Err(e) => {
+                    error!("Sink write failed, retrying in {:?}: {}", backoff, e);
+                    // The connection may be what failed.
+                    self.client = None;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+            }
+        }
+    }
+
+    fn statements(&self) -> Statements {
+        let (int, blob) = match self.dialect {
+            Dialect::Sqlite => ("INTEGER", "BLOB"),
+            Dialect::Postgres => ("BIGINT", "BYTEA"),
+        };
+        let param = |n: usize| match self.dialect {
+            Dialect::Sqlite => format!("?{}", n),
+            Dialect::Postgres => format!("${}", n),
+        };
+        let table = &self.table;
+        Statements {
+            schema: format!(
+                "CREATE TABLE IF NOT EXISTS {table} (db {int} NOT NULL, \"key\" {blob} NOT NULL, \
+                 \"value\" {blob} NOT NULL, \"type\" TEXT NOT NULL, ttl {int}, \
+                 PRIMARY KEY (db, \"key\"))"
+            ),
+            clear: format!("DELETE FROM {table}"),
+            // TTLs aren't mirrored, so `ttl` is always null.
+            upsert: format!(
+                "INSERT INTO {table} (db, \"key\", \"value\", \"type\", ttl) \
+                 VALUES ({}, {}, {}, {}, NULL) \
+                 ON CONFLICT (db, \"key\") DO UPDATE SET \"value\" = excluded.\"value\", \
+                 \"type\" = excluded.\"type\", ttl = excluded.ttl",
+                param(1),
+                param(2),
+                param(3),
+                param(4)
+            ),
+            delete: format!(
+                "DELETE FROM {table} WHERE db = {} AND \"key\" = {}",
+                param(1),
+                param(2)
+            ),
+        }
+    }
+
+    /// Writes `batch` in one transaction, connecting first if need be.
+    async fn write(&mut self, batch: Batch) -> Result<(), SinkError> {
+        let statements = self.statements();
+        let client = match self.client.take() {
+            Some(client) => client,
+            None => self.connect().await?,
+        };
+        let client = self.client.insert(client);
+        match client {
+            Client::Sqlite(connection) => {
+                let connection = connection.clone();
+                tokio::task::spawn_blocking(move || {
+                    let mut connection = connection.lock().unwrap();
+                    write_sqlite(&mut connection, &statements, &batch)
+                })
+                .await??;
+            }
+            Client::Postgres(client) => write_postgres(client, &statements, &batch).await?,
+        }
+        Ok(())
+    }
+
+    async fn connect(&self) -> Result<Client, SinkError> {
+        match self.dialect {
+            Dialect::Sqlite => {
+                let path = self.target.clone();
+                let connection =
+                    tokio::task::spawn_blocking(move || rusqlite::Connection::open(path)).await??;
+                Ok(Client::Sqlite(Arc::new(Mutex::new(connection))))
+            }
+            Dialect::Postgres => {
+                let (client, connection) =
+                    tokio_postgres::connect(&self.target, tokio_postgres::NoTls).await?;
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        warn!("Sink connection to postgres closed: {}", e);
+                    }
+                });
+                Ok(Client::Postgres(client))
+            }
+        }
+    }
+}
+
+/// A batch writing the current value of each of `keys`, or deleting its
+/// row if it's gone.
+async fn changes_batch(db: &Db, keys: &BTreeSet<(usize, Bytes)>) -> Batch {
+    let mut by_db: BTreeMap<usize, Vec<Bytes>> = BTreeMap::new();
+    for (index, key) in keys {
+        by_db.entry(*index).or_default().push(key.clone());
+    }
+    let mut batch = Batch::default();
+    for (index, keys) in by_db {
+        let mut store = db.lock(&keys).await;
+        store
+            .select(index)
+            .expect("changes come from valid databases");
+        for key in keys {
+            let row = match store.shard(&key).value(&key) {
+                Some(value) => upsert(index, key, value),
+                None => Row::Delete {
+                    db: index as i64,
+                    key,
+                },
+            };
+            batch.rows.push(row);
+        }
+    }
+    batch
+}
+
+/// A batch replacing the table's rows with `databases`.
+fn resync_batch(databases: &[Vec<(Bytes, Value)>]) -> Batch {
+    Batch {
+        replace: true,
+        rows: databases
+            .iter()
+            .enumerate()
+            .flat_map(|(index, entries)| {
+                entries
+                    .iter()
+                    .map(move |(key, value)| upsert(index, key.clone(), value))
+            })
+            .collect(),
+    }
+}
+
+fn upsert(db: usize, key: Bytes, value: &Value) -> Row {
+    Row::Upsert {
+        db: db as i64,
+        key,
+        value: encode(value),
+        kind: value.type_name(),
+    }
+}
+
+fn write_sqlite(
+    connection: &mut rusqlite::Connection,
+    statements: &Statements,
+    batch: &Batch,
+) -> Result<(), rusqlite::Error> {
+    let tx = connection.transaction()?;
+    if batch.replace {
+        tx.execute(&statements.schema, [])?;
+        tx.execute(&statements.clear, [])?;
+    }
+    {
+        let mut upsert = tx.prepare_cached(&statements.upsert)?;
+        let mut delete = tx.prepare_cached(&statements.delete)?;
+        for row in &batch.rows {
+            match row {
+                Row::Upsert {
+                    db,
+                    key,
+                    value,
+                    kind,
+                } => upsert.execute(rusqlite::params![db, &key[..], value, kind])?,
+                Row::Delete { db, key } => delete.execute(rusqlite::params![db, &key[..]])?,
+            };
+        }
+    }
+    tx.commit()
+}
+
+async fn write_postgres(
+    client: &mut tokio_postgres::Client,
+    statements: &Statements,
+    batch: &Batch,
+) -> Result<(), tokio_postgres::Error> {
+    let tx = client.transaction().await?;
+    if batch.replace {
+        tx.execute(&statements.schema, &[]).await?;
+        tx.execute(&statements.clear, &[]).await?;
+    }
+    let upsert = tx.prepare(&statements.upsert).await?;
+    let delete = tx.prepare(&statements.delete).await?;
+    for row in &batch.rows {
+        match row {
+            Row::Upsert {
+                db,
+                key,
+                value,
+                kind,
+            } => tx.execute(&upsert, &[db, &&key[..], value, kind]).await?,
+            Row::Delete { db, key } => tx.execute(&delete, &[db, &&key[..]]).await?,
+        };
+    }
+    tx.commit().await
+}
+
+/// Strings are stored as they are; sets and lists as a RESP array of their
+/// members and sorted sets as one of members and scores.
+fn encode(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(s) => s.to_vec(),
+        Value::Set(set) => {
+            let mut members: Vec<_> = set.iter().collect();
+            members.sort();
+            RespValue::Array(
+                members
+                    .into_iter()
+                    .map(|m| RespValue::bulk(m.clone()))
+                    .collect(),
+            )
+            .serialize()
+        }
+        Value::SortedSet(zset) => RespValue::Array(
+            zset.iter()
+                .flat_map(|(member, score)| {
+                    [
+                        RespValue::bulk(member.clone()),
+                        RespValue::bulk(crate::commands::format_score(score)),
+                    ]
+                })
+                .collect(),
+        )
+        .serialize(),
+        Value::List(list) => {
+            RespValue::Array(list.iter().map(|e| RespValue::bulk(e.clone())).collect()).serialize()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(url: &str) -> Sink {
+        Sink::new(&SinkConfig {
+            url: Some(url.to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_config_is_checked() {
+        assert_eq!(sink("sqlite:/tmp/rdb.db").target, "/tmp/rdb.db");
+        assert_eq!(sink("postgres://localhost/rdb").dialect, Dialect::Postgres);
+        assert!(matches!(
+            Sink::new(&SinkConfig::default()),
+            Err(SinkError::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            Sink::new(&SinkConfig {
+                url: Some("sqlite:x.db".to_string()),
+                table: "keys; DROP TABLE users".to_string(),
+                ..Default::default()
+            }),
+            Err(SinkError::InvalidTable(_))
+        ));
+    }
+
+    #[test]
+    fn test_statements() {
+        let postgres = sink("postgres://localhost/rdb").statements();
+        assert_eq!(
+            postgres.delete,
+            "DELETE FROM rdb_keys WHERE db = $1 AND \"key\" = $2"
+        );
+        assert!(postgres.schema.contains("\"key\" BYTEA NOT NULL"));
+        let sqlite = sink("sqlite:x.db").statements();
+        assert!(sqlite.upsert.contains("VALUES (?1, ?2, ?3, ?4, NULL)"));
+    }
+
+    #[tokio::test]
+    async fn test_writes_to_sqlite() {
+        let path = std::env::temp_dir().join(format!("rdb-sink-test-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut sink = sink(&format!("sqlite:{}", path.display()));
+
+        let set = Value::Set(["b".into(), "a".into()].into_iter().collect());
+        let databases = vec![
+            vec![("gone".into(), Value::String("x".into()))],
+            vec![("k".into(), set)],
+        ];
+        sink.write(resync_batch(&databases)).await.unwrap();
+        sink.write(Batch {
+            replace: false,
+            rows: vec![
+                upsert(0, "s".into(), &Value::String("it's".into())),
+                Row::Delete {
+                    db: 0,
+                    key: "gone".into(),
+                },
+            ],
+        })
+        .await
+        .unwrap();
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        let mut query = connection
+            .prepare("SELECT db, \"key\", \"value\", \"type\" FROM rdb_keys ORDER BY db")
+            .unwrap();
+        let rows: Vec<(i64, Vec<u8>, Vec<u8>, String)> = query
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (0, b"s".to_vec(), b"it's".to_vec(), "string".to_string(),),
+                (
+                    1,
+                    b"k".to_vec(),
+                    b"*2\r\n$1\r\na\r\n$1\r\nb\r\n".to_vec(),
+                    "set".to_string(),
+                ),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_mirrors_writes() {
+        use crate::commands::{execute, Command};
+        use crate::connection::{ClientRegistry, ConnCtx};
+        use crate::storage::{Deadline, Shards};
+
+        let path = std::env::temp_dir().join(format!("rdb-sink-run-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sink = Sink::new(&SinkConfig {
+            url: Some(format!("sqlite:{}", path.display())),
+            flush_interval_ms: 10,
+            ..Default::default()
+        })
+        .unwrap();
+        let db = Arc::new(Shards::new(Default::default()));
+        let running = tokio::spawn(sink.run(db.clone()));
+
+        let set = Command::Set("k".into(), "v".into(), Default::default());
+        let reply = tokio::time::timeout(
+            Duration::from_secs(5),
+            execute(
+                set,
+                &db,
+                &ConnCtx::default(),
+                &ClientRegistry::new(),
+                Deadline::after(None),
+            ),
+        )
+        .await
+        .expect("the sink left the keyspace locked");
+        assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+
+        let mut value = None;
+        for _ in 0..500 {
+            value = rusqlite::Connection::open(&path)
+                .and_then(|connection| {
+                    connection.query_row(
+                        "SELECT \"value\" FROM rdb_keys WHERE db = 0 AND \"key\" = X'6b'",
+                        [],
+                        |row| row.get::<_, Vec<u8>>(0),
+                    )
+                })
+                .ok();
+            if value.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        running.abort();
+        assert_eq!(value.as_deref(), Some(&b"v"[..]));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+//! Server-wide counters behind `INFO stats`, `INFO commandstats` and the
+//! metrics endpoint
+//!
+//! Everything is a plain atomic or a short-held lock, so the hot paths can
+//! count without waiting on each other.
+use crate::config::LatencyConfig;
+use crate::latency::{LatencyMonitor, SlowLog, UserLatency};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Calls of one command and the time spent running them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub usec: u64,
+}
+
+#[derive(Default)]
+pub struct Stats {
+    commands: Mutex<HashMap<&'static str, CommandStats>>,
+    connections_received: AtomicU64,
+    rejected_connections: AtomicU64,
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
+    expired_keys: AtomicU64,
+    evicted_keys: AtomicU64,
+    eviction_vetoes: AtomicU64,
+    pipeline_yields: AtomicU64,
+    result_cache_hits: AtomicU64,
+    result_cache_misses: AtomicU64,
+    hot_key_hits: AtomicU64,
+    slowlog: SlowLog,
+    latency: LatencyMonitor,
+    users: UserLatency,
+}
+
+impl Stats {
+    pub fn configure_latency(&self, config: &LatencyConfig) {
+        self.slowlog
+            .configure(config.slowlog_slower_than_us, config.slowlog_max_len);
+        self.latency.set_threshold(config.monitor_threshold_ms);
+    }
+
+    /// Counts one call of the command named `name`, which took `elapsed`.
+    pub fn record_command(&self, name: &'static str, elapsed: Duration) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(name).or_default();
+        stats.calls += 1;
+        stats.usec += elapsed.as_micros() as u64;
+    }
+
+    /// Counts a client connecting, or being turned away for the connection
+    /// limit.
+    pub fn record_connection(&self, accepted: bool) {
+        let counter = if accepted {
+            &self.connections_received
+        } else {
+            &self.rejected_connections
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts keys read commands found, and those they didn't.
+    pub fn record_lookups(&self, hits: u64, misses: u64) {
+        self.keyspace_hits.fetch_add(hits, Ordering::Relaxed);
+        self.keyspace_misses.fetch_add(misses, Ordering::Relaxed);
+    }
+
+    pub fn record_expired(&self, keys: u64) {
+        self.expired_keys.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    /// Counts keys evicted at `max_memory`, by the shard evicting them.
+    pub fn record_evicted(&self, keys: u64) {
+        self.evicted_keys.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    /// Counts keys an eviction hook kept from being evicted.
+    pub fn record_eviction_vetoes(&self, keys: u64) {
+        if keys > 0 {
+            self.eviction_vetoes.fetch_add(keys, Ordering::Relaxed);
+        }
+    }
+
+    /// Counts a connection letting others go before the rest of its
+    /// pipeline.
+    pub fn record_yield(&self) {
+        self.pipeline_yields.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a cacheable read answered from the result cache, or not.
+    pub fn record_result_cache(&self, hit: bool) {
+        let counter = if hit {
+            &self.result_cache_hits
+        } else {
+            &self.result_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a `GET` answered from a hot key's copy, without locking.
+    pub fn record_hot_key_hit(&self) {
+        self.hot_key_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every command called so far, by name.
+    pub fn commands(&self) -> Vec<(&'static str, CommandStats)> {
+        let mut commands: Vec<_> = self
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (*name, *stats))
+            .collect();
+        commands.sort_unstable_by_key(|(name, _)| *name);
+        commands
+    }
+
+    pub fn total_commands(&self) -> u64 {
+        let commands = self.commands.lock().unwrap();
+        commands.values().map(|stats| stats.calls).sum()
+    }
+
+    pub fn connections_received(&self) -> u64 {
+        self.connections_received.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn keyspace_hits(&self) -> u64 {
+        self.keyspace_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn keyspace_misses(&self) -> u64 {
+        self.keyspace_misses.load(Ordering::Relaxed)
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn eviction_vetoes(&self) -> u64 {
+        self.eviction_vetoes.load(Ordering::Relaxed)
+    }
+
+    pub fn pipeline_yields(&self) -> u64 {
+        self.pipeline_yields.load(Ordering::Relaxed)
+    }
+
+    pub fn result_cache_hits(&self) -> u64 {
+        self.result_cache_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn result_cache_misses(&self) -> u64 {
+        self.result_cache_misses.load(Ordering::Relaxed)
+    }
+
+    pub fn hot_key_hits(&self) -> u64 {
+        self.hot_key_hits.load(Ordering::Relaxed)
+    }
+
+    pub fn slowlog(&self) -> &SlowLog {
+        &self.slowlog
+    }
+
+    pub fn latency(&self) -> &LatencyMonitor {
+        &self.latency
+    }
+
+    pub fn users(&self) -> &UserLatency {
+        &self.users
+    }
+
+    /// Formats the `# Stats` section of an `INFO` reply.
+    pub fn to_info_section(&self) -> String {
+        format!(
+            "# Stats\r\n\
+            total_connections_received:{}\r\n\
+            total_commands_processed:{}\r\n\
+            rejected_connections:{}\r\n\
+            expired_keys:{}\r\n\
+            evicted_keys:{}\r\n\
+            keyspace_hits:{}\r\n\
+            keyspace_misses:{}\r\n\
+            pipeline_yields:{}\r\n\
+            result_cache_hits:{}\r\n\
+            result_cache_misses:{}\r\n\
+            hot_key_hits:{}\r\n\
+            eviction_vetoes:{}\r\n",
+            self.connections_received(),
+            self.total_commands(),
+            self.rejected_connections(),
+            self.expired_keys(),
+            self.evicted_keys(),
+            self.keyspace_hits(),
+            self.keyspace_misses(),
+            self.pipeline_yields(),
+            self.result_cache_hits(),
+            self.result_cache_misses(),
+            self.hot_key_hits(),
+            self.eviction_vetoes(),
+        )
+    }
+
+    /// Formats the `# Commandstats` section of an `INFO` reply.
+    pub fn to_commandstats_section(&self) -> String {
+        let mut info = String::from("# Commandstats\r\n");
+        for (name, stats) in self.commands() {
+            info.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\r\n",
+                name,
+                stats.calls,
+                stats.usec,
+                stats.usec as f64 / stats.calls as f64
+            ));
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_commands_and_lookups() {
+        let stats = Stats::default();
+        stats.record_command("get", Duration::from_micros(3));
+        stats.record_command("set", Duration::from_micros(10));
+        stats.record_command("get", Duration::from_micros(5));
+        stats.record_lookups(1, 2);
+        stats.record_evicted(4);
+        stats.record_connection(true);
+        stats.record_connection(false);
+
+        assert_eq!(
+            stats.commands(),
+            vec![
+                ("get", CommandStats { calls: 2, usec: 8 }),
+                ("set", CommandStats { calls: 1, usec: 10 })
+            ]
+        );
+        let info = stats.to_info_section();
+        assert!(info.contains("total_connections_received:1\r\n"));
+        assert!(info.contains("total_commands_processed:3\r\n"));
+        assert!(info.contains("rejected_connections:1\r\n"));
+        assert!(info.contains("evicted_keys:4\r\nkeyspace_hits:1\r\nkeyspace_misses:2\r\n"));
+        assert_eq!(
+            stats.to_commandstats_section(),
+            "# Commandstats\r\n\
+             cmdstat_get:calls=2,usec=8,usec_per_call=4.00\r\n\
+             cmdstat_set:calls=1,usec=10,usec_per_call=10.00\r\n"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Early warning as memory fills up
+//!
+//! Eviction and rejected writes start at `max_memory`; `memory_alarms`
+//! sets percentages of it to hear about before that. Once a second the
+//! shards' memory is added up, and when it crosses one of them going up,
+//! a warning is logged and `raised:<percent>` published on
+//! [`ALARM_CHANNEL`]; going back under, `cleared:<percent>`. Only the
+//! highest threshold crossed counts, so a jump from 70% to 95% is one
+//! alarm, not two. `INFO memory` shows the alarm in effect, if any.
+use super::{ShardLocks, Shards};
+use crate::storage::Db;
+use bytes::Bytes;
+use log::{info, warn};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Where alarms are published, whatever `notify-keyspace-events` says:
+/// setting thresholds is asking for them.
+pub const ALARM_CHANNEL: &str = "__rdb__:memory";
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+impl Shards {
+    /// The highest alarm threshold memory is over, in percent of
+    /// `max_memory`, or `None`.
+    pub fn memory_alarm(&self) -> Option<u8> {
+        match self.memory_alarm.load(Ordering::Relaxed) {
+            0 => None,
+            percent => Some(percent),
+        }
+    }
+
+    /// Raises or clears alarms for `used` bytes in use.
+    fn check_memory_alarms(&self, used: usize) {
+        let (level, max_memory) = {
+            let config = self.config();
+            let level = config
+                .memory_alarms
+                .iter()
+                .copied()
+                .filter(|&percent| {
+                    percent > 0 && used as u128 * 100 >= config.max_memory as u128 * percent as u128
+                })
+                .max()
+                .unwrap_or(0);
+            (level, config.max_memory)
+        };
+        let previous = self.memory_alarm.swap(level, Ordering::Relaxed);
+        if level > previous {
+            warn!(
+                "Memory usage over {}% of maxmemory: {} of {} bytes",
+                level, used, max_memory
+            );
+            self.publish_alarm(format!("raised:{}", level));
+        } else if level < previous {
+            info!(
+                "Memory usage back under {}% of maxmemory: {} of {} bytes",
+                previous, used, max_memory
+            );
+            self.publish_alarm(format!("cleared:{}", previous));
+        }
+    }
+
+    fn publish_alarm(&self, message: String) {
+        self.broker()
+            .publish(Bytes::from_static(ALARM_CHANNEL.as_bytes()), message.into());
+    }
+}
+
+impl ShardLocks<'_> {
+    pub fn memory_alarm(&self) -> Option<u8> {
+        self.db.memory_alarm()
+    }
+}
+
+/// Background task adding up the memory in use, a shard at a time, and
+/// raising or clearing alarms.
+pub async fn run_memory_alarms(db: Db) {
+    let mut tick = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tick.tick().await;
+        if db.config().memory_alarms.is_empty() && db.memory_alarm().is_none() {
+            continue;
+        }
+        let mut used = 0;
+        for shard in db.shards.iter() {
+            used += shard.read().await.memory_usage();
+        }
+        db.check_memory_alarms(used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::protocol::RespValue;
+    use crate::pubsub::Subscriber;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_alarms_rise_and_clear() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1000,
+            memory_alarms: vec![80, 90],
+            ..Default::default()
+        });
+        let mut subscriber = Subscriber::new(Arc::clone(db.broker()));
+        subscriber.subscribe(vec![ALARM_CHANNEL.into()]);
+        let message = |payload: &'static str| {
+            RespValue::Push(vec![
+                RespValue::bulk("message"),
+                RespValue::bulk(ALARM_CHANNEL),
+                RespValue::bulk(payload),
+            ])
+        };
+
+        db.check_memory_alarms(500);
+        assert_eq!(db.memory_alarm(), None);
+        // Straight past both: one alarm, for the higher.
+        db.check_memory_alarms(950);
+        assert_eq!(db.memory_alarm(), Some(90));
+        assert_eq!(subscriber.recv().await, message("raised:90"));
+        db.check_memory_alarms(960);
+        db.check_memory_alarms(850);
+        assert_eq!(db.memory_alarm(), Some(80));
+        assert_eq!(subscriber.recv().await, message("cleared:90"));
+        db.check_memory_alarms(100);
+        assert_eq!(db.memory_alarm(), None);
+        assert_eq!(subscriber.recv().await, message("cleared:80"));
+    }
+}
@@ -0,0 +1,161 @@
+//! `DEBUG BIGKEYS`: the largest key of each type
+//!
+//! The walk goes a page of keys at a time with a single shard locked, like
+//! `SCAN`, so other clients keep being served while it runs. Strings are
+//! measured in bytes and collections in members, as `redis-cli --bigkeys`
+//! does.
+use super::{Db, ShardLocks, StorageError, Value};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+
+/// Keys looked at per lock.
+const PAGE: usize = 256;
+
+/// Keys of one type seen by the walk.
+#[derive(Debug, Default, PartialEq)]
+pub struct TypeSummary {
+    pub keys: u64,
+    /// Bytes or members over all the keys.
+    pub total: u64,
+    pub biggest: Option<(Bytes, u64)>,
+}
+
+/// What the walk found, by type name.
+#[derive(Debug, Default, PartialEq)]
+pub struct BigKeys(pub BTreeMap<&'static str, TypeSummary>);
+
+impl BigKeys {
+    fn record(&mut self, key: &Bytes, value: &Value) {
+        let size = match value {
+            Value::String(s) => s.len(),
+            Value::Set(set) => set.len(),
+            Value::SortedSet(zset) => zset.len(),
+            Value::List(list) => list.len(),
+        } as u64;
+        let summary = self.0.entry(value.type_name()).or_default();
+        summary.keys += 1;
+        summary.total += size;
+        if summary
+            .biggest
+            .as_ref()
+            .is_none_or(|(_, most)| size > *most)
+        {
+            summary.biggest = Some((key.clone(), size));
+        }
+    }
+
+    /// The report, in the words of `redis-cli --bigkeys`.
+    pub fn report(&self) -> String {
+        let unit = |type_name: &str| {
+            if type_name == "string" {
+                "bytes"
+            } else {
+                "members"
+            }
+        };
+        let mut report = String::new();
+        for (type_name, summary) in &self.0 {
+            if let Some((key, size)) = &summary.biggest {
+                report.push_str(&format!(
+                    "Biggest {} found '{}' has {} {}\n",
+                    type_name,
+                    String::from_utf8_lossy(key),
+                    size,
+                    unit(type_name)
+                ));
+            }
+        }
+        for (type_name, summary) in &self.0 {
+            report.push_str(&format!(
+                "{} {}s with {} {} (avg size {:.2})\n",
+                summary.keys,
+                type_name,
+                summary.total,
+                unit(type_name),
+                summary.total as f64 / summary.keys as f64
+            ));
+        }
+        report
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Records the keys of the locked shard `index` from position `from`
+    /// on, up to a page of them, and returns where the next page starts.
+    fn big_keys_page(&self, index: usize, from: u64, found: &mut BigKeys) -> Option<u64> {
+        let shard = self.guards[index]
+            .as_deref()
+            .expect("the shard being walked is locked");
+        let (keys, next) = shard.scan(from, PAGE);
+        for key in keys {
+            if let Some(value) = shard.value(key) {
+                found.record(key, value);
+            }
+        }
+        next
+    }
+}
+
+/// Walks database `index`, a page at a time.
+pub async fn big_keys(db: &Db, index: usize) -> Result<BigKeys, StorageError> {
+    let mut found = BigKeys::default();
+    for shard in 0..db.count() {
+        let mut from = Some(0);
+        while let Some(position) = from {
+            let mut store = db.lock_shard(shard).await;
+            store.select(index)?;
+            from = store.big_keys_page(shard, position, &mut found);
+            drop(store);
+            tokio::task::yield_now().await;
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_big_keys() {
+        let db: Db = Arc::new(Shards::new(StorageConfig {
+            shards: 4,
+            ..Default::default()
+        }));
+        {
+            let mut store = db.lock_all().await;
+            for i in 0..1000 {
+                let key = Bytes::from(format!("key:{}", i));
+                store.shard_mut(&key).insert(key.clone(), "v".into());
+            }
+            store
+                .shard_mut(b"big")
+                .insert("big".into(), "0123456789".into());
+            store
+                .shard_mut(b"s")
+                .sadd(b"s", vec!["a".into(), "b".into()])
+                .unwrap();
+        }
+
+        let found = big_keys(&db, 0).await.unwrap();
+        assert_eq!(
+            found.0["string"],
+            TypeSummary {
+                keys: 1001,
+                total: 1010,
+                biggest: Some(("big".into(), 10)),
+            }
+        );
+        assert_eq!(
+            found.report(),
+            "Biggest set found 's' has 2 members\n\
+             Biggest string found 'big' has 10 bytes\n\
+             1 sets with 2 members (avg size 2.00)\n\
+             1001 strings with 1010 bytes (avg size 1.01)\n"
+        );
+        assert_eq!(big_keys(&db, 1).await.unwrap(), BigKeys::default());
+    }
+}
@@ -0,0 +1,126 @@
+//! Clients waiting on lists, for `BLPOP` and `BRPOP`
+//!
+//! A blocked client registers on each of its keys, in the order clients
+//! arrive, then tries its pop; pushes wake every client registered on the
+//! key pushed to, which retry in that order. Registering before the first
+//! try means a push landing in between still counts: the wakeup is kept
+//! until the client waits for it.
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// The clients registered on one key, by id, oldest first.
+type Queue = Vec<(u64, Arc<Notify>)>;
+
+/// Clients registered on each key, by database and key.
+#[derive(Default)]
+pub struct Waiters {
+    next_id: AtomicU64,
+    queues: Mutex<HashMap<(usize, Bytes), Queue>>,
+}
+
+impl Waiters {
+    /// Registers a client on `keys` of database `db`, until the returned
+    /// waiter is dropped.
+    pub fn register(&self, db: usize, keys: &[Bytes]) -> Waiter<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+        let mut queues = self.queues.lock().unwrap();
+        for key in keys {
+            queues
+                .entry((db, key.clone()))
+                .or_default()
+                .push((id, notify.clone()));
+        }
+        Waiter {
+            waiters: self,
+            id,
+            db,
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    /// Wakes the clients waiting on `key` of database `db`.
+    pub fn wake(&self, db: usize, key: &[u8]) {
+        let queues = self.queues.lock().unwrap();
+        if queues.is_empty() {
+            return;
+        }
+        if let Some(queue) = queues.get(&(db, Bytes::copy_from_slice(key))) {
+            for (_, notify) in queue {
+                notify.notify_one();
+            }
+        }
+    }
+
+    /// Keys with clients waiting on them.
+    pub fn len(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// One blocked client's registration.
+pub struct Waiter<'a> {
+    waiters: &'a Waiters,
+    id: u64,
+    db: usize,
+    keys: Vec<Bytes>,
+    notify: Arc<Notify>,
+}
+
+impl Waiter<'_> {
+    /// Resolves once one of the keys was pushed to since the last wakeup.
+    pub async fn woken(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        let mut queues = self.waiters.queues.lock().unwrap();
+        for key in self.keys.drain(..) {
+            let slot = (self.db, key);
+            if let Some(queue) = queues.get_mut(&slot) {
+                queue.retain(|(id, _)| *id != self.id);
+                if queue.is_empty() {
+                    queues.remove(&slot);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_push_wakes_waiters_on_the_key() {
+        let waiters = Waiters::default();
+        let first = waiters.register(0, &["jobs".into(), "other".into()]);
+        let second = waiters.register(0, &["jobs".into()]);
+        let elsewhere = waiters.register(1, &["jobs".into()]);
+
+        // Woken before waiting: the wakeup is kept.
+        waiters.wake(0, b"jobs");
+        let short = Duration::from_millis(20);
+        assert!(timeout(short, first.woken()).await.is_ok());
+        assert!(timeout(short, second.woken()).await.is_ok());
+        assert!(timeout(short, elsewhere.woken()).await.is_err());
+
+        drop(first);
+        drop(second);
+        assert_eq!(waiters.len(), 1);
+        drop(elsewhere);
+        assert!(waiters.is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+//! Numbered databases: `SELECT`, `DBSIZE`, `FLUSHDB`, `FLUSHALL`, `SWAPDB`
+//!
+//! Each shard holds its part of every database. The selected one lives in
+//! the shard's own fields, where all the commands find it; the others are
+//! parked until selected, so switching is a swap of four maps.
+use super::evict::Evictor;
+use super::expire::{unix_ms, Expires};
+use super::{ShardLocks, Storage, StorageError, Value};
+use crate::config::MaxMemoryPolicy;
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// A database that isn't selected.
+#[derive(Debug)]
+pub(super) struct Keyspace {
+    pub(super) data: HashMap<Bytes, Value>,
+    pub(super) versions: HashMap<Bytes, u64>,
+    pub(super) expires: Expires,
+    pub(super) evictor: Evictor,
+}
+
+impl Keyspace {
+    pub(super) fn new(policy: MaxMemoryPolicy) -> Self {
+        Keyspace {
+            data: HashMap::new(),
+            versions: HashMap::new(),
+            expires: Expires::default(),
+            evictor: Evictor::new(policy),
+        }
+    }
+}
+
+impl Storage {
+    pub fn databases(&self) -> usize {
+        self.parked.len()
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Makes database `index` the one commands act on.
+    pub fn select(&mut self, index: usize) {
+        if index != self.selected {
+            self.swap_keyspace(index);
+            // The parked slot now holds the database just deselected.
+            self.parked.swap(index, self.selected);
+            self.selected = index;
+        }
+    }
+
+    fn swap_keyspace(&mut self, index: usize) {
+        let parked = &mut self.parked[index];
+        std::mem::swap(&mut self.data, &mut parked.data);
+        std::mem::swap(&mut self.versions, &mut parked.versions);
+        std::mem::swap(&mut self.expires, &mut parked.expires);
+        std::mem::swap(&mut self.evictor, &mut parked.evictor);
+    }
+
+    /// The keys and values of database `index`.
+    pub(super) fn database(&self, index: usize) -> &HashMap<Bytes, Value> {
+        if index == self.selected {
+            &self.data
+        } else {
+            &self.parked[index].data
+        }
+    }
+
+    pub(super) fn expires_in(&self, index: usize) -> &Expires {
+        if index == self.selected {
+            &self.expires
+        } else {
+            &self.parked[index].expires
+        }
+    }
+
+    /// [`Storage::watch_version`] of `key` in database `index`.
+    pub fn watch_version_in(&self, index: usize, key: &[u8]) -> u64 {
+        let versions = if index == self.selected {
+            &self.versions
+        } else {
+            &self.parked[index].versions
+        };
+        versions.get(key).copied().unwrap_or(self.removed)
+    }
+
+    /// Drops every key of the selected database.
+    pub fn flush(&mut self) {
+        self.preserve_database(self.selected);
+        self.hot.clear();
+        let size: usize = self.data.iter().map(|(k, v)| k.len() + v.size()).sum();
+        self.current_memory -= size;
+        self.released += size;
+        self.data = HashMap::new();
+        self.versions.clear();
+        self.expires.clear();
+        self.evictor.clear();
+        self.recount_quotas();
+        self.record_removal();
+    }
+
+    /// Exchanges the contents of databases `a` and `b`.
+    pub fn swap_databases(&mut self, a: usize, b: usize) {
+        if a != b {
+            self.preserve_database(a);
+            self.preserve_database(b);
+            self.hot.clear();
+            let selected = self.selected;
+            self.select(a);
+            self.swap_keyspace(b);
+            self.select(selected);
+            self.recount_quotas();
+        }
+        self.record_removal();
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Selects database `index` in every locked shard. Shards locked for
+    /// reading must have it selected already.
+    pub fn select(&mut self, index: usize) -> Result<(), StorageError> {
+        if index >= self.db.databases() {
+            return Err(StorageError::InvalidDbIndex);
+        }
+        if self.iter().any(|shard| shard.selected() != index) {
+            for shard in self.iter_mut() {
+                shard.select(index);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn selected(&self) -> usize {
+        self.any().selected()
+    }
+
+    pub fn flushdb(&mut self) {
+        for shard in self.iter_mut() {
+            shard.flush();
+        }
+    }
+
+    pub fn flushall(&mut self) {
+        debug_assert!(self.is_complete());
+        let selected = self.selected();
+        for index in 0..self.db.databases() {
+            self.select(index).expect("index is in range");
+            self.flushdb();
+        }
+        self.select(selected).expect("index is in range");
+    }
+
+    pub fn swapdb(&mut self, a: usize, b: usize) -> Result<(), StorageError> {
+        debug_assert!(self.is_complete());
+        let databases = self.db.databases();
+        if a >= databases || b >= databases {
+            return Err(StorageError::InvalidDbIndex);
+        }
+        for shard in self.iter_mut() {
+            shard.swap_databases(a, b);
+        }
+        Ok(())
+    }
+
+    /// Keys in each database over the locked shards, indexed by database.
+    pub fn database_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![0; self.db.databases()];
+        for shard in self.iter() {
+            for (index, size) in sizes.iter_mut().enumerate() {
+                *size += shard.database(index).len();
+            }
+        }
+        sizes
+    }
+
+    /// Formats the `# Keyspace` section of an `INFO` reply, listing the
+    /// databases holding keys, with how many have a TTL and its average.
+    pub fn keyspace_info(&self) -> String {
+        let now = unix_ms();
+        let mut info = String::from("# Keyspace\r\n");
+        for (index, keys) in self.database_sizes().into_iter().enumerate() {
+            if keys > 0 {
+                let (mut expires, mut total_ttl) = (0, 0);
+                for shard in self.iter() {
+                    expires += shard.expires_in(index).len();
+                    total_ttl += shard.expires_in(index).total_ttl(now);
+                }
+                let avg_ttl = total_ttl.checked_div(expires as u64).unwrap_or(0);
+                info.push_str(&format!(
+                    "db{}:keys={},expires={},avg_ttl={}\r\n",
+                    index, keys, expires, avg_ttl
+                ));
+            }
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+
+    #[tokio::test]
+    async fn test_databases_are_separate() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024,
+            shards: 2,
+            databases: 3,
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        store.shard_mut(b"k").insert("k".into(), "zero".into());
+        store.select(2).unwrap();
+        assert_eq!(store.shard(b"k").get(b"k"), Ok(None));
+        store.shard_mut(b"k").insert("k".into(), "two".into());
+        store.shard_mut(b"x").insert("x".into(), "two".into());
+        assert_eq!(store.key_count(), 2);
+        assert_eq!(store.select(3), Err(StorageError::InvalidDbIndex));
+        // Memory is shared by all databases.
+        assert_eq!(store.memory_usage(), 1 + 4 + 2 * (1 + 3));
+
+        let version = store.shard(b"k").watch_version_in(0, b"k");
+        store.swapdb(0, 2).unwrap();
+        assert_eq!(store.shard(b"k").get(b"k"), Ok(Some(&Bytes::from("zero"))));
+        assert_ne!(store.shard(b"k").watch_version_in(0, b"k"), version);
+        assert_eq!(store.database_sizes(), vec![2, 0, 1]);
+        assert_eq!(
+            store.keyspace_info(),
+            "# Keyspace\r\ndb0:keys=2,expires=0,avg_ttl=0\r\n\
+             db2:keys=1,expires=0,avg_ttl=0\r\n"
+        );
+
+        store.flushdb();
+        assert_eq!(store.database_sizes(), vec![2, 0, 0]);
+        assert_eq!(store.memory_usage(), 2 * (1 + 3));
+        store.flushall();
+        assert_eq!(store.database_sizes(), vec![0, 0, 0]);
+        assert_eq!(store.memory_usage(), 0);
+        assert_eq!(store.selected(), 2);
+
+        // A new lock starts out on database 0.
+        drop(store);
+        assert_eq!(db.lock_all().await.selected(), 0);
+    }
+}
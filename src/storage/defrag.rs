@@ -0,0 +1,235 @@
+//! Active defragmentation: reallocating long-lived entries after churn
+use super::{Db, ShardLocks, SortedSet, Storage, Value};
+use bytes::Bytes;
+use indexmap::IndexSet;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Progress of the current pass and totals over all passes.
+#[derive(Debug, Default)]
+pub struct Defrag {
+    /// Keys left to visit in the running pass, with their database.
+    pending: Option<Vec<(usize, Bytes)>>,
+    /// Allocations moved: a key, a string value or a collection member.
+    pub hits: u64,
+    /// Keys visited and reallocated.
+    pub key_hits: u64,
+    /// Keys deleted before the pass got to them.
+    pub key_misses: u64,
+    pub passes: u64,
+}
+
+impl Defrag {
+    pub fn is_running(&self) -> bool {
+        self.pending.is_some()
+    }
+}
+
+impl Storage {
+    /// Estimated ratio of memory held to memory used. Freed entries leave
+    /// holes behind, and collections keep their capacity after shrinking,
+    /// until a defrag pass reallocates them.
+    pub fn fragmentation_ratio(&self) -> f64 {
+        if self.current_memory == 0 {
+            return 1.0;
+        }
+        (self.current_memory + self.released) as f64 / self.current_memory as f64
+    }
+
+    pub fn defrag_stats(&self) -> &Defrag {
+        &self.defrag
+    }
+
+    fn is_fragmented(&self) -> bool {
+        let config = &self.config.defrag;
+        self.released >= config.ignore_bytes
+            && self.released * 100 >= self.current_memory * config.threshold_percent
+    }
+
+    /// Runs one step of defragmentation, starting a pass if fragmentation
+    /// crossed the thresholds. Returns whether a pass is still running.
+    pub fn defrag_cycle(&mut self) -> bool {
+        if self.defrag.pending.is_none() {
+            if !self.is_fragmented() {
+                return false;
+            }
+            let keys = (0..self.databases())
+                .flat_map(|index| {
+                    self.database(index)
+                        .keys()
+                        .map(move |key| (index, key.clone()))
+                })
+                .collect();
+            self.defrag.pending = Some(keys);
+        }
+
+        let selected = self.selected;
+        let budget = self.config.defrag.keys_per_cycle.max(1);
+        let mut running = true;
+        for _ in 0..budget {
+            let Some((index, key)) = self.defrag.pending.as_mut().and_then(|keys| keys.pop())
+            else {
+                running = false;
+                break;
+            };
+            self.select(index);
+            match self.data.remove(&key) {
+                Some(value) => {
+                    // `key` still shares the old allocation; it's freed
+                    // once this handle drops too.
+                    let key = self.slab.copy(&key);
+                    let (value, moved) = self.reallocate(value);
+                    self.data.insert(key, value);
+                    self.defrag.hits += 1 + moved;
+                    self.defrag.key_hits += 1;
+                }
+                None => self.defrag.key_misses += 1,
+            }
+        }
+        if !running {
+            self.finish_defrag();
+        }
+        self.select(selected);
+        running
+    }
+
+    fn finish_defrag(&mut self) {
+        for index in 0..self.databases() {
+            self.select(index);
+            self.data.shrink_to_fit();
+            self.versions.shrink_to_fit();
+        }
+        self.released = 0;
+        self.defrag.pending = None;
+        self.defrag.passes += 1;
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Ratio of memory held to memory used, over the locked shards.
+    pub fn fragmentation_ratio(&self) -> f64 {
+        let used = self.memory_usage();
+        if used == 0 {
+            return 1.0;
+        }
+        let released: usize = self.iter().map(|shard| shard.released).sum();
+        (used + released) as f64 / used as f64
+    }
+
+    /// Defrag counters summed over the shards; running while any shard is
+    /// in a pass.
+    pub fn defrag_stats(&self) -> Defrag {
+        let mut total = Defrag::default();
+        for stats in self.iter().map(Storage::defrag_stats) {
+            if stats.is_running() {
+                total.pending = Some(Vec::new());
+            }
+            total.hits += stats.hits;
+            total.key_hits += stats.key_hits;
+            total.key_misses += stats.key_misses;
+            total.passes += stats.passes;
+        }
+        total
+    }
+}
+
+impl Storage {
+    /// Copies `value` into fresh, tightly sized allocations, packing small
+    /// ones into the current slab chunk. Interned strings stay shared.
+    /// Returns it with the number of allocations moved.
+    fn reallocate(&mut self, value: Value) -> (Value, u64) {
+        match value {
+            Value::String(s) => match self.interner.get(&s) {
+                Some(shared) => (Value::String(shared), 0),
+                None => (Value::String(self.slab.copy(&s)), 1),
+            },
+            Value::Set(set) => {
+                let mut fresh = IndexSet::with_capacity(set.len());
+                fresh.extend(set.iter().map(|m| self.slab.copy(m)));
+                let moved = fresh.len() as u64;
+                (Value::Set(fresh), moved)
+            }
+            Value::SortedSet(zset) => {
+                let fresh: SortedSet = zset
+                    .iter()
+                    .map(|(m, score)| (self.slab.copy(m), score))
+                    .collect::<Vec<_>>()
+                    .into();
+                let moved = fresh.len() as u64;
+                (Value::SortedSet(fresh), moved)
+            }
+            Value::List(list) => {
+                let fresh: VecDeque<Bytes> = list.iter().map(|e| self.slab.copy(e)).collect();
+                let moved = fresh.len() as u64;
+                (Value::List(fresh), moved)
+            }
+        }
+    }
+}
+
+/// Background task running defrag steps every `interval`. Each step holds a
+/// shard's lock for at most `keys_per_cycle` keys, so clients interleave with
+/// a pass.
+pub async fn run_defrag(db: Db, interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        for shard in db.shards.iter() {
+            shard.write().await.defrag_cycle();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DefragConfig, StorageConfig};
+
+    #[test]
+    fn test_defrag_after_churn() {
+        let mut store = Storage::new(StorageConfig {
+            max_memory: 1024 * 1024,
+            defrag: DefragConfig {
+                ignore_bytes: 100,
+                keys_per_cycle: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        for i in 0..100 {
+            store.insert(format!("key:{}", i).into(), "value".into());
+        }
+        let members: Vec<Bytes> = (0..50).map(|i| format!("m{}", i).into()).collect();
+        store.sadd(b"set", members.clone()).unwrap();
+        store.zadd(b"zset", vec![(1.0, "a".into())]).unwrap();
+        assert!(!store.defrag_cycle());
+
+        store.srem(b"set", &members[..45]).unwrap();
+        for i in 10..100 {
+            store.delete(format!("key:{}", i).as_bytes());
+        }
+        let memory = store.memory_usage();
+        let version = store.version(b"set");
+        assert!(store.fragmentation_ratio() > 1.0);
+
+        let mut steps = 0;
+        while store.defrag_cycle() {
+            steps += 1;
+        }
+        // 12 keys at 8 per step: the second step finishes the pass.
+        assert_eq!(steps, 1);
+        let stats = store.defrag_stats();
+        assert_eq!((stats.key_hits, stats.passes), (12, 1));
+        assert_eq!(stats.hits, 12 + 10 + 5 + 1);
+        assert!(!stats.is_running());
+        assert_eq!(store.fragmentation_ratio(), 1.0);
+
+        // Reallocating isn't a modification.
+        assert_eq!(store.memory_usage(), memory);
+        assert_eq!(store.version(b"set"), version);
+        assert_eq!(store.get(b"key:3").unwrap(), Some(&Bytes::from("value")));
+        assert!(store.sismember(b"set", b"m49").unwrap());
+        assert_eq!(store.zscore(b"zset", b"a"), Ok(Some(1.0)));
+        assert!(store.data.capacity() < 100);
+    }
+}
@@ -0,0 +1,127 @@
+//! `DUMP` and `RESTORE`: moving single keys between servers as serialized
+//! values
+use super::{rdb, Storage, StorageError, Value};
+use bytes::Bytes;
+
+/// How `RESTORE` stores a key, besides its value.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RestoreOptions {
+    /// Overwrite the key if it exists, rather than failing.
+    pub replace: bool,
+    /// Unix time in milliseconds at which the key expires.
+    pub expires_at: Option<u64>,
+    /// Milliseconds since the key was last accessed, as LRU eviction
+    /// comes to see it.
+    pub idle_ms: Option<u64>,
+}
+
+impl Storage {
+    /// The value at `key` serialized for `RESTORE`, or `None` if there is no
+    /// such key.
+    pub fn dump(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.data.get(key).map(rdb::dump)
+    }
+
+    /// Stores the key `DUMP` serialized as `payload`. An expiry already past
+    /// leaves nothing behind, as if the key expired right away.
+    pub fn restore(
+        &mut self,
+        key: Bytes,
+        payload: &[u8],
+        options: RestoreOptions,
+    ) -> Result<(), StorageError> {
+        let exists = self.data.contains_key(&key);
+        if exists && !options.replace {
+            return Err(StorageError::BusyKey);
+        }
+        let value = match rdb::undump(payload) {
+            Ok(value) => value,
+            Err(rdb::RdbError::BadPayload) => return Err(StorageError::BadDumpPayload),
+            Err(_) => return Err(StorageError::BadDataFormat),
+        };
+        if options.expires_at.is_some_and(|at| at <= super::unix_ms()) {
+            self.delete(&key);
+            return Ok(());
+        }
+
+        let entry_size = key.len() + value.size();
+        let old_size = self.data.get(&key).map_or(0, |old| key.len() + old.size());
+        if entry_size > old_size {
+            self.make_room(entry_size - old_size, &key)?;
+        }
+        self.release(&key, old_size);
+        self.account(&key, entry_size);
+
+        self.preserve(&key);
+        self.touch(&key);
+        let value = match value {
+            Value::String(s) => Value::String(self.share(s)),
+            value => value,
+        };
+        match self.data.get_mut(&key) {
+            Some(old) => *old = value,
+            None => {
+                let key = self.slab.alloc(key.clone());
+                self.data.insert(key, value);
+            }
+        }
+        match options.expires_at {
+            Some(at) => self.expires.set(&key, at),
+            None => self.expires.remove(&key),
+        }
+        if let Some(idle_ms) = options.idle_ms {
+            self.evictor.record_idle(&key, idle_ms);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MaxMemoryPolicy, StorageConfig};
+
+    #[test]
+    fn test_dump_and_restore() {
+        let mut store = Storage::new(StorageConfig {
+            max_memory: 64,
+            maxmemory_policy: MaxMemoryPolicy::AllKeysLru,
+            ..Default::default()
+        });
+        store.sadd(b"set", vec!["a".into(), "b".into()]).unwrap();
+        let payload = store.dump(b"set").unwrap();
+        assert_eq!(store.dump(b"missing"), None);
+
+        let copy = RestoreOptions::default();
+        assert_eq!(
+            store.restore("set".into(), &payload, copy),
+            Err(StorageError::BusyKey)
+        );
+        store.restore("copy".into(), &payload, copy).unwrap();
+        assert_eq!(store.value(b"copy"), store.value(b"set"));
+        assert_eq!(store.memory_usage(), 3 + 2 + 4 + 2);
+        assert_eq!(
+            store.restore("bad".into(), &payload[1..], copy),
+            Err(StorageError::BadDumpPayload)
+        );
+
+        // Restored as idle for an hour, it's the first key to be evicted.
+        let idle = RestoreOptions {
+            replace: true,
+            idle_ms: Some(3_600_000),
+            ..copy
+        };
+        store.restore("set".into(), &payload, idle).unwrap();
+        store.insert("big".into(), vec![b'x'; 52].into());
+        assert!(store.value(b"set").is_none());
+        assert!(store.value(b"copy").is_some());
+
+        let expired = RestoreOptions {
+            replace: true,
+            expires_at: Some(1),
+            ..copy
+        };
+        store.restore("copy".into(), &payload, expired).unwrap();
+        assert!(store.value(b"copy").is_none());
+    }
+}
@@ -0,0 +1,407 @@
+//! Making room at `max_memory` by evicting keys
+//!
+//! An [`EvictionHook`] can be told of each key the policy picks, before it
+//! goes, and keep it: the next candidate is offered instead, up to
+//! [`MAX_VETOES`] of them per key evicted.
+use super::quota::quota_of;
+use super::{unix_ms, ShardLocks, Shards, Storage};
+use crate::config::MaxMemoryPolicy;
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+/// Bits of an access time below the millisecond, counting the accesses
+/// within one.
+const TICK_BITS: u32 = 16;
+
+/// Candidates a hook may keep per key evicted before the database is given
+/// up on, so a hook keeping most keys doesn't make every write crawl.
+pub const MAX_VETOES: usize = 16;
+
+/// Told of each key about to be evicted, for logging evictions or keeping
+/// keys an application can't do without. Hooks run with the key's shard
+/// locked, so they must be quick and can't look at the keyspace.
+pub trait EvictionHook: Send + Sync {
+    /// Whether `key`, in database `db`, may be evicted; `false` keeps it.
+    fn allow(&self, db: usize, key: &[u8]) -> bool;
+}
+
+/// The hook every shard consults, if any; shared like `Storage::stats`.
+pub(super) type HookSlot = Arc<RwLock<Option<Arc<dyn EvictionHook>>>>;
+
+/// Tracks when each key was last accessed and picks the keys to evict.
+/// Access times are Unix milliseconds with a counter below them, so each
+/// is unique; keys are indexed by them, so the least recently used one is
+/// always at hand.
+#[derive(Debug)]
+pub struct Evictor {
+    policy: MaxMemoryPolicy,
+    /// The latest access time handed out.
+    clock: u64,
+    accessed: HashMap<Bytes, u64>,
+    by_access: BTreeMap<u64, Bytes>,
+    /// State of the xorshift generator used by `allkeys-random`.
+    rng: u64,
+}
+
+impl Evictor {
+    pub fn new(policy: MaxMemoryPolicy) -> Self {
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed).expect("failed to seed the eviction sampler");
+        Evictor {
+            policy,
+            clock: 0,
+            accessed: HashMap::new(),
+            by_access: BTreeMap::new(),
+            rng: u64::from_le_bytes(seed) | 1,
+        }
+    }
+
+    /// Whether the policy evicts anything, and so needs accesses tracked.
+    pub fn is_enabled(&self) -> bool {
+        matches!(
+            self.policy,
+            MaxMemoryPolicy::AllKeysLru | MaxMemoryPolicy::AllKeysRandom
+        )
+    }
+
+    pub fn record(&mut self, key: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.clock = (self.clock + 1).max(unix_ms() << TICK_BITS);
+        self.set_access(key, self.clock);
+    }
+
+    /// Makes `key` look last accessed `idle_ms` milliseconds ago, as
+    /// `RESTORE ... IDLETIME` asks.
+    pub fn record_idle(&mut self, key: &[u8], idle_ms: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut at = unix_ms().saturating_sub(idle_ms) << TICK_BITS;
+        while self.by_access.contains_key(&at) {
+            at += 1;
+        }
+        // Later accesses are stamped after it, so they can't collide.
+        self.clock = self.clock.max(at);
+        self.set_access(key, at);
+    }
+
+    fn set_access(&mut self, key: &[u8], at: u64) {
+        match self.accessed.get_mut(key) {
+            Some(old) => {
+                let key = self.by_access.remove(old).expect("indexed key");
+                *old = at;
+                self.by_access.insert(at, key);
+            }
+            None => {
+                // A copy, so the index doesn't pin the key's allocation.
+                let key = Bytes::copy_from_slice(key);
+                self.accessed.insert(key.clone(), at);
+                self.by_access.insert(at, key);
+            }
+        }
+    }
+
+    /// Switches to `policy`. Accesses weren't tracked under a policy that
+    /// doesn't evict, so `keys`, the ones present, start out used alike.
+    pub fn set_policy<'a>(
+        &mut self,
+        policy: MaxMemoryPolicy,
+        keys: impl Iterator<Item = &'a Bytes>,
+    ) {
+        let was_enabled = self.is_enabled();
+        self.policy = policy;
+        if !was_enabled && self.is_enabled() {
+            for key in keys {
+                self.record(key);
+            }
+        }
+    }
+
+    pub fn forget(&mut self, key: &[u8]) {
+        if let Some(at) = self.accessed.remove(key) {
+            self.by_access.remove(&at);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.accessed.clear();
+        self.by_access.clear();
+    }
+
+    /// The next key to evict other than `keep`, among those `eligible`, that
+    /// `allow` lets go, or `None` if the policy has none to offer.
+    fn victim(
+        &mut self,
+        keep: &[u8],
+        eligible: impl Fn(&Bytes) -> bool,
+        mut allow: impl FnMut(&Bytes) -> bool,
+    ) -> Option<Bytes> {
+        let candidates: Box<dyn Iterator<Item = &Bytes>> = match self.policy {
+            MaxMemoryPolicy::AllKeysLru => Box::new(self.by_access.values()),
+            MaxMemoryPolicy::AllKeysRandom => {
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                // The key accessed at a random time, or the next one after.
+                let first = *self.by_access.keys().next()?;
+                let last = *self.by_access.keys().next_back()?;
+                let at = first + self.rng % (last - first + 1);
+                Box::new(
+                    self.by_access
+                        .range(at..)
+                        .chain(self.by_access.range(..at))
+                        .map(|(_, key)| key),
+                )
+            }
+            // `volatile-ttl` goes by the expiry times `Storage` keeps.
+            MaxMemoryPolicy::VolatileTtl | MaxMemoryPolicy::NoEviction => return None,
+        };
+        candidates
+            .filter(|key| key.as_ref() != keep && eligible(key))
+            .take(MAX_VETOES + 1)
+            .find(|key| allow(key))
+            .cloned()
+    }
+}
+
+impl Storage {
+    /// Makes sure `size` more bytes fit under `max_memory`, and under the
+    /// quota of `keep` if it has one, evicting keys other than `keep` as far
+    /// as the policy allows. Only keys of the same quota, or of none if
+    /// `keep` has none, are evicted. Replicas take no decisions of their
+    /// own and apply the deletions of their primary.
+    pub(super) fn make_room(
+        &mut self,
+        size: usize,
+        keep: &[u8],
+    ) -> Result<(), super::StorageError> {
+        if self.follows_primary() {
+            return Ok(());
+        }
+        let selected = self.selected;
+        let quota = self.quota_of(keep);
+        let hook = self.eviction_hook.read().unwrap().clone();
+        while self.current_memory + size > self.config.max_memory || self.over_quota(quota, size) {
+            // Memory is shared by all databases, so once the selected one
+            // has nothing left to give the others are evicted from too.
+            let databases = self.databases();
+            let victim = (0..databases).find_map(|i| {
+                let index = (selected + i) % databases;
+                self.select(index);
+                let keep = if index == selected { keep } else { b"" };
+                let mut vetoed = 0;
+                let allow = |key: &Bytes| match &hook {
+                    Some(hook) if !hook.allow(index, key) => {
+                        vetoed += 1;
+                        false
+                    }
+                    _ => true,
+                };
+                let config = &self.config;
+                let eligible = |key: &Bytes| quota_of(config, index, key) == quota;
+                let victim = match config.maxmemory_policy {
+                    MaxMemoryPolicy::VolatileTtl => self.expires.soonest(keep, eligible, allow),
+                    _ => self.evictor.victim(keep, eligible, allow),
+                };
+                self.stats.record_eviction_vetoes(vetoed);
+                victim
+            });
+            let Some(victim) = victim else {
+                self.select(selected);
+                return Err(super::StorageError::OutOfMemory);
+            };
+            self.delete(&victim);
+            self.last_version += 1;
+            self.stats.record_evicted(1);
+            self.just_evicted.push((self.selected, victim.clone()));
+            // Logged and replicated ahead of the write that needed the room.
+            if self.is_propagating() {
+                self.propagate(&RespValue::Array(vec![
+                    RespValue::bulk("DEL"),
+                    RespValue::bulk(victim),
+                ]));
+            }
+            self.select(selected);
+        }
+        Ok(())
+    }
+
+    /// Keys evicted since the last call, for keyspace notifications.
+    pub fn take_evicted(&mut self) -> Vec<(usize, Bytes)> {
+        std::mem::take(&mut self.just_evicted)
+    }
+
+    /// Marks `keys` as just used, if they exist.
+    pub fn record_access(&mut self, keys: &[Bytes]) {
+        for key in keys {
+            if self.data.contains_key(key) {
+                self.evictor.record(key);
+            }
+        }
+    }
+
+    pub fn is_evicting(&self) -> bool {
+        self.evictor.is_enabled()
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.config.max_memory
+    }
+
+    pub fn maxmemory_policy(&self) -> MaxMemoryPolicy {
+        self.config.maxmemory_policy
+    }
+
+    pub fn set_max_memory(&mut self, max_memory: usize) {
+        self.config.max_memory = max_memory;
+    }
+
+    /// Switches every database's evictor to `policy`.
+    pub fn set_maxmemory_policy(&mut self, policy: MaxMemoryPolicy) {
+        self.config.maxmemory_policy = policy;
+        self.evictor.set_policy(policy, self.data.keys());
+        for keyspace in &mut self.parked {
+            keyspace.evictor.set_policy(policy, keyspace.data.keys());
+        }
+    }
+}
+
+impl Shards {
+    /// Consults `hook` before every eviction from now on, or no hook for
+    /// `None`.
+    pub fn set_eviction_hook(&self, hook: Option<Arc<dyn EvictionHook>>) {
+        *self.eviction_hook.write().unwrap() = hook;
+    }
+}
+
+impl ShardLocks<'_> {
+    pub fn set_eviction_hook(&self, hook: Option<Arc<dyn EvictionHook>>) {
+        self.db.set_eviction_hook(hook);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::StorageError;
+
+    fn store(policy: MaxMemoryPolicy) -> Storage {
+        Storage::new(StorageConfig {
+            max_memory: 8,
+            maxmemory_policy: policy,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut store = store(MaxMemoryPolicy::AllKeysLru);
+        store.insert("a".into(), "1".into());
+        store.insert("b".into(), "1".into());
+        store.insert("c".into(), "1".into());
+        store.insert("d".into(), "1".into());
+        store.record_access(&["a".into()]);
+
+        // 8 bytes hold four keys; "b" is now the least recently used.
+        assert!(store.insert("e".into(), "1".into()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert!(store.get(b"a").unwrap().is_some());
+        assert_eq!(store.stats.evicted_keys(), 1);
+
+        // Growing a set evicts others, oldest first, never the set itself.
+        store.sadd(b"s", vec!["x".into(), "y".into()]).unwrap();
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.get(b"d").unwrap(), None);
+        assert_eq!(store.key_count(), 3);
+        assert_eq!(
+            store.sadd(b"s", vec!["0123456789".into()]),
+            Err(StorageError::OutOfMemory)
+        );
+        assert!(store.sismember(b"s", b"x").unwrap());
+    }
+
+    #[test]
+    fn test_policies_without_candidates_reject_writes() {
+        for policy in [MaxMemoryPolicy::NoEviction, MaxMemoryPolicy::VolatileTtl] {
+            let mut store = store(policy);
+            assert!(store.insert("a".into(), "123".into()));
+            assert!(store.insert("b".into(), "123".into()));
+            assert!(!store.insert("c".into(), "123".into()));
+            assert_eq!(store.key_count(), 2);
+        }
+
+        // Random eviction makes room too, sparing the key being written.
+        let mut store = store(MaxMemoryPolicy::AllKeysRandom);
+        for key in ["a", "b", "c"] {
+            assert!(store.insert(key.into(), "123".into()));
+        }
+        assert_eq!(store.key_count(), 2);
+        assert!(store.get(b"c").unwrap().is_some());
+
+        // Keys with a TTL are candidates for volatile-ttl, soonest first.
+        let mut store = self::store(MaxMemoryPolicy::VolatileTtl);
+        let now = crate::storage::unix_ms();
+        for (key, ttl) in [("a", 2000), ("b", 1000)] {
+            assert!(store.insert(key.into(), "123".into()));
+            store.set_expiry(key.as_bytes(), Some(now + ttl));
+        }
+        assert!(store.insert("c".into(), "123".into()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.expiry(b"b"), None);
+        assert!(store.insert("d".into(), "123".into()));
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert!(!store.insert("e".into(), "123".into()));
+    }
+
+    /// Keeps keys under a prefix, and counts the keys it's asked about.
+    struct KeepPrefix(&'static [u8], std::sync::atomic::AtomicUsize);
+
+    impl EvictionHook for KeepPrefix {
+        fn allow(&self, _db: usize, key: &[u8]) -> bool {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            !key.starts_with(self.0)
+        }
+    }
+
+    #[test]
+    fn test_hooks_keep_keys() {
+        for policy in [MaxMemoryPolicy::AllKeysLru, MaxMemoryPolicy::VolatileTtl] {
+            // Room for the four keys below.
+            let mut store = Storage::new(StorageConfig {
+                max_memory: 12,
+                maxmemory_policy: policy,
+                ..Default::default()
+            });
+            let hook = Arc::new(KeepPrefix(b"s:", Default::default()));
+            *store.eviction_hook.write().unwrap() = Some(hook.clone());
+            let now = unix_ms();
+            for (i, key) in ["s:1", "a", "s:2", "b"].into_iter().enumerate() {
+                assert!(store.insert(key.into(), "1".into()));
+                store.set_expiry(key.as_bytes(), Some(now + 1000 + i as u64));
+            }
+
+            // The oldest keys are kept, and the first others go instead.
+            assert!(store.insert("c".into(), "1".into()));
+            assert_eq!(store.get(b"a").unwrap(), None);
+            assert!(store.get(b"s:1").unwrap().is_some());
+            assert_eq!(store.stats.eviction_vetoes(), 1);
+            store.set_expiry(b"c", Some(now + 5000));
+            assert!(store.insert("d".into(), "1".into()));
+            assert_eq!(store.get(b"b").unwrap(), None);
+            assert!(store.get(b"s:2").unwrap().is_some());
+
+            // With only kept keys left to evict, writes are refused.
+            store.delete(b"c");
+            store.delete(b"d");
+            assert!(store.insert("s:3".into(), "1".into()));
+            assert!(!store.insert("e".into(), "1".into()));
+            assert!(store.get(b"s:1").unwrap().is_some());
+            assert!(hook.1.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        }
+    }
+}
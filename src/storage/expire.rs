@@ -0,0 +1,378 @@
+//! Key expiry: the TTLs `SET` gives keys with `EX`, `PX`, `EXAT` and `PXAT`
+//!
+//! An expired key is deleted by the first write to come across it, or by
+//! the background task, whichever is first. Until then reads pass over it,
+//! so it's gone for clients from the moment it expires.
+use super::shards::{key_hash, Guard};
+use super::{Db, ShardLocks, Storage};
+use crate::glob;
+use crate::protocol::RespValue;
+use crate::pubsub::EventClass;
+use bytes::Bytes;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the background task looks for expired keys.
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+/// Most keys a shard's database deletes per run of the task, so the lock
+/// is held briefly even when many keys expire at once.
+const ACTIVE_EXPIRE_KEYS: usize = 200;
+
+/// The current Unix time in milliseconds, which expiry times are in.
+pub fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// `ttl`, in milliseconds, lengthened by up to `percent` of itself, by an
+/// amount `key`'s hash picks. The same key and TTL always get the same
+/// expiry, but keys written in a burst with the same TTL don't all expire
+/// at once.
+pub fn jittered_ttl(key: &[u8], ttl: u64, percent: u8) -> u64 {
+    let spread = ttl.saturating_mul(u64::from(percent.min(100))) / 100;
+    if spread == 0 {
+        return ttl;
+    }
+    // The low bits pick the shard; the jitter shouldn't follow it.
+    ttl + key_hash(key).rotate_left(32) % (spread + 1)
+}
+
+/// When each key with a TTL expires, indexed by time too so the keys due
+/// are always the first ones.
+#[derive(Debug, Default)]
+pub(super) struct Expires {
+    at: HashMap<Bytes, u64>,
+    by_time: BTreeSet<(u64, Bytes)>,
+}
+
+impl Expires {
+    pub(super) fn get(&self, key: &[u8]) -> Option<u64> {
+        self.at.get(key).copied()
+    }
+
+    pub(super) fn set(&mut self, key: &[u8], at: u64) {
+        self.remove(key);
+        // A copy, so the index doesn't pin the key's allocation.
+        let key = Bytes::copy_from_slice(key);
+        self.at.insert(key.clone(), at);
+        self.by_time.insert((at, key));
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) {
+        if let Some((key, at)) = self.at.remove_entry(key) {
+            self.by_time.remove(&(at, key));
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.at.clear();
+        self.by_time.clear();
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.at.len()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.at.is_empty()
+    }
+
+    pub(super) fn is_due(&self, key: &[u8], now: u64) -> bool {
+        self.get(key).is_some_and(|at| at <= now)
+    }
+
+    /// Keys expired by `now`, soonest first.
+    pub(super) fn due(&self, now: u64) -> impl Iterator<Item = &Bytes> {
+        self.by_time
+            .iter()
+            .take_while(move |(at, _)| *at <= now)
+            .map(|(_, key)| key)
+    }
+
+    /// Keys yet to expire after `now`, soonest first, with their expiry;
+    /// only those after `after` in that order if given.
+    pub(super) fn upcoming(
+        &self,
+        now: u64,
+        after: Option<&(u64, Bytes)>,
+    ) -> impl Iterator<Item = (u64, &Bytes)> {
+        let from = match after {
+            Some(after) => Bound::Excluded(after.clone()),
+            None => Bound::Unbounded,
+        };
+        self.by_time
+            .range((from, Bound::Unbounded))
+            .skip_while(move |(at, _)| *at <= now)
+            .map(|(at, key)| (*at, key))
+    }
+
+    /// The key expiring soonest other than `keep`, among those `eligible`,
+    /// that `allow` lets go, for `volatile-ttl`.
+    pub(super) fn soonest(
+        &self,
+        keep: &[u8],
+        eligible: impl Fn(&Bytes) -> bool,
+        mut allow: impl FnMut(&Bytes) -> bool,
+    ) -> Option<Bytes> {
+        self.by_time
+            .iter()
+            .map(|(_, key)| key)
+            .filter(|key| key.as_ref() != keep && eligible(key))
+            .take(super::evict::MAX_VETOES + 1)
+            .find(|key| allow(key))
+            .cloned()
+    }
+
+    /// Milliseconds left to the keys' expiry, summed over all of them.
+    pub(super) fn total_ttl(&self, now: u64) -> u64 {
+        self.at.values().map(|at| at.saturating_sub(now)).sum()
+    }
+}
+
+impl Storage {
+    /// Unix time in milliseconds at which `key` expires, or `None` if it
+    /// doesn't.
+    pub fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.expires.get(key)
+    }
+
+    /// Makes `key`, which must exist, expire at `at`, or never if `None`.
+    pub fn set_expiry(&mut self, key: &[u8], at: Option<u64>) {
+        self.hot.invalidate(self.selected, key);
+        match at {
+            Some(at) if self.data.contains_key(key) => self.expires.set(key, at),
+            _ => self.expires.remove(key),
+        }
+    }
+
+    /// Whether `key` reached its expiry time and is only waiting to be
+    /// deleted.
+    pub fn is_expired(&self, key: &[u8]) -> bool {
+        !self.expires.is_empty() && self.expires.is_due(key, unix_ms())
+    }
+
+    /// Keys of the selected database, leaving out the expired ones.
+    pub(super) fn live_keys(&self) -> impl Iterator<Item = &Bytes> {
+        let now = match self.expires.is_empty() {
+            true => 0,
+            false => unix_ms(),
+        };
+        self.data
+            .keys()
+            .filter(move |key| now == 0 || !self.expires.is_due(key, now))
+    }
+
+    /// How many keys of the selected database haven't expired.
+    pub(super) fn live_count(&self) -> usize {
+        match self.expires.is_empty() {
+            true => self.data.len(),
+            false => self.data.len() - self.expires.due(unix_ms()).count(),
+        }
+    }
+
+    /// Deletes `keys` that expired, returning how many did.
+    pub fn expire_due(&mut self, keys: &[Bytes]) -> usize {
+        if self.expires.is_empty() {
+            return 0;
+        }
+        let now = unix_ms();
+        keys.iter()
+            .filter(|key| self.expires.is_due(key, now) && self.expire(key))
+            .count()
+    }
+
+    /// Deletes up to `limit` expired keys of every database, returning how
+    /// many went.
+    pub fn expire_cycle(&mut self, limit: usize) -> usize {
+        let selected = self.selected;
+        let now = unix_ms();
+        let mut deleted = 0;
+        for index in 0..self.databases() {
+            if index != selected && self.parked[index].expires.is_empty() {
+                continue;
+            }
+            self.select(index);
+            let due: Vec<Bytes> = self.expires.due(now).take(limit).cloned().collect();
+            for key in due {
+                deleted += self.expire(&key) as usize;
+            }
+        }
+        self.select(selected);
+        deleted
+    }
+
+    /// Keys expired since the last call, with their database.
+    pub fn take_expired(&mut self) -> Vec<(usize, Bytes)> {
+        std::mem::take(&mut self.just_expired)
+    }
+
+    /// Deletes `key` as expired. As with evictions, the deletion is logged
+    /// and replicated as a `DEL`.
+    fn expire(&mut self, key: &[u8]) -> bool {
+        if !self.delete(key) {
+            self.expires.remove(key);
+            return false;
+        }
+        self.last_version += 1;
+        self.stats.record_expired(1);
+        let key = Bytes::copy_from_slice(key);
+        if self.is_propagating() {
+            self.propagate(&RespValue::Array(vec![
+                RespValue::bulk("DEL"),
+                RespValue::bulk(key.clone()),
+            ]));
+        }
+        self.just_expired.push((self.selected, key));
+        true
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Whether any of `keys` is expired and waiting to be deleted.
+    pub fn any_expired(&self, keys: &[Bytes]) -> bool {
+        keys.iter().any(|key| self.shard(key).is_expired(key))
+    }
+
+    /// Deletes `keys` that expired, in the shards locked exclusively; reads
+    /// sharing their locks only run on keys that didn't.
+    pub fn expire_due(&mut self, keys: &[Bytes]) {
+        for key in keys {
+            let index = self.db.shard_of(key);
+            if let Some(shard) = self.guards[index].as_mut().and_then(Guard::get_mut) {
+                shard.expire_due(std::slice::from_ref(key));
+            }
+        }
+    }
+
+    /// Keys expired since the last call, with their database.
+    pub fn take_expired(&mut self) -> Vec<(usize, Bytes)> {
+        self.guards
+            .iter_mut()
+            .flatten()
+            .filter_map(Guard::get_mut)
+            .flat_map(|shard| shard.take_expired())
+            .collect()
+    }
+
+    /// `EXPIRES NEXT`: the `count` keys matching `pattern` in the locked
+    /// shards that expire soonest, with the milliseconds they have left.
+    pub fn expiring_next(&self, count: usize, pattern: Option<&[u8]>) -> Vec<(Bytes, u64)> {
+        let now = unix_ms();
+        // Each shard's index is in expiry order: the first `count` of each
+        // hold the first `count` of all.
+        let mut next: Vec<(u64, &Bytes)> = self
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .expires
+                    .upcoming(now, None)
+                    .filter(|(_, key)| pattern.is_none_or(|pattern| glob::matches(pattern, key)))
+                    .take(count)
+            })
+            .collect();
+        next.sort_unstable();
+        next.into_iter()
+            .take(count)
+            .map(|(at, key)| (key.clone(), at - now))
+            .collect()
+    }
+}
+
+/// Background task deleting expired keys nobody writes to, a shard at a
+/// time, publishing an `expired` notification for each.
+pub async fn run_active_expiry(db: Db) {
+    let mut tick = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+    loop {
+        tick.tick().await;
+        for index in 0..db.count() {
+            let mut store = db.lock_shard(index).await;
+            for shard in store.iter_mut() {
+                shard.expire_cycle(ACTIVE_EXPIRE_KEYS);
+            }
+            for (db, key) in store.take_expired() {
+                store.notify(EventClass::Expired, "expired", db, &key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+
+    #[tokio::test]
+    async fn test_expired_keys_are_deleted() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024,
+            shards: 1,
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        let shard = store.shard_mut(b"k");
+        for key in ["gone", "later", "never"] {
+            shard.insert(key.into(), "v".into());
+        }
+        let now = unix_ms();
+        shard.set_expiry(b"gone", Some(now - 1));
+        shard.set_expiry(b"later", Some(now + 60_000));
+        assert_eq!(shard.expiry(b"later"), Some(now + 60_000));
+        assert!(shard.is_expired(b"gone"));
+        assert!(!shard.is_expired(b"later"));
+        let mut live: Vec<_> = shard.live_keys().cloned().collect();
+        live.sort();
+        assert_eq!(live, vec![Bytes::from("later"), Bytes::from("never")]);
+
+        // Expired keys go when a write comes across them, or in a cycle.
+        assert_eq!(shard.expire_due(&["later".into(), "gone".into()]), 1);
+        assert_eq!(shard.key_count(), 2);
+        shard.insert("gone".into(), "v".into());
+        shard.set_expiry(b"gone", Some(now - 1));
+        shard.select(1);
+        assert_eq!(shard.expire_cycle(10), 1);
+        shard.select(0);
+        assert_eq!(shard.key_count(), 2);
+        assert_eq!(
+            store.take_expired(),
+            vec![(0, Bytes::from("gone")), (0, Bytes::from("gone"))]
+        );
+        assert_eq!(db.stats().expired_keys(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expiring_next() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024,
+            shards: 4,
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        let now = unix_ms();
+        for (key, ttl) in [("a", 50_000), ("b", 10_000), ("c", 30_000), ("d", 20_000)] {
+            let shard = store.shard_mut(key.as_bytes());
+            shard.insert(key.into(), "v".into());
+            shard.set_expiry(key.as_bytes(), Some(now + ttl));
+        }
+        for key in ["gone", "never"] {
+            store
+                .shard_mut(key.as_bytes())
+                .insert(key.into(), "v".into());
+        }
+        store.shard_mut(b"gone").set_expiry(b"gone", Some(now - 1));
+
+        let next = store.expiring_next(3, None);
+        let keys: Vec<_> = next.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(keys, ["b", "d", "c"]);
+        assert!(next[0].1 <= 10_000 && next[0].1 > 9_000);
+        let keys: Vec<_> = store
+            .expiring_next(10, Some(b"[ac]"))
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["c", "a"]);
+        assert!(store.expiring_next(0, None).is_empty());
+    }
+}
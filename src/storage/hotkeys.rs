@@ -0,0 +1,256 @@
+//! Serving the hottest keys without their shard's lock
+//!
+//! A single key read by every client, say a celebrity's profile, has all
+//! its readers queue on the lock of the one shard holding it, however many
+//! shards there are. With `hot_keys.enabled`, `GET`s run under the lock
+//! are counted, one in [`SAMPLE`], and a string key read more than
+//! `reads_per_sec` times a second gets its value copied out of the shard.
+//! `GET`s of it are then answered from the copy before any shard lock is
+//! taken.
+//!
+//! The copy is dropped as soon as anything changes the key or its expiry:
+//! every write records a new version of the key, or deletes it, with the
+//! shard locked exclusively, so no read under the lock can be copying it at
+//! the time. Flushes, swaps and loads of databases
+//! drop every copy. The next `GET` runs under the lock again and copies the
+//! new value, and a key read less than `reads_per_sec` times in a second
+//! goes back to being an ordinary one. At most `max_keys` keys are hot at
+//! once. Copies served this way don't count as accesses for eviction by
+//! `allkeys-lru`: a key evicted for looking idle just loses its copy.
+use super::{unix_ms, ShardLocks, Shards};
+use crate::config::HotKeysConfig;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// One in this many locked reads is counted.
+pub const SAMPLE: u64 = 16;
+
+/// Keys counted at most per window, so a scan over many keys can't grow
+/// the counts without bound.
+const MAX_COUNTED: usize = 10_000;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// The hot keys' values, by database and key, and the counts finding them.
+/// Shared by the shards of a [`Shards`], which drop the copies of the keys
+/// they change.
+pub struct HotKeys {
+    entries: RwLock<HashMap<(usize, Bytes), HotValue>>,
+    /// Whether `entries` has any, so writes to other keys skip its lock.
+    any: AtomicBool,
+    sampled: AtomicU64,
+    counts: Mutex<Counts>,
+}
+
+struct HotValue {
+    value: Bytes,
+    expires_at: Option<u64>,
+    /// Reads served from the copy this window.
+    reads: AtomicU64,
+}
+
+struct Counts {
+    window: Instant,
+    reads: HashMap<(usize, Bytes), u64>,
+}
+
+impl HotKeys {
+    pub fn new() -> Self {
+        HotKeys {
+            entries: RwLock::default(),
+            any: AtomicBool::new(false),
+            sampled: AtomicU64::new(0),
+            counts: Mutex::new(Counts {
+                window: Instant::now(),
+                reads: HashMap::new(),
+            }),
+        }
+    }
+
+    /// The copied value of `key` in database `db`, if it's hot and hasn't
+    /// expired.
+    pub fn get(&self, db: usize, key: &Bytes) -> Option<Bytes> {
+        if !self.any.load(Ordering::Acquire) {
+            return None;
+        }
+        let entries = self.entries.read().unwrap();
+        let hot = entries.get(&(db, key.clone()))?;
+        if hot.expires_at.is_some_and(|at| at <= unix_ms()) {
+            return None;
+        }
+        hot.reads.fetch_add(1, Ordering::Relaxed);
+        Some(hot.value.clone())
+    }
+
+    /// Counts a read of `key` run under its shard's lock, which holds
+    /// `value` expiring at `expires_at`, and copies the value out if that
+    /// makes the key hot. The lock must be held, so no write to the key
+    /// races the copy.
+    fn record_read(
+        &self,
+        config: &HotKeysConfig,
+        db: usize,
+        key: &Bytes,
+        value: Option<&Bytes>,
+        expires_at: Option<u64>,
+    ) {
+        if !self
+            .sampled
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(SAMPLE)
+        {
+            return;
+        }
+        let Some(value) = value else {
+            return;
+        };
+        let threshold = config.reads_per_sec;
+        let mut counts = self.counts.lock().unwrap();
+        if counts.window.elapsed() >= WINDOW {
+            self.end_window(&mut counts, threshold);
+        }
+        if counts.reads.len() >= MAX_COUNTED && !counts.reads.contains_key(&(db, key.clone())) {
+            return;
+        }
+        let reads = counts.reads.entry((db, key.clone())).or_default();
+        *reads += SAMPLE;
+        if *reads < threshold {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap();
+        if entries.len() >= config.max_keys {
+            return;
+        }
+        entries.insert(
+            (db, key.clone()),
+            HotValue {
+                value: value.clone(),
+                expires_at,
+                reads: AtomicU64::new(0),
+            },
+        );
+        self.any.store(true, Ordering::Release);
+    }
+
+    /// Starts a new window of counts, cooling down the keys read less than
+    /// `threshold` times in the last one, copies included.
+    fn end_window(&self, counts: &mut Counts, threshold: u64) {
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|id, hot| {
+            let locked = counts.reads.get(id).copied().unwrap_or(0);
+            locked + hot.reads.swap(0, Ordering::Relaxed) >= threshold
+        });
+        self.any.store(!entries.is_empty(), Ordering::Release);
+        counts.reads.clear();
+        counts.window = Instant::now();
+    }
+
+    /// Drops the copy of `key` in database `db`, about to change.
+    pub fn invalidate(&self, db: usize, key: &[u8]) {
+        if !self.any.load(Ordering::Acquire) {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap();
+        entries.remove(&(db, Bytes::copy_from_slice(key)));
+        self.any.store(!entries.is_empty(), Ordering::Release);
+    }
+
+    /// Drops every copy, for changes to whole databases.
+    pub fn clear(&self) {
+        if !self.any.load(Ordering::Acquire) {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap();
+        entries.clear();
+        self.any.store(false, Ordering::Release);
+    }
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys::new()
+    }
+}
+
+impl Shards {
+    /// The value of `key` in database `db` if it's hot, read without
+    /// locking its shard.
+    pub fn hot_get(&self, db: usize, key: &Bytes) -> Option<Bytes> {
+        let value = self.hot.get(db, key)?;
+        self.stats.record_hot_key_hit();
+        self.stats.record_lookups(1, 0);
+        Some(value)
+    }
+
+    /// Whether `GET`s are looked for in the hot keys first.
+    pub fn is_serving_hot_keys(&self) -> bool {
+        self.config().hot_keys.enabled
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Counts a `GET` of `key` in the selected database, run with its
+    /// shard locked, towards making the key hot.
+    pub fn record_hot_read(&self, key: &Bytes) {
+        let config = self.db.config().hot_keys.clone();
+        if !config.enabled {
+            return;
+        }
+        let shard = self.shard(key);
+        let value = shard.get(key).ok().flatten();
+        self.db
+            .hot
+            .record_read(&config, self.selected(), key, value, shard.expiry(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_key_lifecycle() {
+        let hot = HotKeys::new();
+        let config = HotKeysConfig {
+            enabled: true,
+            reads_per_sec: SAMPLE * 3,
+            max_keys: 1,
+        };
+        let key = Bytes::from("celebrity");
+        let value = Bytes::from("v");
+        for _ in 0..SAMPLE * 2 {
+            hot.record_read(&config, 0, &key, Some(&value), None);
+        }
+        assert_eq!(hot.get(0, &key), None);
+        for _ in 0..SAMPLE {
+            hot.record_read(&config, 0, &key, Some(&value), None);
+        }
+        assert_eq!(hot.get(0, &key), Some(value.clone()));
+        // Same key, other database.
+        assert_eq!(hot.get(1, &key), None);
+
+        // Full: no other key gets hot.
+        let other = Bytes::from("other");
+        for _ in 0..SAMPLE * 3 {
+            hot.record_read(&config, 0, &other, Some(&value), None);
+        }
+        assert_eq!(hot.get(0, &other), None);
+
+        hot.invalidate(0, &key);
+        assert_eq!(hot.get(0, &key), None);
+        assert!(hot.entries.read().unwrap().is_empty());
+
+        // An expired copy isn't served.
+        let mut counts = hot.counts.lock().unwrap();
+        counts.reads.clear();
+        drop(counts);
+        for _ in 0..SAMPLE * 3 {
+            hot.record_read(&config, 0, &key, Some(&value), Some(1));
+        }
+        assert_eq!(hot.entries.read().unwrap().len(), 1);
+        assert_eq!(hot.get(0, &key), None);
+    }
+}
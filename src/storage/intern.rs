@@ -0,0 +1,96 @@
+//! Sharing one copy of small values stored under many keys
+use crate::config::InternConfig;
+use bytes::Bytes;
+use std::collections::HashSet;
+
+/// Pool of small values handed out as refcounted handles, so a million keys
+/// holding "1" share a single allocation. The pool only grows up to
+/// `max_entries` and keeps its values for the process' lifetime, which
+/// bounds it to `max_entries * max_value_size` bytes.
+#[derive(Debug)]
+pub struct Interner {
+    config: InternConfig,
+    pool: HashSet<Bytes>,
+    hits: u64,
+}
+
+impl Interner {
+    pub fn new(config: InternConfig) -> Self {
+        Interner {
+            config,
+            pool: HashSet::new(),
+            hits: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// The shared copy of `value`, if one is pooled.
+    pub fn get(&mut self, value: &[u8]) -> Option<Bytes> {
+        let shared = self.pool.get(value)?.clone();
+        self.hits += 1;
+        Some(shared)
+    }
+
+    /// Pools `value` for later lookups if it's small and there's room.
+    pub fn admit(&mut self, value: &Bytes) {
+        if self.config.enabled
+            && value.len() <= self.config.max_value_size
+            && self.pool.len() < self.config.max_entries
+        {
+            self.pool.insert(value.clone());
+        }
+    }
+
+    pub fn entries(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Lookups answered from the pool.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::{Storage, Value};
+
+    #[test]
+    fn test_duplicate_values_share_storage() {
+        let mut store = Storage::new(StorageConfig {
+            max_memory: 1024 * 1024,
+            intern: InternConfig {
+                enabled: true,
+                max_value_size: 4,
+                max_entries: 2,
+            },
+            ..Default::default()
+        });
+        // Owned values: static ones would all point at the same literal.
+        let value = |s: &str| Bytes::copy_from_slice(s.as_bytes());
+        for i in 0..10 {
+            store.insert(format!("flag:{}", i).into(), value("1"));
+            store.insert(format!("name:{}", i).into(), value("alice"));
+        }
+        store.insert("a".into(), value("0"));
+        store.insert("b".into(), value("true"));
+
+        let ptr = |key: &str| match store.data.get(key.as_bytes()) {
+            Some(Value::String(s)) => s.as_ptr(),
+            _ => panic!("missing {}", key),
+        };
+        assert_eq!(ptr("flag:0"), ptr("flag:9"));
+        // Too long to pool.
+        assert_ne!(ptr("name:0"), ptr("name:9"));
+        // "1" and "0" filled the pool, so "true" didn't get in.
+        assert_eq!(store.interner.entries(), 2);
+        assert_eq!(store.interner.hits(), 9);
+        // Accounting is by content, shared or not.
+        assert_eq!(store.memory_usage(), 10 * (6 + 1) + 10 * (6 + 5) + 2 + 5);
+    }
+}
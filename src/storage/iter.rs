@@ -0,0 +1,317 @@
+//! Walking the store from a program embedding it
+//!
+//! [`Walk`] streams the keys of a database starting with a prefix, or those
+//! expiring before a time, for maintenance jobs built on the store. It
+//! reads a page at a time with a single shard locked, like `SCAN`, and
+//! holds no lock in between, so it can be awaited across while clients
+//! keep writing. Entries are copies taken under the lock. A key present
+//! for the whole walk, and not given a new TTL during it, comes out once;
+//! keys that come, go or change meanwhile may or may not, with whichever
+//! value they had when their page was read.
+use super::{unix_ms, Db, Namespace, ShardLocks, Shards, Storage, StorageError, Value};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio_stream::Stream;
+
+/// Keys looked at per lock.
+const PAGE: usize = 256;
+
+/// A key and what it held when its page was read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: Bytes,
+    pub value: Value,
+    /// Unix time in milliseconds at which the key expires, if it does.
+    pub expires_at: Option<u64>,
+}
+
+/// Where a walk is within a shard.
+#[derive(Debug, Clone)]
+enum Position {
+    /// Keys starting with `prefix`, in `SCAN` order from position `from`.
+    Prefix { prefix: Bytes, from: u64 },
+    /// Keys expiring before `before`, soonest first, after the key
+    /// returned last.
+    Expiring {
+        before: u64,
+        after: Option<(u64, Bytes)>,
+    },
+}
+
+type Page = Result<(Vec<Entry>, Option<Position>), StorageError>;
+
+/// A stream of the entries of a database, from [`Shards::iter_prefix`] or
+/// [`Shards::iter_expiring_before`].
+pub struct Walk {
+    db: Db,
+    database: usize,
+    shard: usize,
+    /// Where each shard's walk starts.
+    start: Position,
+    /// Where the current shard's next page starts; `None` once the walk is
+    /// over.
+    position: Option<Position>,
+    entries: VecDeque<Entry>,
+    reading: Option<Pin<Box<dyn Future<Output = Page> + Send>>>,
+}
+
+impl Shards {
+    /// Walks the keys of database 0 starting with `prefix`.
+    pub fn iter_prefix(self: &Arc<Self>, prefix: impl Into<Bytes>) -> Walk {
+        Walk::new(
+            self.clone(),
+            Position::Prefix {
+                prefix: prefix.into(),
+                from: 0,
+            },
+        )
+    }
+
+    /// Walks the keys of database 0 that expire before `at`, a Unix time in
+    /// milliseconds, soonest first within each shard. Keys already expired
+    /// and waiting to be deleted are left out.
+    pub fn iter_expiring_before(self: &Arc<Self>, at: u64) -> Walk {
+        Walk::new(
+            self.clone(),
+            Position::Expiring {
+                before: at,
+                after: None,
+            },
+        )
+    }
+}
+
+impl Walk {
+    fn new(db: Db, position: Position) -> Self {
+        Walk {
+            db,
+            database: 0,
+            shard: 0,
+            start: position.clone(),
+            position: Some(position),
+            entries: VecDeque::new(),
+            reading: None,
+        }
+    }
+
+    /// Walks database `database` instead, as clients number them. An index
+    /// out of range ends the walk with [`StorageError::InvalidDbIndex`].
+    pub fn in_database(mut self, database: usize) -> Self {
+        self.database = database;
+        self
+    }
+}
+
+impl Stream for Walk {
+    type Item = Result<Entry, StorageError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let walk = &mut *self;
+        loop {
+            if let Some(entry) = walk.entries.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            let reading = match &mut walk.reading {
+                Some(reading) => reading,
+                None => {
+                    let Some(position) = walk.position.take() else {
+                        return Poll::Ready(None);
+                    };
+                    let page = read_page(walk.db.clone(), walk.database, walk.shard, position);
+                    walk.reading.insert(Box::pin(page))
+                }
+            };
+            let page = ready!(reading.as_mut().poll(cx));
+            walk.reading = None;
+            let (entries, next) = match page {
+                Ok(page) => page,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            };
+            walk.entries.extend(entries);
+            walk.position = match next {
+                Some(next) => Some(next),
+                None if walk.shard + 1 < walk.db.count() => {
+                    walk.shard += 1;
+                    Some(walk.start.clone())
+                }
+                None => None,
+            };
+        }
+    }
+}
+
+/// Reads the page of shard `shard` at `position`, with only that shard
+/// locked, and where the next one starts.
+async fn read_page(db: Db, database: usize, shard: usize, position: Position) -> Page {
+    // Prefixed databases are all stored in database 0, which always exists.
+    if database >= db.databases() {
+        return Err(StorageError::InvalidDbIndex);
+    }
+    let namespace = db.namespace(database);
+    let mut store = db.lock_shard(shard).await;
+    store.select(namespace.storage_db())?;
+    Ok(store.walk_page(shard, namespace, position))
+}
+
+impl ShardLocks<'_> {
+    fn walk_page(
+        &self,
+        index: usize,
+        namespace: Namespace,
+        position: Position,
+    ) -> (Vec<Entry>, Option<Position>) {
+        let shard = self.guards[index]
+            .as_deref()
+            .expect("the shard being walked is locked");
+        match position {
+            Position::Prefix { prefix, from } => {
+                let stored = namespace.key(&prefix);
+                let (keys, next) = shard.scan(from, PAGE);
+                let entries = keys
+                    .into_iter()
+                    .filter(|key| key.starts_with(&stored))
+                    .filter_map(|key| entry(shard, namespace, key))
+                    .collect();
+                let next = next.map(|from| Position::Prefix { prefix, from });
+                (entries, next)
+            }
+            Position::Expiring { before, after } => {
+                let page: Vec<(u64, &Bytes)> = shard
+                    .expires
+                    .upcoming(unix_ms(), after.as_ref())
+                    .take_while(|(at, _)| *at < before)
+                    .take(PAGE)
+                    .collect();
+                let next = match page.last() {
+                    Some(&(at, key)) if page.len() == PAGE => Some(Position::Expiring {
+                        before,
+                        after: Some((at, key.clone())),
+                    }),
+                    _ => None,
+                };
+                let entries = page
+                    .into_iter()
+                    .filter_map(|(_, key)| entry(shard, namespace, key))
+                    .collect();
+                (entries, next)
+            }
+        }
+    }
+}
+
+/// `key` of `shard` as an entry of `namespace`, if it's one of its keys.
+fn entry(shard: &Storage, namespace: Namespace, key: &Bytes) -> Option<Entry> {
+    Some(Entry {
+        key: namespace.strip(key)?,
+        value: shard.value(key)?.clone(),
+        expires_at: shard.expiry(key),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use std::collections::HashSet;
+    use tokio_stream::StreamExt;
+
+    fn store(config: StorageConfig) -> Db {
+        Arc::new(Shards::new(StorageConfig {
+            shards: 4,
+            ..config
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_prefix_walk_survives_writes() {
+        let db = store(StorageConfig::default());
+        {
+            let mut store = db.lock_all().await;
+            for i in 0..3000 {
+                let key = Bytes::from(format!("user:{}", i));
+                store.shard_mut(&key).insert(key, "v".into());
+                let key = Bytes::from(format!("order:{}", i));
+                store.shard_mut(&key).insert(key, "v".into());
+            }
+        }
+        let mut walk = db.iter_prefix("user:");
+        let mut seen = HashSet::new();
+        let mut i = 0;
+        while let Some(entry) = walk.next().await {
+            let entry = entry.unwrap();
+            assert_eq!(entry.value, Value::String("v".into()));
+            assert!(seen.insert(entry.key), "a key came out twice");
+            // Clients write between pages: the walk takes no lock meanwhile.
+            let mut store = db.lock_all().await;
+            let added = Bytes::from(format!("user:new:{}", i));
+            store.shard_mut(&added).insert(added, "v".into());
+            let gone = format!("user:{}", 2000 + i % 1000);
+            store.shard_mut(gone.as_bytes()).del(&[gone.clone().into()]);
+            i += 1;
+        }
+        assert!(seen.iter().all(|key| key.starts_with(b"user:")));
+        // Every key there from start to end came out.
+        assert!((0..2000).all(|i| seen.contains(format!("user:{}", i).as_bytes())));
+    }
+
+    #[tokio::test]
+    async fn test_expiring_walk() {
+        let db = store(StorageConfig::default());
+        let now = unix_ms();
+        {
+            let mut store = db.lock_all().await;
+            for i in 0..1000u64 {
+                let key = Bytes::from(format!("k{}", i));
+                let shard = store.shard_mut(&key);
+                shard.insert(key.clone(), "v".into());
+                shard.set_expiry(&key, Some(now + 1000 + i * 10));
+            }
+            let shard = store.shard_mut(b"gone");
+            shard.insert("gone".into(), "v".into());
+            shard.set_expiry(b"gone", Some(now - 1));
+            store.shard_mut(b"never").insert("never".into(), "v".into());
+        }
+        let entries: Vec<Entry> = db
+            .iter_expiring_before(now + 1000 + 600 * 10)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(entries.len(), 600);
+        assert!(entries
+            .iter()
+            .all(|entry| entry.expires_at.is_some_and(|at| at < now + 7000)));
+    }
+
+    #[tokio::test]
+    async fn test_walks_stay_in_their_database() {
+        let db = store(StorageConfig {
+            databases: 4,
+            prefixed_databases: true,
+            ..Default::default()
+        });
+        {
+            let mut store = db.lock_all().await;
+            for database in 0..2 {
+                let namespace = db.namespace(database);
+                let key = namespace.key(&Bytes::from("user:1"));
+                store.shard_mut(&key).insert(key, "v".into());
+            }
+        }
+        for database in 0..2 {
+            let keys: Vec<Bytes> = db
+                .iter_prefix("user:")
+                .in_database(database)
+                .map(|entry| entry.unwrap().key)
+                .collect()
+                .await;
+            assert_eq!(keys, [Bytes::from("user:1")]);
+        }
+        let mut walk = db.iter_prefix("").in_database(9);
+        assert_eq!(walk.next().await, Some(Err(StorageError::InvalidDbIndex)));
+        assert_eq!(walk.next().await, None);
+    }
+}
@@ -0,0 +1,164 @@
+//! List commands: pushes and pops at either end, `LLEN`, `LRANGE`
+use super::{range, Deadline, ShardLocks, Storage, StorageError, Value};
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+/// Which end of a list a push or pop works on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl Storage {
+    /// Pushes `elements` one by one onto `end` of the list at `key`,
+    /// creating it if needed, and returns the new length.
+    pub fn push(
+        &mut self,
+        key: &[u8],
+        elements: Vec<Bytes>,
+        end: End,
+    ) -> Result<usize, StorageError> {
+        // A key of another type fails before any room is made.
+        self.list(key)?;
+        let grows = self.new_entry_size(key) + elements.iter().map(Bytes::len).sum::<usize>();
+        self.make_room(grows, key)?;
+        let elements: Vec<Bytes> = elements.into_iter().map(|e| self.slab.alloc(e)).collect();
+        let Value::List(list) = self.entry(key, || Value::List(VecDeque::new())) else {
+            return Err(StorageError::WrongType);
+        };
+        for element in elements {
+            match end {
+                End::Left => list.push_front(element),
+                End::Right => list.push_back(element),
+            }
+        }
+        let len = list.len();
+        self.account(key, grows);
+        self.touch(key);
+        Ok(len)
+    }
+
+    /// Removes up to `count` elements from `end` of the list at `key`, in
+    /// the order popped.
+    pub fn pop(&mut self, key: &[u8], end: End, count: usize) -> Result<Vec<Bytes>, StorageError> {
+        self.preserve(key);
+        let list = match self.data.get_mut(key) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Err(StorageError::WrongType),
+            None => return Ok(vec![]),
+        };
+        let popped: Vec<Bytes> = match end {
+            End::Left => std::iter::from_fn(|| list.pop_front())
+                .take(count)
+                .collect(),
+            End::Right => std::iter::from_fn(|| list.pop_back()).take(count).collect(),
+        };
+        if !popped.is_empty() {
+            let size: usize = popped.iter().map(Bytes::len).sum();
+            self.release(key, size);
+            self.touch(key);
+        }
+        self.remove_if_empty(key);
+        Ok(popped)
+    }
+
+    pub fn llen(&self, key: &[u8]) -> Result<usize, StorageError> {
+        Ok(self.list(key)?.map_or(0, VecDeque::len))
+    }
+
+    /// Elements `start..=stop`; negative indexes count from the end.
+    pub fn lrange(
+        &self,
+        key: &[u8],
+        start: i64,
+        stop: i64,
+        deadline: &Deadline,
+    ) -> Result<Vec<&Bytes>, StorageError> {
+        let Some(list) = self.list(key)? else {
+            return Ok(vec![]);
+        };
+        match range::rank_range(start, stop, list.len()) {
+            Some(range) => deadline.collect(list.range(range)),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn list(&self, key: &[u8]) -> Result<Option<&VecDeque<Bytes>>, StorageError> {
+        match self.data.get(key) {
+            Some(Value::List(list)) => Ok(Some(list)),
+            Some(_) => Err(StorageError::WrongType),
+            None => Ok(None),
+        }
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Pushes onto the list at `key` of the selected database, waking the
+    /// clients blocked on it.
+    pub fn push(
+        &mut self,
+        key: &[u8],
+        elements: Vec<Bytes>,
+        end: End,
+    ) -> Result<usize, StorageError> {
+        let len = self.shard_mut(key).push(key, elements, end)?;
+        self.db.waiters().wake(self.selected(), key);
+        Ok(len)
+    }
+
+    /// The first of `keys` holding a list, with the element popped off its
+    /// `end`; `None` if they're all empty. A key of another type fails the
+    /// pop, as in Redis.
+    pub fn pop_first(
+        &mut self,
+        keys: &[Bytes],
+        end: End,
+    ) -> Result<Option<(Bytes, Bytes)>, StorageError> {
+        for key in keys {
+            if let Some(element) = self.shard_mut(key).pop(key, end, 1)?.pop() {
+                return Ok(Some((key.clone(), element)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+
+    #[test]
+    fn test_push_pop_and_range() {
+        let mut storage = Storage::new(StorageConfig::default());
+        let elements = |items: &[&'static str]| items.iter().map(|&e| Bytes::from(e)).collect();
+        assert_eq!(storage.push(b"l", elements(&["a", "b"]), End::Left), Ok(2));
+        assert_eq!(storage.push(b"l", elements(&["c"]), End::Right), Ok(3));
+        assert_eq!(storage.memory_usage(), 1 + 3);
+
+        let deadline = Deadline::after(None);
+        let all: Vec<&Bytes> = storage.lrange(b"l", 0, -1, &deadline).unwrap();
+        assert_eq!(all, vec!["b", "a", "c"]);
+        assert_eq!(
+            storage.lrange(b"l", -2, 10, &deadline).unwrap(),
+            vec!["a", "c"]
+        );
+
+        assert_eq!(storage.pop(b"l", End::Right, 2), Ok(elements(&["c", "a"])));
+        assert_eq!(storage.llen(b"l"), Ok(1));
+        assert_eq!(storage.pop(b"l", End::Left, 5), Ok(elements(&["b"])));
+        assert!(storage.value(b"l").is_none(), "empty lists are removed");
+        assert_eq!(storage.memory_usage(), 0);
+
+        storage.insert("s".into(), "v".into());
+        assert_eq!(
+            storage.push(b"s", elements(&["x"]), End::Left),
+            Err(StorageError::WrongType)
+        );
+        assert_eq!(
+            storage.pop(b"s", End::Left, 1),
+            Err(StorageError::WrongType)
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! How long commands wait for the shard locks, and how long they hold them
+//!
+//! Told apart per shard, the two show whether slow commands were slow to
+//! run or slow to get their turn: long waits behind short holds elsewhere
+//! are contention, on one hot shard or on all of them.
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in microseconds. A last bucket
+/// takes everything longer.
+pub const BOUNDS_USEC: [u64; 7] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000];
+
+const BUCKETS: usize = BOUNDS_USEC.len() + 1;
+
+#[derive(Default)]
+pub struct Histogram {
+    counts: [AtomicU64; BUCKETS],
+    nanos: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, elapsed: Duration) {
+        let usec = elapsed.as_micros() as u64;
+        let bucket = BOUNDS_USEC
+            .iter()
+            .position(|&bound| usec <= bound)
+            .unwrap_or(BUCKETS - 1);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// How many fell in each bucket, the last being over every bound.
+    pub fn counts(&self) -> [u64; BUCKETS] {
+        std::array::from_fn(|bucket| self.counts[bucket].load(Ordering::Relaxed))
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+
+    /// Everything recorded, added up.
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+
+    /// The bound of the bucket holding the `fraction` quantile, or `None`
+    /// if that's the last bucket or nothing was recorded.
+    fn quantile_bound(&self, fraction: f64) -> Option<u64> {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = (total as f64 * fraction).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BOUNDS_USEC.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+/// One shard's lock: the waits for it, from asking to getting, and the
+/// holds, from the command having all its locks to letting them go.
+#[derive(Default)]
+pub struct ShardLockStats {
+    pub wait: Histogram,
+    pub hold: Histogram,
+}
+
+pub struct LockStats {
+    shards: Box<[ShardLockStats]>,
+}
+
+impl LockStats {
+    pub fn new(count: usize) -> Self {
+        LockStats {
+            shards: (0..count).map(|_| ShardLockStats::default()).collect(),
+        }
+    }
+
+    pub fn shard(&self, index: usize) -> &ShardLockStats {
+        &self.shards[index]
+    }
+
+    pub fn shards(&self) -> &[ShardLockStats] {
+        &self.shards
+    }
+
+    /// `DEBUG LOCKSTATS`: a line per shard and histogram, with the count,
+    /// the total and the bucket bounds the median and 99th percentile fall
+    /// under.
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        for (index, shard) in self.shards.iter().enumerate() {
+            for (name, histogram) in [("wait", &shard.wait), ("hold", &shard.hold)] {
+                let bound = |fraction| match histogram.quantile_bound(fraction) {
+                    Some(usec) => format!("<={}us", usec),
+                    None if histogram.count() == 0 => "-".to_string(),
+                    None => format!(">{}us", BOUNDS_USEC[BUCKETS - 2]),
+                };
+                let _ = writeln!(
+                    report,
+                    "shard:{} {}:count={},usec={},p50={},p99={}",
+                    index,
+                    name,
+                    histogram.count(),
+                    histogram.total().as_micros(),
+                    bound(0.5),
+                    bound(0.99)
+                );
+            }
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histograms_and_report() {
+        let stats = LockStats::new(2);
+        let wait = &stats.shard(1).wait;
+        for _ in 0..98 {
+            wait.record(Duration::from_nanos(500));
+        }
+        wait.record(Duration::from_micros(50));
+        wait.record(Duration::from_secs(2));
+        assert_eq!(wait.counts(), [98, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(wait.total(), Duration::from_nanos(2_000_099_000));
+        stats.shard(1).hold.record(Duration::from_millis(3));
+
+        assert_eq!(
+            stats.report(),
+            "shard:0 wait:count=0,usec=0,p50=-,p99=-\n\
+             shard:0 hold:count=0,usec=0,p50=-,p99=-\n\
+             shard:1 wait:count=100,usec=2000099,p50=<=1us,p99=<=100us\n\
+             shard:1 hold:count=1,usec=3000,p50=<=10000us,p99=<=10000us\n"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! Memory reports: `MEMORY USAGE`, `MEMORY STATS` and `MEMORY DOCTOR`
+//!
+//! `max_memory` is charged for the bytes of keys, values and members only.
+//! The reports add estimates of what storing them costs on top: the hash
+//! table slot of each key, the handle each collection keeps per element,
+//! and the index of keys with a TTL.
+use super::{ShardLocks, SlabStats, Storage, Value};
+use crate::config::MaxMemoryPolicy;
+use bytes::Bytes;
+use std::mem::size_of;
+
+/// A key's slot in its database's table, with the value stored inline.
+const KEY_OVERHEAD: usize = size_of::<(Bytes, Value)>();
+/// A key's entries in the TTL index: by key and by time. The key bytes are
+/// copied too, and counted separately.
+const EXPIRE_OVERHEAD: usize = 2 * size_of::<(Bytes, u64)>();
+/// The handle a set or list keeps per element.
+const ELEMENT_OVERHEAD: usize = size_of::<Bytes>();
+/// A sorted set member is kept twice: by name and ordered by score.
+const ZSET_MEMBER_OVERHEAD: usize = 2 * size_of::<(Bytes, f64)>();
+
+/// Elements `MEMORY USAGE` looks at in a collection by default.
+pub const DEFAULT_SAMPLES: usize = 5;
+
+/// Below this much data `MEMORY DOCTOR` has nothing to say.
+const DOCTOR_MIN_BYTES: usize = 1024 * 1024;
+
+impl Value {
+    /// Estimated bytes the value takes, per-element bookkeeping included.
+    /// Collections are sized from their first `samples` elements, scaled
+    /// to their length; 0 samples every element.
+    pub fn usage(&self, samples: usize) -> usize {
+        fn sampled(len: usize, sizes: impl Iterator<Item = usize>, samples: usize) -> usize {
+            let samples = if samples == 0 { len } else { samples.min(len) };
+            if samples == 0 {
+                return 0;
+            }
+            let total: usize = sizes.take(samples).sum();
+            total * len / samples
+        }
+        match self {
+            Value::String(s) => s.len(),
+            Value::Set(set) => sampled(
+                set.len(),
+                set.iter().map(|m| m.len() + ELEMENT_OVERHEAD),
+                samples,
+            ),
+            Value::SortedSet(zset) => sampled(
+                zset.len(),
+                zset.iter().map(|(m, _)| m.len() + ZSET_MEMBER_OVERHEAD),
+                samples,
+            ),
+            Value::List(list) => sampled(
+                list.len(),
+                list.iter().map(|e| e.len() + ELEMENT_OVERHEAD),
+                samples,
+            ),
+        }
+    }
+}
+
+/// The bookkeeping of one database, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DbOverhead {
+    pub keys: usize,
+    /// The table of keys.
+    pub main: usize,
+    /// The TTL index.
+    pub expires: usize,
+}
+
+impl Storage {
+    /// Estimated bytes `key` and its value take, bookkeeping included, or
+    /// `None` if it doesn't exist. See [`Value::usage`] for `samples`.
+    pub fn key_usage(&self, key: &[u8], samples: usize) -> Option<usize> {
+        let value = self.data.get(key)?;
+        let expires = match self.expires.get(key) {
+            Some(_) => EXPIRE_OVERHEAD + key.len(),
+            None => 0,
+        };
+        Some(KEY_OVERHEAD + key.len() + value.usage(samples) + expires)
+    }
+
+    /// Bookkeeping of each database in this shard, by index. Counted from
+    /// the number of keys, so it's cheap whatever their size.
+    pub fn overhead(&self) -> Vec<DbOverhead> {
+        (0..self.databases())
+            .map(|index| {
+                let keys = self.database(index).len();
+                let expires = self.expires_in(index).len();
+                DbOverhead {
+                    keys,
+                    main: keys * KEY_OVERHEAD,
+                    expires: expires * EXPIRE_OVERHEAD,
+                }
+            })
+            .collect()
+    }
+}
+
+/// What `MEMORY STATS` reports, summed over the locked shards.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    /// Bytes of keys, values and members: what `max_memory` limits.
+    pub dataset: usize,
+    /// Bookkeeping of each database, by index.
+    pub databases: Vec<DbOverhead>,
+    pub max_memory: usize,
+    pub policy: MaxMemoryPolicy,
+    pub fragmentation: f64,
+    pub defrag_enabled: bool,
+    pub slab: Option<SlabStats>,
+    /// Pooled values and lookups they answered, if interning is on.
+    pub intern: Option<(usize, u64)>,
+}
+
+impl MemoryReport {
+    pub fn keys(&self) -> usize {
+        self.databases.iter().map(|db| db.keys).sum()
+    }
+
+    pub fn overhead(&self) -> usize {
+        self.databases.iter().map(|db| db.main + db.expires).sum()
+    }
+
+    pub fn total(&self) -> usize {
+        self.dataset + self.overhead()
+    }
+
+    /// Share of the total that is data, in percent.
+    pub fn dataset_percentage(&self) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => self.dataset as f64 * 100.0 / total as f64,
+        }
+    }
+
+    /// The `MEMORY DOCTOR` report: one line per problem found, with what
+    /// to do about it.
+    pub fn diagnose(&self) -> String {
+        if self.total() < DOCTOR_MIN_BYTES {
+            return "This instance is empty or uses very little memory: there is nothing to \
+                    diagnose yet."
+                .to_string();
+        }
+        let mut issues = Vec::new();
+        if self.fragmentation > 1.4 {
+            let advice = if self.defrag_enabled {
+                "active defrag is on and should bring it down as it catches up"
+            } else {
+                "consider enabling active defrag (storage.defrag.enabled)"
+            };
+            issues.push(format!(
+                "High fragmentation: {:.2} bytes are held for every byte used; {}.",
+                self.fragmentation, advice
+            ));
+        }
+        if self.max_memory > 0 && self.total() * 10 >= self.max_memory * 9 {
+            let consequence = match self.policy {
+                MaxMemoryPolicy::NoEviction => "writes will fail once it is reached",
+                _ => "keys will be evicted once it is reached",
+            };
+            issues.push(format!(
+                "Close to maxmemory: {} of {} bytes in use; {}.",
+                self.total(),
+                self.max_memory,
+                consequence
+            ));
+        }
+        if self.overhead() > self.dataset {
+            let advice = if self.slab.is_some() {
+                "grouping small values into fewer, larger keys would help"
+            } else {
+                "enabling slab allocation (storage.slab.enabled) or grouping small values \
+                 into fewer, larger keys would help"
+            };
+            issues.push(format!(
+                "Bookkeeping outweighs data: {} bytes of overhead for {} bytes of keys and \
+                 values; {}.",
+                self.overhead(),
+                self.dataset,
+                advice
+            ));
+        }
+        if issues.is_empty() {
+            "No memory issues found in this instance.".to_string()
+        } else {
+            issues.join("\n")
+        }
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Estimated bytes `key` takes in the selected database.
+    pub fn key_usage(&self, key: &[u8], samples: usize) -> Option<usize> {
+        self.shard(key).key_usage(key, samples)
+    }
+
+    pub fn memory_report(&self) -> MemoryReport {
+        let config = self.config();
+        let mut databases = vec![DbOverhead::default(); self.db.databases()];
+        for shard in self.iter() {
+            for (total, db) in databases.iter_mut().zip(shard.overhead()) {
+                total.keys += db.keys;
+                total.main += db.main;
+                total.expires += db.expires;
+            }
+        }
+        MemoryReport {
+            dataset: self.memory_usage(),
+            databases,
+            max_memory: config.max_memory,
+            policy: config.maxmemory_policy,
+            fragmentation: self.fragmentation_ratio(),
+            defrag_enabled: config.defrag.enabled,
+            slab: self.slab_stats(),
+            intern: self.intern_stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn test_usage_counts_overhead_and_samples() {
+        let list = Value::List(VecDeque::from(vec![
+            Bytes::from("a"),
+            Bytes::from("bbb"),
+            Bytes::from("a"),
+            Bytes::from("bbb"),
+        ]));
+        let exact = 8 + 4 * ELEMENT_OVERHEAD;
+        assert_eq!(list.usage(0), exact);
+        assert_eq!(list.usage(100), exact);
+        // Two elements averaging 2 bytes stand for all four.
+        assert_eq!(list.usage(2), exact);
+        assert_eq!(list.usage(1), 4 * (1 + ELEMENT_OVERHEAD));
+
+        let mut storage = Storage::new(StorageConfig {
+            max_memory: 1 << 20,
+            ..Default::default()
+        });
+        storage.insert("k".into(), "value".into());
+        assert_eq!(storage.key_usage(b"k", 5), Some(KEY_OVERHEAD + 1 + 5));
+        storage.set_expiry(b"k", Some(u64::MAX));
+        assert_eq!(
+            storage.key_usage(b"k", 5),
+            Some(KEY_OVERHEAD + 1 + 5 + EXPIRE_OVERHEAD + 1)
+        );
+        assert_eq!(storage.key_usage(b"missing", 5), None);
+
+        let overhead = storage.overhead();
+        assert_eq!(
+            overhead[0],
+            DbOverhead {
+                keys: 1,
+                main: KEY_OVERHEAD,
+                expires: EXPIRE_OVERHEAD,
+            }
+        );
+        assert_eq!(overhead[1], DbOverhead::default());
+    }
+
+    #[test]
+    fn test_doctor() {
+        let mut report = MemoryReport::default();
+        assert!(report.diagnose().contains("nothing to diagnose"));
+
+        report.dataset = 10 * DOCTOR_MIN_BYTES;
+        report.fragmentation = 1.0;
+        assert_eq!(
+            report.diagnose(),
+            "No memory issues found in this instance."
+        );
+
+        report.fragmentation = 2.0;
+        report.max_memory = report.dataset;
+        let diagnosis = report.diagnose();
+        assert!(diagnosis.contains("High fragmentation"), "{}", diagnosis);
+        assert!(diagnosis.contains("writes will fail"), "{}", diagnosis);
+        assert_eq!(diagnosis.lines().count(), 2);
+    }
+}
@@ -1,71 +1,800 @@
+mod alarms;
+mod bigkeys;
+mod blocking;
+mod databases;
+mod defrag;
+mod dump;
+mod evict;
+mod expire;
+mod hotkeys;
+mod intern;
+mod iter;
+mod list;
+mod lockstats;
+mod memory;
+mod namespace;
+mod quota;
+mod range;
+pub mod rdb;
+mod result_cache;
+mod save;
+mod scan;
+mod setops;
+mod shards;
+mod slab;
+mod snapshot;
+mod strings;
+mod zset;
+
+pub use alarms::{run_memory_alarms, ALARM_CHANNEL};
+pub use bigkeys::{big_keys, BigKeys, TypeSummary};
+pub use blocking::{Waiter, Waiters};
+pub use defrag::run_defrag;
+pub use dump::RestoreOptions;
+pub use evict::{EvictionHook, MAX_VETOES};
+pub use expire::{jittered_ttl, run_active_expiry, unix_ms};
+pub use iter::{Entry, Walk};
+pub use list::End;
+pub use lockstats::{Histogram, LockStats, ShardLockStats, BOUNDS_USEC};
+pub use memory::{DbOverhead, MemoryReport, DEFAULT_SAMPLES};
+pub use namespace::{Namespace, MAX_PREFIXED_DATABASES};
+pub use rdb::RdbError;
+pub use save::{run_autosave, save_on_shutdown, SaveStats};
+pub use scan::cursor_shard;
+pub use setops::Aggregate;
+pub use shards::{ShardLocks, Shards};
+pub use slab::SlabStats;
+pub use snapshot::Snapshot;
+pub use strings::{SetCondition, SetExpiry, SetOptions, SetOutcome, StringOps};
+pub use zset::{ScoreBound, SortedSet};
+
+use crate::aof::Aof;
 use crate::config::StorageConfig;
-use std::collections::HashMap;
+use crate::protocol::RespValue;
+use crate::replication::Replication;
+use crate::stats::Stats;
+use bytes::Bytes;
+use databases::Keyspace;
+use defrag::Defrag;
+use evict::{Evictor, HookSlot};
+use expire::Expires;
+use hotkeys::HotKeys;
+use indexmap::IndexSet;
+use intern::Interner;
+use log::error;
+use slab::Slab;
+use snapshot::CopyOnWrite;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Bytes accounted for each sorted set member on top of its name.
+const SCORE_SIZE: usize = std::mem::size_of::<f64>();
+
+#[derive(Error, Debug, PartialEq)]
+pub enum StorageError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR max memory limit exceeded")]
+    OutOfMemory,
+    #[error("TIMEOUT command exceeded its max execution time")]
+    Timeout,
+    #[error("ERR invalid cursor")]
+    InvalidCursor,
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR increment or decrement would overflow")]
+    Overflow,
+    #[error("ERR DB index is out of range")]
+    InvalidDbIndex,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    #[error("ERR DUMP payload version or checksum are wrong")]
+    BadDumpPayload,
+    #[error("ERR Bad data format")]
+    BadDataFormat,
+}
+
+/// How many items a long-running operation processes between clock checks.
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+/// Most members `SRANDMEMBER` picks with a negative count, which may repeat
+/// them: without a command timeout nothing else would stop it.
+pub const MAX_REPEATED_PICKS: u64 = 1 << 20;
+
+/// Point in time after which a command gives up. Operations that walk large
+/// collections check it cooperatively and bail out with
+/// `StorageError::Timeout`, so one expensive command can't hold the lock for
+/// an unbounded time.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// A deadline `limit` from now; `None` means no limit.
+    pub fn after(limit: Option<Duration>) -> Self {
+        Deadline(limit.map(|limit| Instant::now() + limit))
+    }
+
+    pub fn check(&self) -> Result<(), StorageError> {
+        match self.0 {
+            Some(at) if Instant::now() >= at => Err(StorageError::Timeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the locks on the shards owning `keys`, or on every shard
+    /// if there are none, giving up once the deadline passes.
+    pub async fn lock<'a>(
+        &self,
+        db: &'a Db,
+        keys: &[Bytes],
+    ) -> Result<ShardLocks<'a>, StorageError> {
+        self.wait(async {
+            match keys {
+                [] => db.lock_all().await,
+                keys => db.lock(keys).await,
+            }
+        })
+        .await
+    }
+
+    /// Waits for the lock on the shard at `index` alone.
+    pub async fn lock_shard<'a>(
+        &self,
+        db: &'a Db,
+        index: usize,
+    ) -> Result<ShardLocks<'a>, StorageError> {
+        self.wait(db.lock_shard(index)).await
+    }
+
+    /// Like [`Deadline::lock`], but sharing the locks with other readers;
+    /// `None` if that can't be done for database `index`.
+    pub async fn lock_shared<'a>(
+        &self,
+        db: &'a Db,
+        keys: &[Bytes],
+        index: usize,
+    ) -> Result<Option<ShardLocks<'a>>, StorageError> {
+        self.wait(db.lock_shared(keys, index)).await
+    }
+
+    async fn wait<T>(
+        &self,
+        locks: impl std::future::Future<Output = T>,
+    ) -> Result<T, StorageError> {
+        match self.0 {
+            Some(at) => tokio::time::timeout_at(at.into(), locks)
+                .await
+                .map_err(|_| StorageError::Timeout),
+            None => Ok(locks.await),
+        }
+    }
+
+    /// Checks the deadline on every few iterations of a loop over `i`.
+    fn check_every(&self, i: usize) -> Result<(), StorageError> {
+        if i.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+            self.check()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Collects `items`, checking the deadline every few iterations.
+    fn collect<T>(&self, items: impl Iterator<Item = T>) -> Result<Vec<T>, StorageError> {
+        let mut collected = Vec::new();
+        for (i, item) in items.enumerate() {
+            self.check_every(i)?;
+            collected.push(item);
+        }
+        Ok(collected)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Bytes),
+    Set(IndexSet<Bytes>),
+    SortedSet(SortedSet),
+    List(VecDeque<Bytes>),
+}
+
+impl Value {
+    /// Type name as `TYPE` reports it.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::List(_) => "list",
+        }
+    }
 
+    /// Bytes accounted against `max_memory` for this value.
+    pub fn size(&self) -> usize {
+        match self {
+            Value::String(s) => s.len(),
+            Value::Set(set) => set.iter().map(|m| m.len()).sum(),
+            Value::SortedSet(zset) => zset.iter().map(|(m, _)| m.len() + SCORE_SIZE).sum(),
+            Value::List(list) => list.iter().map(|e| e.len()).sum(),
+        }
+    }
+}
+
+/// One shard of the keyspace: the keys hashing to it, with their memory
+/// accounting against the shard's part of `max_memory`. `data`, `versions`,
+/// `expires` and `evictor` belong to the selected database.
 pub struct Storage {
-    data: HashMap<String, String>,
+    data: HashMap<Bytes, Value>,
+    /// Version of each key's last modification, for `WATCH`. Versions come
+    /// from one counter, so a key deleted and recreated never repeats one.
+    versions: HashMap<Bytes, u64>,
+    last_version: u64,
+    /// Version of the last removal of keys, in any database: the version
+    /// `WATCH` sees for keys that don't exist.
+    removed: u64,
+    expires: Expires,
+    aof: Option<Arc<Aof>>,
+    replication: Option<Arc<Replication>>,
     config: StorageConfig,
     current_memory: usize,
+    /// Memory used in each of `config.quotas`.
+    quota_memory: Vec<usize>,
+    /// Bytes freed since the last defrag pass.
+    released: usize,
+    defrag: Defrag,
+    slab: Slab,
+    interner: Interner,
+    evictor: Evictor,
+    /// Keys evicted and not yet notified of, with their database.
+    just_evicted: Vec<(usize, Bytes)>,
+    /// Keys expired and not yet notified of, with their database.
+    just_expired: Vec<(usize, Bytes)>,
+    /// Where evictions are counted; shared by the shards of a [`Shards`].
+    stats: Arc<Stats>,
+    /// Copies of hot keys' values, dropped as the keys change; shared like
+    /// `stats`.
+    hot: Arc<HotKeys>,
+    /// Consulted before each eviction; shared like `stats`.
+    eviction_hook: HookSlot,
+    selected: usize,
+    /// Every database but the selected one, by index; the selected one's
+    /// slot holds an empty placeholder.
+    parked: Vec<Keyspace>,
+    /// Values kept for the snapshots still to copy the shard.
+    cow: Vec<CopyOnWrite>,
 }
 
 impl Storage {
     pub fn new(config: StorageConfig) -> Self {
+        let slab = Slab::new(config.slab.clone());
+        let interner = Interner::new(config.intern.clone());
+        let evictor = Evictor::new(config.maxmemory_policy);
+        let parked = (0..config.databases.max(1))
+            .map(|_| Keyspace::new(config.maxmemory_policy))
+            .collect();
         Storage {
             data: HashMap::new(),
+            versions: HashMap::new(),
+            last_version: 0,
+            removed: 0,
+            expires: Expires::default(),
+            aof: None,
+            replication: None,
+            quota_memory: vec![0; config.quotas.len()],
             config,
             current_memory: 0,
+            released: 0,
+            defrag: Defrag::default(),
+            slab,
+            interner,
+            evictor,
+            just_evicted: Vec::new(),
+            just_expired: Vec::new(),
+            stats: Arc::default(),
+            hot: Arc::default(),
+            eviction_hook: HookSlot::default(),
+            selected: 0,
+            parked,
+            cow: Vec::new(),
         }
     }
 
-    pub fn insert(&mut self, key: String, value: String) -> bool {
+    pub fn insert(&mut self, key: Bytes, value: Bytes) -> bool {
         let entry_size = key.len() + value.len();
+        let old_size = self
+            .data
+            .get(&key)
+            .map(|old| key.len() + old.size())
+            .unwrap_or(0);
 
-        // Check if we would exceed memory limit
-        if self.current_memory + entry_size > self.config.max_memory {
+        if entry_size > old_size && self.make_room(entry_size - old_size, &key).is_err() {
             return false;
         }
 
-        // Update memory usage
-        if let Some(old_value) = self.data.get(&key) {
-            self.current_memory -= key.len() + old_value.len();
-        }
-        self.current_memory += entry_size;
+        self.release(&key, old_size);
+        self.account(&key, entry_size);
 
-        self.data.insert(key, value);
+        self.preserve(&key);
+        self.touch(&key);
+        let value = self.share(value);
+        match self.data.get_mut(&key) {
+            Some(old) => *old = Value::String(value),
+            None => {
+                let key = self.slab.alloc(key);
+                self.data.insert(key, Value::String(value));
+            }
+        }
         true
     }
 
-    pub fn get(&self, key: &str) -> Option<&String> {
+    /// The value at `key`, whatever its type.
+    pub fn value(&self, key: &[u8]) -> Option<&Value> {
         self.data.get(key)
     }
 
+    pub fn get(&self, key: &[u8]) -> Result<Option<&Bytes>, StorageError> {
+        match self.data.get(key) {
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(StorageError::WrongType),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes `keys`, returning how many existed.
+    pub fn del(&mut self, keys: &[Bytes]) -> usize {
+        let mut deleted = 0;
+        for key in keys {
+            if self.delete(key) {
+                self.last_version += 1;
+                deleted += 1;
+            }
+        }
+        deleted
+    }
+
+    /// Adds members to the set at `key`, returning how many were new.
+    pub fn sadd(&mut self, key: &[u8], members: Vec<Bytes>) -> Result<usize, StorageError> {
+        let mut added = 0;
+        for member in members {
+            if self.sismember(key, &member)? {
+                continue;
+            }
+            let grows = self.new_entry_size(key) + member.len();
+            self.make_room(grows, key)?;
+            let member = self.slab.alloc(member);
+            let set = match self.entry(key, || Value::Set(IndexSet::new())) {
+                Value::Set(set) => set,
+                _ => return Err(StorageError::WrongType),
+            };
+            set.insert(member);
+            self.account(key, grows);
+            added += 1;
+            self.touch(key);
+        }
+        Ok(added)
+    }
+
+    pub fn srem(&mut self, key: &[u8], members: &[Bytes]) -> Result<usize, StorageError> {
+        self.preserve(key);
+        let set = match self.data.get_mut(key) {
+            Some(Value::Set(set)) => set,
+            Some(_) => return Err(StorageError::WrongType),
+            None => return Ok(0),
+        };
+        let (mut removed, mut freed) = (0, 0);
+        for member in members {
+            if set.swap_remove(member) {
+                freed += member.len();
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.release(key, freed);
+            self.touch(key);
+        }
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    pub fn smembers(&self, key: &[u8], deadline: &Deadline) -> Result<Vec<&Bytes>, StorageError> {
+        match self.data.get(key) {
+            Some(Value::Set(set)) => deadline.collect(set.iter()),
+            Some(_) => Err(StorageError::WrongType),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Removes up to `count` members of the set at `key`, picked at
+    /// random, and returns them.
+    pub fn spop(
+        &mut self,
+        key: &[u8],
+        count: usize,
+        deadline: &Deadline,
+    ) -> Result<Vec<Bytes>, StorageError> {
+        let popped = self.srandmember(key, count.min(i64::MAX as usize) as i64, deadline)?;
+        self.srem(key, &popped)?;
+        Ok(popped)
+    }
+
+    /// Up to `count` distinct members of the set at `key`, picked at random,
+    /// or with a negative count `-count` of them, each picked independently
+    /// so the same one may come up more than once. Those picks aren't
+    /// bounded by the set's size, so there are at most
+    /// [`MAX_REPEATED_PICKS`], made checking `deadline`.
+    pub fn srandmember(
+        &self,
+        key: &[u8],
+        count: i64,
+        deadline: &Deadline,
+    ) -> Result<Vec<Bytes>, StorageError> {
+        let set = match self.data.get(key) {
+            Some(Value::Set(set)) if !set.is_empty() => set,
+            Some(Value::Set(_)) | None => return Ok(vec![]),
+            Some(_) => return Err(StorageError::WrongType),
+        };
+        let member = |i: usize| set.get_index(i).expect("picked within the set").clone();
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed).expect("failed to draw random members");
+        // xorshift, as for `allkeys-random`.
+        let mut state = u64::from_le_bytes(seed) | 1;
+        let mut below = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % n as u64) as usize
+        };
+        if count < 0 {
+            let picks = count.unsigned_abs().min(MAX_REPEATED_PICKS);
+            return deadline.collect((0..picks).map(|_| member(below(set.len()))));
+        }
+        let count = count as usize;
+        if count >= set.len() {
+            return Ok(set.iter().cloned().collect());
+        }
+        // Floyd's algorithm: `count` distinct positions, uniformly, in as
+        // many draws.
+        let mut picked = HashSet::with_capacity(count);
+        for last in set.len() - count..set.len() {
+            let at = below(last + 1);
+            if !picked.insert(at) {
+                picked.insert(last);
+            }
+        }
+        Ok(picked.into_iter().map(member).collect())
+    }
+
+    pub fn sismember(&self, key: &[u8], member: &[u8]) -> Result<bool, StorageError> {
+        match self.data.get(key) {
+            Some(Value::Set(set)) => Ok(set.contains(member)),
+            Some(_) => Err(StorageError::WrongType),
+            None => Ok(false),
+        }
+    }
+
+    /// Adds or updates members of the sorted set at `key`, returning how many
+    /// were new.
+    pub fn zadd(&mut self, key: &[u8], members: Vec<(f64, Bytes)>) -> Result<usize, StorageError> {
+        let mut added = 0;
+        for (score, member) in members {
+            let grows = self.new_entry_size(key) + member.len() + SCORE_SIZE;
+            let old = self.zscore(key, &member)?;
+            if old == Some(score) {
+                continue;
+            }
+            let member = if old.is_none() {
+                self.make_room(grows, key)?;
+                self.slab.alloc(member)
+            } else {
+                member
+            };
+            let zset = match self.entry(key, || Value::SortedSet(SortedSet::new())) {
+                Value::SortedSet(zset) => zset,
+                _ => return Err(StorageError::WrongType),
+            };
+            if zset.insert(member, score) {
+                self.account(key, grows);
+                added += 1;
+            }
+            self.touch(key);
+        }
+        Ok(added)
+    }
+
+    pub fn zscore(&self, key: &[u8], member: &[u8]) -> Result<Option<f64>, StorageError> {
+        Ok(self.zset(key)?.and_then(|zset| zset.score(member)))
+    }
+
+    pub fn zrem(&mut self, key: &[u8], members: &[Bytes]) -> Result<usize, StorageError> {
+        self.preserve(key);
+        let zset = match self.data.get_mut(key) {
+            Some(Value::SortedSet(zset)) => zset,
+            Some(_) => return Err(StorageError::WrongType),
+            None => return Ok(0),
+        };
+        let (mut removed, mut freed) = (0, 0);
+        for member in members {
+            if zset.remove(member) {
+                freed += member.len() + SCORE_SIZE;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            self.release(key, freed);
+            self.touch(key);
+        }
+        self.remove_if_empty(key);
+        Ok(removed)
+    }
+
+    /// Members ranked `start..=stop`; negative indexes count from the end.
+    pub fn zrange(
+        &self,
+        key: &[u8],
+        start: i64,
+        stop: i64,
+        deadline: &Deadline,
+    ) -> Result<Vec<(&Bytes, f64)>, StorageError> {
+        let zset = match self.zset(key)? {
+            Some(zset) => zset,
+            None => return Ok(vec![]),
+        };
+        match range::rank_range(start, stop, zset.len()) {
+            Some(range) => deadline.collect(zset.range_by_rank(*range.start(), *range.end())),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Members scored between `min` and `max`, optionally windowed by an
+    /// `(offset, count)` limit.
+    pub fn zrangebyscore(
+        &self,
+        key: &[u8],
+        min: ScoreBound,
+        max: ScoreBound,
+        limit: Option<(usize, usize)>,
+        deadline: &Deadline,
+    ) -> Result<Vec<(&Bytes, f64)>, StorageError> {
+        let zset = match self.zset(key)? {
+            Some(zset) => zset,
+            None => return Ok(vec![]),
+        };
+        let (offset, count) = limit.unwrap_or((0, usize::MAX));
+        deadline.collect(zset.range_by_score(min, max).skip(offset).take(count))
+    }
+
+    /// Version of the last change to `key`, or `None` if it doesn't exist.
+    pub fn version(&self, key: &[u8]) -> Option<u64> {
+        self.versions.get(key).copied()
+    }
+
+    /// Version `WATCH` compares for `key`: that of its last change, or for
+    /// a key that doesn't exist, that of the last removal of any, so one
+    /// created and removed again meanwhile isn't taken for untouched.
+    pub fn watch_version(&self, key: &[u8]) -> u64 {
+        self.version(key).unwrap_or(self.removed)
+    }
+
+    /// Number of modifications so far; a command changed data if this moved.
+    pub fn changes(&self) -> u64 {
+        self.last_version
+    }
+
+    /// Starts logging writes to `aof`.
+    pub fn attach_aof(&mut self, aof: Arc<Aof>) {
+        self.aof = Some(aof);
+    }
+
+    pub fn is_logging(&self) -> bool {
+        self.aof.is_some()
+    }
+
+    /// Streams writes to replicas through `replication`.
+    pub fn attach_replication(&mut self, replication: Arc<Replication>) {
+        self.replication = Some(replication);
+    }
+
+    pub fn replication(&self) -> Option<&Arc<Replication>> {
+        self.replication.as_ref()
+    }
+
+    /// Whether writes need to be passed to `propagate`.
+    pub fn is_propagating(&self) -> bool {
+        self.aof.is_some() || self.replication.as_ref().is_some_and(|r| r.has_replicas())
+    }
+
+    /// Appends a write command to the AOF, if one is attached, and streams it
+    /// to replicas, both preceded by a `SELECT` when it ran in another
+    /// database than the command before. A replica's own replicas get its
+    /// primary's stream instead, passed on as received.
+    pub fn propagate(&self, frame: &RespValue) {
+        if let Some(aof) = &self.aof {
+            if let Err(e) = aof.append(self.selected, frame) {
+                error!("Failed to write to the append only file: {}", e);
+            }
+        }
+        if let Some(replication) = &self.replication {
+            if !replication.is_replica() {
+                replication.feed_write(self.selected, &frame.serialize());
+            }
+        }
+    }
+
+    /// Forces logged writes to disk.
+    pub fn sync_aof(&self) -> std::io::Result<()> {
+        match &self.aof {
+            Some(aof) => aof.sync(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_aof_rewriting(&self) -> bool {
+        self.aof.as_ref().is_some_and(|aof| aof.is_rewriting())
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Distinct pooled values and lookups they answered, or `None` if
+    /// interning is off.
+    pub fn intern_stats(&self) -> Option<(usize, u64)> {
+        self.interner
+            .is_enabled()
+            .then(|| (self.interner.entries(), self.interner.hits()))
+    }
+
+    /// Slab counters, or `None` if slab allocation is off.
+    pub fn slab_stats(&self) -> Option<SlabStats> {
+        self.slab.is_enabled().then(|| self.slab.stats())
+    }
+
     pub fn memory_usage(&self) -> usize {
         self.current_memory
     }
 
-    pub fn is_persistence_enabled(&self) -> bool {
-        self.config.persistence_enabled
+    /// Drops every key and loads `databases` instead, the entries of each
+    /// database by index. Databases past the end are left empty.
+    pub fn replace_dataset(&mut self, databases: Vec<Vec<(Bytes, Value)>>) {
+        for index in 0..self.databases() {
+            self.preserve_database(index);
+        }
+        self.hot.clear();
+        let selected = self.selected;
+        self.released += self.current_memory;
+        self.current_memory = 0;
+        let mut databases = databases.into_iter();
+        for index in 0..self.databases() {
+            self.select(index);
+            self.data = databases.next().unwrap_or_default().into_iter().collect();
+            self.versions.clear();
+            self.expires.clear();
+            self.current_memory += self
+                .data
+                .iter()
+                .map(|(k, v)| k.len() + v.size())
+                .sum::<usize>();
+            self.evictor.clear();
+            for key in self.data.keys() {
+                self.evictor.record(key);
+            }
+        }
+        self.select(selected);
+        self.recount_quotas();
+        self.record_removal();
+    }
+
+    fn zset(&self, key: &[u8]) -> Result<Option<&SortedSet>, StorageError> {
+        match self.data.get(key) {
+            Some(Value::SortedSet(zset)) => Ok(Some(zset)),
+            Some(_) => Err(StorageError::WrongType),
+            None => Ok(None),
+        }
     }
 
-    pub fn save_to_disk(&self) -> std::io::Result<()> {
-        if !self.config.persistence_enabled {
-            return Ok(());
+    /// Key bytes that creating `key` would add, or 0 if it already exists.
+    fn new_entry_size(&self, key: &[u8]) -> usize {
+        if self.data.contains_key(key) {
+            0
+        } else {
+            key.len()
         }
-        let data = serde_json::to_string(&self.data)?;
-        std::fs::write("dump.rdb", data)
     }
 
-    pub fn load_from_disk(&mut self) -> std::io::Result<()> {
-        if !self.config.persistence_enabled {
-            return Ok(());
+    /// Fails unless `size` more bytes fit under `max_memory`, for results
+    /// built while their sources are borrowed. With an eviction policy the
+    /// check is left to `make_room` once the result is stored.
+    fn reserve(&self, size: usize) -> Result<(), StorageError> {
+        if self.evictor.is_enabled() || self.follows_primary() {
+            Ok(())
+        } else if self.current_memory + size > self.config.max_memory {
+            Err(StorageError::OutOfMemory)
+        } else {
+            Ok(())
         }
-        if let Ok(data) = std::fs::read_to_string("dump.rdb") {
-            self.data = serde_json::from_str(&data)?;
-            self.current_memory = self.data.iter().map(|(k, v)| k.len() + v.len()).sum();
+    }
+
+    fn entry(&mut self, key: &[u8], default: impl FnOnce() -> Value) -> &mut Value {
+        self.preserve(key);
+        if !self.data.contains_key(key) {
+            let key = self.slab.copy(key);
+            self.data.insert(key, default());
+        }
+        self.data.get_mut(key).unwrap()
+    }
+
+    /// Storage for a string value: the pooled copy if an identical one is
+    /// interned, otherwise `value` moved into the slab.
+    fn share(&mut self, value: Bytes) -> Bytes {
+        if let Some(shared) = self.interner.get(&value) {
+            return shared;
+        }
+        let value = self.slab.alloc(value);
+        self.interner.admit(&value);
+        value
+    }
+
+    /// Whether this is a replica, which applies its primary's writes as they
+    /// come regardless of `max_memory`.
+    fn follows_primary(&self) -> bool {
+        self.replication.as_ref().is_some_and(|r| r.is_replica())
+    }
+
+    /// Records a modification of `key`.
+    fn touch(&mut self, key: &[u8]) {
+        self.hot.invalidate(self.selected, key);
+        self.evictor.record(key);
+        self.last_version += 1;
+        match self.versions.get_mut(key) {
+            Some(version) => *version = self.last_version,
+            None => {
+                self.versions
+                    .insert(Bytes::copy_from_slice(key), self.last_version);
+            }
+        }
+    }
+
+    /// Records that keys went away, moving the version of absent keys.
+    fn record_removal(&mut self) {
+        self.last_version += 1;
+        self.removed = self.last_version;
+    }
+
+    /// Removes `key` and releases its memory. Returns whether it existed.
+    fn delete(&mut self, key: &[u8]) -> bool {
+        self.preserve(key);
+        match self.data.remove(key) {
+            Some(value) => {
+                self.hot.invalidate(self.selected, key);
+                self.release(key, key.len() + value.size());
+                self.versions.remove(key);
+                self.record_removal();
+                self.expires.remove(key);
+                self.evictor.forget(key);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops a collection once its last member is gone, like Redis does.
+    fn remove_if_empty(&mut self, key: &[u8]) {
+        let empty = match self.data.get(key) {
+            Some(Value::Set(set)) => set.is_empty(),
+            Some(Value::SortedSet(zset)) => zset.is_empty(),
+            Some(Value::List(list)) => list.is_empty(),
+            _ => false,
+        };
+        if empty {
+            self.data.remove(key);
+            self.versions.remove(key);
+            self.record_removal();
+            self.expires.remove(key);
+            self.evictor.forget(key);
+            self.release(key, key.len());
         }
-        Ok(())
     }
 }
 
-pub type Db = Arc<Mutex<Storage>>;
+pub type Db = Arc<Shards>;
@@ -0,0 +1,152 @@
+//! Databases as key prefixes, for cluster mode
+//!
+//! With `prefixed_databases` on, every database is kept in database 0: the
+//! keys of database 3 are stored as `\x03` followed by the key, and those of
+//! database 0 as they are. Clients still `SELECT`, even in cluster mode,
+//! where only database 0 exists; their keys are translated on the way in
+//! and out, so each database still only sees its own. Keys of database 0
+//! starting with the byte of another database belong to that one.
+use super::{ShardLocks, Shards};
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// The most databases prefixes can tell apart: one byte's worth.
+pub const MAX_PREFIXED_DATABASES: usize = 256;
+
+/// Where a client's keys live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// Databases are kept apart; keys are stored as given.
+    Database(usize),
+    /// Keys of database `db` are stored with its byte in front, in database
+    /// 0.
+    Prefixed { db: u8, databases: usize },
+}
+
+impl Namespace {
+    /// The database the keys are actually stored in.
+    pub fn storage_db(&self) -> usize {
+        match self {
+            Namespace::Database(db) => *db,
+            Namespace::Prefixed { .. } => 0,
+        }
+    }
+
+    pub fn is_prefixed(&self) -> bool {
+        matches!(self, Namespace::Prefixed { .. })
+    }
+
+    fn prefix(&self) -> Option<u8> {
+        match self {
+            Namespace::Prefixed { db, .. } if *db > 0 => Some(*db),
+            _ => None,
+        }
+    }
+
+    /// How `key` is stored.
+    pub fn key(&self, key: &Bytes) -> Bytes {
+        match self.prefix() {
+            Some(prefix) => {
+                let mut stored = BytesMut::with_capacity(key.len() + 1);
+                stored.put_u8(prefix);
+                stored.put_slice(key);
+                stored.freeze()
+            }
+            None => key.clone(),
+        }
+    }
+
+    pub fn keys(&self, keys: &[Bytes]) -> Vec<Bytes> {
+        keys.iter().map(|key| self.key(key)).collect()
+    }
+
+    /// The key a client knows as stored `key`, or `None` if it belongs to
+    /// another database.
+    pub fn strip(&self, key: &Bytes) -> Option<Bytes> {
+        match self {
+            Namespace::Database(_) => Some(key.clone()),
+            Namespace::Prefixed { db: 0, databases } => match key.first() {
+                Some(&first) if first > 0 && (first as usize) < *databases => None,
+                _ => Some(key.clone()),
+            },
+            Namespace::Prefixed { db, .. } => (key.first() == Some(db)).then(|| key.slice(1..)),
+        }
+    }
+
+    /// A glob pattern matching the stored keys `pattern` matches in the
+    /// database. Database 0 matches those of the others too, left for
+    /// [`Namespace::strip`] to filter out.
+    pub fn pattern(&self, pattern: &Bytes) -> Bytes {
+        match self.prefix() {
+            Some(prefix) => {
+                let mut stored = BytesMut::with_capacity(pattern.len() + 2);
+                if matches!(prefix, b'*' | b'?' | b'[' | b'\\') {
+                    stored.put_u8(b'\\');
+                }
+                stored.put_u8(prefix);
+                stored.put_slice(pattern);
+                stored.freeze()
+            }
+            None => pattern.clone(),
+        }
+    }
+}
+
+impl Shards {
+    /// Where the keys of database `db` live.
+    pub fn namespace(&self, db: usize) -> Namespace {
+        namespace(db, self.config().prefixed_databases, self.databases())
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Where the keys of database `db` live.
+    pub fn namespace(&self, db: usize) -> Namespace {
+        self.db.namespace(db)
+    }
+}
+
+fn namespace(db: usize, prefixed: bool, databases: usize) -> Namespace {
+    match u8::try_from(db) {
+        Ok(db) if prefixed => Namespace::Prefixed {
+            db,
+            databases: databases.min(MAX_PREFIXED_DATABASES),
+        },
+        _ => Namespace::Database(db),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::glob;
+
+    #[test]
+    fn test_prefixes() {
+        let third = namespace(3, true, 16);
+        let key = Bytes::from("user:1");
+        let stored = third.key(&key);
+        assert_eq!(&stored[..], b"\x03user:1");
+        assert_eq!(third.storage_db(), 0);
+        assert_eq!(third.strip(&stored), Some(key.clone()));
+        assert_eq!(third.strip(&key), None);
+        assert!(glob::matches(third.pattern(&"user:*".into()), &stored));
+
+        let first = namespace(0, true, 16);
+        assert_eq!(first.key(&key), key);
+        assert_eq!(first.strip(&key), Some(key.clone()));
+        assert_eq!(first.strip(&stored), None);
+        // Past the last database, the byte is just part of a key.
+        assert!(first.strip(&Bytes::from("\x20key")).is_some());
+
+        // Bytes that mean something in a glob are escaped.
+        let star = namespace(b'*' as usize, true, 256);
+        let stored = star.key(&key);
+        assert!(glob::matches(star.pattern(&"user:*".into()), &stored));
+        assert!(!glob::matches(star.pattern(&"*".into()), &key));
+
+        let separate = namespace(3, false, 16);
+        assert_eq!(separate, Namespace::Database(3));
+        assert_eq!(separate.key(&key), key);
+        assert_eq!(separate.storage_db(), 3);
+    }
+}
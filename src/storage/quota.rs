@@ -0,0 +1,187 @@
+//! Memory quotas for parts of the keyspace
+//!
+//! Each quota in `quotas` caps the memory of the keys it matches, by
+//! database, key prefix or both, and split between the shards as
+//! `max_memory` is. Making room for a key only ever evicts keys of the
+//! same quota, or, for a key outside every quota, keys outside every
+//! quota, so a namespace filling up evicts its own keys and never another
+//! one's.
+use super::namespace::MAX_PREFIXED_DATABASES;
+use super::{ShardLocks, Storage};
+use crate::config::StorageConfig;
+
+/// The quota of `config` that `key`, stored in database `db`, counts
+/// against, if any.
+pub(super) fn quota_of(config: &StorageConfig, db: usize, key: &[u8]) -> Option<usize> {
+    if config.quotas.is_empty() {
+        return None;
+    }
+    // With prefixed databases, the database is the key's first byte.
+    let databases = config.databases.clamp(1, MAX_PREFIXED_DATABASES);
+    let (db, key) = match key.first() {
+        Some(&first) if config.prefixed_databases && first > 0 && (first as usize) < databases => {
+            (first as usize, &key[1..])
+        }
+        _ => (db, key),
+    };
+    config
+        .quotas
+        .iter()
+        .position(|quota| quota.matches(db, key))
+}
+
+impl Storage {
+    pub(super) fn quota_of_in(&self, db: usize, key: &[u8]) -> Option<usize> {
+        quota_of(&self.config, db, key)
+    }
+
+    pub(super) fn quota_of(&self, key: &[u8]) -> Option<usize> {
+        self.quota_of_in(self.selected, key)
+    }
+
+    /// Whether `size` more bytes of `quota` would go over its budget.
+    pub(super) fn over_quota(&self, quota: Option<usize>, size: usize) -> bool {
+        quota.is_some_and(|quota| {
+            self.quota_memory[quota] + size > self.config.quotas[quota].max_memory
+        })
+    }
+
+    /// Counts `size` more bytes for `key`, of the selected database.
+    pub(super) fn account(&mut self, key: &[u8], size: usize) {
+        self.current_memory += size;
+        if let Some(quota) = self.quota_of(key) {
+            self.quota_memory[quota] += size;
+        }
+    }
+
+    /// Counts `size` bytes of `key`, of the selected database, as freed.
+    pub(super) fn release(&mut self, key: &[u8], size: usize) {
+        self.current_memory -= size;
+        self.released += size;
+        if let Some(quota) = self.quota_of(key) {
+            self.quota_memory[quota] -= size;
+        }
+    }
+
+    /// Adds up each quota's memory afresh, after whole databases moved.
+    pub(super) fn recount_quotas(&mut self) {
+        let mut usage = vec![0; self.config.quotas.len()];
+        if !usage.is_empty() {
+            for index in 0..self.databases() {
+                for (key, value) in self.database(index) {
+                    if let Some(quota) = self.quota_of_in(index, key) {
+                        usage[quota] += key.len() + value.size();
+                    }
+                }
+            }
+        }
+        self.quota_memory = usage;
+    }
+
+    /// Memory used in each quota on this shard.
+    pub fn quota_usage(&self) -> &[usize] {
+        &self.quota_memory
+    }
+}
+
+impl ShardLocks<'_> {
+    /// Memory used and allowed in each quota, over the locked shards.
+    pub fn quota_usage(&self) -> Vec<(usize, usize)> {
+        let config = self.config();
+        config
+            .quotas
+            .iter()
+            .enumerate()
+            .map(|(index, quota)| {
+                let used = self.iter().map(|shard| shard.quota_usage()[index]).sum();
+                (used, quota.max_memory)
+            })
+            .collect()
+    }
+
+    /// Formats the quotas' lines of `INFO memory`.
+    pub fn quota_info(&self) -> String {
+        self.quota_usage()
+            .into_iter()
+            .enumerate()
+            .map(|(index, (used, max))| format!("quota{}:used={},max={}\r\n", index, used, max))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{MaxMemoryPolicy, QuotaConfig, StorageConfig};
+    use crate::storage::{Shards, StorageError};
+
+    #[tokio::test]
+    async fn test_quotas_evict_their_own_keys() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1000,
+            shards: 1,
+            databases: 2,
+            maxmemory_policy: MaxMemoryPolicy::AllKeysLru,
+            quotas: vec![
+                QuotaConfig {
+                    db: None,
+                    prefix: Some("analytics:".to_string()),
+                    max_memory: 100,
+                },
+                QuotaConfig {
+                    db: Some(1),
+                    prefix: None,
+                    max_memory: 300,
+                },
+            ],
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        let shard = store.shard_mut(b"");
+        assert!(shard.insert("session:1".into(), vec![b'x'; 500].into()));
+        // Ten keys of 20 bytes don't fit in 100: the oldest analytics keys
+        // go, and the session stays.
+        for i in 0..10 {
+            let key = format!("analytics:{}", i);
+            assert!(shard.insert(key.into(), vec![b'x'; 9].into()));
+        }
+        assert_eq!(shard.quota_usage(), [100, 0]);
+        assert!(shard.value(b"session:1").is_some());
+        assert!(shard.value(b"analytics:0").is_none());
+        assert!(shard.value(b"analytics:9").is_some());
+
+        // Database 1 fills its own quota, evicting only its own keys.
+        shard.select(1);
+        assert!(shard.insert("a".into(), vec![b'x'; 199].into()));
+        assert!(shard.insert("b".into(), vec![b'x'; 199].into()));
+        assert_eq!(shard.quota_usage(), [100, 200]);
+        assert!(shard.value(b"a").is_none());
+        // More than the whole quota doesn't fit, whatever is evicted.
+        assert_eq!(
+            shard.sadd(b"s", vec![vec![b'x'; 300].into()]),
+            Err(StorageError::OutOfMemory)
+        );
+        assert_eq!(shard.quota_usage(), [100, 0]);
+        shard.select(0);
+        assert!(shard.value(b"session:1").is_some());
+
+        // Keys outside every quota evict each other, not the quotas' keys.
+        assert!(shard.insert("session:2".into(), vec![b'x'; 500].into()));
+        assert!(shard.value(b"session:1").is_none());
+        assert_eq!(shard.memory_usage(), 509 + 100);
+
+        // Swapping databases moves the usage of database 1 with its keys.
+        store.swapdb(0, 1).unwrap();
+        assert_eq!(store.quota_usage(), [(100, 100), (509, 300)]);
+        assert_eq!(
+            store.quota_info(),
+            "quota0:used=100,max=100\r\nquota1:used=509,max=300\r\n"
+        );
+        // Now over its quota, database 1 evicts its own keys for new ones.
+        store.select(1).unwrap();
+        assert_eq!(store.shard_mut(b"").sadd(b"s", vec!["m".into()]), Ok(1));
+        assert!(store.shard(b"").value(b"session:2").is_none());
+        assert!(store.shard(b"").value(b"analytics:9").is_some());
+        store.flushall();
+        assert_eq!(store.quota_usage(), [(0, 100), (0, 300)]);
+    }
+}
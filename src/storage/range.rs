@@ -0,0 +1,110 @@
+//! Index normalization shared by the range commands
+use std::ops::RangeInclusive;
+
+/// Resolves the inclusive `start`/`stop` indexes of `ZRANGE` (and the other
+/// rank-based range commands) against `len` items, the way Redis does:
+/// negative indexes count from the end, a start before the first item is
+/// clamped to it and a stop past the last item to that. `None` if nothing
+/// is selected.
+pub fn rank_range(start: i64, stop: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+    let start = from_end(start, len).max(0);
+    let stop = from_end(stop, len);
+    if start > stop || start >= len {
+        return None;
+    }
+    Some(start as usize..=stop.min(len - 1) as usize)
+}
+
+/// Resolves `GETRANGE` offsets against a string of `len` bytes. Unlike
+/// `rank_range`, Redis also clamps a stop before the first byte to it, so
+/// `GETRANGE s 0 -100` returns the first byte; only two negative offsets in
+/// the wrong order select nothing.
+pub fn byte_range(start: i64, stop: i64, len: usize) -> Option<RangeInclusive<usize>> {
+    if (start < 0 && stop < 0 && start > stop) || len == 0 {
+        return None;
+    }
+    let len = len as i64;
+    let start = from_end(start, len).max(0);
+    let stop = from_end(stop, len).clamp(0, len - 1);
+    (start <= stop).then_some(start as usize..=stop as usize)
+}
+
+/// `index` with negative values counted back from `len`.
+fn from_end(index: i64, len: i64) -> i64 {
+    if index < 0 {
+        len.saturating_add(index)
+    } else {
+        index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rank_range() {
+        // (start, stop, expected) over 5 items, as `ZRANGE` replies in Redis.
+        let cases = [
+            (0, -1, Some(0..=4)),
+            (0, 4, Some(0..=4)),
+            (0, 0, Some(0..=0)),
+            (4, 4, Some(4..=4)),
+            (1, 3, Some(1..=3)),
+            (-2, -1, Some(3..=4)),
+            (-5, -5, Some(0..=0)),
+            (-1, 4, Some(4..=4)),
+            (2, -2, Some(2..=3)),
+            (-100, 100, Some(0..=4)),
+            (-100, -5, Some(0..=0)),
+            (0, 100, Some(0..=4)),
+            (i64::MIN, i64::MAX, Some(0..=4)),
+            (3, 1, None),
+            (-1, -2, None),
+            (5, 10, None),
+            (5, -1, None),
+            (0, -6, None),
+            (0, -100, None),
+            (-100, -6, None),
+            (i64::MAX, i64::MAX, None),
+            (i64::MIN, i64::MIN, None),
+        ];
+        for (start, stop, expected) in cases {
+            assert_eq!(rank_range(start, stop, 5), expected, "{} {}", start, stop);
+        }
+        for (start, stop) in [(0, -1), (0, 0), (-1, -1), (-100, 100)] {
+            assert_eq!(rank_range(start, stop, 0), None);
+        }
+    }
+
+    #[test]
+    fn test_byte_range() {
+        // (start, stop, expected) over "Hello World", as `GETRANGE` replies
+        // in Redis.
+        let cases = [
+            (0, 3, Some(0..=3)),
+            (-3, -1, Some(8..=10)),
+            (0, -1, Some(0..=10)),
+            (0, 100, Some(0..=10)),
+            (10, 100, Some(10..=10)),
+            (-100, 100, Some(0..=10)),
+            (-100, -100, Some(0..=0)),
+            (0, -100, Some(0..=0)),
+            (-100, 0, Some(0..=0)),
+            (i64::MIN, i64::MAX, Some(0..=10)),
+            (5, 3, None),
+            (-1, -5, None),
+            (11, 20, None),
+            (11, -1, None),
+            (5, -100, None),
+            (i64::MAX, i64::MAX, None),
+        ];
+        for (start, stop, expected) in cases {
+            assert_eq!(byte_range(start, stop, 11), expected, "{} {}", start, stop);
+        }
+        for (start, stop) in [(0, -1), (0, 0), (-1, -1), (-100, 100)] {
+            assert_eq!(byte_range(start, stop, 0), None);
+        }
+    }
+}
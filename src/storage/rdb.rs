@@ -0,0 +1,801 @@
+//! Redis RDB dump format
+//!
+//! Files are written as RDB version 9, which Redis 5 and later load: strings
+//! raw, sets and lists plain and sorted sets with binary scores. Loading also
+//! accepts the compact encodings newer Redis versions write for these types
+//! (integer and LZF strings, intsets, listpacks, quicklists of listpacks).
+use super::{SortedSet, Value};
+use bytes::Bytes;
+use indexmap::IndexSet;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const MAGIC: &[u8] = b"REDIS";
+const VERSION: u32 = 9;
+/// Newest format version we know how to read (Redis 7.2).
+const MAX_VERSION: u32 = 11;
+
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_MODULE_AUX: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Quicklist node holding a single element as is, rather than a listpack.
+const QUICKLIST_NODE_PLAIN: usize = 1;
+
+#[derive(Error, Debug)]
+pub enum RdbError {
+    #[error("not an RDB file")]
+    InvalidHeader,
+    #[error("unsupported RDB version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unsupported RDB value type {0}")]
+    UnsupportedType(u8),
+    /// Redis 7 stores `FUNCTION` libraries in the dump; only `EVAL`
+    /// scripts are supported, so there is nothing to load them into.
+    #[error("RDB file holds function libraries, and functions aren't supported")]
+    Functions,
+    #[error("RDB file truncated")]
+    Truncated,
+    #[error("corrupt RDB file: {0}")]
+    Corrupt(&'static str),
+    #[error("RDB checksum mismatch")]
+    ChecksumMismatch,
+    #[error("DUMP payload version or checksum are wrong")]
+    BadPayload,
+    #[error("RDB file uses database {0}, past the configured databases")]
+    DatabaseOutOfRange(usize),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A dump's contents, as [`read`] loads them.
+pub struct Dump {
+    /// The entries of each database, by index.
+    pub databases: Vec<Vec<(Bytes, Value)>>,
+    /// The sources of the scripts cached when it was written.
+    pub scripts: Vec<Bytes>,
+}
+
+/// Writes `scripts` and `databases` to `path` through a temporary file, so
+/// a crash mid-save never leaves a half-written dump behind.
+pub fn save<'a>(
+    path: &Path,
+    scripts: &[Bytes],
+    databases: impl IntoIterator<Item = impl ExactSizeIterator<Item = (&'a Bytes, &'a Value)>>,
+) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(".temp-{}", std::process::id()));
+
+    let file = File::create(&temp_path)?;
+    let mut out = BufWriter::new(file);
+    write(&mut out, scripts, databases)?;
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// Encodes `databases`, the entries of each database by index, as a
+/// complete RDB file into `out`. `scripts` go in `lua` aux fields, as Redis
+/// 4 to 6 keep their script cache.
+pub fn write<'a>(
+    out: impl Write,
+    scripts: &[Bytes],
+    databases: impl IntoIterator<Item = impl ExactSizeIterator<Item = (&'a Bytes, &'a Value)>>,
+) -> std::io::Result<()> {
+    let mut out = ChecksumWriter { inner: out, crc: 0 };
+    out.write_all(MAGIC)?;
+    out.write_all(format!("{:04}", VERSION).as_bytes())?;
+    write_aux(&mut out, b"redis-ver", env!("CARGO_PKG_VERSION").as_bytes())?;
+    write_aux(&mut out, b"redis-bits", b"64")?;
+    for script in scripts {
+        write_aux(&mut out, b"lua", script)?;
+    }
+
+    for (db, entries) in databases.into_iter().enumerate() {
+        if entries.len() == 0 {
+            continue;
+        }
+        out.write_all(&[OPCODE_SELECTDB])?;
+        write_len(&mut out, db)?;
+        out.write_all(&[OPCODE_RESIZEDB])?;
+        write_len(&mut out, entries.len())?;
+        write_len(&mut out, 0)?;
+        for (key, value) in entries {
+            write_entry(&mut out, key, value)?;
+        }
+    }
+
+    out.write_all(&[OPCODE_EOF])?;
+    let crc = out.crc;
+    out.inner.write_all(&crc.to_le_bytes())?;
+    out.inner.flush()
+}
+
+fn write_entry(out: &mut impl Write, key: &[u8], value: &Value) -> std::io::Result<()> {
+    out.write_all(&[value_type(value)])?;
+    write_string(out, key)?;
+    write_value(out, value)
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => TYPE_STRING,
+        Value::Set(_) => TYPE_SET,
+        Value::SortedSet(_) => TYPE_ZSET_2,
+        Value::List(_) => TYPE_LIST,
+    }
+}
+
+fn write_value(out: &mut impl Write, value: &Value) -> std::io::Result<()> {
+    match value {
+        Value::String(s) => write_string(out, s)?,
+        Value::Set(set) => {
+            write_len(out, set.len())?;
+            for member in set {
+                write_string(out, member)?;
+            }
+        }
+        Value::SortedSet(zset) => {
+            write_len(out, zset.len())?;
+            for (member, score) in zset.iter() {
+                write_string(out, member)?;
+                out.write_all(&score.to_le_bytes())?;
+            }
+        }
+        Value::List(list) => {
+            write_len(out, list.len())?;
+            for element in list {
+                write_string(out, element)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serializes `value` as `DUMP` does: its type and encoding as in a dump
+/// file, then the format version and a CRC64 of it all.
+pub fn dump(value: &Value) -> Vec<u8> {
+    let mut out = vec![value_type(value)];
+    write_value(&mut out, value).expect("writing to a Vec");
+    out.extend_from_slice(&(VERSION as u16).to_le_bytes());
+    let crc = crc64(0, &out);
+    out.extend_from_slice(&crc.to_le_bytes());
+    out
+}
+
+/// Decodes a `DUMP` payload, from this server or a Redis one no newer
+/// than we can read.
+pub fn undump(payload: &[u8]) -> Result<Value, RdbError> {
+    let Some((body, crc)) = payload.split_last_chunk::<8>() else {
+        return Err(RdbError::BadPayload);
+    };
+    let Some((encoded, version)) = body.split_last_chunk::<2>() else {
+        return Err(RdbError::BadPayload);
+    };
+    if u16::from_le_bytes(*version) as u32 > MAX_VERSION
+        || u64::from_le_bytes(*crc) != crc64(0, body)
+    {
+        return Err(RdbError::BadPayload);
+    }
+    let mut r = Reader {
+        data: encoded,
+        pos: 0,
+    };
+    let value_type = r.byte()?;
+    let value = r.value(value_type)?;
+    if !r.is_done() {
+        return Err(RdbError::Corrupt("data after the value"));
+    }
+    Ok(value)
+}
+
+fn write_aux(out: &mut impl Write, name: &[u8], value: &[u8]) -> std::io::Result<()> {
+    out.write_all(&[OPCODE_AUX])?;
+    write_string(out, name)?;
+    write_string(out, value)
+}
+
+fn write_len(out: &mut impl Write, len: usize) -> std::io::Result<()> {
+    if len < 1 << 6 {
+        out.write_all(&[len as u8])
+    } else if len < 1 << 14 {
+        out.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+    } else if let Ok(len) = u32::try_from(len) {
+        out.write_all(&[0x80])?;
+        out.write_all(&len.to_be_bytes())
+    } else {
+        out.write_all(&[0x81])?;
+        out.write_all(&(len as u64).to_be_bytes())
+    }
+}
+
+fn write_string(out: &mut impl Write, s: &[u8]) -> std::io::Result<()> {
+    write_len(out, s.len())?;
+    out.write_all(s)
+}
+
+/// Passes writes through while keeping a running CRC64 of them.
+struct ChecksumWriter<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc64(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A key as stored in a dump file.
+#[derive(Debug, PartialEq)]
+pub struct Entry {
+    pub db: usize,
+    pub key: Bytes,
+    pub value: Value,
+    /// Unix time in milliseconds at which the key expires.
+    pub expires_at: Option<u64>,
+}
+
+/// Decodes a complete RDB file into the entries of each of `databases`
+/// databases, by index, and the scripts it holds. Keys already expired are
+/// dropped; those with an expiry still ahead are loaded without one.
+pub fn read(data: &[u8], databases: usize) -> Result<Dump, RdbError> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let mut loaded: Vec<Vec<_>> = (0..databases).map(|_| Vec::new()).collect();
+    let (entries, scripts) = parse(data)?;
+    for entry in entries {
+        if entry.expires_at.is_some_and(|at| at <= now_ms) {
+            continue;
+        }
+        loaded
+            .get_mut(entry.db)
+            .ok_or(RdbError::DatabaseOutOfRange(entry.db))?
+            .push((entry.key, entry.value));
+    }
+    Ok(Dump {
+        databases: loaded,
+        scripts,
+    })
+}
+
+/// Decodes every entry of a complete RDB file, expired or not, in any
+/// database.
+pub fn read_all(data: &[u8]) -> Result<Vec<Entry>, RdbError> {
+    parse(data).map(|(entries, _)| entries)
+}
+
+/// Every entry of a complete RDB file, and the scripts in its `lua` aux
+/// fields.
+fn parse(data: &[u8]) -> Result<(Vec<Entry>, Vec<Bytes>), RdbError> {
+    let mut r = Reader { data, pos: 0 };
+    if r.take(MAGIC.len())? != MAGIC {
+        return Err(RdbError::InvalidHeader);
+    }
+    let version = std::str::from_utf8(r.take(4)?)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or(RdbError::InvalidHeader)?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut entries = Vec::new();
+    let mut scripts = Vec::new();
+    let mut db = 0;
+    let mut expires_at = None;
+    loop {
+        match r.byte()? {
+            OPCODE_EOF => break,
+            OPCODE_SELECTDB => db = r.len()?,
+            OPCODE_RESIZEDB => {
+                r.len()?;
+                r.len()?;
+            }
+            OPCODE_AUX => {
+                let name = r.string()?;
+                let value = r.string()?;
+                if &name[..] == b"lua" {
+                    scripts.push(value);
+                }
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expires_at = Some(u64::from_le_bytes(r.array()?));
+            }
+            OPCODE_EXPIRETIME => {
+                expires_at = Some(u32::from_le_bytes(r.array()?) as u64 * 1000);
+            }
+            OPCODE_FREQ => {
+                r.byte()?;
+            }
+            OPCODE_IDLE => {
+                r.len()?;
+            }
+            OPCODE_FUNCTION2 => return Err(RdbError::Functions),
+            OPCODE_MODULE_AUX => return Err(RdbError::UnsupportedType(OPCODE_MODULE_AUX)),
+            value_type => {
+                let key = r.string()?;
+                let value = r.value(value_type)?;
+                entries.push(Entry {
+                    db,
+                    key,
+                    value,
+                    expires_at: expires_at.take(),
+                });
+            }
+        }
+    }
+
+    // Version 5 added the checksum; all zeros means it was disabled.
+    if version >= 5 {
+        let body = &data[..r.pos];
+        let expected = u64::from_le_bytes(r.array()?);
+        if expected != 0 && expected != crc64(0, body) {
+            return Err(RdbError::ChecksumMismatch);
+        }
+    }
+    Ok((entries, scripts))
+}
+
+/// A length prefix, or the marker of a specially encoded string.
+enum Length {
+    Plain(usize),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(n).ok_or(RdbError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(RdbError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn byte(&mut self) -> Result<u8, RdbError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn length(&mut self) -> Result<Length, RdbError> {
+        let first = self.byte()?;
+        let len = match first >> 6 {
+            0 => (first & 0x3F) as u64,
+            1 => ((first & 0x3F) as u64) << 8 | self.byte()? as u64,
+            2 => match first {
+                0x80 => u32::from_be_bytes(self.array()?) as u64,
+                0x81 => u64::from_be_bytes(self.array()?),
+                _ => return Err(RdbError::Corrupt("unknown length encoding")),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3F)),
+        };
+        usize::try_from(len)
+            .map(Length::Plain)
+            .map_err(|_| RdbError::Corrupt("length out of range"))
+    }
+
+    fn len(&mut self) -> Result<usize, RdbError> {
+        match self.length()? {
+            Length::Plain(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::Corrupt("expected a length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Bytes, RdbError> {
+        let s = match self.length()? {
+            Length::Plain(len) => Bytes::copy_from_slice(self.take(len)?),
+            Length::Encoded(ENC_INT8) => (self.byte()? as i8).to_string().into(),
+            Length::Encoded(ENC_INT16) => i16::from_le_bytes(self.array()?).to_string().into(),
+            Length::Encoded(ENC_INT32) => i32::from_le_bytes(self.array()?).to_string().into(),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.len()?;
+                let len = self.len()?;
+                lzf_decompress(self.take(compressed_len)?, len)?.into()
+            }
+            Length::Encoded(_) => return Err(RdbError::Corrupt("unknown string encoding")),
+        };
+        Ok(s)
+    }
+
+    /// A score of the old `ZSET` type, stored as text.
+    fn text_score(&mut self) -> Result<f64, RdbError> {
+        match self.byte()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => std::str::from_utf8(self.take(len as usize)?)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(RdbError::Corrupt("invalid score")),
+        }
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<Value, RdbError> {
+        let value = match value_type {
+            TYPE_STRING => Value::String(self.string()?),
+            TYPE_SET => {
+                let len = self.len()?;
+                let mut set = IndexSet::with_capacity(len.min(self.data.len()));
+                for _ in 0..len {
+                    set.insert(self.string()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_LIST => {
+                let len = self.len()?;
+                let mut list = VecDeque::with_capacity(len.min(self.data.len()));
+                for _ in 0..len {
+                    list.push_back(self.string()?);
+                }
+                Value::List(list)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.len()? {
+                    let container = self.len()?;
+                    let node = self.string()?;
+                    if container == QUICKLIST_NODE_PLAIN {
+                        list.push_back(node);
+                    } else {
+                        list.extend(listpack_entries(&node)?);
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let mut zset = SortedSet::new();
+                for _ in 0..self.len()? {
+                    let member = self.string()?;
+                    let score = if value_type == TYPE_ZSET_2 {
+                        f64::from_le_bytes(self.array()?)
+                    } else {
+                        self.text_score()?
+                    };
+                    zset.insert(member, score);
+                }
+                Value::SortedSet(zset)
+            }
+            TYPE_SET_INTSET => Value::Set(intset_members(&self.string()?)?.collect()),
+            TYPE_SET_LISTPACK => Value::Set(listpack_entries(&self.string()?)?.collect()),
+            TYPE_ZSET_LISTPACK => {
+                let entries: Vec<Bytes> = listpack_entries(&self.string()?)?.collect();
+                if !entries.len().is_multiple_of(2) {
+                    return Err(RdbError::Corrupt("odd sorted set listpack"));
+                }
+                let mut zset = SortedSet::new();
+                for pair in entries.chunks(2) {
+                    let score = std::str::from_utf8(&pair[1])
+                        .ok()
+                        .and_then(|s| s.parse().ok())
+                        .ok_or(RdbError::Corrupt("invalid score"))?;
+                    zset.insert(pair[0].clone(), score);
+                }
+                Value::SortedSet(zset)
+            }
+            other => return Err(RdbError::UnsupportedType(other)),
+        };
+        Ok(value)
+    }
+}
+
+/// Members of an intset blob: a little-endian header of element width and
+/// count, then the sorted integers.
+fn intset_members(blob: &[u8]) -> Result<impl Iterator<Item = Bytes> + '_, RdbError> {
+    let mut r = Reader { data: blob, pos: 0 };
+    let width = u32::from_le_bytes(r.array()?) as usize;
+    let count = u32::from_le_bytes(r.array()?) as usize;
+    if !matches!(width, 2 | 4 | 8) || blob.len() != 8 + width * count {
+        return Err(RdbError::Corrupt("invalid intset"));
+    }
+    Ok(blob[8..].chunks(width).map(|int| {
+        let value = match *int {
+            [a, b] => i16::from_le_bytes([a, b]) as i64,
+            [a, b, c, d] => i32::from_le_bytes([a, b, c, d]) as i64,
+            _ => i64::from_le_bytes(int.try_into().unwrap()),
+        };
+        value.to_string().into()
+    }))
+}
+
+/// Entries of a listpack blob, integers rendered as decimal strings.
+fn listpack_entries(blob: &[u8]) -> Result<impl Iterator<Item = Bytes>, RdbError> {
+    // Header: total bytes (u32) and element count (u16).
+    let mut r = Reader { data: blob, pos: 6 };
+    let mut entries = Vec::new();
+    loop {
+        let start = r.pos;
+        let b = r.byte()?;
+        if b == 0xFF {
+            break;
+        }
+        let entry: Bytes = if b & 0x80 == 0 {
+            (b as i64).to_string().into()
+        } else if b & 0xC0 == 0x80 {
+            Bytes::copy_from_slice(r.take((b & 0x3F) as usize)?)
+        } else if b & 0xE0 == 0xC0 {
+            let raw = ((b & 0x1F) as i64) << 8 | r.byte()? as i64;
+            // Sign-extend the 13-bit integer.
+            ((raw << 51) >> 51).to_string().into()
+        } else if b & 0xF0 == 0xE0 {
+            let len = ((b & 0x0F) as usize) << 8 | r.byte()? as usize;
+            Bytes::copy_from_slice(r.take(len)?)
+        } else {
+            match b {
+                0xF0 => {
+                    let len = u32::from_le_bytes(r.array()?) as usize;
+                    Bytes::copy_from_slice(r.take(len)?)
+                }
+                0xF1 => i16::from_le_bytes(r.array()?).to_string().into(),
+                0xF2 => {
+                    let [x, y, z] = r.array()?;
+                    (i32::from_le_bytes([0, x, y, z]) >> 8).to_string().into()
+                }
+                0xF3 => i32::from_le_bytes(r.array()?).to_string().into(),
+                0xF4 => i64::from_le_bytes(r.array()?).to_string().into(),
+                _ => return Err(RdbError::Corrupt("unknown listpack encoding")),
+            }
+        };
+        // Each entry ends with its own length, for walking backwards.
+        let len = r.pos - start;
+        let backlen = match len {
+            0..=127 => 1,
+            128..=16383 => 2,
+            16384..=2097151 => 3,
+            2097152..=268435455 => 4,
+            _ => 5,
+        };
+        r.take(backlen)?;
+        entries.push(entry);
+    }
+    if !r.is_done() {
+        return Err(RdbError::Corrupt("data after listpack end"));
+    }
+    Ok(entries.into_iter())
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, RdbError> {
+    let corrupt = || RdbError::Corrupt("invalid LZF data");
+    let mut out = Vec::with_capacity(len);
+    let mut r = Reader {
+        data: input,
+        pos: 0,
+    };
+    while !r.is_done() {
+        let ctrl = r.byte()? as usize;
+        if ctrl < 32 {
+            out.extend_from_slice(r.take(ctrl + 1)?);
+            continue;
+        }
+        let mut run = ctrl >> 5;
+        if run == 7 {
+            run += r.byte()? as usize;
+        }
+        let offset = ((ctrl & 0x1F) << 8 | r.byte()? as usize) + 1;
+        let start = out.len().checked_sub(offset).ok_or_else(corrupt)?;
+        // Back-references may overlap what they produce, so copy bytewise.
+        for i in 0..run + 2 {
+            out.push(out[start + i]);
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+/// CRC-64/Jones, reflected, as used by Redis.
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95AC_9329_AC4B_C9B5
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        crc = CRC64_TABLE[((crc ^ byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(databases: &[Vec<(Bytes, Value)>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write(
+            &mut out,
+            &[],
+            databases.iter().map(|db| db.iter().map(|(k, v)| (k, v))),
+        )
+        .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_scripts_round_trip() {
+        let scripts = [Bytes::from("return 1"), Bytes::from("return ARGV[1]")];
+        let entries = [(Bytes::from("k"), Value::String("v".into()))];
+        let mut data = Vec::new();
+        let databases = [entries.iter().map(|(k, v)| (k, v))];
+        write(&mut data, &scripts, databases).unwrap();
+        let dump = read(&data, 1).unwrap();
+        assert_eq!(dump.scripts, scripts);
+        assert_eq!(dump.databases, [entries.to_vec()]);
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut zset = SortedSet::new();
+        zset.insert("a".into(), 1.5);
+        zset.insert("b".into(), f64::NEG_INFINITY);
+        let entries = vec![
+            (
+                Bytes::from_static(b"bin\x00key"),
+                Value::String(vec![0xFF; 20000].into()),
+            ),
+            (
+                Bytes::from("set"),
+                Value::Set(["x".into(), "y".into()].into_iter().collect()),
+            ),
+            (Bytes::from("zset"), Value::SortedSet(zset)),
+            (
+                Bytes::from("zzlist"),
+                Value::List(["b".into(), "a".into(), "b".into()].into()),
+            ),
+        ];
+        let other = vec![(Bytes::from("other"), Value::String("x".into()))];
+        let data = encode(&[entries.clone(), vec![], other.clone()]);
+        assert!(data.starts_with(b"REDIS0009"));
+
+        let mut loaded = read(&data, 4).unwrap().databases;
+        loaded[0].sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(loaded, vec![entries, vec![], other, vec![]]);
+        assert!(matches!(
+            read(&data, 2),
+            Err(RdbError::DatabaseOutOfRange(2))
+        ));
+
+        let mut corrupted = data.clone();
+        corrupted[data.len() / 2] ^= 1;
+        assert!(matches!(
+            read(&corrupted, 4),
+            Err(RdbError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            read(&data[..data.len() - 3], 4),
+            Err(RdbError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let value = Value::List(["a".into(), "b".into()].into());
+        let payload = dump(&value);
+        assert_eq!(&payload[..7], b"\x01\x02\x01a\x01b\x09");
+        assert_eq!(undump(&payload).unwrap(), value);
+
+        let mut corrupted = payload.clone();
+        corrupted[3] = b'x';
+        assert!(matches!(undump(&corrupted), Err(RdbError::BadPayload)));
+        assert!(matches!(undump(b"\x00"), Err(RdbError::BadPayload)));
+        // A payload Redis 7.2 wrote: the string "hello", version 11.
+        let mut redis = b"\x00\x05hello\x0b\x00".to_vec();
+        redis.extend_from_slice(&crc64(0, &redis).to_le_bytes());
+        assert_eq!(undump(&redis).unwrap(), Value::String("hello".into()));
+    }
+
+    #[test]
+    fn test_read_compact_encodings() {
+        let mut data = b"REDIS0011".to_vec();
+        // A string holding an int8, and an LZF-compressed "aaaaaaaa".
+        data.extend_from_slice(b"\x00\x01i\xC0\xF6");
+        data.extend_from_slice(b"\x00\x03lzf\xC3\x04\x08\x00a\xA0\x00");
+        // An intset of 16-bit integers 1 and -2.
+        data.extend_from_slice(
+            b"\x0B\x06intset\x0C\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\xFE\xFF",
+        );
+        // A listpack sorted set: "m" scored 7 and "n" scored 300.
+        data.extend_from_slice(b"\x11\x02zs\x12\x12\x00\x00\x00\x04\x00");
+        data.extend_from_slice(b"\x81m\x02\x07\x01\x81n\x02\xC1\x2C\x02\xFF");
+        // A quicklist of a listpack node holding "x" and 5, then a plain node.
+        data.extend_from_slice(b"\x12\x01q\x02\x02\x0C\x0C\x00\x00\x00\x02\x00");
+        data.extend_from_slice(b"\x81x\x02\x05\x01\xFF\x01\x03big");
+        // Expired long ago, so it's skipped.
+        data.extend_from_slice(b"\xFC\x01\x00\x00\x00\x00\x00\x00\x00\x00\x04gone\x01v");
+        data.push(OPCODE_EOF);
+        data.extend_from_slice(&[0; 8]);
+
+        let all = read_all(&data).unwrap();
+        assert_eq!(all.len(), 6);
+        assert_eq!(all[5].expires_at, Some(1));
+
+        let mut loaded = read(&data, 1).unwrap().databases.remove(0);
+        loaded.sort_by(|a, b| a.0.cmp(&b.0));
+        let keys: Vec<&[u8]> = loaded.iter().map(|(k, _)| k.as_ref()).collect();
+        assert_eq!(keys, vec![&b"i"[..], b"intset", b"lzf", b"q", b"zs"]);
+        assert_eq!(loaded[0].1, Value::String("-10".into()));
+        assert_eq!(
+            loaded[1].1,
+            Value::Set(["1".into(), "-2".into()].into_iter().collect())
+        );
+        assert_eq!(loaded[2].1, Value::String("aaaaaaaa".into()));
+        assert_eq!(
+            loaded[3].1,
+            Value::List(["x".into(), "5".into(), "big".into()].into())
+        );
+        let Value::SortedSet(zset) = &loaded[4].1 else {
+            panic!("expected a sorted set");
+        };
+        assert_eq!(
+            (zset.score(b"m"), zset.score(b"n")),
+            (Some(7.0), Some(300.0))
+        );
+
+        let functions = b"REDIS0011\xF5\x04code\xFF".to_vec();
+        assert!(matches!(read_all(&functions), Err(RdbError::Functions)));
+    }
+}
@@ -0,0 +1,91 @@
+//! Replies to expensive reads, kept for a moment
+//!
+//! When many clients ask for the same intersection or range at once, as
+//! they do when a cache in front of the server expires, each of them would
+//! compute it again. With `result_cache.ttl_ms` set, the reply to a read
+//! that has a cache key (see [`crate::commands::Command::cache_key`]) is
+//! kept for that long, along with the version of every key it read. A
+//! later run of the same command in the same database gets the kept reply
+//! if none of those keys changed since, so a write invalidates it as soon
+//! as it's made; otherwise the command runs, and its reply replaces the
+//! entry.
+use super::ShardLocks;
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Replies by database and command arguments.
+#[derive(Default)]
+pub struct ResultCache {
+    entries: Mutex<HashMap<(usize, Vec<Bytes>), Entry>>,
+}
+
+struct Entry {
+    reply: RespValue,
+    /// Each key the command read, with its version then, as `WATCH` sees
+    /// it.
+    keys: Vec<(Bytes, u64)>,
+    expires: Instant,
+}
+
+impl ShardLocks<'_> {
+    /// Whether replies to reads are cached.
+    pub fn is_caching_results(&self) -> bool {
+        self.db.config().result_cache.ttl_ms > 0
+    }
+
+    /// The reply kept for `args` run in the selected database, if it's
+    /// still fresh and none of the keys it read changed since.
+    pub fn cached_reply(&self, args: &[Bytes]) -> Option<RespValue> {
+        let now = Instant::now();
+        let db = self.selected();
+        let entries = self.db.result_cache.entries.lock().unwrap();
+        let reply = match entries.get(&(db, args.to_vec())) {
+            Some(entry) if entry.expires > now && self.unchanged(&entry.keys) => {
+                Some(entry.reply.clone())
+            }
+            _ => None,
+        };
+        self.db.stats.record_result_cache(reply.is_some());
+        reply
+    }
+
+    /// Keeps `reply` to `args`, which read `keys`, for the next runs.
+    pub fn cache_reply(&self, args: Vec<Bytes>, keys: &[Bytes], reply: &RespValue) {
+        if matches!(reply, RespValue::Error(_)) {
+            return;
+        }
+        let versions = keys
+            .iter()
+            .map(|key| (key.clone(), self.shard(key).watch_version(key)))
+            .collect();
+        let (ttl, max_entries) = {
+            let config = self.db.config();
+            let config = &config.result_cache;
+            (Duration::from_millis(config.ttl_ms), config.max_entries)
+        };
+        let now = Instant::now();
+        let mut entries = self.db.result_cache.entries.lock().unwrap();
+        if entries.len() >= max_entries {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= max_entries {
+            return;
+        }
+        entries.insert(
+            (self.selected(), args),
+            Entry {
+                reply: reply.clone(),
+                keys: versions,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    fn unchanged(&self, keys: &[(Bytes, u64)]) -> bool {
+        keys.iter()
+            .all(|(key, version)| self.shard(key).watch_version(key) == *version)
+    }
+}
@@ -0,0 +1,330 @@
+//! SAVE/BGSAVE and the state reported in `INFO persistence`
+use super::{rdb, Db, ShardLocks, Shards};
+use log::{error, info};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// How long automatic saves wait after a failed background save before
+/// trying again, as in Redis.
+const BGSAVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum SaveError {
+    #[error("ERR Background save already in progress")]
+    InProgress,
+    #[error("ERR persistence is disabled")]
+    Disabled,
+    #[error("ERR saving to disk: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Outcome of past saves and progress of a running one. Shared with the
+/// background save task, which updates it when done.
+#[derive(Debug)]
+pub struct SaveState {
+    /// When the running background save started.
+    started: Option<Instant>,
+    /// `ShardLocks::changes` over every shard as of the last successful
+    /// save.
+    saved_changes: u64,
+    last_save: SystemTime,
+    last_bgsave_attempt: Option<SystemTime>,
+    last_bgsave_ok: bool,
+    last_bgsave_duration: Option<Duration>,
+    /// How long the last finished save took, in the foreground or not.
+    last_save_duration: Option<Duration>,
+}
+
+/// Save progress and timings, for the metrics endpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SaveStats {
+    pub changes_since_last_save: u64,
+    pub last_save: SystemTime,
+    pub last_save_duration: Option<Duration>,
+    pub bgsave_in_progress: bool,
+    pub last_bgsave_ok: bool,
+}
+
+impl Default for SaveState {
+    fn default() -> Self {
+        SaveState {
+            started: None,
+            saved_changes: 0,
+            // Like Redis, count what was loaded at startup as saved.
+            last_save: SystemTime::now(),
+            last_bgsave_attempt: None,
+            last_bgsave_ok: true,
+            last_bgsave_duration: None,
+            last_save_duration: None,
+        }
+    }
+}
+
+/// Saving needs every shard locked, for a consistent dataset and change
+/// count.
+impl ShardLocks<'_> {
+    /// Writes the dataset to the dump file, holding the locks throughout.
+    pub fn save_to_disk(&self) -> Result<(), SaveError> {
+        debug_assert!(self.is_complete());
+        let config = self.db.config();
+        if !config.persistence_enabled {
+            return Ok(());
+        }
+        if self.is_saving() {
+            return Err(SaveError::InProgress);
+        }
+        let started = Instant::now();
+        let databases: Vec<Vec<_>> = (0..self.db.databases())
+            .map(|index| {
+                self.iter()
+                    .flat_map(|shard| shard.database(index).iter())
+                    .collect()
+            })
+            .collect();
+        rdb::save(
+            &config.dbfilename,
+            &self.db.script_sources(),
+            databases.into_iter().map(Vec::into_iter),
+        )?;
+        let mut state = self.db.saves.lock().unwrap();
+        state.saved_changes = self.changes();
+        state.last_save = SystemTime::now();
+        state.last_save_duration = Some(started.elapsed());
+        Ok(())
+    }
+
+    /// Snapshots the dataset and writes it on a blocking thread, so clients
+    /// only wait for each shard's copy in turn (see [`super::Snapshot`]).
+    pub fn bgsave(&mut self) -> Result<(), SaveError> {
+        debug_assert!(self.is_complete());
+        if !self.db.config().persistence_enabled {
+            return Err(SaveError::Disabled);
+        }
+        let started = Instant::now();
+        {
+            let mut state = self.db.saves.lock().unwrap();
+            if state.started.is_some() {
+                return Err(SaveError::InProgress);
+            }
+            state.started = Some(started);
+            state.last_bgsave_attempt = Some(SystemTime::now());
+        }
+
+        let snapshot = self.begin_snapshot();
+        let changes = self.changes();
+        let path = self.db.config().dbfilename.clone();
+        let saves = self.db.saves.clone();
+        tokio::spawn(async move {
+            let scripts = snapshot.scripts();
+            let databases = snapshot.collect().await;
+            let result = tokio::task::spawn_blocking(move || {
+                rdb::save(
+                    &path,
+                    &scripts,
+                    databases.iter().map(|db| db.iter().map(|(k, v)| (k, v))),
+                )
+            })
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+            let mut state = saves.lock().unwrap();
+            state.started = None;
+            state.last_bgsave_duration = Some(started.elapsed());
+            state.last_save_duration = state.last_bgsave_duration;
+            state.last_bgsave_ok = result.is_ok();
+            match result {
+                Ok(()) => {
+                    state.saved_changes = changes;
+                    state.last_save = SystemTime::now();
+                    info!("Background saving terminated with success");
+                }
+                Err(e) => error!("Background saving failed: {}", e),
+            }
+        });
+        Ok(())
+    }
+
+    /// Writes since the last successful save.
+    pub fn dirty(&self) -> u64 {
+        self.changes() - self.db.saves.lock().unwrap().saved_changes
+    }
+
+    pub fn save_stats(&self) -> SaveStats {
+        let dirty = self.dirty();
+        let state = self.db.saves.lock().unwrap();
+        SaveStats {
+            changes_since_last_save: dirty,
+            last_save: state.last_save,
+            last_save_duration: state.last_save_duration,
+            bgsave_in_progress: state.started.is_some(),
+            last_bgsave_ok: state.last_bgsave_ok,
+        }
+    }
+
+    /// Whether a save rule matches at `now`.
+    pub fn autosave_due(&self, now: SystemTime) -> bool {
+        let config = self.db.config();
+        if !config.persistence_enabled || self.is_saving() {
+            return false;
+        }
+        let dirty = self.dirty();
+        let state = self.db.saves.lock().unwrap();
+        let since = |at: SystemTime| now.duration_since(at).unwrap_or_default();
+        let retrying = !state.last_bgsave_ok
+            && state
+                .last_bgsave_attempt
+                .is_some_and(|at| since(at) < BGSAVE_RETRY_DELAY);
+        !retrying
+            && config.save_rules.iter().any(|rule| {
+                dirty >= rule.changes && since(state.last_save).as_secs() >= rule.seconds
+            })
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.db.is_saving()
+    }
+
+    /// Formats the `# Persistence` section of an `INFO` reply.
+    pub fn persistence_info(&self) -> String {
+        let state = self.db.saves.lock().unwrap();
+        let seconds = |d: Option<Duration>| d.map_or(-1, |d| d.as_secs() as i64);
+        format!(
+            "# Persistence\r\n\
+            persistence_enabled:{}\r\n\
+            rdb_changes_since_last_save:{}\r\n\
+            rdb_bgsave_in_progress:{}\r\n\
+            rdb_last_save_time:{}\r\n\
+            rdb_last_bgsave_status:{}\r\n\
+            rdb_last_bgsave_time_sec:{}\r\n\
+            rdb_current_bgsave_time_sec:{}\r\n\
+            aof_enabled:{}\r\n\
+            aof_rewrite_in_progress:{}\r\n",
+            self.db.config().persistence_enabled,
+            self.changes() - state.saved_changes,
+            state.started.is_some() as u8,
+            state
+                .last_save
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            if state.last_bgsave_ok { "ok" } else { "err" },
+            seconds(state.last_bgsave_duration),
+            seconds(state.started.map(|at| at.elapsed())),
+            self.is_logging(),
+            self.is_aof_rewriting(),
+        )
+    }
+}
+
+impl Shards {
+    pub fn is_saving(&self) -> bool {
+        self.saves.lock().unwrap().started.is_some()
+    }
+}
+
+/// Background task starting a `BGSAVE` whenever a save rule matches.
+pub async fn run_autosave(db: Db) {
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        let mut store = db.lock_all().await;
+        if store.autosave_due(SystemTime::now()) {
+            info!("{} changes since the last save, saving", store.dirty());
+            if let Err(e) = store.bgsave() {
+                error!("Automatic save failed to start: {}", e);
+            }
+        }
+    }
+}
+
+/// Saves the dataset and flushes the append-only file before exit, after
+/// letting a running background save finish. `save` overrides whether
+/// snapshots are configured, as `SHUTDOWN SAVE` and `NOSAVE` do.
+pub async fn save_on_shutdown(db: &Db, save: Option<bool>) {
+    while db.is_saving() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let store = db.lock_all().await;
+    if save.unwrap_or(db.config().persistence_enabled) {
+        match store.save_to_disk() {
+            Ok(()) => info!("DB saved on disk"),
+            Err(e) => error!("Failed to save on shutdown: {}", e),
+        }
+    }
+    if let Err(e) = store.sync_aof() {
+        error!("Failed to fsync the append only file: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{SaveRule, StorageConfig};
+    use crate::storage::Value;
+
+    #[tokio::test]
+    async fn test_bgsave_snapshots_dataset() {
+        let path = std::env::temp_dir().join(format!("rdb-{}-bgsave.rdb", std::process::id()));
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024 * 1024,
+            persistence_enabled: true,
+            dbfilename: path.clone(),
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        store.shard_mut(b"k").insert("k".into(), "v".into());
+        assert!(store
+            .persistence_info()
+            .contains("rdb_changes_since_last_save:1\r\n"));
+
+        store.bgsave().unwrap();
+        assert!(matches!(store.bgsave(), Err(SaveError::InProgress)));
+        // Not part of the snapshot, so it's still unsaved afterwards.
+        store.shard_mut(b"late").insert("late".into(), "v".into());
+        store.shard_mut(b"k").insert("k".into(), "changed".into());
+        drop(store);
+        while db.is_saving() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let store = db.lock_all().await;
+
+        let info = store.persistence_info();
+        assert!(info.contains("rdb_changes_since_last_save:2\r\n"));
+        assert!(info.contains("rdb_last_bgsave_status:ok\r\n"));
+        let saved = rdb::read(&std::fs::read(&path).unwrap(), 16)
+            .unwrap()
+            .databases;
+        assert_eq!(saved[0].len(), 1);
+        assert_eq!(saved[0][0].0, "k");
+        assert!(matches!(&saved[0][0].1, Value::String(v) if v == "v"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_autosave_rules() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024 * 1024,
+            persistence_enabled: true,
+            save_rules: vec![SaveRule {
+                seconds: 10,
+                changes: 2,
+            }],
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        let later = |secs| SystemTime::now() + Duration::from_secs(secs);
+        store.shard_mut(b"a").insert("a".into(), "1".into());
+        assert!(!store.autosave_due(later(20)));
+        store.shard_mut(b"b").insert("b".into(), "1".into());
+        assert_eq!(store.dirty(), 2);
+        assert!(!store.autosave_due(later(5)));
+        assert!(store.autosave_due(later(20)));
+
+        // A failed save is retried only after a delay.
+        {
+            let mut state = db.saves.lock().unwrap();
+            state.last_bgsave_ok = false;
+            state.last_bgsave_attempt = Some(later(18));
+        }
+        assert!(!store.autosave_due(later(20)));
+        assert!(store.autosave_due(later(25)));
+    }
+}
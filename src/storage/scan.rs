@@ -0,0 +1,172 @@
+//! Walking the keyspace: `KEYS`, `SCAN`, `TYPE` and `RANDOMKEY`
+use super::shards::key_hash;
+use super::{Deadline, ShardLocks, Storage, StorageError, Value};
+use crate::glob;
+use bytes::Bytes;
+
+/// Low bits of a `SCAN` cursor: the position within a shard. The bits above
+/// hold the shard's index.
+const POSITION_BITS: u32 = 48;
+
+/// Where `key` sorts within its shard. Positions come from the key's hash,
+/// so they don't move as other keys come and go.
+fn position(key: &[u8]) -> u64 {
+    key_hash(key) >> (64 - POSITION_BITS)
+}
+
+/// The shard a `SCAN` cursor points into.
+pub fn cursor_shard(cursor: u64) -> usize {
+    (cursor >> POSITION_BITS) as usize
+}
+
+impl Storage {
+    /// Type name of the value at `key`, as `TYPE` reports it.
+    pub fn key_type(&self, key: &[u8]) -> &'static str {
+        self.data.get(key).map_or("none", Value::type_name)
+    }
+
+    /// Up to `count` keys from position `from` on, in position order, and
+    /// the position to continue from, or `None` once past the last key.
+    pub(super) fn scan(&self, from: u64, count: usize) -> (Vec<&Bytes>, Option<u64>) {
+        let mut page: Vec<(u64, &Bytes)> = self
+            .live_keys()
+            .map(|key| (position(key), key))
+            .filter(|(at, _)| *at >= from)
+            .collect();
+        if page.len() <= count {
+            return (page.into_iter().map(|(_, key)| key).collect(), None);
+        }
+        page.select_nth_unstable_by_key(count, |(at, _)| *at);
+        // The page ends before the first position left out, so keys sharing
+        // a position are returned together.
+        let mut next = page[count].0;
+        if page[..count].iter().all(|(at, _)| *at == next) {
+            next += 1;
+        }
+        let keys = page
+            .into_iter()
+            .filter(|(at, _)| *at < next)
+            .map(|(_, key)| key)
+            .collect();
+        (keys, Some(next))
+    }
+}
+
+impl ShardLocks<'_> {
+    /// One `SCAN` step over the shard `cursor` points into, which must be
+    /// locked: keys matching `pattern` among the next `count`, and the
+    /// cursor to continue from, 0 once every shard is done.
+    ///
+    /// A key present for the whole scan is returned at least once; keys that
+    /// come or go during it may or may not be.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        pattern: Option<&[u8]>,
+        count: usize,
+    ) -> Result<(u64, Vec<Bytes>), StorageError> {
+        let index = cursor_shard(cursor);
+        let shard = self
+            .guards
+            .get(index)
+            .ok_or(StorageError::InvalidCursor)?
+            .as_deref()
+            .expect("the shard of the cursor is locked");
+        let from = cursor & ((1 << POSITION_BITS) - 1);
+        let (keys, next) = shard.scan(from, count);
+        let keys = keys
+            .into_iter()
+            .filter(|key| match pattern {
+                Some(pattern) => glob::matches(pattern, key),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        let next = match next {
+            Some(at) => (index as u64) << POSITION_BITS | at,
+            None if index + 1 < self.db.count() => ((index + 1) as u64) << POSITION_BITS,
+            None => 0,
+        };
+        Ok((next, keys))
+    }
+
+    /// Keys in the locked shards matching `pattern`.
+    pub fn keys(&self, pattern: &[u8], deadline: &Deadline) -> Result<Vec<Bytes>, StorageError> {
+        deadline.collect(
+            self.iter()
+                .flat_map(|shard| shard.live_keys())
+                .filter(|key| glob::matches(pattern, key))
+                .cloned(),
+        )
+    }
+
+    /// A key picked uniformly at random from the locked shards.
+    pub fn random_key(&self) -> Option<Bytes> {
+        let total: usize = self.iter().map(Storage::live_count).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut seed = [0u8; 8];
+        getrandom::getrandom(&mut seed).expect("failed to draw a random key");
+        let mut nth = (u64::from_le_bytes(seed) % total as u64) as usize;
+        for shard in self.iter() {
+            match shard.live_keys().nth(nth) {
+                Some(key) => return Some(key.clone()),
+                None => nth -= shard.live_count(),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+    use std::collections::HashSet;
+
+    #[tokio::test]
+    async fn test_scan_visits_every_key() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1024 * 1024,
+            shards: 4,
+            ..Default::default()
+        });
+        let keys: HashSet<Bytes> = (0..100).map(|i| format!("key:{}", i).into()).collect();
+        {
+            let mut store = db.lock_all().await;
+            for key in &keys {
+                store.shard_mut(key).insert(key.clone(), "v".into());
+            }
+        }
+
+        let (mut cursor, mut seen, mut calls) = (0, HashSet::new(), 0);
+        loop {
+            let store = db.lock_shard(cursor_shard(cursor)).await;
+            let (next, page) = store.scan(cursor, None, 10).unwrap();
+            assert!(page.len() <= 10);
+            seen.extend(page);
+            calls += 1;
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen, keys);
+        assert!(calls >= 10);
+
+        let store = db.lock_all().await;
+        let (_, page) = store.scan(0, Some(b"key:1?"), 1000).unwrap();
+        assert!(page.iter().all(|key| glob::matches("key:1?", key)));
+        assert_eq!(
+            store.keys(b"key:1?", &Deadline::after(None)).unwrap().len(),
+            10
+        );
+        assert!(keys.contains(&store.random_key().unwrap()));
+        assert_eq!(
+            store.scan(u64::MAX, None, 10),
+            Err(StorageError::InvalidCursor)
+        );
+    }
+}
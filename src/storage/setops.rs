@@ -0,0 +1,426 @@
+//! Set algebra: SINTER/SUNION and the STORE variants for sets and sorted sets
+use super::{Deadline, ShardLocks, SortedSet, Storage, StorageError, Value, SCORE_SIZE};
+use bytes::Bytes;
+use indexmap::IndexSet;
+use std::collections::HashSet;
+
+/// How `ZUNIONSTORE`/`ZINTERSTORE` combine the scores of a member present in
+/// several inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Aggregate {
+    #[default]
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, a: f64, b: f64) -> f64 {
+        let score = match self {
+            Aggregate::Sum => a + b,
+            Aggregate::Min => a.min(b),
+            Aggregate::Max => a.max(b),
+        };
+        // inf + -inf has no meaningful value; Redis settles on 0.
+        if score.is_nan() {
+            0.0
+        } else {
+            score
+        }
+    }
+}
+
+/// Input of a sorted set operation. Plain sets take part with every member
+/// scored 1.
+#[derive(Clone, Copy)]
+enum Scored<'a> {
+    Set(&'a IndexSet<Bytes>),
+    SortedSet(&'a SortedSet),
+}
+
+impl<'a> Scored<'a> {
+    fn len(&self) -> usize {
+        match self {
+            Scored::Set(set) => set.len(),
+            Scored::SortedSet(zset) => zset.len(),
+        }
+    }
+
+    fn score(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Scored::Set(set) => set.contains(member).then_some(1.0),
+            Scored::SortedSet(zset) => zset.score(member),
+        }
+    }
+
+    fn iter(self) -> Box<dyn Iterator<Item = (&'a Bytes, f64)> + 'a> {
+        match self {
+            Scored::Set(set) => Box::new(set.iter().map(|m| (m, 1.0))),
+            Scored::SortedSet(zset) => Box::new(zset.iter()),
+        }
+    }
+}
+
+/// Multiplies a score by its input's weight; `0 * inf` counts as 0.
+fn weigh(score: f64, weight: f64) -> f64 {
+    let weighted = score * weight;
+    if weighted.is_nan() {
+        0.0
+    } else {
+        weighted
+    }
+}
+
+/// The sources may live in other shards than the destination; all of them
+/// must be locked.
+impl ShardLocks<'_> {
+    pub fn sinter(&self, keys: &[Bytes], deadline: &Deadline) -> Result<Vec<&Bytes>, StorageError> {
+        let sets = self.sets(keys)?;
+        deadline.collect(intersection(sets))
+    }
+
+    pub fn sunion(&self, keys: &[Bytes], deadline: &Deadline) -> Result<Vec<&Bytes>, StorageError> {
+        let sets = self.sets(keys)?;
+        let mut seen = HashSet::new();
+        let members = sets.into_iter().flatten().flatten();
+        deadline.collect(members.filter(|m| seen.insert(*m)))
+    }
+
+    /// Stores the intersection of `keys` at `dest`, returning its size.
+    pub fn sinterstore(
+        &mut self,
+        dest: &[u8],
+        keys: &[Bytes],
+        deadline: &Deadline,
+    ) -> Result<usize, StorageError> {
+        let sets = self.sets(keys)?;
+        let (result, size) = self.build_set(dest, intersection(sets), deadline)?;
+        self.shard_mut(dest).store(dest, result, size)
+    }
+
+    /// Stores the union of `keys` at `dest`, returning its size.
+    pub fn sunionstore(
+        &mut self,
+        dest: &[u8],
+        keys: &[Bytes],
+        deadline: &Deadline,
+    ) -> Result<usize, StorageError> {
+        let sets = self.sets(keys)?;
+        let members = sets.into_iter().flatten().flatten();
+        let (result, size) = self.build_set(dest, members, deadline)?;
+        self.shard_mut(dest).store(dest, result, size)
+    }
+
+    /// Stores the weighted union of the sets and sorted sets at `keys` as a
+    /// sorted set at `dest`, returning its size.
+    pub fn zunionstore(
+        &mut self,
+        dest: &[u8],
+        keys: &[Bytes],
+        weights: &[f64],
+        aggregate: Aggregate,
+        deadline: &Deadline,
+    ) -> Result<usize, StorageError> {
+        let inputs = self.scored(keys)?;
+        let mut result = SortedSet::new();
+        let mut size = 0;
+        let mut processed = 0;
+        for (input, weight) in inputs.into_iter().zip(weights) {
+            for (member, score) in input.into_iter().flat_map(Scored::iter) {
+                deadline.check_every(processed)?;
+                processed += 1;
+                let score = weigh(score, *weight);
+                match result.score(member) {
+                    Some(old) => {
+                        result.insert(member.clone(), aggregate.apply(old, score));
+                    }
+                    None => {
+                        let grows = member.len() + SCORE_SIZE;
+                        self.shard(dest).reserve(dest.len() + size + grows)?;
+                        result.insert(member.clone(), score);
+                        size += grows;
+                    }
+                }
+            }
+        }
+        self.shard_mut(dest)
+            .store(dest, Value::SortedSet(result), size)
+    }
+
+    /// Stores the weighted intersection of the sets and sorted sets at `keys`
+    /// as a sorted set at `dest`, returning its size.
+    pub fn zinterstore(
+        &mut self,
+        dest: &[u8],
+        keys: &[Bytes],
+        weights: &[f64],
+        aggregate: Aggregate,
+        deadline: &Deadline,
+    ) -> Result<usize, StorageError> {
+        let inputs = self.scored(keys)?;
+        let mut result = SortedSet::new();
+        let mut size = 0;
+        // A missing key is an empty input, which empties the intersection.
+        if let Some(inputs) = inputs.into_iter().collect::<Option<Vec<_>>>() {
+            let mut order: Vec<usize> = (0..inputs.len()).collect();
+            order.sort_by_key(|&i| inputs[i].len());
+            let (first, rest) = order.split_first().expect("at least one key");
+
+            for (i, (member, score)) in inputs[*first].iter().enumerate() {
+                deadline.check_every(i)?;
+                let mut total = weigh(score, weights[*first]);
+                let mut in_all = true;
+                for &j in rest {
+                    match inputs[j].score(member) {
+                        Some(other) => total = aggregate.apply(total, weigh(other, weights[j])),
+                        None => {
+                            in_all = false;
+                            break;
+                        }
+                    }
+                }
+                if in_all {
+                    let grows = member.len() + SCORE_SIZE;
+                    self.shard(dest).reserve(dest.len() + size + grows)?;
+                    result.insert(member.clone(), total);
+                    size += grows;
+                }
+            }
+        }
+        self.shard_mut(dest)
+            .store(dest, Value::SortedSet(result), size)
+    }
+
+    /// The sets at `keys`, with `None` for missing keys.
+    fn sets(&self, keys: &[Bytes]) -> Result<Vec<Option<&IndexSet<Bytes>>>, StorageError> {
+        keys.iter()
+            .map(|key| match self.shard(key).data.get(key) {
+                Some(Value::Set(set)) => Ok(Some(set)),
+                Some(_) => Err(StorageError::WrongType),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn scored(&self, keys: &[Bytes]) -> Result<Vec<Option<Scored<'_>>>, StorageError> {
+        keys.iter()
+            .map(|key| match self.shard(key).data.get(key) {
+                Some(Value::Set(set)) => Ok(Some(Scored::Set(set))),
+                Some(Value::SortedSet(zset)) => Ok(Some(Scored::SortedSet(zset))),
+                Some(_) => Err(StorageError::WrongType),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    /// Collects `members` into a new set for `dest`, accounting each new
+    /// member against `max_memory` as it goes so an oversized result fails
+    /// part way instead of after being built in full. The sources are still
+    /// live while the result grows, so both count.
+    fn build_set<'a>(
+        &self,
+        dest: &[u8],
+        members: impl Iterator<Item = &'a Bytes>,
+        deadline: &Deadline,
+    ) -> Result<(Value, usize), StorageError> {
+        let mut result = IndexSet::new();
+        let mut size = 0;
+        for (i, member) in members.enumerate() {
+            deadline.check_every(i)?;
+            if result.contains(member) {
+                continue;
+            }
+            self.shard(dest).reserve(dest.len() + size + member.len())?;
+            result.insert(member.clone());
+            size += member.len();
+        }
+        Ok((Value::Set(result), size))
+    }
+}
+
+impl Storage {
+    /// Replaces `dest` with a freshly built collection of `size` accounted
+    /// bytes, or deletes it if the collection is empty. Returns its length.
+    fn store(&mut self, dest: &[u8], value: Value, size: usize) -> Result<usize, StorageError> {
+        let len = match &value {
+            Value::Set(set) => set.len(),
+            Value::SortedSet(zset) => zset.len(),
+            Value::List(list) => list.len(),
+            Value::String(_) => 1,
+        };
+        if len > 0 {
+            let old_size = self.data.get(dest).map_or(0, |old| dest.len() + old.size());
+            self.make_room((dest.len() + size).saturating_sub(old_size), dest)?;
+        }
+        self.delete(dest);
+        if len > 0 {
+            self.account(dest, dest.len() + size);
+            self.touch(dest);
+            let dest = self.slab.copy(dest);
+            self.data.insert(dest, value);
+        }
+        Ok(len)
+    }
+}
+
+/// Members of every set, walking the smallest and probing the rest. Any
+/// missing set makes the intersection empty.
+fn intersection(sets: Vec<Option<&IndexSet<Bytes>>>) -> impl Iterator<Item = &Bytes> {
+    let mut sets = sets
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_default();
+    sets.sort_by_key(|set| set.len());
+    let smallest = (!sets.is_empty()).then(|| sets.remove(0));
+    smallest
+        .into_iter()
+        .flatten()
+        .filter(move |member| sets.iter().all(|set| set.contains(*member)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::Shards;
+
+    /// Spread over several shards, so sources and destinations mostly live
+    /// in different ones.
+    fn shards(max_memory: usize, shards: usize) -> Shards {
+        Shards::new(StorageConfig {
+            max_memory,
+            shards,
+            persistence_enabled: false,
+            ..Default::default()
+        })
+    }
+
+    fn keys(names: &[&'static str]) -> Vec<Bytes> {
+        names
+            .iter()
+            .map(|name| Bytes::from_static(name.as_bytes()))
+            .collect()
+    }
+
+    fn sadd(store: &mut ShardLocks, key: &'static str, members: &[&'static str]) {
+        let key = key.as_bytes();
+        store.shard_mut(key).sadd(key, keys(members)).unwrap();
+    }
+
+    fn sorted(mut members: Vec<&Bytes>) -> Vec<&[u8]> {
+        members.sort();
+        members.into_iter().map(|m| m.as_ref()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_sinter_sunion() {
+        let db = shards(1024, 8);
+        let mut store = db.lock_all().await;
+        let deadline = Deadline::after(None);
+        sadd(&mut store, "a", &["1", "2", "3"]);
+        sadd(&mut store, "b", &["2", "3", "4"]);
+
+        let inter = store.sinter(&keys(&["a", "b"]), &deadline).unwrap();
+        assert_eq!(sorted(inter), vec![&b"2"[..], b"3"]);
+        let union = store
+            .sunion(&keys(&["a", "b", "missing"]), &deadline)
+            .unwrap();
+        assert_eq!(sorted(union), vec![&b"1"[..], b"2", b"3", b"4"]);
+        assert!(store
+            .sinter(&keys(&["a", "missing"]), &deadline)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_variants_account_memory() {
+        let db = shards(1024, 8);
+        let mut store = db.lock_all().await;
+        let deadline = Deadline::after(None);
+        sadd(&mut store, "a", &["1", "2", "3"]);
+        sadd(&mut store, "b", &["2", "3", "4"]);
+        let before = store.memory_usage();
+
+        assert_eq!(
+            store.sunionstore(b"d", &keys(&["a", "b"]), &deadline),
+            Ok(4)
+        );
+        assert_eq!(store.memory_usage(), before + 1 + 4);
+        // Overwriting releases the old destination.
+        assert_eq!(
+            store.sinterstore(b"d", &keys(&["a", "b"]), &deadline),
+            Ok(2)
+        );
+        assert_eq!(store.memory_usage(), before + 1 + 2);
+        // An empty result deletes the destination.
+        assert_eq!(
+            store.sinterstore(b"d", &keys(&["a", "x"]), &deadline),
+            Ok(0)
+        );
+        assert_eq!(store.memory_usage(), before);
+        assert!(store
+            .shard(b"d")
+            .smembers(b"d", &deadline)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_store_fails_mid_operation_when_out_of_memory() {
+        let db = shards(12, 1);
+        let mut store = db.lock_all().await;
+        let deadline = Deadline::after(None);
+        sadd(&mut store, "a", &["1", "2", "3", "4", "5"]);
+        sadd(&mut store, "d", &["x"]);
+        let before = store.memory_usage();
+
+        assert_eq!(
+            store.sunionstore(b"d", &keys(&["a"]), &deadline),
+            Err(StorageError::OutOfMemory)
+        );
+        // The destination is untouched.
+        assert_eq!(store.memory_usage(), before);
+        assert!(store.shard(b"d").sismember(b"d", b"x").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_zunionstore_zinterstore() {
+        let db = shards(1024, 8);
+        let mut store = db.lock_all().await;
+        let deadline = Deadline::after(None);
+        store
+            .shard_mut(b"z")
+            .zadd(b"z", vec![(1.0, "a".into()), (2.0, "b".into())])
+            .unwrap();
+        sadd(&mut store, "s", &["b", "c"]);
+
+        let union = store.zunionstore(
+            b"u",
+            &keys(&["z", "s"]),
+            &[2.0, 1.0],
+            Aggregate::Sum,
+            &deadline,
+        );
+        assert_eq!(union, Ok(3));
+        let u = store.shard(b"u");
+        assert_eq!(u.zscore(b"u", b"a"), Ok(Some(2.0)));
+        assert_eq!(u.zscore(b"u", b"b"), Ok(Some(5.0)));
+        assert_eq!(u.zscore(b"u", b"c"), Ok(Some(1.0)));
+
+        let inter = store.zinterstore(
+            b"i",
+            &keys(&["z", "s"]),
+            &[1.0, 1.0],
+            Aggregate::Min,
+            &deadline,
+        );
+        assert_eq!(inter, Ok(1));
+        assert_eq!(store.shard(b"i").zscore(b"i", b"b"), Ok(Some(1.0)));
+
+        store.shard_mut(b"str").insert("str".into(), "v".into());
+        assert_eq!(
+            store.zunionstore(b"u", &keys(&["str"]), &[1.0], Aggregate::Sum, &deadline),
+            Err(StorageError::WrongType)
+        );
+    }
+}
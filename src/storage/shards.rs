@@ -0,0 +1,673 @@
+//! The keyspace split into independently locked shards
+use super::evict::HookSlot;
+use super::hotkeys::HotKeys;
+use super::lockstats::LockStats;
+use super::result_cache::ResultCache;
+use super::save::SaveState;
+use super::{rdb, RdbError, SlabStats, Storage, Value, Waiters};
+use crate::aof::{Aof, AofError};
+use crate::changefeed::ChangeFeed;
+use crate::config::{MaxMemoryPolicy, QuotaConfig, StorageConfig};
+use crate::monitor::Monitor;
+use crate::protocol::RespValue;
+use crate::pubsub::{Broker, EventClass};
+use crate::replication::Replication;
+#[cfg(feature = "scripting")]
+use crate::scripting::ScriptCache;
+use crate::stats::Stats;
+use bytes::Bytes;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::AtomicU8;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::Instant;
+use tokio::sync;
+
+/// Every key lives in the shard its hash picks, and each shard is a
+/// `Storage` of its own: its own lock, memory accounting and eviction.
+/// Commands lock just the shards owning their keys, so commands on
+/// different keys run in parallel, and reads share their locks, so reads
+/// of the same keys do too.
+pub struct Shards {
+    /// Shared with the snapshots copying them.
+    pub(super) shards: Arc<[sync::RwLock<Storage>]>,
+    /// Read by everything consulting the storage settings, and changed by
+    /// `CONFIG SET`.
+    config: RwLock<StorageConfig>,
+    pub(super) saves: Arc<std::sync::Mutex<SaveState>>,
+    changefeed: ChangeFeed,
+    monitor: Monitor,
+    waiters: Waiters,
+    broker: Arc<Broker>,
+    #[cfg(feature = "scripting")]
+    scripts: ScriptCache,
+    pub(super) result_cache: ResultCache,
+    pub(super) hot: Arc<HotKeys>,
+    pub(super) eviction_hook: HookSlot,
+    /// The memory alarm in effect, in percent; 0 for none.
+    pub(super) memory_alarm: AtomicU8,
+    pub(super) stats: Arc<Stats>,
+    lock_stats: LockStats,
+    /// Serving a snapshot: writes are refused and nothing is saved.
+    read_only: bool,
+}
+
+impl Shards {
+    pub fn new(config: StorageConfig) -> Self {
+        let count = config.shards.max(1);
+        let stats = Arc::new(Stats::default());
+        let hot = Arc::new(HotKeys::new());
+        let eviction_hook = HookSlot::default();
+        let shards = (0..count)
+            .map(|i| {
+                let mut shard = Storage::new(StorageConfig {
+                    max_memory: memory_budget(config.max_memory, count, i),
+                    quotas: config
+                        .quotas
+                        .iter()
+                        .map(|quota| QuotaConfig {
+                            max_memory: memory_budget(quota.max_memory, count, i),
+                            ..quota.clone()
+                        })
+                        .collect(),
+                    ..config.clone()
+                });
+                shard.stats = stats.clone();
+                shard.hot = hot.clone();
+                shard.eviction_hook = eviction_hook.clone();
+                sync::RwLock::new(shard)
+            })
+            .collect();
+        let broker = Arc::new(Broker::with_history(config.pubsub_history.clone()));
+        Shards {
+            shards,
+            config: RwLock::new(config),
+            saves: Arc::default(),
+            changefeed: ChangeFeed::default(),
+            monitor: Monitor::default(),
+            waiters: Waiters::default(),
+            broker,
+            #[cfg(feature = "scripting")]
+            scripts: ScriptCache::default(),
+            result_cache: ResultCache::default(),
+            hot,
+            eviction_hook,
+            memory_alarm: AtomicU8::new(0),
+            stats,
+            lock_stats: LockStats::new(count),
+            read_only: false,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Number of databases, each spread over every shard.
+    pub fn databases(&self) -> usize {
+        self.config().databases.max(1)
+    }
+
+    /// The storage settings as they are now.
+    pub fn config(&self) -> RwLockReadGuard<'_, StorageConfig> {
+        self.config.read().unwrap()
+    }
+
+    /// Every write, for `CDC TAIL`.
+    pub fn changefeed(&self) -> &ChangeFeed {
+        &self.changefeed
+    }
+
+    /// Every command run, for `MONITOR`.
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    /// Clients blocked on lists.
+    pub fn waiters(&self) -> &Waiters {
+        &self.waiters
+    }
+
+    /// Pub/sub channels, which keyspace notifications are published on too.
+    pub fn broker(&self) -> &Arc<Broker> {
+        &self.broker
+    }
+
+    /// Scripts loaded for `EVALSHA`.
+    #[cfg(feature = "scripting")]
+    pub fn scripts(&self) -> &ScriptCache {
+        &self.scripts
+    }
+
+    /// The source of every script cached, for dumps and replicas to keep.
+    #[cfg(feature = "scripting")]
+    pub fn script_sources(&self) -> Vec<Bytes> {
+        self.scripts.sources()
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    pub fn script_sources(&self) -> Vec<Bytes> {
+        Vec::new()
+    }
+
+    /// Caches the scripts a dump held. Builds without `scripting` drop them.
+    #[cfg(feature = "scripting")]
+    pub fn load_scripts(&self, sources: Vec<Bytes>) {
+        for source in sources {
+            if crate::scripting::compile(&source).is_ok() {
+                self.scripts.load(source);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "scripting"))]
+    pub fn load_scripts(&self, _sources: Vec<Bytes>) {}
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Commands' waits for each shard's lock, and holds of it.
+    pub fn lock_stats(&self) -> &LockStats {
+        &self.lock_stats
+    }
+
+    /// Refuses writes from here on, for serving a snapshot as it is.
+    /// Persistence has to be off in the config as well.
+    pub fn make_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Index of the shard owning `key`.
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        (key_hash(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Locks the shards owning `keys`.
+    pub async fn lock(&self, keys: &[Bytes]) -> ShardLocks<'_> {
+        let mut wanted = vec![false; self.shards.len()];
+        for key in keys {
+            wanted[self.shard_of(key)] = true;
+        }
+        self.lock_where(|i| wanted[i]).await
+    }
+
+    pub async fn lock_all(&self) -> ShardLocks<'_> {
+        self.lock_where(|_| true).await
+    }
+
+    /// Locks the shard at `index` alone.
+    pub async fn lock_shard(&self, index: usize) -> ShardLocks<'_> {
+        self.lock_where(|i| i == index).await
+    }
+
+    /// Takes the locks in index order, so two commands locking overlapping
+    /// shards can't deadlock. The locks start out on database 0.
+    async fn lock_where(&self, wanted: impl Fn(usize) -> bool) -> ShardLocks<'_> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for (i, shard) in self.shards.iter().enumerate() {
+            guards.push(match wanted(i) {
+                true => {
+                    let asked = Instant::now();
+                    let mut guard = shard.write().await;
+                    self.lock_stats.shard(i).wait.record(asked.elapsed());
+                    guard.select(0);
+                    Some(Guard::Exclusive(guard))
+                }
+                false => None,
+            });
+        }
+        ShardLocks {
+            db: self,
+            guards,
+            locked_at: Instant::now(),
+        }
+    }
+
+    /// Locks the shards owning `keys`, or every shard if there are none,
+    /// for reading alongside other readers, in index order like
+    /// [`Shards::lock`]. `None` if one of them has another database than
+    /// `index` selected: switching takes an exclusive lock.
+    pub async fn lock_shared(&self, keys: &[Bytes], index: usize) -> Option<ShardLocks<'_>> {
+        let mut wanted = vec![keys.is_empty(); self.shards.len()];
+        for key in keys {
+            wanted[self.shard_of(key)] = true;
+        }
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for (i, (shard, wanted)) in self.shards.iter().zip(wanted).enumerate() {
+            guards.push(match wanted {
+                true => {
+                    let asked = Instant::now();
+                    let guard = shard.read().await;
+                    self.lock_stats.shard(i).wait.record(asked.elapsed());
+                    if guard.selected() != index {
+                        return None;
+                    }
+                    Some(Guard::Shared(guard))
+                }
+                false => None,
+            });
+        }
+        Some(ShardLocks {
+            db: self,
+            guards,
+            locked_at: Instant::now(),
+        })
+    }
+
+    /// Whether reads can share their locks: accesses aren't tracked for
+    /// eviction, which changes the shard.
+    pub fn shares_reads(&self) -> bool {
+        let config = self.config();
+        config.shared_reads
+            && !matches!(
+                config.maxmemory_policy,
+                MaxMemoryPolicy::AllKeysLru | MaxMemoryPolicy::AllKeysRandom
+            )
+    }
+
+    /// Starts logging writes to `aof`. Only before the server is shared.
+    pub fn attach_aof(&mut self, aof: Arc<Aof>) {
+        for shard in self.unshared().iter_mut() {
+            shard.get_mut().attach_aof(aof.clone());
+        }
+    }
+
+    /// Streams writes to replicas through `replication`. Only before the
+    /// server is shared.
+    pub fn attach_replication(&mut self, replication: Arc<Replication>) {
+        for shard in self.unshared().iter_mut() {
+            shard.get_mut().attach_replication(replication.clone());
+        }
+    }
+
+    fn unshared(&mut self) -> &mut [sync::RwLock<Storage>] {
+        Arc::get_mut(&mut self.shards).expect("no snapshot shares the shards yet")
+    }
+}
+
+/// The hash picking a key's shard; stable across restarts.
+pub(super) fn key_hash(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// A shard's lock as held: shared by reads, exclusive for everything else.
+pub(super) enum Guard<'a> {
+    Shared(sync::RwLockReadGuard<'a, Storage>),
+    Exclusive(sync::RwLockWriteGuard<'a, Storage>),
+}
+
+impl Guard<'_> {
+    /// The shard, if it may be changed.
+    pub(super) fn get_mut(&mut self) -> Option<&mut Storage> {
+        match self {
+            Guard::Shared(_) => None,
+            Guard::Exclusive(guard) => Some(guard),
+        }
+    }
+}
+
+impl Deref for Guard<'_> {
+    type Target = Storage;
+
+    fn deref(&self) -> &Storage {
+        match self {
+            Guard::Shared(guard) => guard,
+            Guard::Exclusive(guard) => guard,
+        }
+    }
+}
+
+/// Locks held on some of the shards, for the duration of a command.
+pub struct ShardLocks<'a> {
+    pub(super) db: &'a Shards,
+    /// Indexed by shard; `None` for the shards not locked.
+    pub(super) guards: Vec<Option<Guard<'a>>>,
+    /// When the last lock was taken; the holds are counted from here.
+    locked_at: Instant,
+}
+
+impl Drop for ShardLocks<'_> {
+    fn drop(&mut self) {
+        let held = self.locked_at.elapsed();
+        for (i, guard) in self.guards.iter().enumerate() {
+            if guard.is_some() {
+                self.db.lock_stats.shard(i).hold.record(held);
+            }
+        }
+    }
+}
+
+impl<'a> ShardLocks<'a> {
+    /// The shard owning `key`, which must have been locked.
+    pub fn shard(&self, key: &[u8]) -> &Storage {
+        self.guards[self.db.shard_of(key)]
+            .as_deref()
+            .expect("the shard of the key is locked")
+    }
+
+    /// The shard owning `key`, which must have been locked exclusively.
+    pub fn shard_mut(&mut self, key: &[u8]) -> &mut Storage {
+        self.guards[self.db.shard_of(key)]
+            .as_mut()
+            .and_then(Guard::get_mut)
+            .expect("the shard of the key is locked exclusively")
+    }
+
+    /// The locked shards.
+    pub fn iter(&self) -> impl Iterator<Item = &Storage> {
+        self.guards.iter().flatten().map(|guard| &**guard)
+    }
+
+    /// The locked shards, which must have been locked exclusively.
+    pub(super) fn iter_mut(&mut self) -> impl Iterator<Item = &mut Storage> + use<'_, 'a> {
+        self.guards
+            .iter_mut()
+            .flatten()
+            .map(|guard| guard.get_mut().expect("the shards are locked exclusively"))
+    }
+
+    /// Any locked shard, for what they all share: persistence and
+    /// replication.
+    pub(super) fn any(&self) -> &Storage {
+        self.iter().next().expect("at least one shard is locked")
+    }
+
+    pub(super) fn is_complete(&self) -> bool {
+        self.guards.iter().all(Option::is_some)
+    }
+
+    /// Modifications so far in the locked shards; a command changed data if
+    /// this moved.
+    pub fn changes(&self) -> u64 {
+        self.iter().map(Storage::changes).sum()
+    }
+
+    pub fn is_propagating(&self) -> bool {
+        self.any().is_propagating()
+    }
+
+    pub fn propagate(&self, frame: &RespValue) {
+        self.any().propagate(frame);
+    }
+
+    pub fn replication(&self) -> Option<&Arc<Replication>> {
+        self.any().replication()
+    }
+
+    pub fn changefeed(&self) -> &ChangeFeed {
+        &self.db.changefeed
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.db.stats
+    }
+
+    pub fn lock_stats(&self) -> &LockStats {
+        &self.db.lock_stats
+    }
+
+    pub fn is_evicting(&self) -> bool {
+        self.any().is_evicting()
+    }
+
+    /// Publishes keyspace notification `event` on `key` of database `db`,
+    /// if `notify-keyspace-events` asks for events of `class`.
+    pub fn notify(&self, class: EventClass, event: &str, db: usize, key: &[u8]) {
+        let events = self.db.config().notify_keyspace_events;
+        events.notify(&self.db.broker, class, event, db, key);
+    }
+
+    /// Keys evicted since the last call, with their database. Only writes
+    /// evict, so shards locked for reading have none.
+    pub fn take_evicted(&mut self) -> Vec<(usize, Bytes)> {
+        self.guards
+            .iter_mut()
+            .flatten()
+            .filter_map(Guard::get_mut)
+            .flat_map(|shard| shard.take_evicted())
+            .collect()
+    }
+
+    /// Marks `keys` as just used, for eviction. Reads sharing their locks
+    /// can't, but they only do while nothing is evicted by access.
+    pub fn record_access(&mut self, keys: &[Bytes]) {
+        for key in keys {
+            let index = self.db.shard_of(key);
+            if let Some(shard) = self.guards[index].as_mut().and_then(Guard::get_mut) {
+                shard.record_access(std::slice::from_ref(key));
+            }
+        }
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.iter().map(Storage::memory_usage).sum()
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.iter().map(Storage::key_count).sum()
+    }
+
+    /// The storage settings as they are now.
+    pub fn config(&self) -> RwLockReadGuard<'_, StorageConfig> {
+        self.db.config()
+    }
+
+    #[cfg(feature = "scripting")]
+    pub fn scripts(&self) -> &ScriptCache {
+        self.db.scripts()
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.db.config().max_memory
+    }
+
+    pub fn maxmemory_policy(&self) -> MaxMemoryPolicy {
+        self.db.config().maxmemory_policy
+    }
+
+    /// Changes the memory limit, split between the shards as at startup.
+    /// Shards over their new budget evict on the next write.
+    pub fn set_max_memory(&mut self, max_memory: usize) {
+        debug_assert!(self.is_complete());
+        let count = self.db.count();
+        for (i, shard) in self.iter_mut().enumerate() {
+            shard.set_max_memory(memory_budget(max_memory, count, i));
+        }
+        self.db.config.write().unwrap().max_memory = max_memory;
+    }
+
+    pub fn set_maxmemory_policy(&mut self, policy: MaxMemoryPolicy) {
+        debug_assert!(self.is_complete());
+        for shard in self.iter_mut() {
+            shard.set_maxmemory_policy(policy);
+        }
+        self.db.config.write().unwrap().maxmemory_policy = policy;
+    }
+
+    /// Changes settings read where they're used, such as the save rules.
+    /// Holding every lock, no command sees the change half made.
+    pub fn update_config(&mut self, update: impl FnOnce(&mut StorageConfig)) {
+        debug_assert!(self.is_complete());
+        update(&mut self.db.config.write().unwrap());
+    }
+
+    /// Slab counters summed over the shards, or `None` if slab allocation
+    /// is off.
+    pub fn slab_stats(&self) -> Option<SlabStats> {
+        self.iter()
+            .map(Storage::slab_stats)
+            .reduce(|total, stats| {
+                let (total, stats) = (total?, stats?);
+                Some(SlabStats {
+                    chunks: total.chunks + stats.chunks,
+                    chunk_bytes: total.chunk_bytes + stats.chunk_bytes,
+                    small_allocations: total.small_allocations + stats.small_allocations,
+                    small_bytes: total.small_bytes + stats.small_bytes,
+                    large_allocations: total.large_allocations + stats.large_allocations,
+                })
+            })
+            .flatten()
+    }
+
+    /// Pooled values and lookups they answered over the shards, or `None`
+    /// if interning is off.
+    pub fn intern_stats(&self) -> Option<(usize, u64)> {
+        self.iter()
+            .map(Storage::intern_stats)
+            .reduce(|total, stats| {
+                let (total, stats) = (total?, stats?);
+                Some((total.0 + stats.0, total.1 + stats.1))
+            })
+            .flatten()
+    }
+
+    pub fn is_logging(&self) -> bool {
+        self.any().is_logging()
+    }
+
+    pub fn is_aof_rewriting(&self) -> bool {
+        self.any().is_aof_rewriting()
+    }
+
+    pub fn sync_aof(&self) -> std::io::Result<()> {
+        self.any().sync_aof()
+    }
+
+    /// Starts compacting the AOF from a snapshot of the dataset.
+    pub fn rewrite_aof(&mut self) -> Result<(), AofError> {
+        debug_assert!(self.is_complete());
+        let aof = self.any().aof.clone().ok_or(AofError::Disabled)?;
+        aof.start_rewrite(|| self.begin_snapshot())
+    }
+
+    /// Drops every key and loads `databases`, the entries of each database
+    /// by index, instead, as when a replica syncs from its primary.
+    pub fn replace_dataset(&mut self, databases: Vec<Vec<(Bytes, Value)>>) {
+        debug_assert!(self.is_complete());
+        let mut partitions: Vec<Vec<Vec<_>>> = (0..self.db.count())
+            .map(|_| (0..databases.len()).map(|_| Vec::new()).collect())
+            .collect();
+        for (index, entries) in databases.into_iter().enumerate() {
+            for (key, value) in entries {
+                partitions[self.db.shard_of(&key)][index].push((key, value));
+            }
+        }
+        for (shard, databases) in self.iter_mut().zip(partitions) {
+            shard.replace_dataset(databases);
+        }
+    }
+
+    pub fn load_from_disk(&mut self) -> Result<(), RdbError> {
+        let path = {
+            let config = self.db.config();
+            if !config.persistence_enabled {
+                return Ok(());
+            }
+            config.dbfilename.clone()
+        };
+        match self.load_dump(&path) {
+            Err(RdbError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Replaces the dataset with the dump file at `path`.
+    pub fn load_dump(&mut self, path: &Path) -> Result<(), RdbError> {
+        let data = std::fs::read(path)?;
+        let dump = rdb::read(&data, self.db.databases())?;
+        self.replace_dataset(dump.databases);
+        self.db.load_scripts(dump.scripts);
+        Ok(())
+    }
+}
+
+/// Shard `i`'s part of `max_memory` split over `count` shards. The
+/// remainder goes to the first shards, so the budgets add up to
+/// `max_memory` exactly.
+fn memory_budget(max_memory: usize, count: usize, i: usize) -> usize {
+    max_memory / count + usize::from(i < max_memory % count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_keys_spread_over_shards() {
+        let db = Shards::new(StorageConfig {
+            max_memory: 1001,
+            shards: 4,
+            ..Default::default()
+        });
+        let budgets: Vec<usize> = db
+            .lock_all()
+            .await
+            .iter()
+            .map(|shard| shard.config.max_memory)
+            .collect();
+        assert_eq!(budgets, vec![251, 250, 250, 250]);
+
+        let keys: Vec<Bytes> = (0..100).map(|i| format!("key:{}", i).into()).collect();
+        {
+            let mut store = db.lock(&keys).await;
+            for key in &keys {
+                assert!(store.shard_mut(key).insert(key.clone(), "v".into()));
+            }
+        }
+        let store = db.lock_all().await;
+        assert_eq!(store.key_count(), 100);
+        assert!(store.iter().all(|shard| shard.key_count() > 0));
+        for key in &keys {
+            assert!(store.shard(key).get(key).unwrap().is_some());
+        }
+
+        // Locking one key's shard leaves the others free.
+        drop(store);
+        let one = db.lock(&keys[..1]).await;
+        assert_eq!(one.iter().count(), 1);
+        let other = keys
+            .iter()
+            .find(|key| db.shard_of(key) != db.shard_of(&keys[0]));
+        assert!(db.lock(&[other.unwrap().clone()]).await.key_count() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_shared_reads() {
+        let db = Shards::new(StorageConfig {
+            shards: 2,
+            ..Default::default()
+        });
+        let key = Bytes::from("k");
+        let keys = [key.clone()];
+        db.lock(&keys)
+            .await
+            .shard_mut(&key)
+            .insert(key.clone(), "v".into());
+
+        // Readers share the lock, and a writer waits for all of them.
+        let first = db.lock_shared(&keys, 0).await.unwrap();
+        let second = db.lock_shared(&keys, 0).await.unwrap();
+        assert!(second.shard(&key).get(&key).unwrap().is_some());
+        let writer = db.lock(&keys);
+        tokio::pin!(writer);
+        drop(first);
+        let wait = std::time::Duration::from_millis(20);
+        assert!(tokio::time::timeout(wait, writer.as_mut()).await.is_err());
+        drop(second);
+        let mut store = writer.await;
+
+        // Switching databases needs the exclusive lock.
+        store.select(1).unwrap();
+        drop(store);
+        assert!(db.lock_shared(&keys, 0).await.is_none());
+    }
+}
@@ -0,0 +1,275 @@
+//! Consistent views of the dataset while writes go on
+//!
+//! Redis forks to save: the child sees the dataset frozen at the fork, the
+//! parent goes on writing, and the kernel copies the pages written. A
+//! thread can't be forked off like that, and copying everything with every
+//! shard locked stops every client for as long as the copy takes. So the
+//! copying is done one shard at a time, after the fact, and each shard keeps
+//! what it had when the snapshot was taken until it's been copied.
+//!
+//! - **The epoch.** [`ShardLocks::begin_snapshot`] runs with every shard
+//!   locked, as `BGSAVE`, `BGREWRITEAOF` and `PSYNC` do, so no write is half
+//!   done. It only hands each shard a marker for the new snapshot, so it
+//!   takes no time whatever the size of the dataset. Everything needing the
+//!   dataset as of that moment is decided under the same locks: the change
+//!   count a save clears, where the AOF starts buffering the writes after
+//!   the rewritten data, the replication offset a replica resumes from, the
+//!   scripts cached.
+//! - **Copy on write.** Until a shard has been copied, the first change to
+//!   each of its keys keeps the value the key had before, or that it didn't
+//!   exist, in [`Storage::preserve`], called by every write ahead of
+//!   changing anything. Flushes, swaps and loads of whole databases keep
+//!   every key of them.
+//! - **The copy.** [`Snapshot::collect`] then locks the shards one after the
+//!   other and copies each: its keys as they are, except those changed
+//!   since, which get their kept values instead. Clients only ever wait for
+//!   one shard's copy; writes to the other shards go on meanwhile, and once
+//!   a shard is copied it stops keeping anything.
+//!
+//! Several snapshots can run at once, each keeping its own values. One
+//! dropped before it's done, its task aborted say, is forgotten by the
+//! shards on their next write. Values kept for a snapshot aren't counted
+//! against `max_memory`, just as the pages Redis copies after a fork aren't:
+//! a snapshot taken during heavy writes can briefly take up to another copy
+//! of the keys written.
+use super::{ShardLocks, Storage, Value};
+use crate::stats::Stats;
+use bytes::Bytes;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+static EPOCHS: AtomicU64 = AtomicU64::new(0);
+
+/// The moment a snapshot is of. Shards hold it weakly, so they can tell
+/// once nobody's waiting for their copy any more.
+#[derive(Debug)]
+struct Epoch(u64);
+
+/// Values a shard keeps for a snapshot that hasn't copied it yet.
+#[derive(Debug)]
+pub(super) struct CopyOnWrite {
+    epoch: Weak<Epoch>,
+    /// By database, what each key changed since the epoch held then, `None`
+    /// if it didn't exist.
+    before: Vec<HashMap<Bytes, Option<Value>>>,
+}
+
+impl CopyOnWrite {
+    /// The kept values of database `index`.
+    fn database(&mut self, index: usize) -> &mut HashMap<Bytes, Option<Value>> {
+        if self.before.len() <= index {
+            self.before.resize_with(index + 1, HashMap::new);
+        }
+        &mut self.before[index]
+    }
+}
+
+/// The dataset as of one moment, still to be copied out of the shards.
+pub struct Snapshot {
+    shards: Arc<[RwLock<Storage>]>,
+    epoch: Arc<Epoch>,
+    stats: Arc<Stats>,
+    scripts: Vec<Bytes>,
+}
+
+impl ShardLocks<'_> {
+    /// Takes a snapshot of the dataset as it is now, to be copied with
+    /// [`Snapshot::collect`] once the locks are released.
+    pub fn begin_snapshot(&mut self) -> Snapshot {
+        debug_assert!(self.is_complete());
+        let epoch = Arc::new(Epoch(EPOCHS.fetch_add(1, Ordering::Relaxed)));
+        for shard in self.iter_mut() {
+            shard.forget_abandoned();
+            shard.cow.push(CopyOnWrite {
+                epoch: Arc::downgrade(&epoch),
+                before: Vec::new(),
+            });
+        }
+        Snapshot {
+            shards: self.db.shards.clone(),
+            epoch,
+            stats: self.db.stats.clone(),
+            scripts: self.db.script_sources(),
+        }
+    }
+}
+
+impl Snapshot {
+    /// The sources of the scripts cached when the snapshot was taken.
+    pub fn scripts(&self) -> Vec<Bytes> {
+        self.scripts.clone()
+    }
+
+    /// Copies the dataset out, a shard at a time, by database. Keys and
+    /// members are refcounted, so the copy shares their bytes.
+    pub async fn collect(self) -> Vec<Vec<(Bytes, Value)>> {
+        let mut databases: Vec<Vec<(Bytes, Value)>> = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().await;
+            let copying = Instant::now();
+            let copy = shard.copy_for(&self.epoch);
+            drop(shard);
+            self.stats.latency().record("snapshot", copying.elapsed());
+            if databases.len() < copy.len() {
+                databases.resize_with(copy.len(), Vec::new);
+            }
+            for (entries, part) in databases.iter_mut().zip(copy) {
+                entries.extend(part);
+            }
+            tokio::task::yield_now().await;
+        }
+        debug!(
+            "Snapshot {} copied: {} keys",
+            self.epoch.0,
+            databases.iter().map(Vec::len).sum::<usize>()
+        );
+        databases
+    }
+}
+
+impl Storage {
+    /// Keeps what `key` of the selected database holds for the snapshots
+    /// yet to copy this shard, unless they have it already. Called before
+    /// anything changes the key.
+    pub(super) fn preserve(&mut self, key: &[u8]) {
+        if self.cow.is_empty() {
+            return;
+        }
+        self.forget_abandoned();
+        for cow in &mut self.cow {
+            let before = cow.database(self.selected);
+            if !before.contains_key(key) {
+                before.insert(Bytes::copy_from_slice(key), self.data.get(key).cloned());
+            }
+        }
+    }
+
+    /// [`Storage::preserve`] for every key of database `index`, ahead of a
+    /// change to all of them.
+    pub(super) fn preserve_database(&mut self, index: usize) {
+        if self.cow.is_empty() {
+            return;
+        }
+        self.forget_abandoned();
+        let data = if index == self.selected {
+            &self.data
+        } else {
+            &self.parked[index].data
+        };
+        for cow in &mut self.cow {
+            let before = cow.database(index);
+            for (key, value) in data {
+                before
+                    .entry(key.clone())
+                    .or_insert_with(|| Some(value.clone()));
+            }
+        }
+    }
+
+    /// Drops what was kept for snapshots nobody's collecting any more.
+    fn forget_abandoned(&mut self) {
+        self.cow.retain(|cow| cow.epoch.strong_count() > 0);
+    }
+
+    /// The shard's part of every database as of `epoch`, after which it no
+    /// longer keeps anything for it.
+    fn copy_for(&mut self, epoch: &Arc<Epoch>) -> Vec<Vec<(Bytes, Value)>> {
+        self.forget_abandoned();
+        let target = Arc::downgrade(epoch);
+        let mut before = match self.cow.iter().position(|cow| cow.epoch.ptr_eq(&target)) {
+            Some(index) => self.cow.swap_remove(index).before,
+            None => Vec::new(),
+        };
+        before.resize_with(self.databases(), HashMap::new);
+        before
+            .into_iter()
+            .enumerate()
+            .map(|(index, changed)| {
+                let mut entries: Vec<(Bytes, Value)> = self
+                    .database(index)
+                    .iter()
+                    .filter(|(key, _)| !changed.contains_key(*key))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                entries.extend(
+                    changed
+                        .into_iter()
+                        .filter_map(|(key, value)| Some((key, value?))),
+                );
+                entries
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::StorageConfig;
+    use crate::storage::{Shards, Value};
+    use bytes::Bytes;
+    use std::collections::HashMap;
+
+    fn strings(databases: Vec<Vec<(Bytes, Value)>>) -> Vec<HashMap<Bytes, Bytes>> {
+        databases
+            .into_iter()
+            .map(|entries| {
+                entries
+                    .into_iter()
+                    .map(|(key, value)| match value {
+                        Value::String(s) => (key, s),
+                        other => (key, other.type_name().into()),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_ignores_later_writes() {
+        let db = Shards::new(StorageConfig {
+            shards: 4,
+            databases: 2,
+            ..Default::default()
+        });
+        let mut store = db.lock_all().await;
+        for key in ["a", "b", "c", "d"] {
+            store
+                .shard_mut(key.as_bytes())
+                .insert(key.into(), "old".into());
+        }
+        store.select(1).unwrap();
+        store.shard_mut(b"one").insert("one".into(), "1".into());
+        store.select(0).unwrap();
+        let snapshot = store.begin_snapshot();
+        let abandoned = store.begin_snapshot();
+        drop(abandoned);
+
+        // Every kind of change after the epoch.
+        store.shard_mut(b"a").insert("a".into(), "new".into());
+        store.shard_mut(b"b").del(&["b".into()]);
+        store.shard_mut(b"e").insert("e".into(), "new".into());
+        store.shard_mut(b"s").sadd(b"s", vec!["x".into()]).unwrap();
+        store.select(1).unwrap();
+        store.flushdb();
+        store.select(0).unwrap();
+        drop(store);
+
+        let copy = strings(snapshot.collect().await);
+        assert_eq!(copy.len(), 2);
+        let expected: HashMap<Bytes, Bytes> = ["a", "b", "c", "d"]
+            .into_iter()
+            .map(|key| (key.into(), "old".into()))
+            .collect();
+        assert_eq!(copy[0], expected);
+        assert_eq!(copy[1].get(&Bytes::from("one")), Some(&Bytes::from("1")));
+
+        // Copied, the shards keep nothing more, for this snapshot or the
+        // dropped one.
+        let store = db.lock_all().await;
+        assert!(store.iter().all(|shard| shard.cow.is_empty()));
+    }
+}
@@ -0,0 +1,322 @@
+//! The string API: `SET` and its options, counters, `APPEND`, ranges,
+//! `MSET`
+use super::{range, unix_ms, ShardLocks, Storage, StorageError};
+use bytes::{Bytes, BytesMut};
+
+/// Whether a `SET` stores its value, given whether the key exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SetCondition {
+    #[default]
+    Always,
+    /// `NX`: only if the key doesn't exist.
+    IfMissing,
+    /// `XX`: only if it does.
+    IfExists,
+}
+
+/// What a `SET` does with the TTL of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SetExpiry {
+    /// The key doesn't expire, whether it did before or not.
+    #[default]
+    Persist,
+    /// `KEEPTTL`: the key keeps the TTL it had, as it does through
+    /// `INCRBY` or `APPEND`.
+    Keep,
+    /// `EX` or `PX`: the key expires this many milliseconds from now.
+    After(u64),
+    /// `EXAT` or `PXAT`: the key expires at this Unix time in milliseconds.
+    At(u64),
+}
+
+/// The options of `SET`, which `SETNX` and `GETSET` are special cases of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SetOptions {
+    pub condition: SetCondition,
+    /// Reply with the string replaced, as `GETSET` does.
+    pub get: bool,
+    pub expiry: SetExpiry,
+}
+
+impl SetOptions {
+    /// Options for a write changing the value in place, which keeps its
+    /// TTL.
+    fn keep_ttl() -> Self {
+        SetOptions {
+            expiry: SetExpiry::Keep,
+            ..SetOptions::default()
+        }
+    }
+}
+
+/// What a `SET` did.
+#[derive(Debug, PartialEq)]
+pub struct SetOutcome {
+    /// Whether the value was stored; `false` if the condition failed.
+    pub stored: bool,
+    /// The string replaced, if `get` was asked for.
+    pub old: Option<Bytes>,
+}
+
+/// Every change to a string goes through these, so the commands share one
+/// treatment of types and memory: reading a value of another type fails
+/// with `WRONGTYPE`, and a write that doesn't fit under `max_memory` fails
+/// leaving the old value in place. Plain reads are [`Storage::get`].
+pub trait StringOps {
+    /// Stores `value` at `key` if `options` allow, replacing whatever was
+    /// there, strings or not.
+    fn set_with_options(
+        &mut self,
+        key: Bytes,
+        value: Bytes,
+        options: SetOptions,
+    ) -> Result<SetOutcome, StorageError>;
+
+    /// Adds `delta` to the integer at `key`, a missing key counting as 0,
+    /// and returns the new value.
+    fn incr_by(&mut self, key: Bytes, delta: i64) -> Result<i64, StorageError>;
+
+    /// Appends `suffix` to the string at `key`, creating it if needed, and
+    /// returns the new length.
+    fn append(&mut self, key: Bytes, suffix: &[u8]) -> Result<usize, StorageError>;
+
+    fn strlen(&self, key: &[u8]) -> Result<usize, StorageError>;
+
+    /// Bytes `start..=stop` of the string at `key`; negative offsets count
+    /// from the end.
+    fn getrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Bytes, StorageError>;
+}
+
+impl StringOps for Storage {
+    fn set_with_options(
+        &mut self,
+        key: Bytes,
+        value: Bytes,
+        options: SetOptions,
+    ) -> Result<SetOutcome, StorageError> {
+        let old = match options.get {
+            true => self.get(&key)?.cloned(),
+            false => None,
+        };
+        let go = match options.condition {
+            SetCondition::Always => true,
+            SetCondition::IfMissing => !self.exists(&key),
+            SetCondition::IfExists => self.exists(&key),
+        };
+        if !go {
+            return Ok(SetOutcome { stored: false, old });
+        }
+        if !self.insert(key.clone(), value) {
+            return Err(StorageError::OutOfMemory);
+        }
+        match options.expiry {
+            SetExpiry::Persist => self.set_expiry(&key, None),
+            SetExpiry::Keep => {}
+            SetExpiry::After(ms) => self.set_expiry(&key, Some(unix_ms().saturating_add(ms))),
+            SetExpiry::At(at) => self.set_expiry(&key, Some(at)),
+        }
+        Ok(SetOutcome { stored: true, old })
+    }
+
+    fn incr_by(&mut self, key: Bytes, delta: i64) -> Result<i64, StorageError> {
+        let current = match self.get(&key)? {
+            Some(value) => std::str::from_utf8(value)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or(StorageError::NotAnInteger)?,
+            None => 0,
+        };
+        let value = current.checked_add(delta).ok_or(StorageError::Overflow)?;
+        self.set_with_options(key, value.to_string().into(), SetOptions::keep_ttl())?;
+        Ok(value)
+    }
+
+    fn append(&mut self, key: Bytes, suffix: &[u8]) -> Result<usize, StorageError> {
+        let mut value = BytesMut::new();
+        if let Some(current) = self.get(&key)? {
+            value.extend_from_slice(current);
+        }
+        value.extend_from_slice(suffix);
+        let len = value.len();
+        self.set_with_options(key, value.freeze(), SetOptions::keep_ttl())?;
+        Ok(len)
+    }
+
+    fn strlen(&self, key: &[u8]) -> Result<usize, StorageError> {
+        Ok(self.get(key)?.map_or(0, Bytes::len))
+    }
+
+    fn getrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Bytes, StorageError> {
+        let Some(value) = self.get(key)? else {
+            return Ok(Bytes::new());
+        };
+        Ok(match range::byte_range(start, stop, value.len()) {
+            Some(range) => value.slice(range),
+            None => Bytes::new(),
+        })
+    }
+}
+
+impl Storage {
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.data.contains_key(key)
+    }
+}
+
+impl ShardLocks<'_> {
+    /// How many of `keys` exist, counting repeats every time.
+    pub fn exists(&self, keys: &[Bytes]) -> usize {
+        keys.iter()
+            .filter(|key| self.shard(key).exists(key))
+            .count()
+    }
+
+    /// Stores every pair in order. A pair that doesn't fit under
+    /// `max_memory` fails the command, leaving the pairs before it set.
+    pub fn mset(&mut self, pairs: Vec<(Bytes, Bytes)>) -> Result<(), StorageError> {
+        for (key, value) in pairs {
+            self.shard_mut(&key)
+                .set_with_options(key, value, SetOptions::default())?;
+        }
+        Ok(())
+    }
+
+    /// The string values at `keys`; missing keys and other types read as
+    /// nil.
+    pub fn mget(&self, keys: &[Bytes]) -> Vec<Option<Bytes>> {
+        keys.iter()
+            .map(|key| self.shard(key).get(key).ok().flatten().cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+
+    #[test]
+    fn test_string_commands() {
+        let mut store = Storage::new(StorageConfig {
+            max_memory: 64,
+            ..Default::default()
+        });
+        assert_eq!(store.incr_by("n".into(), 5), Ok(5));
+        assert_eq!(store.incr_by("n".into(), -7), Ok(-2));
+        assert_eq!(store.memory_usage(), 3);
+        let set = SetOptions::default();
+        store
+            .set_with_options("n".into(), i64::MAX.to_string().into(), set)
+            .unwrap();
+        assert_eq!(store.incr_by("n".into(), 1), Err(StorageError::Overflow));
+        store
+            .set_with_options("s".into(), "12a".into(), set)
+            .unwrap();
+        assert_eq!(
+            store.incr_by("s".into(), 1),
+            Err(StorageError::NotAnInteger)
+        );
+
+        assert_eq!(store.append("s".into(), b"bc"), Ok(5));
+        assert_eq!(store.strlen(b"s"), Ok(5));
+        assert_eq!(store.strlen(b"missing"), Ok(0));
+        assert_eq!(store.getrange(b"s", 1, -2), Ok("2ab".into()));
+        let nx = SetOptions {
+            condition: SetCondition::IfMissing,
+            get: true,
+            ..set
+        };
+        assert_eq!(
+            store.set_with_options("s".into(), "y".into(), nx),
+            Ok(SetOutcome {
+                stored: false,
+                old: Some("12abc".into())
+            })
+        );
+        let getset = SetOptions { get: true, ..set };
+        assert_eq!(
+            store.set_with_options("s".into(), "x".into(), getset),
+            Ok(SetOutcome {
+                stored: true,
+                old: Some("12abc".into())
+            })
+        );
+        assert_eq!(store.memory_usage(), 1 + 19 + 2);
+
+        // Growing past the limit fails and leaves the value as it was.
+        let long = Bytes::from(vec![b'a'; 64]);
+        assert_eq!(
+            store.append("s".into(), &long),
+            Err(StorageError::OutOfMemory)
+        );
+        assert_eq!(store.get(b"s"), Ok(Some(&Bytes::from("x"))));
+
+        store.sadd(b"set", vec!["m".into()]).unwrap();
+        assert_eq!(store.incr_by("set".into(), 1), Err(StorageError::WrongType));
+        assert_eq!(
+            store.append("set".into(), b"x"),
+            Err(StorageError::WrongType)
+        );
+        assert_eq!(
+            store.set_with_options("set".into(), "x".into(), getset),
+            Err(StorageError::WrongType)
+        );
+        // Without GET, SET replaces a value of any type.
+        let xx = SetOptions {
+            condition: SetCondition::IfExists,
+            ..set
+        };
+        assert_eq!(
+            store.set_with_options("set".into(), "x".into(), xx),
+            Ok(SetOutcome {
+                stored: true,
+                old: None
+            })
+        );
+        assert_eq!(
+            store.set_with_options("nope".into(), "x".into(), xx),
+            Ok(SetOutcome {
+                stored: false,
+                old: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_set_expiry() {
+        let mut store = Storage::new(StorageConfig::default());
+        let options = |expiry| SetOptions {
+            expiry,
+            ..SetOptions::default()
+        };
+        let before = unix_ms();
+        store
+            .set_with_options("n".into(), "1".into(), options(SetExpiry::After(60_000)))
+            .unwrap();
+        let at = store.expiry(b"n").unwrap();
+        assert!(at >= before + 60_000 && at <= unix_ms() + 60_000);
+
+        // Changing the value in place keeps the TTL, and so does KEEPTTL.
+        store.incr_by("n".into(), 1).unwrap();
+        store.append("n".into(), b"0").unwrap();
+        store
+            .set_with_options("n".into(), "3".into(), options(SetExpiry::Keep))
+            .unwrap();
+        assert_eq!(store.expiry(b"n"), Some(at));
+        store
+            .set_with_options("n".into(), "4".into(), options(SetExpiry::At(at + 1)))
+            .unwrap();
+        assert_eq!(store.expiry(b"n"), Some(at + 1));
+        // A SET whose condition fails sets no TTL, and a plain SET drops it.
+        let xx = SetOptions {
+            condition: SetCondition::IfExists,
+            ..options(SetExpiry::At(1))
+        };
+        store.set_with_options("x".into(), "1".into(), xx).unwrap();
+        assert_eq!(store.expiry(b"x"), None);
+        store
+            .set_with_options("n".into(), "5".into(), SetOptions::default())
+            .unwrap();
+        assert_eq!(store.expiry(b"n"), None);
+    }
+}
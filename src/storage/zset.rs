@@ -0,0 +1,211 @@
+//! Score-ordered set used for the sorted set type
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+
+/// An `f64` with a total order so it can key a `BTreeSet`. NaN is rejected
+/// when parsing scores, so it never ends up in a set.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// One end of a `ZRANGEBYSCORE` interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ScoreBound {
+    fn admits_min(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(min) => score >= min,
+            ScoreBound::Exclusive(min) => score > min,
+        }
+    }
+
+    fn admits_max(&self, score: f64) -> bool {
+        match *self {
+            ScoreBound::Inclusive(max) => score <= max,
+            ScoreBound::Exclusive(max) => score < max,
+        }
+    }
+}
+
+/// Members ordered by `(score, member)`, with a hash index for score lookups.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    ordered: BTreeSet<(Score, Bytes)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet::default()
+    }
+
+    /// Adds or updates a member; returns true if the member is new. A score
+    /// of -0 is kept as 0, which the total order would otherwise put below
+    /// it, out of ranges starting at 0.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        let score = if score == 0.0 { 0.0 } else { score };
+        match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.ordered.remove(&(Score(old), member.clone()));
+                self.ordered.insert((Score(score), member));
+                false
+            }
+            None => {
+                self.ordered.insert((Score(score), member));
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove_entry(member) {
+            Some((member, score)) => {
+                self.ordered.remove(&(Score(score), member));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// Members with ranks in `start..=stop`, both already clamped to the set.
+    pub fn range_by_rank(&self, start: usize, stop: usize) -> impl Iterator<Item = (&Bytes, f64)> {
+        self.iter()
+            .skip(start)
+            .take((stop + 1).saturating_sub(start))
+    }
+
+    pub fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> impl Iterator<Item = (&Bytes, f64)> {
+        let lower = match min {
+            ScoreBound::Inclusive(score) | ScoreBound::Exclusive(score) => {
+                Bound::Included((Score(score), Bytes::new()))
+            }
+        };
+        self.ordered
+            .range((lower, Bound::Unbounded))
+            .map(|(score, member)| (member, score.0))
+            .skip_while(move |(_, score)| !min.admits_min(*score))
+            .take_while(move |(_, score)| max.admits_max(*score))
+    }
+}
+
+impl From<Vec<(Bytes, f64)>> for SortedSet {
+    fn from(members: Vec<(Bytes, f64)>) -> Self {
+        let mut set = SortedSet::new();
+        for (member, score) in members {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl From<SortedSet> for Vec<(Bytes, f64)> {
+    fn from(set: SortedSet) -> Self {
+        set.scores.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> SortedSet {
+        let mut set = SortedSet::new();
+        set.insert(Bytes::from_static(b"b"), 2.0);
+        set.insert(Bytes::from_static(b"a"), 1.0);
+        set.insert(Bytes::from_static(b"c"), 3.0);
+        set.insert(Bytes::from_static(b"a2"), 1.0);
+        set
+    }
+
+    #[test]
+    fn test_ordering() {
+        let set = sample();
+        let members: Vec<&[u8]> = set.iter().map(|(m, _)| m.as_ref()).collect();
+        assert_eq!(members, vec![&b"a"[..], b"a2", b"b", b"c"]);
+    }
+
+    #[test]
+    fn test_update_score() {
+        let mut set = sample();
+        assert!(!set.insert(Bytes::from_static(b"a"), 10.0));
+        assert_eq!(set.score(b"a"), Some(10.0));
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.iter().last(), Some((&Bytes::from_static(b"a"), 10.0)));
+        assert!(set.remove(b"a"));
+        assert!(!set.remove(b"a"));
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let set = sample();
+        let members = |min, max| -> Vec<&[u8]> {
+            set.range_by_score(min, max)
+                .map(|(m, _)| m.as_ref())
+                .collect()
+        };
+        assert_eq!(
+            members(ScoreBound::Inclusive(1.0), ScoreBound::Inclusive(2.0)),
+            vec![&b"a"[..], b"a2", b"b"]
+        );
+        assert_eq!(
+            members(ScoreBound::Exclusive(1.0), ScoreBound::Exclusive(3.0)),
+            vec![&b"b"[..]]
+        );
+        let mut zero = SortedSet::new();
+        zero.insert(Bytes::from_static(b"z"), -0.0);
+        let zeros = zero.range_by_score(ScoreBound::Inclusive(0.0), ScoreBound::Inclusive(0.0));
+        assert_eq!(zeros.count(), 1);
+        assert_eq!(zero.score(b"z").map(f64::is_sign_negative), Some(false));
+        let range = set.range_by_score(
+            ScoreBound::Inclusive(f64::NEG_INFINITY),
+            ScoreBound::Inclusive(f64::INFINITY),
+        );
+        assert_eq!(range.count(), 4);
+    }
+}
@@ -0,0 +1,345 @@
+//! Linearizability of single-key reads, writes and compare-and-sets
+//!
+//! Clients run `GET`, `SET` and a compare-and-set built on `WATCH`
+//! concurrently on a few keys of an in-process server, noting when each
+//! call went out and when its reply came back. Each key's history is then
+//! checked against a register: some order of the operations, keeping every
+//! one that returned before another was called ahead of it, has to explain
+//! every reply. The search is Wing and Gong's, with Lowe's memoization of
+//! the states already explored. Linearizability is local, so checking the
+//! keys one at a time checks the whole history.
+//!
+//! The servers run with each locking mode there is: reads sharing their
+//! locks, every command locking exclusively, and hot keys read without
+//! locking at all.
+use rdb::config::Config;
+use rdb::protocol::{parse_resp, RespError, RespValue};
+use rdb::Server;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CLIENTS: u64 = 8;
+const OPS_PER_CLIENT: usize = 200;
+const KEYS: [&str; 4] = ["lin:a", "lin:b", "lin:c", "lin:d"];
+
+/// What an operation did, as far as its reply tells. Values are unique per
+/// write; `None` is the key missing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Read(Option<u64>),
+    Write(u64),
+    /// A compare-and-set that went through.
+    Cas {
+        expected: Option<u64>,
+        new: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Op {
+    key: &'static str,
+    call: Instant,
+    ret: Instant,
+    kind: Kind,
+}
+
+/// The register's state after `kind`, or `None` if `kind` can't have
+/// happened with the register at `state`.
+fn step(state: Option<u64>, kind: Kind) -> Option<Option<u64>> {
+    match kind {
+        Kind::Read(value) => (state == value).then_some(state),
+        Kind::Write(value) => Some(Some(value)),
+        Kind::Cas { expected, new } => (state == expected).then_some(Some(new)),
+    }
+}
+
+/// Whether some order of `ops` consistent with real time explains them all,
+/// starting from a missing key.
+fn linearizable(ops: &[Op]) -> bool {
+    let mut done = vec![false; ops.len()];
+    search(ops, &mut done, None, &mut HashSet::new())
+}
+
+fn search(
+    ops: &[Op],
+    done: &mut Vec<bool>,
+    state: Option<u64>,
+    explored: &mut HashSet<(Vec<bool>, Option<u64>)>,
+) -> bool {
+    // The first pending operation to return bounds which can go next: none
+    // called after it returned can come before it.
+    let Some(horizon) = ops
+        .iter()
+        .zip(done.iter())
+        .filter(|(_, done)| !**done)
+        .map(|(op, _)| op.ret)
+        .min()
+    else {
+        return true;
+    };
+    if !explored.insert((done.clone(), state)) {
+        return false;
+    }
+    for (i, op) in ops.iter().enumerate() {
+        if done[i] || op.call > horizon {
+            continue;
+        }
+        if let Some(next) = step(state, op.kind) {
+            done[i] = true;
+            if search(ops, done, next, explored) {
+                return true;
+            }
+            done[i] = false;
+        }
+    }
+    false
+}
+
+/// A bare RESP client.
+struct Client {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> Self {
+        Client {
+            stream: TcpStream::connect(addr).await.expect("connect"),
+            buffer: Vec::new(),
+        }
+    }
+
+    async fn request(&mut self, args: &[&str]) -> RespValue {
+        let frame = RespValue::Array(
+            args.iter()
+                .map(|arg| RespValue::bulk(arg.to_string()))
+                .collect(),
+        );
+        self.stream
+            .write_all(&frame.serialize())
+            .await
+            .expect("write");
+        loop {
+            match parse_resp(&self.buffer) {
+                Ok((reply, used)) => {
+                    self.buffer.drain(..used);
+                    return reply;
+                }
+                Err(RespError::Incomplete) => {
+                    let mut chunk = [0; 4096];
+                    let n = self.stream.read(&mut chunk).await.expect("read");
+                    assert!(n > 0, "server closed the connection");
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) => panic!("bad reply: {}", e),
+            }
+        }
+    }
+
+    async fn get(&mut self, key: &str) -> Option<u64> {
+        match self.request(&["GET", key]).await {
+            RespValue::BulkString(Some(value)) => {
+                Some(String::from_utf8_lossy(&value).parse().expect("a number"))
+            }
+            RespValue::BulkString(None) => None,
+            reply => panic!("GET replied {:?}", reply),
+        }
+    }
+}
+
+/// A xorshift generator, so runs don't need a random crate.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// One client's share of the load: random reads, writes and
+/// compare-and-sets, the latter expecting what the client last saw.
+async fn run_client(addr: SocketAddr, id: u64) -> Vec<Op> {
+    let mut client = Client::connect(addr).await;
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (id + 1));
+    let mut last_seen = [None; KEYS.len()];
+    let mut history = Vec::with_capacity(OPS_PER_CLIENT);
+    for seq in 0..OPS_PER_CLIENT {
+        let index = rng.below(KEYS.len());
+        let key = KEYS[index];
+        let value = id * 1_000_000 + seq as u64;
+        let call = Instant::now();
+        let (ret, kind) = match rng.below(3) {
+            0 => {
+                let read = client.get(key).await;
+                last_seen[index] = read;
+                (Instant::now(), Kind::Read(read))
+            }
+            1 => {
+                let reply = client.request(&["SET", key, &value.to_string()]).await;
+                assert_eq!(reply, RespValue::SimpleString("OK".to_string()));
+                (Instant::now(), Kind::Write(value))
+            }
+            _ => {
+                let expected = last_seen[index];
+                client.request(&["WATCH", key]).await;
+                let read = client.get(key).await;
+                let read_at = Instant::now();
+                last_seen[index] = read;
+                if read != expected {
+                    client.request(&["UNWATCH"]).await;
+                    (read_at, Kind::Read(read))
+                } else {
+                    client.request(&["MULTI"]).await;
+                    client.request(&["SET", key, &value.to_string()]).await;
+                    match client.request(&["EXEC"]).await {
+                        RespValue::Array(_) => {
+                            last_seen[index] = Some(value);
+                            (
+                                Instant::now(),
+                                Kind::Cas {
+                                    expected,
+                                    new: value,
+                                },
+                            )
+                        }
+                        // Someone wrote the key since; only the read counts.
+                        _ => (read_at, Kind::Read(read)),
+                    }
+                }
+            }
+        };
+        history.push(Op {
+            key,
+            call,
+            ret,
+            kind,
+        });
+    }
+    history
+}
+
+/// Runs the clients against a server configured by `configure` and checks
+/// each key's history. Returns the server's `INFO stats`, to tell which
+/// paths the commands took.
+async fn check_server(configure: impl FnOnce(&mut Config)) -> String {
+    let mut config = Config::default();
+    config.storage.save_rules = vec![];
+    configure(&mut config);
+    let server = Server::builder()
+        .config(config)
+        .bind("127.0.0.1:0".parse().unwrap())
+        .run()
+        .await
+        .expect("server starts");
+    let addr = server.local_addr();
+
+    let mut clients = tokio::task::JoinSet::new();
+    for id in 0..CLIENTS {
+        clients.spawn(run_client(addr, id));
+    }
+    let mut history = Vec::new();
+    while let Some(ops) = clients.join_next().await {
+        history.extend(ops.unwrap());
+    }
+    let info = match Client::connect(addr)
+        .await
+        .request(&["INFO", "stats"])
+        .await
+    {
+        RespValue::BulkString(Some(info)) => String::from_utf8_lossy(&info).into_owned(),
+        reply => panic!("INFO replied {:?}", reply),
+    };
+    server.shutdown().await;
+
+    for key in KEYS {
+        let mut ops: Vec<_> = history.iter().filter(|op| op.key == key).cloned().collect();
+        ops.sort_by_key(|op| op.call);
+        assert!(
+            linearizable(&ops),
+            "history of {} isn't linearizable: {:#?}",
+            key,
+            ops
+        );
+    }
+    info
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_shared_reads_are_linearizable() {
+    check_server(|config| config.storage.shared_reads = true).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_exclusive_locks_are_linearizable() {
+    check_server(|config| config.storage.shared_reads = false).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_hot_keys_are_linearizable() {
+    let info = check_server(|config| {
+        config.storage.hot_keys.enabled = true;
+        config.storage.hot_keys.reads_per_sec = 1;
+    })
+    .await;
+    // The reads did skip the locks.
+    let hits = info
+        .lines()
+        .find_map(|line| line.strip_prefix("hot_key_hits:"))
+        .and_then(|hits| hits.parse::<u64>().ok());
+    assert!(hits.is_some_and(|hits| hits > 0), "{}", info);
+}
+
+#[test]
+fn test_checker_catches_stale_reads() {
+    let start = Instant::now();
+    let at = |from: u64, to: u64, kind| Op {
+        key: "k",
+        call: start + std::time::Duration::from_millis(from),
+        ret: start + std::time::Duration::from_millis(to),
+        kind,
+    };
+
+    // Reads overlapping a write may see either value, but once one has
+    // seen the new one, later reads can't see the old.
+    assert!(linearizable(&[
+        at(0, 10, Kind::Write(1)),
+        at(5, 6, Kind::Read(None)),
+        at(7, 8, Kind::Read(Some(1))),
+    ]));
+    assert!(!linearizable(&[
+        at(0, 10, Kind::Write(1)),
+        at(5, 6, Kind::Read(Some(1))),
+        at(7, 8, Kind::Read(None)),
+    ]));
+    // Once the write returned, reads have to see it.
+    assert!(!linearizable(&[
+        at(0, 1, Kind::Write(1)),
+        at(2, 3, Kind::Read(None)),
+    ]));
+    // Two compare-and-sets from the same value can't both succeed.
+    assert!(!linearizable(&[
+        at(0, 1, Kind::Write(1)),
+        at(
+            2,
+            5,
+            Kind::Cas {
+                expected: Some(1),
+                new: 2,
+            }
+        ),
+        at(
+            3,
+            6,
+            Kind::Cas {
+                expected: Some(1),
+                new: 3,
+            }
+        ),
+    ]));
+}